@@ -0,0 +1,1381 @@
+//! Ownership and borrow checking.
+//!
+//! Each function body, closure body and the top level script is lowered into
+//! a control flow graph with one node per access to a place: a read, a move,
+//! an assignment, a `ref` or `ref mut` borrow, or a binding going out of
+//! scope. Two dataflow analyses run over the graph:
+//!
+//! * Moves. Values of types that aren't copied (records, unions, generic
+//!   parameters, `ref mut` references and tuples holding any of them) are
+//!   moved when used by value, and using a place that may have been moved
+//!   is an error until it is assigned again.
+//! * Borrows. A reference stored in a binding keeps its borrow alive for as
+//!   long as the binding may still be used, so a borrow ends at its last use
+//!   rather than at the end of a scope; one that isn't stored ends with its
+//!   statement. While a `ref mut` borrow is alive nothing else may use the
+//!   place; while a `ref` borrow is alive the place may only be read.
+//!
+//! Places are locals and their fields: `h::name` and `h::age` can be
+//! borrowed independently, `h` and `h::name` can't. Everything reached
+//! through a reference is checked when the reference itself is borrowed.
+//! Closures borrow what they capture when they are created, `ref mut` if it
+//! was declared `mut`, and hold those borrows for as long as they live. Their
+//! bodies are checked on their own, reaching captures through references; a
+//! capture the body moves out of is moved when the closure is created. Top level bindings are locals of the script;
+//! functions, which may run at any point of it, use them untracked.
+
+use super::items::ItemTable;
+use super::resolve::Res;
+use super::types::Ty;
+use super::{FnOwner, TypeckResults};
+use crate::errorhandler::{Diagnostic, ErrorHandler};
+use crate::lexer::size::Span;
+use crate::parser::ast::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+
+type LocalId = NodeId;
+type LoanId = usize;
+
+/// Stands in for `self`, which has no declaration node.
+const SELF_LOCAL: LocalId = NodeId::MAX;
+
+/// A local or a field path inside it, e.g. `h::pet::name`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Place {
+    local: LocalId,
+    fields: Vec<String>,
+}
+
+impl Place {
+    fn local(local: LocalId) -> Self {
+        Place {
+            local,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Whether `self` is `other` or contains it.
+    fn contains(&self, other: &Place) -> bool {
+        self.local == other.local && other.fields.starts_with(&self.fields)
+    }
+
+    fn overlaps(&self, other: &Place) -> bool {
+        self.contains(other) || other.contains(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    Read,
+    Move,
+    Write,
+    Borrow {
+        mutable: bool,
+        loan: LoanId,
+    },
+    /// The binding goes out of scope.
+    Dead,
+}
+
+#[derive(Debug)]
+struct Event {
+    place: Place,
+    action: Action,
+    span: Span,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    event: Option<Event>,
+    succs: Vec<usize>,
+}
+
+#[derive(Debug)]
+struct Loan {
+    place: Place,
+    mutable: bool,
+    span: Span,
+    /// The node creating the loan.
+    created: usize,
+    /// The node ending the statement that created the loan; until then the
+    /// reference is alive even if no binding holds it.
+    stmt_end: usize,
+    /// Bindings the reference was stored in.
+    holders: BTreeSet<LocalId>,
+}
+
+/// How an expression's value is used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Read,
+    Consume,
+    /// Passed as an argument: consumed, except that a `ref mut` is only
+    /// reborrowed for the call.
+    Arg,
+}
+
+#[derive(Default)]
+struct LoopTarget {
+    head: usize,
+    breaks: Vec<usize>,
+    values: Vec<LoanId>,
+}
+
+/// Checks every body of a module that type checked without errors.
+pub(super) fn check(
+    program: &Program,
+    items: &ItemTable,
+    results: &TypeckResults,
+    handler: &mut ErrorHandler,
+) {
+    let mut script: Vec<&Stmt> = Vec::new();
+    for item in &program.items {
+        match &item.kind {
+            ItemKind::Function(function) => check_function(items, results, function, None, handler),
+            ItemKind::Impl(imp) => {
+                let Some((name, generics)) = items.impl_target(&imp.target) else {
+                    continue;
+                };
+                let self_ty = Ty::Adt {
+                    name,
+                    args: generics.into_iter().map(Ty::Param).collect(),
+                };
+                for method in &imp.methods {
+                    check_function(items, results, &method.function, Some(&self_ty), handler);
+                }
+            }
+            ItemKind::Stmt(stmt) => script.push(stmt),
+            ItemKind::Record(_)
+            | ItemKind::Union(_)
+            | ItemKind::Protocol(_)
+            | ItemKind::Import(_) => {}
+        }
+    }
+    let mut builder = Builder::new(items, results);
+    for stmt in &script {
+        if let StmtKind::Let { binding, .. } = &stmt.kind {
+            let name = items.canonical(&binding.name.name);
+            builder.globals.insert(name, binding.id);
+        }
+    }
+    for stmt in script {
+        let first = builder.loans.len();
+        builder.stmt(stmt);
+        builder.end_statement(first, &[]);
+    }
+    builder.finish(handler);
+}
+
+fn check_function<'a>(
+    items: &'a ItemTable,
+    results: &'a TypeckResults,
+    function: &'a Function,
+    self_ty: Option<&Ty>,
+    handler: &mut ErrorHandler,
+) {
+    let Some(body) = &function.body else {
+        return;
+    };
+    let mut builder = Builder::new(items, results);
+    builder.scopes.push(Vec::new());
+    if let (Some(param), Some(self_ty)) = (&function.self_param, self_ty) {
+        let ty = match param.kind {
+            SelfKind::Value | SelfKind::MutValue => self_ty.clone(),
+            SelfKind::Ref | SelfKind::RefMut => Ty::Ref {
+                mutable: param.kind == SelfKind::RefMut,
+                inner: Box::new(self_ty.clone()),
+            },
+        };
+        builder.declare(SELF_LOCAL, "self", ty, &[], param.span);
+    }
+    for param in &function.params {
+        let ty = results
+            .binding_types
+            .get(&param.id)
+            .cloned()
+            .unwrap_or(Ty::Error);
+        if param.mutable {
+            builder.mutable.insert(param.id);
+        }
+        builder.declare(param.id, &param.name.name, ty, &[], param.name.span);
+    }
+    let (value, span) = match body {
+        FnBody::Block(block) => (builder.block(block, Mode::Consume), block.span),
+        FnBody::Inline(expr) => (builder.expr(expr, Mode::Consume), expr.span),
+    };
+    builder.returned.push((value, span));
+    builder.exit(function.span);
+    builder.finish(handler);
+}
+
+struct Builder<'a> {
+    items: &'a ItemTable,
+    results: &'a TypeckResults,
+    nodes: Vec<Node>,
+    /// The node new nodes follow, `None` after `return`, `break` and
+    /// `continue`.
+    current: Option<usize>,
+    loans: Vec<Loan>,
+    /// Loans whose reference each binding may hold.
+    held: HashMap<LocalId, BTreeSet<LoanId>>,
+    names: HashMap<LocalId, String>,
+    types: HashMap<LocalId, Ty>,
+    /// Bindings declared in each enclosing block, in order.
+    scopes: Vec<Vec<LocalId>>,
+    loops: Vec<LoopTarget>,
+    /// Nodes ending in `return`.
+    returns: Vec<usize>,
+    /// Loans of each returned value, with where it is returned.
+    returned: Vec<(Vec<LoanId>, Span)>,
+    /// Bodies of the closures created here, checked after this one.
+    closures: Vec<Builder<'a>>,
+    /// Locals declared `mut`, which closures capture by `ref mut`.
+    mutable: HashSet<LocalId>,
+    /// In a closure body, the enclosing locals it captures, which it
+    /// reaches through references.
+    captured: HashSet<LocalId>,
+    /// Captures the body moves out of.
+    moved_captures: BTreeSet<LocalId>,
+    /// The script's bindings, which paths resolve to as globals.
+    globals: HashMap<String, LocalId>,
+    errors: Vec<Diagnostic>,
+}
+
+impl<'a> Builder<'a> {
+    fn new(items: &'a ItemTable, results: &'a TypeckResults) -> Self {
+        Builder {
+            items,
+            results,
+            nodes: vec![Node::default()],
+            current: Some(0),
+            loans: Vec::new(),
+            held: HashMap::new(),
+            names: HashMap::new(),
+            types: HashMap::new(),
+            scopes: Vec::new(),
+            loops: Vec::new(),
+            returns: Vec::new(),
+            returned: Vec::new(),
+            closures: Vec::new(),
+            mutable: HashSet::new(),
+            captured: HashSet::new(),
+            moved_captures: BTreeSet::new(),
+            globals: HashMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Graph construction
+    // ---------------------------------------------------------------------
+
+    fn node(&mut self, event: Option<Event>) -> usize {
+        let id = self.nodes.len();
+        self.nodes.push(Node {
+            event,
+            succs: Vec::new(),
+        });
+        if let Some(prev) = self.current {
+            self.nodes[prev].succs.push(id);
+        }
+        self.current = Some(id);
+        id
+    }
+
+    fn access(&mut self, place: Place, action: Action, span: Span) -> usize {
+        self.node(Some(Event {
+            place,
+            action,
+            span,
+        }))
+    }
+
+    /// Continues from all of `ends` that are reachable.
+    fn join(&mut self, ends: &[Option<usize>]) {
+        let reachable: Vec<usize> = ends.iter().flatten().copied().collect();
+        self.current = None;
+        if reachable.is_empty() {
+            return;
+        }
+        let node = self.node(None);
+        for end in reachable {
+            self.nodes[end].succs.push(node);
+        }
+    }
+
+    fn declare(&mut self, id: LocalId, name: &str, ty: Ty, loans: &[LoanId], span: Span) {
+        if self.holds_refs(&ty, 0) && !loans.is_empty() {
+            self.held.entry(id).or_default().extend(loans);
+        }
+        self.names.insert(id, name.to_string());
+        self.types.insert(id, ty);
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.push(id);
+        }
+        self.access(Place::local(id), Action::Write, span);
+    }
+
+    /// Ends the innermost scope at `span`, the closing brace.
+    fn end_scope(&mut self, span: Span) {
+        let locals = self.scopes.pop().unwrap_or_default();
+        if self.current.is_none() {
+            return;
+        }
+        for local in locals.into_iter().rev() {
+            self.access(Place::local(local), Action::Dead, span);
+        }
+    }
+
+    /// Ends the temporary lifetime of loans created since `first`, except
+    /// those in `keep`, which live on in the value being produced.
+    fn end_statement(&mut self, first: LoanId, keep: &[LoanId]) {
+        let end = self.nodes.len() - 1;
+        for (id, loan) in self.loans.iter_mut().enumerate().skip(first) {
+            if loan.stmt_end == usize::MAX && !keep.contains(&id) {
+                loan.stmt_end = end;
+            }
+        }
+    }
+
+    /// Joins every `return` with the end of the body, then ends the
+    /// parameters' scope at the end of `span`.
+    fn exit(&mut self, span: Span) {
+        self.end_statement(0, &[]);
+        let mut ends = vec![self.current];
+        ends.extend(self.returns.iter().map(|&n| Some(n)));
+        self.join(&ends);
+        self.end_scope(Span::new(span.end.saturating_sub(1), span.end));
+    }
+
+    fn block(&mut self, block: &'a Block, mode: Mode) -> Vec<LoanId> {
+        self.scopes.push(Vec::new());
+        let tail = block.tail().map(|expr| expr.id);
+        let mut value = Vec::new();
+        for stmt in &block.stmts {
+            let first = self.loans.len();
+            match &stmt.kind {
+                StmtKind::Expr(expr) if Some(expr.id) == tail => {
+                    value = self.expr(expr, mode);
+                    self.end_statement(first, &value);
+                }
+                _ => {
+                    self.stmt(stmt);
+                    self.end_statement(first, &[]);
+                }
+            }
+        }
+        self.end_scope(Span::new(block.span.end.saturating_sub(1), block.span.end));
+        value
+    }
+
+    fn stmt(&mut self, stmt: &'a Stmt) {
+        match &stmt.kind {
+            StmtKind::Let { binding, init } => {
+                let loans = self.expr(init, Mode::Consume);
+                self.declare_binding(binding, &loans);
+            }
+            StmtKind::Destructure { bindings, init } => {
+                let loans = self.expr(init, Mode::Consume);
+                for binding in bindings {
+                    self.declare_binding(binding, &loans);
+                }
+            }
+            StmtKind::Assign { target, op, value } => {
+                let loans = self.expr(value, Mode::Consume);
+                self.assign(target, *op != AssignOp::Set, &loans);
+            }
+            StmtKind::Step { target, .. } => self.assign(target, true, &[]),
+            StmtKind::Expr(expr) => {
+                self.expr(expr, Mode::Read);
+            }
+        }
+    }
+
+    fn declare_binding(&mut self, binding: &Binding, loans: &[LoanId]) {
+        let ty = self
+            .results
+            .binding_types
+            .get(&binding.id)
+            .cloned()
+            .unwrap_or(Ty::Error);
+        if binding.mutable {
+            self.mutable.insert(binding.id);
+        }
+        self.declare(binding.id, &binding.name.name, ty, loans, binding.name.span);
+    }
+
+    /// `target = value`; a compound assignment reads the target first.
+    fn assign(&mut self, target: &'a Expr, reads: bool, loans: &[LoanId]) {
+        match self.place(target) {
+            Some((place, false)) => {
+                if reads {
+                    self.access(place.clone(), Action::Read, target.span);
+                }
+                if place.fields.is_empty() {
+                    let ty = self.types.get(&place.local).cloned().unwrap_or(Ty::Error);
+                    if self.holds_refs(&ty, 0) {
+                        self.held.entry(place.local).or_default().extend(loans);
+                    }
+                }
+                self.access(place, Action::Write, target.span);
+            }
+            // Writing through a reference only uses the reference.
+            Some((place, true)) => {
+                self.access(place, Action::Read, target.span);
+            }
+            None => {
+                self.expr(target, Mode::Read);
+            }
+        }
+    }
+
+    /// Lowers `expr`, returning the loans its value may hold references
+    /// from.
+    fn expr(&mut self, expr: &'a Expr, mode: Mode) -> Vec<LoanId> {
+        match &expr.kind {
+            ExprKind::Path(_) | ExprKind::Field { .. } | ExprKind::Deref(_) => {
+                match self.place(expr) {
+                    Some((place, indirect)) => {
+                        let ty = self.results.type_of(expr.id).cloned().unwrap_or(Ty::Error);
+                        self.use_place(place, indirect, &ty, mode, expr.span)
+                    }
+                    None => {
+                        if let ExprKind::Field { base: inner, .. } | ExprKind::Deref(inner) =
+                            &expr.kind
+                        {
+                            self.expr(inner, Mode::Read);
+                        }
+                        Vec::new()
+                    }
+                }
+            }
+            ExprKind::Literal(_) => Vec::new(),
+            ExprKind::Asm(block) => {
+                for operand in &block.operands {
+                    if operand.dir == AsmDir::In {
+                        self.expr(&operand.expr, Mode::Read);
+                    }
+                }
+                for operand in &block.operands {
+                    if operand.dir.writes() {
+                        self.assign(&operand.expr, operand.dir.reads(), &[]);
+                    }
+                }
+                Vec::new()
+            }
+            ExprKind::Tuple(elems) => elems
+                .iter()
+                .flat_map(|elem| self.expr(elem, Mode::Consume))
+                .collect(),
+            ExprKind::Unary { expr: inner, .. } => {
+                self.expr(inner, Mode::Read);
+                Vec::new()
+            }
+            ExprKind::Binary { lhs, rhs, .. } => {
+                self.expr(lhs, Mode::Read);
+                self.expr(rhs, Mode::Read);
+                Vec::new()
+            }
+            ExprKind::Range { start, end } => {
+                self.expr(start, Mode::Read);
+                self.expr(end, Mode::Read);
+                Vec::new()
+            }
+            ExprKind::Call { callee, args, .. } => self.call(expr, callee, args),
+            ExprKind::RecordLit { fields, .. } => fields
+                .iter()
+                .flat_map(|field| self.expr(&field.value, Mode::Consume))
+                .collect(),
+            ExprKind::Block(block) | ExprKind::Unsafe(block) => self.block(block, mode),
+            ExprKind::If {
+                cond,
+                then_block,
+                else_branch,
+            } => {
+                self.expr(cond, Mode::Read);
+                let branch = self.current;
+                let mut value = self.block(then_block, mode);
+                let then_end = self.current;
+                self.current = branch;
+                if let Some(else_branch) = else_branch {
+                    value.extend(self.expr(else_branch, mode));
+                }
+                let else_end = self.current;
+                self.join(&[then_end, else_end]);
+                value
+            }
+            ExprKind::While { cond, body } => {
+                let head = self.node(None);
+                self.expr(cond, Mode::Read);
+                let exit = self.current;
+                self.loop_body(head, body, None);
+                let target = self.loops.pop().unwrap_or_default();
+                let mut ends = vec![exit];
+                ends.extend(target.breaks.into_iter().map(Some));
+                self.join(&ends);
+                Vec::new()
+            }
+            ExprKind::For {
+                binding,
+                binding_id,
+                iter,
+                body,
+            } => {
+                self.expr(iter, Mode::Read);
+                let head = self.node(None);
+                self.loop_body(head, body, Some((*binding_id, binding)));
+                let target = self.loops.pop().unwrap_or_default();
+                let mut ends = vec![Some(head)];
+                ends.extend(target.breaks.into_iter().map(Some));
+                self.join(&ends);
+                Vec::new()
+            }
+            ExprKind::Loop(body) => {
+                let head = self.node(None);
+                self.loop_body(head, body, None);
+                let target = self.loops.pop().unwrap_or_default();
+                let ends: Vec<Option<usize>> = target.breaks.into_iter().map(Some).collect();
+                self.join(&ends);
+                target.values
+            }
+            ExprKind::Match { scrutinee, arms } => {
+                let moves = arms.iter().any(|arm| self.pattern_moves(&arm.pattern));
+                let mode_in = if moves { Mode::Consume } else { Mode::Read };
+                let loans = self.expr(scrutinee, mode_in);
+                let branch = self.current;
+                let mut ends = Vec::new();
+                let mut value = Vec::new();
+                for arm in arms {
+                    self.current = branch;
+                    self.scopes.push(Vec::new());
+                    self.pattern(&arm.pattern, &loans);
+                    value.extend(self.expr(&arm.body, mode));
+                    let end = arm.span.end;
+                    self.end_scope(Span::new(end.saturating_sub(1), end));
+                    ends.push(self.current);
+                }
+                self.join(&ends);
+                value
+            }
+            ExprKind::Break(value) => {
+                let loans = match value {
+                    Some(value) => self.expr(value, Mode::Consume),
+                    None => Vec::new(),
+                };
+                let current = self.current.take();
+                if let Some(target) = self.loops.last_mut() {
+                    target.breaks.extend(current);
+                    target.values.extend(loans);
+                }
+                Vec::new()
+            }
+            ExprKind::Continue => {
+                if let (Some(current), Some(target)) = (self.current.take(), self.loops.last()) {
+                    self.nodes[current].succs.push(target.head);
+                }
+                Vec::new()
+            }
+            ExprKind::Return(value) => {
+                let loans = match value {
+                    Some(value) => self.expr(value, Mode::Consume),
+                    None => Vec::new(),
+                };
+                self.returned.push((loans, expr.span));
+                if let Some(current) = self.current.take() {
+                    self.returns.push(current);
+                }
+                Vec::new()
+            }
+            ExprKind::Ref {
+                mutable,
+                expr: inner,
+            } => self.borrow(inner, *mutable, expr.span),
+            // Raw references aren't tracked; using them is `unsafe`.
+            ExprKind::RawRef { expr: inner, .. } => {
+                if let Some((place, _)) = self.place(inner) {
+                    self.access(place, Action::Read, inner.span);
+                } else {
+                    self.expr(inner, Mode::Read);
+                }
+                Vec::new()
+            }
+            ExprKind::Try(inner) => self.expr(inner, Mode::Consume),
+            ExprKind::Closure(closure) => {
+                let mut captured: Vec<(LocalId, Span)> = Vec::new();
+                visit_exprs(&closure.body, &mut |e| {
+                    if let ExprKind::Path(segments) = &e.kind
+                        && let Some(Res::Local(id)) = self.results.resolutions.get(e.id)
+                        && self.types.contains_key(id)
+                        && !captured.iter().any(|(local, _)| local == id)
+                    {
+                        captured.push((*id, segments[0].span));
+                    }
+                });
+                let body = self.closure_body(closure, &captured);
+                let mut loans = Vec::new();
+                for (local, span) in captured {
+                    let place = Place::local(local);
+                    if body.moved_captures.contains(&local) {
+                        self.access(place, Action::Move, span);
+                    } else {
+                        let mutable = self.mutable.contains(&local);
+                        loans.extend(self.borrow_place(place, mutable, span));
+                    }
+                }
+                self.closures.push(body);
+                loans
+            }
+        }
+    }
+
+    /// Lowers a loop body starting at `head`, leaving its `LoopTarget` on
+    /// the stack for the caller to collect the `break`s from.
+    fn loop_body(&mut self, head: usize, body: &'a Block, binding: Option<(LocalId, &Ident)>) {
+        self.loops.push(LoopTarget {
+            head,
+            ..LoopTarget::default()
+        });
+        self.scopes.push(Vec::new());
+        if let Some((id, name)) = binding {
+            let ty = self
+                .results
+                .binding_types
+                .get(&id)
+                .cloned()
+                .unwrap_or(Ty::Error);
+            self.declare(id, &name.name, ty, &[], name.span);
+        }
+        let first = self.loans.len();
+        self.block(body, Mode::Read);
+        self.end_statement(first, &[]);
+        self.end_scope(Span::new(body.span.end.saturating_sub(1), body.span.end));
+        if let Some(end) = self.current.take() {
+            self.nodes[end].succs.push(head);
+        }
+    }
+
+    fn pattern(&mut self, pattern: &Pattern, loans: &[LoanId]) {
+        match &pattern.kind {
+            PatternKind::Binding(name) => {
+                if let Some(ty) = self.results.binding_types.get(&pattern.id) {
+                    self.declare(pattern.id, &name.name, ty.clone(), loans, name.span);
+                }
+            }
+            PatternKind::Variant { fields, .. } | PatternKind::Tuple(fields) => {
+                for field in fields {
+                    self.pattern(field, loans);
+                }
+            }
+            PatternKind::Wildcard | PatternKind::Literal(_) => {}
+        }
+    }
+
+    /// Whether matching `pattern` moves out of the scrutinee, which happens
+    /// when it binds a value that isn't copied.
+    fn pattern_moves(&self, pattern: &Pattern) -> bool {
+        match &pattern.kind {
+            PatternKind::Binding(_) => self
+                .results
+                .binding_types
+                .get(&pattern.id)
+                .is_some_and(|ty| !is_copy(ty)),
+            PatternKind::Variant { fields, .. } | PatternKind::Tuple(fields) => {
+                fields.iter().any(|field| self.pattern_moves(field))
+            }
+            PatternKind::Wildcard | PatternKind::Literal(_) => false,
+        }
+    }
+
+    fn call(&mut self, expr: &'a Expr, callee: &'a Expr, args: &'a [Arg]) -> Vec<LoanId> {
+        // The receiver of a method call: `h::older()` or `make()::older()`.
+        let receiver = match &callee.kind {
+            ExprKind::Path(segments) if segments.len() > 1 => self
+                .local(callee.id)
+                .map(|local| Ok(self.project(local, &segments[..segments.len() - 1]))),
+            ExprKind::Field { base, .. } => Some(Err(&**base)),
+            _ => None,
+        };
+        if receiver.is_none() {
+            self.expr(callee, Mode::Read);
+        }
+        let mut loans = Vec::new();
+        for arg in args {
+            loans.extend(self.expr(&arg.value, Mode::Arg));
+        }
+        // The receiver is borrowed after the arguments are evaluated, so
+        // `v::push(v::len())` is fine.
+        if let Some(receiver) = receiver {
+            let kind = self.self_kind(expr);
+            loans.extend(match (receiver, kind) {
+                (Ok((place, indirect)), kind) => {
+                    let ty = self.place_ty(&place);
+                    let indirect = indirect || matches!(ty, Ty::Ref { .. } | Ty::RawRef { .. });
+                    match kind {
+                        Some(SelfKind::Ref | SelfKind::RefMut) if !indirect => {
+                            self.borrow_place(place, kind == Some(SelfKind::RefMut), callee.span)
+                        }
+                        Some(SelfKind::Value | SelfKind::MutValue) => {
+                            self.use_place(place, indirect, &ty, Mode::Consume, callee.span)
+                        }
+                        _ => self.use_place(place, indirect, &ty, Mode::Read, callee.span),
+                    }
+                }
+                (Err(base), Some(SelfKind::Ref | SelfKind::RefMut)) => {
+                    self.borrow(base, kind == Some(SelfKind::RefMut), callee.span)
+                }
+                (Err(base), Some(SelfKind::Value | SelfKind::MutValue)) => {
+                    self.expr(base, Mode::Consume)
+                }
+                (Err(base), None) => self.expr(base, Mode::Read),
+            });
+        }
+        let ret = self.results.type_of(expr.id).cloned().unwrap_or(Ty::Error);
+        if self.holds_refs(&ret, 0) {
+            loans
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn self_kind(&self, call: &Expr) -> Option<SelfKind> {
+        let callee = self.results.callees.get(&call.id)?;
+        let sig = match &callee.func.owner {
+            FnOwner::Free => return None,
+            FnOwner::Type(ty) => self.items.method(ty, &callee.func.name)?,
+            FnOwner::Protocol(protocol) => self
+                .items
+                .protocols
+                .get(protocol)?
+                .iter()
+                .find(|sig| sig.name == callee.func.name)?,
+        };
+        sig.self_kind
+    }
+
+    fn borrow(&mut self, inner: &'a Expr, mutable: bool, span: Span) -> Vec<LoanId> {
+        match self.place(inner) {
+            Some((place, false)) => self.borrow_place(place, mutable, span),
+            // Reborrowing through a reference uses the reference.
+            Some((place, true)) => {
+                let loans = self.held_by(&place);
+                self.access(place, Action::Read, span);
+                loans
+            }
+            None => self.expr(inner, Mode::Read),
+        }
+    }
+
+    fn borrow_place(&mut self, place: Place, mutable: bool, span: Span) -> Vec<LoanId> {
+        let loan = self.loans.len();
+        self.loans.push(Loan {
+            place: place.clone(),
+            mutable,
+            span,
+            created: self.nodes.len(),
+            stmt_end: usize::MAX,
+            holders: BTreeSet::new(),
+        });
+        self.access(place, Action::Borrow { mutable, loan }, span);
+        vec![loan]
+    }
+
+    fn use_place(
+        &mut self,
+        place: Place,
+        indirect: bool,
+        ty: &Ty,
+        mode: Mode,
+        span: Span,
+    ) -> Vec<LoanId> {
+        let moves = match mode {
+            Mode::Read => false,
+            Mode::Consume => !is_copy(ty),
+            Mode::Arg => !is_copy(ty) && !matches!(ty, Ty::Ref { .. }),
+        };
+        let loans = if self.holds_refs(ty, 0) {
+            self.held_by(&place)
+        } else {
+            Vec::new()
+        };
+        if moves && indirect {
+            let name = self.describe(&place);
+            let behind = if place.fields.is_empty() && self.captured.contains(&place.local) {
+                self.moved_captures.insert(place.local);
+                "captured by reference"
+            } else {
+                "behind a reference"
+            };
+            self.errors.push(
+                Diagnostic::error(
+                    format!("cannot move out of `{}`, which is {}", name, behind),
+                    span,
+                )
+                .with_note(format!("`{}` has type `{}`, which is not copied", name, ty)),
+            );
+            self.access(place, Action::Read, span);
+        } else {
+            let action = if moves { Action::Move } else { Action::Read };
+            self.access(place, action, span);
+        }
+        loans
+    }
+
+    fn held_by(&self, place: &Place) -> Vec<LoanId> {
+        self.held
+            .get(&place.local)
+            .map(|loans| loans.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    // ---------------------------------------------------------------------
+    // Places and types
+    // ---------------------------------------------------------------------
+
+    /// The place `expr` names and whether it is reached through a
+    /// reference, or `None` for expressions that aren't places.
+    fn place(&mut self, expr: &Expr) -> Option<(Place, bool)> {
+        match &expr.kind {
+            ExprKind::Path(segments) => {
+                let local = self.local(expr.id)?;
+                if self.captured.contains(&local) {
+                    self.project(local, &segments[..1]);
+                    return Some((Place::local(local), true));
+                }
+                Some(self.project(local, segments))
+            }
+            ExprKind::Field { base, name } => {
+                let (mut place, indirect) = self.place(base)?;
+                if indirect || matches!(self.place_ty(&place), Ty::Ref { .. } | Ty::RawRef { .. }) {
+                    return Some((place, true));
+                }
+                place.fields.push(name.name.clone());
+                Some((place, false))
+            }
+            ExprKind::Deref(inner) => {
+                let (place, _) = self.place(inner)?;
+                Some((place, true))
+            }
+            _ => None,
+        }
+    }
+
+    /// The local the path expression `id` starts with.
+    fn local(&self, id: NodeId) -> Option<LocalId> {
+        match self.results.resolutions.get(id)? {
+            Res::Local(id) => Some(*id),
+            Res::SelfValue => Some(SELF_LOCAL),
+            Res::Global(name) => self.globals.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    /// The place named by a path rooted at `local`; the first segment is
+    /// the local itself.
+    fn project(&mut self, local: LocalId, segments: &[Ident]) -> (Place, bool) {
+        self.names
+            .entry(local)
+            .or_insert_with(|| segments[0].name.clone());
+        let mut place = Place::local(local);
+        let mut ty = self.local_ty(local);
+        for segment in &segments[1..] {
+            if matches!(ty, Ty::Ref { .. } | Ty::RawRef { .. }) {
+                return (place, true);
+            }
+            ty = self.field_ty(&ty, &segment.name);
+            place.fields.push(segment.name.clone());
+        }
+        (place, false)
+    }
+
+    fn local_ty(&self, local: LocalId) -> Ty {
+        self.types
+            .get(&local)
+            .or_else(|| self.results.binding_types.get(&local))
+            .cloned()
+            .unwrap_or(Ty::Error)
+    }
+
+    fn place_ty(&self, place: &Place) -> Ty {
+        let mut ty = self.local_ty(place.local);
+        for field in &place.fields {
+            ty = self.field_ty(&ty, field);
+        }
+        ty
+    }
+
+    fn field_ty(&self, ty: &Ty, field: &str) -> Ty {
+        match ty {
+            Ty::Adt { name, args } => self
+                .items
+                .record_fields(name, args)
+                .and_then(|fields| fields.into_iter().find(|(n, _)| n == field))
+                .map_or(Ty::Error, |(_, ty)| ty),
+            Ty::Tuple(elems) => field
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| elems.get(i).cloned())
+                .unwrap_or(Ty::Error),
+            _ => Ty::Error,
+        }
+    }
+
+    /// Whether a value of `ty` can contain a reference, so storing it keeps
+    /// borrows alive.
+    fn holds_refs(&self, ty: &Ty, depth: usize) -> bool {
+        match ty {
+            // A function value may be a closure holding references.
+            Ty::Ref { .. } | Ty::Fn { .. } | Ty::Param(_) | Ty::Error => true,
+            Ty::Tuple(elems) => elems.iter().any(|t| self.holds_refs(t, depth)),
+            Ty::Adt { name, args } => {
+                args.iter().any(|t| self.holds_refs(t, depth))
+                    || (depth < 4
+                        && self.items.record_fields(name, args).is_some_and(|fields| {
+                            fields.iter().any(|(_, t)| self.holds_refs(t, depth + 1))
+                        }))
+            }
+            _ => false,
+        }
+    }
+
+    fn describe(&self, place: &Place) -> String {
+        let mut name = match place.local {
+            SELF_LOCAL => "self".to_string(),
+            local => self.names.get(&local).cloned().unwrap_or_default(),
+        };
+        for field in &place.fields {
+            name.push_str("::");
+            name.push_str(field);
+        }
+        name
+    }
+
+    // ---------------------------------------------------------------------
+    // Analysis
+    // ---------------------------------------------------------------------
+
+    fn finish(mut self, handler: &mut ErrorHandler) {
+        // Loans escaping through the return value, which are reported once
+        // here rather than again where their place goes out of scope.
+        let mut escaped = HashSet::new();
+        for (loans, span) in std::mem::take(&mut self.returned) {
+            for loan in loans {
+                if !escaped.insert(loan) {
+                    continue;
+                }
+                let loan = &self.loans[loan];
+                let name = self.describe(&Place::local(loan.place.local));
+                self.errors.push(
+                    Diagnostic::error(
+                        format!("cannot return a reference to local `{}`", name),
+                        span,
+                    )
+                    .with_label(loan.span, format!("`{}` is borrowed here", name))
+                    .with_note(format!(
+                        "`{}` goes out of scope when the function returns",
+                        name
+                    )),
+                );
+            }
+        }
+        let live_out = self.liveness();
+        self.check_moves();
+        self.check_loans(&live_out, escaped);
+        let mut errors = std::mem::take(&mut self.errors);
+        errors.sort_by_key(|error| error.span.start);
+        let mut seen = HashSet::new();
+        for error in errors {
+            if seen.insert((error.span, error.message.clone())) {
+                handler.emit(error);
+            }
+        }
+        for closure in std::mem::take(&mut self.closures) {
+            closure.finish(handler);
+        }
+    }
+
+    /// Builds the graph of a closure's body, which reaches `captured`
+    /// through the references the closure holds.
+    fn closure_body(&self, closure: &'a Closure, captured: &[(LocalId, Span)]) -> Builder<'a> {
+        let mut builder = Builder::new(self.items, self.results);
+        builder.captured = captured.iter().map(|&(local, _)| local).collect();
+        builder.scopes.push(Vec::new());
+        for param in &closure.params {
+            let ty = self.local_ty(param.id);
+            if param.mutable {
+                builder.mutable.insert(param.id);
+            }
+            builder.declare(param.id, &param.name.name, ty, &[], param.name.span);
+        }
+        let value = builder.expr(&closure.body, Mode::Consume);
+        builder.returned.push((value, closure.body.span));
+        builder.exit(closure.body.span);
+        builder
+    }
+
+    fn preds(&self) -> Vec<Vec<usize>> {
+        let mut preds = vec![Vec::new(); self.nodes.len()];
+        for (n, node) in self.nodes.iter().enumerate() {
+            for &succ in &node.succs {
+                preds[succ].push(n);
+            }
+        }
+        preds
+    }
+
+    /// The locals each node's successors may still use, by backward
+    /// dataflow. Assigning a whole local or ending its scope kills it.
+    fn liveness(&self) -> Vec<HashSet<LocalId>> {
+        let count = self.nodes.len();
+        let mut live_in: Vec<HashSet<LocalId>> = vec![HashSet::new(); count];
+        let mut live_out: Vec<HashSet<LocalId>> = vec![HashSet::new(); count];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for n in (0..count).rev() {
+                let out: HashSet<LocalId> = self.nodes[n]
+                    .succs
+                    .iter()
+                    .flat_map(|&s| live_in[s].iter().copied())
+                    .collect();
+                let mut inn = out.clone();
+                if let Some(event) = &self.nodes[n].event {
+                    match event.action {
+                        Action::Dead => {
+                            inn.remove(&event.place.local);
+                        }
+                        Action::Write if event.place.fields.is_empty() => {
+                            inn.remove(&event.place.local);
+                        }
+                        _ => {
+                            inn.insert(event.place.local);
+                        }
+                    }
+                }
+                if inn != live_in[n] || out != live_out[n] {
+                    live_in[n] = inn;
+                    live_out[n] = out;
+                    changed = true;
+                }
+            }
+        }
+        live_out
+    }
+
+    /// Reports uses of places that may have been moved out of.
+    fn check_moves(&mut self) {
+        let count = self.nodes.len();
+        let preds = self.preds();
+        // Moved places and the node that moved each.
+        let mut moved_in: Vec<BTreeMap<Place, usize>> = vec![BTreeMap::new(); count];
+        let mut moved_out: Vec<BTreeMap<Place, usize>> = vec![BTreeMap::new(); count];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for n in 0..count {
+                let mut state = BTreeMap::new();
+                for &p in &preds[n] {
+                    for (place, &node) in &moved_out[p] {
+                        state.entry(place.clone()).or_insert(node);
+                    }
+                }
+                let mut out = state.clone();
+                if let Some(event) = &self.nodes[n].event {
+                    match event.action {
+                        Action::Move => {
+                            out.entry(event.place.clone()).or_insert(n);
+                        }
+                        Action::Write => out.retain(|place, _| !event.place.contains(place)),
+                        Action::Dead => out.retain(|place, _| place.local != event.place.local),
+                        _ => {}
+                    }
+                }
+                if state != moved_in[n] || out != moved_out[n] {
+                    moved_in[n] = state;
+                    moved_out[n] = out;
+                    changed = true;
+                }
+            }
+        }
+        let mut reported = HashSet::new();
+        for (n, moved) in moved_in.iter().enumerate() {
+            let Some(event) = &self.nodes[n].event else {
+                continue;
+            };
+            for (place, &node) in moved {
+                if !place.overlaps(&event.place) {
+                    continue;
+                }
+                let what = match event.action {
+                    Action::Dead => continue,
+                    Action::Write if event.place.contains(place) => continue,
+                    Action::Write => "assign to part of moved value",
+                    Action::Borrow { .. } => "borrow of moved value",
+                    _ if event.place.contains(place) && event.place != *place => {
+                        "use of partially moved value"
+                    }
+                    _ => "use of moved value",
+                };
+                if !reported.insert(node) {
+                    continue;
+                }
+                let move_span = self.nodes[node]
+                    .event
+                    .as_ref()
+                    .map_or(event.span, |e| e.span);
+                let label = if node >= n {
+                    "value moved here, in previous iteration of loop"
+                } else {
+                    "value moved here"
+                };
+                let name = self.describe(place);
+                let ty = self.place_ty(place);
+                self.errors.push(
+                    Diagnostic::error(
+                        format!("{}: `{}`", what, self.describe(&event.place)),
+                        event.span,
+                    )
+                    .with_label(move_span, label)
+                    .with_note(format!(
+                        "move occurs because `{}` has type `{}`, which is not copied",
+                        name, ty
+                    )),
+                );
+            }
+        }
+    }
+
+    /// Reports accesses that conflict with a borrow that is still alive.
+    fn check_loans(&mut self, live_out: &[HashSet<LocalId>], mut reported: HashSet<LoanId>) {
+        for loan in 0..self.loans.len() {
+            for (local, held) in &self.held {
+                if held.contains(&loan) {
+                    self.loans[loan].holders.insert(*local);
+                }
+            }
+        }
+        let count = self.nodes.len();
+        let preds = self.preds();
+        let alive = |loan: &Loan, n: usize| {
+            (loan.created <= n && n < loan.stmt_end)
+                || loan.holders.iter().any(|h| live_out[n].contains(h))
+        };
+        let mut loans_in: Vec<BTreeSet<LoanId>> = vec![BTreeSet::new(); count];
+        let mut loans_out: Vec<BTreeSet<LoanId>> = vec![BTreeSet::new(); count];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for n in 0..count {
+                let state: BTreeSet<LoanId> = preds[n]
+                    .iter()
+                    .flat_map(|&p| loans_out[p].iter().copied())
+                    .collect();
+                let mut out = state.clone();
+                if let Some(Event {
+                    action: Action::Borrow { loan, .. },
+                    ..
+                }) = &self.nodes[n].event
+                {
+                    out.insert(*loan);
+                }
+                out.retain(|&loan| alive(&self.loans[loan], n));
+                if state != loans_in[n] || out != loans_out[n] {
+                    loans_in[n] = state;
+                    loans_out[n] = out;
+                    changed = true;
+                }
+            }
+        }
+        for (n, live) in loans_in.iter().enumerate() {
+            let Some(event) = &self.nodes[n].event else {
+                continue;
+            };
+            for &id in live {
+                let loan = &self.loans[id];
+                if !loan.place.overlaps(&event.place) || reported.contains(&id) {
+                    continue;
+                }
+                if let Some(error) = self.conflict(loan, event, n, live_out) {
+                    reported.insert(id);
+                    self.errors.push(error);
+                }
+            }
+        }
+    }
+
+    fn conflict(
+        &self,
+        loan: &Loan,
+        event: &Event,
+        n: usize,
+        live_out: &[HashSet<LocalId>],
+    ) -> Option<Diagnostic> {
+        let name = self.describe(&event.place);
+        let (message, borrow_label) = match (event.action, loan.mutable) {
+            (Action::Read, false) => return None,
+            (Action::Read, true) => (
+                format!("cannot use `{}` because it was mutably borrowed", name),
+                "mutable borrow occurs here",
+            ),
+            (Action::Move, _) => (
+                format!("cannot move out of `{}` because it is borrowed", name),
+                "borrow occurs here",
+            ),
+            (Action::Write, _) => (
+                format!("cannot assign to `{}` because it is borrowed", name),
+                "borrow occurs here",
+            ),
+            (Action::Borrow { mutable: false, .. }, false) => return None,
+            (Action::Borrow { mutable: false, .. }, true) => (
+                format!(
+                    "cannot borrow `{}` as immutable because it is also borrowed as mutable",
+                    name
+                ),
+                "mutable borrow occurs here",
+            ),
+            (Action::Borrow { mutable: true, .. }, true) => (
+                format!(
+                    "cannot borrow `{}` as mutable more than once at a time",
+                    name
+                ),
+                "first mutable borrow occurs here",
+            ),
+            (Action::Borrow { mutable: true, .. }, false) => (
+                format!(
+                    "cannot borrow `{}` as mutable because it is also borrowed as immutable",
+                    name
+                ),
+                "immutable borrow occurs here",
+            ),
+            (Action::Dead, _) => {
+                let mut error =
+                    Diagnostic::error(format!("`{}` does not live long enough", name), loan.span)
+                        .with_label(
+                            event.span,
+                            format!("`{}` goes out of scope here while still borrowed", name),
+                        );
+                if let Some(span) = self.later_use(loan, n, live_out) {
+                    error = error.with_label(span, "borrow later used here");
+                }
+                return Some(error);
+            }
+        };
+        let mut error = Diagnostic::error(message, event.span).with_label(loan.span, borrow_label);
+        if let Some(span) = self.later_use(loan, n, live_out) {
+            error = error.with_label(span, "borrow later used here");
+        }
+        Some(error)
+    }
+
+    /// The first use after `n` of a binding holding `loan`, which is what
+    /// keeps the borrow alive.
+    fn later_use(&self, loan: &Loan, n: usize, live_out: &[HashSet<LocalId>]) -> Option<Span> {
+        if !loan.holders.iter().any(|h| live_out[n].contains(h)) {
+            return None;
+        }
+        let mut seen = HashSet::new();
+        let mut queue: VecDeque<usize> = self.nodes[n].succs.iter().copied().collect();
+        while let Some(next) = queue.pop_front() {
+            if !seen.insert(next) {
+                continue;
+            }
+            if let Some(event) = &self.nodes[next].event
+                && loan.holders.contains(&event.place.local)
+            {
+                let redefines = event.action == Action::Dead
+                    || (event.action == Action::Write && event.place.fields.is_empty());
+                if !redefines {
+                    return Some(event.span);
+                }
+                continue;
+            }
+            queue.extend(self.nodes[next].succs.iter().copied());
+        }
+        None
+    }
+}
+
+/// Whether values of `ty` are copied rather than moved.
+fn is_copy(ty: &Ty) -> bool {
+    match ty {
+        Ty::Adt { .. } | Ty::Param(_) | Ty::Dyn(_) => false,
+        Ty::Ref { mutable, .. } => !mutable,
+        Ty::Tuple(elems) => elems.iter().all(is_copy),
+        _ => true,
+    }
+}
+
+/// Calls `f` on `expr` and every expression nested in it.
+fn visit_exprs<'e>(expr: &'e Expr, f: &mut dyn FnMut(&'e Expr)) {
+    f(expr);
+    let block = |block: &'e Block, f: &mut dyn FnMut(&'e Expr)| {
+        for stmt in &block.stmts {
+            match &stmt.kind {
+                StmtKind::Let { init, .. } | StmtKind::Destructure { init, .. } => {
+                    visit_exprs(init, f)
+                }
+                StmtKind::Assign { target, value, .. } => {
+                    visit_exprs(target, f);
+                    visit_exprs(value, f);
+                }
+                StmtKind::Step { target, .. } => visit_exprs(target, f),
+                StmtKind::Expr(expr) => visit_exprs(expr, f),
+            }
+        }
+    };
+    match &expr.kind {
+        ExprKind::Literal(_) | ExprKind::Path(_) | ExprKind::Continue => {}
+        ExprKind::Asm(block) => block.operands.iter().for_each(|o| visit_exprs(&o.expr, f)),
+        ExprKind::Tuple(elems) => elems.iter().for_each(|e| visit_exprs(e, f)),
+        ExprKind::Unary { expr: inner, .. }
+        | ExprKind::Field { base: inner, .. }
+        | ExprKind::Ref { expr: inner, .. }
+        | ExprKind::Deref(inner)
+        | ExprKind::RawRef { expr: inner, .. }
+        | ExprKind::Try(inner) => visit_exprs(inner, f),
+        ExprKind::Binary { lhs, rhs, .. } => {
+            visit_exprs(lhs, f);
+            visit_exprs(rhs, f);
+        }
+        ExprKind::Range { start, end } => {
+            visit_exprs(start, f);
+            visit_exprs(end, f);
+        }
+        ExprKind::Call { callee, args, .. } => {
+            visit_exprs(callee, f);
+            args.iter().for_each(|arg| visit_exprs(&arg.value, f));
+        }
+        ExprKind::RecordLit { fields, .. } => {
+            fields.iter().for_each(|field| visit_exprs(&field.value, f))
+        }
+        ExprKind::Block(b) | ExprKind::Unsafe(b) | ExprKind::Loop(b) => block(b, f),
+        ExprKind::If {
+            cond,
+            then_block,
+            else_branch,
+        } => {
+            visit_exprs(cond, f);
+            block(then_block, f);
+            if let Some(else_branch) = else_branch {
+                visit_exprs(else_branch, f);
+            }
+        }
+        ExprKind::While { cond, body } => {
+            visit_exprs(cond, f);
+            block(body, f);
+        }
+        ExprKind::For { iter, body, .. } => {
+            visit_exprs(iter, f);
+            block(body, f);
+        }
+        ExprKind::Match { scrutinee, arms } => {
+            visit_exprs(scrutinee, f);
+            arms.iter().for_each(|arm| visit_exprs(&arm.body, f));
+        }
+        ExprKind::Break(value) | ExprKind::Return(value) => {
+            if let Some(value) = value {
+                visit_exprs(value, f);
+            }
+        }
+        ExprKind::Closure(closure) => visit_exprs(&closure.body, f),
+    }
+}
@@ -0,0 +1,143 @@
+use super::types::Ty;
+
+/// Inference variables and their solutions. Types are checked
+/// bidirectionally: an expected type flows down into an expression, and
+/// wherever a type isn't known yet (generic arguments of a call, untyped
+/// closure parameters, `Option::None` with no annotation) a fresh variable
+/// stands in until unification pins it down.
+#[derive(Debug, Default)]
+pub struct InferCtxt {
+    vars: Vec<Option<Ty>>,
+}
+
+impl InferCtxt {
+    pub fn fresh(&mut self) -> Ty {
+        self.vars.push(None);
+        Ty::Infer(self.vars.len() as u32 - 1)
+    }
+
+    /// Follows solved variables at the top of `ty` only.
+    pub fn shallow(&self, ty: &Ty) -> Ty {
+        let mut ty = ty.clone();
+        while let Ty::Infer(var) = ty {
+            match &self.vars[var as usize] {
+                Some(solution) => ty = solution.clone(),
+                None => break,
+            }
+        }
+        ty
+    }
+
+    /// Replaces every solved variable in `ty`.
+    pub fn resolve(&self, ty: &Ty) -> Ty {
+        match self.shallow(ty) {
+            Ty::Tuple(elems) => Ty::Tuple(elems.iter().map(|t| self.resolve(t)).collect()),
+            Ty::Ref { mutable, inner } => Ty::Ref {
+                mutable,
+                inner: Box::new(self.resolve(&inner)),
+            },
+            Ty::RawRef { mutable, inner } => Ty::RawRef {
+                mutable,
+                inner: Box::new(self.resolve(&inner)),
+            },
+            Ty::Adt { name, args } => Ty::Adt {
+                name,
+                args: args.iter().map(|t| self.resolve(t)).collect(),
+            },
+            Ty::Fn { params, ret } => Ty::Fn {
+                params: params.iter().map(|t| self.resolve(t)).collect(),
+                ret: Box::new(self.resolve(&ret)),
+            },
+            ty => ty,
+        }
+    }
+
+    /// Makes a value of type `found` usable where `expected` is required,
+    /// solving variables on either side. On failure no variable is changed.
+    pub fn unify(&mut self, found: &Ty, expected: &Ty) -> bool {
+        let snapshot = self.vars.clone();
+        let ok = self.unify_inner(found, expected);
+        if !ok {
+            self.vars = snapshot;
+        }
+        ok
+    }
+
+    fn unify_inner(&mut self, found: &Ty, expected: &Ty) -> bool {
+        let (found, expected) = (self.shallow(found), self.shallow(expected));
+        match (&found, &expected) {
+            (Ty::Never, _) => true,
+            // Solving to `{unknown}` keeps one error from causing another
+            // at every later use of the variable.
+            (Ty::Error, Ty::Infer(var)) | (Ty::Infer(var), Ty::Error) => {
+                self.vars[*var as usize] = Some(Ty::Error);
+                true
+            }
+            (Ty::Error, _) | (_, Ty::Error) => true,
+            (Ty::Infer(a), Ty::Infer(b)) if a == b => true,
+            (Ty::Infer(var), other) | (other, Ty::Infer(var)) => {
+                if self.occurs(*var, other) {
+                    return false;
+                }
+                self.vars[*var as usize] = Some(other.clone());
+                true
+            }
+            (Ty::Tuple(a), Ty::Tuple(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(x, y)| self.unify_inner(x, y))
+            }
+            (
+                Ty::Ref {
+                    mutable: m1,
+                    inner: i1,
+                },
+                Ty::Ref {
+                    mutable: m2,
+                    inner: i2,
+                },
+            )
+            | (
+                Ty::RawRef {
+                    mutable: m1,
+                    inner: i1,
+                },
+                Ty::RawRef {
+                    mutable: m2,
+                    inner: i2,
+                },
+            ) => (*m1 || !*m2) && self.unify_inner(i1, i2),
+            (Ty::Adt { name: n1, args: a1 }, Ty::Adt { name: n2, args: a2 }) => {
+                n1 == n2
+                    && a1.len() == a2.len()
+                    && a1.iter().zip(a2).all(|(x, y)| self.unify_inner(x, y))
+            }
+            (
+                Ty::Fn {
+                    params: p1,
+                    ret: r1,
+                },
+                Ty::Fn {
+                    params: p2,
+                    ret: r2,
+                },
+            ) => {
+                p1.len() == p2.len()
+                    && p1.iter().zip(p2).all(|(x, y)| self.unify_inner(y, x))
+                    && self.unify_inner(r1, r2)
+            }
+            _ => found == expected,
+        }
+    }
+
+    fn occurs(&self, var: u32, ty: &Ty) -> bool {
+        match self.shallow(ty) {
+            Ty::Infer(other) => other == var,
+            Ty::Tuple(elems) => elems.iter().any(|t| self.occurs(var, t)),
+            Ty::Ref { inner, .. } | Ty::RawRef { inner, .. } => self.occurs(var, &inner),
+            Ty::Adt { args, .. } => args.iter().any(|t| self.occurs(var, t)),
+            Ty::Fn { params, ret } => {
+                params.iter().any(|t| self.occurs(var, t)) || self.occurs(var, &ret)
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,1400 @@
+use super::resolve::with_suggestion;
+use super::types::{PRIMITIVES, Ty};
+use crate::errorhandler::{Diagnostic, ErrorHandler};
+use crate::lexer::size::Span;
+use crate::parser::ast::*;
+use crate::prelude;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamSig {
+    pub label: Option<String>,
+    pub name: String,
+    pub ty: Ty,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FnSig {
+    pub name: String,
+    /// Defining module and whether other modules may call it.
+    pub module: String,
+    pub is_pub: bool,
+    /// Parameters introduced by the enclosing `implement`, e.g. `T` in
+    /// `implement Option[T]`; they are fixed by the receiver's type.
+    pub impl_generics: Vec<String>,
+    pub generics: Vec<String>,
+    /// Protocols each of `generics` must implement, e.g. `live` in `[T: live]`.
+    pub bounds: Vec<Vec<String>>,
+    /// The implementing type for methods, `None` for free functions.
+    pub owner: Option<String>,
+    /// Declared `unsafe @name`; calls need an `unsafe` block.
+    pub is_unsafe: bool,
+    /// An `extern` declaration: the body is in an object linked with the
+    /// program. Calls to it need an `unsafe` block.
+    pub is_extern: bool,
+    pub self_kind: Option<SelfKind>,
+    pub params: Vec<ParamSig>,
+    /// Takes any number of arguments after `params`, as C's `printf` does.
+    pub variadic: bool,
+    pub ret: Ty,
+    pub attrs: FnAttrs,
+}
+
+/// What a function's attributes ask of code generation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FnAttrs {
+    /// `#[interrupt]`: entered by the processor through the interrupt
+    /// descriptor table. It saves every register it could change and
+    /// returns with `iretq`.
+    pub interrupt: bool,
+    /// `#[naked]`: no prologue or epilogue, only the body's assembly.
+    pub naked: bool,
+    /// `#[no_mangle]`: the symbol is the function's own name.
+    pub no_mangle: bool,
+    /// `#[section(".boot")]`: the section the code goes in instead of
+    /// `.text`.
+    pub section: Option<String>,
+    /// `#[align(16)]`: the alignment of the first instruction, in bytes.
+    pub align: Option<u64>,
+    /// `extern "C"`: the symbol is the function's own name, and it takes
+    /// its arguments and returns its result as C functions do.
+    pub c_abi: bool,
+}
+
+impl std::fmt::Display for FnAttrs {
+    /// Each attribute as written, on a line of its own.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let flags = [
+            ("interrupt", self.interrupt),
+            ("naked", self.naked),
+            ("no_mangle", self.no_mangle),
+        ];
+        for (name, set) in flags {
+            if set {
+                writeln!(f, "#[{}]", name)?;
+            }
+        }
+        if let Some(section) = &self.section {
+            writeln!(f, "#[section({:?})]", section)?;
+        }
+        if let Some(align) = self.align {
+            writeln!(f, "#[align({})]", align)?;
+        }
+        if self.c_abi {
+            write!(f, "extern \"C\" ")?;
+        }
+        Ok(())
+    }
+}
+
+/// The attributes a function may have.
+pub const FN_ATTRIBUTES: [&str; 5] = ["interrupt", "naked", "no_mangle", "section", "align"];
+
+impl FnSig {
+    pub fn fn_ty(&self) -> Ty {
+        Ty::Fn {
+            params: self.params.iter().map(|p| p.ty.clone()).collect(),
+            ret: Box::new(self.ret.clone()),
+        }
+    }
+
+    /// The signature as written, e.g. `@eat(ref self, int)::string`.
+    pub fn describe(&self) -> String {
+        let params: Vec<String> = self
+            .self_kind
+            .map(|kind| kind.as_str().to_string())
+            .into_iter()
+            .chain(self.params.iter().map(|p| p.ty.to_string()))
+            .collect();
+        let variadic = match (self.variadic, params.is_empty()) {
+            (false, _) => "",
+            (true, true) => "...",
+            (true, false) => ", ...",
+        };
+        format!(
+            "@{}({}{})::{}",
+            self.name,
+            params.join(", "),
+            variadic,
+            self.ret
+        )
+    }
+
+    /// The signature with its `impl_generics` replaced by `args`, e.g. a
+    /// protocol method for particular protocol arguments.
+    pub fn with_impl_args(&self, args: &[Ty]) -> FnSig {
+        let params = self
+            .params
+            .iter()
+            .map(|p| ParamSig {
+                ty: p.ty.subst(&self.impl_generics, args),
+                ..p.clone()
+            })
+            .collect();
+        FnSig {
+            impl_generics: Vec::new(),
+            params,
+            ret: self.ret.subst(&self.impl_generics, args),
+            ..self.clone()
+        }
+    }
+
+    /// Whether an implementation with signature `self` fits the protocol
+    /// method `required`.
+    pub fn matches(&self, required: &FnSig) -> bool {
+        self.self_kind == required.self_kind
+            && self.generics.len() == required.generics.len()
+            && self.fn_ty() == required.fn_ty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordDef {
+    pub generics: Vec<String>,
+    pub fields: Vec<(String, Ty)>,
+    /// The width of each field declared as a bitfield, by field index.
+    pub widths: Vec<Option<u32>>,
+    pub repr: Repr,
+}
+
+impl RecordDef {
+    /// The builtin `Range[T]` that `start..end` makes.
+    pub fn range() -> Self {
+        let elem = Ty::Param("T".to_string());
+        RecordDef {
+            generics: vec!["T".to_string()],
+            fields: vec![
+                ("start".to_string(), elem.clone()),
+                ("end".to_string(), elem),
+            ],
+            widths: Vec::new(),
+            repr: Repr::default(),
+        }
+    }
+
+    /// The width of field `index`, if it is a bitfield.
+    pub fn bits(&self, index: usize) -> Option<u32> {
+        self.widths.get(index).copied().flatten()
+    }
+}
+
+/// How a record's fields are placed in memory, from its attributes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Repr {
+    /// `#[repr(C)]`: fields in declaration order, padded as C pads them.
+    /// Other records may put their fields in any order.
+    pub c: bool,
+    /// `#[packed]`: no padding, every field aligned to a byte.
+    pub packed: bool,
+    /// `#[align(16)]`: the least alignment of the record, in bytes.
+    pub align: Option<u64>,
+}
+
+/// The attributes a record may have.
+pub const RECORD_ATTRIBUTES: [&str; 3] = ["repr", "packed", "align"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnionDef {
+    pub generics: Vec<String>,
+    pub variants: Vec<(String, Vec<Ty>)>,
+}
+
+impl UnionDef {
+    pub fn variant(&self, name: &str) -> Option<&[Ty]> {
+        self.variants
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, fields)| fields.as_slice())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalDef {
+    pub ty: Ty,
+    pub mutable: bool,
+}
+
+/// What a name written in a module refers to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Visible {
+    pub canonical: String,
+    pub is_pub: bool,
+    /// Dotted path of the defining module, empty for the root module.
+    pub module: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LookupError {
+    Missing,
+    Private { module: String },
+}
+
+/// Signatures of everything declared at the top level of a module, plus the
+/// public items of the modules it imports. Bodies are checked against this
+/// table, so items can be used before they appear.
+///
+/// Definitions are keyed by canonical name (`shapes.geo::point`, or just
+/// `point` in the root module); `types` and `values` map the names written in
+/// this module, including `alias::item` for imports, onto those keys.
+#[derive(Debug, Default)]
+pub struct ItemTable {
+    pub module: String,
+    pub types: HashMap<String, Visible>,
+    pub values: HashMap<String, Visible>,
+    pub records: HashMap<String, RecordDef>,
+    pub unions: HashMap<String, UnionDef>,
+    pub functions: HashMap<String, FnSig>,
+    /// Methods and associated functions keyed by the implementing type.
+    pub methods: HashMap<String, HashMap<String, FnSig>>,
+    pub protocols: HashMap<String, Vec<FnSig>>,
+    /// Generic parameters of each protocol, e.g. `E` in `into_error[E]`.
+    /// Its method signatures use them as `impl_generics`.
+    pub protocol_generics: HashMap<String, Vec<String>>,
+    /// Protocols implemented by each type, from `implement proto for type`.
+    pub impls: HashMap<String, Vec<ProtocolImpl>>,
+    pub globals: HashMap<String, GlobalDef>,
+}
+
+/// Whether a generic parameter is bounded by a protocol.
+pub type Bound<'a> = dyn Fn(&str, &str) -> bool + 'a;
+
+/// An `implement protocol for type` block, recorded under the type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtocolImpl {
+    pub protocol: String,
+    /// The protocol's generic arguments, e.g. `[io_error]` in
+    /// `implement into_error[io_error] for parse_error`.
+    pub args: Vec<Ty>,
+    /// The module containing the `implement` block.
+    pub module: String,
+}
+
+impl ItemTable {
+    /// Collects a single file program that imports nothing.
+    pub fn collect(program: &Program, handler: &mut ErrorHandler) -> Self {
+        Self::collect_module(program, "", &[], None, handler)
+    }
+
+    /// Collects the items of `module`, making every item of the modules in
+    /// `imports` available as `alias::item` and the public items of
+    /// `prelude` available unqualified.
+    pub fn collect_module(
+        program: &Program,
+        module: &str,
+        imports: &[(String, &ItemTable)],
+        prelude: Option<&ItemTable>,
+        handler: &mut ErrorHandler,
+    ) -> Self {
+        let mut table = ItemTable {
+            module: module.to_string(),
+            ..ItemTable::default()
+        };
+        if let Some(prelude) = prelude {
+            table.import_prelude(prelude);
+        }
+        for (alias, dep) in imports {
+            table.import(alias, dep);
+        }
+
+        // Names first, so field and parameter types can refer to any type.
+        for item in &program.items {
+            let (name, generics, is_record) = match &item.kind {
+                ItemKind::Record(record) => (&record.name, &record.generics, true),
+                ItemKind::Union(union) => (&union.name, &union.generics, false),
+                ItemKind::Protocol(protocol) => {
+                    table.define_type(&protocol.name, item.is_pub, handler);
+                    continue;
+                }
+                _ => continue,
+            };
+            let canonical = table.define_type(name, item.is_pub, handler);
+            let generics = generic_names(generics);
+            if is_record {
+                table.records.insert(
+                    canonical,
+                    RecordDef {
+                        generics,
+                        fields: Vec::new(),
+                        widths: Vec::new(),
+                        repr: Repr::default(),
+                    },
+                );
+            } else {
+                table.unions.insert(
+                    canonical,
+                    UnionDef {
+                        generics,
+                        variants: Vec::new(),
+                    },
+                );
+            }
+        }
+
+        for item in &program.items {
+            match &item.kind {
+                ItemKind::Record(record) => {
+                    let generics = generic_names(&record.generics);
+                    let fields: Vec<(String, Ty)> = record
+                        .fields
+                        .iter()
+                        .map(|f| (f.name.name.clone(), table.lower(&f.ty, &generics, handler)))
+                        .collect();
+                    let widths = record
+                        .fields
+                        .iter()
+                        .zip(&fields)
+                        .map(|(field, (_, ty))| bitfield_width(field, ty, handler))
+                        .collect();
+                    let repr = record_repr(record, handler);
+                    let canonical = table.canonical(&record.name.name);
+                    let def = table.records.get_mut(&canonical).unwrap();
+                    def.fields = fields;
+                    def.widths = widths;
+                    def.repr = repr;
+                }
+                ItemKind::Union(union) => {
+                    let generics = generic_names(&union.generics);
+                    let variants = union
+                        .variants
+                        .iter()
+                        .map(|v| {
+                            let fields = v
+                                .fields
+                                .iter()
+                                .map(|t| table.lower(t, &generics, handler))
+                                .collect();
+                            (v.name.name.clone(), fields)
+                        })
+                        .collect();
+                    let canonical = table.canonical(&union.name.name);
+                    table.unions.get_mut(&canonical).unwrap().variants = variants;
+                }
+                ItemKind::Function(function) => {
+                    let mut sig = table.signature(function, &[], item.is_pub, handler);
+                    sig.attrs = table.attrs(function, &sig, handler);
+                    let canonical = table.define_value(&function.name, item.is_pub, handler);
+                    table.functions.insert(canonical, sig);
+                }
+                ItemKind::Protocol(protocol) => {
+                    for method in &protocol.methods {
+                        if let Some(abi) = &method.abi {
+                            handler.emit(Diagnostic::error(
+                                "protocol methods can't be `extern`",
+                                abi.span,
+                            ));
+                        }
+                    }
+                    let generics = generic_names(&protocol.generics);
+                    let sigs = protocol
+                        .methods
+                        .iter()
+                        .map(|m| table.signature(m, &generics, true, handler))
+                        .collect();
+                    let canonical = table.canonical(&protocol.name.name);
+                    table.protocols.insert(canonical.clone(), sigs);
+                    table.protocol_generics.insert(canonical, generics);
+                }
+                ItemKind::Impl(imp) => table.collect_impl(imp, handler),
+                ItemKind::Stmt(Stmt {
+                    kind: StmtKind::Let { binding, .. },
+                    ..
+                }) => {
+                    // The declaration itself is checked as a statement, which
+                    // reports any problem with the type.
+                    let ty = table.lower(&binding.ty, &[], &mut ErrorHandler::new());
+                    let canonical = table.define_value(&binding.name, item.is_pub, handler);
+                    table.globals.insert(
+                        canonical,
+                        GlobalDef {
+                            ty,
+                            mutable: binding.mutable,
+                        },
+                    );
+                }
+                ItemKind::Stmt(_) | ItemKind::Import(_) => {}
+            }
+        }
+
+        // Every record's fields are known now, including those of records
+        // that come later, and so is every protocol's object safety.
+        for item in &program.items {
+            match &item.kind {
+                ItemKind::Record(record) => {
+                    for field in &record.fields {
+                        table.check_objects(&field.ty, handler);
+                    }
+                    table.check_repr_c(record, handler);
+                }
+                ItemKind::Union(union) => {
+                    for ty in union.variants.iter().flat_map(|v| &v.fields) {
+                        table.check_objects(ty, handler);
+                    }
+                }
+                ItemKind::Protocol(protocol) => {
+                    for method in &protocol.methods {
+                        table.check_signature_objects(method, handler);
+                    }
+                }
+                ItemKind::Impl(imp) => {
+                    for method in &imp.methods {
+                        table.check_signature_objects(&method.function, handler);
+                    }
+                }
+                ItemKind::Function(function) => {
+                    table.check_signature_objects(function, handler);
+                    // A generic `extern "C"` function was already reported.
+                    if function.abi.is_some() && function.generics.is_empty() {
+                        table.check_c_signature(function, handler);
+                    }
+                }
+                _ => {}
+            }
+        }
+        table
+    }
+
+    pub fn canonical(&self, name: &str) -> String {
+        if self.module.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}::{}", self.module, name)
+        }
+    }
+
+    fn define_type(&mut self, name: &Ident, is_pub: bool, handler: &mut ErrorHandler) -> String {
+        let canonical = self.canonical(&name.name);
+        let visible = Visible {
+            canonical: canonical.clone(),
+            is_pub,
+            module: self.module.clone(),
+        };
+        // Local items shadow the prelude.
+        let previous = self.types.insert(name.name.clone(), visible);
+        if previous.is_some_and(|previous| previous.module == self.module) {
+            handler.emit(Diagnostic::error(
+                format!("the type `{}` is defined multiple times", name.name),
+                name.span,
+            ));
+        }
+        canonical
+    }
+
+    fn define_value(&mut self, name: &Ident, is_pub: bool, handler: &mut ErrorHandler) -> String {
+        let canonical = self.canonical(&name.name);
+        let visible = Visible {
+            canonical: canonical.clone(),
+            is_pub,
+            module: self.module.clone(),
+        };
+        // Local items shadow the prelude.
+        let previous = self.values.insert(name.name.clone(), visible);
+        if previous.is_some_and(|previous| previous.module == self.module) {
+            handler.emit(Diagnostic::error(
+                format!("`{}` is defined multiple times", name.name),
+                name.span,
+            ));
+        }
+        canonical
+    }
+
+    /// Makes the public items of the prelude reachable by their plain names
+    /// and as `core::name`. Items defined in the module replace them.
+    fn import_prelude(&mut self, prelude: &ItemTable) {
+        self.import(&prelude.module, prelude);
+        for (names, prelude_names) in [
+            (&mut self.types, &prelude.types),
+            (&mut self.values, &prelude.values),
+        ] {
+            for (name, visible) in prelude_names {
+                if visible.is_pub && visible.module == prelude.module {
+                    names.insert(name.clone(), visible.clone());
+                }
+            }
+        }
+    }
+
+    /// Makes the items defined in `dep` reachable as `alias::name`. Private
+    /// items are recorded too, so using them reports a visibility error
+    /// instead of "not found".
+    fn import(&mut self, alias: &str, dep: &ItemTable) {
+        for (names, dep_names) in [
+            (&mut self.types, &dep.types),
+            (&mut self.values, &dep.values),
+        ] {
+            for (name, visible) in dep_names {
+                if visible.module == dep.module {
+                    names.insert(format!("{}::{}", alias, name), visible.clone());
+                }
+            }
+        }
+        // Definitions are keyed canonically, so merging can't clash.
+        self.records.extend(dep.records.clone());
+        self.unions.extend(dep.unions.clone());
+        self.functions.extend(dep.functions.clone());
+        self.protocols.extend(dep.protocols.clone());
+        self.protocol_generics.extend(dep.protocol_generics.clone());
+        for (ty, impls) in &dep.impls {
+            let known = self.impls.entry(ty.clone()).or_default();
+            for imp in impls {
+                if !known.contains(imp) {
+                    known.push(imp.clone());
+                }
+            }
+        }
+        self.globals.extend(dep.globals.clone());
+        for (ty, methods) in &dep.methods {
+            self.methods
+                .entry(ty.clone())
+                .or_default()
+                .extend(methods.clone());
+        }
+    }
+
+    fn lookup<'a>(
+        &'a self,
+        names: &'a HashMap<String, Visible>,
+        path: &str,
+    ) -> Result<&'a str, LookupError> {
+        let visible = names.get(path).ok_or(LookupError::Missing)?;
+        if visible.is_pub || visible.module == self.module {
+            Ok(&visible.canonical)
+        } else {
+            Err(LookupError::Private {
+                module: visible.module.clone(),
+            })
+        }
+    }
+
+    /// Canonical name of the record, union or protocol written as `path`.
+    pub fn lookup_type(&self, path: &str) -> Result<&str, LookupError> {
+        self.lookup(&self.types, path)
+    }
+
+    /// Canonical name of the function or global written as `path`.
+    pub fn lookup_value(&self, path: &str) -> Result<&str, LookupError> {
+        self.lookup(&self.values, path)
+    }
+
+    fn collect_impl(&mut self, imp: &Impl, handler: &mut ErrorHandler) {
+        let Some((type_name, generics)) = self.impl_target(&imp.target) else {
+            handler.emit(Diagnostic::error(
+                "`implement` target must be a record or union",
+                imp.target.span,
+            ));
+            return;
+        };
+        if imp.is_unsafe
+            && !imp
+                .protocol
+                .as_ref()
+                .and_then(|name| self.lookup_type(&name.name).ok())
+                .is_some_and(is_thread_marker)
+        {
+            handler.emit(
+                Diagnostic::error(
+                    "only `send` and `share` are implemented with `unsafe implement`",
+                    imp.protocol
+                        .as_ref()
+                        .map_or(imp.target.span, |name| name.span),
+                )
+                .with_note("other implementations can't break what the checker proves"),
+            );
+        }
+        if let Some(name) = &imp.protocol
+            && let Some(protocol) = self.protocol(name, handler)
+        {
+            if is_thread_marker(&protocol) && !imp.is_unsafe {
+                handler.emit(
+                    Diagnostic::error(
+                        format!("implementing `{}` needs `unsafe implement`", name.name),
+                        name.span,
+                    )
+                    .with_note(format!(
+                        "the checker can't prove `{}` is safe to use from other threads, \
+                         so the implementation vouches for it",
+                        type_name
+                    )),
+                );
+            }
+            let args: Vec<Ty> = imp
+                .protocol_args
+                .iter()
+                .map(|t| self.lower(t, &generics, handler))
+                .collect();
+            let expected = self.protocol_generics.get(&protocol).map_or(0, Vec::len);
+            if args.len() != expected {
+                handler.emit(Diagnostic::error(
+                    format!(
+                        "protocol `{}` expects {} generic argument(s), found {}",
+                        name.name,
+                        expected,
+                        args.len()
+                    ),
+                    name.span,
+                ));
+            } else {
+                let imp = ProtocolImpl {
+                    protocol,
+                    args,
+                    module: self.module.clone(),
+                };
+                self.check_coherence(&imp, &type_name, name.span, handler);
+                self.impls.entry(type_name.clone()).or_default().push(imp);
+            }
+        }
+        let sigs: Vec<FnSig> = imp
+            .methods
+            .iter()
+            .map(|m| {
+                let mut sig = self.signature(&m.function, &generics, m.is_pub, handler);
+                sig.owner = Some(type_name.clone());
+                sig.attrs = self.attrs(&m.function, &sig, handler);
+                sig
+            })
+            .collect();
+        let methods = self.methods.entry(type_name.clone()).or_default();
+        for (sig, method) in sigs.into_iter().zip(&imp.methods) {
+            if methods.contains_key(&sig.name) {
+                handler.emit(Diagnostic::error(
+                    format!("duplicate definition of `{}` for `{}`", sig.name, type_name),
+                    method.function.name.span,
+                ));
+            }
+            methods.insert(sig.name.clone(), sig);
+        }
+    }
+
+    /// Every (protocol, type) pair may be implemented once in the whole
+    /// program, per protocol arguments. Implementations must be in the module
+    /// defining the protocol, the type or one of the protocol arguments (the
+    /// orphan rule); that module imports the others, so an earlier
+    /// implementation is always visible here.
+    fn check_coherence(
+        &self,
+        imp: &ProtocolImpl,
+        type_name: &str,
+        span: Span,
+        handler: &mut ErrorHandler,
+    ) {
+        let protocol = &imp.protocol;
+        let local = [protocol.as_str(), type_name]
+            .into_iter()
+            .chain(imp.args.iter().filter_map(|arg| match arg {
+                Ty::Adt { name, .. } => Some(name.as_str()),
+                _ => None,
+            }))
+            .any(|name| module_of(name) == self.module);
+        if !local {
+            handler.emit(
+                Diagnostic::error(
+                    format!(
+                        "cannot implement protocol `{}` for `{}` outside the modules that define them",
+                        protocol, type_name
+                    ),
+                    span,
+                )
+                .with_note(
+                    "implement a protocol in the module that defines the protocol or the type",
+                ),
+            );
+            return;
+        }
+        let existing = self.impls.get(type_name).and_then(|impls| {
+            impls
+                .iter()
+                .find(|other| other.protocol == *protocol && other.args == imp.args)
+        });
+        if let Some(existing) = existing {
+            let place = if existing.module.is_empty() {
+                "the root module".to_string()
+            } else {
+                format!("module `{}`", existing.module)
+            };
+            handler.emit(
+                Diagnostic::error(
+                    format!(
+                        "conflicting implementations of protocol `{}` for `{}`",
+                        protocol, type_name
+                    ),
+                    span,
+                )
+                .with_note(format!("the first implementation is in {}", place)),
+            );
+        }
+    }
+
+    /// The implemented type's canonical name and the generic parameters it
+    /// introduces: in `implement Option[T]` every unknown argument name is a
+    /// parameter.
+    pub fn impl_target(&self, target: &TypeExpr) -> Option<(String, Vec<String>)> {
+        let TypeExprKind::Named { path, args } = &target.kind else {
+            return None;
+        };
+        let name = self.lookup_type(&join_path(path)).ok()?.to_string();
+        if !self.records.contains_key(&name) && !self.unions.contains_key(&name) {
+            return None;
+        }
+        let generics = args
+            .iter()
+            .filter_map(|arg| match &arg.kind {
+                TypeExprKind::Named { path, args } if path.len() == 1 && args.is_empty() => {
+                    let n = &path[0].name;
+                    let known = Ty::primitive(n).is_some() || self.types.contains_key(n);
+                    (!known).then(|| n.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        Some((name, generics))
+    }
+
+    pub fn signature(
+        &self,
+        function: &Function,
+        impl_generics: &[String],
+        is_pub: bool,
+        handler: &mut ErrorHandler,
+    ) -> FnSig {
+        let mut generics = impl_generics.to_vec();
+        generics.extend(generic_names(&function.generics));
+        let params = function
+            .params
+            .iter()
+            .map(|p| ParamSig {
+                label: p.label.as_ref().map(|l| l.name.clone()),
+                name: p.name.name.clone(),
+                ty: self.lower(&p.ty, &generics, handler),
+            })
+            .collect();
+        let ret = match &function.ret {
+            Some(ty) => self.lower(ty, &generics, handler),
+            None => Ty::Unit,
+        };
+        let bounds = function
+            .generics
+            .iter()
+            .map(|g| {
+                g.bounds
+                    .iter()
+                    .filter_map(|bound| self.protocol(bound, handler))
+                    .collect()
+            })
+            .collect();
+        FnSig {
+            name: function.name.name.clone(),
+            module: self.module.clone(),
+            is_pub,
+            impl_generics: impl_generics.to_vec(),
+            generics: generic_names(&function.generics),
+            bounds,
+            owner: None,
+            is_unsafe: function.is_unsafe,
+            is_extern: function.abi.is_some() && function.body.is_none(),
+            self_kind: function.self_param.as_ref().map(|s| s.kind),
+            params,
+            variadic: function.variadic,
+            ret,
+            attrs: FnAttrs::default(),
+        }
+    }
+
+    /// The attributes of `function`, whose signature is `sig`. Interrupt
+    /// handlers and unmangled functions need a single symbol, so they must
+    /// be free functions without generic parameters.
+    pub fn attrs(&self, function: &Function, sig: &FnSig, handler: &mut ErrorHandler) -> FnAttrs {
+        let mut attrs = FnAttrs::default();
+        for attr in known_attributes(&function.attrs, &FN_ATTRIBUTES, "functions", handler) {
+            let name = attr.name.name.as_str();
+            match (name, &attr.args[..]) {
+                ("interrupt", []) => attrs.interrupt = true,
+                ("naked", []) => attrs.naked = true,
+                ("no_mangle", []) => attrs.no_mangle = true,
+                ("section", [AttrArg::Str(section, _)]) if !section.is_empty() => {
+                    attrs.section = Some(section.clone());
+                }
+                ("section", _) => handler.emit(Diagnostic::error(
+                    "`section` takes the name of a section, as in `#[section(\".boot\")]`",
+                    attr.span,
+                )),
+                ("align", args) => attrs.align = alignment(attr, args, handler),
+                (_, args) => no_arguments(attr, args, handler),
+            }
+        }
+
+        if let Some(abi) = &function.abi {
+            attrs.c_abi = true;
+            if abi.name != "C" {
+                handler.emit(
+                    Diagnostic::error(format!("unknown ABI `{:?}`", abi.name), abi.span)
+                        .with_note("the only ABI is `extern \"C\"`"),
+                );
+            }
+        }
+
+        let span = function.name.span;
+        let single_symbol =
+            sig.owner.is_none() && sig.impl_generics.is_empty() && sig.generics.is_empty();
+        for (set, name) in [
+            (attrs.interrupt, "`#[interrupt]`"),
+            (attrs.no_mangle, "`#[no_mangle]`"),
+            (attrs.c_abi, "`extern \"C\"`"),
+        ] {
+            if set && !single_symbol {
+                handler.emit(Diagnostic::error(
+                    format!(
+                        "{} only applies to free functions without generic parameters",
+                        name
+                    ),
+                    span,
+                ));
+            }
+        }
+        for (set, name) in [(attrs.naked, "`#[naked]`"), (attrs.c_abi, "`extern \"C\"`")] {
+            if attrs.interrupt && set {
+                handler.emit(Diagnostic::error(
+                    format!("a function can't be both `#[interrupt]` and {}", name),
+                    span,
+                ));
+            }
+        }
+        if let (true, Some(attr)) = (sig.is_extern, function.attrs.first()) {
+            handler.emit(Diagnostic::error(
+                "attributes don't apply to `extern` declarations",
+                attr.span,
+            ));
+        }
+        if attrs.interrupt {
+            let error_code = matches!(&sig.params[..], [] | [ParamSig { ty: Ty::Int, .. }]);
+            if !error_code || sig.self_kind.is_some() {
+                handler.emit(
+                    Diagnostic::error(
+                        "an interrupt handler takes no parameters, or just an `int`",
+                        span,
+                    )
+                    .with_note("the `int` is the error code the processor pushes for exceptions like page faults"),
+                );
+            }
+            if sig.ret != Ty::Unit {
+                handler.emit(Diagnostic::error(
+                    format!("an interrupt handler returns `unit`, not `{}`", sig.ret),
+                    span,
+                ));
+            }
+        }
+        attrs
+    }
+
+    /// Reports fields of a `#[repr(C)]` record that C has no counterpart
+    /// for.
+    fn check_repr_c(&self, record: &Record, handler: &mut ErrorHandler) {
+        let def = &self.records[&self.canonical(&record.name.name)];
+        if !def.repr.c {
+            return;
+        }
+        for (field, (_, ty)) in record.fields.iter().zip(&def.fields) {
+            if !self.c_compatible(ty) {
+                handler.emit(
+                    Diagnostic::error(
+                        format!(
+                            "the `#[repr(C)]` record `{}` can't have a field of type `{}`",
+                            record.name.name, ty
+                        ),
+                        field.name.span,
+                    )
+                    .with_note(
+                        "fields of a `#[repr(C)]` record are numbers, `bool`, `char`, \
+                         references or other `#[repr(C)]` records",
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Reports parameter and result types of an `extern "C"` function that
+    /// C has no counterpart for.
+    fn check_c_signature(&self, function: &Function, handler: &mut ErrorHandler) {
+        let sig = &self.functions[&self.canonical(&function.name.name)];
+        let mut report = |ty: &Ty, span: Span| {
+            handler.emit(
+                Diagnostic::error(
+                    format!("`extern \"C\"` functions can't pass a `{}`", ty),
+                    span,
+                )
+                .with_note(
+                    "C has counterparts for numbers, `bool`, `char`, references and \
+                     `#[repr(C)]` records",
+                ),
+            );
+        };
+        for (param, sig) in function.params.iter().zip(&sig.params) {
+            if !self.c_compatible(&sig.ty) {
+                report(&sig.ty, param.ty.span);
+            }
+        }
+        if let Some(ret) = &function.ret
+            && !matches!(sig.ret, Ty::Unit | Ty::Never)
+            && !self.c_compatible(&sig.ret)
+        {
+            report(&sig.ret, ret.span);
+        }
+    }
+
+    /// Whether C lays out a `ty` as this program does.
+    pub(super) fn c_compatible(&self, ty: &Ty) -> bool {
+        match ty {
+            Ty::Int | Ty::Float | Ty::Char | Ty::Byte | Ty::Bool | Ty::Error => true,
+            Ty::Ref { inner, .. } | Ty::RawRef { inner, .. } => !matches!(**inner, Ty::Dyn(_)),
+            Ty::Adt { name, .. } => self.records.get(name).is_some_and(|def| def.repr.c),
+            _ => false,
+        }
+    }
+
+    /// Canonical name of the protocol `name` refers to.
+    fn protocol(&self, name: &Ident, handler: &mut ErrorHandler) -> Option<String> {
+        match self.lookup_type(&name.name) {
+            Ok(canonical)
+                if !self.records.contains_key(canonical)
+                    && !self.unions.contains_key(canonical) =>
+            {
+                Some(canonical.to_string())
+            }
+            Ok(_) => {
+                handler.emit(Diagnostic::error(
+                    format!("expected a protocol, found type `{}`", name.name),
+                    name.span,
+                ));
+                None
+            }
+            Err(err) => {
+                handler.emit(lookup_error("protocol", &name.name, err, name.span));
+                None
+            }
+        }
+    }
+
+    /// Whether the record or union `ty` implements `protocol`.
+    pub fn implements(&self, ty: &str, protocol: &str) -> bool {
+        self.impls
+            .get(ty)
+            .is_some_and(|impls| impls.iter().any(|imp| imp.protocol == protocol))
+    }
+
+    /// Whether `ty` implements the thread marker `marker` (`send` or
+    /// `share`): through an `unsafe implement`, or because every part of it
+    /// does. A `ref T` is `send` only when `T` is `share`, since both
+    /// threads then see `T`; a `ref mut T` moves its access with it. Raw
+    /// references, functions, which may capture locals, and protocol
+    /// objects of unknown type are neither. `bound` tells whether a generic
+    /// parameter is bounded by a marker.
+    pub fn is_thread_safe(&self, ty: &Ty, marker: &str, bound: &Bound<'_>) -> bool {
+        self.thread_safe(ty, marker, bound, &mut Vec::new())
+    }
+
+    fn thread_safe(&self, ty: &Ty, marker: &str, bound: &Bound<'_>, seen: &mut Vec<Ty>) -> bool {
+        let all = |tys: &[Ty], seen: &mut Vec<Ty>| {
+            tys.iter()
+                .all(|ty| self.thread_safe(ty, marker, bound, seen))
+        };
+        match ty {
+            Ty::Tuple(elems) => all(elems, seen),
+            Ty::Ref { mutable, inner } => {
+                let needs = if *mutable { marker } else { prelude::SHARE };
+                self.thread_safe(inner, needs, bound, seen)
+            }
+            Ty::RawRef { .. } | Ty::Fn { .. } | Ty::Dyn(_) => false,
+            Ty::Param(name) => bound(name, marker),
+            Ty::Adt { name, .. } if self.implements(name, marker) => true,
+            // A recursive type is as safe as its other parts.
+            Ty::Adt { .. } if seen.contains(ty) => true,
+            Ty::Adt { name, args } => {
+                seen.push(ty.clone());
+                let range = RecordDef::range();
+                let parts: Vec<Ty> = if let Some(record) = self
+                    .records
+                    .get(name)
+                    .or((name == "Range").then_some(&range))
+                {
+                    record
+                        .fields
+                        .iter()
+                        .map(|(_, field)| field.subst(&record.generics, args))
+                        .collect()
+                } else if let Some(union) = self.unions.get(name) {
+                    union
+                        .variants
+                        .iter()
+                        .flat_map(|(_, fields)| fields)
+                        .map(|field| field.subst(&union.generics, args))
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                let safe = all(&parts, seen);
+                seen.pop();
+                safe
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether the record or union `ty` implements `protocol` with the
+    /// generic arguments `args`.
+    pub fn implements_with(&self, ty: &str, protocol: &str, args: &[Ty]) -> bool {
+        self.impls.get(ty).is_some_and(|impls| {
+            impls
+                .iter()
+                .any(|imp| imp.protocol == protocol && imp.args == args)
+        })
+    }
+
+    /// Why `protocol` can't be used as `ref protocol`, one reason per
+    /// offending method. Every method of a protocol object is called through
+    /// a vtable on a reference to a value of unknown type, so it must take
+    /// `self` by reference and can't have generic parameters. A generic
+    /// protocol has no single vtable layout, so it is never object safe.
+    pub fn object_safety_violations(&self, protocol: &str) -> Vec<String> {
+        let mut violations = Vec::new();
+        if self
+            .protocol_generics
+            .get(protocol)
+            .is_some_and(|generics| !generics.is_empty())
+        {
+            violations.push("the protocol has generic parameters".to_string());
+        }
+        for sig in self.protocols.get(protocol).into_iter().flatten() {
+            match sig.self_kind {
+                None => violations.push(format!("`{}` has no `self` parameter", sig.name)),
+                Some(SelfKind::Value | SelfKind::MutValue) => violations.push(format!(
+                    "`{}` takes `self` by value; use `ref self` or `ref mut self`",
+                    sig.name
+                )),
+                Some(SelfKind::Ref | SelfKind::RefMut) => {}
+            }
+            if !sig.generics.is_empty() {
+                violations.push(format!("`{}` has generic parameters", sig.name));
+            }
+        }
+        violations
+    }
+
+    /// The error for a `ref protocol` at `span` when `protocol` can't be
+    /// made into an object.
+    fn object_safety_error(&self, protocol: &str, span: Span) -> Option<Diagnostic> {
+        let violations = self.object_safety_violations(protocol);
+        if violations.is_empty() {
+            return None;
+        }
+        let mut diagnostic = Diagnostic::error(
+            format!("protocol `{}` cannot be made into an object", protocol),
+            span,
+        );
+        for violation in violations {
+            diagnostic = diagnostic.with_note(violation);
+        }
+        Some(diagnostic)
+    }
+
+    /// Reports every `ref protocol` written in `ty` whose protocol can't be
+    /// made into an object.
+    pub fn check_objects(&self, ty: &TypeExpr, handler: &mut ErrorHandler) {
+        match &ty.kind {
+            TypeExprKind::Named { args: elems, .. } | TypeExprKind::Tuple(elems) => {
+                for elem in elems {
+                    self.check_objects(elem, handler);
+                }
+            }
+            TypeExprKind::Ref { inner, .. } => match self.object_protocol(inner) {
+                Some(protocol) => {
+                    if let Some(diagnostic) = self.object_safety_error(&protocol, ty.span) {
+                        handler.emit(diagnostic);
+                    }
+                }
+                None => self.check_objects(inner, handler),
+            },
+            TypeExprKind::RawRef { inner, .. } => self.check_objects(inner, handler),
+            TypeExprKind::Fn { params, ret } => {
+                for param in params.iter().chain(ret.as_deref()) {
+                    self.check_objects(param, handler);
+                }
+            }
+        }
+    }
+
+    /// `check_objects` for the parameter and return types of `function`.
+    fn check_signature_objects(&self, function: &Function, handler: &mut ErrorHandler) {
+        for ty in function.params.iter().map(|p| &p.ty).chain(&function.ret) {
+            self.check_objects(ty, handler);
+        }
+    }
+
+    /// The protocol a `ref` target names, making it a protocol object.
+    fn object_protocol(&self, ty: &TypeExpr) -> Option<String> {
+        let TypeExprKind::Named { path, args } = &ty.kind else {
+            return None;
+        };
+        let canonical = self.lookup_type(&join_path(path)).ok()?;
+        let is_protocol =
+            !self.records.contains_key(canonical) && !self.unions.contains_key(canonical);
+        (is_protocol && args.is_empty()).then(|| canonical.to_string())
+    }
+
+    /// Converts a written type into a `Ty`, reporting unknown names.
+    pub fn lower(&self, ty: &TypeExpr, generics: &[String], handler: &mut ErrorHandler) -> Ty {
+        match &ty.kind {
+            TypeExprKind::Tuple(elems) if elems.is_empty() => Ty::Unit,
+            TypeExprKind::Tuple(elems) => Ty::Tuple(
+                elems
+                    .iter()
+                    .map(|t| self.lower(t, generics, handler))
+                    .collect(),
+            ),
+            TypeExprKind::Ref { mutable, inner } => Ty::Ref {
+                mutable: *mutable,
+                inner: Box::new(match self.object_protocol(inner) {
+                    Some(protocol) => Ty::Dyn(protocol),
+                    None => self.lower(inner, generics, handler),
+                }),
+            },
+            TypeExprKind::RawRef { mutable, inner } => Ty::RawRef {
+                mutable: *mutable,
+                inner: Box::new(self.lower(inner, generics, handler)),
+            },
+            TypeExprKind::Fn { params, ret } => Ty::Fn {
+                params: params
+                    .iter()
+                    .map(|t| self.lower(t, generics, handler))
+                    .collect(),
+                ret: Box::new(
+                    ret.as_ref()
+                        .map_or(Ty::Unit, |t| self.lower(t, generics, handler)),
+                ),
+            },
+            TypeExprKind::Named { path, args } => {
+                let name = join_path(path);
+                let args: Vec<Ty> = args
+                    .iter()
+                    .map(|t| self.lower(t, generics, handler))
+                    .collect();
+                if path.len() == 1 && generics.contains(&name) {
+                    return Ty::Param(name);
+                }
+                if let Some(prim) = Ty::primitive(&name) {
+                    return prim;
+                }
+                let canonical = match self.lookup_type(&name) {
+                    Ok(canonical) => canonical.to_string(),
+                    Err(err) => {
+                        let span = path[0].span.to(path.last().unwrap().span);
+                        let candidates = self
+                            .types
+                            .keys()
+                            .map(String::as_str)
+                            .chain(PRIMITIVES.iter().copied())
+                            .chain(generics.iter().map(String::as_str));
+                        handler.emit(with_suggestion(
+                            lookup_error("type", &name, err, ty.span),
+                            &name,
+                            candidates,
+                            span,
+                        ));
+                        return Ty::Error;
+                    }
+                };
+                let expected = match (self.records.get(&canonical), self.unions.get(&canonical)) {
+                    (Some(record), _) => record.generics.len(),
+                    (_, Some(union)) => union.generics.len(),
+                    _ => {
+                        handler.emit(
+                            Diagnostic::error(
+                                format!("expected a type, found protocol `{}`", name),
+                                ty.span,
+                            )
+                            .with_note(format!(
+                                "use `ref {}` for a reference to any type implementing it",
+                                name
+                            )),
+                        );
+                        return Ty::Error;
+                    }
+                };
+                if args.len() != expected {
+                    handler.emit(Diagnostic::error(
+                        format!(
+                            "type `{}` expects {} generic argument(s), found {}",
+                            name,
+                            expected,
+                            args.len()
+                        ),
+                        ty.span,
+                    ));
+                    return Ty::Error;
+                }
+                Ty::Adt {
+                    name: canonical,
+                    args,
+                }
+            }
+        }
+    }
+
+    /// Field types of a record instantiated with `args`.
+    pub fn record_fields(&self, name: &str, args: &[Ty]) -> Option<Vec<(String, Ty)>> {
+        let record = self.records.get(name)?;
+        Some(
+            record
+                .fields
+                .iter()
+                .map(|(n, t)| (n.clone(), t.subst(&record.generics, args)))
+                .collect(),
+        )
+    }
+
+    pub fn method(&self, type_name: &str, method: &str) -> Option<&FnSig> {
+        self.methods.get(type_name)?.get(method)
+    }
+}
+
+/// The module a canonical name was defined in; empty for the root module.
+/// Whether `protocol` is `send` or `share`, which every type made of
+/// parts implementing it implements too.
+pub fn is_thread_marker(protocol: &str) -> bool {
+    protocol == prelude::SEND || protocol == prelude::SHARE
+}
+
+pub fn module_of(canonical: &str) -> &str {
+    canonical.rsplit_once("::").map_or("", |(module, _)| module)
+}
+
+pub fn generic_names(params: &[GenericParam]) -> Vec<String> {
+    params.iter().map(|p| p.name.name.clone()).collect()
+}
+
+pub fn join_path(path: &[Ident]) -> String {
+    path.iter()
+        .map(|segment| segment.name.as_str())
+        .collect::<Vec<_>>()
+        .join("::")
+}
+
+/// The diagnostic for a failed `lookup_type`/`lookup_value`.
+pub fn lookup_error(kind: &str, name: &str, err: LookupError, span: Span) -> Diagnostic {
+    match err {
+        LookupError::Missing => Diagnostic::error(
+            format!("cannot find {} `{}` in this scope", kind, name),
+            span,
+        ),
+        LookupError::Private { module } => Diagnostic::error(
+            format!("{} `{}` is private to module `{}`", kind, name, module),
+            span,
+        )
+        .with_note("mark the item `pub` to use it from other modules"),
+    }
+}
+
+/// The attributes among `attrs` that are in `known`, reporting unknown and
+/// repeated ones.
+fn known_attributes<'a>(
+    attrs: &'a [Attribute],
+    known: &[&str],
+    what: &str,
+    handler: &mut ErrorHandler,
+) -> Vec<&'a Attribute> {
+    let mut seen: Vec<&Attribute> = Vec::new();
+    for attr in attrs {
+        let name = attr.name.name.as_str();
+        if !known.contains(&name) {
+            let names: Vec<String> = known.iter().map(|a| format!("`{}`", a)).collect();
+            handler.emit(
+                Diagnostic::error(format!("unknown attribute `{}`", name), attr.name.span)
+                    .with_note(format!("{} take the attributes {}", what, names.join(", "))),
+            );
+        } else if seen.iter().any(|a| a.name.name == name) {
+            handler.emit(Diagnostic::error(
+                format!("duplicate attribute `{}`", name),
+                attr.span,
+            ));
+        } else {
+            seen.push(attr);
+        }
+    }
+    seen
+}
+
+/// The alignment `#[align(n)]` asks for.
+fn alignment(attr: &Attribute, args: &[AttrArg], handler: &mut ErrorHandler) -> Option<u64> {
+    match args {
+        [AttrArg::Int(n, _)] if n.is_power_of_two() => Some(*n as u64),
+        _ => {
+            handler.emit(Diagnostic::error(
+                "`align` takes a power of two, as in `#[align(16)]`",
+                attr.span,
+            ));
+            None
+        }
+    }
+}
+
+fn no_arguments(attr: &Attribute, args: &[AttrArg], handler: &mut ErrorHandler) {
+    if let Some(arg) = args.first() {
+        handler.emit(Diagnostic::error(
+            format!("`{}` takes no arguments", attr.name.name),
+            arg.span(),
+        ));
+    }
+}
+
+/// How the attributes of `record` ask for it to be laid out.
+fn record_repr(record: &Record, handler: &mut ErrorHandler) -> Repr {
+    let mut repr = Repr::default();
+    for attr in known_attributes(&record.attrs, &RECORD_ATTRIBUTES, "records", handler) {
+        match (attr.name.name.as_str(), &attr.args[..]) {
+            ("repr", [AttrArg::Ident(c)]) if c.name == "C" => repr.c = true,
+            ("repr", _) => handler.emit(Diagnostic::error(
+                "`repr` takes `C`, as in `#[repr(C)]`",
+                attr.span,
+            )),
+            ("packed", []) => repr.packed = true,
+            ("align", args) => repr.align = alignment(attr, args, handler),
+            (_, args) => no_arguments(attr, args, handler),
+        }
+    }
+    if repr.packed && repr.align.is_some() {
+        handler.emit(Diagnostic::error(
+            "a record can't be both `#[packed]` and `#[align]`",
+            record.name.span,
+        ));
+        repr.align = None;
+    }
+    repr
+}
+
+/// The width of `field` if it is a bitfield of type `ty`, which must be
+/// wide enough.
+fn bitfield_width(field: &Field, ty: &Ty, handler: &mut ErrorHandler) -> Option<u32> {
+    let (width, span) = field.bits?;
+    let max = match ty {
+        Ty::Int => 64,
+        Ty::Byte => 8,
+        Ty::Bool => 1,
+        Ty::Error => return None,
+        _ => {
+            handler.emit(Diagnostic::error(
+                format!("a bitfield is an `int`, `byte` or `bool`, not `{}`", ty),
+                field.name.span,
+            ));
+            return None;
+        }
+    };
+    if width == 0 || width > max {
+        let widths = match max {
+            1 => "1 bit".to_string(),
+            _ => format!("1 to {} bits", max),
+        };
+        handler.emit(Diagnostic::error(
+            format!("`{}` bitfields are {} wide", ty, widths),
+            span,
+        ));
+        return None;
+    }
+    Some(width as u32)
+}
@@ -1 +1,216 @@
+mod borrowck;
+mod infer;
+pub mod items;
+pub mod resolve;
+mod typeck;
+pub mod types;
 
+use crate::errorhandler::{Diagnostic, ErrorHandler};
+use crate::lexer::size::Span;
+use crate::loader::ModuleGraph;
+use crate::parser::ast::{ItemKind, NodeId, Program};
+use crate::prelude;
+use crate::target::{self, Target};
+use items::ItemTable;
+use resolve::Resolutions;
+use std::collections::HashMap;
+use types::Ty;
+
+/// What a function is declared in.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FnOwner {
+    Free,
+    /// An `implement` block for the record or union with this canonical name.
+    Type(String),
+    /// A protocol method; calls through it are resolved per receiver type.
+    Protocol(String),
+}
+
+/// A function or method, by canonical name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FnRef {
+    pub owner: FnOwner,
+    pub name: String,
+}
+
+/// The function a call expression invokes and the generic arguments it was
+/// instantiated with.
+#[derive(Debug, Clone)]
+pub struct Callee {
+    pub func: FnRef,
+    /// The impl's generic arguments followed by the function's own.
+    pub args: Vec<Ty>,
+    /// The receiver type of a protocol method call.
+    pub self_ty: Option<Ty>,
+    /// The function containing the call; `None` for top level statements.
+    pub caller: Option<FnRef>,
+    pub span: Span,
+}
+
+/// A reference to a concrete type converted to a protocol object, which
+/// needs that type's vtable for the protocol.
+#[derive(Debug, Clone)]
+pub struct Coercion {
+    pub ty: Ty,
+    pub protocol: String,
+    /// The function containing the conversion; `None` at top level.
+    pub caller: Option<FnRef>,
+    pub span: Span,
+}
+
+/// Everything the checker learned about a program that later phases need.
+#[derive(Debug, Default)]
+pub struct TypeckResults {
+    pub expr_types: HashMap<NodeId, Ty>,
+    /// Types of `let`, parameter, `for` and pattern bindings, by the id of
+    /// their declaration.
+    pub binding_types: HashMap<NodeId, Ty>,
+    pub resolutions: Resolutions,
+    /// Call expressions that name a function or method directly.
+    pub callees: HashMap<NodeId, Callee>,
+    /// Expressions converted from `ref T` to `ref protocol`.
+    pub coercions: HashMap<NodeId, Coercion>,
+    /// Calls to `size_of`, `align_of` and `offset_of`, and what they
+    /// evaluate to.
+    pub constants: HashMap<NodeId, i64>,
+}
+
+impl TypeckResults {
+    pub fn type_of(&self, id: NodeId) -> Option<&Ty> {
+        self.expr_types.get(&id)
+    }
+}
+
+/// Collects item signatures, then resolves and type checks every body in a
+/// single file program, which sees the `core` prelude but imports nothing.
+pub fn check_program(program: &Program, handler: &mut ErrorHandler) -> (ItemTable, TypeckResults) {
+    let (prelude, _) = check_prelude(handler);
+    let items = ItemTable::collect_module(program, "", &[], Some(&prelude), handler);
+    let results = check_bodies(program, &items, Target::Hosted, handler);
+    (items, results)
+}
+
+/// Parses and checks the `core` prelude on its own.
+pub fn check_prelude(handler: &mut ErrorHandler) -> (ItemTable, TypeckResults) {
+    let program = crate::parser::parse(prelude::SOURCE, handler);
+    let items = ItemTable::collect_module(&program, prelude::MODULE, &[], None, handler);
+    let results = check_bodies(&program, &items, Target::Hosted, handler);
+    (items, results)
+}
+
+fn check_bodies(
+    program: &Program,
+    items: &ItemTable,
+    target: Target,
+    handler: &mut ErrorHandler,
+) -> TypeckResults {
+    let errors = handler.error_count();
+    let resolutions = resolve::resolve(program, items, handler);
+    let mut checker = typeck::TypeChecker::new(items, &resolutions, target, handler);
+    checker.check_program(program);
+    let mut results = checker.finish();
+    results.resolutions = resolutions;
+    // Ownership is only meaningful once every type is known.
+    if handler.error_count() == errors {
+        borrowck::check(program, items, &results, handler);
+    }
+    results
+}
+
+/// Checks every module of a loaded program, dependencies first, so each
+/// module sees the finished item tables of the modules it imports and of the
+/// prelude, which is loaded first. Results are indexed by `ModuleId`.
+pub fn check_graph(
+    graph: &ModuleGraph,
+    handler: &mut ErrorHandler,
+) -> Vec<(ItemTable, TypeckResults)> {
+    let mut checked: Vec<Option<(ItemTable, TypeckResults)>> =
+        graph.modules.iter().map(|_| None).collect();
+    for &id in &graph.order {
+        let module = &graph.modules[id];
+        let imports: Vec<(String, &ItemTable)> = module
+            .imports
+            .iter()
+            .filter_map(|(alias, dep)| Some((alias.clone(), &checked[*dep].as_ref()?.0)))
+            .collect();
+        let prelude = match graph.by_name(prelude::MODULE) {
+            Some(core) if core.id != id => checked[core.id].as_ref().map(|(items, _)| items),
+            _ => None,
+        };
+        let items =
+            ItemTable::collect_module(&module.program, &module.name, &imports, prelude, handler);
+        let results = check_bodies(&module.program, &items, graph.target, handler);
+        if graph.target == Target::Freestanding && module.name.is_empty() {
+            check_entry_points(graph, &items, handler);
+        }
+        checked[id] = Some((items, results));
+    }
+    checked.into_iter().map(Option::unwrap).collect()
+}
+
+/// Checks that the entry module of a freestanding program defines `@start`,
+/// taking nothing or the Multiboot2 magic and information address, and
+/// `@panic`, taking the message.
+fn check_entry_points(graph: &ModuleGraph, items: &ItemTable, handler: &mut ErrorHandler) {
+    let root = graph.root();
+    let file = root.path.display().to_string();
+    let top = graph
+        .sources
+        .files()
+        .iter()
+        .find(|source| source.name == file)
+        .map_or(Span::default(), |source| {
+            Span::new(source.base, source.base)
+        });
+    let expected = [
+        (
+            target::ENTRY,
+            "@start()",
+            vec![vec![], vec![Ty::Int, Ty::Int]],
+        ),
+        (target::PANIC, "@panic(string message)", vec![vec![Ty::Str]]),
+    ];
+    for (name, declaration, params) in expected {
+        let span = root.program.items.iter().find_map(|item| match &item.kind {
+            ItemKind::Function(function) if function.name.name == name => Some(function.name.span),
+            _ => None,
+        });
+        let sig = items
+            .functions
+            .get(&items.canonical(name))
+            .filter(|sig| !sig.is_extern);
+        let (Some(span), Some(sig)) = (span, sig) else {
+            handler.emit(Diagnostic::error(
+                format!("a freestanding program must define `{}`", declaration),
+                top,
+            ));
+            continue;
+        };
+        if sig.attrs.interrupt || sig.attrs.naked || sig.attrs.no_mangle || sig.attrs.c_abi {
+            handler.emit(
+                Diagnostic::error(
+                    format!(
+                        "`@{}` can't be `#[interrupt]`, `#[naked]`, `#[no_mangle]` or `extern \"C\"`",
+                        name
+                    ),
+                    span,
+                )
+                .with_note("the generated entry calls it as an ordinary function"),
+            );
+        }
+        let tys: Vec<Ty> = sig.params.iter().map(|param| param.ty.clone()).collect();
+        if sig.generics.is_empty() && sig.ret == Ty::Unit && params.contains(&tys) {
+            continue;
+        }
+        let mut diagnostic = Diagnostic::error(
+            format!("`@{}` must be declared as `{}`", name, declaration),
+            span,
+        );
+        if name == target::ENTRY {
+            diagnostic = diagnostic.with_note(
+                "it may also take the Multiboot2 magic and boot information address as `int`s",
+            );
+        }
+        handler.emit(diagnostic);
+    }
+}
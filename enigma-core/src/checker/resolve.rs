@@ -0,0 +1,772 @@
+use super::items::{ItemTable, LookupError, join_path, lookup_error};
+use crate::errorhandler::{Diagnostic, ErrorHandler};
+use crate::lexer::size::Span;
+use crate::parser::ast::*;
+use std::collections::HashMap;
+
+/// Functions the checker provides without a declaration.
+pub const BUILTINS: &[&str] = &[
+    "print",
+    "exit",
+    "size_of",
+    "align_of",
+    "offset_of",
+    "volatile_read",
+    "volatile_write",
+    "inb",
+    "inw",
+    "inl",
+    "outb",
+    "outw",
+    "outl",
+    "fence",
+    "load_fence",
+    "store_fence",
+    "atomic_load",
+    "atomic_store",
+    "atomic_swap",
+    "atomic_compare_exchange",
+    "atomic_fetch_add",
+    "atomic_fetch_sub",
+];
+
+/// What a path in the program refers to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Res {
+    /// A `let`, parameter, `for` or pattern binding, by the id of its
+    /// declaration. Any further path segments are fields or a method.
+    Local(NodeId),
+    SelfValue,
+    Global(String),
+    Function(String),
+    Variant {
+        union: String,
+        variant: String,
+    },
+    AssocFn {
+        ty: String,
+        name: String,
+    },
+    Record(String),
+    Builtin(String),
+    /// Resolution failed and an error was reported.
+    Err,
+}
+
+/// Resolutions of path expressions, record literals and variant patterns,
+/// keyed by the node's id.
+#[derive(Debug, Default)]
+pub struct Resolutions {
+    pub paths: HashMap<NodeId, Res>,
+}
+
+impl Resolutions {
+    pub fn get(&self, id: NodeId) -> Option<&Res> {
+        self.paths.get(&id)
+    }
+
+    pub fn is_err(&self, id: NodeId) -> bool {
+        self.get(id) == Some(&Res::Err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScopeKind {
+    /// Top level statements of the module.
+    Module,
+    Impl,
+    /// Parameters; lookups don't see past it into the enclosing items.
+    Function,
+    /// Closure parameters; closures see the locals around them.
+    Closure,
+    Block,
+    For,
+    Arm,
+}
+
+struct Scope {
+    kind: ScopeKind,
+    names: HashMap<String, (Res, Span)>,
+}
+
+/// A global or function a body names, which must be initialized, or only
+/// read initialized globals, before the script gets to it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Use {
+    Global(String),
+    /// A free function or a method, by `Type::name`.
+    Function(String),
+}
+
+struct Resolver<'a> {
+    items: &'a ItemTable,
+    handler: &'a mut ErrorHandler,
+    scopes: Vec<Scope>,
+    resolutions: Resolutions,
+    /// The function or method whose body is being resolved; `None` in the
+    /// script.
+    owner: Option<String>,
+    /// The top level item being resolved.
+    item: usize,
+    /// What each function and method names.
+    uses: HashMap<String, Vec<Use>>,
+    /// What each top level statement names, and where.
+    script_uses: Vec<(usize, Use, Span)>,
+}
+
+/// Resolves every path in `program` against its lexical scopes and `items`.
+pub fn resolve(program: &Program, items: &ItemTable, handler: &mut ErrorHandler) -> Resolutions {
+    let mut resolver = Resolver {
+        items,
+        handler,
+        scopes: Vec::new(),
+        resolutions: Resolutions::default(),
+        owner: None,
+        item: 0,
+        uses: HashMap::new(),
+        script_uses: Vec::new(),
+    };
+    resolver.with_scope(ScopeKind::Module, |r| {
+        for (i, item) in program.items.iter().enumerate() {
+            r.item = i;
+            match &item.kind {
+                ItemKind::Function(function) => {
+                    r.owner = Some(items.canonical(&function.name.name));
+                    r.resolve_function(function);
+                    r.owner = None;
+                }
+                ItemKind::Impl(imp) => r.with_scope(ScopeKind::Impl, |r| {
+                    let ty = items.impl_target(&imp.target).map(|(ty, _)| ty);
+                    for method in &imp.methods {
+                        r.owner = ty
+                            .as_ref()
+                            .map(|ty| format!("{}::{}", ty, method.function.name.name));
+                        r.resolve_function(&method.function);
+                        r.owner = None;
+                    }
+                }),
+                // Top level `let`s are globals, found through the item table.
+                ItemKind::Stmt(Stmt {
+                    kind: StmtKind::Let { init, .. },
+                    ..
+                }) => r.resolve_expr(init),
+                ItemKind::Stmt(stmt) => r.resolve_stmt(stmt),
+                ItemKind::Record(_)
+                | ItemKind::Union(_)
+                | ItemKind::Protocol(_)
+                | ItemKind::Import(_) => {}
+            }
+        }
+    });
+    resolver.check_initialized(program);
+    resolver.resolutions
+}
+
+impl<'a> Resolver<'a> {
+    /// Globals are initialized by the script, in order, so it can't read
+    /// one before its declaration, directly or by calling a function that
+    /// does.
+    fn check_initialized(&mut self, program: &Program) {
+        let declared: HashMap<String, (usize, Span)> = program
+            .items
+            .iter()
+            .enumerate()
+            .filter_map(|(i, item)| match &item.kind {
+                ItemKind::Stmt(Stmt {
+                    kind: StmtKind::Let { binding, .. },
+                    ..
+                }) => Some((
+                    self.items.canonical(&binding.name.name),
+                    (i, binding.name.span),
+                )),
+                _ => None,
+            })
+            .collect();
+        for (item, used, span) in std::mem::take(&mut self.script_uses) {
+            let Some((global, reached)) = self.first_uninitialized(&used, item, &declared) else {
+                continue;
+            };
+            let (_, declaration) = declared[&global];
+            let message = match (&used, reached) {
+                (Use::Global(_), _) => {
+                    format!("`{}` is used before its declaration", short(&global))
+                }
+                (Use::Function(function), true) => format!(
+                    "`@{}` is called before `{}` is declared, and reads it",
+                    short(function),
+                    short(&global)
+                ),
+                (Use::Function(function), false) => format!(
+                    "`@{}` is called before `{}` is declared, and calls a function reading it",
+                    short(function),
+                    short(&global)
+                ),
+            };
+            self.handler.emit(
+                Diagnostic::error(message, span)
+                    .with_label(declaration, "declared here")
+                    .with_note("the top level runs in order, initializing globals as it goes"),
+            );
+        }
+    }
+
+    /// A global of this module that `used` reads, itself or through the
+    /// functions it calls, and that isn't initialized before the top level
+    /// item `item`. Also whether `used` reads it itself.
+    fn first_uninitialized(
+        &self,
+        used: &Use,
+        item: usize,
+        declared: &HashMap<String, (usize, Span)>,
+    ) -> Option<(String, bool)> {
+        let mut seen = vec![used.clone()];
+        let mut stack = vec![(used.clone(), true)];
+        while let Some((used, direct)) = stack.pop() {
+            match &used {
+                Use::Global(name) => {
+                    if declared.get(name).is_some_and(|(i, _)| *i >= item) {
+                        return Some((name.clone(), direct));
+                    }
+                }
+                Use::Function(name) => {
+                    for next in self.uses.get(name).into_iter().flatten() {
+                        if !seen.contains(next) {
+                            seen.push(next.clone());
+                            let reads = direct && matches!(next, Use::Global(_));
+                            stack.push((next.clone(), reads));
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Notes that the current body names what `res` resolved to.
+    fn note_use(&mut self, res: &Res, span: Span) {
+        let used = match res {
+            Res::Global(name) => Use::Global(name.clone()),
+            Res::Function(name) => Use::Function(name.clone()),
+            Res::AssocFn { ty, name } => Use::Function(format!("{}::{}", ty, name)),
+            _ => return,
+        };
+        match &self.owner {
+            Some(owner) => self.uses.entry(owner.clone()).or_default().push(used),
+            None => self.script_uses.push((self.item, used, span)),
+        }
+    }
+
+    fn with_scope(&mut self, kind: ScopeKind, f: impl FnOnce(&mut Self)) {
+        self.scopes.push(Scope {
+            kind,
+            names: HashMap::new(),
+        });
+        f(self);
+        self.scopes.pop();
+    }
+
+    /// Scopes a name can be found in, innermost first. Function scopes are
+    /// opaque: a body can't see the locals of the code around it.
+    fn visible_scopes(&self) -> impl Iterator<Item = &Scope> {
+        let boundary = self
+            .scopes
+            .iter()
+            .rposition(|scope| scope.kind == ScopeKind::Function)
+            .unwrap_or(0);
+        self.scopes[boundary..].iter().rev()
+    }
+
+    fn lookup_local(&self, name: &str) -> Option<&(Res, Span)> {
+        self.visible_scopes()
+            .find_map(|scope| scope.names.get(name))
+    }
+
+    /// Binds `name`. Rebinding in the same scope is allowed silently;
+    /// hiding a binding of an enclosing scope gets a warning, since it is
+    /// easy to mistake for an assignment.
+    fn declare(&mut self, name: &Ident, res: Res) {
+        if !name.name.starts_with('_') {
+            let outer = self
+                .visible_scopes()
+                .skip(1)
+                .find_map(|scope| scope.names.get(&name.name));
+            if let Some(&(_, previous)) = outer {
+                self.handler.emit(
+                    Diagnostic::warning(
+                        format!("`{}` shadows a binding from an outer scope", name.name),
+                        name.span,
+                    )
+                    .with_label(previous, "previously bound here"),
+                );
+            }
+        }
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.names.insert(name.name.clone(), (res, name.span));
+        }
+    }
+
+    fn resolve_function(&mut self, function: &Function) {
+        let Some(body) = &function.body else {
+            return;
+        };
+        self.with_scope(ScopeKind::Function, |r| {
+            if let Some(param) = &function.self_param {
+                let ident = Ident {
+                    name: "self".to_string(),
+                    span: param.span,
+                };
+                r.declare(&ident, Res::SelfValue);
+            }
+            for (i, param) in function.params.iter().enumerate() {
+                if let Some(first) = function.params[..i]
+                    .iter()
+                    .find(|p| p.name.name == param.name.name)
+                {
+                    r.handler.emit(
+                        Diagnostic::error(
+                            format!(
+                                "identifier `{}` is bound more than once in this parameter list",
+                                param.name.name
+                            ),
+                            param.name.span,
+                        )
+                        .with_label(first.name.span, "first bound here"),
+                    );
+                }
+                r.declare(&param.name, Res::Local(param.id));
+            }
+            match body {
+                FnBody::Block(block) => r.resolve_block(block),
+                FnBody::Inline(expr) => r.resolve_expr(expr),
+            }
+        });
+    }
+
+    fn resolve_block(&mut self, block: &Block) {
+        self.with_scope(ScopeKind::Block, |r| {
+            for stmt in &block.stmts {
+                r.resolve_stmt(stmt);
+            }
+        });
+    }
+
+    fn resolve_stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Let { binding, init } => {
+                // The initializer can't see the name it initializes.
+                self.resolve_expr(init);
+                self.declare(&binding.name, Res::Local(binding.id));
+            }
+            StmtKind::Destructure { bindings, init } => {
+                self.resolve_expr(init);
+                for binding in bindings {
+                    self.declare(&binding.name, Res::Local(binding.id));
+                }
+            }
+            StmtKind::Assign { target, value, .. } => {
+                self.resolve_expr(target);
+                self.resolve_expr(value);
+            }
+            StmtKind::Step { target, .. } => self.resolve_expr(target),
+            StmtKind::Expr(expr) => self.resolve_expr(expr),
+        }
+    }
+
+    fn resolve_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Literal(_) | ExprKind::Continue => {}
+            ExprKind::Asm(block) => {
+                for operand in &block.operands {
+                    self.resolve_expr(&operand.expr);
+                }
+            }
+            ExprKind::Path(segments) => {
+                let res = self.resolve_path(segments, expr.span);
+                self.note_use(&res, expr.span);
+                self.resolutions.paths.insert(expr.id, res);
+            }
+            ExprKind::Tuple(elems) => {
+                for elem in elems {
+                    self.resolve_expr(elem);
+                }
+            }
+            ExprKind::Unary { expr: inner, .. }
+            | ExprKind::Ref { expr: inner, .. }
+            | ExprKind::RawRef { expr: inner, .. }
+            | ExprKind::Deref(inner)
+            | ExprKind::Try(inner) => self.resolve_expr(inner),
+            ExprKind::Binary { lhs, rhs, .. } => {
+                self.resolve_expr(lhs);
+                self.resolve_expr(rhs);
+            }
+            ExprKind::Range { start, end } => {
+                self.resolve_expr(start);
+                self.resolve_expr(end);
+            }
+            ExprKind::Call { callee, args, .. } => {
+                self.resolve_expr(callee);
+                // `offset_of(record, field)` names a type and a field, not
+                // values.
+                if matches!(self.resolutions.paths.get(&callee.id),
+                    Some(Res::Builtin(name)) if name == "offset_of")
+                {
+                    return;
+                }
+                for arg in args {
+                    self.resolve_expr(&arg.value);
+                }
+            }
+            ExprKind::Field { base, .. } => self.resolve_expr(base),
+            ExprKind::RecordLit { path, fields, .. } => {
+                let res = self.resolve_record(path);
+                self.resolutions.paths.insert(expr.id, res);
+                for field in fields {
+                    self.resolve_expr(&field.value);
+                }
+            }
+            ExprKind::Block(block) | ExprKind::Loop(block) | ExprKind::Unsafe(block) => {
+                self.resolve_block(block)
+            }
+            ExprKind::If {
+                cond,
+                then_block,
+                else_branch,
+            } => {
+                self.resolve_expr(cond);
+                self.resolve_block(then_block);
+                if let Some(else_branch) = else_branch {
+                    self.resolve_expr(else_branch);
+                }
+            }
+            ExprKind::While { cond, body } => {
+                self.resolve_expr(cond);
+                self.resolve_block(body);
+            }
+            ExprKind::For {
+                binding,
+                binding_id,
+                iter,
+                body,
+            } => {
+                self.resolve_expr(iter);
+                self.with_scope(ScopeKind::For, |r| {
+                    r.declare(binding, Res::Local(*binding_id));
+                    r.resolve_block(body);
+                });
+            }
+            ExprKind::Match { scrutinee, arms } => {
+                self.resolve_expr(scrutinee);
+                for arm in arms {
+                    self.with_scope(ScopeKind::Arm, |r| {
+                        r.resolve_pattern(&arm.pattern);
+                        r.resolve_expr(&arm.body);
+                    });
+                }
+            }
+            ExprKind::Closure(closure) => self.with_scope(ScopeKind::Closure, |r| {
+                for param in &closure.params {
+                    r.declare(&param.name, Res::Local(param.id));
+                }
+                r.resolve_expr(&closure.body);
+            }),
+            ExprKind::Break(value) | ExprKind::Return(value) => {
+                if let Some(value) = value {
+                    self.resolve_expr(value);
+                }
+            }
+        }
+    }
+
+    fn resolve_pattern(&mut self, pattern: &Pattern) {
+        match &pattern.kind {
+            PatternKind::Wildcard | PatternKind::Literal(_) => {}
+            PatternKind::Binding(name) => {
+                // A bare variant name (`None`) is checked against the
+                // scrutinee's type instead of binding anything.
+                let is_variant = self
+                    .items
+                    .unions
+                    .values()
+                    .any(|union| union.variant(&name.name).is_some());
+                if !is_variant {
+                    self.declare(name, Res::Local(pattern.id));
+                }
+            }
+            PatternKind::Tuple(elems) => {
+                for elem in elems {
+                    self.resolve_pattern(elem);
+                }
+            }
+            PatternKind::Variant { path, fields } => {
+                // `Some(x)` is resolved against the scrutinee by the type
+                // checker; qualified paths are resolved here.
+                if path.len() >= 2 {
+                    let span = path[0].span.to(path.last().unwrap().span);
+                    let res = match self.resolve_member(path, span) {
+                        Ok(res @ Res::Variant { .. }) => res,
+                        Ok(_) => {
+                            self.handler.emit(Diagnostic::error(
+                                format!("expected a union variant, found `{}`", join_path(path)),
+                                span,
+                            ));
+                            Res::Err
+                        }
+                        Err(diagnostic) => {
+                            self.handler.emit(diagnostic);
+                            Res::Err
+                        }
+                    };
+                    self.resolutions.paths.insert(pattern.id, res);
+                }
+                for field in fields {
+                    self.resolve_pattern(field);
+                }
+            }
+        }
+    }
+
+    fn resolve_path(&mut self, segments: &[Ident], span: Span) -> Res {
+        let first = &segments[0];
+        if let Some((res, _)) = self.lookup_local(&first.name) {
+            return res.clone();
+        }
+        // `g::field` or `g::method` on a global.
+        if segments.len() >= 2
+            && let Ok(name) = self.items.lookup_value(&first.name)
+            && self.items.globals.contains_key(name)
+        {
+            return Res::Global(name.to_string());
+        }
+        if segments.len() == 1 && BUILTINS.contains(&first.name.as_str()) {
+            return Res::Builtin(first.name.clone());
+        }
+        match self.items.lookup_value(&join_path(segments)) {
+            Ok(name) if self.items.globals.contains_key(name) => {
+                return Res::Global(name.to_string());
+            }
+            Ok(name) => return Res::Function(name.to_string()),
+            Err(err @ LookupError::Private { .. }) => {
+                self.handler
+                    .emit(lookup_error("value", &join_path(segments), err, span));
+                return Res::Err;
+            }
+            Err(LookupError::Missing) => {}
+        }
+        if segments.len() >= 2 {
+            let type_path = join_path(&segments[..segments.len() - 1]);
+            if !matches!(
+                self.items.lookup_type(&type_path),
+                Err(LookupError::Missing)
+            ) {
+                return match self.resolve_member(segments, span) {
+                    Ok(res) => res,
+                    Err(diagnostic) => {
+                        self.handler.emit(diagnostic);
+                        Res::Err
+                    }
+                };
+            }
+        }
+        self.unresolved(segments, span);
+        Res::Err
+    }
+
+    /// `Type::member`: a union variant or an associated function.
+    fn resolve_member(&self, segments: &[Ident], span: Span) -> Result<Res, Diagnostic> {
+        let (member, type_path) = segments.split_last().unwrap();
+        let written = join_path(type_path);
+        let ty = match self.items.lookup_type(&written) {
+            Ok(ty) => ty.to_string(),
+            Err(err) => {
+                let diagnostic = lookup_error("type", &written, err, span);
+                let candidates = self.items.types.keys().map(String::as_str);
+                return Err(with_suggestion(
+                    diagnostic,
+                    &written,
+                    candidates,
+                    type_path[0].span.to(type_path.last().unwrap().span),
+                ));
+            }
+        };
+        if let Some(union) = self.items.unions.get(&ty)
+            && union.variant(&member.name).is_some()
+        {
+            return Ok(Res::Variant {
+                union: ty,
+                variant: member.name.clone(),
+            });
+        }
+        if let Some(sig) = self.items.method(&ty, &member.name) {
+            if !sig.is_pub && sig.module != self.items.module {
+                return Err(lookup_error(
+                    "function",
+                    &join_path(segments),
+                    LookupError::Private {
+                        module: sig.module.clone(),
+                    },
+                    member.span,
+                ));
+            }
+            return Ok(Res::AssocFn {
+                ty,
+                name: member.name.clone(),
+            });
+        }
+        let mut candidates: Vec<&str> = self
+            .items
+            .methods
+            .get(&ty)
+            .map(|methods| methods.keys().map(String::as_str).collect())
+            .unwrap_or_default();
+        if let Some(union) = self.items.unions.get(&ty) {
+            candidates.extend(union.variants.iter().map(|(name, _)| name.as_str()));
+        }
+        Err(with_suggestion(
+            Diagnostic::error(
+                format!(
+                    "no variant or associated function named `{}` in `{}`",
+                    member.name, written
+                ),
+                member.span,
+            ),
+            &member.name,
+            candidates.into_iter(),
+            member.span,
+        ))
+    }
+
+    fn unresolved(&mut self, segments: &[Ident], span: Span) {
+        let written = join_path(segments);
+        let (kind, candidates): (&str, Vec<String>) = if segments.len() == 1 {
+            let locals = self
+                .visible_scopes()
+                .flat_map(|scope| scope.names.keys().cloned());
+            let items = self.visible_values();
+            let builtins = BUILTINS.iter().map(|b| b.to_string());
+            ("value", locals.chain(items).chain(builtins).collect())
+        } else {
+            // A misspelled type or member: offer every qualified name.
+            let mut candidates = self.visible_values();
+            for (name, visible) in &self.items.types {
+                if let Some(union) = self.items.unions.get(&visible.canonical) {
+                    candidates.extend(
+                        union
+                            .variants
+                            .iter()
+                            .map(|(v, _)| format!("{}::{}", name, v)),
+                    );
+                }
+                if let Some(methods) = self.items.methods.get(&visible.canonical) {
+                    candidates.extend(methods.keys().map(|m| format!("{}::{}", name, m)));
+                }
+            }
+            ("item", candidates)
+        };
+        let diagnostic = lookup_error(kind, &written, LookupError::Missing, span);
+        let diagnostic = with_suggestion(
+            diagnostic,
+            &written,
+            candidates.iter().map(String::as_str),
+            span,
+        );
+        self.handler.emit(diagnostic);
+    }
+
+    /// Functions and globals this module may name.
+    fn visible_values(&self) -> Vec<String> {
+        self.items
+            .values
+            .iter()
+            .filter(|(_, v)| v.is_pub || v.module == self.items.module)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    fn resolve_record(&mut self, path: &[Ident]) -> Res {
+        let written = join_path(path);
+        let span = path[0].span.to(path.last().unwrap().span);
+        match self.items.lookup_type(&written) {
+            Ok(name) if self.items.records.contains_key(name) => Res::Record(name.to_string()),
+            Err(err @ LookupError::Private { .. }) => {
+                self.handler
+                    .emit(lookup_error("record", &written, err, span));
+                Res::Err
+            }
+            _ => {
+                let records = self
+                    .items
+                    .types
+                    .iter()
+                    .filter(|(_, v)| self.items.records.contains_key(&v.canonical))
+                    .map(|(name, _)| name.as_str());
+                let diagnostic = Diagnostic::error(
+                    format!("cannot find record `{}` in this scope", written),
+                    span,
+                );
+                self.handler
+                    .emit(with_suggestion(diagnostic, &written, records, span));
+                Res::Err
+            }
+        }
+    }
+}
+
+/// The last segment of a canonical name.
+fn short(name: &str) -> &str {
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+/// Adds a "did you mean" fix-it when one of `candidates` is close to `name`.
+pub fn with_suggestion<'c>(
+    diagnostic: Diagnostic,
+    name: &str,
+    candidates: impl Iterator<Item = &'c str>,
+    span: Span,
+) -> Diagnostic {
+    match similar_name(name, candidates) {
+        Some(similar) => {
+            let message = format!("did you mean `{}`?", similar);
+            diagnostic.with_fixit(span, similar, message)
+        }
+        None => diagnostic,
+    }
+}
+
+/// The closest candidate within a third of `name`'s length (at least one
+/// edit). Ties go to the alphabetically first name so output is stable.
+pub fn similar_name<'c>(name: &str, candidates: impl Iterator<Item = &'c str>) -> Option<String> {
+    let limit = (name.chars().count() / 3).max(1);
+    candidates
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .min()
+        .map(|(_, candidate)| candidate.to_string())
+}
+
+/// Edit distance counting insertions, deletions, substitutions and swaps of
+/// adjacent chars (optimal string alignment), so `conut` is one edit from
+/// `count`.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
@@ -0,0 +1,3462 @@
+use super::infer::InferCtxt;
+use super::items::{
+    FnSig, ItemTable, LookupError, ParamSig, generic_names, is_thread_marker, join_path,
+    lookup_error,
+};
+use super::resolve::{Res, Resolutions};
+use super::types::Ty;
+use super::{Callee, Coercion, FnOwner, FnRef, TypeckResults};
+use crate::codegen::layout;
+use crate::errorhandler::{Diagnostic, ErrorHandler};
+use crate::lexer::size::Span;
+use crate::lexer::tokens::Literal;
+use crate::parser::ast::*;
+use crate::prelude;
+use crate::target::Target;
+use std::collections::HashMap;
+
+const PORT_IO: &str = "port I/O talks to devices directly";
+
+#[derive(Debug, Clone)]
+struct Local {
+    ty: Ty,
+    mutable: bool,
+    fixes: MutFixes,
+}
+
+/// Edits to a declaration that would make the binding, or the reference
+/// it holds, mutable. Offered when an assignment is rejected.
+#[derive(Debug, Clone, Default)]
+struct MutFixes {
+    binding: Option<(Span, String)>,
+    reference: Option<(Span, String)>,
+}
+
+impl MutFixes {
+    /// For `ty name`: `mut` goes in front of the type, and a `ref T` type
+    /// becomes `ref mut T`.
+    fn declared(ty: Option<&TypeExpr>, start: Span) -> Self {
+        let reference = match ty.map(|ty| &ty.kind) {
+            Some(TypeExprKind::Ref {
+                mutable: false,
+                inner,
+            }) => Some((
+                Span::new(inner.span.start, inner.span.start),
+                "mut ".to_string(),
+            )),
+            _ => None,
+        };
+        MutFixes {
+            binding: Some((Span::new(start.start, start.start), "mut ".to_string())),
+            reference,
+        }
+    }
+
+    fn self_param(param: &SelfParam) -> Self {
+        let mut fixes = MutFixes::default();
+        match param.kind {
+            SelfKind::Value => {
+                fixes.binding = Some((Span::new(param.span.start, param.span.start), "mut ".into()))
+            }
+            SelfKind::Ref => fixes.reference = Some((param.span, "ref mut self".into())),
+            SelfKind::MutValue | SelfKind::RefMut => {}
+        }
+        fixes
+    }
+}
+
+/// Why a place can't be assigned to or borrowed mutably.
+enum Immutable {
+    /// The place is, or is part of, a binding not declared `mut`.
+    Binding {
+        name: String,
+        fix: Option<(Span, String)>,
+    },
+    /// The place is reached through a `ref` (not `ref mut`) reference.
+    Ref { fix: Option<(Span, String)> },
+}
+
+/// A place written as an expression, or as the receiver part of a method
+/// path like `h::cough`.
+#[derive(Clone, Copy)]
+enum PlaceExpr<'p> {
+    Expr(&'p Expr),
+    /// The receiver segments of the method path with this id.
+    Path(NodeId, &'p [Ident]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LoopKind {
+    Loop,
+    While,
+    For,
+}
+
+impl LoopKind {
+    fn keyword(self) -> &'static str {
+        match self {
+            LoopKind::Loop => "loop",
+            LoopKind::While => "while",
+            LoopKind::For => "for",
+        }
+    }
+}
+
+struct LoopCtx {
+    kind: LoopKind,
+    span: Span,
+    expected: Option<Ty>,
+    /// Type of the first `break` value seen, and where it was.
+    break_ty: Option<(Ty, Span)>,
+}
+
+struct FnCtx {
+    ret: Ty,
+    /// The body of an `unsafe @fn`, where unsafe operations are allowed
+    /// without an `unsafe` block.
+    is_unsafe: bool,
+}
+
+/// An inference variable that must be solved by the end of checking.
+struct Pending {
+    var: Ty,
+    what: String,
+    span: Span,
+}
+
+/// A generic argument that must implement a protocol, from a bound like
+/// `[T: live]` on the called function.
+struct Obligation {
+    ty: Ty,
+    protocol: String,
+    generic: String,
+    function: String,
+    span: Span,
+}
+
+/// The function a call invokes, as far as name lookup got.
+struct CallTarget {
+    func: FnRef,
+    self_ty: Option<Ty>,
+    /// Generic arguments of the implemented type, e.g. `int` in a call on an
+    /// `Option[int]`.
+    impl_args: Vec<Ty>,
+}
+
+impl CallTarget {
+    fn free(name: String) -> Self {
+        Self {
+            func: FnRef {
+                owner: FnOwner::Free,
+                name,
+            },
+            self_ty: None,
+            impl_args: Vec::new(),
+        }
+    }
+
+    fn method(type_name: &str, name: &str, impl_args: Vec<Ty>) -> Self {
+        Self {
+            func: FnRef {
+                owner: FnOwner::Type(type_name.to_string()),
+                name: name.to_string(),
+            },
+            self_ty: None,
+            impl_args,
+        }
+    }
+}
+
+/// A path that names something declared at module level.
+enum Resolved<'p> {
+    /// Canonical name of a function.
+    Value(String),
+    /// A variant or associated function of the type with this canonical name.
+    Member(String, &'p Ident),
+}
+
+pub(super) struct TypeChecker<'a> {
+    items: &'a ItemTable,
+    /// What each path names. Paths the resolver couldn't resolve are
+    /// already reported; they check as `{unknown}` without further errors.
+    resolutions: &'a Resolutions,
+    handler: &'a mut ErrorHandler,
+    results: TypeckResults,
+    /// Bindings by the id of their declaration, which the resolver
+    /// resolved their uses to.
+    locals: HashMap<NodeId, Local>,
+    /// `self` in the method being checked.
+    self_local: Option<Local>,
+    /// Edits making this module's globals mutable, by canonical name.
+    global_fixes: HashMap<String, MutFixes>,
+    loops: Vec<LoopCtx>,
+    function: Option<FnCtx>,
+    generics: Vec<String>,
+    /// Protocols each generic parameter in scope is bounded by.
+    bounds: HashMap<String, Vec<String>>,
+    /// The function whose body is being checked.
+    current: Option<FnRef>,
+    infcx: InferCtxt,
+    pending: Vec<Pending>,
+    obligations: Vec<Obligation>,
+    /// Enclosing `unsafe` blocks, innermost last, and whether each contains
+    /// an unsafe operation yet.
+    unsafe_blocks: Vec<bool>,
+    /// Freestanding programs have no C library to print, exit or allocate
+    /// with.
+    target: Target,
+}
+
+impl<'a> TypeChecker<'a> {
+    pub(super) fn new(
+        items: &'a ItemTable,
+        resolutions: &'a Resolutions,
+        target: Target,
+        handler: &'a mut ErrorHandler,
+    ) -> Self {
+        Self {
+            items,
+            resolutions,
+            target,
+            handler,
+            results: TypeckResults::default(),
+            locals: HashMap::new(),
+            self_local: None,
+            global_fixes: HashMap::new(),
+            loops: Vec::new(),
+            function: None,
+            generics: Vec::new(),
+            bounds: HashMap::new(),
+            current: None,
+            infcx: InferCtxt::default(),
+            pending: Vec::new(),
+            obligations: Vec::new(),
+            unsafe_blocks: Vec::new(),
+        }
+    }
+
+    /// Reports variables inference couldn't solve and writes the final
+    /// types into the results; anything still unknown becomes `{unknown}`.
+    pub(super) fn finish(mut self) -> TypeckResults {
+        let obligations = std::mem::take(&mut self.obligations);
+        self.check_obligations(obligations);
+        for pending in std::mem::take(&mut self.pending) {
+            if let Ty::Infer(_) = self.infcx.shallow(&pending.var) {
+                self.handler.emit(
+                    Diagnostic::error(
+                        format!("type annotations needed: cannot infer {}", pending.what),
+                        pending.span,
+                    )
+                    .with_note("give the type explicitly, e.g. `f[int](..)`"),
+                );
+                self.infcx.unify(&pending.var, &Ty::Error);
+            }
+        }
+        let infcx = &self.infcx;
+        for ty in self
+            .results
+            .expr_types
+            .values_mut()
+            .chain(self.results.binding_types.values_mut())
+        {
+            *ty = without_vars(&infcx.resolve(ty));
+        }
+        for callee in self.results.callees.values_mut() {
+            for arg in &mut callee.args {
+                *arg = without_vars(&infcx.resolve(arg));
+            }
+            if let Some(ty) = &mut callee.self_ty {
+                *ty = without_vars(&infcx.resolve(ty));
+            }
+        }
+        for coercion in self.results.coercions.values_mut() {
+            coercion.ty = without_vars(&infcx.resolve(&coercion.ty));
+        }
+        self.results
+    }
+
+    fn fresh_var(&mut self, what: String, span: Span) -> Ty {
+        let var = self.infcx.fresh();
+        self.pending.push(Pending {
+            var: var.clone(),
+            what,
+            span,
+        });
+        var
+    }
+
+    fn error(&mut self, diagnostic: Diagnostic) {
+        self.handler.emit(diagnostic);
+    }
+
+    pub(super) fn check_program(&mut self, program: &Program) {
+        for item in &program.items {
+            if let ItemKind::Stmt(Stmt {
+                kind: StmtKind::Let { binding, .. },
+                ..
+            }) = &item.kind
+            {
+                let fixes = MutFixes::declared(Some(&binding.ty), binding.ty.span);
+                let name = self.items.canonical(&binding.name.name);
+                self.global_fixes.insert(name, fixes);
+            }
+        }
+        for item in &program.items {
+            match &item.kind {
+                ItemKind::Function(function) => {
+                    let func = FnRef {
+                        owner: FnOwner::Free,
+                        name: self.items.canonical(&function.name.name),
+                    };
+                    self.check_function(function, func, &[], None)
+                }
+                ItemKind::Impl(imp) => self.check_impl(imp),
+                ItemKind::Stmt(stmt) => {
+                    if self.check_semi(stmt).is_none() {
+                        self.check_stmt(stmt);
+                    }
+                }
+                ItemKind::Record(_)
+                | ItemKind::Union(_)
+                | ItemKind::Protocol(_)
+                | ItemKind::Import(_) => {}
+            }
+        }
+    }
+
+    fn check_impl(&mut self, imp: &Impl) {
+        let Some((type_name, generics)) = self.items.impl_target(&imp.target) else {
+            return;
+        };
+        let mut scratch = ErrorHandler::new();
+        let self_ty = self.items.lower(&imp.target, &generics, &mut scratch);
+        if let Some(protocol) = &imp.protocol
+            && let Ok(protocol) = self.items.lookup_type(&protocol.name)
+            && let Some(required) = self.items.protocols.get(protocol)
+        {
+            let args: Vec<Ty> = imp
+                .protocol_args
+                .iter()
+                .map(|t| self.items.lower(t, &generics, &mut scratch))
+                .collect();
+            // A wrong number of arguments was reported with the items.
+            if self
+                .items
+                .protocol_generics
+                .get(protocol)
+                .map_or(0, Vec::len)
+                == args.len()
+            {
+                let required: Vec<FnSig> = required
+                    .iter()
+                    .map(|sig| sig.with_impl_args(&args))
+                    .collect();
+                self.check_conformance(imp, &type_name, protocol, &required);
+            }
+        }
+        for method in &imp.methods {
+            let func = FnRef {
+                owner: FnOwner::Type(type_name.clone()),
+                name: method.function.name.name.clone(),
+            };
+            self.check_function(&method.function, func, &generics, Some(&self_ty));
+        }
+    }
+
+    /// An implementation of a protocol provides exactly its methods, with
+    /// the same signatures, so every slot of the type's vtable is filled.
+    fn check_conformance(
+        &mut self,
+        imp: &Impl,
+        type_name: &str,
+        protocol: &str,
+        required: &[FnSig],
+    ) {
+        for method in &imp.methods {
+            let name = &method.function.name;
+            let Some(expected) = required.iter().find(|sig| sig.name == name.name) else {
+                self.error(Diagnostic::error(
+                    format!(
+                        "method `{}` is not a member of protocol `{}`",
+                        name.name, protocol
+                    ),
+                    name.span,
+                ));
+                continue;
+            };
+            let Some(found) = self.items.method(type_name, &name.name) else {
+                continue;
+            };
+            if !found.matches(expected) {
+                self.error(
+                    Diagnostic::error(
+                        format!(
+                            "method `{}` has an incompatible signature for protocol `{}`",
+                            name.name, protocol
+                        ),
+                        name.span,
+                    )
+                    .with_note(format!(
+                        "expected `{}`, found `{}`",
+                        expected.describe(),
+                        found.describe()
+                    )),
+                );
+            }
+        }
+        let missing: Vec<String> = required
+            .iter()
+            .filter(|sig| imp.methods.iter().all(|m| m.function.name.name != sig.name))
+            .map(|sig| format!("`{}`", sig.name))
+            .collect();
+        if !missing.is_empty() {
+            self.error(Diagnostic::error(
+                format!(
+                    "not all methods of protocol `{}` are implemented for `{}`: missing {}",
+                    protocol,
+                    type_name,
+                    missing.join(", ")
+                ),
+                imp.target.span,
+            ));
+        }
+    }
+
+    fn check_function(
+        &mut self,
+        function: &Function,
+        func: FnRef,
+        outer: &[String],
+        self_ty: Option<&Ty>,
+    ) {
+        let Some(body) = &function.body else {
+            return;
+        };
+        let mut generics = outer.to_vec();
+        generics.extend(generic_names(&function.generics));
+        // Signature problems were already reported while collecting items.
+        let sig = self
+            .items
+            .signature(function, outer, true, &mut ErrorHandler::new());
+
+        let bounds = sig
+            .generics
+            .iter()
+            .cloned()
+            .zip(sig.bounds.clone())
+            .collect();
+        let saved_generics = std::mem::replace(&mut self.generics, generics);
+        let saved_bounds = std::mem::replace(&mut self.bounds, bounds);
+        let saved_current = self.current.replace(func);
+        let saved_obligations = std::mem::take(&mut self.obligations);
+        let saved_loops = std::mem::take(&mut self.loops);
+        let saved_fn = self.function.replace(FnCtx {
+            ret: sig.ret.clone(),
+            is_unsafe: sig.is_unsafe,
+        });
+        let saved_self = self.self_local.take();
+
+        if let (Some(param), Some(self_ty)) = (&function.self_param, self_ty) {
+            let ty = match param.kind {
+                SelfKind::Value | SelfKind::MutValue => self_ty.clone(),
+                SelfKind::Ref | SelfKind::RefMut => Ty::Ref {
+                    mutable: param.kind == SelfKind::RefMut,
+                    inner: Box::new(self_ty.clone()),
+                },
+            };
+            self.declare(
+                None,
+                ty,
+                matches!(param.kind, SelfKind::MutValue | SelfKind::RefMut),
+                MutFixes::self_param(param),
+            );
+        }
+        for (param, param_sig) in function.params.iter().zip(&sig.params) {
+            self.declare(
+                Some(param.id),
+                param_sig.ty.clone(),
+                param.mutable,
+                MutFixes::declared(Some(&param.ty), param.ty.span),
+            );
+        }
+
+        match body {
+            FnBody::Block(block) if sig.ret == Ty::Unit => {
+                // A unit function discards whatever its last expression produces.
+                self.check_block(block, None);
+            }
+            FnBody::Block(block) => {
+                let found = self.check_block(block, Some(&sig.ret));
+                let span = block.tail().map_or(block.span, |tail| tail.span);
+                self.demand(&found, &sig.ret, span, block_ret_note(function));
+            }
+            FnBody::Inline(expr) => {
+                self.check_expr_against(expr, &sig.ret);
+            }
+        }
+        if function.attrs.iter().any(|attr| attr.name.name == "naked") {
+            self.check_naked(function, body);
+        }
+
+        // Bounds on this function's parameters only hold inside it, so its
+        // obligations are settled before leaving.
+        let obligations = std::mem::replace(&mut self.obligations, saved_obligations);
+        self.check_obligations(obligations);
+
+        self.self_local = saved_self;
+        self.function = saved_fn;
+        self.loops = saved_loops;
+        self.generics = saved_generics;
+        self.bounds = saved_bounds;
+        self.current = saved_current;
+    }
+
+    /// A naked function has no prologue to give other code a frame, so its
+    /// body is assembly alone, which must return by itself.
+    fn check_naked(&mut self, function: &Function, body: &FnBody) {
+        let mut blocks = Vec::new();
+        let only_asm = match body {
+            FnBody::Block(block) => naked_asm(&block.stmts, &mut blocks),
+            FnBody::Inline(expr) => Err(expr.span),
+        };
+        if let Err(span) = only_asm {
+            self.error(
+                Diagnostic::error("a `#[naked]` function can only contain `asm` blocks", span)
+                    .with_note("it has no frame for other code to use"),
+            );
+            return;
+        }
+        for (asm, span) in &blocks {
+            if !asm.operands.is_empty() {
+                self.error(
+                    Diagnostic::error(
+                        "assembly in a `#[naked]` function can't have operands",
+                        *span,
+                    )
+                    .with_note(
+                        "the arguments are in the registers the calling convention passes them in",
+                    ),
+                );
+            }
+        }
+        if !blocks.last().is_some_and(|(asm, _)| asm.options.noreturn) {
+            self.error(
+                Diagnostic::error(
+                    "the assembly of a `#[naked]` function must end with `options(noreturn)`",
+                    function.name.span,
+                )
+                .with_note("without an epilogue, it must return by itself, e.g. with `ret`"),
+            );
+        }
+    }
+
+    /// Whether `ty` is `send` or `share` (`marker`) where a generic
+    /// parameter only is if it is bounded by it.
+    fn is_thread_safe(&self, ty: &Ty, marker: &str) -> bool {
+        let bound = |name: &str, marker: &str| {
+            self.bounds
+                .get(name)
+                .is_some_and(|bounds| bounds.iter().any(|bound| bound == marker))
+        };
+        self.items.is_thread_safe(ty, marker, &bound)
+    }
+
+    /// Reports generic arguments that don't implement a protocol their
+    /// parameter is bounded by. Arguments inference couldn't solve are
+    /// reported on their own.
+    fn check_obligations(&mut self, obligations: Vec<Obligation>) {
+        for obligation in obligations {
+            let ty = self.infcx.resolve(&obligation.ty);
+            let holds = match &ty {
+                Ty::Infer(_) | Ty::Error => true,
+                _ if is_thread_marker(&obligation.protocol) => {
+                    self.is_thread_safe(&ty, &obligation.protocol)
+                }
+                Ty::Adt { name, .. } => self.items.implements(name, &obligation.protocol),
+                Ty::Param(name) => self
+                    .bounds
+                    .get(name)
+                    .is_some_and(|bounds| bounds.contains(&obligation.protocol)),
+                _ => false,
+            };
+            if !holds {
+                self.error(
+                    Diagnostic::error(
+                        format!(
+                            "the type `{}` does not implement protocol `{}`",
+                            ty, obligation.protocol
+                        ),
+                        obligation.span,
+                    )
+                    .with_note(format!(
+                        "required by the bound `{}: {}` on `{}`",
+                        obligation.generic, obligation.protocol, obligation.function
+                    )),
+                );
+            }
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Scopes and type helpers
+    // ---------------------------------------------------------------------
+
+    /// Records a binding's type. `id` is its declaration's node, absent
+    /// only for `self`.
+    fn declare(&mut self, id: Option<NodeId>, ty: Ty, mutable: bool, fixes: MutFixes) {
+        let local = Local { ty, mutable, fixes };
+        match id {
+            Some(id) => {
+                self.results.binding_types.insert(id, local.ty.clone());
+                self.locals.insert(id, local);
+            }
+            None => self.self_local = Some(local),
+        }
+    }
+
+    /// The binding the path expression `id` starts with, if the resolver
+    /// found one.
+    fn lookup(&self, id: NodeId) -> Option<&Local> {
+        match self.resolutions.get(id)? {
+            Res::Local(decl) => self.locals.get(decl),
+            Res::SelfValue => self.self_local.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Whether a callee path is a method of the binding or global it
+    /// starts with, like `h::cough`.
+    fn is_method_path(&self, id: NodeId, segments: &[Ident]) -> bool {
+        match self.resolutions.get(id) {
+            Some(Res::Local(_) | Res::SelfValue) => segments.len() >= 2,
+            Some(Res::Global(name)) => self.global_segments(name, segments) < segments.len(),
+            _ => false,
+        }
+    }
+
+    /// How many leading segments of a path resolved to the global `name`
+    /// name it: `g::field` names `g` by its first, `io::count` by both.
+    fn global_segments(&self, name: &str, segments: &[Ident]) -> usize {
+        if self.items.lookup_value(&segments[0].name).ok() == Some(name) {
+            1
+        } else {
+            segments.len()
+        }
+    }
+
+    fn lower(&mut self, ty: &TypeExpr) -> Ty {
+        self.items.check_objects(ty, self.handler);
+        self.items.lower(ty, &self.generics, self.handler)
+    }
+
+    fn record(&mut self, id: NodeId, ty: &Ty) {
+        self.results.expr_types.insert(id, ty.clone());
+    }
+
+    /// Reports a mismatch unless `found` can be used where `expected` is
+    /// required. Returns the type the expression should be treated as.
+    fn demand(&mut self, found: &Ty, expected: &Ty, span: Span, note: Option<String>) -> Ty {
+        if self.infcx.unify(found, expected) {
+            return if found == &Ty::Never {
+                Ty::Never
+            } else {
+                self.infcx.resolve(expected)
+            };
+        }
+        let mut diagnostic = Diagnostic::error(
+            format!(
+                "mismatched types: expected `{}`, found `{}`",
+                self.infcx.resolve(expected),
+                self.infcx.resolve(found)
+            ),
+            span,
+        );
+        if let Some(note) = note {
+            diagnostic = diagnostic.with_note(note);
+        }
+        self.error(diagnostic);
+        Ty::Error
+    }
+
+    fn check_expr_against(&mut self, expr: &Expr, expected: &Ty) -> Ty {
+        let found = self.check_expr(expr, Some(expected));
+        if let Some(ty) = self.coerce_to_object(expr, &found, expected) {
+            return ty;
+        }
+        self.demand(&found, expected, expr.span, None)
+    }
+
+    /// Converts `ref T` to `ref protocol` when `T` implements the protocol,
+    /// recording the conversion so `T`'s vtable gets built. Returns `None`
+    /// when `expected` isn't a protocol object or `found` is one already.
+    fn coerce_to_object(&mut self, expr: &Expr, found: &Ty, expected: &Ty) -> Option<Ty> {
+        let Ty::Ref {
+            mutable: want_mut,
+            inner: target,
+        } = self.infcx.resolve(expected)
+        else {
+            return None;
+        };
+        let Ty::Dyn(protocol) = *target else {
+            return None;
+        };
+        let Ty::Ref { mutable, inner } = self.infcx.resolve(found) else {
+            return None;
+        };
+        if !mutable && want_mut {
+            return None;
+        }
+        let holds = match inner.as_ref() {
+            Ty::Dyn(_) | Ty::Infer(_) => return None,
+            Ty::Error => return Some(Ty::Error),
+            inner if is_thread_marker(&protocol) => self.is_thread_safe(inner, &protocol),
+            Ty::Adt { name, .. } => self.items.implements(name, &protocol),
+            Ty::Param(name) => self
+                .bounds
+                .get(name)
+                .is_some_and(|bounds| bounds.contains(&protocol)),
+            _ => false,
+        };
+        if !holds {
+            self.error(Diagnostic::error(
+                format!(
+                    "the type `{}` does not implement protocol `{}`",
+                    inner, protocol
+                ),
+                expr.span,
+            ));
+            return Some(Ty::Error);
+        }
+        // Reported where the `ref protocol` is written.
+        if !self.items.object_safety_violations(&protocol).is_empty() {
+            return Some(Ty::Error);
+        }
+        self.results.coercions.insert(
+            expr.id,
+            Coercion {
+                ty: *inner,
+                protocol: protocol.clone(),
+                caller: self.current.clone(),
+                span: expr.span,
+            },
+        );
+        Some(Ty::Ref {
+            mutable: want_mut,
+            inner: Box::new(Ty::Dyn(protocol)),
+        })
+    }
+
+    // ---------------------------------------------------------------------
+    // Statements and blocks
+    // ---------------------------------------------------------------------
+
+    /// A block's type is the type of its trailing expression. Blocks without
+    /// one are `unit`, or `never` if some statement always diverges.
+    fn check_block(&mut self, block: &Block, expected: Option<&Ty>) -> Ty {
+        let mut diverges = false;
+        let mut ty = Ty::Unit;
+        let last = block.stmts.len().saturating_sub(1);
+        for (i, stmt) in block.stmts.iter().enumerate() {
+            let stmt_ty = match (self.check_semi(stmt), &stmt.kind) {
+                (Some(ty), _) => ty,
+                (None, StmtKind::Expr(expr)) if i == last => self.check_expr(expr, expected),
+                (None, _) => self.check_stmt(stmt),
+            };
+            if stmt_ty == Ty::Never {
+                diverges = true;
+            }
+            ty = match &stmt.kind {
+                StmtKind::Expr(_) if i == last => stmt_ty,
+                _ => Ty::Unit,
+            };
+        }
+        if diverges && ty == Ty::Unit {
+            ty = Ty::Never;
+        }
+        self.record(block.id, &ty);
+        ty
+    }
+
+    /// `val;` is shorthand for `return val`; a `;` anywhere else is an error.
+    /// Returns the statement's type when it had to be checked here instead of
+    /// by the caller.
+    fn check_semi(&mut self, stmt: &Stmt) -> Option<Ty> {
+        let semi = stmt.semi?;
+        let remove = |d: Diagnostic| d.with_fixit(semi, "", "remove the `;`");
+        match &stmt.kind {
+            // Desugared by the parser: the `return` node ends at the `;`.
+            StmtKind::Expr(Expr {
+                kind: ExprKind::Return(Some(value)),
+                span,
+                ..
+            }) if span.end == semi.end => {
+                if self.function.is_some() {
+                    return None;
+                }
+                self.error(remove(
+                    Diagnostic::error(
+                        "`;` returns from a function, but this code is not inside one",
+                        semi,
+                    )
+                    .with_note("`val;` is shorthand for `return val`"),
+                ));
+                Some(self.check_expr(value, None))
+            }
+            StmtKind::Expr(Expr { kind, .. }) => {
+                let keyword = match kind {
+                    ExprKind::Break(_) => "break",
+                    ExprKind::Continue => "continue",
+                    _ => "return",
+                };
+                self.error(remove(Diagnostic::error(
+                    format!("`;` after `{}` is not allowed", keyword),
+                    semi,
+                )));
+                None
+            }
+            _ => {
+                self.error(remove(
+                    Diagnostic::error("`;` can only follow a value to return", semi).with_note(
+                        "`val;` is shorthand for `return val`; statements need no terminator",
+                    ),
+                ));
+                None
+            }
+        }
+    }
+
+    fn check_stmt(&mut self, stmt: &Stmt) -> Ty {
+        match &stmt.kind {
+            StmtKind::Let { binding, init } => {
+                let ty = self.lower(&binding.ty);
+                let found = self.check_expr_against(init, &ty);
+                let fixes = MutFixes::declared(Some(&binding.ty), binding.ty.span);
+                self.declare(Some(binding.id), ty, binding.mutable, fixes);
+                if found == Ty::Never {
+                    Ty::Never
+                } else {
+                    Ty::Unit
+                }
+            }
+            StmtKind::Destructure { bindings, init } => {
+                let tys: Vec<Ty> = bindings.iter().map(|b| self.lower(&b.ty)).collect();
+                let expected = Ty::Tuple(tys.clone());
+                self.check_expr_against(init, &expected);
+                for (binding, ty) in bindings.iter().zip(tys) {
+                    let fixes = MutFixes::declared(Some(&binding.ty), binding.ty.span);
+                    self.declare(Some(binding.id), ty, binding.mutable, fixes);
+                }
+                Ty::Unit
+            }
+            StmtKind::Assign { target, op, value } => {
+                let target_ty = self.check_place(target);
+                self.require_mutable(PlaceExpr::Expr(target), "assign to");
+                if *op != AssignOp::Set && !target_ty.is_numeric() && !target_ty.is_error() {
+                    self.error(Diagnostic::error(
+                        format!(
+                            "compound assignment requires a numeric type, found `{}`",
+                            target_ty
+                        ),
+                        target.span,
+                    ));
+                }
+                self.check_expr_against(value, &target_ty);
+                Ty::Unit
+            }
+            StmtKind::Step { target, increment } => {
+                let target_ty = self.check_place(target);
+                self.require_mutable(PlaceExpr::Expr(target), "assign to");
+                if !target_ty.is_integer() && !target_ty.is_error() {
+                    self.error(Diagnostic::error(
+                        format!(
+                            "`{}` requires an integer, found `{}`",
+                            if *increment { "++" } else { "--" },
+                            target_ty
+                        ),
+                        target.span,
+                    ));
+                }
+                Ty::Unit
+            }
+            StmtKind::Expr(expr) => self.check_expr(expr, None),
+        }
+    }
+
+    fn check_place(&mut self, target: &Expr) -> Ty {
+        let is_place = matches!(
+            target.kind,
+            ExprKind::Path(_) | ExprKind::Field { .. } | ExprKind::Deref(_)
+        );
+        let ty = self.check_expr(target, None);
+        if !is_place {
+            self.error(Diagnostic::error(
+                "invalid left-hand side of assignment",
+                target.span,
+            ));
+            return Ty::Error;
+        }
+        ty
+    }
+
+    // ---------------------------------------------------------------------
+    // Unsafety
+    // ---------------------------------------------------------------------
+
+    /// Reports `what` unless it is inside an `unsafe` block or `unsafe @fn`,
+    /// and marks the innermost `unsafe` block as needed.
+    fn require_unsafe(&mut self, what: &str, span: Span, note: &str) {
+        if let Some(used) = self.unsafe_blocks.last_mut() {
+            *used = true;
+            return;
+        }
+        if self.function.as_ref().is_some_and(|f| f.is_unsafe) {
+            return;
+        }
+        self.error(
+            Diagnostic::error(
+                format!("{} is unsafe and requires an `unsafe` block", what),
+                span,
+            )
+            .with_note(note.to_string()),
+        );
+    }
+
+    fn check_unsafe_call(&mut self, call: &Expr, callee: &Expr) {
+        let Some(func) = self.results.callees.get(&call.id).map(|c| c.func.clone()) else {
+            return;
+        };
+        let sig = match &func.owner {
+            FnOwner::Free => self.items.functions.get(&func.name),
+            FnOwner::Type(ty) => self.items.method(ty, &func.name),
+            FnOwner::Protocol(_) => None,
+        };
+        let name = func.name.rsplit("::").next().unwrap_or(&func.name);
+        if sig.is_some_and(|sig| sig.is_extern) {
+            self.require_unsafe(
+                &format!("call to `extern` function `{}`", name),
+                callee.span,
+                "nothing checks what the foreign code does with its arguments",
+            );
+        } else if sig.is_some_and(|sig| sig.is_unsafe) {
+            self.require_unsafe(
+                &format!("call to unsafe function `{}`", name),
+                callee.span,
+                "the function's safety requirements must be upheld by the caller",
+            );
+        }
+    }
+
+    /// Changing a mutable global from a function is unsafe: nothing stops
+    /// two callers, or an interrupt handler, from doing it at once. The top
+    /// level script runs once before anything else and may change them
+    /// freely.
+    fn check_global_write(&mut self, place: PlaceExpr, what: &str) {
+        if self.function.is_none() {
+            return;
+        }
+        let PlaceExpr::Expr(mut expr) = place else {
+            return;
+        };
+        let path = loop {
+            match &expr.kind {
+                ExprKind::Path(_) => break expr,
+                ExprKind::Field { base, .. } => expr = base,
+                _ => return,
+            }
+        };
+        self.check_global_path(path, what);
+    }
+
+    /// Checks a change to the place named by `path`, a path expression or
+    /// a method call's callee path.
+    fn check_global_path(&mut self, path: &Expr, what: &str) {
+        let Some(Res::Global(name)) = self.resolutions.get(path.id) else {
+            return;
+        };
+        if self.function.is_none() || !self.items.globals.get(name).is_some_and(|g| g.mutable) {
+            return;
+        }
+        let name = name.rsplit("::").next().unwrap_or(name).to_string();
+        self.require_unsafe(
+            &format!("{} mutable global `{}`", what, name),
+            path.span,
+            "mutable globals can be changed from anywhere, so the compiler \
+             can't rule out data races",
+        );
+    }
+
+    // ---------------------------------------------------------------------
+    // Inline assembly
+    // ---------------------------------------------------------------------
+
+    /// Checks the operands, template references and clobbers of an `asm`
+    /// block. Operands must fit in the register they are given.
+    fn check_asm(&mut self, block: &AsmBlock, span: Span) -> Ty {
+        let mut names: HashMap<&str, usize> = HashMap::new();
+        let mut registers: Vec<PhysReg> = Vec::new();
+        let mut classes = Vec::new();
+        for (i, operand) in block.operands.iter().enumerate() {
+            if let Some(name) = &operand.name
+                && names.insert(&name.name, i).is_some()
+            {
+                self.error(Diagnostic::error(
+                    format!("duplicate assembly operand `{}`", name.name),
+                    name.span,
+                ));
+            }
+            let ty = if operand.dir.writes() {
+                let ty = self.check_place(&operand.expr);
+                self.require_mutable(PlaceExpr::Expr(&operand.expr), "assign to");
+                ty
+            } else {
+                self.check_expr(&operand.expr, None)
+            };
+            let ty = self.infcx.resolve(&ty);
+            let is_float = match &ty {
+                Ty::Float => true,
+                Ty::Int | Ty::Byte | Ty::Bool | Ty::Char => false,
+                Ty::Ref { inner, .. } | Ty::RawRef { inner, .. }
+                    if !matches!(**inner, Ty::Dyn(_)) =>
+                {
+                    false
+                }
+                Ty::Error => {
+                    classes.push(None);
+                    continue;
+                }
+                _ => {
+                    self.error(
+                        Diagnostic::error(
+                            format!("`{}` doesn't fit in a register", ty),
+                            operand.expr.span,
+                        )
+                        .with_note(
+                            "assembly operands are integers, bytes, booleans, chars, \
+                             floats or thin references",
+                        ),
+                    );
+                    classes.push(None);
+                    continue;
+                }
+            };
+            classes.push(Some(is_float));
+            let wants_float = match &operand.reg {
+                AsmReg::Gpr => false,
+                AsmReg::Xmm => true,
+                AsmReg::Explicit(name) => {
+                    match PhysReg::parse(name) {
+                        Some(reg) if reg.is_frame() => {
+                            self.error(
+                                Diagnostic::error(
+                                    format!("`{}` can't be an assembly operand", name),
+                                    operand.reg_span,
+                                )
+                                .with_note("it holds the stack frame"),
+                            );
+                            continue;
+                        }
+                        Some(reg) => {
+                            if registers.contains(&reg) {
+                                self.error(Diagnostic::error(
+                                    format!("`{}` is used by two assembly operands", name),
+                                    operand.reg_span,
+                                ));
+                            }
+                            registers.push(reg);
+                            matches!(reg, PhysReg::Xmm(_))
+                        }
+                        None => {
+                            self.error(
+                            Diagnostic::error(format!("unknown register `{}`", name), operand.reg_span)
+                                .with_note("operands take `reg`, `xreg` or a register such as `rax` or `xmm0`"),
+                        );
+                            continue;
+                        }
+                    }
+                }
+            };
+            if wants_float != is_float {
+                let note = if is_float {
+                    "floats go in `xreg` or `xmm` registers"
+                } else {
+                    "`xreg` and `xmm` registers only hold floats"
+                };
+                self.error(
+                    Diagnostic::error(
+                        format!("a `{}` operand can't go in `{}`", ty, operand.reg),
+                        operand.reg_span,
+                    )
+                    .with_note(note),
+                );
+            }
+        }
+
+        for piece in block.template.iter().flatten() {
+            let AsmPiece::Operand {
+                operand,
+                modifier,
+                span,
+            } = piece
+            else {
+                continue;
+            };
+            let index = match operand {
+                AsmRef::Index(i) if *i < block.operands.len() => *i,
+                AsmRef::Index(i) => {
+                    self.error(
+                        Diagnostic::error(
+                            format!("invalid reference to assembly operand {}", i),
+                            *span,
+                        )
+                        .with_note(format!(
+                            "the block has {} operand{}",
+                            block.operands.len(),
+                            if block.operands.len() == 1 { "" } else { "s" }
+                        )),
+                    );
+                    continue;
+                }
+                AsmRef::Name(name) => match names.get(name.as_str()) {
+                    Some(&i) => i,
+                    None => {
+                        self.error(Diagnostic::error(
+                            format!("no assembly operand named `{}`", name),
+                            *span,
+                        ));
+                        continue;
+                    }
+                },
+            };
+            match (modifier, classes[index]) {
+                (None, _) | (Some('b' | 'w' | 'd' | 'q'), Some(false) | None) => {}
+                (Some(m @ ('b' | 'w' | 'd' | 'q')), Some(true)) => {
+                    self.error(Diagnostic::error(
+                        format!(
+                            "the modifier `{}` only applies to general purpose registers",
+                            m
+                        ),
+                        *span,
+                    ));
+                }
+                (Some(m), _) => {
+                    self.error(
+                        Diagnostic::error(format!("unknown register modifier `{}`", m), *span)
+                            .with_note("use `b`, `w`, `d` or `q`"),
+                    );
+                }
+            }
+        }
+
+        for clobber in &block.clobbers {
+            if clobber.name == "memory" {
+                continue;
+            }
+            match PhysReg::parse(&clobber.name) {
+                Some(reg) if reg.is_frame() => self.error(
+                    Diagnostic::error(
+                        format!("`{}` can't be clobbered", clobber.name),
+                        clobber.span,
+                    )
+                    .with_note("it holds the stack frame"),
+                ),
+                Some(reg) if registers.contains(&reg) => self.error(Diagnostic::error(
+                    format!(
+                        "`{}` is clobbered and also used by an operand",
+                        clobber.name
+                    ),
+                    clobber.span,
+                )),
+                Some(_) => {}
+                None => self.error(
+                    Diagnostic::error(format!("unknown register `{}`", clobber.name), clobber.span)
+                        .with_note("clobbers name registers, or `memory`"),
+                ),
+            }
+        }
+
+        if block.options.noreturn {
+            if block.operands.iter().any(|operand| operand.dir.writes()) {
+                self.error(Diagnostic::error(
+                    "`noreturn` assembly can't have outputs",
+                    span,
+                ));
+            }
+            Ty::Never
+        } else {
+            Ty::Unit
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Mutability
+    // ---------------------------------------------------------------------
+
+    /// Reports `place` unless it may be changed: bindings must be declared
+    /// `mut`, and a place reached through a reference needs `ref mut`.
+    fn require_mutable(&mut self, place: PlaceExpr, action: &str) {
+        if let Err(reason) = self.place_mutability(place).0 {
+            self.error(immutable_error(place, reason, action));
+            return;
+        }
+        let what = match action {
+            "assign to" => "assignment to",
+            "mutably borrow" => "mutable borrow of",
+            other => other,
+        };
+        self.check_global_write(place, what);
+    }
+
+    /// Methods taking `mut self` or `ref mut self` change their receiver.
+    fn check_receiver(&mut self, call: &Expr, place: PlaceExpr, method: &Ident) {
+        let Some(callee) = self.results.callees.get(&call.id) else {
+            return;
+        };
+        let sig = match &callee.func.owner {
+            FnOwner::Type(ty) => self.items.method(ty, &callee.func.name),
+            FnOwner::Protocol(protocol) => self
+                .items
+                .protocols
+                .get(protocol)
+                .and_then(|sigs| sigs.iter().find(|sig| sig.name == callee.func.name)),
+            FnOwner::Free => None,
+        };
+        let Some(kind @ (SelfKind::MutValue | SelfKind::RefMut)) = sig.and_then(|s| s.self_kind)
+        else {
+            return;
+        };
+        // The receiver is dereferenced automatically, so a reference to it
+        // must be `ref mut` too.
+        let fix = match place {
+            PlaceExpr::Path(id, [_]) => self
+                .lookup(id)
+                .and_then(|local| local.fixes.reference.clone()),
+            _ => None,
+        };
+        let (result, ty) = self.place_mutability(place);
+        if let Err(reason) = deref_mutability(result, &ty, fix).0 {
+            let action = format!("call `{}` on", method.name);
+            self.error(immutable_error(place, reason, &action).with_note(format!(
+                "`{}` takes `{}`",
+                method.name,
+                kind.as_str()
+            )));
+        } else if !matches!(ty, Ty::Ref { .. } | Ty::RawRef { .. })
+            && let ExprKind::Call { callee, .. } = &call.kind
+        {
+            self.check_global_path(callee, &format!("call to `{}` on", method.name));
+        }
+    }
+
+    /// Whether `place` may be changed, and its type.
+    fn place_mutability(&self, place: PlaceExpr) -> (Result<(), Immutable>, Ty) {
+        match place {
+            PlaceExpr::Path(id, segments) => self.path_mutability(id, segments),
+            PlaceExpr::Expr(expr) => match &expr.kind {
+                ExprKind::Path(segments) => self.path_mutability(expr.id, segments),
+                ExprKind::Field { base, name } => {
+                    let (result, base_ty) = self.place_mutability(PlaceExpr::Expr(base));
+                    let (result, base_ty) = deref_mutability(result, &base_ty, None);
+                    (result, self.field_ty_quiet(&base_ty, &name.name))
+                }
+                ExprKind::Deref(inner) => {
+                    let ty = self.expr_ty(inner);
+                    let fix = match &inner.kind {
+                        ExprKind::Path(segments) if segments.len() == 1 => self
+                            .lookup(inner.id)
+                            .and_then(|local| local.fixes.reference.clone()),
+                        _ => None,
+                    };
+                    match ty {
+                        Ty::Ref { mutable, inner } | Ty::RawRef { mutable, inner } => {
+                            let result = if mutable {
+                                Ok(())
+                            } else {
+                                Err(Immutable::Ref { fix })
+                            };
+                            (result, *inner)
+                        }
+                        _ => (Ok(()), Ty::Error),
+                    }
+                }
+                // Temporaries may be changed freely.
+                _ => (Ok(()), self.expr_ty(expr)),
+            },
+        }
+    }
+
+    fn path_mutability(&self, id: NodeId, segments: &[Ident]) -> (Result<(), Immutable>, Ty) {
+        let (mutable, fixes, ty, named) = if let Some(local) = self.lookup(id) {
+            (local.mutable, local.fixes.clone(), &local.ty, 1)
+        } else if let Some(Res::Global(name)) = self.resolutions.get(id) {
+            let global = &self.items.globals[name];
+            let named = self.global_segments(name, segments);
+            let fixes = self.global_fixes.get(name).cloned().unwrap_or_default();
+            (global.mutable, fixes, &global.ty, named)
+        } else {
+            return (Ok(()), Ty::Error);
+        };
+        let mut result = if mutable {
+            Ok(())
+        } else {
+            Err(Immutable::Binding {
+                name: join_path(&segments[..named]),
+                fix: fixes.binding,
+            })
+        };
+        let mut ty = self.infcx.resolve(ty);
+        let mut fix = fixes.reference;
+        for segment in &segments[named..] {
+            (result, ty) = deref_mutability(result, &ty, fix.take());
+            ty = self.field_ty_quiet(&ty, &segment.name);
+        }
+        (result, ty)
+    }
+
+    /// The type of field `name` of `ty`, or `{unknown}`; errors were
+    /// reported when the place was type checked.
+    fn field_ty_quiet(&self, ty: &Ty, name: &str) -> Ty {
+        match ty {
+            Ty::Adt { name: adt, args } => self
+                .items
+                .record_fields(adt, args)
+                .and_then(|fields| fields.into_iter().find(|(n, _)| n == name))
+                .map_or(Ty::Error, |(_, ty)| ty),
+            Ty::Tuple(elems) => name
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| elems.get(i).cloned())
+                .unwrap_or(Ty::Error),
+            _ => Ty::Error,
+        }
+    }
+
+    /// Bitfields share their bytes with their neighbours, so they have no
+    /// address of their own.
+    fn forbid_bitfield_ref(&mut self, place: &Expr) {
+        let (base, name) = match &place.kind {
+            ExprKind::Field { base, name } => (self.expr_ty(base), name),
+            ExprKind::Path(segments) if segments.len() >= 2 => {
+                let (name, prefix) = segments.split_last().unwrap();
+                (self.path_mutability(place.id, prefix).1, name)
+            }
+            _ => return,
+        };
+        let mut base = &base;
+        while let Ty::Ref { inner, .. } | Ty::RawRef { inner, .. } = base {
+            base = inner;
+        }
+        let Ty::Adt { name: record, .. } = base else {
+            return;
+        };
+        let is_bitfield = self.items.records.get(record).is_some_and(|def| {
+            def.fields
+                .iter()
+                .position(|(n, _)| *n == name.name)
+                .is_some_and(|index| def.bits(index).is_some())
+        });
+        if is_bitfield {
+            self.error(
+                Diagnostic::error(
+                    format!("can't take a reference to the bitfield `{}`", name.name),
+                    place.span,
+                )
+                .with_note(
+                    "bitfields share their bytes with other fields, so they have no address",
+                ),
+            );
+        }
+    }
+
+    fn expr_ty(&self, expr: &Expr) -> Ty {
+        self.results
+            .type_of(expr.id)
+            .map_or(Ty::Error, |ty| self.infcx.resolve(ty))
+    }
+
+    // ---------------------------------------------------------------------
+    // Expressions
+    // ---------------------------------------------------------------------
+
+    /// Computes the type of `expr`. `expected` is only a hint used to pick
+    /// literal types and generic arguments; callers compare the result.
+    fn check_expr(&mut self, expr: &Expr, expected: Option<&Ty>) -> Ty {
+        let expected = expected.map(|ty| self.infcx.resolve(ty));
+        let expected = expected.as_ref();
+        let ty = match &expr.kind {
+            ExprKind::Literal(literal) => literal_ty(literal, expected),
+            ExprKind::Path(_) if self.resolutions.is_err(expr.id) => Ty::Error,
+            ExprKind::Path(segments) => self.check_path(expr.id, segments, expected, expr.span),
+            ExprKind::Tuple(elems) if elems.is_empty() => Ty::Unit,
+            ExprKind::Tuple(elems) => {
+                let hints = match expected {
+                    Some(Ty::Tuple(tys)) if tys.len() == elems.len() => Some(tys.clone()),
+                    _ => None,
+                };
+                Ty::Tuple(
+                    elems
+                        .iter()
+                        .enumerate()
+                        .map(|(i, e)| {
+                            let hint = hints.as_ref().map(|h| &h[i]);
+                            self.check_expr(e, hint)
+                        })
+                        .collect(),
+                )
+            }
+            ExprKind::Unary { op, expr: inner } => self.check_unary(*op, inner, expected),
+            ExprKind::Binary { op, lhs, rhs } => self.check_binary(*op, lhs, rhs, expected),
+            ExprKind::Range { start, end } => {
+                let start_ty = self.check_expr(start, None);
+                if !start_ty.is_integer() && !start_ty.is_error() {
+                    self.error(Diagnostic::error(
+                        format!("range bounds must be integers, found `{}`", start_ty),
+                        start.span,
+                    ));
+                }
+                self.check_expr_against(end, &start_ty);
+                Ty::Adt {
+                    name: "Range".to_string(),
+                    args: vec![start_ty],
+                }
+            }
+            ExprKind::Call {
+                callee,
+                generics,
+                args,
+            } => {
+                let ty = self.check_call(expr, callee, generics, args, expected);
+                self.check_unsafe_call(expr, callee);
+                ty
+            }
+            ExprKind::Field { base, name } => {
+                let base_ty = self.check_expr(base, None);
+                self.field_ty(&base_ty, name)
+            }
+            ExprKind::RecordLit { fields, .. } if self.resolutions.is_err(expr.id) => {
+                for field in fields {
+                    self.check_expr(&field.value, None);
+                }
+                Ty::Error
+            }
+            ExprKind::RecordLit {
+                path,
+                generics,
+                fields,
+            } => self.check_record_lit(expr, path, generics, fields, expected),
+            ExprKind::Block(block) => self.check_block(block, expected),
+            ExprKind::If {
+                cond,
+                then_block,
+                else_branch,
+            } => self.check_if(cond, then_block, else_branch.as_deref(), expected),
+            ExprKind::While { cond, body } => {
+                self.check_condition(cond, "while");
+                self.check_loop_body(LoopKind::While, expr.span, body, None);
+                Ty::Unit
+            }
+            ExprKind::For {
+                binding: _,
+                binding_id,
+                iter,
+                body,
+            } => {
+                let iter_ty = self.check_expr(iter, None);
+                let iter_ty = self.infcx.resolve(&iter_ty);
+                let elem_ty = match &iter_ty {
+                    Ty::Adt { name, args } if name == "Range" => args[0].clone(),
+                    Ty::Adt { name, .. } if self.items.implements(name, prelude::ITERATOR) => {
+                        self.iterator_elem(expr, &iter_ty)
+                    }
+                    Ty::Error => Ty::Error,
+                    other => {
+                        self.error(Diagnostic::error(
+                            format!("`{}` cannot be iterated over with `for`", other),
+                            iter.span,
+                        ));
+                        Ty::Error
+                    }
+                };
+                self.declare(Some(*binding_id), elem_ty, false, MutFixes::default());
+                self.check_loop_body(LoopKind::For, expr.span, body, None);
+                Ty::Unit
+            }
+            ExprKind::Loop(body) => {
+                let ctx = self.check_loop_body(LoopKind::Loop, expr.span, body, expected);
+                // A `loop` only finishes through `break`, so without one it
+                // never produces a value.
+                ctx.break_ty.map_or(Ty::Never, |(ty, _)| ty)
+            }
+            ExprKind::Match { scrutinee, arms } => self.check_match(scrutinee, arms, expected),
+            ExprKind::Break(value) => {
+                self.check_break(expr.span, value.as_deref());
+                Ty::Never
+            }
+            ExprKind::Continue => {
+                if self.loops.is_empty() {
+                    self.error(Diagnostic::error("`continue` outside of a loop", expr.span));
+                }
+                Ty::Never
+            }
+            ExprKind::Return(value) => {
+                self.check_return(expr.span, value.as_deref());
+                Ty::Never
+            }
+            ExprKind::Ref {
+                mutable,
+                expr: inner,
+            } => {
+                let hint = match expected {
+                    Some(Ty::Ref { inner, .. }) => Some(inner.as_ref().clone()),
+                    _ => None,
+                };
+                let inner_ty = self.check_expr(inner, hint.as_ref());
+                if *mutable {
+                    self.require_mutable(PlaceExpr::Expr(inner), "mutably borrow");
+                }
+                self.forbid_bitfield_ref(inner);
+                Ty::Ref {
+                    mutable: *mutable,
+                    inner: Box::new(inner_ty),
+                }
+            }
+            ExprKind::RawRef {
+                mutable,
+                expr: inner,
+            } => {
+                let inner_ty = self.check_expr(inner, None);
+                self.forbid_bitfield_ref(inner);
+                Ty::RawRef {
+                    mutable: *mutable,
+                    inner: Box::new(inner_ty),
+                }
+            }
+            ExprKind::Deref(inner) => match self.check_expr(inner, None) {
+                Ty::Ref { inner, .. } => *inner,
+                Ty::RawRef { inner, .. } => {
+                    self.require_unsafe(
+                        "dereference of a raw reference",
+                        expr.span,
+                        "raw references may be null, dangling or unaligned",
+                    );
+                    *inner
+                }
+                Ty::Error => Ty::Error,
+                other => {
+                    self.error(Diagnostic::error(
+                        format!("type `{}` cannot be dereferenced", other),
+                        inner.span,
+                    ));
+                    Ty::Error
+                }
+            },
+            ExprKind::Try(inner) => self.check_try(expr, inner),
+            ExprKind::Unsafe(block) => {
+                self.unsafe_blocks.push(false);
+                let ty = self.check_block(block, expected);
+                if self.unsafe_blocks.pop() == Some(false) {
+                    let keyword = Span::new(expr.span.start, expr.span.start + "unsafe".len());
+                    self.error(
+                        Diagnostic::warning("unnecessary `unsafe` block", keyword)
+                            .with_note("it contains no unsafe operations"),
+                    );
+                }
+                ty
+            }
+            ExprKind::Asm(block) => {
+                self.require_unsafe(
+                    "use of inline assembly",
+                    expr.span,
+                    "the compiler can't check what the assembly does",
+                );
+                self.check_asm(block, expr.span)
+            }
+            ExprKind::Closure(closure) => self.check_closure(closure, expected),
+        };
+        let ty = self.infcx.resolve(&ty);
+        self.record(expr.id, &ty);
+        ty
+    }
+
+    /// Parameter and return types come from annotations, then from the
+    /// expected function type, and are otherwise inferred from the body.
+    fn check_closure(&mut self, closure: &Closure, expected: Option<&Ty>) -> Ty {
+        let (expected_params, expected_ret) = match expected {
+            Some(Ty::Fn { params, ret }) if params.len() == closure.params.len() => {
+                (Some(params.clone()), Some(ret.as_ref().clone()))
+            }
+            _ => (None, None),
+        };
+        let mut params = Vec::new();
+        for (i, param) in closure.params.iter().enumerate() {
+            let ty = match (&param.ty, &expected_params) {
+                (Some(ty), _) => self.lower(ty),
+                (None, Some(expected)) => expected[i].clone(),
+                (None, None) => self.fresh_var(
+                    format!("the type of parameter `{}`", param.name.name),
+                    param.name.span,
+                ),
+            };
+            params.push(ty);
+        }
+        let ret = match (&closure.ret, expected_ret) {
+            (Some(ty), _) => self.lower(ty),
+            (None, Some(ret)) => ret,
+            (None, None) => self.infcx.fresh(),
+        };
+
+        // A closure body is its own function for `return`, but not for
+        // scoping: it sees the locals around it.
+        let saved_loops = std::mem::take(&mut self.loops);
+        // A closure inherits the unsafety of the function it is written in.
+        let is_unsafe = self.function.as_ref().is_some_and(|f| f.is_unsafe);
+        let saved_fn = self.function.replace(FnCtx {
+            ret: ret.clone(),
+            is_unsafe,
+        });
+        for (param, ty) in closure.params.iter().zip(&params) {
+            let start = param.ty.as_ref().map_or(param.name.span, |ty| ty.span);
+            let fixes = MutFixes::declared(param.ty.as_ref(), start);
+            self.declare(Some(param.id), ty.clone(), param.mutable, fixes);
+        }
+        self.check_expr_against(&closure.body, &ret);
+        self.function = saved_fn;
+        self.loops = saved_loops;
+
+        Ty::Fn {
+            params,
+            ret: Box::new(ret),
+        }
+    }
+
+    fn check_condition(&mut self, cond: &Expr, keyword: &str) {
+        // `0` is false and any other integer is true, as in the spec.
+        let ty = self.check_expr(cond, Some(&Ty::Bool));
+        if !matches!(ty, Ty::Bool | Ty::Never | Ty::Error) && !ty.is_integer() {
+            self.error(Diagnostic::error(
+                format!(
+                    "`{}` condition must be `bool` or an integer, found `{}`",
+                    keyword, ty
+                ),
+                cond.span,
+            ));
+        }
+    }
+
+    fn check_if(
+        &mut self,
+        cond: &Expr,
+        then_block: &Block,
+        else_branch: Option<&Expr>,
+        expected: Option<&Ty>,
+    ) -> Ty {
+        self.check_condition(cond, "if");
+        let Some(else_branch) = else_branch else {
+            let then_ty = self.check_block(then_block, None);
+            if then_ty.is_unit_like() {
+                return Ty::Unit;
+            }
+            let span = then_block.tail().map_or(then_block.span, |tail| tail.span);
+            let mut diagnostic = Diagnostic::error(
+                format!(
+                    "`if` without `else` must have type `unit`, but its block evaluates to `{}`",
+                    then_ty
+                ),
+                span,
+            )
+            .with_note("when the condition is false an `if` without `else` produces `unit`");
+            if expected.is_some_and(|t| !t.is_unit_like()) {
+                diagnostic = diagnostic.with_fixit(
+                    Span::new(then_block.span.end, then_block.span.end),
+                    " else { .. }",
+                    "add an `else` branch that produces a value",
+                );
+            }
+            self.error(diagnostic);
+            return Ty::Error;
+        };
+
+        let then_ty = self.check_block(then_block, expected);
+        let hint = expected.cloned().or_else(|| match &then_ty {
+            Ty::Never | Ty::Error => None,
+            ty => Some(ty.clone()),
+        });
+        let else_ty = self.check_expr(else_branch, hint.as_ref());
+        let then_span = then_block.tail().map_or(then_block.span, |tail| tail.span);
+        let else_span = match &else_branch.kind {
+            ExprKind::Block(block) => block.tail().map_or(block.span, |tail| tail.span),
+            _ => else_branch.span,
+        };
+        self.unify_branches(
+            then_ty,
+            then_span,
+            else_ty,
+            else_span,
+            "`if` and `else` have incompatible types",
+        )
+    }
+
+    /// The common type of two branches; `never` defers to the other side.
+    fn unify_branches(
+        &mut self,
+        first: Ty,
+        first_span: Span,
+        second: Ty,
+        second_span: Span,
+        message: &str,
+    ) -> Ty {
+        if first == Ty::Never {
+            return second;
+        }
+        if self.infcx.unify(&second, &first) {
+            let first = self.infcx.resolve(&first);
+            return if first.is_error() { second } else { first };
+        }
+        self.error(
+            Diagnostic::error(message, second_span)
+                .with_label(first_span, format!("this is of type `{}`", first))
+                .with_note(format!("expected `{}`, found `{}`", first, second)),
+        );
+        Ty::Error
+    }
+
+    fn check_loop_body(
+        &mut self,
+        kind: LoopKind,
+        span: Span,
+        body: &Block,
+        expected: Option<&Ty>,
+    ) -> LoopCtx {
+        let keyword_span = Span::new(span.start, span.start + kind.keyword().len());
+        self.loops.push(LoopCtx {
+            kind,
+            span: keyword_span,
+            expected: expected.cloned(),
+            break_ty: None,
+        });
+        // Loop bodies run for their effects; their value is discarded.
+        self.check_block(body, None);
+        self.loops.pop().unwrap()
+    }
+
+    fn check_break(&mut self, span: Span, value: Option<&Expr>) {
+        let Some(ctx) = self.loops.last() else {
+            self.error(Diagnostic::error("`break` outside of a loop", span));
+            if let Some(value) = value {
+                self.check_expr(value, None);
+            }
+            return;
+        };
+        let (kind, loop_span) = (ctx.kind, ctx.span);
+        let hint = ctx
+            .expected
+            .clone()
+            .or_else(|| ctx.break_ty.as_ref().map(|(ty, _)| ty.clone()));
+
+        let (ty, ty_span) = match value {
+            Some(value) => (self.check_expr(value, hint.as_ref()), value.span),
+            None => (Ty::Unit, span),
+        };
+        if kind != LoopKind::Loop {
+            if let Some(value) = value {
+                self.error(
+                    Diagnostic::error(
+                        format!(
+                            "`break` with a value is only allowed inside `loop`, not `{}`",
+                            kind.keyword()
+                        ),
+                        value.span,
+                    )
+                    .with_label(
+                        loop_span,
+                        format!("`{}` loops always evaluate to `unit`", kind.keyword()),
+                    )
+                    .with_fixit(
+                        Span::new(span.start, value.span.end),
+                        "break",
+                        "remove the value from `break`",
+                    ),
+                );
+            }
+            return;
+        }
+
+        let ctx = self.loops.last_mut().unwrap();
+        match &ctx.break_ty {
+            None => ctx.break_ty = Some((ty, ty_span)),
+            Some((first, first_span)) => {
+                let (first, first_span) = (first.clone(), *first_span);
+                if !self.infcx.unify(&ty, &first) {
+                    self.error(
+                        Diagnostic::error(
+                            format!(
+                                "`break` values in this `loop` have incompatible types: expected `{}`, found `{}`",
+                                first, ty
+                            ),
+                            ty_span,
+                        )
+                        .with_label(first_span, format!("first `break` has type `{}`", first))
+                        .with_label(loop_span, "in this `loop`"),
+                    );
+                } else if first.is_error() {
+                    self.loops.last_mut().unwrap().break_ty = Some((ty, ty_span));
+                }
+            }
+        }
+    }
+
+    fn check_return(&mut self, span: Span, value: Option<&Expr>) {
+        let Some(ret) = self.function.as_ref().map(|f| f.ret.clone()) else {
+            self.error(Diagnostic::error("`return` outside of a function", span));
+            return;
+        };
+        match value {
+            Some(value) => {
+                self.check_expr_against(value, &ret);
+            }
+            None if ret != Ty::Unit => {
+                self.error(Diagnostic::error(
+                    format!("`return` without a value in a function returning `{}`", ret),
+                    span,
+                ));
+            }
+            None => {}
+        }
+    }
+
+    /// `value?` unpacks `Some(v)` or `Ok(v)`, and otherwise returns `None`
+    /// or `Err(e)` from the enclosing function, which must return the same
+    /// kind of value. An error of another type is converted with its
+    /// `into_error` implementation.
+    fn check_try(&mut self, expr: &Expr, inner: &Expr) -> Ty {
+        let (kind, args) = match self.check_expr(inner, None) {
+            Ty::Adt { name, args } if name == prelude::OPTION || name == prelude::RESULT => {
+                (name, args)
+            }
+            Ty::Error => return Ty::Error,
+            other => {
+                self.error(Diagnostic::error(
+                    format!(
+                        "the `?` operator can only be applied to `Option` or `Result`, found `{}`",
+                        other
+                    ),
+                    expr.span,
+                ));
+                return Ty::Error;
+            }
+        };
+        let ret = self.function.as_ref().map(|f| self.infcx.resolve(&f.ret));
+        // A closure without a declared result type returns what `?` needs.
+        if let Some(ret @ Ty::Infer(_)) = &ret {
+            let mut ret_args = vec![self.infcx.fresh()];
+            ret_args.extend(args.get(1).cloned());
+            let wanted = Ty::Adt {
+                name: kind.clone(),
+                args: ret_args,
+            };
+            self.infcx.unify(ret, &wanted);
+        }
+        let ret = ret.map(|ret| self.infcx.resolve(&ret));
+        match &ret {
+            Some(Ty::Adt {
+                name,
+                args: ret_args,
+            }) if *name == kind => {
+                if kind == prelude::RESULT {
+                    self.convert_error(expr, &args[1], &ret_args[1]);
+                }
+            }
+            Some(Ty::Error) => {}
+            _ => {
+                let kind = kind.trim_start_matches("core::");
+                let note = match &ret {
+                    Some(ret) => format!("the enclosing function returns `{}`", ret),
+                    None => "top level statements are not inside a function".to_string(),
+                };
+                self.error(
+                    Diagnostic::error(
+                        format!(
+                            "the `?` operator can only be used on `{}` in a function that returns `{}`",
+                            kind, kind
+                        ),
+                        expr.span,
+                    )
+                    .with_note(note),
+                );
+            }
+        }
+        args[0].clone()
+    }
+
+    /// The element type of a `for` loop over a user-defined iterator. The
+    /// loop calls `next` on it, recorded as a call at the loop's id.
+    fn iterator_elem(&mut self, expr: &Expr, iter_ty: &Ty) -> Ty {
+        let Ty::Adt { name, args } = iter_ty else {
+            return Ty::Error;
+        };
+        let ret = self
+            .items
+            .method(name, prelude::ITERATOR_METHOD)
+            .map(|sig| sig.with_impl_args(args).ret);
+        let Some(Ty::Adt { name: option, args }) = ret else {
+            return Ty::Error;
+        };
+        if option != prelude::OPTION || args.len() != 1 {
+            return Ty::Error;
+        }
+        self.results.callees.insert(
+            expr.id,
+            Callee {
+                func: FnRef {
+                    owner: FnOwner::Protocol(prelude::ITERATOR.to_string()),
+                    name: prelude::ITERATOR_METHOD.to_string(),
+                },
+                args: args.clone(),
+                self_ty: Some(iter_ty.clone()),
+                caller: self.current.clone(),
+                span: expr.span,
+            },
+        );
+        args[0].clone()
+    }
+
+    /// Lets `?` pass an error of type `from` on as `to`: the same type, or
+    /// a type implementing `into_error[to]`, whose method is then called.
+    fn convert_error(&mut self, expr: &Expr, from: &Ty, to: &Ty) {
+        let (from, to) = (self.infcx.resolve(from), self.infcx.resolve(to));
+        if let Ty::Adt { name, .. } = &from
+            && from != to
+            && self
+                .items
+                .implements_with(name, prelude::INTO_ERROR, std::slice::from_ref(&to))
+        {
+            self.results.callees.insert(
+                expr.id,
+                Callee {
+                    func: FnRef {
+                        owner: FnOwner::Protocol(prelude::INTO_ERROR.to_string()),
+                        name: prelude::INTO_ERROR_METHOD.to_string(),
+                    },
+                    args: vec![to],
+                    self_ty: Some(from),
+                    caller: self.current.clone(),
+                    span: expr.span,
+                },
+            );
+            return;
+        }
+        if !self.infcx.unify(&from, &to) && !from.references_error() && !to.references_error() {
+            self.error(
+                Diagnostic::error(
+                    format!("`?` cannot convert the error type `{}` into `{}`", from, to),
+                    expr.span,
+                )
+                .with_note(format!(
+                    "implement `into_error[{}]` for `{}` to convert it",
+                    to, from
+                )),
+            );
+        }
+    }
+
+    fn check_unary(&mut self, op: UnaryOp, inner: &Expr, expected: Option<&Ty>) -> Ty {
+        let ty = self.check_expr(inner, expected);
+        let ok = match op {
+            UnaryOp::Neg => matches!(ty, Ty::Int | Ty::Float),
+            UnaryOp::Not => ty == Ty::Bool || ty.is_integer(),
+        };
+        if !ok && !ty.is_error() {
+            let symbol = if op == UnaryOp::Neg { "-" } else { "!" };
+            self.error(Diagnostic::error(
+                format!("cannot apply unary `{}` to type `{}`", symbol, ty),
+                inner.span,
+            ));
+            return Ty::Error;
+        }
+        ty
+    }
+
+    fn check_binary(&mut self, op: BinOp, lhs: &Expr, rhs: &Expr, expected: Option<&Ty>) -> Ty {
+        if op.is_logical() {
+            self.check_expr_against(lhs, &Ty::Bool);
+            self.check_expr_against(rhs, &Ty::Bool);
+            return Ty::Bool;
+        }
+        let hint = if op.is_comparison() { None } else { expected };
+        let lhs_ty = self.check_expr(lhs, hint);
+        let rhs_ty = self.check_expr(rhs, Some(&lhs_ty));
+        if lhs_ty.is_error() || rhs_ty.is_error() {
+            return if op.is_comparison() {
+                Ty::Bool
+            } else {
+                Ty::Error
+            };
+        }
+        if !self.infcx.unify(&rhs_ty, &lhs_ty) {
+            self.error(
+                Diagnostic::error(
+                    format!(
+                        "cannot apply `{}` to `{}` and `{}`",
+                        op.symbol(),
+                        lhs_ty,
+                        rhs_ty
+                    ),
+                    rhs.span,
+                )
+                .with_label(lhs.span, format!("this is of type `{}`", lhs_ty)),
+            );
+            return if op.is_comparison() {
+                Ty::Bool
+            } else {
+                Ty::Error
+            };
+        }
+        let lhs_ty = self.infcx.resolve(&lhs_ty);
+        let operand_ok = match op {
+            BinOp::Eq | BinOp::Ne => true,
+            BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge => {
+                lhs_ty.is_numeric() || matches!(lhs_ty, Ty::Char | Ty::Str)
+            }
+            BinOp::Add => lhs_ty.is_numeric() || lhs_ty == Ty::Str,
+            BinOp::Sub | BinOp::Mul | BinOp::Div => lhs_ty.is_numeric(),
+            BinOp::Rem | BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor => lhs_ty.is_integer(),
+            BinOp::And | BinOp::Or => unreachable!(),
+        };
+        // Operands of unknown type are settled once the variable is solved.
+        if !operand_ok && !matches!(lhs_ty, Ty::Param(_) | Ty::Infer(_)) {
+            self.error(Diagnostic::error(
+                format!("cannot apply `{}` to type `{}`", op.symbol(), lhs_ty),
+                lhs.span.to(rhs.span),
+            ));
+            return Ty::Error;
+        }
+        if op == BinOp::Add && lhs_ty == Ty::Str && self.target == Target::Freestanding {
+            self.error(
+                Diagnostic::error(
+                    "strings can't be concatenated in freestanding programs",
+                    lhs.span.to(rhs.span),
+                )
+                .with_note("concatenation allocates the result, and there is no allocator"),
+            );
+        }
+        if op.is_comparison() {
+            Ty::Bool
+        } else if lhs_ty == Ty::Never {
+            rhs_ty
+        } else {
+            lhs_ty
+        }
+    }
+
+    fn check_path(
+        &mut self,
+        id: NodeId,
+        segments: &[Ident],
+        expected: Option<&Ty>,
+        span: Span,
+    ) -> Ty {
+        let base = if let Some(local) = self.lookup(id) {
+            Some((local.ty.clone(), 1))
+        } else if let Some(Res::Global(name)) = self.resolutions.get(id) {
+            let named = self.global_segments(name, segments);
+            Some((self.items.globals[name].ty.clone(), named))
+        } else {
+            None
+        };
+        if let Some((mut ty, named)) = base {
+            for segment in &segments[named..] {
+                ty = self.field_ty(&ty, segment);
+            }
+            return ty;
+        }
+        match self.resolved(id, segments) {
+            Some(Resolved::Value(name)) => {
+                let sig = self.items.functions[&name].clone();
+                self.check_not_interrupt(&sig, "used as a value", span);
+                if sig.is_extern {
+                    self.error(
+                        Diagnostic::error(
+                            format!(
+                                "the `extern` function `@{}` can't be used as a value",
+                                sig.name
+                            ),
+                            span,
+                        )
+                        .with_note("call it from a closure, which is a function value"),
+                    );
+                }
+                sig.fn_ty()
+            }
+            Some(Resolved::Member(type_name, item)) => {
+                if let Some(union) = self.items.unions.get(&type_name) {
+                    let args = self.adt_args(expected, &type_name, &union.generics, span);
+                    return match union.variant(&item.name) {
+                        Some([]) => Ty::Adt {
+                            name: type_name,
+                            args,
+                        },
+                        Some(fields) => Ty::Fn {
+                            params: fields
+                                .iter()
+                                .map(|t| t.subst(&union.generics, &args))
+                                .collect(),
+                            ret: Box::new(Ty::Adt {
+                                name: type_name,
+                                args,
+                            }),
+                        },
+                        None => {
+                            self.error(Diagnostic::error(
+                                format!(
+                                    "no variant named `{}` in union `{}`",
+                                    item.name, type_name
+                                ),
+                                item.span,
+                            ));
+                            Ty::Error
+                        }
+                    };
+                }
+                match self.method(&type_name, item) {
+                    Some(sig) => {
+                        // Calls through a function value would escape
+                        // `check_ordering`.
+                        let ordered = prelude::ATOMICS.contains(&type_name.as_str())
+                            && sig.params.iter().any(|param| {
+                                prelude::forbidden_orderings(&sig.name, &param.name).is_some()
+                            });
+                        if ordered {
+                            self.error(
+                                Diagnostic::error(
+                                    format!(
+                                        "`{}::{}` can't be used as a value",
+                                        type_name, item.name
+                                    ),
+                                    span,
+                                )
+                                .with_note("its ordering must be written out where it is called"),
+                            );
+                        }
+                        sig.fn_ty()
+                    }
+                    None => {
+                        self.error(Diagnostic::error(
+                            format!(
+                                "no function named `{}` found for `{}`",
+                                item.name, type_name
+                            ),
+                            item.span,
+                        ));
+                        Ty::Error
+                    }
+                }
+            }
+            None => {
+                // Builtins are only called.
+                if let Some(Res::Builtin(name)) = self.resolutions.get(id) {
+                    self.error(lookup_error("value", name, LookupError::Missing, span));
+                }
+                Ty::Error
+            }
+        }
+    }
+
+    /// What the resolver found a path naming a module level item to be: a
+    /// function (`f`, `io::f`) or something inside a type (`Option::Some`,
+    /// `shapes::point::new`).
+    fn resolved<'p>(&self, id: NodeId, segments: &'p [Ident]) -> Option<Resolved<'p>> {
+        let item = segments.last()?;
+        match self.resolutions.get(id)? {
+            Res::Function(name) => Some(Resolved::Value(name.clone())),
+            Res::Variant { union: ty, .. } | Res::AssocFn { ty, .. } => {
+                Some(Resolved::Member(ty.clone(), item))
+            }
+            _ => None,
+        }
+    }
+
+    /// Interrupt handlers return with `iretq` to whatever the processor
+    /// interrupted, so nothing else may call them.
+    fn check_not_interrupt(&mut self, sig: &FnSig, what: &str, span: Span) {
+        if sig.attrs.interrupt {
+            self.error(
+                Diagnostic::error(
+                    format!("the interrupt handler `@{}` can't be {}", sig.name, what),
+                    span,
+                )
+                .with_note("the processor calls it through the interrupt descriptor table; mark it `#[no_mangle]` to take its address in `asm`"),
+            );
+        }
+    }
+
+    /// A method or associated function, if it is visible from this module.
+    fn method(&mut self, type_name: &str, name: &Ident) -> Option<FnSig> {
+        let sig = self.items.method(type_name, &name.name)?.clone();
+        if !sig.is_pub && sig.module != self.items.module {
+            self.error(lookup_error(
+                "function",
+                &format!("{}::{}", type_name, name.name),
+                LookupError::Private {
+                    module: sig.module.clone(),
+                },
+                name.span,
+            ));
+        }
+        Some(sig)
+    }
+
+    fn field_ty(&mut self, base: &Ty, name: &Ident) -> Ty {
+        match base {
+            Ty::Ref { inner, .. } => self.field_ty(&inner.clone(), name),
+            Ty::Adt { name: adt, args } => {
+                if let Some(fields) = self.items.record_fields(adt, args)
+                    && let Some((_, ty)) = fields.iter().find(|(n, _)| n == &name.name)
+                {
+                    return ty.clone();
+                }
+                self.error(Diagnostic::error(
+                    format!("no field `{}` on type `{}`", name.name, base),
+                    name.span,
+                ));
+                Ty::Error
+            }
+            Ty::Error | Ty::Param(_) => Ty::Error,
+            Ty::Infer(_) => {
+                self.error(unknown_type(name.span));
+                Ty::Error
+            }
+            Ty::Tuple(elems) => match name.name.parse::<usize>() {
+                Ok(index) if index < elems.len() => elems[index].clone(),
+                _ => {
+                    self.error(Diagnostic::error(
+                        format!("no field `{}` on type `{}`", name.name, base),
+                        name.span,
+                    ));
+                    Ty::Error
+                }
+            },
+            _ => {
+                self.error(Diagnostic::error(
+                    format!("type `{}` has no fields", base),
+                    name.span,
+                ));
+                Ty::Error
+            }
+        }
+    }
+
+    fn check_call(
+        &mut self,
+        expr: &Expr,
+        callee: &Expr,
+        generics: &[TypeExpr],
+        args: &[Arg],
+        expected: Option<&Ty>,
+    ) -> Ty {
+        let explicit: Vec<Ty> = generics.iter().map(|g| self.lower(g)).collect();
+        if self.resolutions.is_err(callee.id) {
+            for arg in args {
+                self.check_expr(&arg.value, None);
+            }
+            return Ty::Error;
+        }
+
+        // Method calls: `value::method(..)` or `(expr)::method(..)`.
+        let receiver = match &callee.kind {
+            ExprKind::Path(segments) if self.is_method_path(callee.id, segments) => {
+                let (method, receiver) = segments.split_last().unwrap();
+                let receiver_ty = self.check_path(callee.id, receiver, None, callee.span);
+                Some((receiver_ty, method, PlaceExpr::Path(callee.id, receiver)))
+            }
+            ExprKind::Field { base, name } => {
+                Some((self.check_expr(base, None), name, PlaceExpr::Expr(base)))
+            }
+            _ => None,
+        };
+        if let Some((receiver_ty, method, place)) = receiver {
+            let ty = self.check_method_call(expr, &receiver_ty, method, &explicit, args, expected);
+            self.check_receiver(expr, place, method);
+            return ty;
+        }
+
+        if let ExprKind::Path(segments) = &callee.kind {
+            if let Some(Res::Builtin(_)) = self.resolutions.get(callee.id)
+                && let Some(ty) = self.check_builtin_call(expr, &segments[0], &explicit, args)
+            {
+                return ty;
+            }
+            match self.resolved(callee.id, segments) {
+                Some(Resolved::Value(name)) => {
+                    let sig = self.items.functions[&name].clone();
+                    self.check_not_interrupt(&sig, "called", callee.span);
+                    let target = CallTarget::free(name);
+                    return self.check_args(expr, target, &sig, &explicit, args, expected);
+                }
+                Some(Resolved::Member(type_name, variant))
+                    if self.items.unions.contains_key(&type_name) =>
+                {
+                    return self.check_variant_ctor(&type_name, variant, args, expected, expr.span);
+                }
+                Some(Resolved::Member(type_name, item)) => {
+                    if let Some(sig) = self.method(&type_name, item) {
+                        let target = CallTarget::method(&type_name, &item.name, Vec::new());
+                        return self.check_args(expr, target, &sig, &explicit, args, expected);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        match self.check_expr(callee, None) {
+            Ty::Fn { params, ret } => {
+                if params.len() != args.len() {
+                    self.error(Diagnostic::error(
+                        format!(
+                            "this call takes {} argument(s) but {} were supplied",
+                            params.len(),
+                            args.len()
+                        ),
+                        expr.span,
+                    ));
+                }
+                for (arg, param) in args.iter().zip(&params) {
+                    self.check_expr_against(&arg.value, param);
+                }
+                *ret
+            }
+            Ty::Error => {
+                for arg in args {
+                    self.check_expr(&arg.value, None);
+                }
+                Ty::Error
+            }
+            other => {
+                self.error(Diagnostic::error(
+                    format!("expected a function, found `{}`", other),
+                    callee.span,
+                ));
+                Ty::Error
+            }
+        }
+    }
+
+    /// `print`, `exit` and the intrinsics are provided by the compiler.
+    fn check_builtin_call(
+        &mut self,
+        expr: &Expr,
+        name: &Ident,
+        explicit: &[Ty],
+        args: &[Arg],
+    ) -> Option<Ty> {
+        let ret = match name.name.as_str() {
+            "print" => Ty::Unit,
+            "exit" => Ty::Never,
+            "size_of" | "align_of" => {
+                self.check_layout_of(expr, name, explicit, args);
+                return Some(Ty::Int);
+            }
+            "offset_of" => {
+                self.check_offset_of(expr, name, args);
+                return Some(Ty::Int);
+            }
+            "volatile_read"
+            | "volatile_write"
+            | "atomic_load"
+            | "atomic_store"
+            | "atomic_swap"
+            | "atomic_compare_exchange"
+            | "atomic_fetch_add"
+            | "atomic_fetch_sub" => {
+                return Some(self.check_access(name, args));
+            }
+            _ => return self.check_intrinsic(expr, name, args),
+        };
+        if args.len() != 1 {
+            self.error(Diagnostic::error(
+                format!("`{}` takes exactly one argument", name.name),
+                name.span,
+            ));
+        }
+        let tys: Vec<Ty> = args
+            .iter()
+            .map(|arg| {
+                let ty = self.check_expr(&arg.value, None);
+                self.infcx.resolve(&ty)
+            })
+            .collect();
+        // Without the C library there is no output to print to or process
+        // to exit; `exit` with a message calls the panic handler instead.
+        let message = matches!(tys.as_slice(), [Ty::Str | Ty::Error]);
+        if self.target == Target::Freestanding && (name.name == "print" || !message) {
+            let what = match name.name.as_str() {
+                "print" => "`print`",
+                _ => "`exit` with a code",
+            };
+            self.error(
+                Diagnostic::error(
+                    format!("{} isn't available in freestanding programs", what),
+                    name.span,
+                )
+                .with_note("it needs the C library, which freestanding programs don't link with"),
+            );
+        }
+        Some(ret)
+    }
+
+    /// `volatile_read(p)` and `volatile_write(p, value)` access what the
+    /// raw reference `p` points to exactly once, as a single instruction.
+    /// The atomic intrinsics do so indivisibly: `atomic_load(p)`,
+    /// `atomic_store(p, value)`, `atomic_swap(p, value)`,
+    /// `atomic_compare_exchange(p, current, new)` and
+    /// `atomic_fetch_add(p, value)` or `atomic_fetch_sub`, which all but the
+    /// store return the value `p` held before.
+    fn check_access(&mut self, name: &Ident, args: &[Arg]) -> Ty {
+        let (write, values) = match name.name.as_str() {
+            "volatile_read" | "atomic_load" => (false, 0),
+            "atomic_compare_exchange" => (true, 2),
+            _ => (true, 1),
+        };
+        let atomic = name.name.starts_with("atomic_");
+        let arithmetic = name.name.starts_with("atomic_fetch_");
+        let returns = !matches!(name.name.as_str(), "volatile_write" | "atomic_store");
+        self.require_unsafe(
+            &format!("call to `{}`", name.name),
+            name.span,
+            "raw references may be null, dangling or unaligned",
+        );
+        if args.len() != values + 1 {
+            self.error(Diagnostic::error(
+                format!(
+                    "this call takes {} argument(s) but {} were supplied",
+                    values + 1,
+                    args.len()
+                ),
+                name.span,
+            ));
+        }
+        let Some(pointer) = args.first() else {
+            return if returns { Ty::Error } else { Ty::Unit };
+        };
+        let ty = self.check_expr(&pointer.value, None);
+        let inner = match self.infcx.resolve(&ty) {
+            Ty::RawRef { mutable, inner } if mutable || !write => Some(*inner),
+            Ty::Error => None,
+            other => {
+                let wanted = if write { "raw_ref mut" } else { "raw_ref" };
+                self.error(Diagnostic::error(
+                    format!("`{}` takes a `{}`, found `{}`", name.name, wanted, other),
+                    pointer.value.span,
+                ));
+                None
+            }
+        };
+        let accessible = match &inner {
+            Some(Ty::Int | Ty::Byte | Ty::Error) | None => true,
+            Some(Ty::Bool | Ty::Char | Ty::Ref { .. } | Ty::RawRef { .. }) => !arithmetic,
+            Some(Ty::Float) => !atomic,
+            Some(_) => false,
+        };
+        if let Some(inner) = &inner
+            && !accessible
+        {
+            let diagnostic = if arithmetic {
+                Diagnostic::error(
+                    format!("`{}` can't do arithmetic on a `{}`", name.name, inner),
+                    pointer.value.span,
+                )
+                .with_note("atomic arithmetic is on `int`s and `byte`s")
+            } else {
+                let (kind, numbers) = if atomic {
+                    ("atomic", "an integer")
+                } else {
+                    ("volatile", "a number")
+                };
+                Diagnostic::error(
+                    format!("`{}` can't access a `{}` at once", name.name, inner),
+                    pointer.value.span,
+                )
+                .with_note(format!(
+                    "{} accesses are to {}, `bool`, `char` or reference",
+                    kind, numbers
+                ))
+            };
+            self.error(diagnostic);
+        }
+        for (i, arg) in args.iter().enumerate().skip(1) {
+            match &inner {
+                Some(inner) if i <= values => self.check_expr_against(&arg.value, inner),
+                _ => self.check_expr(&arg.value, None),
+            };
+        }
+        match inner {
+            _ if !returns => Ty::Unit,
+            Some(inner) => inner,
+            None => Ty::Error,
+        }
+    }
+
+    /// Port I/O and fences, which compile to the instructions they name.
+    fn check_intrinsic(&mut self, expr: &Expr, name: &Ident, args: &[Arg]) -> Option<Ty> {
+        let (params, ret, note) = match name.name.as_str() {
+            "inb" => (vec![Ty::Int], Ty::Byte, PORT_IO),
+            "inw" | "inl" => (vec![Ty::Int], Ty::Int, PORT_IO),
+            "outb" => (vec![Ty::Int, Ty::Byte], Ty::Unit, PORT_IO),
+            "outw" | "outl" => (vec![Ty::Int, Ty::Int], Ty::Unit, PORT_IO),
+            "fence" | "load_fence" | "store_fence" => (
+                Vec::new(),
+                Ty::Unit,
+                "fences order memory accesses for devices and other processors",
+            ),
+            _ => return None,
+        };
+        self.require_unsafe(&format!("call to `{}`", name.name), name.span, note);
+        if params.len() != args.len() {
+            self.error(Diagnostic::error(
+                format!(
+                    "this call takes {} argument(s) but {} were supplied",
+                    params.len(),
+                    args.len()
+                ),
+                expr.span,
+            ));
+        }
+        for (i, arg) in args.iter().enumerate() {
+            match params.get(i) {
+                Some(param) => self.check_expr_against(&arg.value, param),
+                None => self.check_expr(&arg.value, None),
+            };
+        }
+        Some(ret)
+    }
+
+    /// `size_of[T]()` and `align_of[T]()` are evaluated here, so `T` must
+    /// have the same layout in every instantiation.
+    fn check_layout_of(&mut self, expr: &Expr, name: &Ident, explicit: &[Ty], args: &[Arg]) {
+        for arg in args {
+            self.check_expr(&arg.value, None);
+        }
+        let [ty] = explicit else {
+            self.error(Diagnostic::error(
+                format!(
+                    "`{}` takes exactly one type, as in `{}[int]()`",
+                    name.name, name.name
+                ),
+                name.span,
+            ));
+            return;
+        };
+        if !args.is_empty() {
+            self.error(Diagnostic::error(
+                format!("`{}` takes no arguments", name.name),
+                name.span,
+            ));
+        }
+        if ty.references_error() {
+            return;
+        }
+        match layout::layout(self.items, ty) {
+            Ok(layout) => {
+                let value = match name.name.as_str() {
+                    "size_of" => layout.size,
+                    _ => layout.align,
+                };
+                self.results.constants.insert(expr.id, value as i64);
+            }
+            Err(message) => self.error(
+                Diagnostic::error(
+                    format!("`{}` can't measure {}", name.name, message),
+                    name.span,
+                )
+                .with_note("only types with the same layout in every program can be measured"),
+            ),
+        }
+    }
+
+    /// `offset_of(record, field)` names its arguments rather than
+    /// evaluating them.
+    fn check_offset_of(&mut self, expr: &Expr, name: &Ident, args: &[Arg]) {
+        let (record, field) = match args {
+            [record, field] => match (&record.value.kind, &field.value.kind) {
+                (ExprKind::Path(record), ExprKind::Path(field)) if field.len() == 1 => {
+                    (record, &field[0])
+                }
+                _ => {
+                    self.error(Diagnostic::error(
+                        "`offset_of` takes a record and one of its fields, as in `offset_of(Header, len)`",
+                        expr.span,
+                    ));
+                    return;
+                }
+            },
+            _ => {
+                self.error(Diagnostic::error(
+                    "`offset_of` takes exactly two arguments",
+                    name.span,
+                ));
+                return;
+            }
+        };
+        let span = record[0].span.to(record[record.len() - 1].span);
+        let path = join_path(record);
+        let canonical = match self.items.lookup_type(&path) {
+            Ok(canonical) => canonical.to_string(),
+            Err(err) => {
+                self.error(lookup_error("record", &path, err, span));
+                return;
+            }
+        };
+        let Some(def) = self.items.records.get(&canonical) else {
+            self.error(Diagnostic::error(
+                format!("`{}` isn't a record", path),
+                span,
+            ));
+            return;
+        };
+        if !def.generics.is_empty() {
+            self.error(
+                Diagnostic::error(
+                    format!("`offset_of` can't measure the generic record `{}`", path),
+                    span,
+                )
+                .with_note("its fields move with its generic arguments"),
+            );
+            return;
+        }
+        let Some(index) = def.fields.iter().position(|(n, _)| *n == field.name) else {
+            self.error(Diagnostic::error(
+                format!("record `{}` has no field `{}`", path, field.name),
+                field.span,
+            ));
+            return;
+        };
+        if def.bits(index).is_some() {
+            self.error(Diagnostic::error(
+                format!("the bitfield `{}` has no byte offset", field.name),
+                field.span,
+            ));
+            return;
+        }
+        let ty = Ty::Adt {
+            name: canonical,
+            args: Vec::new(),
+        };
+        match layout::fields(self.items, &ty, None) {
+            Ok(fields) => {
+                let offset = fields[index].offset;
+                self.results.constants.insert(expr.id, offset as i64);
+            }
+            Err(message) => self.error(Diagnostic::error(
+                format!("`offset_of` can't measure {}", message),
+                span,
+            )),
+        }
+    }
+
+    fn check_method_call(
+        &mut self,
+        expr: &Expr,
+        receiver: &Ty,
+        method: &Ident,
+        explicit: &[Ty],
+        args: &[Arg],
+        expected: Option<&Ty>,
+    ) -> Ty {
+        let mut base = receiver;
+        while let Ty::Ref { inner, .. } = base {
+            base = inner;
+        }
+        let (type_name, type_args) = match base {
+            Ty::Adt { name, args } => (name.clone(), args.clone()),
+            Ty::Param(param) => {
+                return self.check_bounded_call(expr, param, method, explicit, args, expected);
+            }
+            Ty::Dyn(protocol) => {
+                return self.check_object_call(expr, protocol, method, explicit, args, expected);
+            }
+            Ty::Error | Ty::Infer(_) => {
+                if let Ty::Infer(_) = base {
+                    self.error(unknown_type(method.span));
+                }
+                for arg in args {
+                    self.check_expr(&arg.value, None);
+                }
+                return Ty::Error;
+            }
+            other => {
+                self.error(Diagnostic::error(
+                    format!("no method named `{}` found for `{}`", method.name, other),
+                    method.span,
+                ));
+                return Ty::Error;
+            }
+        };
+        let Some(sig) = self.method(&type_name, method) else {
+            self.error(Diagnostic::error(
+                format!("no method named `{}` found for `{}`", method.name, base),
+                method.span,
+            ));
+            return Ty::Error;
+        };
+        if sig.self_kind.is_none() {
+            self.error(
+                Diagnostic::error(
+                    format!(
+                        "`{}` is an associated function of `{}`, not a method",
+                        method.name, type_name
+                    ),
+                    method.span,
+                )
+                .with_note(format!("call it as `{}::{}(..)`", type_name, method.name)),
+            );
+        }
+        let target = CallTarget::method(&type_name, &method.name, type_args);
+        self.check_args(expr, target, &sig, explicit, args, expected)
+    }
+
+    /// The `Ordering` of an atomic load or store, which must be written out
+    /// so that one it can't have, like a `Release` load, is always caught
+    /// here and never has to be checked at runtime.
+    fn check_ordering(&mut self, sig: &FnSig, param: &ParamSig, arg: &Expr) {
+        let Some(owner) = &sig.owner else {
+            return;
+        };
+        if !prelude::ATOMICS.contains(&owner.as_str()) {
+            return;
+        }
+        let Some((operation, forbidden)) = prelude::forbidden_orderings(&sig.name, &param.name)
+        else {
+            return;
+        };
+        let written = match (&arg.kind, self.resolutions.get(arg.id)) {
+            (ExprKind::Path(_), Some(Res::Variant { union, variant }))
+                if union == prelude::ORDERING =>
+            {
+                Some(variant)
+            }
+            _ => None,
+        };
+        match written {
+            Some(variant) if forbidden.contains(&variant.as_str()) => {
+                self.error(Diagnostic::error(
+                    format!("a {} can't have `{}` ordering", operation, variant),
+                    arg.span,
+                ));
+            }
+            Some(_) => {}
+            None => {
+                let forbidden: Vec<String> = forbidden.iter().map(|o| format!("`{}`", o)).collect();
+                self.error(
+                    Diagnostic::error(
+                        format!("the ordering of a {} must be written out", operation),
+                        arg.span,
+                    )
+                    .with_note(format!(
+                        "write `Ordering::` and its name, so the checker can tell it isn't {}",
+                        forbidden.join(" or ")
+                    )),
+                );
+            }
+        }
+    }
+
+    /// A method called on a protocol object dispatches through its vtable.
+    fn check_object_call(
+        &mut self,
+        expr: &Expr,
+        protocol: &str,
+        method: &Ident,
+        explicit: &[Ty],
+        args: &[Arg],
+        expected: Option<&Ty>,
+    ) -> Ty {
+        let sig = self
+            .items
+            .protocols
+            .get(protocol)
+            .and_then(|sigs| sigs.iter().find(|sig| sig.name == method.name))
+            .cloned();
+        let Some(sig) = sig else {
+            self.error(Diagnostic::error(
+                format!(
+                    "no method named `{}` found for protocol `{}`",
+                    method.name, protocol
+                ),
+                method.span,
+            ));
+            for arg in args {
+                self.check_expr(&arg.value, None);
+            }
+            return Ty::Error;
+        };
+        let target = CallTarget {
+            func: FnRef {
+                owner: FnOwner::Protocol(protocol.to_string()),
+                name: method.name.clone(),
+            },
+            self_ty: Some(Ty::Dyn(protocol.to_string())),
+            impl_args: Vec::new(),
+        };
+        self.check_args(expr, target, &sig, explicit, args, expected)
+    }
+
+    /// A method called on a generic parameter must come from one of the
+    /// protocols it is bounded by; which implementation runs is decided per
+    /// instance.
+    fn check_bounded_call(
+        &mut self,
+        expr: &Expr,
+        param: &str,
+        method: &Ident,
+        explicit: &[Ty],
+        args: &[Arg],
+        expected: Option<&Ty>,
+    ) -> Ty {
+        let bounds = self.bounds.get(param).cloned().unwrap_or_default();
+        let found = bounds.iter().find_map(|protocol| {
+            let sig = self.items.protocols.get(protocol)?;
+            let sig = sig.iter().find(|sig| sig.name == method.name)?;
+            Some((protocol.clone(), sig.clone()))
+        });
+        let Some((protocol, sig)) = found else {
+            let mut diagnostic = Diagnostic::error(
+                format!(
+                    "no method named `{}` found for type parameter `{}`",
+                    method.name, param
+                ),
+                method.span,
+            );
+            let mut providers: Vec<&String> = self
+                .items
+                .protocols
+                .iter()
+                .filter(|(_, sigs)| sigs.iter().any(|sig| sig.name == method.name))
+                .map(|(protocol, _)| protocol)
+                .collect();
+            providers.sort();
+            if let Some(protocol) = providers.first() {
+                diagnostic = diagnostic.with_note(format!(
+                    "protocol `{}` provides `{}`; bound the parameter with `[{}: {}]`",
+                    protocol, method.name, param, protocol
+                ));
+            }
+            self.error(diagnostic);
+            for arg in args {
+                self.check_expr(&arg.value, None);
+            }
+            return Ty::Error;
+        };
+        let target = CallTarget {
+            func: FnRef {
+                owner: FnOwner::Protocol(protocol),
+                name: method.name.clone(),
+            },
+            self_ty: Some(Ty::Param(param.to_string())),
+            impl_args: Vec::new(),
+        };
+        self.check_args(expr, target, &sig, explicit, args, expected)
+    }
+
+    /// Matches labelled and positional arguments against `sig`'s parameters
+    /// and returns the call's result type.
+    fn check_args(
+        &mut self,
+        expr: &Expr,
+        target: CallTarget,
+        sig: &FnSig,
+        explicit: &[Ty],
+        args: &[Arg],
+        expected: Option<&Ty>,
+    ) -> Ty {
+        let span = expr.span;
+        let mut names = sig.impl_generics.clone();
+        let mut subst = target.impl_args;
+        for generic in &sig.impl_generics[subst.len().min(sig.impl_generics.len())..] {
+            let var = self.fresh_var(format!("`{}` for `{}`", generic, sig.name), span);
+            subst.push(var);
+        }
+        subst.truncate(sig.impl_generics.len());
+        if explicit.is_empty() {
+            // Instantiate with fresh variables, solved from the arguments
+            // and the expected result.
+            for generic in &sig.generics {
+                let var = self.fresh_var(format!("`{}` for `{}`", generic, sig.name), span);
+                names.push(generic.clone());
+                subst.push(var);
+            }
+        } else {
+            if explicit.len() != sig.generics.len() {
+                self.error(Diagnostic::error(
+                    format!(
+                        "`{}` expects {} generic argument(s), found {}",
+                        sig.name,
+                        sig.generics.len(),
+                        explicit.len()
+                    ),
+                    span,
+                ));
+            } else {
+                names.extend(sig.generics.iter().cloned());
+                subst.extend(explicit.iter().cloned());
+            }
+        }
+
+        if subst.len() == sig.impl_generics.len() + sig.generics.len() {
+            let own = &subst[sig.impl_generics.len()..];
+            for ((generic, bounds), ty) in sig.generics.iter().zip(&sig.bounds).zip(own) {
+                for protocol in bounds {
+                    self.obligations.push(Obligation {
+                        ty: ty.clone(),
+                        protocol: protocol.clone(),
+                        generic: generic.clone(),
+                        function: sig.name.clone(),
+                        span,
+                    });
+                }
+            }
+            self.results.callees.insert(
+                expr.id,
+                Callee {
+                    func: target.func,
+                    args: subst.clone(),
+                    self_ty: target.self_ty,
+                    caller: self.current.clone(),
+                    span,
+                },
+            );
+        }
+
+        // Let the expected result pick generic arguments before the
+        // arguments are checked, so `Option[byte] o := wrap(1)` makes `1` a
+        // byte. A mismatch is reported by the caller, against the result.
+        let ret = sig.ret.subst(&names, &subst);
+        if let Some(expected) = expected {
+            self.infcx.unify(&ret, expected);
+        }
+
+        let mut slots: Vec<Option<&Arg>> = vec![None; sig.params.len()];
+        let mut variadic = Vec::new();
+        let mut next_positional = 0;
+        for arg in args {
+            let index = match &arg.label {
+                Some(label) => {
+                    let found = sig
+                        .params
+                        .iter()
+                        .position(|p| p.label.as_deref().unwrap_or(p.name.as_str()) == label.name);
+                    match found {
+                        Some(index) if slots[index].is_some() => {
+                            self.error(Diagnostic::error(
+                                format!("argument `{}` is supplied more than once", label.name),
+                                label.span,
+                            ));
+                            continue;
+                        }
+                        Some(index) => index,
+                        None => {
+                            self.error(Diagnostic::error(
+                                format!(
+                                    "`{}` has no parameter labelled `{}`",
+                                    sig.name, label.name
+                                ),
+                                label.span,
+                            ));
+                            self.check_expr(&arg.value, None);
+                            continue;
+                        }
+                    }
+                }
+                None => {
+                    while next_positional < slots.len() && slots[next_positional].is_some() {
+                        next_positional += 1;
+                    }
+                    if next_positional == slots.len() && sig.variadic {
+                        variadic.push(arg);
+                        continue;
+                    }
+                    if next_positional == slots.len() {
+                        self.error(Diagnostic::error(
+                            format!(
+                                "`{}` takes {} argument(s) but more were supplied",
+                                sig.name,
+                                sig.params.len()
+                            ),
+                            arg.value.span,
+                        ));
+                        self.check_expr(&arg.value, None);
+                        continue;
+                    }
+                    next_positional
+                }
+            };
+            slots[index] = Some(arg);
+        }
+
+        let mut missing = Vec::new();
+        for (param, slot) in sig.params.iter().zip(&slots) {
+            let ty = param.ty.subst(&names, &subst);
+            match slot {
+                Some(arg) => {
+                    self.check_expr_against(&arg.value, &ty);
+                    self.check_ordering(sig, param, &arg.value);
+                }
+                None => missing.push(format!("`{}`", param.label.as_ref().unwrap_or(&param.name))),
+            }
+        }
+        for arg in variadic {
+            let ty = self.check_expr(&arg.value, None);
+            let ty = self.infcx.resolve(&ty);
+            if !matches!(ty, Ty::Infer(_)) && !self.items.c_compatible(&ty) {
+                self.error(
+                    Diagnostic::error(
+                        format!("a `{}` can't be passed to C as a variadic argument", ty),
+                        arg.value.span,
+                    )
+                    .with_note(
+                        "C has counterparts for numbers, `bool`, `char`, references and \
+                         `#[repr(C)]` records",
+                    ),
+                );
+            }
+        }
+        if !missing.is_empty() {
+            self.error(Diagnostic::error(
+                format!(
+                    "missing argument(s) {} in call to `{}`",
+                    missing.join(", "),
+                    sig.name
+                ),
+                span,
+            ));
+        }
+        ret
+    }
+
+    /// Generic arguments for `name`, taken from the expected type when it
+    /// names the same record or union and inferred otherwise.
+    fn adt_args(
+        &mut self,
+        expected: Option<&Ty>,
+        name: &str,
+        generics: &[String],
+        span: Span,
+    ) -> Vec<Ty> {
+        match expected {
+            Some(Ty::Adt { name: n, args }) if n == name && args.len() == generics.len() => {
+                args.clone()
+            }
+            _ => generics
+                .iter()
+                .map(|g| self.fresh_var(format!("`{}` for `{}`", g, name), span))
+                .collect(),
+        }
+    }
+
+    fn check_variant_ctor(
+        &mut self,
+        union_name: &str,
+        variant: &Ident,
+        args: &[Arg],
+        expected: Option<&Ty>,
+        span: Span,
+    ) -> Ty {
+        let union = &self.items.unions[union_name];
+        let generics = union.generics.clone();
+        let type_args = self.adt_args(expected, union_name, &generics, span);
+        let Some(fields) = union.variant(&variant.name).map(<[Ty]>::to_vec) else {
+            self.error(Diagnostic::error(
+                format!(
+                    "no variant named `{}` in union `{}`",
+                    variant.name, union_name
+                ),
+                variant.span,
+            ));
+            return Ty::Error;
+        };
+        if fields.len() != args.len() {
+            self.error(Diagnostic::error(
+                format!(
+                    "variant `{}::{}` has {} field(s) but {} were supplied",
+                    union_name,
+                    variant.name,
+                    fields.len(),
+                    args.len()
+                ),
+                span,
+            ));
+        }
+        for (arg, field) in args.iter().zip(&fields) {
+            self.check_expr_against(&arg.value, &field.subst(&generics, &type_args));
+        }
+        Ty::Adt {
+            name: union_name.to_string(),
+            args: type_args,
+        }
+    }
+
+    fn check_record_lit(
+        &mut self,
+        expr: &Expr,
+        path: &[Ident],
+        generics: &[TypeExpr],
+        fields: &[FieldInit],
+        expected: Option<&Ty>,
+    ) -> Ty {
+        // Records the resolver couldn't find were reported with it.
+        let Some(Res::Record(name)) = self.resolutions.get(expr.id) else {
+            for field in fields {
+                self.check_expr(&field.value, None);
+            }
+            return Ty::Error;
+        };
+        let name = name.clone();
+        let written = join_path(path);
+        let record = &self.items.records[&name];
+        let args = if generics.is_empty() {
+            let generics = record.generics.clone();
+            self.adt_args(expected, &name, &generics, expr.span)
+        } else {
+            generics.iter().map(|g| self.lower(g)).collect()
+        };
+        let declared = self.items.record_fields(&name, &args).unwrap();
+
+        let mut seen: Vec<&str> = Vec::new();
+        for field in fields {
+            if seen.contains(&field.name.name.as_str()) {
+                self.error(Diagnostic::error(
+                    format!("field `{}` is specified more than once", field.name.name),
+                    field.name.span,
+                ));
+                continue;
+            }
+            seen.push(&field.name.name);
+            match declared.iter().find(|(n, _)| n == &field.name.name) {
+                Some((_, ty)) => {
+                    self.check_expr_against(&field.value, ty);
+                }
+                None => {
+                    self.error(Diagnostic::error(
+                        format!(
+                            "record `{}` has no field named `{}`",
+                            written, field.name.name
+                        ),
+                        field.name.span,
+                    ));
+                    self.check_expr(&field.value, None);
+                }
+            }
+        }
+        // `Option` fields may be left out; they start as `None`.
+        let missing: Vec<String> = declared
+            .iter()
+            .filter(|(n, ty)| {
+                !seen.contains(&n.as_str())
+                    && !matches!(ty, Ty::Adt { name, .. } if name == prelude::OPTION)
+            })
+            .map(|(n, _)| format!("`{}`", n))
+            .collect();
+        if !missing.is_empty() {
+            self.error(Diagnostic::error(
+                format!(
+                    "missing field(s) {} in initializer of `{}`",
+                    missing.join(", "),
+                    written
+                ),
+                expr.span,
+            ));
+        }
+        Ty::Adt { name, args }
+    }
+
+    fn check_match(&mut self, scrutinee: &Expr, arms: &[Arm], expected: Option<&Ty>) -> Ty {
+        let scrutinee_ty = self.check_expr(scrutinee, None);
+        let mut result: Option<(Ty, Span)> = None;
+        for arm in arms {
+            self.check_pattern(&arm.pattern, &scrutinee_ty);
+            let hint = expected.cloned().or_else(|| {
+                result
+                    .as_ref()
+                    .filter(|(ty, _)| ty != &Ty::Never)
+                    .map(|(ty, _)| ty.clone())
+            });
+            let ty = self.check_expr(&arm.body, hint.as_ref());
+            result = Some(match result {
+                None => (ty, arm.body.span),
+                Some((first, first_span)) => {
+                    let unified = self.unify_branches(
+                        first,
+                        first_span,
+                        ty,
+                        arm.body.span,
+                        "`match` arms have incompatible types",
+                    );
+                    (unified, first_span)
+                }
+            });
+        }
+        result.map_or(Ty::Never, |(ty, _)| ty)
+    }
+
+    fn check_pattern(&mut self, pattern: &Pattern, expected: &Ty) {
+        let expected = &self.infcx.resolve(expected);
+        match &pattern.kind {
+            PatternKind::Wildcard => {}
+            PatternKind::Literal(literal) => {
+                let ty = literal_ty(literal, Some(expected));
+                self.demand(&ty, expected, pattern.span, None);
+            }
+            PatternKind::Binding(name) => {
+                // A bare name that is a variant of the matched union (`None`)
+                // is a variant pattern, not a new binding.
+                if let Ty::Adt { name: adt, .. } = expected
+                    && let Some(union) = self.items.unions.get(adt)
+                    && union.variant(&name.name).is_some()
+                {
+                    return;
+                }
+                self.declare(
+                    Some(pattern.id),
+                    expected.clone(),
+                    false,
+                    MutFixes::default(),
+                );
+            }
+            PatternKind::Tuple(elems) => match expected {
+                Ty::Tuple(tys) if tys.len() == elems.len() => {
+                    for (elem, ty) in elems.iter().zip(tys.clone()) {
+                        self.check_pattern(elem, &ty);
+                    }
+                }
+                Ty::Error => {
+                    for elem in elems {
+                        self.check_pattern(elem, &Ty::Error);
+                    }
+                }
+                _ => self.error(Diagnostic::error(
+                    format!(
+                        "mismatched types: expected `{}`, found a tuple pattern",
+                        expected
+                    ),
+                    pattern.span,
+                )),
+            },
+            PatternKind::Variant { fields, .. } if self.resolutions.is_err(pattern.id) => {
+                for field in fields {
+                    self.check_pattern(field, &Ty::Error);
+                }
+            }
+            PatternKind::Variant { path, fields } => {
+                let variant = path.last().unwrap();
+                let (union_name, args) = match expected {
+                    Ty::Adt { name, args } if self.items.unions.contains_key(name) => {
+                        (name.clone(), args.clone())
+                    }
+                    Ty::Error => {
+                        for field in fields {
+                            self.check_pattern(field, &Ty::Error);
+                        }
+                        return;
+                    }
+                    _ => {
+                        self.error(Diagnostic::error(
+                            format!(
+                                "mismatched types: expected `{}`, found a variant pattern",
+                                expected
+                            ),
+                            pattern.span,
+                        ));
+                        return;
+                    }
+                };
+                if let Some(Res::Variant { union, .. }) = self.resolutions.get(pattern.id)
+                    && *union != union_name
+                {
+                    self.error(Diagnostic::error(
+                        format!(
+                            "mismatched types: expected `{}`, found `{}`",
+                            expected,
+                            join_path(&path[..path.len() - 1])
+                        ),
+                        pattern.span,
+                    ));
+                    return;
+                }
+                let union = &self.items.unions[&union_name];
+                let generics = union.generics.clone();
+                let Some(field_tys) = union.variant(&variant.name).map(<[Ty]>::to_vec) else {
+                    self.error(Diagnostic::error(
+                        format!(
+                            "no variant named `{}` in union `{}`",
+                            variant.name, union_name
+                        ),
+                        variant.span,
+                    ));
+                    return;
+                };
+                if field_tys.len() != fields.len() {
+                    self.error(Diagnostic::error(
+                        format!(
+                            "variant `{}` has {} field(s) but the pattern has {}",
+                            variant.name,
+                            field_tys.len(),
+                            fields.len()
+                        ),
+                        pattern.span,
+                    ));
+                }
+                for (field, ty) in fields.iter().zip(field_tys) {
+                    self.check_pattern(field, &ty.subst(&generics, &args));
+                }
+            }
+        }
+    }
+}
+
+/// Follows the references fields are reached through. Past a `ref mut`
+/// the binding's own mutability no longer matters; past a `ref` nothing may
+/// change. `fix` makes the outermost reference mutable.
+fn deref_mutability(
+    mut result: Result<(), Immutable>,
+    ty: &Ty,
+    mut fix: Option<(Span, String)>,
+) -> (Result<(), Immutable>, Ty) {
+    let mut ty = ty.clone();
+    while let Ty::Ref { mutable, inner } = ty {
+        result = match result {
+            Err(Immutable::Ref { fix }) => Err(Immutable::Ref { fix }),
+            _ if mutable => Ok(()),
+            _ => Err(Immutable::Ref { fix: fix.take() }),
+        };
+        ty = *inner;
+    }
+    (result, ty)
+}
+
+/// The error for changing `place`, which `reason` forbids.
+fn immutable_error(place: PlaceExpr, reason: Immutable, action: &str) -> Diagnostic {
+    let (described, span) = match place {
+        PlaceExpr::Path(_, segments) => (
+            Some(join_path(segments)),
+            segments[0].span.to(segments.last().unwrap().span),
+        ),
+        PlaceExpr::Expr(expr) => (describe_place(expr), expr.span),
+    };
+    let what = described.map_or("this value".to_string(), |d| format!("`{}`", d));
+    match reason {
+        Immutable::Binding { name, fix } => {
+            let message = if what == format!("`{}`", name) {
+                format!("cannot {} {}, as it is not declared `mut`", action, what)
+            } else {
+                format!(
+                    "cannot {} {}, as `{}` is not declared `mut`",
+                    action, what, name
+                )
+            };
+            let diagnostic = Diagnostic::error(message, span);
+            match fix {
+                Some((at, edit)) => {
+                    diagnostic.with_fixit(at, edit, format!("make `{}` mutable", name))
+                }
+                None => diagnostic,
+            }
+        }
+        Immutable::Ref { fix } => {
+            let diagnostic = Diagnostic::error(
+                format!(
+                    "cannot {} {}, as it is behind a `ref` reference",
+                    action, what
+                ),
+                span,
+            )
+            .with_note("only a `ref mut` reference allows changes");
+            match fix {
+                Some((at, edit)) => diagnostic.with_fixit(at, edit, "make the reference mutable"),
+                None => diagnostic,
+            }
+        }
+    }
+}
+
+/// How a place is written in diagnostics, e.g. `h::age`.
+fn describe_place(expr: &Expr) -> Option<String> {
+    match &expr.kind {
+        ExprKind::Path(segments) => Some(join_path(segments)),
+        ExprKind::Field { base, name } => Some(format!("{}::{}", describe_place(base)?, name.name)),
+        ExprKind::Deref(inner) => Some(format!("deref {}", describe_place(inner)?)),
+        _ => None,
+    }
+}
+
+fn literal_ty(literal: &Literal, expected: Option<&Ty>) -> Ty {
+    match literal {
+        // Integer literals that fit are accepted where a `byte` is expected.
+        Literal::Int(value) if expected == Some(&Ty::Byte) && *value <= u8::MAX as usize => {
+            Ty::Byte
+        }
+        Literal::Int(_) => Ty::Int,
+        Literal::Float(_) => Ty::Float,
+        Literal::Str(_) => Ty::Str,
+        Literal::Bool(_) => Ty::Bool,
+        Literal::Char(_) => Ty::Char,
+    }
+}
+
+fn unknown_type(span: Span) -> Diagnostic {
+    Diagnostic::error(
+        "type annotations needed: the type of this value isn't known here",
+        span,
+    )
+    .with_note("annotate the closure parameter or binding this value comes from")
+}
+
+/// `ty` with unsolved inference variables replaced by `{unknown}`.
+fn without_vars(ty: &Ty) -> Ty {
+    match ty {
+        Ty::Infer(_) => Ty::Error,
+        Ty::Tuple(elems) => Ty::Tuple(elems.iter().map(without_vars).collect()),
+        Ty::Ref { mutable, inner } => Ty::Ref {
+            mutable: *mutable,
+            inner: Box::new(without_vars(inner)),
+        },
+        Ty::RawRef { mutable, inner } => Ty::RawRef {
+            mutable: *mutable,
+            inner: Box::new(without_vars(inner)),
+        },
+        Ty::Adt { name, args } => Ty::Adt {
+            name: name.clone(),
+            args: args.iter().map(without_vars).collect(),
+        },
+        Ty::Fn { params, ret } => Ty::Fn {
+            params: params.iter().map(without_vars).collect(),
+            ret: Box::new(without_vars(ret)),
+        },
+        ty => ty.clone(),
+    }
+}
+
+/// The `asm` blocks `stmts` consist of, looking into `unsafe` blocks, or the
+/// span of the first statement that is something else.
+fn naked_asm<'a>(stmts: &'a [Stmt], blocks: &mut Vec<(&'a AsmBlock, Span)>) -> Result<(), Span> {
+    for stmt in stmts {
+        match &stmt.kind {
+            StmtKind::Expr(Expr {
+                kind: ExprKind::Asm(asm),
+                span,
+                ..
+            }) => blocks.push((asm, *span)),
+            StmtKind::Expr(Expr {
+                kind: ExprKind::Unsafe(block),
+                ..
+            }) => naked_asm(&block.stmts, blocks)?,
+            _ => return Err(stmt.span),
+        }
+    }
+    Ok(())
+}
+
+fn block_ret_note(function: &Function) -> Option<String> {
+    function.ret.as_ref().map(|_| {
+        format!(
+            "the last expression of `{}` is its return value",
+            function.name.name
+        )
+    })
+}
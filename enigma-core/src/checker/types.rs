@@ -0,0 +1,176 @@
+use std::fmt;
+
+/// Names `Ty::primitive` accepts.
+pub const PRIMITIVES: &[&str] = &["int", "byte", "float", "bool", "char", "string", "unit"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Ty {
+    Int,
+    Byte,
+    Float,
+    Bool,
+    Char,
+    Str,
+    Unit,
+    /// The type of expressions that never produce a value (`return`, `break`,
+    /// `exit(..)`, a `loop` without `break`). It coerces to every type.
+    Never,
+    Tuple(Vec<Ty>),
+    Ref {
+        mutable: bool,
+        inner: Box<Ty>,
+    },
+    RawRef {
+        mutable: bool,
+        inner: Box<Ty>,
+    },
+    /// Records and unions, with their generic arguments.
+    Adt {
+        name: String,
+        args: Vec<Ty>,
+    },
+    Fn {
+        params: Vec<Ty>,
+        ret: Box<Ty>,
+    },
+    /// A protocol object: a value of some type implementing the protocol,
+    /// only found behind a reference (`ref live`). Calls dispatch through
+    /// the type's vtable.
+    Dyn(String),
+    /// A generic parameter of the enclosing item. Inside the item it only
+    /// matches itself.
+    Param(String),
+    /// An inference variable, solved by unification (see `infer.rs`).
+    Infer(u32),
+    /// Produced after an error has been reported; compatible with everything
+    /// so one mistake doesn't cascade into many diagnostics.
+    Error,
+}
+
+impl Ty {
+    pub fn primitive(name: &str) -> Option<Ty> {
+        let ty = match name {
+            "int" => Ty::Int,
+            "byte" => Ty::Byte,
+            "float" => Ty::Float,
+            "bool" => Ty::Bool,
+            "char" => Ty::Char,
+            "string" | "str" => Ty::Str,
+            "unit" => Ty::Unit,
+            _ => return None,
+        };
+        Some(ty)
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        matches!(self, Ty::Int | Ty::Byte | Ty::Float)
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(self, Ty::Int | Ty::Byte)
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, Ty::Error)
+    }
+
+    /// Unit, never and error types are all acceptable where no value is used.
+    pub fn is_unit_like(&self) -> bool {
+        matches!(self, Ty::Unit | Ty::Never | Ty::Error)
+    }
+
+    pub fn references_error(&self) -> bool {
+        match self {
+            Ty::Error => true,
+            Ty::Tuple(elems) => elems.iter().any(Ty::references_error),
+            Ty::Ref { inner, .. } | Ty::RawRef { inner, .. } => inner.references_error(),
+            Ty::Adt { args, .. } => args.iter().any(Ty::references_error),
+            Ty::Fn { params, ret } => {
+                params.iter().any(Ty::references_error) || ret.references_error()
+            }
+            _ => false,
+        }
+    }
+
+    /// Replaces generic parameters by name.
+    pub fn subst(&self, names: &[String], args: &[Ty]) -> Ty {
+        match self {
+            Ty::Param(name) => names
+                .iter()
+                .position(|n| n == name)
+                .and_then(|i| args.get(i).cloned())
+                .unwrap_or_else(|| self.clone()),
+            Ty::Tuple(elems) => Ty::Tuple(elems.iter().map(|t| t.subst(names, args)).collect()),
+            Ty::Ref { mutable, inner } => Ty::Ref {
+                mutable: *mutable,
+                inner: Box::new(inner.subst(names, args)),
+            },
+            Ty::RawRef { mutable, inner } => Ty::RawRef {
+                mutable: *mutable,
+                inner: Box::new(inner.subst(names, args)),
+            },
+            Ty::Adt { name, args: inner } => Ty::Adt {
+                name: name.clone(),
+                args: inner.iter().map(|t| t.subst(names, args)).collect(),
+            },
+            Ty::Fn { params, ret } => Ty::Fn {
+                params: params.iter().map(|t| t.subst(names, args)).collect(),
+                ret: Box::new(ret.subst(names, args)),
+            },
+            _ => self.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ty::Int => write!(f, "int"),
+            Ty::Byte => write!(f, "byte"),
+            Ty::Float => write!(f, "float"),
+            Ty::Bool => write!(f, "bool"),
+            Ty::Char => write!(f, "char"),
+            Ty::Str => write!(f, "string"),
+            Ty::Unit => write!(f, "unit"),
+            Ty::Never => write!(f, "never"),
+            Ty::Tuple(elems) => {
+                write!(f, "(")?;
+                write_list(f, elems)?;
+                write!(f, ")")
+            }
+            Ty::Ref { mutable, inner } => {
+                write!(f, "ref {}{}", if *mutable { "mut " } else { "" }, inner)
+            }
+            Ty::RawRef { mutable, inner } => {
+                write!(f, "raw_ref {}{}", if *mutable { "mut " } else { "" }, inner)
+            }
+            Ty::Adt { name, args } => {
+                write!(f, "{}", name)?;
+                if !args.is_empty() {
+                    write!(f, "[")?;
+                    write_list(f, args)?;
+                    write!(f, "]")?;
+                }
+                Ok(())
+            }
+            Ty::Fn { params, ret } => {
+                write!(f, "@(")?;
+                write_list(f, params)?;
+                write!(f, ")::{}", ret)
+            }
+            Ty::Dyn(name) | Ty::Param(name) => write!(f, "{}", name),
+            Ty::Infer(_) => write!(f, "_"),
+            Ty::Error => write!(f, "{{unknown}}"),
+        }
+    }
+}
+
+fn write_list(f: &mut fmt::Formatter<'_>, tys: &[Ty]) -> fmt::Result {
+    for (i, ty) in tys.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", ty)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,732 @@
+//! ELF64 relocatable objects, as `ld` links them: sections, a symbol table
+//! and relocations with addends. Objects are written from and read back
+//! into the same plain model, which leaves out the tables derived from it
+//! (the string tables, and one relocation section per section relocated).
+//! Linked executables are written from a model of their own, with the
+//! sections at their addresses and loaded by segment.
+
+use std::collections::HashMap;
+
+pub const SHF_WRITE: u64 = 1;
+pub const SHF_ALLOC: u64 = 2;
+pub const SHF_EXECINSTR: u64 = 4;
+
+pub const R_X86_64_64: u32 = 1;
+pub const R_X86_64_PC32: u32 = 2;
+pub const R_X86_64_PLT32: u32 = 4;
+pub const R_X86_64_32: u32 = 10;
+pub const R_X86_64_32S: u32 = 11;
+
+/// The size of the pages segments are mapped in.
+pub const PAGE_SIZE: u64 = 4096;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOTE: u32 = 7;
+const SHT_NOBITS: u32 = 8;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const EM_X86_64: u16 = 62;
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionKind {
+    Progbits,
+    /// Takes no room in the file, like `.bss`.
+    Nobits,
+    Note,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub kind: SectionKind,
+    pub flags: u64,
+    pub align: u64,
+    /// The contents, empty for `Nobits` sections.
+    pub data: Vec<u8>,
+    /// The size in memory, which is that of the contents unless `Nobits`.
+    pub size: u64,
+    pub relocs: Vec<Reloc>,
+}
+
+impl Section {
+    pub fn new(name: &str, kind: SectionKind, flags: u64, align: u64) -> Self {
+        Section {
+            name: name.to_string(),
+            kind,
+            flags,
+            align,
+            data: Vec::new(),
+            size: 0,
+            relocs: Vec::new(),
+        }
+    }
+
+    /// Pads the section to `align`, and returns the offset reached.
+    pub fn align_to(&mut self, align: u64, fill: u8) -> u64 {
+        self.align = self.align.max(align);
+        let size = self.size.div_ceil(align) * align;
+        if self.kind != SectionKind::Nobits {
+            self.data.resize(size as usize, fill);
+        }
+        self.size = size;
+        size
+    }
+
+    /// Appends `bytes`, returning their offset.
+    pub fn append(&mut self, bytes: &[u8]) -> u64 {
+        let offset = self.size;
+        self.data.extend_from_slice(bytes);
+        self.size += bytes.len() as u64;
+        offset
+    }
+
+    /// Reserves `size` zeroed bytes, returning their offset.
+    pub fn reserve(&mut self, size: u64) -> u64 {
+        let offset = self.size;
+        if self.kind != SectionKind::Nobits {
+            self.data.resize((offset + size) as usize, 0);
+        }
+        self.size += size;
+        offset
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    Local,
+    Global,
+    Weak,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    NoType,
+    Object,
+    Func,
+    Section,
+    File,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    /// The section defining the symbol, `None` if it is undefined.
+    pub section: Option<usize>,
+    pub value: u64,
+    pub size: u64,
+    pub binding: Binding,
+    pub kind: SymbolKind,
+}
+
+/// A place to patch with the address of `symbol` plus `addend`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reloc {
+    pub offset: u64,
+    /// An index into the object's symbols.
+    pub symbol: usize,
+    pub kind: u32,
+    pub addend: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Object {
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+}
+
+impl Object {
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// The index of the symbol `name`, added as undefined if it is new.
+    pub fn symbol_index(&mut self, name: &str) -> usize {
+        match self.symbols.iter().position(|symbol| symbol.name == name) {
+            Some(index) => index,
+            None => {
+                self.symbols.push(Symbol {
+                    name: name.to_string(),
+                    section: None,
+                    value: 0,
+                    size: 0,
+                    binding: Binding::Global,
+                    kind: SymbolKind::NoType,
+                });
+                self.symbols.len() - 1
+            }
+        }
+    }
+}
+
+/// A string table under construction.
+struct Strtab {
+    data: Vec<u8>,
+    offsets: HashMap<String, u32>,
+}
+
+impl Strtab {
+    fn new() -> Self {
+        Strtab {
+            data: vec![0],
+            offsets: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        if s.is_empty() {
+            return 0;
+        }
+        if let Some(&offset) = self.offsets.get(s) {
+            return offset;
+        }
+        let offset = self.data.len() as u32;
+        self.data.extend_from_slice(s.as_bytes());
+        self.data.push(0);
+        self.offsets.insert(s.to_string(), offset);
+        offset
+    }
+}
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+impl SectionHeader {
+    /// The header of `section`, which is at `offset` in the file and
+    /// `addr` in memory.
+    fn new(shstrtab: &mut Strtab, section: &Section, addr: u64, offset: u64) -> Self {
+        SectionHeader {
+            name: shstrtab.add(&section.name),
+            kind: match section.kind {
+                SectionKind::Progbits => SHT_PROGBITS,
+                SectionKind::Nobits => SHT_NOBITS,
+                SectionKind::Note => SHT_NOTE,
+            },
+            flags: section.flags,
+            addr,
+            offset,
+            size: section.size,
+            link: 0,
+            info: 0,
+            align: section.align.max(1),
+            entsize: 0,
+        }
+    }
+}
+
+/// Writes `object` as an ELF64 relocatable object for x86-64.
+pub fn write(object: &Object) -> Vec<u8> {
+    let (order, renumbered) = locals_first(&object.symbols);
+    let mut out = vec![0; HEADER_SIZE];
+    let mut headers = Vec::new();
+    let mut shstrtab = Strtab::new();
+
+    for section in &object.sections {
+        let offset = match section.kind {
+            SectionKind::Nobits => out.len() as u64,
+            _ => place(&mut out, &section.data, section.align.max(1) as usize),
+        };
+        headers.push(SectionHeader::new(&mut shstrtab, section, 0, offset));
+    }
+
+    let symtab_index = (1 + object.sections.len() + relocated(object)) as u32;
+    for (s, section) in object.sections.iter().enumerate() {
+        if section.relocs.is_empty() {
+            continue;
+        }
+        let mut rela = Vec::new();
+        for reloc in &section.relocs {
+            let info = (renumbered[reloc.symbol] as u64) << 32 | reloc.kind as u64;
+            rela.extend_from_slice(&reloc.offset.to_le_bytes());
+            rela.extend_from_slice(&info.to_le_bytes());
+            rela.extend_from_slice(&reloc.addend.to_le_bytes());
+        }
+        headers.push(SectionHeader {
+            name: shstrtab.add(&format!(".rela{}", section.name)),
+            kind: SHT_RELA,
+            flags: SHF_INFO_LINK,
+            addr: 0,
+            offset: place(&mut out, &rela, 8),
+            size: rela.len() as u64,
+            link: symtab_index,
+            info: s as u32 + 1,
+            align: 8,
+            entsize: RELA_SIZE as u64,
+        });
+    }
+
+    let (table, sections) = write_tables(&mut out, headers, shstrtab, &object.symbols, &order);
+    let header = file_header(ET_REL, 0, 0, table, sections);
+    out[..HEADER_SIZE].copy_from_slice(&header);
+    out
+}
+
+/// A linked program: sections at their load addresses with their
+/// relocations applied, and symbols whose values are addresses.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Executable {
+    pub entry: u64,
+    pub sections: Vec<Section>,
+    /// The address of each section.
+    pub addresses: Vec<u64>,
+    pub symbols: Vec<Symbol>,
+}
+
+/// A loadable segment: sections sharing pages, mapped together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub address: u64,
+    /// The size of the part stored in the file.
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub flags: u64,
+}
+
+impl Executable {
+    /// Groups the sections into segments, in the order of their addresses.
+    /// A segment ends where the next section starts on a later page, so
+    /// no two segments share a page; sections within one are padded with
+    /// zeros in between.
+    pub fn segments(&self) -> Vec<Segment> {
+        let mut order: Vec<usize> = (0..self.sections.len())
+            .filter(|&i| self.sections[i].size > 0)
+            .collect();
+        order.sort_by_key(|&i| self.addresses[i]);
+        let mut segments: Vec<Segment> = Vec::new();
+        for i in order {
+            let (section, address) = (&self.sections[i], self.addresses[i]);
+            let end = address + section.size;
+            match segments.last_mut() {
+                Some(segment)
+                    if address / PAGE_SIZE
+                        <= (segment.address + segment.memory_size - 1) / PAGE_SIZE =>
+                {
+                    segment.memory_size = segment.memory_size.max(end - segment.address);
+                    if section.kind != SectionKind::Nobits {
+                        segment.file_size = end - segment.address;
+                    }
+                    segment.flags |= section.flags;
+                }
+                _ => segments.push(Segment {
+                    address,
+                    file_size: match section.kind {
+                        SectionKind::Nobits => 0,
+                        _ => section.size,
+                    },
+                    memory_size: section.size,
+                    flags: section.flags,
+                }),
+            }
+        }
+        segments
+    }
+}
+
+/// Writes `executable` as an ELF64 executable for x86-64, with a program
+/// header per segment. Each segment's file offset is congruent to its
+/// address modulo the page size, so it can be mapped as is.
+pub fn write_executable(executable: &Executable) -> Vec<u8> {
+    let segments = executable.segments();
+    let mut out = vec![0; HEADER_SIZE + segments.len() * PROGRAM_HEADER_SIZE];
+    let mut program_headers = Vec::new();
+    let mut offsets = Vec::new();
+    for segment in &segments {
+        let mut offset = out.len() as u64;
+        offset += (segment.address % PAGE_SIZE + PAGE_SIZE - offset % PAGE_SIZE) % PAGE_SIZE;
+        out.resize((offset + segment.file_size) as usize, 0);
+        offsets.push(offset);
+        // Readable always, then writable and executable as the sections are.
+        let mut flags = 4u32;
+        if segment.flags & SHF_WRITE != 0 {
+            flags |= 2;
+        }
+        if segment.flags & SHF_EXECINSTR != 0 {
+            flags |= 1;
+        }
+        program_headers.extend_from_slice(&PT_LOAD.to_le_bytes());
+        program_headers.extend_from_slice(&flags.to_le_bytes());
+        program_headers.extend_from_slice(&offset.to_le_bytes());
+        // The physical address is the virtual one, which Multiboot2 loaders
+        // load to.
+        program_headers.extend_from_slice(&segment.address.to_le_bytes());
+        program_headers.extend_from_slice(&segment.address.to_le_bytes());
+        program_headers.extend_from_slice(&segment.file_size.to_le_bytes());
+        program_headers.extend_from_slice(&segment.memory_size.to_le_bytes());
+        program_headers.extend_from_slice(&PAGE_SIZE.to_le_bytes());
+    }
+    out[HEADER_SIZE..HEADER_SIZE + program_headers.len()].copy_from_slice(&program_headers);
+
+    let mut headers = Vec::new();
+    let mut shstrtab = Strtab::new();
+    for (section, &address) in executable.sections.iter().zip(&executable.addresses) {
+        // Sections of no segment are empty, and kept where the file ends.
+        let offset = segments
+            .iter()
+            .zip(&offsets)
+            .find(|(segment, _)| {
+                address >= segment.address && address < segment.address + segment.memory_size
+            })
+            .map_or(out.len() as u64, |(segment, offset)| {
+                offset + address - segment.address
+            });
+        if section.kind != SectionKind::Nobits {
+            out[offset as usize..(offset + section.size) as usize].copy_from_slice(&section.data);
+        }
+        headers.push(SectionHeader::new(&mut shstrtab, section, address, offset));
+    }
+
+    let (order, _) = locals_first(&executable.symbols);
+    let (table, sections) = write_tables(&mut out, headers, shstrtab, &executable.symbols, &order);
+    let header = file_header(ET_EXEC, executable.entry, segments.len(), table, sections);
+    out[..HEADER_SIZE].copy_from_slice(&header);
+    out
+}
+
+/// Orders the symbols with the local ones first, as the symbol table needs,
+/// and gives the index in the table of each, after the null symbol.
+fn locals_first(symbols: &[Symbol]) -> (Vec<usize>, Vec<usize>) {
+    let mut order: Vec<usize> = (0..symbols.len()).collect();
+    order.sort_by_key(|&i| symbols[i].binding != Binding::Local);
+    let mut renumbered = vec![0; symbols.len()];
+    for (new, &old) in order.iter().enumerate() {
+        renumbered[old] = new + 1;
+    }
+    (order, renumbered)
+}
+
+/// Pads `out` to `align` and appends `bytes`, returning their offset.
+fn place(out: &mut Vec<u8>, bytes: &[u8], align: usize) -> u64 {
+    while !out.len().is_multiple_of(align) {
+        out.push(0);
+    }
+    out.extend_from_slice(bytes);
+    (out.len() - bytes.len()) as u64
+}
+
+/// Appends the symbol table, the string tables and the section header
+/// table, after the `headers` so far. Returns the header table's offset and
+/// how many headers it has.
+fn write_tables(
+    out: &mut Vec<u8>,
+    mut headers: Vec<SectionHeader>,
+    mut shstrtab: Strtab,
+    symbols: &[Symbol],
+    order: &[usize],
+) -> (u64, usize) {
+    let mut strtab = Strtab::new();
+    let mut symtab = vec![0; SYMBOL_SIZE];
+    for &i in order {
+        let symbol = &symbols[i];
+        let binding = match symbol.binding {
+            Binding::Local => 0,
+            Binding::Global => 1,
+            Binding::Weak => 2,
+        };
+        let kind = match symbol.kind {
+            SymbolKind::NoType => 0,
+            SymbolKind::Object => 1,
+            SymbolKind::Func => 2,
+            SymbolKind::Section => 3,
+            SymbolKind::File => 4,
+        };
+        let shndx = match (symbol.section, symbol.kind) {
+            (Some(section), _) => section as u16 + 1,
+            (None, SymbolKind::File) => SHN_ABS,
+            (None, _) => SHN_UNDEF,
+        };
+        symtab.extend_from_slice(&strtab.add(&symbol.name).to_le_bytes());
+        symtab.push(binding << 4 | kind);
+        symtab.push(0);
+        symtab.extend_from_slice(&shndx.to_le_bytes());
+        symtab.extend_from_slice(&symbol.value.to_le_bytes());
+        symtab.extend_from_slice(&symbol.size.to_le_bytes());
+    }
+    let first_global = 1 + order
+        .iter()
+        .take_while(|&&i| symbols[i].binding == Binding::Local)
+        .count();
+
+    let symtab_index = headers.len() as u32 + 1;
+    headers.push(SectionHeader {
+        name: shstrtab.add(".symtab"),
+        kind: SHT_SYMTAB,
+        flags: 0,
+        addr: 0,
+        offset: place(out, &symtab, 8),
+        size: symtab.len() as u64,
+        link: symtab_index + 1,
+        info: first_global as u32,
+        align: 8,
+        entsize: SYMBOL_SIZE as u64,
+    });
+    headers.push(SectionHeader {
+        name: shstrtab.add(".strtab"),
+        kind: SHT_STRTAB,
+        flags: 0,
+        addr: 0,
+        offset: place(out, &strtab.data, 1),
+        size: strtab.data.len() as u64,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    });
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    headers.push(SectionHeader {
+        name: shstrtab_name,
+        kind: SHT_STRTAB,
+        flags: 0,
+        addr: 0,
+        offset: place(out, &shstrtab.data, 1),
+        size: shstrtab.data.len() as u64,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    });
+
+    let mut table = vec![0; SECTION_HEADER_SIZE];
+    for header in &headers {
+        table.extend_from_slice(&header.name.to_le_bytes());
+        table.extend_from_slice(&header.kind.to_le_bytes());
+        table.extend_from_slice(&header.flags.to_le_bytes());
+        table.extend_from_slice(&header.addr.to_le_bytes());
+        table.extend_from_slice(&header.offset.to_le_bytes());
+        table.extend_from_slice(&header.size.to_le_bytes());
+        table.extend_from_slice(&header.link.to_le_bytes());
+        table.extend_from_slice(&header.info.to_le_bytes());
+        table.extend_from_slice(&header.align.to_le_bytes());
+        table.extend_from_slice(&header.entsize.to_le_bytes());
+    }
+    (place(out, &table, 8), headers.len() + 1)
+}
+
+/// The file header, with `sections` section headers, the last of them
+/// `.shstrtab`'s.
+fn file_header(kind: u16, entry: u64, segments: usize, table: u64, sections: usize) -> Vec<u8> {
+    let sections = sections as u16;
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(b"\x7fELF");
+    // 64-bit, little-endian, version 1, System V ABI.
+    header.extend_from_slice(&[2, 1, 1, 0]);
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&kind.to_le_bytes());
+    header.extend_from_slice(&EM_X86_64.to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header.extend_from_slice(&entry.to_le_bytes());
+    // Program headers, if any, follow this one.
+    let program_headers = if segments > 0 { HEADER_SIZE as u64 } else { 0 };
+    header.extend_from_slice(&program_headers.to_le_bytes());
+    header.extend_from_slice(&table.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    let entry_size = if segments > 0 { PROGRAM_HEADER_SIZE } else { 0 };
+    header.extend_from_slice(&(entry_size as u16).to_le_bytes());
+    header.extend_from_slice(&(segments as u16).to_le_bytes());
+    header.extend_from_slice(&(SECTION_HEADER_SIZE as u16).to_le_bytes());
+    header.extend_from_slice(&sections.to_le_bytes());
+    header.extend_from_slice(&(sections - 1).to_le_bytes());
+    header
+}
+
+/// How many sections have relocations.
+fn relocated(object: &Object) -> usize {
+    object
+        .sections
+        .iter()
+        .filter(|section| !section.relocs.is_empty())
+        .count()
+}
+
+/// Reads bytes of the file at fixed offsets.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes(&self, offset: u64, len: u64) -> Result<&[u8], String> {
+        let start = offset as usize;
+        let end = start
+            .checked_add(len as usize)
+            .filter(|&end| end <= self.0.len())
+            .ok_or_else(|| format!("the file ends before {:#x}", offset + len))?;
+        Ok(&self.0[start..end])
+    }
+
+    fn u8(&self, offset: u64) -> Result<u8, String> {
+        Ok(self.bytes(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: u64) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(
+            self.bytes(offset, 2)?.try_into().unwrap(),
+        ))
+    }
+
+    fn u32(&self, offset: u64) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(
+            self.bytes(offset, 4)?.try_into().unwrap(),
+        ))
+    }
+
+    fn u64(&self, offset: u64) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(
+            self.bytes(offset, 8)?.try_into().unwrap(),
+        ))
+    }
+
+    /// The NUL-terminated string at `offset` of the string table at `table`.
+    fn string(&self, table: u64, offset: u32) -> Result<String, String> {
+        let start = (table + offset as u64) as usize;
+        let bytes = self.0.get(start..).ok_or("a name is past the end")?;
+        let len = bytes
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or("a name isn't terminated")?;
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+}
+
+/// Reads an ELF64 relocatable object for x86-64. Sections other than
+/// those with contents, symbol tables and relocations are skipped.
+pub fn read(bytes: &[u8]) -> Result<Object, String> {
+    let file = Reader(bytes);
+    if file.bytes(0, 4)? != b"\x7fELF" {
+        return Err("not an ELF file".to_string());
+    }
+    if file.u8(4)? != 2 || file.u8(5)? != 1 {
+        return Err("not a little-endian 64-bit ELF file".to_string());
+    }
+    if file.u16(16)? != ET_REL || file.u16(18)? != EM_X86_64 {
+        return Err("not a relocatable object for x86-64".to_string());
+    }
+    let table = file.u64(40)?;
+    let count = file.u16(60)? as u64;
+    let names = file.u16(62)? as u64;
+    let header = |index: u64| table + index * SECTION_HEADER_SIZE as u64;
+    let names_offset = file.u64(header(names) + 24)?;
+
+    // Sections with contents, by their index in the file.
+    let mut object = Object::default();
+    let mut kept = HashMap::new();
+    let mut symtab = None;
+    let mut relas = Vec::new();
+    for index in 1..count {
+        let at = header(index);
+        let kind = file.u32(at + 4)?;
+        let flags = file.u64(at + 8)?;
+        let offset = file.u64(at + 24)?;
+        let size = file.u64(at + 32)?;
+        let link = file.u32(at + 40)?;
+        let info = file.u32(at + 44)?;
+        let align = file.u64(at + 48)?;
+        let name = file.string(names_offset, file.u32(at)?)?;
+        let kind = match kind {
+            SHT_PROGBITS => SectionKind::Progbits,
+            SHT_NOBITS => SectionKind::Nobits,
+            SHT_NOTE => SectionKind::Note,
+            SHT_SYMTAB => {
+                symtab = Some((offset, size, link));
+                continue;
+            }
+            SHT_RELA => {
+                relas.push((offset, size, info));
+                continue;
+            }
+            _ => continue,
+        };
+        let data = match kind {
+            SectionKind::Nobits => Vec::new(),
+            _ => file.bytes(offset, size)?.to_vec(),
+        };
+        kept.insert(index, object.sections.len());
+        object.sections.push(Section {
+            name,
+            kind,
+            flags,
+            align: align.max(1),
+            data,
+            size,
+            relocs: Vec::new(),
+        });
+    }
+
+    let (offset, size, link) = symtab.ok_or("the object has no symbol table")?;
+    let strings = file.u64(header(link as u64) + 24)?;
+    for index in 1..size / SYMBOL_SIZE as u64 {
+        let at = offset + index * SYMBOL_SIZE as u64;
+        let info = file.u8(at + 4)?;
+        let shndx = file.u16(at + 6)? as u64;
+        let kind = match info & 0xf {
+            1 => SymbolKind::Object,
+            2 => SymbolKind::Func,
+            3 => SymbolKind::Section,
+            4 => SymbolKind::File,
+            _ => SymbolKind::NoType,
+        };
+        let binding = match info >> 4 {
+            0 => Binding::Local,
+            2 => Binding::Weak,
+            _ => Binding::Global,
+        };
+        let mut name = file.string(strings, file.u32(at)?)?;
+        if kind == SymbolKind::Section && name.is_empty() {
+            let section = header(shndx);
+            name = file.string(names_offset, file.u32(section)?)?;
+        }
+        object.symbols.push(Symbol {
+            name,
+            section: kept.get(&shndx).copied(),
+            value: file.u64(at + 8)?,
+            size: file.u64(at + 16)?,
+            binding,
+            kind,
+        });
+    }
+
+    for (offset, size, info) in relas {
+        let Some(&section) = kept.get(&(info as u64)) else {
+            continue;
+        };
+        for index in 0..size / RELA_SIZE as u64 {
+            let at = offset + index * RELA_SIZE as u64;
+            let info = file.u64(at + 8)?;
+            let symbol = (info >> 32) as usize;
+            if symbol == 0 {
+                return Err("a relocation has no symbol".to_string());
+            }
+            object.sections[section].relocs.push(Reloc {
+                offset: file.u64(at)?,
+                symbol: symbol - 1,
+                kind: info as u32,
+                addend: file.u64(at + 16)? as i64,
+            });
+        }
+    }
+    Ok(object)
+}
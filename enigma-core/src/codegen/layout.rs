@@ -0,0 +1,319 @@
+//! How values are laid out in memory.
+//!
+//! Scalars have their natural size: `int` and `float` take 8 bytes, `char`
+//! 4, `byte` and `bool` 1, and references 8. Tuples place their fields in
+//! order, each at the next offset its alignment allows, as C does. Records
+//! do too when they are `#[repr(C)]` or `#[packed]`; others put their most
+//! aligned fields first, which leaves the least padding. Neighbouring
+//! bitfields of any types are packed together from the lowest bit up, as
+//! System V C packs them: each takes the next bits that don't cross a
+//! boundary of its type's size.
+//! A union starts with a 4-byte tag holding the variant's index, followed by
+//! the fields of whichever variant it holds. A `string` is a pointer to its
+//! bytes and their length, and a function value a pointer to its code and one
+//! to the environment of a closure, null for plain functions. A reference to
+//! a protocol object is a pointer to the value and one to its vtable.
+//!
+//! The checker uses the same layouts to evaluate `size_of`, `align_of` and
+//! `offset_of`, so they are computed from either its item tables or a
+//! lowered program.
+
+use crate::checker::items::{ItemTable, RecordDef, UnionDef};
+use crate::checker::types::Ty;
+use crate::mir::{AdtDef, Program};
+use std::sync::LazyLock;
+
+/// Where layouts look up records and unions.
+pub trait Adts {
+    fn record(&self, name: &str) -> Option<&RecordDef>;
+    fn union(&self, name: &str) -> Option<&UnionDef>;
+}
+
+impl Adts for Program {
+    fn record(&self, name: &str) -> Option<&RecordDef> {
+        match self.adts.get(name)? {
+            AdtDef::Record(def) => Some(def),
+            AdtDef::Union(_) => None,
+        }
+    }
+
+    fn union(&self, name: &str) -> Option<&UnionDef> {
+        match self.adts.get(name)? {
+            AdtDef::Union(def) => Some(def),
+            AdtDef::Record(_) => None,
+        }
+    }
+}
+
+/// The checker knows `Range` without a definition.
+static RANGE: LazyLock<RecordDef> = LazyLock::new(RecordDef::range);
+
+impl Adts for ItemTable {
+    fn record(&self, name: &str) -> Option<&RecordDef> {
+        self.records
+            .get(name)
+            .or_else(|| (name == "Range").then(|| &*RANGE))
+    }
+
+    fn union(&self, name: &str) -> Option<&UnionDef> {
+        self.unions.get(name)
+    }
+}
+
+/// The size of a union's tag.
+pub const TAG_SIZE: u64 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub size: u64,
+    pub align: u64,
+}
+
+impl Layout {
+    const fn scalar(size: u64) -> Self {
+        Self { size, align: size }
+    }
+
+    const ZERO: Layout = Layout { size: 0, align: 1 };
+    const PAIR: Layout = Layout { size: 16, align: 8 };
+}
+
+pub fn align_to(offset: u64, align: u64) -> u64 {
+    offset.div_ceil(align) * align
+}
+
+/// The layout of `ty`, or why the backend can't represent it.
+pub fn layout(program: &impl Adts, ty: &Ty) -> Result<Layout, String> {
+    Ok(match ty {
+        Ty::Int | Ty::Float => Layout::scalar(8),
+        Ty::Char => Layout::scalar(4),
+        Ty::Byte | Ty::Bool => Layout::scalar(1),
+        Ty::Unit | Ty::Never => Layout::ZERO,
+        Ty::Str | Ty::Fn { .. } => Layout::PAIR,
+        Ty::Ref { inner, .. } | Ty::RawRef { inner, .. } => match **inner {
+            Ty::Dyn(_) => Layout::PAIR,
+            _ => Layout::scalar(8),
+        },
+        Ty::Tuple(elems) => fields_layout(program, elems, 0)?.0,
+        Ty::Adt { name, args } => {
+            if let Some(def) = program.record(name) {
+                record_layout(program, def, &record_fields(def, args))?.0
+            } else if program.union(name).is_some() {
+                let mut layout = Layout::scalar(TAG_SIZE);
+                for fields in variants(program, ty) {
+                    let (variant, _) = fields_layout(program, &fields, TAG_SIZE)?;
+                    layout.size = layout.size.max(variant.size);
+                    layout.align = layout.align.max(variant.align);
+                }
+                Layout {
+                    size: align_to(layout.size, layout.align),
+                    align: layout.align,
+                }
+            } else {
+                return Err(format!("the unknown type `{}`", name));
+            }
+        }
+        Ty::Param(_) | Ty::Infer(_) | Ty::Dyn(_) | Ty::Error => {
+            return Err(format!("values of type `{}`", ty));
+        }
+    })
+}
+
+/// The layout of fields placed in order from `start`, and their offsets.
+fn fields_layout(
+    program: &impl Adts,
+    fields: &[Ty],
+    start: u64,
+) -> Result<(Layout, Vec<u64>), String> {
+    let mut offset = start;
+    let mut align = 1;
+    let mut offsets = Vec::new();
+    for field in fields {
+        let field = layout(program, field)?;
+        offset = align_to(offset, field.align);
+        offsets.push(offset);
+        offset += field.size;
+        align = align.max(field.align);
+    }
+    let size = align_to(offset, align);
+    Ok((Layout { size, align }, offsets))
+}
+
+fn record_fields(def: &RecordDef, args: &[Ty]) -> Vec<Ty> {
+    def.fields
+        .iter()
+        .map(|(_, field)| field.subst(&def.generics, args))
+        .collect()
+}
+
+/// Where a bitfield's bits are in the unit holding it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bits {
+    pub shift: u32,
+    pub width: u32,
+}
+
+/// Fields placed together: a field, or a run of adjacent bitfields.
+struct Unit {
+    layout: Layout,
+    /// Each field, with its offset in the unit and its bits.
+    fields: Vec<(usize, u64, Option<Bits>)>,
+    /// How many bits the run of bitfields uses.
+    bitfields: Option<u32>,
+}
+
+/// The offset of each field of a record, with its bits if it is a
+/// bitfield.
+type Places = Vec<(u64, Option<Bits>)>;
+
+/// The layout of a record whose fields have the types `tys`, and where
+/// its fields are.
+fn record_layout(
+    program: &impl Adts,
+    def: &RecordDef,
+    tys: &[Ty],
+) -> Result<(Layout, Places), String> {
+    let mut units: Vec<Unit> = Vec::new();
+    for (index, ty) in tys.iter().enumerate() {
+        let mut layout = layout(program, ty)?;
+        if def.repr.packed {
+            layout.align = 1;
+        }
+        let Some(width) = def.bits(index) else {
+            units.push(Unit {
+                layout,
+                fields: vec![(index, 0, None)],
+                bitfields: None,
+            });
+            continue;
+        };
+        // As in System V C, a bitfield takes the next bits of the run that
+        // don't cross a boundary of its type's size, and is read and
+        // written as a value of its type at the boundary before it.
+        let used = match units.last() {
+            Some(Unit {
+                bitfields: Some(used),
+                ..
+            }) => *used,
+            _ => {
+                units.push(Unit {
+                    layout: Layout::ZERO,
+                    fields: Vec::new(),
+                    bitfields: Some(0),
+                });
+                0
+            }
+        };
+        let type_bits = layout.size as u32 * 8;
+        let start = if used / type_bits == (used + width - 1) / type_bits {
+            used
+        } else {
+            used.next_multiple_of(type_bits)
+        };
+        let offset = (start / type_bits) as u64 * layout.size;
+        let unit = units.last_mut().unwrap();
+        unit.fields.push((
+            index,
+            offset,
+            Some(Bits {
+                shift: start % type_bits,
+                width,
+            }),
+        ));
+        unit.bitfields = Some(start + width);
+        unit.layout.align = unit.layout.align.max(layout.align);
+        let mut size = (start + width).div_ceil(8) as u64;
+        if def.repr.packed {
+            // Nothing pads a packed record out to the values read.
+            size = size.max(offset + layout.size);
+        }
+        unit.layout.size = unit.layout.size.max(size);
+    }
+    if !def.repr.c && !def.repr.packed {
+        units.sort_by_key(|unit| std::cmp::Reverse(unit.layout.align));
+    }
+
+    let mut places = vec![(0, None); tys.len()];
+    let mut offset = 0;
+    let mut align = def.repr.align.unwrap_or(1);
+    for unit in &units {
+        offset = align_to(offset, unit.layout.align);
+        for &(index, start, bits) in &unit.fields {
+            places[index] = (offset + start, bits);
+        }
+        offset += unit.layout.size;
+        align = align.max(unit.layout.align);
+    }
+    let size = align_to(offset, align);
+    Ok((Layout { size, align }, places))
+}
+
+/// The field types of each variant of the union `ty`.
+pub fn variants(program: &impl Adts, ty: &Ty) -> Vec<Vec<Ty>> {
+    let Ty::Adt { name, args } = ty else {
+        return Vec::new();
+    };
+    match program.union(name) {
+        Some(def) => def
+            .variants
+            .iter()
+            .map(|(_, fields)| {
+                fields
+                    .iter()
+                    .map(|field| field.subst(&def.generics, args))
+                    .collect()
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// A field of a tuple, record or union variant, and where it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldLayout {
+    pub ty: Ty,
+    pub offset: u64,
+    pub bits: Option<Bits>,
+}
+
+/// The fields of a tuple or record, or of `variant` of a union, in
+/// declaration order.
+pub fn fields(
+    program: &impl Adts,
+    ty: &Ty,
+    variant: Option<usize>,
+) -> Result<Vec<FieldLayout>, String> {
+    let (tys, start) = match (ty, variant) {
+        (Ty::Tuple(elems), None) => (elems.clone(), 0),
+        (Ty::Adt { .. }, Some(variant)) => (
+            variants(program, ty)
+                .into_iter()
+                .nth(variant)
+                .ok_or_else(|| format!("`{}` has no variant {}", ty, variant))?,
+            TAG_SIZE,
+        ),
+        (Ty::Adt { name, args }, None) => match program.record(name) {
+            Some(def) => {
+                let tys = record_fields(def, args);
+                let (_, places) = record_layout(program, def, &tys)?;
+                return Ok(tys
+                    .into_iter()
+                    .zip(places)
+                    .map(|(ty, (offset, bits))| FieldLayout { ty, offset, bits })
+                    .collect());
+            }
+            None => return Err(format!("`{}` has no fields", ty)),
+        },
+        _ => return Err(format!("`{}` has no fields", ty)),
+    };
+    let (_, offsets) = fields_layout(program, &tys, start)?;
+    Ok(tys
+        .into_iter()
+        .zip(offsets)
+        .map(|(ty, offset)| FieldLayout {
+            ty,
+            offset,
+            bits: None,
+        })
+        .collect())
+}
@@ -0,0 +1,465 @@
+//! Links relocatable objects into an executable or a flat binary, placing
+//! their sections as a linker description says.
+//!
+//! A description has one statement per line, and `#` starts a comment:
+//!
+//! ```text
+//! entry _start32          # the symbol the program starts at
+//! at 0x100000             # moves on to an address
+//! align 4096              # moves on to a multiple
+//! section .text .boot*    # an output section, and its input sections
+//! discard .comment        # input sections to leave out
+//! ```
+//!
+//! An output section takes the input sections of its name, then those its
+//! other patterns match, object by object; a pattern ending in `*` matches
+//! names starting with the rest. Allocated sections no statement places are
+//! added after the last output section with contents, or after the last one
+//! for those without. Sections that aren't allocated, such as
+//! `.note.GNU-stack`, are left out.
+
+use super::elf::{
+    Binding, Executable, Object, R_X86_64_32, R_X86_64_32S, R_X86_64_64, R_X86_64_PC32,
+    R_X86_64_PLT32, SHF_ALLOC, Section, SectionKind, Symbol, SymbolKind,
+};
+use std::collections::HashMap;
+
+/// A Multiboot2 kernel, loaded at 1 MiB with its header first.
+pub const KERNEL: &str = "\
+entry _start32
+at 0x100000
+section .multiboot2
+section .text .text.*
+section .rodata .rodata.*
+section .data .data.*
+section .bss .bss.*
+";
+
+/// A flat binary, loaded at 1 MiB and entered at its first byte, which is
+/// `_start`'s.
+pub const FLAT: &str = "\
+entry _start
+at 0x100000
+discard .multiboot2
+section .text .text.*
+section .rodata .rodata.*
+section .data .data.*
+section .bss .bss.*
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Elf,
+    /// The memory image from the first section with contents to the end of
+    /// the last, with nothing to say where it goes or where it starts.
+    Binary,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "elf" => Some(Format::Elf),
+            "bin" => Some(Format::Binary),
+            _ => None,
+        }
+    }
+
+    /// The description used when none is given.
+    pub fn script(self) -> &'static str {
+        match self {
+            Format::Elf => KERNEL,
+            Format::Binary => FLAT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    At(u64),
+    Align(u64),
+    /// An output section, and the patterns of its input sections.
+    Section(String, Vec<String>),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Script {
+    /// The entry symbol, `_start` if there is none.
+    pub entry: Option<String>,
+    pub statements: Vec<Statement>,
+    pub discard: Vec<String>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut script = Script::default();
+        for (line, text) in text.lines().enumerate() {
+            let text = text.split('#').next().unwrap_or("");
+            let mut words = text.split_whitespace();
+            let Some(keyword) = words.next() else {
+                continue;
+            };
+            let words: Vec<&str> = words.collect();
+            let error = |message: &str| format!("line {}: {}", line + 1, message);
+            match (keyword, words.as_slice()) {
+                ("entry", [symbol]) => script.entry = Some(symbol.to_string()),
+                ("at", [address]) => {
+                    let address = number(address).ok_or_else(|| error("expected an address"))?;
+                    script.statements.push(Statement::At(address));
+                }
+                ("align", [align]) => match number(align) {
+                    Some(align) if align.is_power_of_two() => {
+                        script.statements.push(Statement::Align(align))
+                    }
+                    _ => return Err(error("expected a power of two")),
+                },
+                ("section", [name, patterns @ ..]) => {
+                    let mut inputs = vec![name.to_string()];
+                    inputs.extend(patterns.iter().map(|pattern| pattern.to_string()));
+                    script
+                        .statements
+                        .push(Statement::Section(name.to_string(), inputs));
+                }
+                ("discard", patterns) if !patterns.is_empty() => script
+                    .discard
+                    .extend(patterns.iter().map(|pattern| pattern.to_string())),
+                ("entry" | "at" | "align" | "section" | "discard", _) => {
+                    return Err(error(&format!("wrong operands for `{}`", keyword)));
+                }
+                _ => return Err(error(&format!("unknown statement `{}`", keyword))),
+            }
+        }
+        Ok(script)
+    }
+}
+
+/// A decimal or `0x` hexadecimal number.
+fn number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+enum Step {
+    At(u64),
+    Align(u64),
+    /// Places an output section.
+    Place(usize),
+}
+
+/// Where an input section went: its output section and its offset there.
+type Placement = Option<(usize, u64)>;
+
+/// Links `objects`, each with the name errors call it by. Every error
+/// found is returned, such as each undefined symbol.
+pub fn link(objects: &[(String, Object)], script: &Script) -> Result<Executable, Vec<String>> {
+    let discarded = |section: &Section| {
+        section.flags & SHF_ALLOC == 0 || script.discard.iter().any(|p| matches(p, &section.name))
+    };
+
+    // Each input section goes to the first output section matching it.
+    let mut outputs: Vec<Section> = Vec::new();
+    let mut inputs: Vec<Vec<(usize, usize)>> = Vec::new();
+    let mut steps = Vec::new();
+    let mut placed: Vec<Vec<bool>> = objects
+        .iter()
+        .map(|(_, object)| object.sections.iter().map(discarded).collect())
+        .collect();
+    for statement in &script.statements {
+        let (name, patterns) = match statement {
+            Statement::At(address) => {
+                steps.push(Step::At(*address));
+                continue;
+            }
+            Statement::Align(align) => {
+                steps.push(Step::Align(*align));
+                continue;
+            }
+            Statement::Section(name, patterns) => (name, patterns),
+        };
+        let mut taken = Vec::new();
+        for pattern in patterns {
+            for (o, (_, object)) in objects.iter().enumerate() {
+                for (s, section) in object.sections.iter().enumerate() {
+                    if !placed[o][s] && matches(pattern, &section.name) {
+                        placed[o][s] = true;
+                        taken.push((o, s));
+                    }
+                }
+            }
+        }
+        if !taken.is_empty() {
+            steps.push(Step::Place(outputs.len()));
+            outputs.push(Section::new(name, SectionKind::Nobits, 0, 1));
+            inputs.push(taken);
+        }
+    }
+    let has_contents = |taken: &[(usize, usize)]| {
+        taken
+            .iter()
+            .any(|&(o, s)| objects[o].1.sections[s].kind != SectionKind::Nobits)
+    };
+    // The rest are orphans, which go to output sections of their names.
+    let scripted = outputs.len();
+    for (o, (_, object)) in objects.iter().enumerate() {
+        for (s, section) in object.sections.iter().enumerate() {
+            if placed[o][s] {
+                continue;
+            }
+            match (scripted..outputs.len()).find(|&i| outputs[i].name == section.name) {
+                Some(i) => inputs[i].push((o, s)),
+                None => {
+                    outputs.push(Section::new(&section.name, SectionKind::Nobits, 0, 1));
+                    inputs.push(vec![(o, s)]);
+                }
+            }
+        }
+    }
+    for i in scripted..outputs.len() {
+        let placing = |step: &Step| matches!(step, Step::Place(j) if has_contents(&inputs[*j]));
+        let at = if !has_contents(&inputs[i]) {
+            steps.len()
+        } else if let Some(last) = steps.iter().rposition(placing) {
+            last + 1
+        } else {
+            let first = steps.iter().position(|step| matches!(step, Step::Place(_)));
+            first.unwrap_or(steps.len())
+        };
+        steps.insert(at, Step::Place(i));
+    }
+
+    // Concatenates the input sections, aligning each.
+    let mut placements: Vec<Vec<Placement>> = objects
+        .iter()
+        .map(|(_, object)| vec![None; object.sections.len()])
+        .collect();
+    for (i, output) in outputs.iter_mut().enumerate() {
+        if has_contents(&inputs[i]) {
+            output.kind = SectionKind::Progbits;
+        }
+        for &(o, s) in &inputs[i] {
+            let section = &objects[o].1.sections[s];
+            output.flags |= section.flags;
+            let offset = output.align_to(section.align.max(1), 0);
+            match section.kind {
+                SectionKind::Nobits => output.reserve(section.size),
+                _ => output.append(&section.data),
+            };
+            placements[o][s] = Some((i, offset));
+        }
+    }
+
+    // Addresses, moving on from each output section to the next.
+    let mut addresses = vec![0; outputs.len()];
+    let mut order = Vec::new();
+    let mut counter = 0u64;
+    let mut errors = Vec::new();
+    for step in &steps {
+        match *step {
+            Step::At(address) => counter = address,
+            Step::Align(align) => counter = counter.next_multiple_of(align),
+            Step::Place(i) => {
+                counter = counter.next_multiple_of(outputs[i].align);
+                addresses[i] = counter;
+                order.push(i);
+                match counter.checked_add(outputs[i].size) {
+                    Some(end) => counter = end,
+                    None => {
+                        errors.push(format!("`{}` ends past the address space", outputs[i].name))
+                    }
+                }
+            }
+        }
+    }
+    let mut by_address = order.clone();
+    by_address.retain(|&i| outputs[i].size > 0);
+    by_address.sort_by_key(|&i| addresses[i]);
+    for pair in by_address.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        if addresses[a] + outputs[a].size > addresses[b] {
+            errors.push(format!(
+                "`{}` and `{}` overlap",
+                outputs[a].name, outputs[b].name
+            ));
+        }
+    }
+
+    // The address of each symbol, `None` if it is in a discarded section or
+    // is undefined.
+    let address = |o: usize, symbol: &Symbol| match symbol.section {
+        Some(s) => placements[o][s].map(|(i, offset)| addresses[i] + offset + symbol.value),
+        None if symbol.binding == Binding::Local => Some(symbol.value),
+        None => None,
+    };
+    let mut globals: HashMap<&str, (u64, Binding, usize)> = HashMap::new();
+    for (o, (file, object)) in objects.iter().enumerate() {
+        for symbol in &object.symbols {
+            if symbol.binding == Binding::Local || symbol.section.is_none() {
+                continue;
+            }
+            let Some(value) = address(o, symbol) else {
+                continue;
+            };
+            match globals.get(symbol.name.as_str()) {
+                Some(&(_, Binding::Global, first)) if symbol.binding == Binding::Global => errors
+                    .push(format!(
+                        "`{}` is defined in both `{}` and `{}`",
+                        symbol.name, objects[first].0, file
+                    )),
+                Some(&(_, Binding::Global, _)) => {}
+                Some(_) if symbol.binding == Binding::Weak => {}
+                _ => {
+                    globals.insert(&symbol.name, (value, symbol.binding, o));
+                }
+            }
+        }
+    }
+    let resolve = |o: usize, index: usize| -> Result<u64, String> {
+        let (file, object) = &objects[o];
+        let symbol = &object.symbols[index];
+        if let Some(value) = address(o, symbol) {
+            return Ok(value);
+        }
+        if let Some(s) = symbol.section {
+            return Err(format!(
+                "`{}` refers to `{}` in `{}`, which is discarded",
+                file, symbol.name, object.sections[s].name
+            ));
+        }
+        match globals.get(symbol.name.as_str()) {
+            Some(&(value, _, _)) => Ok(value),
+            // Undefined weak symbols are null.
+            None if symbol.binding == Binding::Weak => Ok(0),
+            None => Err(format!(
+                "undefined symbol `{}`, used in `{}`",
+                symbol.name, file
+            )),
+        }
+    };
+
+    for (o, (file, object)) in objects.iter().enumerate() {
+        for (s, section) in object.sections.iter().enumerate() {
+            let Some((i, offset)) = placements[o][s] else {
+                continue;
+            };
+            for reloc in &section.relocs {
+                let target = match resolve(o, reloc.symbol) {
+                    Ok(target) => target,
+                    Err(error) => {
+                        if !errors.contains(&error) {
+                            errors.push(error);
+                        }
+                        continue;
+                    }
+                };
+                let place = addresses[i] + offset + reloc.offset;
+                let value = target.wrapping_add(reloc.addend as u64);
+                let bytes = match reloc.kind {
+                    R_X86_64_64 => Some(value.to_le_bytes().to_vec()),
+                    R_X86_64_PC32 | R_X86_64_PLT32 => {
+                        i32::try_from(value.wrapping_sub(place) as i64)
+                            .ok()
+                            .map(|value| value.to_le_bytes().to_vec())
+                    }
+                    R_X86_64_32 => u32::try_from(value)
+                        .ok()
+                        .map(|value| value.to_le_bytes().to_vec()),
+                    R_X86_64_32S => i32::try_from(value as i64)
+                        .ok()
+                        .map(|value| value.to_le_bytes().to_vec()),
+                    kind => {
+                        errors.push(format!(
+                            "`{}` has a relocation of unsupported type {} in `{}`",
+                            file, kind, section.name
+                        ));
+                        continue;
+                    }
+                };
+                let Some(bytes) = bytes else {
+                    errors.push(format!(
+                        "the address of `{}` doesn't fit where `{}` of `{}` needs it",
+                        object.symbols[reloc.symbol].name, section.name, file
+                    ));
+                    continue;
+                };
+                let at = (offset + reloc.offset) as usize;
+                outputs[i].data[at..at + bytes.len()].copy_from_slice(&bytes);
+            }
+        }
+    }
+
+    let entry_name = script.entry.as_deref().unwrap_or("_start");
+    let entry = match globals.get(entry_name) {
+        Some(&(value, _, _)) => value,
+        None => {
+            errors.push(format!("the entry `{}` isn't defined", entry_name));
+            0
+        }
+    };
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // The symbols that made it, their values now addresses.
+    let mut renumbered = vec![0; outputs.len()];
+    for (new, &old) in order.iter().enumerate() {
+        renumbered[old] = new;
+    }
+    let mut symbols = Vec::new();
+    for (o, (_, object)) in objects.iter().enumerate() {
+        for symbol in &object.symbols {
+            if matches!(symbol.kind, SymbolKind::Section | SymbolKind::File) {
+                continue;
+            }
+            let Some(s) = symbol.section else {
+                continue;
+            };
+            let Some((i, offset)) = placements[o][s] else {
+                continue;
+            };
+            symbols.push(Symbol {
+                section: Some(renumbered[i]),
+                value: addresses[i] + offset + symbol.value,
+                ..symbol.clone()
+            });
+        }
+    }
+    Ok(Executable {
+        entry,
+        sections: order.iter().map(|&i| outputs[i].clone()).collect(),
+        addresses: order.iter().map(|&i| addresses[i]).collect(),
+        symbols,
+    })
+}
+
+/// The memory image of `executable` as a flat binary, from its first
+/// section with contents to the end of its last, with zeros in between.
+pub fn flat_binary(executable: &Executable) -> Vec<u8> {
+    let stored = || {
+        executable
+            .sections
+            .iter()
+            .zip(&executable.addresses)
+            .filter(|(section, _)| section.kind != SectionKind::Nobits && section.size > 0)
+    };
+    let Some(start) = stored().map(|(_, &address)| address).min() else {
+        return Vec::new();
+    };
+    let end = stored()
+        .map(|(section, &address)| address + section.size)
+        .max()
+        .unwrap_or(start);
+    let mut image = vec![0; (end - start) as usize];
+    for (section, &address) in stored() {
+        let at = (address - start) as usize;
+        image[at..at + section.data.len()].copy_from_slice(&section.data);
+    }
+    image
+}
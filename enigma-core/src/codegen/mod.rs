@@ -0,0 +1,40 @@
+//! Native code generation from the MIR.
+//!
+//! Generic functions are first instantiated for every set of type arguments
+//! the program uses (see `mono.rs`), then each instance is compiled by the
+//! target backend. The only target so far is x86-64 Linux, written out as
+//! assembly or as an ELF object (see `x86` and `elf.rs`). Objects are
+//! linked into executables and flat binaries by `link.rs`.
+//!
+//! A `ref` to a protocol object is a pair of pointers, to the value and to
+//! the vtable of its type for the protocol, laid out as `crate::mono::Vtable`
+//! describes. Calls through it load the method from the vtable.
+//!
+//! Not everything the interpreter runs can be compiled yet: printing
+//! `char`s and `float`s and comparing compound values are reported as
+//! unsupported instead.
+
+pub mod elf;
+pub mod layout;
+pub mod link;
+pub mod mono;
+pub mod x86;
+
+use std::fmt;
+
+/// Why a function can't be compiled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodegenError {
+    pub function: String,
+    pub message: String,
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cannot compile `{}`: {} aren't supported yet",
+            self.function, self.message
+        )
+    }
+}
@@ -0,0 +1,351 @@
+//! Instantiates the MIR for code generation. The instances are the ones
+//! `crate::mono` collected from the checked program, along with every module
+//! initializer; the closures, function items and calls in their MIR are
+//! followed to the lifted closures and thunks they need too. Each is compiled
+//! once under its own symbol. Protocol objects point to the vtables
+//! `crate::mono` collected, laid out as its `Vtable` describes.
+
+use super::CodegenError;
+use crate::checker::items::FnAttrs;
+use crate::checker::types::Ty;
+use crate::checker::{FnOwner, FnRef};
+use crate::lexer::size::Span;
+use crate::mir::{
+    AggregateKind, Block, Callee, Function, Inst, InstKind, Operand, Owner, Program, Terminator,
+    ValueId,
+};
+use std::collections::HashSet;
+
+/// An instance of a function, ready to compile: its body with the generic
+/// parameters substituted.
+#[derive(Debug, Clone)]
+pub struct MonoFunction {
+    pub symbol: String,
+    /// The generic arguments the function was instantiated with.
+    pub args: Vec<Ty>,
+    pub function: Function,
+}
+
+/// The symbol of the thunk through which a function value calls the
+/// function `symbol`. It takes the closure environment first, as lifted
+/// closures do, and ignores it.
+pub fn thunk_symbol(symbol: &str) -> String {
+    format!("{}$thunk", symbol)
+}
+
+/// The symbol of the thunk through which a function value builds the
+/// variant `variant` of the union `ty`.
+pub fn ctor_symbol(ty: &Ty, variant: usize) -> String {
+    format!("{}$ctor{}", symbol(&ty.to_string(), &[]), variant)
+}
+
+/// The symbol of the vtable of `ty` for `protocol`.
+pub fn vtable_symbol(ty: &Ty, protocol: &str) -> String {
+    symbol(&format!("{}::{{vtable}}::{}", ty, protocol), &[])
+}
+
+/// The slot of `method` in the vtables for `protocol`.
+pub fn vtable_slot(program: &Program, protocol: &str, method: &str) -> Option<usize> {
+    let index = program
+        .protocols
+        .get(protocol)?
+        .iter()
+        .position(|name| name == method)?;
+    Some(crate::mono::VTABLE_HEADER.len() + index)
+}
+
+/// The symbol of the instance of the function named `name` for `args`.
+/// Names are kept readable: `human::new` becomes `_EN.human.new` and
+/// `id[int]` becomes `_EN.id$Lint$R`. No source name contains `$`, so the
+/// compiler's own names such as `{init}` can't collide with the user's.
+pub fn symbol(name: &str, args: &[Ty]) -> String {
+    let mut symbol = String::from("_EN.");
+    mangle(&mut symbol, name);
+    if !args.is_empty() {
+        let args: Vec<String> = args.iter().map(Ty::to_string).collect();
+        mangle(&mut symbol, &format!("[{}]", args.join(",")));
+    }
+    symbol
+}
+
+/// The symbol of the instance of `function` for `args`: its own name if it
+/// is `#[no_mangle]` or `extern "C"`, which only free functions without
+/// generic parameters are.
+pub fn function_symbol(function: &Function, args: &[Ty]) -> String {
+    if function.attrs.no_mangle || function.attrs.c_abi {
+        let name = function.name.rsplit("::").next().unwrap_or(&function.name);
+        return name.to_string();
+    }
+    symbol(&function.name, args)
+}
+
+fn mangle(out: &mut String, name: &str) {
+    for c in name.replace("::", ".").chars() {
+        match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' => out.push(c),
+            '{' => out.push('$'),
+            '}' | '#' => {}
+            ' ' => out.push('_'),
+            '[' => out.push_str("$L"),
+            ']' => out.push_str("$R"),
+            ',' => out.push_str("$C"),
+            '(' => out.push_str("$P"),
+            ')' => out.push_str("$Q"),
+            '@' => out.push_str("$F"),
+            other => out.push_str(&format!("$u{:x}", other as u32)),
+        }
+    }
+}
+
+/// The MIR function a call runs and the type arguments it runs with. A
+/// protocol method resolves to the receiver's implementation, which must
+/// not be generic.
+pub fn resolve<'p>(
+    program: &'p Program,
+    callee: &Callee,
+) -> Result<(&'p Function, Vec<Ty>), String> {
+    let Callee::Fn {
+        func,
+        args,
+        self_ty,
+    } = callee
+    else {
+        return Err("calls to function values".to_string());
+    };
+    let (func, args) = match (&func.owner, self_ty) {
+        (FnOwner::Protocol(_), Some(Ty::Adt { name, .. })) => (
+            FnRef {
+                owner: FnOwner::Type(name.clone()),
+                name: func.name.clone(),
+            },
+            Vec::new(),
+        ),
+        (FnOwner::Protocol(protocol), _) => {
+            return Err(format!("calls through the protocol `{}`", protocol));
+        }
+        _ => (func.clone(), args.clone()),
+    };
+    find(program, &func, args)
+}
+
+/// The function `func` with generic arguments `args`.
+pub fn find<'p>(
+    program: &'p Program,
+    func: &FnRef,
+    args: Vec<Ty>,
+) -> Result<(&'p Function, Vec<Ty>), String> {
+    let owner = Owner::Function(func.clone());
+    let function = program
+        .functions
+        .iter()
+        .find(|f| f.owner == owner)
+        .ok_or_else(|| format!("functions without a body like `{}`", func.name))?;
+    if function.generics.len() != args.len() {
+        return Err(format!(
+            "methods of the generic implementation `{}`",
+            function.name
+        ));
+    }
+    Ok((function, args))
+}
+
+/// Every instance the program needs: those `crate::mono` found and the
+/// module initializers, in program order, each followed by the closures and
+/// calls it needs, and finally the thunks of the functions and constructors
+/// used as values.
+pub fn instances(program: &Program) -> Result<Vec<MonoFunction>, CodegenError> {
+    let mut work: Vec<(&Function, Vec<Ty>)> = Vec::new();
+    for instance in &program.mono.instances {
+        // Extern functions have no body; calls to them are `Callee::Extern`.
+        let owner = Owner::Function(instance.func.clone());
+        if !program.functions.iter().any(|f| f.owner == owner) {
+            continue;
+        }
+        let found = find(program, &instance.func, instance.args.clone());
+        work.push(found.map_err(|message| CodegenError {
+            function: instance.to_string(),
+            message,
+        })?);
+    }
+    work.extend(
+        program
+            .functions
+            .iter()
+            .filter(|f| matches!(f.owner, Owner::Init(_)))
+            .map(|f| (f, Vec::new())),
+    );
+    // Compile in program order, which keeps the output stable.
+    let position = |function: &Function| {
+        let found = program
+            .functions
+            .iter()
+            .position(|f| std::ptr::eq(f, function));
+        found.unwrap_or(usize::MAX)
+    };
+    work.sort_by_key(|(function, _)| position(function));
+    work.reverse();
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut instances = Vec::new();
+    let mut thunks = Vec::new();
+    while let Some((generic, args)) = work.pop() {
+        let symbol = function_symbol(generic, &args);
+        if !seen.insert(symbol.clone()) {
+            continue;
+        }
+        let function = generic.instantiate(&args);
+        let error = |message: String| CodegenError {
+            function: function.name.clone(),
+            message,
+        };
+        let mut callees = Vec::new();
+        for inst in function.blocks.iter().flat_map(|block| &block.insts) {
+            match &inst.kind {
+                // The methods a vtable points to are instances of their own.
+                InstKind::Call {
+                    callee:
+                        Callee::Fn {
+                            self_ty: Some(Ty::Dyn(_)),
+                            ..
+                        },
+                    ..
+                } => {}
+                InstKind::Call {
+                    callee: callee @ Callee::Fn { .. },
+                    ..
+                } => callees.push(resolve(program, callee).map_err(error)?),
+                InstKind::FnItem { func, args } => {
+                    let (target, args) = find(program, func, args.clone()).map_err(error)?;
+                    let thunk = fn_thunk(func, &target.instantiate(&args), &args);
+                    if seen.insert(thunk.symbol.clone()) {
+                        thunks.push(thunk);
+                    }
+                    callees.push((target, args));
+                }
+                InstKind::VariantCtor { union, variant } => {
+                    let result = inst.result.map(|value| function.value_ty(value));
+                    let Some(Ty::Fn { params, ret }) = result else {
+                        return Err(error(format!("constructors of `{}` used as values", union)));
+                    };
+                    let thunk = ctor_thunk(union, *variant, params, ret);
+                    if seen.insert(thunk.symbol.clone()) {
+                        thunks.push(thunk);
+                    }
+                }
+                // A closure shares the generic parameters of the function
+                // it was lifted from.
+                InstKind::Closure { function: name, .. } => {
+                    let closure = program
+                        .function(name)
+                        .ok_or_else(|| error(format!("closures without a body like `{}`", name)))?;
+                    callees.push((closure, args.clone()));
+                }
+                _ => {}
+            }
+        }
+        work.extend(callees.into_iter().rev());
+        instances.push(MonoFunction {
+            symbol,
+            args,
+            function,
+        });
+    }
+    instances.extend(thunks);
+    Ok(instances)
+}
+
+/// A function of `params` whose only block passes them to `body` and
+/// returns its result, after an environment parameter it ignores.
+fn thunk(name: String, owner: Owner, params: &[Ty], ret: &Ty, body: InstKind) -> Function {
+    let env = Ty::RawRef {
+        mutable: false,
+        inner: Box::new(Ty::Unit),
+    };
+    let mut values = vec![env];
+    values.extend(params.iter().cloned());
+    let result = ValueId(values.len() as u32);
+    values.push(ret.clone());
+    let (result, term) = match ret {
+        Ty::Never => (None, Terminator::Unreachable),
+        _ => (Some(result), Terminator::Return(Operand::Value(result))),
+    };
+    let params: Vec<ValueId> = (0..=params.len() as u32).map(ValueId).collect();
+    let mut body = body;
+    for operand in body.operands_mut() {
+        // The body's operands are the parameters after the environment.
+        if let Operand::Value(id) = operand {
+            *id = ValueId(id.0 + 1);
+        }
+    }
+    Function {
+        name,
+        owner,
+        generics: Vec::new(),
+        ret: ret.clone(),
+        attrs: FnAttrs::default(),
+        locals: Vec::new(),
+        values,
+        blocks: vec![Block {
+            params,
+            insts: vec![Inst {
+                result,
+                kind: body,
+                span: Span::default(),
+            }],
+            term,
+        }],
+    }
+}
+
+fn fn_thunk(func: &FnRef, target: &Function, args: &[Ty]) -> MonoFunction {
+    let params: Vec<Ty> = target
+        .params()
+        .iter()
+        .map(|&param| target.value_ty(param).clone())
+        .collect();
+    let call = InstKind::Call {
+        callee: Callee::Fn {
+            func: func.clone(),
+            args: args.to_vec(),
+            self_ty: None,
+        },
+        args: (0..params.len() as u32)
+            .map(|i| Operand::Value(ValueId(i)))
+            .collect(),
+    };
+    let function = thunk(
+        format!("{}::{{thunk}}", target.name),
+        Owner::Function(func.clone()),
+        &params,
+        &target.ret,
+        call,
+    );
+    MonoFunction {
+        symbol: thunk_symbol(&function_symbol(target, args)),
+        args: Vec::new(),
+        function,
+    }
+}
+
+fn ctor_thunk(union: &str, variant: usize, params: &[Ty], ret: &Ty) -> MonoFunction {
+    let aggregate = InstKind::Aggregate {
+        kind: AggregateKind::Variant {
+            union: union.to_string(),
+            variant,
+        },
+        fields: (0..params.len() as u32)
+            .map(|i| Operand::Value(ValueId(i)))
+            .collect(),
+    };
+    let function = thunk(
+        format!("{}::{{ctor#{}}}", ret, variant),
+        Owner::Init(String::new()),
+        params,
+        ret,
+        aggregate,
+    );
+    MonoFunction {
+        symbol: ctor_symbol(ret, variant),
+        args: Vec::new(),
+        function,
+    }
+}
@@ -0,0 +1,179 @@
+//! Boot code for freestanding programs: a Multiboot2 header, and `_start32`,
+//! which a Multiboot2 loader such as GRUB enters.
+//!
+//! The loader leaves the machine in 32-bit protected mode with paging off,
+//! the magic number in `eax` and the address of the boot information in
+//! `ebx`. `_start32` identity maps the first GiB with 2 MiB pages, enables
+//! long mode and SSE, loads a GDT with a 64-bit code segment and calls
+//! `_start` with the magic and the address as its arguments, on a stack of
+//! its own. The encoder only knows 64-bit code, so the 32-bit part is kept
+//! here already encoded, with the relocations it needs.
+
+use crate::codegen::layout::Layout;
+
+/// The section holding the header, which must be within the first 32 KiB of
+/// the kernel image.
+pub const HEADER_SECTION: &str = ".multiboot2";
+pub const ENTRY: &str = "_start32";
+/// The symbol `_start32` calls.
+pub const CALLS: &str = "_start";
+
+const PML4: &str = "__enigma.boot.pml4";
+const PDPT: &str = "__enigma.boot.pdpt";
+const PD: &str = "__enigma.boot.pd";
+const STACK: &str = "__enigma.boot.stack";
+const STACK_SIZE: i64 = 64 * 1024;
+
+const MAGIC: u32 = 0xe852_50d6;
+const LENGTH: u32 = 24;
+
+/// The header: the magic number, the architecture (i386), the header's
+/// length and a checksum making the three sum to zero, then the end tag.
+pub fn header() -> [u32; 6] {
+    let checksum = 0u32.wrapping_sub(MAGIC.wrapping_add(LENGTH));
+    [MAGIC, 0, LENGTH, checksum, 0, 8]
+}
+
+/// The page tables and the stack, zeroed memory the program reserves.
+pub fn globals() -> Vec<(String, Layout)> {
+    let table = Layout {
+        size: 4096,
+        align: 4096,
+    };
+    vec![
+        (PML4.to_string(), table),
+        (PDPT.to_string(), table),
+        (PD.to_string(), table),
+        (
+            STACK.to_string(),
+            Layout {
+                size: STACK_SIZE as u64,
+                align: 16,
+            },
+        ),
+    ]
+}
+
+/// Part of an encoded instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Piece {
+    Bytes(&'static [u8]),
+    /// The 32-bit address of a symbol plus an addend.
+    Abs32(&'static str, i64),
+    /// A symbol's 32-bit offset from this field, plus an addend.
+    Rel32(&'static str, i64),
+}
+
+use Piece::*;
+
+/// The code of `_start32`, each instruction with its Intel syntax. Labels
+/// within it are addressed as offsets from `_start32`.
+pub const CODE: &[(&str, &[Piece])] = &[
+    ("cli", &[Bytes(&[0xfa])]),
+    ("cld", &[Bytes(&[0xfc])]),
+    (
+        "mov esp, __enigma.boot.stack + 65536",
+        &[Bytes(&[0xbc]), Abs32(STACK, STACK_SIZE)],
+    ),
+    ("mov edi, eax", &[Bytes(&[0x89, 0xc7])]),
+    ("mov esi, ebx", &[Bytes(&[0x89, 0xde])]),
+    // The PML4 and the PDPT each map their first entry to the next table,
+    // present and writable.
+    (
+        "mov eax, __enigma.boot.pdpt + 3",
+        &[Bytes(&[0xb8]), Abs32(PDPT, 3)],
+    ),
+    (
+        "mov [__enigma.boot.pml4], eax",
+        &[Bytes(&[0xa3]), Abs32(PML4, 0)],
+    ),
+    (
+        "mov eax, __enigma.boot.pd + 3",
+        &[Bytes(&[0xb8]), Abs32(PD, 3)],
+    ),
+    (
+        "mov [__enigma.boot.pdpt], eax",
+        &[Bytes(&[0xa3]), Abs32(PDPT, 0)],
+    ),
+    // Each of the 512 entries of the PD maps a present, writable 2 MiB
+    // page.
+    ("xor ecx, ecx", &[Bytes(&[0x31, 0xc9])]),
+    ("mov eax, ecx", &[Bytes(&[0x89, 0xc8])]),
+    ("shl eax, 21", &[Bytes(&[0xc1, 0xe0, 0x15])]),
+    ("or eax, 0x83", &[Bytes(&[0x0d, 0x83, 0x00, 0x00, 0x00])]),
+    (
+        "mov [__enigma.boot.pd + ecx * 8], eax",
+        &[Bytes(&[0x89, 0x04, 0xcd]), Abs32(PD, 0)],
+    ),
+    ("inc ecx", &[Bytes(&[0x41])]),
+    (
+        "cmp ecx, 512",
+        &[Bytes(&[0x81, 0xf9, 0x00, 0x02, 0x00, 0x00])],
+    ),
+    ("jne _start32 + 0x21", &[Bytes(&[0x75, 0xe6])]),
+    (
+        "mov eax, __enigma.boot.pml4",
+        &[Bytes(&[0xb8]), Abs32(PML4, 0)],
+    ),
+    ("mov cr3, eax", &[Bytes(&[0x0f, 0x22, 0xd8])]),
+    // PAE, and SSE with its exceptions.
+    ("mov eax, cr4", &[Bytes(&[0x0f, 0x20, 0xe0])]),
+    ("or eax, 0x620", &[Bytes(&[0x0d, 0x20, 0x06, 0x00, 0x00])]),
+    ("mov cr4, eax", &[Bytes(&[0x0f, 0x22, 0xe0])]),
+    // Long mode, in the EFER.
+    (
+        "mov ecx, 0xc0000080",
+        &[Bytes(&[0xb9, 0x80, 0x00, 0x00, 0xc0])],
+    ),
+    ("rdmsr", &[Bytes(&[0x0f, 0x32])]),
+    ("or eax, 0x100", &[Bytes(&[0x0d, 0x00, 0x01, 0x00, 0x00])]),
+    ("wrmsr", &[Bytes(&[0x0f, 0x30])]),
+    // Paging, and the FPU without emulation.
+    ("mov eax, cr0", &[Bytes(&[0x0f, 0x20, 0xc0])]),
+    ("and eax, 0xfffffffb", &[Bytes(&[0x83, 0xe0, 0xfb])]),
+    (
+        "or eax, 0x80000002",
+        &[Bytes(&[0x0d, 0x02, 0x00, 0x00, 0x80])],
+    ),
+    ("mov cr0, eax", &[Bytes(&[0x0f, 0x22, 0xc0])]),
+    (
+        "lgdt [_start32 + 0xb5]",
+        &[Bytes(&[0x0f, 0x01, 0x15]), Abs32(ENTRY, 0xb5)],
+    ),
+    (
+        "jmp 0x08:_start32 + 0x78",
+        &[Bytes(&[0xea]), Abs32(ENTRY, 0x78), Bytes(&[0x08, 0x00])],
+    ),
+    // In 64-bit code from here on.
+    ("mov eax, 0x10", &[Bytes(&[0xb8, 0x10, 0x00, 0x00, 0x00])]),
+    ("mov ds, eax", &[Bytes(&[0x8e, 0xd8])]),
+    ("mov es, eax", &[Bytes(&[0x8e, 0xc0])]),
+    ("mov ss, eax", &[Bytes(&[0x8e, 0xd0])]),
+    ("xor eax, eax", &[Bytes(&[0x31, 0xc0])]),
+    ("mov fs, eax", &[Bytes(&[0x8e, 0xe0])]),
+    ("mov gs, eax", &[Bytes(&[0x8e, 0xe8])]),
+    (
+        "lea rsp, [rip + __enigma.boot.stack + 65536]",
+        &[Bytes(&[0x48, 0x8d, 0x25]), Rel32(STACK, STACK_SIZE - 4)],
+    ),
+    ("mov edi, edi", &[Bytes(&[0x89, 0xff])]),
+    ("mov esi, esi", &[Bytes(&[0x89, 0xf6])]),
+    ("call _start", &[Bytes(&[0xe8]), Rel32(CALLS, -4)]),
+    ("cli", &[Bytes(&[0xfa])]),
+    ("hlt", &[Bytes(&[0xf4])]),
+    ("jmp _start32 + 0x99", &[Bytes(&[0xeb, 0xfc])]),
+    // The GDT, then the pointer `lgdt` loads.
+    ("null descriptor", &[Bytes(&[0; 8])]),
+    (
+        "64-bit code segment",
+        &[Bytes(&[0xff, 0xff, 0x00, 0x00, 0x00, 0x9a, 0xaf, 0x00])],
+    ),
+    (
+        "data segment",
+        &[Bytes(&[0xff, 0xff, 0x00, 0x00, 0x00, 0x92, 0xcf, 0x00])],
+    ),
+    (
+        "GDT limit and base",
+        &[Bytes(&[0x17, 0x00]), Abs32(ENTRY, 0x9d)],
+    ),
+];
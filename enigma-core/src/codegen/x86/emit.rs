@@ -0,0 +1,552 @@
+//! Prints allocated machine IR as assembly, in the AT&T syntax of the GNU
+//! assembler or the Intel syntax of NASM.
+
+use super::boot::{self, Piece};
+use super::isel::Data;
+use super::lir::*;
+use super::{Compiled, Syntax};
+use crate::codegen::layout::Layout;
+use crate::target::Target;
+use std::fmt::Write;
+
+pub fn assembly(compiled: &Compiled, syntax: Syntax) -> String {
+    let mut printer = Printer {
+        out: String::new(),
+        syntax,
+    };
+    let sections = compiled.sections();
+    printer.text(&sections[0].1, &compiled.data);
+    if compiled.target == Target::Freestanding {
+        printer.boot();
+    }
+    for (name, functions) in &sections[1..] {
+        printer.section(name, functions);
+    }
+    printer.data(&compiled.data, &compiled.globals);
+    printer.out
+}
+
+struct Printer {
+    out: String,
+    syntax: Syntax,
+}
+
+impl Printer {
+    fn line(&mut self, line: impl AsRef<str>) {
+        self.out.push_str(line.as_ref());
+        self.out.push('\n');
+    }
+
+    fn directive(&mut self, line: impl AsRef<str>) {
+        self.out.push('\t');
+        self.line(line);
+    }
+
+    fn text(&mut self, functions: &[&MFunction], data: &Data) {
+        match self.syntax {
+            Syntax::Att => self.directive(".text"),
+            Syntax::Intel => {
+                self.line("default rel");
+                for name in &data.externs {
+                    self.line(format!("extern {}", name));
+                }
+                self.line("");
+                // NASM aligns `.text` to 16 bytes unless told otherwise.
+                match max_align(functions) {
+                    align if align > 16 => self.line(format!("section .text align={}", align)),
+                    _ => self.line("section .text"),
+                }
+            }
+        }
+        for function in functions {
+            self.line("");
+            self.function(function);
+        }
+    }
+
+    /// Writes the functions `#[section]` puts in the section `name`.
+    fn section(&mut self, name: &str, functions: &[&MFunction]) {
+        self.line("");
+        match self.syntax {
+            Syntax::Att => self.directive(format!(".section {}, \"ax\", @progbits", name)),
+            Syntax::Intel => self.line(format!(
+                "section {} progbits alloc exec nowrite align={}",
+                name,
+                max_align(functions)
+            )),
+        }
+        for function in functions {
+            self.line("");
+            self.function(function);
+        }
+    }
+
+    fn function(&mut self, function: &MFunction) {
+        let symbol = &function.symbol;
+        // Padding with `nop`s, as NASM does by default.
+        if let Some(align) = function.align {
+            match self.syntax {
+                Syntax::Att => self.directive(format!(".balign {}, 0x90", align)),
+                Syntax::Intel => self.directive(format!("align {}", align)),
+            }
+        }
+        match self.syntax {
+            Syntax::Att => {
+                if function.global {
+                    self.directive(format!(".globl {}", symbol));
+                }
+                self.directive(format!(".type {}, @function", symbol));
+            }
+            Syntax::Intel if function.global => self.line(format!("global {}:function", symbol)),
+            Syntax::Intel => {}
+        }
+        self.line(format!("{}:", symbol));
+        for (b, block) in function.blocks.iter().enumerate() {
+            // The first block is entered by calls, through the symbol.
+            if b > 0 {
+                self.line(format!("{}:", block.label));
+            }
+            for inst in &block.insts {
+                self.inst(inst);
+            }
+        }
+        if self.syntax == Syntax::Att {
+            self.directive(format!(".size {}, .-{}", symbol, symbol));
+        }
+    }
+
+    /// Writes `_start32` into `.text`, as the bytes `boot.rs` has encoded,
+    /// and the Multiboot2 header into its section.
+    fn boot(&mut self) {
+        let intel = self.syntax == Syntax::Intel;
+        let comment = if intel { ";" } else { "#" };
+        self.line("");
+        if intel {
+            self.line(format!("global {}:function", boot::ENTRY));
+        } else {
+            self.directive(format!(".globl {}", boot::ENTRY));
+            self.directive(format!(".type {}, @function", boot::ENTRY));
+        }
+        self.line(format!("{}:", boot::ENTRY));
+        for (text, pieces) in boot::CODE {
+            for (k, piece) in pieces.iter().enumerate() {
+                let value = |symbol: &str, addend: i64| match addend {
+                    0 => symbol.to_string(),
+                    _ => format!("{}{:+}", symbol, addend),
+                };
+                let mut line = match (piece, intel) {
+                    (Piece::Bytes(bytes), _) => {
+                        let bytes: Vec<String> =
+                            bytes.iter().map(|byte| format!("{:#04x}", byte)).collect();
+                        let directive = if intel { "db" } else { ".byte" };
+                        format!("{} {}", directive, bytes.join(", "))
+                    }
+                    (Piece::Abs32(symbol, addend), false) => {
+                        format!(".long {}", value(symbol, *addend))
+                    }
+                    (Piece::Abs32(symbol, addend), true) => {
+                        format!("dd {}", value(symbol, *addend))
+                    }
+                    (Piece::Rel32(symbol, addend), false) => {
+                        format!(".long {}-.", value(symbol, *addend))
+                    }
+                    (Piece::Rel32(symbol, addend), true) => {
+                        format!("dd {}-$", value(symbol, *addend))
+                    }
+                };
+                if k == 0 {
+                    write!(line, "\t{} {}", comment, text).unwrap();
+                }
+                self.directive(line);
+            }
+        }
+        if !intel {
+            self.directive(format!(".size {}, .-{}", boot::ENTRY, boot::ENTRY));
+        }
+
+        let header: Vec<String> = boot::header()
+            .iter()
+            .map(|word| format!("{:#x}", word))
+            .collect();
+        self.line("");
+        if intel {
+            self.line(format!(
+                "section {} progbits alloc noexec nowrite align=8",
+                boot::HEADER_SECTION
+            ));
+            self.line(format!("\tdd {}", header.join(", ")));
+        } else {
+            self.directive(format!(".section {}, \"a\"", boot::HEADER_SECTION));
+            self.directive(".balign 8");
+            self.directive(format!(".long {}", header.join(", ")));
+        }
+    }
+
+    fn data(&mut self, data: &Data, globals: &[(String, Layout)]) {
+        let intel = self.syntax == Syntax::Intel;
+        // NASM doesn't raise a section's alignment to that of its contents.
+        let section = |name: &str, align: u64| {
+            if intel {
+                format!("section {} align={}", name, align)
+            } else {
+                format!("\t.section {}", name)
+            }
+        };
+        let align = |align: u64| {
+            if intel {
+                format!("\talign {}", align)
+            } else {
+                format!("\t.balign {}", align)
+            }
+        };
+        let quad = |value: &str| {
+            if intel {
+                format!("\tdq {}", value)
+            } else {
+                format!("\t.quad {}", value)
+            }
+        };
+        if !data.strings.is_empty()
+            || !data.cstrings.is_empty()
+            || !data.floats.is_empty()
+            || data.sign_mask
+        {
+            self.line("");
+            self.line(section(".rodata", 16));
+            if data.sign_mask {
+                self.line(align(16));
+                self.line(format!("{}:", Data::SIGN_MASK));
+                self.line(quad("0x8000000000000000"));
+                self.line(quad("0"));
+            }
+            for (i, bits) in data.floats.iter().enumerate() {
+                self.line(align(8));
+                self.line(format!("{}:", Data::float_label(i)));
+                self.line(quad(&format!("{:#x}", bits)));
+            }
+            for (i, string) in data.strings.iter().enumerate() {
+                self.line(format!("{}:", Data::bytes_label(i)));
+                self.bytes(string.as_bytes(), false);
+            }
+            for (i, string) in data.cstrings.iter().enumerate() {
+                self.line(format!("{}:", Data::cstring_label(i)));
+                self.bytes(string.as_bytes(), true);
+            }
+        }
+        // The headers of strings point to their bytes and vtables to
+        // functions, so they are written by the dynamic loader in
+        // position-independent executables.
+        if !data.strings.is_empty() || !data.vtables.is_empty() {
+            self.line("");
+            self.line(section(".data", 8));
+            self.line(align(8));
+            for (i, string) in data.strings.iter().enumerate() {
+                self.line(format!("{}:", Data::string_label(i)));
+                self.line(quad(&Data::bytes_label(i)));
+                self.line(quad(&string.len().to_string()));
+            }
+            for vtable in &data.vtables {
+                self.line(format!("{}:", vtable.symbol));
+                self.line(quad("0"));
+                self.line(quad(&vtable.size.to_string()));
+                self.line(quad(&vtable.align.to_string()));
+                for method in &vtable.methods {
+                    self.line(quad(method));
+                }
+            }
+        }
+        if !globals.is_empty() {
+            self.line("");
+            match self.syntax {
+                Syntax::Att => self.directive(".bss"),
+                Syntax::Intel => {
+                    let align = globals.iter().map(|(_, layout)| layout.align).max();
+                    self.line(format!(
+                        "section .bss nobits align={}",
+                        align.unwrap_or(8).max(8)
+                    ));
+                }
+            }
+            for (symbol, layout) in globals {
+                if intel {
+                    self.line(format!("\talignb {}", layout.align));
+                    self.line(format!("{}:", symbol));
+                    self.line(format!("\tresb {}", layout.size));
+                } else {
+                    self.line(format!("\t.balign {}", layout.align));
+                    self.line(format!("{}:", symbol));
+                    self.line(format!("\t.zero {}", layout.size));
+                }
+            }
+        }
+        self.line("");
+        match self.syntax {
+            Syntax::Att => self.directive(".section .note.GNU-stack,\"\",@progbits"),
+            Syntax::Intel => {
+                self.line("section .note.GNU-stack noalloc noexec nowrite progbits");
+            }
+        }
+    }
+
+    /// Writes `bytes` as a string, escaping anything unprintable.
+    fn bytes(&mut self, bytes: &[u8], nul: bool) {
+        let mut escaped = String::new();
+        for &byte in bytes {
+            match byte {
+                b'\n' => escaped.push_str("\\n"),
+                b'\t' => escaped.push_str("\\t"),
+                b'\\' => escaped.push_str("\\\\"),
+                b'"' if self.syntax == Syntax::Att => escaped.push_str("\\\""),
+                b'`' if self.syntax == Syntax::Intel => escaped.push_str("\\`"),
+                b' '..=b'~' => escaped.push(byte as char),
+                _ => write!(escaped, "\\{:03o}", byte).unwrap(),
+            }
+        }
+        let line = match (self.syntax, nul) {
+            (Syntax::Att, false) => format!(".ascii \"{}\"", escaped),
+            (Syntax::Att, true) => format!(".asciz \"{}\"", escaped),
+            (Syntax::Intel, _) if bytes.is_empty() && !nul => String::new(),
+            (Syntax::Intel, _) if bytes.is_empty() => "db 0".to_string(),
+            (Syntax::Intel, false) => format!("db `{}`", escaped),
+            (Syntax::Intel, true) => format!("db `{}`, 0", escaped),
+        };
+        if !line.is_empty() {
+            self.directive(line);
+        }
+    }
+
+    // -----------------------------------------------------------------
+    // Instructions
+    // -----------------------------------------------------------------
+
+    fn reg(&self, reg: Reg, size: Size) -> String {
+        let name = match reg {
+            Reg::Gpr(gpr) => gpr.name(size).to_string(),
+            Reg::Xmm(n) => format!("xmm{}", n),
+            Reg::Virt(n, _) => format!("v{}", n),
+        };
+        match self.syntax {
+            Syntax::Att => format!("%{}", name),
+            Syntax::Intel => name,
+        }
+    }
+
+    fn mem(&self, mem: &Mem, size: Option<Size>) -> String {
+        let disp = |disp: i64| match disp {
+            0 => String::new(),
+            d if d < 0 => d.to_string(),
+            d => format!("+{}", d),
+        };
+        match self.syntax {
+            Syntax::Att => match &mem.base {
+                Base::Reg(reg) if mem.disp == 0 => format!("({})", self.reg(*reg, Size::Q)),
+                Base::Reg(reg) => format!("{}({})", mem.disp, self.reg(*reg, Size::Q)),
+                Base::Symbol(symbol) => format!("{}{}(%rip)", symbol, disp(mem.disp)),
+                base => unreachable!("{:?} left after frame layout", base),
+            },
+            Syntax::Intel => {
+                let address = match &mem.base {
+                    Base::Reg(reg) => format!("[{}{}]", self.reg(*reg, Size::Q), disp(mem.disp)),
+                    Base::Symbol(symbol) => format!("[rel {}{}]", symbol, disp(mem.disp)),
+                    base => unreachable!("{:?} left after frame layout", base),
+                };
+                match size {
+                    Some(size) => format!("{} {}", size, address),
+                    None => address,
+                }
+            }
+        }
+    }
+
+    fn operand(&self, operand: &Operand, size: Size) -> String {
+        match operand {
+            Operand::Reg(reg) => self.reg(*reg, size),
+            Operand::Imm(n) => match self.syntax {
+                Syntax::Att => format!("${}", n),
+                Syntax::Intel => n.to_string(),
+            },
+            Operand::Mem(mem) => self.mem(mem, Some(size)),
+        }
+    }
+
+    /// Writes an instruction of two operands in the syntax's order. AT&T
+    /// mnemonics take the operand size as a suffix.
+    fn binary(&mut self, mnemonic: &str, size: Option<Size>, dst: String, src: String) {
+        let line = match self.syntax {
+            Syntax::Att => {
+                let suffix = size.map_or("", suffix);
+                format!("{}{} {}, {}", mnemonic, suffix, src, dst)
+            }
+            Syntax::Intel => format!("{} {}, {}", mnemonic, dst, src),
+        };
+        self.directive(line);
+    }
+
+    fn unary(&mut self, mnemonic: &str, size: Option<Size>, operand: String) {
+        let line = match self.syntax {
+            Syntax::Att => format!("{}{} {}", mnemonic, size.map_or("", suffix), operand),
+            Syntax::Intel => format!("{} {}", mnemonic, operand),
+        };
+        self.directive(line);
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        let att = self.syntax == Syntax::Att;
+        match inst {
+            Inst::Mov {
+                size: Size::Q,
+                dst: dst @ Operand::Reg(_),
+                src: Operand::Imm(n),
+            } if att && i32::try_from(*n).is_err() => {
+                let dst = self.operand(dst, Size::Q);
+                self.directive(format!("movabsq ${}, {}", n, dst));
+            }
+            Inst::Mov { size, dst, src } => {
+                let (dst, src) = (self.operand(dst, *size), self.operand(src, *size));
+                self.binary("mov", Some(*size), dst, src);
+            }
+            Inst::MovZx { size, dst, src } => {
+                // Writing the low 32 bits of a register clears the rest.
+                let (mnemonic, dst) = match size {
+                    Size::D | Size::Q => ("mov", self.reg(*dst, *size)),
+                    _ if att => (
+                        if *size == Size::B { "movzb" } else { "movzw" },
+                        self.reg(*dst, Size::D),
+                    ),
+                    _ => ("movzx", self.reg(*dst, Size::D)),
+                };
+                let src = self.operand(src, *size);
+                let size = match size {
+                    Size::D | Size::Q => Some(*size),
+                    _ => Some(Size::D),
+                };
+                self.binary(mnemonic, size, dst, src);
+            }
+            Inst::Lea { dst, src } => {
+                let (dst, src) = (self.reg(*dst, Size::Q), self.mem(src, None));
+                self.binary("lea", Some(Size::Q), dst, src);
+            }
+            Inst::Alu { op, size, dst, src } => {
+                let (dst, src) = (self.operand(dst, *size), self.operand(src, *size));
+                self.binary(op.mnemonic(), Some(*size), dst, src);
+            }
+            Inst::Imul { size, dst, src } => {
+                let (dst, src) = (self.reg(*dst, *size), self.operand(src, *size));
+                self.binary("imul", Some(*size), dst, src);
+            }
+            Inst::Neg { size, dst } => {
+                let dst = self.operand(dst, *size);
+                self.unary("neg", Some(*size), dst);
+            }
+            Inst::Not { size, dst } => {
+                let dst = self.operand(dst, *size);
+                self.unary("not", Some(*size), dst);
+            }
+            Inst::Shift {
+                op,
+                size,
+                dst,
+                amount,
+            } => {
+                let dst = self.operand(dst, *size);
+                let amount = self.operand(&Operand::Imm(*amount as i64), *size);
+                self.binary(op.mnemonic(), Some(*size), dst, amount);
+            }
+            Inst::Cqo => self.directive(if att { "cqto" } else { "cqo" }),
+            Inst::Div { signed, size, src } => {
+                let src = self.operand(src, *size);
+                self.unary(if *signed { "idiv" } else { "div" }, Some(*size), src);
+            }
+            Inst::Setcc { cond, dst } => {
+                let dst = self.reg(*dst, Size::B);
+                self.unary(&format!("set{}", cond.suffix()), None, dst);
+            }
+            Inst::Cmov { cond, dst, src } => {
+                let (dst, src) = (self.reg(*dst, Size::Q), self.operand(src, Size::Q));
+                self.binary(&format!("cmov{}", cond.suffix()), Some(Size::Q), dst, src);
+            }
+            Inst::Sse { op, dst, src } => {
+                let sse = |printer: &Self, operand: &Operand| match operand {
+                    // `xorpd` reads 16 bytes.
+                    Operand::Mem(mem) if *op == SseOp::Xorpd => printer.mem(mem, None),
+                    operand => printer.operand(operand, Size::Q),
+                };
+                let (dst, src) = (sse(self, dst), sse(self, src));
+                self.binary(op.mnemonic(), None, dst, src);
+            }
+            Inst::Movq { dst, src } => {
+                let (dst, src) = (self.reg(*dst, Size::Q), self.reg(*src, Size::Q));
+                self.binary("movq", None, dst, src);
+            }
+            Inst::Jmp(label) => self.directive(format!("jmp {}", label)),
+            Inst::Jcc(cond, label) => self.directive(format!("j{} {}", cond.suffix(), label)),
+            Inst::Call { target, .. } => {
+                let target = match (target, self.syntax) {
+                    (CallTarget::Symbol(symbol), _) => symbol.clone(),
+                    (CallTarget::External(name), Syntax::Att) => format!("{}@PLT", name),
+                    (CallTarget::External(name), Syntax::Intel) => format!("{} wrt ..plt", name),
+                    (CallTarget::Reg(reg), Syntax::Att) => format!("*{}", self.reg(*reg, Size::Q)),
+                    (CallTarget::Reg(reg), Syntax::Intel) => self.reg(*reg, Size::Q),
+                };
+                self.directive(format!("call {}", target));
+            }
+            Inst::Ret { .. } => self.directive("ret"),
+            Inst::Push(gpr) => {
+                let reg = self.reg(Reg::Gpr(*gpr), Size::Q);
+                self.unary("push", Some(Size::Q), reg);
+            }
+            Inst::Pop(gpr) => {
+                let reg = self.reg(Reg::Gpr(*gpr), Size::Q);
+                self.unary("pop", Some(Size::Q), reg);
+            }
+            Inst::Ud2 => self.directive("ud2"),
+            Inst::In { size } => {
+                let (port, value) = (
+                    self.reg(Gpr::Rdx.into(), Size::W),
+                    self.reg(Gpr::Rax.into(), *size),
+                );
+                self.binary("in", Some(*size), value, port);
+            }
+            Inst::Out { size } => {
+                let (port, value) = (
+                    self.reg(Gpr::Rdx.into(), Size::W),
+                    self.reg(Gpr::Rax.into(), *size),
+                );
+                self.binary("out", Some(*size), port, value);
+            }
+            Inst::Fence(fence) => self.directive(fence.mnemonic()),
+            Inst::Atomic { op, size, dst, src } => {
+                let (dst, src) = (self.mem(dst, Some(*size)), self.reg(*src, *size));
+                self.binary(op.mnemonic(), Some(*size), dst, src);
+            }
+            Inst::Asm { lines, .. } => {
+                if att {
+                    self.directive(".intel_syntax noprefix");
+                }
+                for line in lines {
+                    self.directive(line.trim());
+                }
+                if att {
+                    self.directive(".att_syntax prefix");
+                }
+            }
+        }
+    }
+}
+
+fn suffix(size: Size) -> &'static str {
+    match size {
+        Size::B => "b",
+        Size::W => "w",
+        Size::D => "l",
+        Size::Q => "q",
+    }
+}
+
+/// The largest alignment `functions` ask for, at least a byte.
+fn max_align(functions: &[&MFunction]) -> u64 {
+    functions
+        .iter()
+        .filter_map(|function| function.align)
+        .fold(1, u64::max)
+}
@@ -0,0 +1,896 @@
+//! Encodes allocated machine IR as x86-64 machine code, for writing
+//! objects without an assembler.
+//!
+//! Jumps within a function are resolved here, as short as their distance
+//! allows; references to symbols are left as relocations. Inline assembly
+//! is assembled too, for the instructions `assemble` knows.
+
+use super::lir::*;
+use crate::codegen::elf::{R_X86_64_PC32, R_X86_64_PLT32};
+use std::collections::HashMap;
+
+/// A reference to a symbol, to relocate when the code is placed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub offset: u64,
+    pub symbol: String,
+    pub kind: u32,
+    pub addend: i64,
+}
+
+/// A function's machine code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Encoded {
+    pub code: Vec<u8>,
+    pub relocs: Vec<Relocation>,
+}
+
+/// A piece of a function's code: instructions encoded in full, or a jump
+/// whose size depends on how far it goes.
+enum Chunk {
+    Bytes(Vec<u8>, Vec<Relocation>),
+    Jump {
+        cond: Option<Cond>,
+        label: String,
+        long: bool,
+    },
+    Label(String),
+}
+
+/// The r/m operand of an instruction.
+#[derive(Debug, Clone, Copy)]
+enum Rm<'a> {
+    Reg(u8),
+    Mem(u8, i64),
+    Rip(&'a str, i64),
+}
+
+pub fn function(function: &MFunction) -> Result<Encoded, String> {
+    let mut encoder = Encoder::default();
+    for block in &function.blocks {
+        encoder.flush();
+        encoder.chunks.push(Chunk::Label(block.label.clone()));
+        for inst in &block.insts {
+            encoder.inst(inst)?;
+        }
+    }
+    encoder.flush();
+    encoder.link()
+}
+
+#[derive(Default)]
+struct Encoder {
+    chunks: Vec<Chunk>,
+    code: Vec<u8>,
+    relocs: Vec<Relocation>,
+}
+
+fn number(reg: Reg) -> u8 {
+    match reg {
+        Reg::Gpr(gpr) => gpr.number(),
+        Reg::Xmm(n) => n,
+        Reg::Virt(..) => unreachable!("{:?} left after register allocation", reg),
+    }
+}
+
+fn rm(operand: &Operand) -> Rm<'_> {
+    match operand {
+        Operand::Reg(reg) => Rm::Reg(number(*reg)),
+        Operand::Mem(mem) => mem_rm(mem),
+        Operand::Imm(_) => unreachable!("an immediate isn't an r/m operand"),
+    }
+}
+
+fn mem_rm(mem: &Mem) -> Rm<'_> {
+    match &mem.base {
+        Base::Reg(reg) => Rm::Mem(number(*reg), mem.disp),
+        Base::Symbol(symbol) => Rm::Rip(symbol, mem.disp),
+        base => unreachable!("{:?} left after frame layout", base),
+    }
+}
+
+fn fits_i8(n: i64) -> bool {
+    i8::try_from(n).is_ok()
+}
+
+/// An immediate of `size`, which is at most 32 bits wide.
+fn imm(size: Size, n: i64) -> Result<Vec<u8>, String> {
+    match size {
+        Size::B => Ok(vec![n as u8]),
+        Size::W => Ok((n as u16).to_le_bytes().to_vec()),
+        _ => i32::try_from(n)
+            .map(|n| n.to_le_bytes().to_vec())
+            .map_err(|_| format!("the immediate `{}`", n)),
+    }
+}
+
+/// The opcode extension of an ALU operation in the `0x80` group, which
+/// also places its other forms.
+fn alu_ext(op: AluOp) -> u8 {
+    match op {
+        AluOp::Add => 0,
+        AluOp::Or => 1,
+        AluOp::And => 4,
+        AluOp::Sub => 5,
+        AluOp::Xor => 6,
+        AluOp::Cmp => 7,
+        AluOp::Test => unreachable!("`test` has no form in the `0x80` group"),
+    }
+}
+
+impl Encoder {
+    /// Moves the instructions encoded so far into a chunk.
+    fn flush(&mut self) {
+        if !self.code.is_empty() {
+            let code = std::mem::take(&mut self.code);
+            let relocs = std::mem::take(&mut self.relocs);
+            self.chunks.push(Chunk::Bytes(code, relocs));
+        }
+    }
+
+    fn jump(&mut self, cond: Option<Cond>, label: &str) {
+        self.flush();
+        self.chunks.push(Chunk::Jump {
+            cond,
+            label: label.to_string(),
+            long: false,
+        });
+    }
+
+    /// Lays out the chunks, lengthening jumps until each reaches its label,
+    /// and joins them.
+    fn link(mut self) -> Result<Encoded, String> {
+        let size = |chunk: &Chunk| match chunk {
+            Chunk::Bytes(code, _) => code.len() as i64,
+            Chunk::Jump { long: false, .. } => 2,
+            Chunk::Jump {
+                cond: None,
+                long: true,
+                ..
+            } => 5,
+            Chunk::Jump { long: true, .. } => 6,
+            Chunk::Label(_) => 0,
+        };
+        let (offsets, labels) = loop {
+            let mut offsets = Vec::with_capacity(self.chunks.len() + 1);
+            let mut labels = HashMap::new();
+            let mut offset = 0;
+            for chunk in &self.chunks {
+                offsets.push(offset);
+                if let Chunk::Label(label) = chunk {
+                    labels.insert(label.clone(), offset);
+                }
+                offset += size(chunk);
+            }
+            offsets.push(offset);
+            let mut grown = false;
+            for (i, chunk) in self.chunks.iter_mut().enumerate() {
+                if let Chunk::Jump { label, long, .. } = chunk {
+                    let target = *labels
+                        .get(label)
+                        .ok_or_else(|| format!("jumps to the missing label `{}`", label))?;
+                    if !*long && !fits_i8(target - offsets[i + 1]) {
+                        *long = true;
+                        grown = true;
+                    }
+                }
+            }
+            if !grown {
+                break (offsets, labels);
+            }
+        };
+
+        let mut encoded = Encoded {
+            code: Vec::new(),
+            relocs: Vec::new(),
+        };
+        for (i, chunk) in self.chunks.into_iter().enumerate() {
+            match chunk {
+                Chunk::Bytes(code, relocs) => {
+                    let base = encoded.code.len() as u64;
+                    encoded.code.extend(code);
+                    encoded
+                        .relocs
+                        .extend(relocs.into_iter().map(|reloc| Relocation {
+                            offset: base + reloc.offset,
+                            ..reloc
+                        }));
+                }
+                Chunk::Jump { cond, label, long } => {
+                    let rel = labels[&label] - offsets[i + 1];
+                    match (cond, long) {
+                        (None, false) => encoded.code.extend([0xeb, rel as u8]),
+                        (Some(cond), false) => {
+                            encoded.code.extend([0x70 + cond.number(), rel as u8])
+                        }
+                        (None, true) => {
+                            encoded.code.push(0xe9);
+                            encoded.code.extend((rel as i32).to_le_bytes());
+                        }
+                        (Some(cond), true) => {
+                            encoded.code.extend([0x0f, 0x80 + cond.number()]);
+                            encoded.code.extend((rel as i32).to_le_bytes());
+                        }
+                    }
+                }
+                Chunk::Label(_) => {}
+            }
+        }
+        Ok(encoded)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// Encodes an instruction with a ModRM byte: `prefix`, a REX prefix
+    /// with at least the bits of `rex`, `opcode`, the operands `reg` and
+    /// `rm`, and then `imm`.
+    fn modrm(&mut self, prefix: &[u8], rex: u8, opcode: &[u8], reg: u8, rm: Rm, imm: &[u8]) {
+        self.bytes(prefix);
+        let mut rex = rex;
+        if reg >= 8 {
+            rex |= 0x44;
+        }
+        if let Rm::Reg(n) | Rm::Mem(n, _) = rm
+            && n >= 8
+        {
+            rex |= 0x41;
+        }
+        if rex != 0 {
+            self.code.push(rex | 0x40);
+        }
+        self.bytes(opcode);
+        let reg = (reg & 7) << 3;
+        match rm {
+            Rm::Reg(n) => self.code.push(0xc0 | reg | n & 7),
+            Rm::Mem(base, disp) => {
+                // `rbp` and `r13` have no form without a displacement.
+                let mode = if disp == 0 && base & 7 != 5 {
+                    0x00
+                } else if fits_i8(disp) {
+                    0x40
+                } else {
+                    0x80
+                };
+                self.code.push(mode | reg | base & 7);
+                // `rsp` and `r12` are only bases through a SIB byte.
+                if base & 7 == 4 {
+                    self.code.push(0x24);
+                }
+                match mode {
+                    0x40 => self.code.push(disp as u8),
+                    0x80 => self.bytes(&(disp as i32).to_le_bytes()),
+                    _ => {}
+                }
+            }
+            Rm::Rip(symbol, disp) => {
+                self.code.push(reg | 5);
+                // The displacement is from the end of the instruction.
+                self.relocs.push(Relocation {
+                    offset: self.code.len() as u64,
+                    symbol: symbol.to_string(),
+                    kind: R_X86_64_PC32,
+                    addend: disp - 4 - imm.len() as i64,
+                });
+                self.bytes(&[0; 4]);
+            }
+        }
+        self.bytes(imm);
+    }
+
+    /// Encodes an instruction of operand `size`, whose byte form is
+    /// `op8` and other forms `op`, with the register `reg` and `rm`.
+    fn sized(&mut self, size: Size, op8: &[u8], op: &[u8], reg: u8, rm: Rm, imm: &[u8]) {
+        // Without a REX prefix, the byte registers 4 to 7 are `ah` to `bh`.
+        let rex = if size == Size::B && (4..8).contains(&reg) {
+            0x40
+        } else {
+            0
+        };
+        self.sized_ext(size, op8, op, reg, rm, imm, rex);
+    }
+
+    /// Like `sized`, but with an opcode extension in place of a register.
+    #[allow(clippy::too_many_arguments)]
+    fn sized_ext(
+        &mut self,
+        size: Size,
+        op8: &[u8],
+        op: &[u8],
+        ext: u8,
+        rm: Rm,
+        imm: &[u8],
+        rex: u8,
+    ) {
+        let rex = match (size, rm) {
+            (Size::Q, _) => rex | 0x48,
+            (Size::B, Rm::Reg(n)) if (4..8).contains(&n) => rex | 0x40,
+            _ => rex,
+        };
+        let prefix: &[u8] = if size == Size::W { &[0x66] } else { &[] };
+        let opcode = if size == Size::B { op8 } else { op };
+        self.modrm(prefix, rex, opcode, ext, rm, imm);
+    }
+
+    /// Encodes an instruction whose register operand is in its opcode.
+    fn plus_reg(&mut self, size: Size, opcode: u8, n: u8, imm: &[u8]) {
+        if size == Size::W {
+            self.code.push(0x66);
+        }
+        let mut rex = if size == Size::Q { 0x48 } else { 0 };
+        if n >= 8 {
+            rex |= 0x41;
+        } else if size == Size::B && n >= 4 {
+            rex |= 0x40;
+        }
+        if rex != 0 {
+            self.code.push(rex);
+        }
+        self.code.push(opcode + (n & 7));
+        self.bytes(imm);
+    }
+
+    fn inst(&mut self, inst: &Inst) -> Result<(), String> {
+        match inst {
+            Inst::Mov { size, dst, src } => self.mov(*size, dst, src)?,
+            Inst::MovZx { size, dst, src } => match size {
+                Size::B | Size::W => {
+                    let opcode = if *size == Size::B { 0xb6 } else { 0xb7 };
+                    let rm = rm(src);
+                    let rex = match rm {
+                        Rm::Reg(n) if *size == Size::B && (4..8).contains(&n) => 0x40,
+                        _ => 0,
+                    };
+                    self.modrm(&[], rex, &[0x0f, opcode], number(*dst), rm, &[]);
+                }
+                _ => self.mov(*size, &Operand::Reg(*dst), src)?,
+            },
+            Inst::Lea { dst, src } => {
+                self.modrm(&[], 0x48, &[0x8d], number(*dst), mem_rm(src), &[])
+            }
+            Inst::Alu { op, size, dst, src } => self.alu(*op, *size, dst, src)?,
+            Inst::Imul { size, dst, src } => {
+                let reg = number(*dst);
+                match src {
+                    Operand::Imm(n) if fits_i8(*n) => {
+                        self.sized(*size, &[], &[0x6b], reg, Rm::Reg(reg), &[*n as u8])
+                    }
+                    Operand::Imm(n) => {
+                        let imm = imm(*size, *n)?;
+                        self.sized(*size, &[], &[0x69], reg, Rm::Reg(reg), &imm);
+                    }
+                    src => self.sized(*size, &[], &[0x0f, 0xaf], reg, rm(src), &[]),
+                }
+            }
+            Inst::Neg { size, dst } => self.sized_ext(*size, &[0xf6], &[0xf7], 3, rm(dst), &[], 0),
+            Inst::Not { size, dst } => self.sized_ext(*size, &[0xf6], &[0xf7], 2, rm(dst), &[], 0),
+            Inst::Shift {
+                op,
+                size,
+                dst,
+                amount,
+            } => {
+                let ext = match op {
+                    ShiftOp::Shl => 4,
+                    ShiftOp::Shr => 5,
+                };
+                // Shifts by one have a form without the immediate.
+                match amount {
+                    1 => self.sized_ext(*size, &[0xd0], &[0xd1], ext, rm(dst), &[], 0),
+                    _ => self.sized_ext(*size, &[0xc0], &[0xc1], ext, rm(dst), &[*amount], 0),
+                }
+            }
+            Inst::Cqo => self.bytes(&[0x48, 0x99]),
+            Inst::Div { signed, size, src } => {
+                let ext = if *signed { 7 } else { 6 };
+                self.sized_ext(*size, &[0xf6], &[0xf7], ext, rm(src), &[], 0);
+            }
+            Inst::Setcc { cond, dst } => {
+                let opcode = [0x0f, 0x90 + cond.number()];
+                self.sized_ext(Size::B, &opcode, &[], 0, Rm::Reg(number(*dst)), &[], 0);
+            }
+            Inst::Cmov { cond, dst, src } => {
+                let opcode = [0x0f, 0x40 + cond.number()];
+                self.modrm(&[], 0x48, &opcode, number(*dst), rm(src), &[]);
+            }
+            Inst::Sse { op, dst, src } => {
+                let (prefix, opcode) = match op {
+                    SseOp::Movsd if matches!(dst, Operand::Mem(_)) => {
+                        let src = match src {
+                            Operand::Reg(reg) => number(*reg),
+                            _ => unreachable!("`movsd` stores a register"),
+                        };
+                        self.modrm(&[0xf2], 0, &[0x0f, 0x11], src, rm(dst), &[]);
+                        return Ok(());
+                    }
+                    SseOp::Movsd => (0xf2, 0x10),
+                    SseOp::Addsd => (0xf2, 0x58),
+                    SseOp::Mulsd => (0xf2, 0x59),
+                    SseOp::Subsd => (0xf2, 0x5c),
+                    SseOp::Divsd => (0xf2, 0x5e),
+                    SseOp::Ucomisd => (0x66, 0x2e),
+                    SseOp::Xorpd => (0x66, 0x57),
+                };
+                let Operand::Reg(dst) = dst else {
+                    unreachable!("`{}` writes a register", op.mnemonic());
+                };
+                self.modrm(&[prefix], 0, &[0x0f, opcode], number(*dst), rm(src), &[]);
+            }
+            Inst::Movq { dst, src } => match (dst, src) {
+                (Reg::Xmm(x), gpr) => {
+                    self.modrm(&[0x66], 0x48, &[0x0f, 0x6e], *x, Rm::Reg(number(*gpr)), &[])
+                }
+                (gpr, Reg::Xmm(x)) => {
+                    self.modrm(&[0x66], 0x48, &[0x0f, 0x7e], *x, Rm::Reg(number(*gpr)), &[])
+                }
+                _ => unreachable!("`movq` moves between register classes"),
+            },
+            Inst::Jmp(label) => self.jump(None, label),
+            Inst::Jcc(cond, label) => self.jump(Some(*cond), label),
+            Inst::Call { target, .. } => match target {
+                CallTarget::Symbol(symbol) | CallTarget::External(symbol) => {
+                    self.code.push(0xe8);
+                    self.relocs.push(Relocation {
+                        offset: self.code.len() as u64,
+                        symbol: symbol.clone(),
+                        kind: R_X86_64_PLT32,
+                        addend: -4,
+                    });
+                    self.bytes(&[0; 4]);
+                }
+                CallTarget::Reg(reg) => self.modrm(&[], 0, &[0xff], 2, Rm::Reg(number(*reg)), &[]),
+            },
+            Inst::Ret { .. } => self.code.push(0xc3),
+            Inst::Push(gpr) => self.plus_reg(Size::D, 0x50, gpr.number(), &[]),
+            Inst::Pop(gpr) => self.plus_reg(Size::D, 0x58, gpr.number(), &[]),
+            Inst::Ud2 => self.bytes(&[0x0f, 0x0b]),
+            Inst::In { size } | Inst::Out { size } => {
+                let opcode = if matches!(inst, Inst::In { .. }) {
+                    0xe4
+                } else {
+                    0xe6
+                };
+                let dx = Operand::Reg(Reg::Gpr(Gpr::Rdx));
+                self.port(*size, opcode, &dx, &dx)?;
+            }
+            Inst::Fence(fence) => self.bytes(plain(fence.mnemonic()).unwrap()),
+            Inst::Atomic { op, size, dst, src } => {
+                // The `lock` prefix; `xchg` with memory locks by itself.
+                if *op != AtomicOp::Xchg {
+                    self.code.push(0xf0);
+                }
+                let (op8, op): (&[u8], &[u8]) = match op {
+                    AtomicOp::Xchg => (&[0x86], &[0x87]),
+                    AtomicOp::Xadd => (&[0x0f, 0xc0], &[0x0f, 0xc1]),
+                    AtomicOp::Cmpxchg => (&[0x0f, 0xb0], &[0x0f, 0xb1]),
+                };
+                self.sized(*size, op8, op, number(*src), mem_rm(dst), &[]);
+            }
+            Inst::Asm { lines, .. } => {
+                for line in lines {
+                    self.assemble(line.trim())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn mov(&mut self, size: Size, dst: &Operand, src: &Operand) -> Result<(), String> {
+        match (dst, src) {
+            (Operand::Reg(reg), Operand::Imm(n)) => {
+                let n = *n;
+                match size {
+                    Size::Q if i32::try_from(n).is_err() => {
+                        self.plus_reg(Size::Q, 0xb8, number(*reg), &n.to_le_bytes())
+                    }
+                    Size::Q => {
+                        let imm = imm(size, n)?;
+                        self.sized_ext(size, &[], &[0xc7], 0, Rm::Reg(number(*reg)), &imm, 0);
+                    }
+                    Size::B => self.plus_reg(size, 0xb0, number(*reg), &[n as u8]),
+                    _ => {
+                        // `mov r32, imm32` takes any 32-bit pattern.
+                        let imm = match size {
+                            Size::D => (n as u32).to_le_bytes().to_vec(),
+                            _ => imm(size, n)?,
+                        };
+                        self.plus_reg(size, 0xb8, number(*reg), &imm);
+                    }
+                }
+            }
+            (dst, Operand::Imm(n)) => {
+                let imm = imm(size, *n)?;
+                self.sized_ext(size, &[0xc6], &[0xc7], 0, rm(dst), &imm, 0);
+            }
+            (dst, Operand::Reg(reg)) => {
+                self.sized(size, &[0x88], &[0x89], number(*reg), rm(dst), &[])
+            }
+            (Operand::Reg(reg), src) => {
+                self.sized(size, &[0x8a], &[0x8b], number(*reg), rm(src), &[])
+            }
+            _ => unreachable!("`mov` between two memory operands"),
+        }
+        Ok(())
+    }
+
+    fn alu(&mut self, op: AluOp, size: Size, dst: &Operand, src: &Operand) -> Result<(), String> {
+        let rax = Operand::Reg(Reg::Gpr(Gpr::Rax));
+        match (dst, src) {
+            (dst, Operand::Imm(n)) if op == AluOp::Test => {
+                let imm = imm(size, *n)?;
+                if *dst == rax {
+                    // The accumulator has a form without a ModRM byte.
+                    self.plus_reg(size, if size == Size::B { 0xa8 } else { 0xa9 }, 0, &imm);
+                } else {
+                    self.sized_ext(size, &[0xf6], &[0xf7], 0, rm(dst), &imm, 0);
+                }
+            }
+            (dst, Operand::Imm(n)) => {
+                let ext = alu_ext(op);
+                if size != Size::B && fits_i8(*n) {
+                    self.sized_ext(size, &[], &[0x83], ext, rm(dst), &[*n as u8], 0);
+                } else if *dst == rax {
+                    // The accumulator has a form without a ModRM byte.
+                    let opcode = ext * 8 + if size == Size::B { 4 } else { 5 };
+                    self.plus_reg(size, opcode, 0, &imm(size, *n)?);
+                } else {
+                    let imm = imm(size, *n)?;
+                    self.sized_ext(size, &[0x80], &[0x81], ext, rm(dst), &imm, 0);
+                }
+            }
+            (dst, Operand::Reg(reg)) => {
+                let (op8, op) = match op {
+                    AluOp::Test => (0x84, 0x85),
+                    op => (alu_ext(op) * 8, alu_ext(op) * 8 + 1),
+                };
+                self.sized(size, &[op8], &[op], number(*reg), rm(dst), &[]);
+            }
+            (Operand::Reg(reg), src) => {
+                let (op8, op) = match op {
+                    // `test` is symmetric.
+                    AluOp::Test => (0x84, 0x85),
+                    op => (alu_ext(op) * 8 + 2, alu_ext(op) * 8 + 3),
+                };
+                self.sized(size, &[op8], &[op], number(*reg), rm(src), &[]);
+            }
+            _ => unreachable!("`{}` between two memory operands", op.mnemonic()),
+        }
+        Ok(())
+    }
+
+    // -----------------------------------------------------------------
+    // Inline assembly
+    // -----------------------------------------------------------------
+
+    /// Assembles a line of Intel syntax. Instructions with a form in the
+    /// machine IR are encoded as that; the rest are looked up by mnemonic.
+    fn assemble(&mut self, line: &str) -> Result<(), String> {
+        let line = line.split(';').next().unwrap_or("").trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if let Some(bytes) = plain(&words.join(" ").to_ascii_lowercase()) {
+            self.bytes(bytes);
+            return Ok(());
+        }
+        let (mnemonic, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let mnemonic = mnemonic.to_ascii_lowercase();
+        let unsupported = || format!("`{}` instructions in objects", mnemonic);
+        // `call` and `jmp` to a symbol take its 32-bit offset, which the
+        // linker fills in.
+        let rest = rest.trim();
+        if let Some(opcode) = match mnemonic.as_str() {
+            "call" => Some(0xe8),
+            "jmp" => Some(0xe9),
+            _ => None,
+        } && is_symbol(rest)
+        {
+            self.code.push(opcode);
+            self.relocs.push(Relocation {
+                offset: self.code.len() as u64,
+                symbol: rest.to_string(),
+                kind: R_X86_64_PLT32,
+                addend: -4,
+            });
+            self.bytes(&[0; 4]);
+            return Ok(());
+        }
+        let operands = if rest.trim().is_empty() {
+            Vec::new()
+        } else {
+            rest.split(',')
+                .map(|operand| parse_operand(operand.trim()).ok_or_else(unsupported))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        let size = operands
+            .iter()
+            .find_map(|operand| operand.size)
+            .unwrap_or(Size::Q);
+        let ops: Vec<&Operand> = operands.iter().map(|operand| &operand.operand).collect();
+        let gpr = |operand: &Operand| match operand {
+            Operand::Reg(reg @ Reg::Gpr(_)) => Some(*reg),
+            _ => None,
+        };
+        let alu = match mnemonic.as_str() {
+            "add" => Some(AluOp::Add),
+            "or" => Some(AluOp::Or),
+            "and" => Some(AluOp::And),
+            "sub" => Some(AluOp::Sub),
+            "xor" => Some(AluOp::Xor),
+            "cmp" => Some(AluOp::Cmp),
+            "test" => Some(AluOp::Test),
+            _ => None,
+        };
+        let dx = Operand::Reg(Reg::Gpr(Gpr::Rdx));
+        let inst = match (mnemonic.as_str(), ops.as_slice()) {
+            ("mov", [dst, src]) if !matches!(dst, Operand::Imm(_)) => {
+                Inst::mov(size, (*dst).clone(), (*src).clone())
+            }
+            (_, [dst, src]) if alu.is_some() && !matches!(dst, Operand::Imm(_)) => {
+                Inst::alu(alu.unwrap(), size, (*dst).clone(), (*src).clone())
+            }
+            ("lea", [Operand::Reg(dst), Operand::Mem(src)]) if size == Size::Q => Inst::Lea {
+                dst: *dst,
+                src: src.clone(),
+            },
+            ("imul", [dst, src]) if gpr(dst).is_some() => Inst::Imul {
+                size,
+                dst: gpr(dst).unwrap(),
+                src: (*src).clone(),
+            },
+            ("neg", [dst]) => Inst::Neg {
+                size,
+                dst: (*dst).clone(),
+            },
+            ("not", [dst]) => Inst::Not {
+                size,
+                dst: (*dst).clone(),
+            },
+            ("shl" | "shr", [dst, Operand::Imm(n)]) if u8::try_from(*n).is_ok() => Inst::Shift {
+                op: if mnemonic == "shl" {
+                    ShiftOp::Shl
+                } else {
+                    ShiftOp::Shr
+                },
+                size,
+                dst: (*dst).clone(),
+                amount: *n as u8,
+            },
+            ("div" | "idiv", [src]) => Inst::Div {
+                signed: mnemonic == "idiv",
+                size,
+                src: (*src).clone(),
+            },
+            (_, [dst @ Operand::Reg(Reg::Xmm(_)), src])
+            | (_, [dst, src @ Operand::Reg(Reg::Xmm(_))])
+                if sse(&mnemonic).is_some() =>
+            {
+                Inst::Sse {
+                    op: sse(&mnemonic).unwrap(),
+                    dst: (*dst).clone(),
+                    src: (*src).clone(),
+                }
+            }
+            ("push", [Operand::Reg(Reg::Gpr(gpr))]) if size == Size::Q => Inst::Push(*gpr),
+            ("pop", [Operand::Reg(Reg::Gpr(gpr))]) if size == Size::Q => Inst::Pop(*gpr),
+            ("call", [Operand::Reg(reg @ Reg::Gpr(_))]) => Inst::Call {
+                target: CallTarget::Reg(*reg),
+                args: Vec::new(),
+                results: Vec::new(),
+            },
+            ("jmp", [Operand::Reg(reg @ Reg::Gpr(_))]) => {
+                self.modrm(&[], 0, &[0xff], 4, Rm::Reg(number(*reg)), &[]);
+                return Ok(());
+            }
+            ("int", [Operand::Imm(n)]) if u8::try_from(*n).is_ok() => {
+                self.bytes(&[0xcd, *n as u8]);
+                return Ok(());
+            }
+            ("lgdt" | "lidt", [Operand::Mem(mem)]) => {
+                let ext = if mnemonic == "lgdt" { 2 } else { 3 };
+                self.modrm(&[], 0, &[0x0f, 0x01], ext, mem_rm(mem), &[]);
+                return Ok(());
+            }
+            ("fxsave64" | "fxrstor64", [Operand::Mem(mem)]) => {
+                let ext = if mnemonic == "fxsave64" { 0 } else { 1 };
+                self.modrm(&[], 0x48, &[0x0f, 0xae], ext, mem_rm(mem), &[]);
+                return Ok(());
+            }
+            // Port I/O takes the port in `dx` or an immediate, and the
+            // value in the accumulator.
+            ("in", [Operand::Reg(Reg::Gpr(Gpr::Rax)), port]) => {
+                return self.port(size, 0xe4, port, &dx);
+            }
+            ("out", [port, Operand::Reg(Reg::Gpr(Gpr::Rax))]) => {
+                let size = operands[1].size.unwrap_or(Size::B);
+                return self.port(size, 0xe6, port, &dx);
+            }
+            _ => return Err(unsupported()),
+        };
+        self.inst(&inst)
+    }
+
+    /// Encodes `in` or `out`, whose form with an immediate port is `opcode`.
+    fn port(&mut self, size: Size, opcode: u8, port: &Operand, dx: &Operand) -> Result<(), String> {
+        let wide = if size == Size::B { 0 } else { 1 };
+        if size == Size::W {
+            self.code.push(0x66);
+        }
+        match port {
+            Operand::Imm(n) if u8::try_from(*n).is_ok() => {
+                self.bytes(&[opcode + wide, *n as u8]);
+            }
+            port if port == dx => self.code.push(opcode + 8 + wide),
+            _ => return Err("ports other than `dx` and bytes".to_string()),
+        }
+        Ok(())
+    }
+}
+
+/// The encoding of instructions without operands, string instructions
+/// with their prefix included.
+fn plain(mnemonic: &str) -> Option<&'static [u8]> {
+    Some(match mnemonic {
+        "nop" => &[0x90],
+        "hlt" => &[0xf4],
+        "cli" => &[0xfa],
+        "sti" => &[0xfb],
+        "cld" => &[0xfc],
+        "std" => &[0xfd],
+        "ret" => &[0xc3],
+        "leave" => &[0xc9],
+        "int3" => &[0xcc],
+        "pause" => &[0xf3, 0x90],
+        "cdq" => &[0x99],
+        "cqo" => &[0x48, 0x99],
+        "pushfq" => &[0x9c],
+        "popfq" => &[0x9d],
+        "iretq" => &[0x48, 0xcf],
+        "ud2" => &[0x0f, 0x0b],
+        "syscall" => &[0x0f, 0x05],
+        "sysretq" => &[0x48, 0x0f, 0x07],
+        "cpuid" => &[0x0f, 0xa2],
+        "rdtsc" => &[0x0f, 0x31],
+        "rdmsr" => &[0x0f, 0x32],
+        "wrmsr" => &[0x0f, 0x30],
+        "swapgs" => &[0x0f, 0x01, 0xf8],
+        "mfence" => &[0x0f, 0xae, 0xf0],
+        "lfence" => &[0x0f, 0xae, 0xe8],
+        "sfence" => &[0x0f, 0xae, 0xf8],
+        "rep movsb" => &[0xf3, 0xa4],
+        "rep stosb" => &[0xf3, 0xaa],
+        "repe cmpsb" => &[0xf3, 0xa6],
+        _ => return None,
+    })
+}
+
+/// An operand of inline assembly, with the size its register or size
+/// keyword gives it.
+struct AsmOperand {
+    operand: Operand,
+    size: Option<Size>,
+}
+
+fn sse(mnemonic: &str) -> Option<SseOp> {
+    [
+        SseOp::Movsd,
+        SseOp::Addsd,
+        SseOp::Subsd,
+        SseOp::Mulsd,
+        SseOp::Divsd,
+        SseOp::Ucomisd,
+        SseOp::Xorpd,
+    ]
+    .into_iter()
+    .find(|op| op.mnemonic() == mnemonic)
+}
+
+fn parse_register(name: &str) -> Option<(Reg, Size)> {
+    let name = name.to_ascii_lowercase();
+    if let Some(n) = name.strip_prefix("xmm")
+        && let Ok(n) = n.parse::<u8>()
+        && n < 16
+    {
+        return Some((Reg::Xmm(n), Size::Q));
+    }
+    Gpr::ALL.iter().find_map(|&gpr| {
+        [Size::B, Size::W, Size::D, Size::Q]
+            .into_iter()
+            .find(|&size| gpr.name(size) == name)
+            .map(|size| (Reg::Gpr(gpr), size))
+    })
+}
+
+fn parse_int(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits.trim()),
+        None => (false, text.strip_prefix('+').unwrap_or(text).trim()),
+    };
+    let value = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()? as i64,
+        None => digits.parse::<i64>().ok()?,
+    };
+    Some(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+/// Whether `text` names a symbol rather than a register or a number.
+fn is_symbol(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$'))
+        && parse_register(text).is_none()
+}
+
+fn parse_operand(text: &str) -> Option<AsmOperand> {
+    if let Some((reg, size)) = parse_register(text) {
+        return Some(AsmOperand {
+            operand: Operand::Reg(reg),
+            size: Some(size),
+        });
+    }
+    if let Some(n) = parse_int(text) {
+        return Some(AsmOperand {
+            operand: Operand::Imm(n),
+            size: None,
+        });
+    }
+    let (size, address) = match text.split_once(char::is_whitespace) {
+        Some((keyword, rest)) if !text.starts_with('[') => {
+            let size = match keyword.to_ascii_lowercase().as_str() {
+                "byte" => Size::B,
+                "word" => Size::W,
+                "dword" => Size::D,
+                "qword" => Size::Q,
+                _ => return None,
+            };
+            let rest = rest.trim();
+            let rest = rest.strip_prefix("ptr").unwrap_or(rest).trim();
+            (Some(size), rest)
+        }
+        _ => (None, text),
+    };
+    let inner = address.strip_prefix('[')?.strip_suffix(']')?.trim();
+    // `[rip + symbol]` addresses a symbol relative to the next instruction.
+    if let Some(rest) = inner.strip_prefix("rip")
+        && let Some(rest) = rest.trim_start().strip_prefix('+')
+    {
+        let rest = rest.trim();
+        let split = rest.find(['+', '-']).unwrap_or(rest.len());
+        let (symbol, disp) = rest.split_at(split);
+        let symbol = symbol.trim();
+        if symbol.is_empty() || symbol.starts_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        let mut mem = Mem::symbol(symbol);
+        if !disp.is_empty() {
+            mem.disp = parse_int(&disp.replace(' ', ""))?;
+        }
+        return Some(AsmOperand {
+            operand: Operand::Mem(mem),
+            size,
+        });
+    }
+    let split = inner.find(['+', '-']).unwrap_or(inner.len());
+    let (base, disp) = inner.split_at(split);
+    let (base, _) = parse_register(base.trim())?;
+    let disp = if disp.is_empty() {
+        0
+    } else {
+        parse_int(&disp.replace(' ', ""))?
+    };
+    Some(AsmOperand {
+        operand: Operand::Mem(Mem::reg(base, disp)),
+        size,
+    })
+}
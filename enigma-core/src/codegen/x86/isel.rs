@@ -0,0 +1,2513 @@
+//! Instruction selection: MIR to machine IR over virtual registers.
+//!
+//! Locals live in frame slots. SSA values of scalar types live in virtual
+//! registers; compound values (tuples, records, unions, strings and
+//! function values) live in frame slots too, and their register holds the
+//! slot's address. Since SSA values are never written after they are built,
+//! passing one around never needs a copy, except into the block parameters
+//! of a join, which get slots of their own.
+//!
+//! Calls follow System V: integer and pointer arguments go in `rdi`, `rsi`,
+//! `rdx`, `rcx`, `r8` and `r9`, `float`s in `xmm0` to `xmm7`, the rest on
+//! the stack, and results come back in `rax` or `xmm0`. Compound arguments
+//! are passed as a pointer to the value, and compound results are written
+//! to memory the caller provides through a hidden first argument, whose
+//! address comes back in `rax`. Values without a size, like `unit`, aren't
+//! passed at all.
+//!
+//! Calls to and from C pass records as C does instead. A record of up to 16
+//! bytes travels in registers, one per eightbyte: a `float` register for an
+//! eightbyte holding only `float`s, a general purpose one otherwise, and
+//! results come back in `rax` and `rdx` or `xmm0` and `xmm1`. Larger records,
+//! those with misaligned fields and those left without enough registers are
+//! copied onto the stack, and larger results still go through a hidden
+//! pointer.
+
+use super::lir::*;
+use crate::checker::types::Ty;
+use crate::checker::{FnOwner, FnRef};
+use crate::codegen::layout::{self, Bits, Layout};
+use crate::codegen::mono::{self, MonoFunction};
+use crate::mir::{
+    self, AggregateKind, Callee, Const, Function, InstKind, Owner, PlaceBase, Program, Projection,
+    Target, Terminator, ValueId,
+};
+use crate::parser::ast::{AsmPiece, AsmRef, AsmReg, BinOp, PhysReg, UnaryOp};
+use crate::target;
+use std::collections::{BTreeSet, HashMap};
+
+type Sel<T = ()> = Result<T, String>;
+
+/// Constants and external symbols shared by every function of the output.
+#[derive(Debug, Default)]
+pub struct Data {
+    /// Contents of `string` constants.
+    pub strings: Vec<String>,
+    /// NUL-terminated strings for the C library.
+    pub cstrings: Vec<String>,
+    /// Bit patterns of `float` constants.
+    pub floats: Vec<u64>,
+    /// Whether the mask flipping the sign of a `float` is used.
+    pub sign_mask: bool,
+    pub externs: BTreeSet<String>,
+    pub vtables: Vec<VtableData>,
+}
+
+/// A vtable as it is written out: the entries of `VTABLE_HEADER`, then a
+/// pointer to each method. No value needs dropping yet, so `drop` is null.
+#[derive(Debug)]
+pub struct VtableData {
+    pub symbol: String,
+    pub size: u64,
+    pub align: u64,
+    pub methods: Vec<String>,
+}
+
+impl Data {
+    /// The label of a `string` value: a pointer to the bytes, then their
+    /// length.
+    pub fn string_label(index: usize) -> String {
+        format!("__enigma.str{}", index)
+    }
+
+    pub fn bytes_label(index: usize) -> String {
+        format!("__enigma.bytes{}", index)
+    }
+
+    pub fn cstring_label(index: usize) -> String {
+        format!("__enigma.cstr{}", index)
+    }
+
+    pub fn float_label(index: usize) -> String {
+        format!("__enigma.f64.{}", index)
+    }
+
+    pub const SIGN_MASK: &'static str = "__enigma.signmask";
+
+    fn string(&mut self, s: &str) -> String {
+        let index = intern(&mut self.strings, s.to_string());
+        Self::string_label(index)
+    }
+
+    fn cstring(&mut self, s: &str) -> String {
+        let index = intern(&mut self.cstrings, s.to_string());
+        Self::cstring_label(index)
+    }
+
+    fn float(&mut self, x: f64) -> String {
+        let index = intern(&mut self.floats, x.to_bits());
+        Self::float_label(index)
+    }
+
+    fn external(&mut self, name: &str) -> CallTarget {
+        self.externs.insert(name.to_string());
+        CallTarget::External(name.to_string())
+    }
+}
+
+fn intern<T: PartialEq>(items: &mut Vec<T>, item: T) -> usize {
+    match items.iter().position(|existing| *existing == item) {
+        Some(index) => index,
+        None => {
+            items.push(item);
+            items.len() - 1
+        }
+    }
+}
+
+/// Registers `reg` operands of inline assembly get, in order of
+/// preference, and the ones left for loading spilled values.
+const ASM_GPRS: [Gpr; 12] = [
+    Gpr::Rax,
+    Gpr::Rcx,
+    Gpr::Rdx,
+    Gpr::Rsi,
+    Gpr::Rdi,
+    Gpr::R8,
+    Gpr::R9,
+    Gpr::Rbx,
+    Gpr::R12,
+    Gpr::R13,
+    Gpr::R14,
+    Gpr::R15,
+];
+const SCRATCH_GPRS: [Gpr; 2] = [Gpr::R10, Gpr::R11];
+
+/// How a value of some type is held.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    /// Nothing: the type has no size.
+    Zero,
+    /// In a general purpose register, zero-extended from its size.
+    Int(Size),
+    Float,
+    /// In memory, addressed by a general purpose register.
+    Memory(Layout),
+}
+
+/// Where an argument or parameter is passed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Loc {
+    Gpr(Gpr),
+    Xmm(u8),
+    /// At this offset among the stack arguments.
+    Stack(u64),
+}
+
+/// The calling convention of a call or function.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Abi {
+    Enigma,
+    /// `variadic` calls tell the callee in `al` how many `float` registers
+    /// hold arguments.
+    C {
+        variadic: bool,
+    },
+}
+
+/// How a call passes a value.
+#[derive(Debug, Clone, PartialEq)]
+enum Pass {
+    /// In a register of its kind, or a stack slot. Compound values are
+    /// passed by address this way, and returned through a hidden pointer.
+    Reg(Kind),
+    /// A small record for C: the offset of each eightbyte holding data, and
+    /// the class of register it goes in.
+    Eightbytes(Layout, Vec<(u64, RegClass)>),
+    /// A record C copies onto the stack, or returns through a hidden
+    /// pointer.
+    Memory(Layout),
+}
+
+impl Pass {
+    /// Whether a result passed this way is written to memory the caller
+    /// provides.
+    fn hidden_pointer(&self) -> bool {
+        matches!(self, Pass::Reg(Kind::Memory(_)) | Pass::Memory(_))
+    }
+}
+
+/// Assigns System V locations to values passed as `passes`, in order: for
+/// each value, where each of its pieces goes, with the piece's offset in
+/// the value. A small record left without enough registers for all its
+/// eightbytes goes on the stack whole, as larger ones do.
+fn assign(passes: &[Pass]) -> (Vec<Vec<(u64, Loc)>>, u64) {
+    let (mut ints, mut floats, mut stack) = (0, 0, 0);
+    let mut locs: Vec<Vec<(u64, Loc)>> = Vec::new();
+    for pass in passes {
+        let pieces = match pass {
+            Pass::Reg(Kind::Zero) => Vec::new(),
+            Pass::Reg(Kind::Float) if floats < 8 => {
+                floats += 1;
+                vec![(0, Loc::Xmm(floats - 1))]
+            }
+            Pass::Reg(Kind::Int(_) | Kind::Memory(_)) if ints < Gpr::ARGS.len() => {
+                ints += 1;
+                vec![(0, Loc::Gpr(Gpr::ARGS[ints - 1]))]
+            }
+            Pass::Reg(_) => {
+                stack += 8;
+                vec![(0, Loc::Stack(stack - 8))]
+            }
+            Pass::Eightbytes(_, eightbytes)
+                if ints + count(eightbytes, RegClass::Int) <= Gpr::ARGS.len()
+                    && floats as usize + count(eightbytes, RegClass::Float) <= 8 =>
+            {
+                eightbytes
+                    .iter()
+                    .map(|&(offset, class)| {
+                        let loc = match class {
+                            RegClass::Int => Loc::Gpr(Gpr::ARGS[ints]),
+                            RegClass::Float => Loc::Xmm(floats),
+                        };
+                        match class {
+                            RegClass::Int => ints += 1,
+                            RegClass::Float => floats += 1,
+                        }
+                        (offset, loc)
+                    })
+                    .collect()
+            }
+            Pass::Eightbytes(layout, _) | Pass::Memory(layout) => {
+                stack = layout::align_to(stack, layout.align.max(8));
+                let start = stack;
+                stack += layout::align_to(layout.size, 8);
+                (0..layout.size.div_ceil(8))
+                    .map(|i| (8 * i, Loc::Stack(start + 8 * i)))
+                    .collect()
+            }
+        };
+        locs.push(pieces);
+    }
+    (locs, stack)
+}
+
+/// How many of `eightbytes` go in registers of `class`.
+fn count(eightbytes: &[(u64, RegClass)], class: RegClass) -> usize {
+    eightbytes.iter().filter(|(_, c)| *c == class).count()
+}
+
+/// The registers a result passed as `ret` comes back in: the eightbytes
+/// of a small record in `rax` and `rdx`, or `xmm0` and `xmm1`, by class.
+fn result_regs(ret: &Pass) -> Vec<Reg> {
+    match ret {
+        Pass::Reg(Kind::Zero) => Vec::new(),
+        Pass::Reg(Kind::Float) => vec![Reg::Xmm(0)],
+        Pass::Reg(_) | Pass::Memory(_) => vec![Reg::Gpr(Gpr::Rax)],
+        Pass::Eightbytes(_, eightbytes) => {
+            let mut gprs = [Gpr::Rax, Gpr::Rdx].into_iter();
+            let mut xmms = 0..;
+            eightbytes
+                .iter()
+                .map(|(_, class)| match class {
+                    RegClass::Int => Reg::Gpr(gprs.next().unwrap()),
+                    RegClass::Float => Reg::Xmm(xmms.next().unwrap()),
+                })
+                .collect()
+        }
+    }
+}
+
+/// Selects instructions for `instance`, the `index`th function of the
+/// output.
+pub fn select(
+    program: &Program,
+    instance: &MonoFunction,
+    index: usize,
+    target: target::Target,
+    data: &mut Data,
+) -> Sel<MFunction> {
+    let function = &instance.function;
+    let mut isel = Isel {
+        program,
+        function,
+        target,
+        args: &instance.args,
+        data,
+        index,
+        blocks: Vec::new(),
+        label: format!(".L{}", index),
+        insts: Vec::new(),
+        slots: Vec::new(),
+        local_slots: Vec::new(),
+        values: Vec::new(),
+        param_slots: HashMap::new(),
+        vregs: 0,
+        outgoing: 0,
+        ret_ptr: None,
+        traps: Vec::new(),
+        block: 0,
+        splits: 0,
+    };
+    for local in &function.locals {
+        let layout = isel.layout(&local.ty)?;
+        let slot = isel.slot(layout);
+        isel.local_slots.push(slot);
+    }
+    for ty in &function.values {
+        let value = match isel.kind(ty)? {
+            Kind::Zero => None,
+            Kind::Float => Some(isel.vreg(RegClass::Float)),
+            _ => Some(isel.vreg(RegClass::Int)),
+        };
+        isel.values.push(value);
+    }
+    let attrs = &function.attrs;
+    if attrs.naked {
+        // The function is its assembly alone, which finds the parameters
+        // where the caller put them. The checker made sure nothing else in
+        // the body has an effect.
+        for inst in function.blocks.iter().flat_map(|block| &block.insts) {
+            if let InstKind::Asm(asm) = &inst.kind {
+                isel.asm(None, &Ty::Never, asm)?;
+            }
+        }
+        isel.emit(Inst::Ud2);
+    } else {
+        isel.receive_params()?;
+        // The parameters are received in a block of their own, as the entry
+        // block may be jumped back to.
+        for (b, block) in function.blocks.iter().enumerate() {
+            isel.start_block(label(index, b));
+            isel.block = b;
+            isel.splits = 0;
+            for inst in &block.insts {
+                isel.inst(inst.result, &inst.kind)?;
+            }
+            isel.terminator(b, &block.term)?;
+        }
+        for (k, message) in std::mem::take(&mut isel.traps).into_iter().enumerate() {
+            isel.start_block(trap_label(index, k));
+            isel.runtime_error(&message);
+        }
+    }
+    isel.start_block(String::new());
+    // An interrupt handler's symbol is its entry, which calls the body.
+    let (symbol, align) = if attrs.interrupt {
+        (interrupt_body(&instance.symbol), None)
+    } else {
+        (instance.symbol.clone(), attrs.align)
+    };
+    Ok(MFunction {
+        symbol,
+        global: true,
+        blocks: isel.blocks,
+        slots: isel.slots,
+        outgoing: isel.outgoing,
+        vregs: isel.vregs,
+        frame_size: 0,
+        saved: Vec::new(),
+        section: attrs.section.clone(),
+        align,
+        naked: attrs.naked,
+    })
+}
+
+/// The symbol of the body of the interrupt handler `symbol`: the function
+/// as written, called by the handler's entry.
+pub fn interrupt_body(symbol: &str) -> String {
+    format!("{}$body", symbol)
+}
+
+/// Registers an interrupt handler's entry saves; the body saves the
+/// callee-saved ones it uses itself.
+const INTERRUPT_SAVED: [Gpr; 9] = [
+    Gpr::Rax,
+    Gpr::Rcx,
+    Gpr::Rdx,
+    Gpr::Rsi,
+    Gpr::Rdi,
+    Gpr::R8,
+    Gpr::R9,
+    Gpr::R10,
+    Gpr::R11,
+];
+
+/// The bytes `fxsave64` writes: the x87 and SSE state.
+const FXSAVE_SIZE: i64 = 512;
+
+/// The entry of the interrupt handler `symbol`, which the processor enters
+/// through the interrupt descriptor table with the interrupted code's
+/// `rip`, `cs`, `rflags`, `rsp` and `ss` on the stack, below them an error
+/// code if the handler takes one. It saves the caller-saved registers and the SSE
+/// state, aligns the stack and calls the body, passing it the error code,
+/// then restores everything, drops the error code and returns with
+/// `iretq`.
+///
+/// ```text
+///   ss, rsp, rflags, cs, rip
+///   error code                  rbp + 8
+///   saved rbp                   rbp
+///   saved caller-saved registers
+///   SSE state                   rsp, aligned to 16
+/// ```
+pub fn interrupt(instance: &MonoFunction) -> MFunction {
+    let symbol = instance.symbol.clone();
+    let attrs = &instance.function.attrs;
+    let error_code = !instance.function.params().is_empty();
+    let line = |text: &str| Inst::Asm {
+        lines: vec![text.to_string()],
+        uses: Vec::new(),
+        defs: Vec::new(),
+        clobbers: Vec::new(),
+    };
+    let mut insts = vec![Inst::Push(Gpr::Rbp), Inst::mov(Size::Q, Gpr::Rbp, Gpr::Rsp)];
+    insts.extend(INTERRUPT_SAVED.iter().map(|&gpr| Inst::Push(gpr)));
+    insts.push(Inst::alu(AluOp::And, Size::Q, Gpr::Rsp, Operand::Imm(-16)));
+    insts.push(Inst::alu(
+        AluOp::Sub,
+        Size::Q,
+        Gpr::Rsp,
+        Operand::Imm(FXSAVE_SIZE),
+    ));
+    insts.push(line("fxsave64 [rsp]"));
+    // The body may rely on the direction flag being clear, as the System V
+    // ABI says it is on entry.
+    insts.push(line("cld"));
+    let mut args = Vec::new();
+    if error_code {
+        insts.push(Inst::mov(Size::Q, Gpr::Rdi, Mem::reg(Gpr::Rbp, 8)));
+        args.push(Reg::Gpr(Gpr::Rdi));
+    }
+    insts.push(Inst::Call {
+        target: CallTarget::Symbol(interrupt_body(&symbol)),
+        args,
+        results: Vec::new(),
+    });
+    insts.push(line("fxrstor64 [rsp]"));
+    insts.push(Inst::Lea {
+        dst: Reg::Gpr(Gpr::Rsp),
+        src: Mem::reg(Gpr::Rbp, -8 * INTERRUPT_SAVED.len() as i64),
+    });
+    insts.extend(INTERRUPT_SAVED.iter().rev().map(|&gpr| Inst::Pop(gpr)));
+    insts.push(Inst::Pop(Gpr::Rbp));
+    if error_code {
+        insts.push(Inst::alu(AluOp::Add, Size::Q, Gpr::Rsp, Operand::Imm(8)));
+    }
+    insts.push(line("iretq"));
+    MFunction {
+        blocks: vec![MBlock {
+            label: format!(".L{}", symbol),
+            insts,
+        }],
+        symbol,
+        global: true,
+        slots: Vec::new(),
+        outgoing: 0,
+        vregs: 0,
+        frame_size: 0,
+        saved: Vec::new(),
+        section: attrs.section.clone(),
+        align: attrs.align,
+        naked: true,
+    }
+}
+
+/// The C entry point: runs the initializer of every module, dependencies
+/// first, and returns 0.
+pub fn entry(inits: &[String]) -> MFunction {
+    let mut insts: Vec<Inst> = inits
+        .iter()
+        .map(|symbol| Inst::Call {
+            target: CallTarget::Symbol(symbol.clone()),
+            args: Vec::new(),
+            results: Vec::new(),
+        })
+        .collect();
+    insts.push(Inst::mov(Size::D, Gpr::Rax, Operand::Imm(0)));
+    insts.push(Inst::Ret {
+        results: vec![Reg::Gpr(Gpr::Rax)],
+    });
+    MFunction {
+        symbol: "main".to_string(),
+        global: true,
+        blocks: vec![MBlock {
+            label: ".Lmain".to_string(),
+            insts,
+        }],
+        slots: Vec::new(),
+        outgoing: 0,
+        vregs: 0,
+        frame_size: 0,
+        saved: Vec::new(),
+        section: None,
+        align: None,
+        naked: false,
+    }
+}
+
+/// The entry of a freestanding program, `_start`: runs the initializer of
+/// every module, dependencies first, then calls `@start` with the arguments
+/// `_start` got, and halts if it returns.
+pub fn start(inits: &[String]) -> MFunction {
+    let (magic, info) = (Reg::Virt(0, RegClass::Int), Reg::Virt(1, RegClass::Int));
+    let mut insts = vec![
+        Inst::mov(Size::Q, magic, Gpr::Rdi),
+        Inst::mov(Size::Q, info, Gpr::Rsi),
+    ];
+    insts.extend(inits.iter().map(|symbol| Inst::Call {
+        target: CallTarget::Symbol(symbol.clone()),
+        args: Vec::new(),
+        results: Vec::new(),
+    }));
+    insts.push(Inst::mov(Size::Q, Gpr::Rdi, magic));
+    insts.push(Inst::mov(Size::Q, Gpr::Rsi, info));
+    insts.push(Inst::Call {
+        target: CallTarget::Symbol(mono::symbol(target::ENTRY, &[])),
+        args: vec![Reg::Gpr(Gpr::Rdi), Reg::Gpr(Gpr::Rsi)],
+        results: Vec::new(),
+    });
+    let halt = ".L_start_halt".to_string();
+    MFunction {
+        symbol: super::boot::CALLS.to_string(),
+        global: true,
+        blocks: vec![
+            MBlock {
+                label: ".L_start".to_string(),
+                insts,
+            },
+            MBlock {
+                label: halt.clone(),
+                insts: vec![halt_inst(), Inst::Jmp(halt)],
+            },
+        ],
+        slots: Vec::new(),
+        outgoing: 0,
+        vregs: 2,
+        frame_size: 0,
+        saved: Vec::new(),
+        section: None,
+        align: None,
+        naked: false,
+    }
+}
+
+/// Stops the processor until an interrupt, with interrupts disabled.
+fn halt_inst() -> Inst {
+    Inst::Asm {
+        lines: vec!["cli".to_string(), "hlt".to_string()],
+        uses: Vec::new(),
+        defs: Vec::new(),
+        clobbers: Vec::new(),
+    }
+}
+
+fn label(function: usize, block: usize) -> String {
+    format!(".L{}_{}", function, block)
+}
+
+fn trap_label(function: usize, trap: usize) -> String {
+    format!(".L{}_trap{}", function, trap)
+}
+
+struct Isel<'a> {
+    program: &'a Program,
+    function: &'a Function,
+    target: target::Target,
+    /// The generic arguments of the instance, which its closures share.
+    args: &'a [Ty],
+    data: &'a mut Data,
+    index: usize,
+    blocks: Vec<MBlock>,
+    /// The block being filled.
+    label: String,
+    insts: Vec<Inst>,
+    slots: Vec<(u64, u64)>,
+    local_slots: Vec<u32>,
+    values: Vec<Option<Reg>>,
+    /// The slots of compound block parameters.
+    param_slots: HashMap<Reg, u32>,
+    vregs: u32,
+    outgoing: u64,
+    /// Where to write a compound result.
+    ret_ptr: Option<Reg>,
+    /// The message of each runtime error some check jumps to.
+    traps: Vec<String>,
+    /// The MIR block being selected, and how many times it was split.
+    block: usize,
+    splits: usize,
+}
+
+impl Isel<'_> {
+    fn emit(&mut self, inst: Inst) {
+        self.insts.push(inst);
+    }
+
+    fn start_block(&mut self, label: String) {
+        let label = std::mem::replace(&mut self.label, label);
+        let insts = std::mem::take(&mut self.insts);
+        self.blocks.push(MBlock { label, insts });
+    }
+
+    fn vreg(&mut self, class: RegClass) -> Reg {
+        self.vregs += 1;
+        Reg::Virt(self.vregs - 1, class)
+    }
+
+    fn slot(&mut self, layout: Layout) -> u32 {
+        self.slots.push((layout.size, layout.align));
+        self.slots.len() as u32 - 1
+    }
+
+    fn layout(&self, ty: &Ty) -> Sel<Layout> {
+        layout::layout(self.program, ty)
+    }
+
+    fn kind(&self, ty: &Ty) -> Sel<Kind> {
+        Ok(match ty {
+            // References to protocol objects are pairs, kept in memory.
+            Ty::Ref { inner, .. } | Ty::RawRef { inner, .. } if matches!(**inner, Ty::Dyn(_)) => {
+                Kind::Memory(self.layout(ty)?)
+            }
+            Ty::Int | Ty::Ref { .. } | Ty::RawRef { .. } => {
+                self.layout(ty)?;
+                Kind::Int(Size::Q)
+            }
+            Ty::Char => Kind::Int(Size::D),
+            Ty::Byte | Ty::Bool => Kind::Int(Size::B),
+            Ty::Float => Kind::Float,
+            _ => match self.layout(ty)? {
+                Layout { size: 0, .. } => Kind::Zero,
+                layout => Kind::Memory(layout),
+            },
+        })
+    }
+
+    /// How a value of type `ty` is passed under `abi`.
+    fn pass(&self, ty: &Ty, abi: Abi) -> Sel<Pass> {
+        let kind = self.kind(ty)?;
+        let (Abi::C { .. }, Kind::Memory(layout)) = (abi, kind) else {
+            return Ok(Pass::Reg(kind));
+        };
+        if layout.size > 16 {
+            return Ok(Pass::Memory(layout));
+        }
+        let mut classes = vec![None; layout.size.div_ceil(8) as usize];
+        if !self.classify(ty, 0, &mut classes)? {
+            return Ok(Pass::Memory(layout));
+        }
+        let eightbytes = classes
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, class)| Some((8 * i as u64, class?)))
+            .collect();
+        Ok(Pass::Eightbytes(layout, eightbytes))
+    }
+
+    /// Records in `classes` the register class of the eightbytes holding
+    /// the scalars of a `ty` at `offset`: `float` if they hold only
+    /// `float`s. Returns false if a scalar is misaligned, which makes C
+    /// pass the whole value in memory.
+    fn classify(&self, ty: &Ty, offset: u64, classes: &mut [Option<RegClass>]) -> Sel<bool> {
+        if let Ty::Adt { .. } | Ty::Tuple(_) = ty {
+            for field in layout::fields(self.program, ty, None)? {
+                if !self.classify(&field.ty, offset + field.offset, classes)? {
+                    return Ok(false);
+                }
+            }
+            return Ok(true);
+        }
+        let layout = self.layout(ty)?;
+        if layout.size == 0 {
+            return Ok(true);
+        }
+        if !offset.is_multiple_of(layout.align) {
+            return Ok(false);
+        }
+        let class = &mut classes[(offset / 8) as usize];
+        *class = match (*class, ty) {
+            (None | Some(RegClass::Float), Ty::Float) => Some(RegClass::Float),
+            _ => Some(RegClass::Int),
+        };
+        Ok(true)
+    }
+
+    /// The convention the function being selected is called with.
+    fn abi(&self) -> Abi {
+        match self.function.attrs.c_abi {
+            true => Abi::C { variadic: false },
+            false => Abi::Enigma,
+        }
+    }
+
+    /// A slot `layout` fits in, rounded up to whole eightbytes so they can
+    /// be moved to and from registers.
+    fn eightbyte_slot(&mut self, layout: Layout) -> Mem {
+        Mem::slot(self.slot(Layout {
+            size: layout::align_to(layout.size, 8),
+            align: layout.align.max(8),
+        }))
+    }
+
+    fn value(&self, id: ValueId) -> Option<Reg> {
+        self.values[id.0 as usize]
+    }
+
+    /// Stops with the runtime error `message` if `cond` holds.
+    fn trap_if(&mut self, cond: Cond, message: String) {
+        let trap = intern(&mut self.traps, message);
+        self.emit(Inst::Jcc(cond, trap_label(self.index, trap)));
+    }
+
+    /// Reports a runtime error as the interpreter does, without the
+    /// source location, and exits with 101. Freestanding programs pass the
+    /// message to their panic handler instead.
+    fn runtime_error(&mut self, message: &str) {
+        if self.target == target::Target::Freestanding {
+            let text = self.data.string(message);
+            let text = self.address(text);
+            self.panic(text);
+            return;
+        }
+        let text = self.data.cstring(&format!("error: {}\n", message));
+        let text = self.address(text);
+        let dprintf = self.data.external("dprintf");
+        self.call_c(dprintf, &[Operand::Imm(2), text.into()], None, true);
+        let exit = self.data.external("exit");
+        self.call_c(exit, &[Operand::Imm(101)], None, false);
+        self.emit(Inst::Ud2);
+    }
+
+    /// Calls the panic handler with the `string` at `message`, and halts
+    /// for good if it returns.
+    fn panic(&mut self, message: Reg) {
+        let handler = CallTarget::Symbol(mono::symbol(target::PANIC, &[]));
+        self.call_c(handler, &[message.into()], None, false);
+        let halt = self.split_label();
+        self.start_block(halt.clone());
+        self.emit(halt_inst());
+        self.emit(Inst::Jmp(halt));
+    }
+
+    /// A label for splitting the current block.
+    fn split_label(&mut self) -> String {
+        self.splits += 1;
+        format!("{}_{}", label(self.index, self.block), self.splits)
+    }
+
+    // -----------------------------------------------------------------
+    // Operands
+    // -----------------------------------------------------------------
+
+    /// The operand as an immediate if it is a small constant, or else in
+    /// a register; `None` if it has no size.
+    fn operand(&mut self, operand: &mir::Operand) -> Option<Operand> {
+        let constant = match operand {
+            mir::Operand::Value(id) => return self.value(*id).map(Operand::Reg),
+            mir::Operand::Const(constant) => constant,
+        };
+        let imm = match constant {
+            Const::Int(n) => *n,
+            Const::Byte(b) => *b as i64,
+            Const::Bool(b) => *b as i64,
+            Const::Char(c) => *c as i64,
+            Const::Unit => return None,
+            Const::Float(x) => {
+                let reg = self.vreg(RegClass::Float);
+                let label = self.data.float(*x);
+                self.emit(Inst::Sse {
+                    op: SseOp::Movsd,
+                    dst: reg.into(),
+                    src: Mem::symbol(label).into(),
+                });
+                return Some(reg.into());
+            }
+            Const::Str(s) => {
+                let reg = self.vreg(RegClass::Int);
+                let label = self.data.string(s);
+                self.emit(Inst::Lea {
+                    dst: reg,
+                    src: Mem::symbol(label),
+                });
+                return Some(reg.into());
+            }
+        };
+        Some(Operand::Imm(imm))
+    }
+
+    /// The operand as an immediate that fits in 32 bits, or in a register.
+    fn small(&mut self, operand: &mir::Operand) -> Operand {
+        match self.operand(operand) {
+            Some(Operand::Imm(n)) if i32::try_from(n).is_err() => {
+                let reg = self.vreg(RegClass::Int);
+                self.emit(Inst::mov(Size::Q, reg, Operand::Imm(n)));
+                reg.into()
+            }
+            Some(operand) => operand,
+            None => Operand::Imm(0),
+        }
+    }
+
+    /// The operand in a register.
+    fn reg(&mut self, operand: &mir::Operand) -> Reg {
+        match self.operand(operand) {
+            Some(Operand::Reg(reg)) => reg,
+            Some(Operand::Imm(n)) => {
+                let reg = self.vreg(RegClass::Int);
+                self.emit(Inst::mov(Size::Q, reg, Operand::Imm(n)));
+                reg
+            }
+            _ => {
+                let reg = self.vreg(RegClass::Int);
+                self.emit(Inst::mov(Size::D, reg, Operand::Imm(0)));
+                reg
+            }
+        }
+    }
+
+    /// A fresh register holding a copy of the operand, to compute into.
+    fn copy_to_reg(&mut self, operand: &mir::Operand, class: RegClass) -> Reg {
+        let reg = self.vreg(class);
+        match class {
+            RegClass::Int => {
+                let src = self.operand(operand).unwrap_or(Operand::Imm(0));
+                self.emit(Inst::mov(Size::Q, reg, src));
+            }
+            RegClass::Float => {
+                let src = self.reg(operand);
+                self.emit(Inst::Sse {
+                    op: SseOp::Movsd,
+                    dst: reg.into(),
+                    src: src.into(),
+                });
+            }
+        }
+        reg
+    }
+
+    // -----------------------------------------------------------------
+    // Memory
+    // -----------------------------------------------------------------
+
+    /// The address of a place and its type, and its bits if it is a
+    /// bitfield.
+    fn place(&mut self, place: &mir::Place) -> Sel<(Mem, Ty, Option<Bits>)> {
+        let (mut mem, mut ty) = match &place.base {
+            PlaceBase::Local(local) => (
+                Mem::slot(self.local_slots[local.0 as usize]),
+                self.function.local(*local).ty.clone(),
+            ),
+            PlaceBase::Global(name) => {
+                let ty = self
+                    .program
+                    .globals
+                    .iter()
+                    .find(|(global, _)| global == name)
+                    .map(|(_, ty)| ty.clone())
+                    .ok_or_else(|| format!("globals without a type like `{}`", name))?;
+                (Mem::symbol(mono::symbol(name, &[])), ty)
+            }
+        };
+        let mut variant = None;
+        let mut bits = None;
+        for projection in &place.projection {
+            match *projection {
+                Projection::Deref => {
+                    let ptr = self.vreg(RegClass::Int);
+                    self.emit(Inst::mov(Size::Q, ptr, mem));
+                    mem = Mem::reg(ptr, 0);
+                    ty = match ty {
+                        Ty::Ref { inner, .. } | Ty::RawRef { inner, .. } => *inner,
+                        other => return Err(format!("dereferences of `{}` values", other)),
+                    };
+                }
+                Projection::Field(index) => {
+                    let fields = layout::fields(self.program, &ty, variant.take())?;
+                    let field = fields[index].clone();
+                    mem = mem.offset(field.offset);
+                    ty = field.ty;
+                    bits = field.bits;
+                }
+                Projection::Downcast(index) => variant = Some(index),
+            }
+        }
+        Ok((mem, ty, bits))
+    }
+
+    /// Reads a value of type `ty` from memory into `dst`.
+    fn load(&mut self, dst: Option<Reg>, mem: Mem, ty: &Ty) -> Sel {
+        let Some(dst) = dst else {
+            return Ok(());
+        };
+        match self.kind(ty)? {
+            Kind::Zero => {}
+            Kind::Int(Size::Q) => self.emit(Inst::mov(Size::Q, dst, mem)),
+            Kind::Int(size) => self.emit(Inst::MovZx {
+                size,
+                dst,
+                src: mem.into(),
+            }),
+            Kind::Float => self.emit(Inst::Sse {
+                op: SseOp::Movsd,
+                dst: dst.into(),
+                src: mem.into(),
+            }),
+            Kind::Memory(layout) => {
+                let slot = self.slot(layout);
+                self.copy(Mem::slot(slot), mem, layout.size);
+                self.emit(Inst::Lea {
+                    dst,
+                    src: Mem::slot(slot),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a value of type `ty` to memory.
+    fn store(&mut self, mem: Mem, ty: &Ty, value: &mir::Operand) -> Sel {
+        match self.kind(ty)? {
+            Kind::Zero => {}
+            Kind::Int(size) => {
+                let src = match self.small(value) {
+                    Operand::Imm(n) if size == Size::B => Operand::Imm(n as u8 as i8 as i64),
+                    src => src,
+                };
+                self.emit(Inst::mov(size, mem, src));
+            }
+            Kind::Float => {
+                let src = self.reg(value);
+                self.emit(Inst::Sse {
+                    op: SseOp::Movsd,
+                    dst: mem.into(),
+                    src: src.into(),
+                });
+            }
+            Kind::Memory(layout) => {
+                let src = self.reg(value);
+                self.copy(mem, Mem::reg(src, 0), layout.size);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the bitfield `bits` of the unit of type `ty` at `mem`: the
+    /// shifts leave its bits at the bottom of `dst`, zero-extended.
+    fn load_bits(&mut self, dst: Option<Reg>, mem: Mem, ty: &Ty, bits: Bits) -> Sel {
+        let Some(dst) = dst else {
+            return Ok(());
+        };
+        self.load(Some(dst), mem, ty)?;
+        self.shift(ShiftOp::Shl, dst, 64 - bits.shift - bits.width);
+        self.shift(ShiftOp::Shr, dst, 64 - bits.width);
+        Ok(())
+    }
+
+    /// Writes the low bits of `value` to the bitfield `bits` of the unit of
+    /// type `ty` at `mem`, keeping the unit's other bits.
+    fn store_bits(&mut self, mem: Mem, ty: &Ty, bits: Bits, value: &mir::Operand) -> Sel {
+        let Kind::Int(size) = self.kind(ty)? else {
+            return Err(format!("bitfields of type `{}`", ty));
+        };
+        if bits.width as u64 == size.bytes() * 8 {
+            return self.store(mem, ty, value);
+        }
+        let field = self.copy_to_reg(value, RegClass::Int);
+        self.shift(ShiftOp::Shl, field, 64 - bits.width);
+        self.shift(ShiftOp::Shr, field, 64 - bits.width - bits.shift);
+        let unit = self.vreg(RegClass::Int);
+        self.load(Some(unit), mem.clone(), ty)?;
+        let mask = ((1u64 << bits.width) - 1) << bits.shift;
+        let keep = self.vreg(RegClass::Int);
+        self.emit(Inst::mov(Size::Q, keep, Operand::Imm(!mask as i64)));
+        self.emit(Inst::alu(AluOp::And, Size::Q, unit, keep));
+        self.emit(Inst::alu(AluOp::Or, Size::Q, unit, field));
+        self.emit(Inst::mov(size, mem, unit));
+        Ok(())
+    }
+
+    fn shift(&mut self, op: ShiftOp, dst: Reg, amount: u32) {
+        if amount > 0 {
+            self.emit(Inst::Shift {
+                op,
+                size: Size::Q,
+                dst: dst.into(),
+                amount: amount as u8,
+            });
+        }
+    }
+
+    /// Copies `size` bytes, eight at a time while that many are left.
+    fn copy(&mut self, dst: Mem, src: Mem, size: u64) {
+        let mut offset = 0;
+        while offset < size {
+            let chunk = [8, 4, 2, 1]
+                .into_iter()
+                .find(|&chunk| chunk <= size - offset)
+                .unwrap();
+            let chunk = Size::of(chunk);
+            let temp = self.vreg(RegClass::Int);
+            self.emit(Inst::mov(chunk, temp, src.offset(offset)));
+            self.emit(Inst::mov(chunk, dst.offset(offset), temp));
+            offset += chunk.bytes();
+        }
+    }
+
+    /// A new slot of `ty`'s layout, and its address in a register.
+    fn temp(&mut self, ty: &Ty) -> Sel<(Mem, Reg)> {
+        let layout = self.layout(ty)?;
+        let slot = self.slot(layout);
+        let reg = self.vreg(RegClass::Int);
+        self.emit(Inst::Lea {
+            dst: reg,
+            src: Mem::slot(slot),
+        });
+        Ok((Mem::slot(slot), reg))
+    }
+
+    // -----------------------------------------------------------------
+    // Instructions
+    // -----------------------------------------------------------------
+
+    fn result_ty(&self, result: Option<ValueId>) -> Ty {
+        result.map_or(Ty::Unit, |id| self.function.value_ty(id).clone())
+    }
+
+    fn inst(&mut self, result: Option<ValueId>, kind: &InstKind) -> Sel {
+        let dst = result.and_then(|id| self.value(id));
+        match kind {
+            InstKind::Unary { op, operand } => {
+                let ty = self.function.operand_ty(operand);
+                self.unary(dst, *op, operand, &ty)?;
+            }
+            InstKind::Binary { op, lhs, rhs } => {
+                let ty = self.function.operand_ty(lhs);
+                self.binary(dst, *op, lhs, rhs, &ty)?;
+            }
+            // A move leaves the place as it was; nothing is freed yet, so
+            // there is nothing to prevent.
+            InstKind::Copy(place) | InstKind::Move(place) => match self.place(place)? {
+                (mem, ty, Some(bits)) => self.load_bits(dst, mem, &ty, bits)?,
+                (mem, ty, None) => self.load(dst, mem, &ty)?,
+            },
+            InstKind::Store { place, value } => match self.place(place)? {
+                (mem, ty, Some(bits)) => self.store_bits(mem, &ty, bits, value)?,
+                (mem, ty, None) => self.store(mem, &ty, value)?,
+            },
+            InstKind::Ref { place, .. } | InstKind::RawRef { place, .. } => {
+                let (mem, _, _) = self.place(place)?;
+                if let Some(dst) = dst {
+                    self.emit(Inst::Lea { dst, src: mem });
+                }
+            }
+            InstKind::Aggregate { kind, fields } => {
+                let ty = self.result_ty(result);
+                self.aggregate(dst, &ty, kind, fields)?;
+            }
+            InstKind::Discriminant(place) => {
+                let (mem, _, _) = self.place(place)?;
+                if let Some(dst) = dst {
+                    self.emit(Inst::MovZx {
+                        size: Size::of(layout::TAG_SIZE),
+                        dst,
+                        src: mem.into(),
+                    });
+                }
+            }
+            InstKind::Call { callee, args } => {
+                let ret = self.result_ty(result);
+                self.call(dst, callee, args, &ret)?;
+            }
+            InstKind::FnItem { func, args } => {
+                let (target, args) = mono::find(self.program, func, args.clone())?;
+                let code = mono::thunk_symbol(&mono::function_symbol(target, &args));
+                self.fn_value(dst, code, None);
+            }
+            InstKind::VariantCtor { variant, .. } => {
+                let Ty::Fn { ret, .. } = self.result_ty(result) else {
+                    return Err("constructors without a function type".to_string());
+                };
+                self.fn_value(dst, mono::ctor_symbol(&ret, *variant), None);
+            }
+            InstKind::Closure { function, captures } => {
+                let captures_ty = Ty::Tuple(
+                    captures
+                        .iter()
+                        .map(|capture| self.function.operand_ty(capture))
+                        .collect(),
+                );
+                let env = self.vreg(RegClass::Int);
+                self.aggregate(Some(env), &captures_ty, &AggregateKind::Tuple, captures)?;
+                let code = mono::symbol(function, self.args);
+                self.fn_value(dst, code, Some(env));
+            }
+            InstKind::ToDyn { value, protocol } => {
+                let ty = match self.function.operand_ty(value) {
+                    Ty::Ref { inner, .. } | Ty::RawRef { inner, .. } => *inner,
+                    other => return Err(format!("protocol objects made from `{}`", other)),
+                };
+                let vtables = &self.program.mono.vtables;
+                if !vtables
+                    .iter()
+                    .any(|vtable| vtable.ty == ty && vtable.protocol == *protocol)
+                {
+                    return Err(format!(
+                        "`ref {}` objects of uncollected `{}`",
+                        protocol, ty
+                    ));
+                }
+                let data = self.reg(value);
+                if let Some(dst) = dst {
+                    let slot = Mem::slot(self.slot(Layout { size: 16, align: 8 }));
+                    self.emit(Inst::mov(Size::Q, slot.clone(), data));
+                    let vtable = self.vreg(RegClass::Int);
+                    self.emit(Inst::Lea {
+                        dst: vtable,
+                        src: Mem::symbol(mono::vtable_symbol(&ty, protocol)),
+                    });
+                    self.emit(Inst::mov(Size::Q, slot.offset(8), vtable));
+                    self.emit(Inst::Lea { dst, src: slot });
+                }
+            }
+            // Values are never freed yet.
+            InstKind::Drop(_) => {}
+            InstKind::Asm(asm) => {
+                let ty = self.result_ty(result);
+                self.asm(dst, &ty, asm)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn aggregate(
+        &mut self,
+        dst: Option<Reg>,
+        ty: &Ty,
+        kind: &AggregateKind,
+        fields: &[mir::Operand],
+    ) -> Sel {
+        let Some(dst) = dst else {
+            return Ok(());
+        };
+        let layout = self.layout(ty)?;
+        let slot = Mem::slot(self.slot(layout));
+        let variant = match kind {
+            AggregateKind::Variant { variant, .. } => {
+                self.emit(Inst::mov(
+                    Size::of(layout::TAG_SIZE),
+                    slot.clone(),
+                    Operand::Imm(*variant as i64),
+                ));
+                Some(*variant)
+            }
+            _ => None,
+        };
+        let layouts = layout::fields(self.program, ty, variant)?;
+        // Bitfields are written into units that start out zero.
+        for field in &layouts {
+            if let (Some(bits), Kind::Int(size)) = (field.bits, self.kind(&field.ty)?)
+                && bits.shift == 0
+            {
+                self.emit(Inst::mov(size, slot.offset(field.offset), Operand::Imm(0)));
+            }
+        }
+        for (layout, field) in layouts.iter().zip(fields) {
+            let mem = slot.offset(layout.offset);
+            match layout.bits {
+                Some(bits) => self.store_bits(mem, &layout.ty, bits, field)?,
+                None => self.store(mem, &layout.ty, field)?,
+            }
+        }
+        self.emit(Inst::Lea { dst, src: slot });
+        Ok(())
+    }
+
+    /// Moves the inputs into their registers, writes the registers into
+    /// the template and moves the outputs out. Assembly without operands
+    /// or clobbers may overwrite any register.
+    fn asm(&mut self, dst: Option<Reg>, ty: &Ty, asm: &mir::Asm) -> Sel {
+        let physical = |name: &str| match PhysReg::parse(name) {
+            Some(PhysReg::Gpr(n)) => Some(Reg::Gpr(Gpr::ALL[n as usize])),
+            Some(PhysReg::Xmm(n)) => Some(Reg::Xmm(n)),
+            None => None,
+        };
+        let mut clobbers: Vec<Reg> = asm.clobbers.iter().filter_map(|c| physical(c)).collect();
+        if asm.operands.is_empty() && asm.clobbers.is_empty() {
+            clobbers.extend(
+                ASM_GPRS
+                    .iter()
+                    .chain(&SCRATCH_GPRS)
+                    .map(|&gpr| Reg::Gpr(gpr)),
+            );
+            clobbers.extend((0..16).map(Reg::Xmm));
+        }
+        // Explicit registers first, then the first free one for the rest;
+        // the scratch registers are left for loading spilled values.
+        let mut taken = clobbers.clone();
+        taken.extend(
+            asm.operands
+                .iter()
+                .filter_map(|operand| match &operand.reg {
+                    AsmReg::Explicit(name) => physical(name),
+                    _ => None,
+                }),
+        );
+        let mut regs = Vec::new();
+        for operand in &asm.operands {
+            let free = match &operand.reg {
+                AsmReg::Explicit(name) => physical(name),
+                AsmReg::Gpr => ASM_GPRS
+                    .iter()
+                    .map(|&gpr| Reg::Gpr(gpr))
+                    .find(|reg| !taken.contains(reg)),
+                AsmReg::Xmm => (0..14).map(Reg::Xmm).find(|reg| !taken.contains(reg)),
+            };
+            let reg = free.ok_or("`asm` blocks needing more registers than are free")?;
+            taken.push(reg);
+            regs.push(reg);
+        }
+
+        let output_tys: Vec<Ty> = match (ty, asm.operands.iter().filter(|o| o.dir.writes()).count())
+        {
+            (_, 0) => Vec::new(),
+            (Ty::Tuple(elems), n) if n > 1 => elems.clone(),
+            (ty, _) => vec![ty.clone()],
+        };
+        let mut kinds = Vec::new();
+        let mut output_tys = output_tys.into_iter();
+        for operand in &asm.operands {
+            let ty = match &operand.input {
+                Some(input) => self.function.operand_ty(input),
+                None => output_tys.next().unwrap_or(Ty::Int),
+            };
+            kinds.push(self.kind(&ty)?);
+        }
+
+        // Loading a spilled value goes through `r10`, then `r11`, so inputs
+        // in those are set last.
+        let scratch_rank = |reg: &Reg| match reg {
+            Reg::Gpr(Gpr::R11) | Reg::Xmm(15) => 1,
+            Reg::Gpr(Gpr::R10) | Reg::Xmm(14) => 2,
+            _ => 0,
+        };
+        let mut inputs: Vec<usize> = (0..asm.operands.len())
+            .filter(|&i| asm.operands[i].input.is_some())
+            .collect();
+        inputs.sort_by_key(|&i| scratch_rank(&regs[i]));
+        for &i in &inputs {
+            let input = asm.operands[i].input.as_ref().unwrap();
+            match regs[i] {
+                reg @ Reg::Xmm(_) => {
+                    let src = self.reg(input);
+                    self.emit(Inst::Sse {
+                        op: SseOp::Movsd,
+                        dst: reg.into(),
+                        src: src.into(),
+                    });
+                }
+                reg => {
+                    let src = self.operand(input).unwrap_or(Operand::Imm(0));
+                    self.emit(Inst::mov(Size::Q, reg, src));
+                }
+            }
+        }
+
+        let lines = asm
+            .template
+            .iter()
+            .map(|line| {
+                line.iter()
+                    .map(|piece| match piece {
+                        AsmPiece::Text(text) => text.clone(),
+                        AsmPiece::Operand {
+                            operand, modifier, ..
+                        } => {
+                            let i = match operand {
+                                AsmRef::Index(i) => *i,
+                                AsmRef::Name(_) => unreachable!("names are resolved in the HIR"),
+                            };
+                            let size = match (modifier, kinds[i]) {
+                                (Some('b'), _) => Size::B,
+                                (Some('w'), _) => Size::W,
+                                (Some('d'), _) => Size::D,
+                                (Some('q'), _) => Size::Q,
+                                (_, Kind::Int(size)) => size,
+                                _ => Size::Q,
+                            };
+                            match regs[i] {
+                                Reg::Gpr(gpr) => gpr.name(size).to_string(),
+                                Reg::Xmm(n) => format!("xmm{}", n),
+                                Reg::Virt(..) => unreachable!(),
+                            }
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        let outputs: Vec<usize> = (0..asm.operands.len())
+            .filter(|&i| asm.operands[i].dir.writes())
+            .collect();
+        self.emit(Inst::Asm {
+            lines,
+            uses: inputs.iter().map(|&i| regs[i]).collect(),
+            defs: outputs.iter().map(|&i| regs[i]).collect(),
+            clobbers,
+        });
+        let Some(dst) = dst else {
+            return Ok(());
+        };
+
+        // Storing a spilled value goes through `r10`, so outputs in the
+        // scratch registers are read first.
+        let mut order: Vec<usize> = (0..outputs.len()).collect();
+        order.sort_by_key(|&o| std::cmp::Reverse(scratch_rank(&regs[outputs[o]])));
+        let mut values = vec![dst; outputs.len()];
+        for o in order {
+            let i = outputs[o];
+            let value = if outputs.len() == 1 {
+                dst
+            } else {
+                self.vreg(regs[i].class())
+            };
+            match kinds[i] {
+                Kind::Float => self.emit(Inst::Sse {
+                    op: SseOp::Movsd,
+                    dst: value.into(),
+                    src: regs[i].into(),
+                }),
+                Kind::Int(Size::Q) => self.emit(Inst::mov(Size::Q, value, regs[i])),
+                Kind::Int(size) => self.emit(Inst::MovZx {
+                    size,
+                    dst: value,
+                    src: regs[i].into(),
+                }),
+                _ => {}
+            }
+            values[o] = value;
+        }
+        if outputs.len() > 1 {
+            let layout = self.layout(ty)?;
+            let slot = Mem::slot(self.slot(layout));
+            let fields = layout::fields(self.program, ty, None)?;
+            for (o, field) in fields.iter().enumerate() {
+                let i = outputs[o];
+                let mem = slot.offset(field.offset);
+                match kinds[i] {
+                    Kind::Float => self.emit(Inst::Sse {
+                        op: SseOp::Movsd,
+                        dst: mem.into(),
+                        src: values[o].into(),
+                    }),
+                    Kind::Int(size) => self.emit(Inst::mov(size, mem, values[o])),
+                    _ => {}
+                }
+            }
+            self.emit(Inst::Lea { dst, src: slot });
+        }
+        Ok(())
+    }
+
+    /// A function value: the address of `code` and of the closure's
+    /// environment, if it has one.
+    fn fn_value(&mut self, dst: Option<Reg>, code: String, env: Option<Reg>) {
+        let Some(dst) = dst else {
+            return;
+        };
+        let slot = Mem::slot(self.slot(Layout { size: 16, align: 8 }));
+        let address = self.vreg(RegClass::Int);
+        self.emit(Inst::Lea {
+            dst: address,
+            src: Mem::symbol(code),
+        });
+        self.emit(Inst::mov(Size::Q, slot.clone(), address));
+        let env = env.map_or(Operand::Imm(0), Operand::Reg);
+        self.emit(Inst::mov(Size::Q, slot.offset(8), env));
+        self.emit(Inst::Lea { dst, src: slot });
+    }
+
+    fn unary(&mut self, dst: Option<Reg>, op: UnaryOp, operand: &mir::Operand, ty: &Ty) -> Sel {
+        let Some(dst) = dst else {
+            return Ok(());
+        };
+        match (op, ty) {
+            (UnaryOp::Neg, Ty::Float) => {
+                let src = self.reg(operand);
+                self.emit(Inst::Sse {
+                    op: SseOp::Movsd,
+                    dst: dst.into(),
+                    src: src.into(),
+                });
+                self.data.sign_mask = true;
+                self.emit(Inst::Sse {
+                    op: SseOp::Xorpd,
+                    dst: dst.into(),
+                    src: Mem::symbol(Data::SIGN_MASK).into(),
+                });
+            }
+            (UnaryOp::Neg, Ty::Int) => {
+                let src = self.operand(operand).unwrap_or(Operand::Imm(0));
+                self.emit(Inst::mov(Size::Q, dst, src));
+                self.emit(Inst::Neg {
+                    size: Size::Q,
+                    dst: dst.into(),
+                });
+                self.trap_if(Cond::O, "attempt to negate with overflow".to_string());
+            }
+            (UnaryOp::Not, Ty::Bool) => {
+                let src = self.operand(operand).unwrap_or(Operand::Imm(0));
+                self.emit(Inst::mov(Size::Q, dst, src));
+                self.emit(Inst::alu(AluOp::Xor, Size::D, dst, Operand::Imm(1)));
+            }
+            (UnaryOp::Not, Ty::Int | Ty::Byte) => {
+                let src = self.operand(operand).unwrap_or(Operand::Imm(0));
+                self.emit(Inst::mov(Size::Q, dst, src));
+                let size = if *ty == Ty::Int { Size::Q } else { Size::B };
+                self.emit(Inst::Not {
+                    size,
+                    dst: dst.into(),
+                });
+            }
+            _ => return Err(format!("`{}` on `{}` values", op_symbol(op), ty)),
+        }
+        Ok(())
+    }
+
+    fn binary(
+        &mut self,
+        dst: Option<Reg>,
+        op: BinOp,
+        lhs: &mir::Operand,
+        rhs: &mir::Operand,
+        ty: &Ty,
+    ) -> Sel {
+        let Some(dst) = dst else {
+            // Only operations that can't fail are left without a result.
+            return Ok(());
+        };
+        match ty {
+            Ty::Float => self.float_binary(dst, op, lhs, rhs),
+            Ty::Str => self.string_binary(dst, op, lhs, rhs),
+            Ty::Unit => {
+                let equal = matches!(op, BinOp::Eq | BinOp::Le | BinOp::Ge);
+                self.emit(Inst::mov(Size::Q, dst, Operand::Imm(equal as i64)));
+                Ok(())
+            }
+            Ty::Int | Ty::Byte | Ty::Bool | Ty::Char => {
+                self.int_binary(dst, op, lhs, rhs, *ty == Ty::Int)
+            }
+            _ => Err(format!("`{}` on `{}` values", op.symbol(), ty)),
+        }
+    }
+
+    /// Integer arithmetic, which traps on overflow. `int` is signed and
+    /// everything else unsigned; narrower values are computed on their
+    /// zero-extension and checked against the type's range.
+    fn int_binary(
+        &mut self,
+        dst: Reg,
+        op: BinOp,
+        lhs: &mir::Operand,
+        rhs: &mir::Operand,
+        signed: bool,
+    ) -> Sel {
+        if let Some(cond) = comparison(op, signed) {
+            let lhs = self.reg(lhs);
+            let rhs = self.small(rhs);
+            self.emit(Inst::alu(AluOp::Cmp, Size::Q, lhs, rhs));
+            self.set(dst, cond);
+            return Ok(());
+        }
+        match op {
+            BinOp::Add
+            | BinOp::Sub
+            | BinOp::BitAnd
+            | BinOp::BitOr
+            | BinOp::BitXor
+            | BinOp::And
+            | BinOp::Or => {
+                let alu = match op {
+                    BinOp::Add => AluOp::Add,
+                    BinOp::Sub => AluOp::Sub,
+                    BinOp::BitAnd | BinOp::And => AluOp::And,
+                    BinOp::BitOr | BinOp::Or => AluOp::Or,
+                    _ => AluOp::Xor,
+                };
+                let lhs = self.operand(lhs).unwrap_or(Operand::Imm(0));
+                self.emit(Inst::mov(Size::Q, dst, lhs));
+                let rhs = self.small(rhs);
+                self.emit(Inst::alu(alu, Size::Q, dst, rhs));
+                match (op, signed) {
+                    (BinOp::Add | BinOp::Sub, true) => self.trap_if(Cond::O, overflow(op)),
+                    (BinOp::Sub, false) => self.trap_if(Cond::B, overflow(op)),
+                    (BinOp::Add, false) => self.check_byte(dst, op),
+                    _ => {}
+                }
+            }
+            BinOp::Mul => {
+                let lhs = self.operand(lhs).unwrap_or(Operand::Imm(0));
+                self.emit(Inst::mov(Size::Q, dst, lhs));
+                let rhs = self.reg(rhs);
+                self.emit(Inst::Imul {
+                    size: Size::Q,
+                    dst,
+                    src: rhs.into(),
+                });
+                if signed {
+                    self.trap_if(Cond::O, overflow(op));
+                } else {
+                    self.check_byte(dst, op);
+                }
+            }
+            // `div` faults on both errors, so they are checked first.
+            BinOp::Div | BinOp::Rem => {
+                let lhs = self.reg(lhs);
+                let rhs = self.reg(rhs);
+                self.emit(Inst::alu(AluOp::Test, Size::Q, rhs, rhs));
+                let message = match op {
+                    BinOp::Div => "attempt to divide by zero",
+                    _ => "attempt to calculate the remainder with a divisor of zero",
+                };
+                self.trap_if(Cond::E, message.to_string());
+                if signed {
+                    let done = self.split_label();
+                    self.emit(Inst::alu(AluOp::Cmp, Size::Q, rhs, Operand::Imm(-1)));
+                    self.emit(Inst::Jcc(Cond::Ne, done.clone()));
+                    let min = self.vreg(RegClass::Int);
+                    self.emit(Inst::mov(Size::Q, min, Operand::Imm(i64::MIN)));
+                    self.emit(Inst::alu(AluOp::Cmp, Size::Q, lhs, min));
+                    self.trap_if(Cond::E, overflow(op));
+                    self.start_block(done);
+                }
+                self.emit(Inst::mov(Size::Q, Gpr::Rax, lhs));
+                if signed {
+                    self.emit(Inst::Cqo);
+                } else {
+                    self.emit(Inst::mov(Size::D, Gpr::Rdx, Operand::Imm(0)));
+                }
+                self.emit(Inst::Div {
+                    signed,
+                    size: Size::Q,
+                    src: rhs.into(),
+                });
+                let result = if op == BinOp::Div { Gpr::Rax } else { Gpr::Rdx };
+                self.emit(Inst::mov(Size::Q, dst, result));
+            }
+            _ => return Err(format!("`{}` on integers", op.symbol())),
+        }
+        Ok(())
+    }
+
+    /// Traps unless `reg` holds a `byte`.
+    fn check_byte(&mut self, reg: Reg, op: BinOp) {
+        self.emit(Inst::alu(AluOp::Cmp, Size::Q, reg, Operand::Imm(0xff)));
+        self.trap_if(Cond::A, overflow(op));
+    }
+
+    /// Sets `dst` to 1 if `cond` holds and 0 otherwise.
+    fn set(&mut self, dst: Reg, cond: Cond) {
+        self.emit(Inst::Setcc { cond, dst });
+        self.emit(Inst::MovZx {
+            size: Size::B,
+            dst,
+            src: dst.into(),
+        });
+    }
+
+    fn float_binary(&mut self, dst: Reg, op: BinOp, lhs: &mir::Operand, rhs: &mir::Operand) -> Sel {
+        let sse = match op {
+            BinOp::Add => SseOp::Addsd,
+            BinOp::Sub => SseOp::Subsd,
+            BinOp::Mul => SseOp::Mulsd,
+            BinOp::Div => SseOp::Divsd,
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge => {
+                let (a, b) = (self.reg(lhs), self.reg(rhs));
+                // `ucomisd` sets the flags of an unsigned comparison, and the
+                // parity flag when either side is NaN.
+                let (a, b, cond) = match op {
+                    BinOp::Gt => (a, b, Cond::A),
+                    BinOp::Ge => (a, b, Cond::Ae),
+                    BinOp::Lt => (b, a, Cond::A),
+                    BinOp::Le => (b, a, Cond::Ae),
+                    BinOp::Eq => (a, b, Cond::E),
+                    _ => (a, b, Cond::Ne),
+                };
+                self.emit(Inst::Sse {
+                    op: SseOp::Ucomisd,
+                    dst: a.into(),
+                    src: b.into(),
+                });
+                self.set(dst, cond);
+                if matches!(op, BinOp::Eq | BinOp::Ne) {
+                    let ordered = self.vreg(RegClass::Int);
+                    let (parity, combine) = match op {
+                        BinOp::Eq => (Cond::Np, AluOp::And),
+                        _ => (Cond::P, AluOp::Or),
+                    };
+                    self.set(ordered, parity);
+                    self.emit(Inst::alu(combine, Size::Q, dst, ordered));
+                }
+                return Ok(());
+            }
+            _ => return Err(format!("`{}` on `float` values", op.symbol())),
+        };
+        let lhs = self.reg(lhs);
+        self.emit(Inst::Sse {
+            op: SseOp::Movsd,
+            dst: dst.into(),
+            src: lhs.into(),
+        });
+        let rhs = self.reg(rhs);
+        self.emit(Inst::Sse {
+            op: sse,
+            dst: dst.into(),
+            src: rhs.into(),
+        });
+        Ok(())
+    }
+
+    /// Concatenation allocates the result with `malloc`; comparing for
+    /// equality compares lengths, then bytes with `memcmp`, or with
+    /// `repe cmpsb` in freestanding programs.
+    fn string_binary(
+        &mut self,
+        dst: Reg,
+        op: BinOp,
+        lhs: &mir::Operand,
+        rhs: &mir::Operand,
+    ) -> Sel {
+        let (a, b) = (self.reg(lhs), self.reg(rhs));
+        let load = |isel: &mut Self, string: Reg, offset: i64| {
+            let reg = isel.vreg(RegClass::Int);
+            isel.emit(Inst::mov(Size::Q, reg, Mem::reg(string, offset)));
+            reg
+        };
+        let (a_ptr, a_len, b_ptr, b_len) = (
+            load(self, a, 0),
+            load(self, a, 8),
+            load(self, b, 0),
+            load(self, b, 8),
+        );
+        match op {
+            BinOp::Add if self.target == target::Target::Freestanding => {
+                return Err("concatenating strings in freestanding programs".to_string());
+            }
+            BinOp::Add => {
+                let len = self.vreg(RegClass::Int);
+                self.emit(Inst::mov(Size::Q, len, a_len));
+                self.emit(Inst::alu(AluOp::Add, Size::Q, len, b_len));
+                let ptr = self.vreg(RegClass::Int);
+                let malloc = self.data.external("malloc");
+                self.call_c(malloc, &[len.into()], Some(ptr), false);
+                let memcpy = self.data.external("memcpy");
+                self.call_c(
+                    memcpy.clone(),
+                    &[ptr.into(), a_ptr.into(), a_len.into()],
+                    None,
+                    false,
+                );
+                let tail = self.vreg(RegClass::Int);
+                self.emit(Inst::mov(Size::Q, tail, ptr));
+                self.emit(Inst::alu(AluOp::Add, Size::Q, tail, a_len));
+                self.call_c(
+                    memcpy,
+                    &[tail.into(), b_ptr.into(), b_len.into()],
+                    None,
+                    false,
+                );
+                let (slot, address) = self.temp(&Ty::Str)?;
+                self.emit(Inst::mov(Size::Q, slot.clone(), ptr));
+                self.emit(Inst::mov(Size::Q, slot.offset(8), len));
+                self.emit(Inst::mov(Size::Q, dst, address));
+            }
+            BinOp::Eq | BinOp::Ne => {
+                // The bytes are compared only as far as both strings go if
+                // their lengths match, and not at all otherwise.
+                let same_len = self.vreg(RegClass::Int);
+                self.emit(Inst::alu(AluOp::Cmp, Size::Q, a_len, b_len));
+                self.set(same_len, Cond::E);
+                let count = self.vreg(RegClass::Int);
+                self.emit(Inst::mov(Size::Q, count, same_len));
+                self.emit(Inst::Neg {
+                    size: Size::Q,
+                    dst: count.into(),
+                });
+                self.emit(Inst::alu(AluOp::And, Size::Q, count, a_len));
+                if self.target == target::Target::Freestanding {
+                    // No bytes to compare leave the flags as `cmp` set them.
+                    let regs = vec![Reg::Gpr(Gpr::Rsi), Reg::Gpr(Gpr::Rdi), Reg::Gpr(Gpr::Rcx)];
+                    self.emit(Inst::mov(Size::Q, Gpr::Rsi, a_ptr));
+                    self.emit(Inst::mov(Size::Q, Gpr::Rdi, b_ptr));
+                    self.emit(Inst::mov(Size::Q, Gpr::Rcx, count));
+                    self.emit(Inst::Asm {
+                        lines: vec!["cmp rcx, rcx".to_string(), "repe cmpsb".to_string()],
+                        uses: regs.clone(),
+                        defs: Vec::new(),
+                        clobbers: regs,
+                    });
+                } else {
+                    let order = self.vreg(RegClass::Int);
+                    let memcmp = self.data.external("memcmp");
+                    self.call_c(
+                        memcmp,
+                        &[a_ptr.into(), b_ptr.into(), count.into()],
+                        Some(order),
+                        false,
+                    );
+                    self.emit(Inst::alu(AluOp::Test, Size::D, order, order));
+                }
+                self.set(dst, Cond::E);
+                self.emit(Inst::alu(AluOp::And, Size::Q, dst, same_len));
+                if op == BinOp::Ne {
+                    self.emit(Inst::alu(AluOp::Xor, Size::D, dst, Operand::Imm(1)));
+                }
+            }
+            _ => return Err(format!("`{}` on `string` values", op.symbol())),
+        }
+        Ok(())
+    }
+
+    // -----------------------------------------------------------------
+    // Calls
+    // -----------------------------------------------------------------
+
+    /// Receives the function's parameters where the caller put them.
+    fn receive_params(&mut self) -> Sel {
+        let abi = self.abi();
+        let mut passes = Vec::new();
+        let ret = self.pass(&self.function.ret.clone(), abi)?;
+        if ret.hidden_pointer() {
+            passes.push(Pass::Reg(Kind::Int(Size::Q)));
+        }
+        for &param in self.function.params() {
+            passes.push(self.pass(self.function.value_ty(param), abi)?);
+        }
+        // Calls through a function value always pass an environment, even
+        // to a closure that captures nothing.
+        if let Owner::Closure { .. } = self.function.owner {
+            let env = usize::from(ret.hidden_pointer());
+            passes[env] = Pass::Reg(Kind::Int(Size::Q));
+        }
+        let (locs, _) = assign(&passes);
+        let mut regs: Vec<Option<Reg>> = self
+            .function
+            .params()
+            .iter()
+            .map(|&param| self.value(param))
+            .collect();
+        if ret.hidden_pointer() {
+            let ptr = self.vreg(RegClass::Int);
+            self.ret_ptr = Some(ptr);
+            regs.insert(0, Some(ptr));
+        }
+        let incoming = |offset| Mem {
+            base: Base::Incoming,
+            disp: offset as i64,
+        };
+        for ((pieces, pass), reg) in locs.into_iter().zip(passes).zip(regs) {
+            let Some(reg) = reg else {
+                continue;
+            };
+            let kind = match pass {
+                Pass::Reg(kind) => kind,
+                Pass::Eightbytes(layout, _) | Pass::Memory(layout) => {
+                    let src = match pieces.first() {
+                        // A record copied onto the stack is used where it is.
+                        Some(&(_, Loc::Stack(offset))) => incoming(offset),
+                        _ => {
+                            let slot = self.eightbyte_slot(layout);
+                            for (offset, loc) in pieces {
+                                self.emit(match loc {
+                                    Loc::Gpr(gpr) => Inst::mov(Size::Q, slot.offset(offset), gpr),
+                                    Loc::Xmm(xmm) => Inst::Sse {
+                                        op: SseOp::Movsd,
+                                        dst: slot.offset(offset).into(),
+                                        src: Reg::Xmm(xmm).into(),
+                                    },
+                                    Loc::Stack(_) => unreachable!("records go on the stack whole"),
+                                });
+                            }
+                            slot
+                        }
+                    };
+                    self.emit(Inst::Lea { dst: reg, src });
+                    continue;
+                }
+            };
+            for (_, loc) in pieces {
+                let inst = match (loc, kind) {
+                    (Loc::Gpr(gpr), _) => Inst::mov(Size::Q, reg, gpr),
+                    (Loc::Xmm(xmm), _) => Inst::Sse {
+                        op: SseOp::Movsd,
+                        dst: reg.into(),
+                        src: Reg::Xmm(xmm).into(),
+                    },
+                    (Loc::Stack(offset), Kind::Float) => Inst::Sse {
+                        op: SseOp::Movsd,
+                        dst: reg.into(),
+                        src: incoming(offset).into(),
+                    },
+                    (Loc::Stack(offset), _) => Inst::mov(Size::Q, reg, incoming(offset)),
+                };
+                self.emit(inst);
+            }
+            // C leaves the bits above a small integer undefined.
+            if let (Abi::C { .. }, Kind::Int(size)) = (abi, kind)
+                && size != Size::Q
+            {
+                self.emit(Inst::MovZx {
+                    size,
+                    dst: reg,
+                    src: reg.into(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, dst: Option<Reg>, callee: &Callee, args: &[mir::Operand], ret: &Ty) -> Sel {
+        if let Callee::Builtin(name) = callee {
+            if let Some(done) = self.intrinsic(name, dst, args, ret) {
+                return done;
+            }
+            let ty = args
+                .first()
+                .map_or(Ty::Unit, |arg| self.function.operand_ty(arg));
+            let value = args.first().and_then(|arg| self.operand(arg));
+            return self.builtin(name, value, &ty);
+        }
+        let abi = match callee {
+            Callee::Fn {
+                self_ty: Some(Ty::Dyn(_)),
+                ..
+            } => Abi::Enigma,
+            Callee::Fn { .. } if mono::resolve(self.program, callee)?.0.attrs.c_abi => {
+                Abi::C { variadic: false }
+            }
+            Callee::Extern { variadic, .. } => Abi::C {
+                variadic: *variadic,
+            },
+            _ => Abi::Enigma,
+        };
+        let mut values = Vec::new();
+        for arg in args {
+            let pass = self.pass(&self.function.operand_ty(arg), abi)?;
+            values.push((self.operand(arg), pass));
+        }
+        let target = match callee {
+            Callee::Fn {
+                func:
+                    FnRef {
+                        owner: FnOwner::Protocol(protocol),
+                        name,
+                    },
+                self_ty: Some(Ty::Dyn(_)),
+                ..
+            } => {
+                // The receiver is a pair of the value and its vtable; the
+                // method gets the value.
+                let slot = mono::vtable_slot(self.program, protocol, name)
+                    .ok_or_else(|| format!("the protocol method `{}::{}`", protocol, name))?;
+                let pair = self.reg(&args[0]);
+                let data = self.vreg(RegClass::Int);
+                self.emit(Inst::mov(Size::Q, data, Mem::reg(pair, 0)));
+                let vtable = self.vreg(RegClass::Int);
+                self.emit(Inst::mov(Size::Q, vtable, Mem::reg(pair, 8)));
+                let code = self.vreg(RegClass::Int);
+                self.emit(Inst::mov(Size::Q, code, Mem::reg(vtable, slot as i64 * 8)));
+                values[0] = (Some(data.into()), Pass::Reg(Kind::Int(Size::Q)));
+                CallTarget::Reg(code)
+            }
+            Callee::Fn { .. } => {
+                let (target, args) = mono::resolve(self.program, callee)?;
+                CallTarget::Symbol(mono::function_symbol(target, &args))
+            }
+            Callee::Extern { symbol, .. } => self.foreign(symbol),
+            Callee::Value(value) => {
+                // The environment of a closure goes first, as its lifted
+                // function expects.
+                let fn_value = self.reg(value);
+                let code = self.vreg(RegClass::Int);
+                self.emit(Inst::mov(Size::Q, code, Mem::reg(fn_value, 0)));
+                let env = self.vreg(RegClass::Int);
+                self.emit(Inst::mov(Size::Q, env, Mem::reg(fn_value, 8)));
+                values.insert(0, (Some(env.into()), Pass::Reg(Kind::Int(Size::Q))));
+                CallTarget::Reg(code)
+            }
+            Callee::Builtin(_) => unreachable!("builtins were handled above"),
+        };
+        let ret = self.pass(ret, abi)?;
+        self.emit_call(target, values, ret, dst, abi);
+        Ok(())
+    }
+
+    /// The target of a call to the foreign function `symbol`: through the
+    /// procedure linkage table in hosted programs, whose C library may be
+    /// shared, and directly in freestanding ones.
+    fn foreign(&mut self, symbol: &str) -> CallTarget {
+        match self.target {
+            target::Target::Hosted => self.data.external(symbol),
+            target::Target::Freestanding => {
+                self.data.externs.insert(symbol.to_string());
+                CallTarget::Symbol(symbol.to_string())
+            }
+        }
+    }
+
+    /// Volatile and atomic accesses, port I/O and fences, which become the
+    /// instructions they name. Each happens even if its result goes unused.
+    /// x86 keeps loads in order with other loads and stores with other
+    /// stores, so atomic loads and stores are plain moves, and
+    /// read-modify-writes are locked instructions.
+    fn intrinsic(
+        &mut self,
+        name: &str,
+        dst: Option<Reg>,
+        args: &[mir::Operand],
+        ret: &Ty,
+    ) -> Option<Sel> {
+        let port_size = match name {
+            "inb" | "outb" => Size::B,
+            "inw" | "outw" => Size::W,
+            _ => Size::D,
+        };
+        match name {
+            "volatile_read" | "atomic_load" => {
+                let ptr = self.reg(&args[0]);
+                let class = match ret {
+                    Ty::Float => RegClass::Float,
+                    _ => RegClass::Int,
+                };
+                let dst = dst.unwrap_or_else(|| self.vreg(class));
+                Some(self.load(Some(dst), Mem::reg(ptr, 0), ret))
+            }
+            "volatile_write" | "atomic_store" => {
+                let ptr = self.reg(&args[0]);
+                let ty = self.function.operand_ty(&args[1]);
+                Some(self.store(Mem::reg(ptr, 0), &ty, &args[1]))
+            }
+            "atomic_swap" | "atomic_compare_exchange" | "atomic_fetch_add" | "atomic_fetch_sub" => {
+                let size = match self.kind(ret) {
+                    Ok(Kind::Int(size)) => size,
+                    Ok(_) => unreachable!("atomics are on integers and references"),
+                    Err(err) => return Some(Err(err)),
+                };
+                let ptr = self.reg(&args[0]);
+                let dst = dst.unwrap_or_else(|| self.vreg(RegClass::Int));
+                let (op, src) = match name {
+                    "atomic_compare_exchange" => {
+                        let new = self.reg(&args[2]);
+                        let current = self.small(&args[1]);
+                        self.emit(Inst::mov(Size::Q, Gpr::Rax, current));
+                        (AtomicOp::Cmpxchg, new)
+                    }
+                    _ => {
+                        let value = self.small(&args[1]);
+                        self.emit(Inst::mov(Size::Q, dst, value));
+                        if name == "atomic_fetch_sub" {
+                            self.emit(Inst::Neg {
+                                size: Size::Q,
+                                dst: dst.into(),
+                            });
+                        }
+                        let op = match name {
+                            "atomic_swap" => AtomicOp::Xchg,
+                            _ => AtomicOp::Xadd,
+                        };
+                        (op, dst)
+                    }
+                };
+                self.emit(Inst::Atomic {
+                    op,
+                    size,
+                    dst: Mem::reg(ptr, 0),
+                    src,
+                });
+                if op == AtomicOp::Cmpxchg {
+                    self.emit(Inst::mov(Size::Q, dst, Gpr::Rax));
+                }
+                // Bytes leave the rest of the register as it was, negated
+                // or not.
+                if size == Size::B {
+                    self.emit(Inst::MovZx {
+                        size,
+                        dst,
+                        src: dst.into(),
+                    });
+                }
+                Some(Ok(()))
+            }
+            "inb" | "inw" | "inl" => {
+                let port = self.small(&args[0]);
+                self.emit(Inst::mov(Size::Q, Gpr::Rdx, port));
+                self.emit(Inst::In { size: port_size });
+                if let Some(dst) = dst {
+                    self.emit(Inst::MovZx {
+                        size: port_size,
+                        dst,
+                        src: Gpr::Rax.into(),
+                    });
+                }
+                Some(Ok(()))
+            }
+            "outb" | "outw" | "outl" => {
+                let port = self.small(&args[0]);
+                let value = self.small(&args[1]);
+                self.emit(Inst::mov(Size::Q, Gpr::Rdx, port));
+                self.emit(Inst::mov(Size::Q, Gpr::Rax, value));
+                self.emit(Inst::Out { size: port_size });
+                Some(Ok(()))
+            }
+            "fence" | "load_fence" | "store_fence" => {
+                let fence = match name {
+                    "fence" => Fence::Full,
+                    "load_fence" => Fence::Load,
+                    _ => Fence::Store,
+                };
+                self.emit(Inst::Fence(fence));
+                Some(Ok(()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Calls `target` with `args` per System V, leaving the result in `dst`.
+    fn emit_call(
+        &mut self,
+        target: CallTarget,
+        mut args: Vec<(Option<Operand>, Pass)>,
+        ret: Pass,
+        dst: Option<Reg>,
+        abi: Abi,
+    ) {
+        let mut result_slot = None;
+        match &ret {
+            Pass::Reg(Kind::Memory(layout)) | Pass::Memory(layout) => {
+                let slot = Mem::slot(self.slot(*layout));
+                let ptr = self.vreg(RegClass::Int);
+                self.emit(Inst::Lea {
+                    dst: ptr,
+                    src: slot.clone(),
+                });
+                args.insert(0, (Some(ptr.into()), Pass::Reg(Kind::Int(Size::Q))));
+                result_slot = Some(slot);
+            }
+            Pass::Eightbytes(layout, _) => result_slot = Some(self.eightbyte_slot(*layout)),
+            Pass::Reg(_) => {}
+        }
+        let passes: Vec<Pass> = args.iter().map(|(_, pass)| pass.clone()).collect();
+        let (locs, stack) = assign(&passes);
+        self.outgoing = self.outgoing.max(stack);
+        let mut regs = Vec::new();
+        let mut moves = Vec::new();
+        for ((value, pass), pieces) in args.into_iter().zip(locs) {
+            let Some(value) = value else {
+                continue;
+            };
+            let kind = match pass {
+                Pass::Reg(kind) => kind,
+                Pass::Eightbytes(layout, _) | Pass::Memory(layout) => {
+                    // The record is copied whole eightbytes at a time, from a
+                    // copy that has room for the last one.
+                    let Operand::Reg(ptr) = value else {
+                        unreachable!("records are addressed by a register");
+                    };
+                    let copy = self.eightbyte_slot(layout);
+                    self.copy(copy.clone(), Mem::reg(ptr, 0), layout.size);
+                    for (offset, loc) in pieces {
+                        let src = copy.offset(offset);
+                        match loc {
+                            Loc::Gpr(gpr) => {
+                                regs.push(Reg::Gpr(gpr));
+                                moves.push(Inst::mov(Size::Q, gpr, src));
+                            }
+                            Loc::Xmm(xmm) => {
+                                regs.push(Reg::Xmm(xmm));
+                                moves.push(Inst::Sse {
+                                    op: SseOp::Movsd,
+                                    dst: Reg::Xmm(xmm).into(),
+                                    src: src.into(),
+                                });
+                            }
+                            Loc::Stack(offset) => {
+                                let temp = self.vreg(RegClass::Int);
+                                self.emit(Inst::mov(Size::Q, temp, src));
+                                let dst = Mem {
+                                    base: Base::Outgoing,
+                                    disp: offset as i64,
+                                };
+                                self.emit(Inst::mov(Size::Q, dst, temp));
+                            }
+                        }
+                    }
+                    continue;
+                }
+            };
+            let Some(&(_, loc)) = pieces.first() else {
+                continue;
+            };
+            match (loc, kind) {
+                (Loc::Stack(offset), Kind::Float) => self.emit(Inst::Sse {
+                    op: SseOp::Movsd,
+                    dst: Mem {
+                        base: Base::Outgoing,
+                        disp: offset as i64,
+                    }
+                    .into(),
+                    src: value,
+                }),
+                (Loc::Stack(offset), _) => {
+                    let value = match value {
+                        Operand::Imm(n) if i32::try_from(n).is_err() => {
+                            let reg = self.vreg(RegClass::Int);
+                            self.emit(Inst::mov(Size::Q, reg, Operand::Imm(n)));
+                            reg.into()
+                        }
+                        value => value,
+                    };
+                    self.emit(Inst::mov(
+                        Size::Q,
+                        Mem {
+                            base: Base::Outgoing,
+                            disp: offset as i64,
+                        },
+                        value,
+                    ));
+                }
+                // Registers are loaded last, right before the call.
+                (Loc::Gpr(gpr), _) => {
+                    regs.push(Reg::Gpr(gpr));
+                    moves.push(Inst::mov(Size::Q, gpr, value));
+                }
+                (Loc::Xmm(xmm), _) => {
+                    regs.push(Reg::Xmm(xmm));
+                    moves.push(Inst::Sse {
+                        op: SseOp::Movsd,
+                        dst: Reg::Xmm(xmm).into(),
+                        src: value,
+                    });
+                }
+            }
+        }
+        for inst in moves {
+            self.emit(inst);
+        }
+        if abi == (Abi::C { variadic: true }) {
+            // `al` holds how many vector registers carry arguments.
+            let sse = regs.iter().filter(|reg| matches!(reg, Reg::Xmm(_))).count();
+            self.emit(Inst::mov(Size::D, Gpr::Rax, Operand::Imm(sse as i64)));
+            regs.push(Reg::Gpr(Gpr::Rax));
+        }
+        let results = result_regs(&ret);
+        self.emit(Inst::Call {
+            target,
+            args: regs,
+            results: results.clone(),
+        });
+        let Some(dst) = dst else {
+            return;
+        };
+        match ret {
+            // C leaves the bits above a small integer undefined.
+            Pass::Reg(Kind::Int(size)) if size != Size::Q && abi != Abi::Enigma => {
+                self.emit(Inst::MovZx {
+                    size,
+                    dst,
+                    src: Gpr::Rax.into(),
+                })
+            }
+            Pass::Reg(Kind::Int(_)) => self.emit(Inst::mov(Size::Q, dst, Gpr::Rax)),
+            Pass::Reg(Kind::Float) => self.emit(Inst::Sse {
+                op: SseOp::Movsd,
+                dst: dst.into(),
+                src: Reg::Xmm(0).into(),
+            }),
+            Pass::Reg(Kind::Zero) => {}
+            Pass::Reg(Kind::Memory(_)) | Pass::Memory(_) => self.emit(Inst::Lea {
+                dst,
+                src: result_slot.unwrap(),
+            }),
+            Pass::Eightbytes(_, eightbytes) => {
+                let slot = result_slot.unwrap();
+                for ((offset, _), reg) in eightbytes.into_iter().zip(results) {
+                    self.emit(match reg {
+                        Reg::Xmm(_) => Inst::Sse {
+                            op: SseOp::Movsd,
+                            dst: slot.offset(offset).into(),
+                            src: reg.into(),
+                        },
+                        _ => Inst::mov(Size::Q, slot.offset(offset), reg),
+                    });
+                }
+                self.emit(Inst::Lea { dst, src: slot });
+            }
+        }
+    }
+
+    /// Calls a C function taking and returning integers and pointers.
+    fn call_c(&mut self, target: CallTarget, args: &[Operand], dst: Option<Reg>, variadic: bool) {
+        let args = args
+            .iter()
+            .map(|arg| (Some(arg.clone()), Pass::Reg(Kind::Int(Size::Q))))
+            .collect();
+        let ret = Pass::Reg(if dst.is_some() {
+            Kind::Int(Size::Q)
+        } else {
+            Kind::Zero
+        });
+        self.emit_call(target, args, ret, dst, Abi::C { variadic });
+    }
+
+    /// `print` and `exit`, through the C library: `print` writes its
+    /// argument and a newline with `printf`, `exit` flushes the output and
+    /// ends the process. `exit` with a message writes it to the standard
+    /// error and exits with 101, as a runtime error does, or calls the panic
+    /// handler of a freestanding program.
+    fn builtin(&mut self, name: &str, value: Option<Operand>, ty: &Ty) -> Sel {
+        let value = value.unwrap_or(Operand::Imm(0));
+        if self.target == target::Target::Freestanding {
+            return match (name, value) {
+                ("exit", Operand::Reg(message)) if *ty == Ty::Str => {
+                    self.panic(message);
+                    Ok(())
+                }
+                _ => Err(format!("calls to `{}` in freestanding programs", name)),
+            };
+        }
+        match (name, ty) {
+            ("print", Ty::Int | Ty::Byte) => {
+                let format = self
+                    .data
+                    .cstring(if *ty == Ty::Int { "%ld\n" } else { "%lu\n" });
+                let format = self.address(format);
+                let printf = self.data.external("printf");
+                self.call_c(printf, &[format.into(), value], None, true);
+            }
+            ("print", Ty::Bool) => {
+                let yes = self.data.cstring("true");
+                let yes = self.address(yes);
+                let no = self.data.cstring("false");
+                let text = self.address(no);
+                let value = match value {
+                    Operand::Imm(n) => {
+                        let reg = self.vreg(RegClass::Int);
+                        self.emit(Inst::mov(Size::D, reg, Operand::Imm(n)));
+                        reg.into()
+                    }
+                    value => value,
+                };
+                self.emit(Inst::alu(AluOp::Test, Size::B, value.clone(), value));
+                self.emit(Inst::Cmov {
+                    cond: Cond::Ne,
+                    dst: text,
+                    src: yes.into(),
+                });
+                let puts = self.data.external("puts");
+                self.call_c(puts, &[text.into()], None, false);
+            }
+            ("print", Ty::Unit) => {
+                let text = self.data.cstring("()");
+                let text = self.address(text);
+                let puts = self.data.external("puts");
+                self.call_c(puts, &[text.into()], None, false);
+            }
+            ("print", Ty::Str) => {
+                let Operand::Reg(string) = value else {
+                    unreachable!("strings are held by address");
+                };
+                let format = self.data.cstring("%.*s\n");
+                let format = self.address(format);
+                let printf = self.data.external("printf");
+                self.call_c(
+                    printf,
+                    &[
+                        format.into(),
+                        Mem::reg(string, 8).into(),
+                        Mem::reg(string, 0).into(),
+                    ],
+                    None,
+                    true,
+                );
+            }
+            ("exit", Ty::Int | Ty::Byte) => {
+                let exit = self.data.external("exit");
+                self.call_c(exit, &[value], None, false);
+            }
+            ("exit", Ty::Str) => {
+                let Operand::Reg(string) = value else {
+                    unreachable!("strings are held by address");
+                };
+                let format = self.data.cstring("error: %.*s\n");
+                let format = self.address(format);
+                let dprintf = self.data.external("dprintf");
+                self.call_c(
+                    dprintf,
+                    &[
+                        Operand::Imm(2),
+                        format.into(),
+                        Mem::reg(string, 8).into(),
+                        Mem::reg(string, 0).into(),
+                    ],
+                    None,
+                    true,
+                );
+                let exit = self.data.external("exit");
+                self.call_c(exit, &[Operand::Imm(101)], None, false);
+            }
+            ("print", _) => return Err(format!("printing `{}` values", ty)),
+            _ => return Err(format!("calls to `{}` with `{}` values", name, ty)),
+        }
+        Ok(())
+    }
+
+    /// The address of a symbol in a register.
+    fn address(&mut self, symbol: String) -> Reg {
+        let reg = self.vreg(RegClass::Int);
+        self.emit(Inst::Lea {
+            dst: reg,
+            src: Mem::symbol(symbol),
+        });
+        reg
+    }
+
+    // -----------------------------------------------------------------
+    // Control flow
+    // -----------------------------------------------------------------
+
+    fn terminator(&mut self, block: usize, term: &Terminator) -> Sel {
+        match term {
+            Terminator::Goto(target) => {
+                self.jump_args(target)?;
+                self.emit(Inst::Jmp(label(self.index, target.block.0 as usize)));
+            }
+            Terminator::Branch {
+                cond,
+                then_target,
+                else_target,
+            } => {
+                if let mir::Operand::Const(Const::Bool(taken)) = cond {
+                    let target = if *taken { then_target } else { else_target };
+                    self.jump_args(target)?;
+                    self.emit(Inst::Jmp(label(self.index, target.block.0 as usize)));
+                    return Ok(());
+                }
+                let cond = self.reg(cond);
+                self.emit(Inst::alu(AluOp::Test, Size::B, cond, cond));
+                // Arguments for the else branch are passed on an edge of
+                // its own, so they aren't computed when the branch is not
+                // taken.
+                let else_label = if else_target.args.is_empty() {
+                    label(self.index, else_target.block.0 as usize)
+                } else {
+                    format!("{}_else", label(self.index, block))
+                };
+                self.emit(Inst::Jcc(Cond::E, else_label.clone()));
+                self.jump_args(then_target)?;
+                self.emit(Inst::Jmp(label(self.index, then_target.block.0 as usize)));
+                if !else_target.args.is_empty() {
+                    self.start_block(else_label);
+                    self.jump_args(else_target)?;
+                    self.emit(Inst::Jmp(label(self.index, else_target.block.0 as usize)));
+                }
+            }
+            Terminator::Return(value) => {
+                let ty = self.function.ret.clone();
+                let ret = self.pass(&ty, self.abi())?;
+                if let Pass::Eightbytes(layout, eightbytes) = &ret {
+                    let value = self.reg(value);
+                    let copy = self.eightbyte_slot(*layout);
+                    self.copy(copy.clone(), Mem::reg(value, 0), layout.size);
+                    let results = result_regs(&ret);
+                    for (&(offset, _), &reg) in eightbytes.iter().zip(&results) {
+                        self.emit(match reg {
+                            Reg::Xmm(_) => Inst::Sse {
+                                op: SseOp::Movsd,
+                                dst: reg.into(),
+                                src: copy.offset(offset).into(),
+                            },
+                            _ => Inst::mov(Size::Q, reg, copy.offset(offset)),
+                        });
+                    }
+                    self.emit(Inst::Ret { results });
+                    return Ok(());
+                }
+                let results = match self.kind(&ty)? {
+                    Kind::Zero => Vec::new(),
+                    Kind::Int(_) => {
+                        let value = self.operand(value).unwrap_or(Operand::Imm(0));
+                        self.emit(Inst::mov(Size::Q, Gpr::Rax, value));
+                        vec![Reg::Gpr(Gpr::Rax)]
+                    }
+                    Kind::Float => {
+                        let value = self.reg(value);
+                        self.emit(Inst::Sse {
+                            op: SseOp::Movsd,
+                            dst: Reg::Xmm(0).into(),
+                            src: value.into(),
+                        });
+                        vec![Reg::Xmm(0)]
+                    }
+                    Kind::Memory(layout) => {
+                        let ptr = self.ret_ptr.unwrap();
+                        let value = self.reg(value);
+                        self.copy(Mem::reg(ptr, 0), Mem::reg(value, 0), layout.size);
+                        self.emit(Inst::mov(Size::Q, Gpr::Rax, ptr));
+                        vec![Reg::Gpr(Gpr::Rax)]
+                    }
+                };
+                self.emit(Inst::Ret { results });
+            }
+            Terminator::Unreachable => self.emit(Inst::Ud2),
+        }
+        Ok(())
+    }
+
+    /// Passes a jump's arguments to the parameters of its target. All are
+    /// read before any is written, since a parameter may be passed on to
+    /// another of the same block.
+    fn jump_args(&mut self, target: &Target) -> Sel {
+        let params = &self.function.block(target.block).params;
+        let mut pending = Vec::new();
+        for (&param, arg) in params.iter().zip(&target.args) {
+            let Some(dst) = self.value(param) else {
+                continue;
+            };
+            let ty = self.function.value_ty(param).clone();
+            match self.kind(&ty)? {
+                Kind::Memory(layout) => {
+                    // The parameter keeps its value in a slot of its own,
+                    // since the argument's may be rewritten while the
+                    // parameter is still in use, as in a loop.
+                    let staging = Mem::slot(self.slot(layout));
+                    let src = self.reg(arg);
+                    self.copy(staging.clone(), Mem::reg(src, 0), layout.size);
+                    pending.push((dst, Some((staging, layout)), None));
+                }
+                kind => {
+                    let class = if kind == Kind::Float {
+                        RegClass::Float
+                    } else {
+                        RegClass::Int
+                    };
+                    let temp = self.copy_to_reg(arg, class);
+                    pending.push((dst, None, Some(temp)));
+                }
+            }
+        }
+        for (dst, staging, temp) in pending {
+            if let Some((staging, layout)) = staging {
+                let home = self.param_slot(dst, layout);
+                self.copy(home.clone(), staging, layout.size);
+                self.emit(Inst::Lea { dst, src: home });
+            } else if let Some(temp) = temp {
+                let inst = match dst.class() {
+                    RegClass::Int => Inst::mov(Size::Q, dst, temp),
+                    RegClass::Float => Inst::Sse {
+                        op: SseOp::Movsd,
+                        dst: dst.into(),
+                        src: temp.into(),
+                    },
+                };
+                self.emit(inst);
+            }
+        }
+        Ok(())
+    }
+
+    /// The slot holding the compound block parameter in `reg`.
+    fn param_slot(&mut self, reg: Reg, layout: Layout) -> Mem {
+        let slot = match self.param_slots.get(&reg) {
+            Some(&slot) => slot,
+            None => {
+                let slot = self.slot(layout);
+                self.param_slots.insert(reg, slot);
+                slot
+            }
+        };
+        Mem::slot(slot)
+    }
+}
+
+/// The runtime error of `op` overflowing.
+fn overflow(op: BinOp) -> String {
+    let verb = match op {
+        BinOp::Add => "add",
+        BinOp::Sub => "subtract",
+        BinOp::Mul => "multiply",
+        BinOp::Div => "divide",
+        _ => "calculate the remainder",
+    };
+    format!("attempt to {} with overflow", verb)
+}
+
+fn comparison(op: BinOp, signed: bool) -> Option<Cond> {
+    Some(match (op, signed) {
+        (BinOp::Eq, _) => Cond::E,
+        (BinOp::Ne, _) => Cond::Ne,
+        (BinOp::Lt, true) => Cond::L,
+        (BinOp::Le, true) => Cond::Le,
+        (BinOp::Gt, true) => Cond::G,
+        (BinOp::Ge, true) => Cond::Ge,
+        (BinOp::Lt, false) => Cond::B,
+        (BinOp::Le, false) => Cond::Be,
+        (BinOp::Gt, false) => Cond::A,
+        (BinOp::Ge, false) => Cond::Ae,
+        _ => return None,
+    })
+}
+
+fn op_symbol(op: UnaryOp) -> &'static str {
+    match op {
+        UnaryOp::Neg => "-",
+        UnaryOp::Not => "!",
+    }
+}
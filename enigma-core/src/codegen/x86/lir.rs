@@ -0,0 +1,575 @@
+//! The machine IR: x86-64 instructions over virtual and physical registers.
+//! Instruction selection produces it with virtual registers and abstract
+//! frame slots; register allocation and frame layout leave only physical
+//! registers and `rbp`/`rsp`-relative memory for the printers.
+
+use std::fmt;
+
+/// General purpose registers, numbered as in their encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Gpr {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl Gpr {
+    pub const ALL: [Gpr; 16] = [
+        Gpr::Rax,
+        Gpr::Rcx,
+        Gpr::Rdx,
+        Gpr::Rbx,
+        Gpr::Rsp,
+        Gpr::Rbp,
+        Gpr::Rsi,
+        Gpr::Rdi,
+        Gpr::R8,
+        Gpr::R9,
+        Gpr::R10,
+        Gpr::R11,
+        Gpr::R12,
+        Gpr::R13,
+        Gpr::R14,
+        Gpr::R15,
+    ];
+
+    /// Where System V passes integer arguments, in order.
+    pub const ARGS: [Gpr; 6] = [Gpr::Rdi, Gpr::Rsi, Gpr::Rdx, Gpr::Rcx, Gpr::R8, Gpr::R9];
+
+    /// Registers a call may overwrite.
+    pub const CALLER_SAVED: [Gpr; 9] = [
+        Gpr::Rax,
+        Gpr::Rcx,
+        Gpr::Rdx,
+        Gpr::Rsi,
+        Gpr::Rdi,
+        Gpr::R8,
+        Gpr::R9,
+        Gpr::R10,
+        Gpr::R11,
+    ];
+
+    /// Registers a function must restore before returning.
+    pub const CALLEE_SAVED: [Gpr; 5] = [Gpr::Rbx, Gpr::R12, Gpr::R13, Gpr::R14, Gpr::R15];
+
+    pub fn number(self) -> u8 {
+        self as u8
+    }
+
+    /// The name of the register's low `size` bytes.
+    pub fn name(self, size: Size) -> &'static str {
+        const NAMES: [[&str; 4]; 16] = [
+            ["al", "ax", "eax", "rax"],
+            ["cl", "cx", "ecx", "rcx"],
+            ["dl", "dx", "edx", "rdx"],
+            ["bl", "bx", "ebx", "rbx"],
+            ["spl", "sp", "esp", "rsp"],
+            ["bpl", "bp", "ebp", "rbp"],
+            ["sil", "si", "esi", "rsi"],
+            ["dil", "di", "edi", "rdi"],
+            ["r8b", "r8w", "r8d", "r8"],
+            ["r9b", "r9w", "r9d", "r9"],
+            ["r10b", "r10w", "r10d", "r10"],
+            ["r11b", "r11w", "r11d", "r11"],
+            ["r12b", "r12w", "r12d", "r12"],
+            ["r13b", "r13w", "r13d", "r13"],
+            ["r14b", "r14w", "r14d", "r14"],
+            ["r15b", "r15w", "r15d", "r15"],
+        ];
+        NAMES[self as usize][size as usize]
+    }
+}
+
+/// Operand sizes, in bytes 1, 2, 4 and 8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Size {
+    B,
+    W,
+    D,
+    Q,
+}
+
+impl Size {
+    pub fn bytes(self) -> u64 {
+        1 << self as u64
+    }
+
+    pub fn of(bytes: u64) -> Size {
+        match bytes {
+            1 => Size::B,
+            2 => Size::W,
+            4 => Size::D,
+            _ => Size::Q,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RegClass {
+    Int,
+    Float,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Reg {
+    Gpr(Gpr),
+    /// `xmm0` to `xmm15`.
+    Xmm(u8),
+    Virt(u32, RegClass),
+}
+
+impl Reg {
+    pub fn class(self) -> RegClass {
+        match self {
+            Reg::Gpr(_) => RegClass::Int,
+            Reg::Xmm(_) => RegClass::Float,
+            Reg::Virt(_, class) => class,
+        }
+    }
+
+    pub fn is_virtual(self) -> bool {
+        matches!(self, Reg::Virt(..))
+    }
+}
+
+impl From<Gpr> for Reg {
+    fn from(gpr: Gpr) -> Self {
+        Reg::Gpr(gpr)
+    }
+}
+
+/// What a memory operand is relative to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Base {
+    Reg(Reg),
+    /// A slot of the function's frame, placed by frame layout.
+    Slot(u32),
+    /// The stack arguments a function received, from the first.
+    Incoming,
+    /// The stack arguments of the calls a function makes, from the first.
+    Outgoing,
+    /// A symbol, addressed relative to `rip`.
+    Symbol(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mem {
+    pub base: Base,
+    pub disp: i64,
+}
+
+impl Mem {
+    pub fn reg(reg: impl Into<Reg>, disp: i64) -> Self {
+        Mem {
+            base: Base::Reg(reg.into()),
+            disp,
+        }
+    }
+
+    pub fn slot(slot: u32) -> Self {
+        Mem {
+            base: Base::Slot(slot),
+            disp: 0,
+        }
+    }
+
+    pub fn symbol(symbol: impl Into<String>) -> Self {
+        Mem {
+            base: Base::Symbol(symbol.into()),
+            disp: 0,
+        }
+    }
+
+    pub fn offset(&self, by: u64) -> Self {
+        Mem {
+            base: self.base.clone(),
+            disp: self.disp + by as i64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Reg(Reg),
+    Imm(i64),
+    Mem(Mem),
+}
+
+impl From<Reg> for Operand {
+    fn from(reg: Reg) -> Self {
+        Operand::Reg(reg)
+    }
+}
+
+impl From<Gpr> for Operand {
+    fn from(gpr: Gpr) -> Self {
+        Operand::Reg(Reg::Gpr(gpr))
+    }
+}
+
+impl From<Mem> for Operand {
+    fn from(mem: Mem) -> Self {
+        Operand::Mem(mem)
+    }
+}
+
+/// Condition codes, as in `jcc` and `setcc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    O,
+    No,
+    B,
+    Ae,
+    E,
+    Ne,
+    Be,
+    A,
+    S,
+    Ns,
+    P,
+    Np,
+    L,
+    Ge,
+    Le,
+    G,
+}
+
+impl Cond {
+    /// The mnemonic suffix, which is also the condition's encoding order.
+    pub fn suffix(self) -> &'static str {
+        [
+            "o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+        ][self as usize]
+    }
+
+    pub fn number(self) -> u8 {
+        self as u8
+    }
+
+    /// The condition holding exactly when this one doesn't.
+    pub fn negate(self) -> Cond {
+        use Cond::*;
+        [O, No, B, Ae, E, Ne, Be, A, S, Ns, P, Np, L, Ge, Le, G][self as usize ^ 1]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOp {
+    Add,
+    Or,
+    And,
+    Sub,
+    Xor,
+    Cmp,
+    Test,
+}
+
+impl AluOp {
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            AluOp::Add => "add",
+            AluOp::Or => "or",
+            AluOp::And => "and",
+            AluOp::Sub => "sub",
+            AluOp::Xor => "xor",
+            AluOp::Cmp => "cmp",
+            AluOp::Test => "test",
+        }
+    }
+
+    /// Whether the instruction only sets flags.
+    pub fn compares(self) -> bool {
+        matches!(self, AluOp::Cmp | AluOp::Test)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShiftOp {
+    Shl,
+    Shr,
+}
+
+impl ShiftOp {
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            ShiftOp::Shl => "shl",
+            ShiftOp::Shr => "shr",
+        }
+    }
+}
+
+/// Which memory accesses a fence orders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fence {
+    /// Loads and stores: `mfence`.
+    Full,
+    /// Loads: `lfence`.
+    Load,
+    /// Stores: `sfence`.
+    Store,
+}
+
+impl Fence {
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Fence::Full => "mfence",
+            Fence::Load => "lfence",
+            Fence::Store => "sfence",
+        }
+    }
+}
+
+/// Read-modify-write instructions that no other processor can come between
+/// the read and write of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtomicOp {
+    /// Swaps memory with the register; locked without a prefix.
+    Xchg,
+    /// Adds the register to memory, leaving the old value in the register.
+    Xadd,
+    /// Stores the register if memory holds `rax`, and loads memory into
+    /// `rax` either way.
+    Cmpxchg,
+}
+
+impl AtomicOp {
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            AtomicOp::Xchg => "xchg",
+            AtomicOp::Xadd => "lock xadd",
+            AtomicOp::Cmpxchg => "lock cmpxchg",
+        }
+    }
+}
+
+/// Scalar double-precision operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SseOp {
+    Movsd,
+    Addsd,
+    Subsd,
+    Mulsd,
+    Divsd,
+    Ucomisd,
+    Xorpd,
+}
+
+impl SseOp {
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            SseOp::Movsd => "movsd",
+            SseOp::Addsd => "addsd",
+            SseOp::Subsd => "subsd",
+            SseOp::Mulsd => "mulsd",
+            SseOp::Divsd => "divsd",
+            SseOp::Ucomisd => "ucomisd",
+            SseOp::Xorpd => "xorpd",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallTarget {
+    /// A function of the program.
+    Symbol(String),
+    /// A function of another object, such as the C library, called
+    /// through the procedure linkage table.
+    External(String),
+    Reg(Reg),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    Mov {
+        size: Size,
+        dst: Operand,
+        src: Operand,
+    },
+    /// Loads `size` bytes zero-extended to the whole register.
+    MovZx {
+        size: Size,
+        dst: Reg,
+        src: Operand,
+    },
+    Lea {
+        dst: Reg,
+        src: Mem,
+    },
+    Alu {
+        op: AluOp,
+        size: Size,
+        dst: Operand,
+        src: Operand,
+    },
+    Imul {
+        size: Size,
+        dst: Reg,
+        src: Operand,
+    },
+    Neg {
+        size: Size,
+        dst: Operand,
+    },
+    Not {
+        size: Size,
+        dst: Operand,
+    },
+    /// Shifts `dst` by a constant number of bits, filling with zeros.
+    Shift {
+        op: ShiftOp,
+        size: Size,
+        dst: Operand,
+        amount: u8,
+    },
+    /// Sign-extends `rax` into `rdx`.
+    Cqo,
+    /// Divides `rdx:rax` by `src`, leaving the quotient in `rax` and the
+    /// remainder in `rdx`.
+    Div {
+        signed: bool,
+        size: Size,
+        src: Operand,
+    },
+    /// Sets the low byte of `dst` to whether `cond` holds.
+    Setcc {
+        cond: Cond,
+        dst: Reg,
+    },
+    Sse {
+        op: SseOp,
+        dst: Operand,
+        src: Operand,
+    },
+    /// Moves `src` to `dst` if `cond` holds.
+    Cmov {
+        cond: Cond,
+        dst: Reg,
+        src: Operand,
+    },
+    /// Moves a `float` between a general purpose and an SSE register.
+    Movq {
+        dst: Reg,
+        src: Reg,
+    },
+    Jmp(String),
+    Jcc(Cond, String),
+    /// Reads the registers holding arguments, overwrites every caller-saved
+    /// register and defines those holding results.
+    Call {
+        target: CallTarget,
+        args: Vec<Reg>,
+        results: Vec<Reg>,
+    },
+    /// Returns with the result in `results`. Frame layout adds the
+    /// epilogue before it.
+    Ret {
+        results: Vec<Reg>,
+    },
+    Push(Gpr),
+    Pop(Gpr),
+    Ud2,
+    /// Reads the I/O port in `dx` into `al`, `ax` or `eax`.
+    In {
+        size: Size,
+    },
+    /// Writes `al`, `ax` or `eax` to the I/O port in `dx`.
+    Out {
+        size: Size,
+    },
+    Fence(Fence),
+    Atomic {
+        op: AtomicOp,
+        size: Size,
+        dst: Mem,
+        src: Reg,
+    },
+    /// Lines of inline assembly in Intel syntax, with their operands'
+    /// registers written in. They read `uses`, write `defs` and may
+    /// overwrite `clobbers`.
+    Asm {
+        lines: Vec<String>,
+        uses: Vec<Reg>,
+        defs: Vec<Reg>,
+        clobbers: Vec<Reg>,
+    },
+}
+
+impl Inst {
+    pub fn mov(size: Size, dst: impl Into<Operand>, src: impl Into<Operand>) -> Self {
+        Inst::Mov {
+            size,
+            dst: dst.into(),
+            src: src.into(),
+        }
+    }
+
+    pub fn alu(op: AluOp, size: Size, dst: impl Into<Operand>, src: impl Into<Operand>) -> Self {
+        Inst::Alu {
+            op,
+            size,
+            dst: dst.into(),
+            src: src.into(),
+        }
+    }
+
+    /// Whether control never continues to the next instruction.
+    pub fn ends_block(&self) -> bool {
+        matches!(self, Inst::Jmp(_) | Inst::Ret { .. } | Inst::Ud2)
+    }
+}
+
+/// A labelled run of instructions, entered only at the top.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MBlock {
+    pub label: String,
+    pub insts: Vec<Inst>,
+}
+
+/// A function ready for register allocation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MFunction {
+    pub symbol: String,
+    /// Whether other objects may call the function.
+    pub global: bool,
+    pub blocks: Vec<MBlock>,
+    /// The size and alignment of each frame slot.
+    pub slots: Vec<(u64, u64)>,
+    /// Bytes of stack arguments the function's calls pass.
+    pub outgoing: u64,
+    pub vregs: u32,
+    /// Filled in by frame layout: bytes below `rbp`, and the callee-saved
+    /// registers to restore.
+    pub frame_size: u64,
+    pub saved: Vec<Gpr>,
+    /// The section the code goes in, `.text` if `None`.
+    pub section: Option<String>,
+    /// The alignment of the first instruction, if more than a byte.
+    pub align: Option<u64>,
+    /// Left without a frame: frame layout adds no prologue or epilogues.
+    pub naked: bool,
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Size::B => "byte",
+            Size::W => "word",
+            Size::D => "dword",
+            Size::Q => "qword",
+        };
+        write!(f, "{}", name)
+    }
+}
@@ -0,0 +1,178 @@
+//! The x86-64 backend, for Linux and the System V ABI.
+//!
+//! Each instance is compiled in three steps: instruction selection lowers
+//! its MIR to machine IR over virtual registers (`isel.rs`), register
+//! allocation assigns them registers and lays out the frame
+//! (`regalloc.rs`), and the result is printed as assembly (`emit.rs`) or
+//! encoded as machine code (`encode.rs`) into an ELF object (`object.rs`).
+//! Hosted output runs on the C library, which provides the process entry,
+//! `print` and `exit`, so it is linked with a C compiler:
+//!
+//! ```text
+//! enigma build --emit=asm main.en > main.s && cc main.s -o main
+//! enigma build --emit=obj -o main.o main.en && cc main.o -o main
+//! ```
+//!
+//! Freestanding output needs nothing but itself. Its entry, `_start`, comes
+//! first in `.text`, and the Multiboot2 boot code (`boot.rs`) last, with
+//! the header in a section of its own:
+//!
+//! ```text
+//! enigma build --target x86_64-none --emit=obj -o kernel.o kernel.en
+//! ld -n --section-start=.multiboot2=0x100000 -Ttext=0x100020 -e _start32 \
+//!     kernel.o -o kernel.elf
+//! ```
+
+pub mod boot;
+pub mod emit;
+pub mod encode;
+pub mod isel;
+pub mod lir;
+pub mod object;
+pub mod regalloc;
+
+use super::CodegenError;
+use super::elf;
+use super::layout::{self, Layout};
+use super::mono;
+use crate::mir::{Owner, Program};
+use crate::target::Target;
+use isel::{Data, VtableData};
+use lir::MFunction;
+
+/// The assembly syntax to write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// AT&T syntax, for the GNU assembler.
+    Att,
+    /// Intel syntax, for NASM.
+    Intel,
+}
+
+impl Syntax {
+    pub fn parse(name: &str) -> Option<Syntax> {
+        match name {
+            "att" => Some(Syntax::Att),
+            "intel" => Some(Syntax::Intel),
+            _ => None,
+        }
+    }
+}
+
+/// A program compiled to machine code, before it is written out.
+#[derive(Debug)]
+pub struct Compiled {
+    pub functions: Vec<MFunction>,
+    pub data: Data,
+    /// The symbol and layout of every global.
+    pub globals: Vec<(String, Layout)>,
+    pub target: Target,
+}
+
+impl Compiled {
+    /// The functions by the section they go in: `.text` first, then those
+    /// `#[section]` names, in the order they first appear.
+    pub fn sections(&self) -> Vec<(&str, Vec<&MFunction>)> {
+        let mut sections: Vec<(&str, Vec<&MFunction>)> = vec![(".text", Vec::new())];
+        for function in &self.functions {
+            let name = function.section.as_deref().unwrap_or(".text");
+            match sections.iter_mut().find(|(section, _)| *section == name) {
+                Some((_, functions)) => functions.push(function),
+                None => sections.push((name, vec![function])),
+            }
+        }
+        sections
+    }
+}
+
+/// Compiles every function `program` needs, and an entry running its
+/// module initializers: a C `main`, or `_start` for freestanding programs.
+pub fn compile(program: &Program, target: Target) -> Result<Compiled, CodegenError> {
+    let mut data = Data::default();
+    let mut functions = Vec::new();
+    for (index, instance) in mono::instances(program)?.iter().enumerate() {
+        let mut function =
+            isel::select(program, instance, index, target, &mut data).map_err(|message| {
+                CodegenError {
+                    function: instance.function.name.clone(),
+                    message,
+                }
+            })?;
+        regalloc::allocate(&mut function);
+        if instance.function.attrs.interrupt {
+            let mut entry = isel::interrupt(instance);
+            regalloc::allocate(&mut entry);
+            functions.push(entry);
+        }
+        functions.push(function);
+    }
+    let inits: Vec<String> = program
+        .functions
+        .iter()
+        .filter(|function| matches!(function.owner, Owner::Init(_)))
+        .map(|function| mono::symbol(&function.name, &[]))
+        .collect();
+    let mut entry = match target {
+        Target::Hosted => isel::entry(&inits),
+        Target::Freestanding => isel::start(&inits),
+    };
+    regalloc::allocate(&mut entry);
+    // A flat binary starts running at its first byte.
+    match target {
+        Target::Hosted => functions.push(entry),
+        Target::Freestanding => functions.insert(0, entry),
+    }
+    // A function declared `extern` in one module may be exported by
+    // another.
+    data.externs
+        .retain(|name| !functions.iter().any(|function| function.symbol == *name));
+    let mut globals = Vec::new();
+    for (name, ty) in &program.globals {
+        let layout = layout::layout(program, ty).map_err(|message| CodegenError {
+            function: name.clone(),
+            message,
+        })?;
+        globals.push((mono::symbol(name, &[]), layout));
+    }
+    if target == Target::Freestanding {
+        globals.extend(boot::globals());
+    }
+    for vtable in &program.mono.vtables {
+        let name = mono::vtable_symbol(&vtable.ty, &vtable.protocol);
+        let error = |message| CodegenError {
+            function: name.clone(),
+            message,
+        };
+        let layout = layout::layout(program, &vtable.ty).map_err(error)?;
+        let mut methods = Vec::new();
+        for method in &vtable.methods {
+            let (function, args) =
+                mono::find(program, &method.func, method.args.clone()).map_err(error)?;
+            methods.push(mono::function_symbol(function, &args));
+        }
+        data.vtables.push(VtableData {
+            symbol: name.clone(),
+            size: layout.size,
+            align: layout.align,
+            methods,
+        });
+    }
+    Ok(Compiled {
+        functions,
+        data,
+        globals,
+        target,
+    })
+}
+
+/// Compiles `program` for `target` to assembly in `syntax`.
+pub fn emit_asm(program: &Program, target: Target, syntax: Syntax) -> Result<String, CodegenError> {
+    let compiled = compile(program, target)?;
+    Ok(emit::assembly(&compiled, syntax))
+}
+
+/// Compiles `program` for `target` to an ELF relocatable object.
+pub fn emit_object(program: &Program, target: Target) -> Result<Vec<u8>, CodegenError> {
+    let compiled = compile(program, target)?;
+    Ok(elf::write(&object::object(&compiled)?))
+}
@@ -0,0 +1,286 @@
+//! Writes a compiled program as an ELF object, the way the assembler would
+//! from the output of `emit.rs`: the same sections, symbols and layout.
+
+use super::Compiled;
+use super::boot::{self, Piece};
+use super::encode::{self, Relocation};
+use super::isel::Data;
+use crate::codegen::CodegenError;
+use crate::codegen::elf::{
+    Binding, Object, R_X86_64_32, R_X86_64_64, R_X86_64_PC32, Reloc, SHF_ALLOC, SHF_EXECINSTR,
+    SHF_WRITE, Section, SectionKind, Symbol, SymbolKind,
+};
+use crate::mono::VTABLE_HEADER;
+use crate::target::Target;
+use std::collections::HashMap;
+
+const TEXT: usize = 0;
+const RODATA: usize = 1;
+const DATA: usize = 2;
+const BSS: usize = 3;
+
+pub fn object(compiled: &Compiled) -> Result<Object, CodegenError> {
+    let mut writer = Writer {
+        object: Object {
+            sections: vec![
+                Section::new(".text", SectionKind::Progbits, SHF_ALLOC | SHF_EXECINSTR, 1),
+                Section::new(".rodata", SectionKind::Progbits, SHF_ALLOC, 1),
+                Section::new(".data", SectionKind::Progbits, SHF_ALLOC | SHF_WRITE, 1),
+                Section::new(".bss", SectionKind::Nobits, SHF_ALLOC | SHF_WRITE, 1),
+                // Marks the stack as not executable.
+                Section::new(".note.GNU-stack", SectionKind::Progbits, 0, 1),
+            ],
+            symbols: Vec::new(),
+        },
+        indices: HashMap::new(),
+    };
+
+    // Relocations by the index of the section they apply to.
+    let mut relocs = Vec::new();
+    for (i, (name, functions)) in compiled.sections().into_iter().enumerate() {
+        let section = if i == 0 {
+            TEXT
+        } else {
+            let flags = SHF_ALLOC | SHF_EXECINSTR;
+            let section = Section::new(name, SectionKind::Progbits, flags, 1);
+            writer.object.sections.push(section);
+            writer.object.sections.len() - 1
+        };
+        for function in functions {
+            let encoded = encode::function(function).map_err(|message| CodegenError {
+                function: function.symbol.clone(),
+                message,
+            })?;
+            let code = &mut writer.object.sections[section];
+            if let Some(align) = function.align {
+                code.align_to(align, 0x90);
+            }
+            let offset = code.append(&encoded.code);
+            relocs.extend(encoded.relocs.into_iter().map(|reloc| {
+                let reloc = Relocation {
+                    offset: offset + reloc.offset,
+                    ..reloc
+                };
+                (section, reloc)
+            }));
+            let binding = if function.global {
+                Binding::Global
+            } else {
+                Binding::Local
+            };
+            writer.define(
+                &function.symbol,
+                section,
+                offset,
+                encoded.code.len() as u64,
+                binding,
+                SymbolKind::Func,
+            );
+        }
+        if i == 0 && compiled.target == Target::Freestanding {
+            relocs.extend(writer.boot().into_iter().map(|reloc| (TEXT, reloc)));
+        }
+    }
+
+    writer.data(&compiled.data);
+    for (symbol, layout) in &compiled.globals {
+        let bss = &mut writer.object.sections[BSS];
+        bss.align_to(layout.align.max(1), 0);
+        let offset = bss.reserve(layout.size);
+        writer.define(
+            symbol,
+            BSS,
+            offset,
+            layout.size,
+            Binding::Local,
+            SymbolKind::Object,
+        );
+    }
+
+    for (section, reloc) in relocs {
+        let symbol = writer.symbol(&reloc.symbol);
+        writer.object.sections[section].relocs.push(Reloc {
+            offset: reloc.offset,
+            symbol,
+            kind: reloc.kind,
+            addend: reloc.addend,
+        });
+    }
+    Ok(writer.object)
+}
+
+struct Writer {
+    object: Object,
+    indices: HashMap<String, usize>,
+}
+
+impl Writer {
+    fn define(
+        &mut self,
+        name: &str,
+        section: usize,
+        value: u64,
+        size: u64,
+        binding: Binding,
+        kind: SymbolKind,
+    ) {
+        self.indices
+            .insert(name.to_string(), self.object.symbols.len());
+        self.object.symbols.push(Symbol {
+            name: name.to_string(),
+            section: Some(section),
+            value,
+            size,
+            binding,
+            kind,
+        });
+    }
+
+    /// The index of the symbol `name`, which is undefined unless the
+    /// program defines it.
+    fn symbol(&mut self, name: &str) -> usize {
+        if let Some(&index) = self.indices.get(name) {
+            return index;
+        }
+        let index = self.object.symbol_index(name);
+        self.indices.insert(name.to_string(), index);
+        index
+    }
+
+    /// Appends `_start32` to `.text` and adds the Multiboot2 header's
+    /// section, returning the relocations of the code.
+    fn boot(&mut self) -> Vec<Relocation> {
+        let text = &mut self.object.sections[TEXT];
+        let start = text.size;
+        let mut relocs = Vec::new();
+        for piece in boot::CODE.iter().flat_map(|(_, pieces)| *pieces) {
+            let (symbol, kind, addend) = match *piece {
+                Piece::Bytes(bytes) => {
+                    text.append(bytes);
+                    continue;
+                }
+                Piece::Abs32(symbol, addend) => (symbol, R_X86_64_32, addend),
+                // The assembler resolves offsets within `.text` itself.
+                Piece::Rel32(symbol, addend)
+                    if let Some(&index) = self.indices.get(symbol)
+                        && self.object.symbols[index].section == Some(TEXT) =>
+                {
+                    let offset =
+                        self.object.symbols[index].value as i64 + addend - text.size as i64;
+                    text.append(&(offset as i32).to_le_bytes());
+                    continue;
+                }
+                Piece::Rel32(symbol, addend) => (symbol, R_X86_64_PC32, addend),
+            };
+            relocs.push(Relocation {
+                offset: text.append(&[0; 4]),
+                symbol: symbol.to_string(),
+                kind,
+                addend,
+            });
+        }
+        let size = text.size - start;
+        self.define(
+            boot::ENTRY,
+            TEXT,
+            start,
+            size,
+            Binding::Global,
+            SymbolKind::Func,
+        );
+
+        let mut header = Section::new(boot::HEADER_SECTION, SectionKind::Progbits, SHF_ALLOC, 8);
+        for word in boot::header() {
+            header.append(&word.to_le_bytes());
+        }
+        self.object.sections.push(header);
+        relocs
+    }
+
+    /// Lays out the constants as `emit.rs` does.
+    fn data(&mut self, data: &Data) {
+        let rodata = &mut self.object.sections[RODATA];
+        let mut labels = Vec::new();
+        if data.sign_mask {
+            rodata.align_to(16, 0);
+            let offset = rodata.append(&0x8000_0000_0000_0000u64.to_le_bytes());
+            rodata.append(&[0; 8]);
+            labels.push((Data::SIGN_MASK.to_string(), offset, 16));
+        }
+        for (i, bits) in data.floats.iter().enumerate() {
+            rodata.align_to(8, 0);
+            let offset = rodata.append(&bits.to_le_bytes());
+            labels.push((Data::float_label(i), offset, 8));
+        }
+        for (i, string) in data.strings.iter().enumerate() {
+            let offset = rodata.append(string.as_bytes());
+            labels.push((Data::bytes_label(i), offset, string.len() as u64));
+        }
+        for (i, string) in data.cstrings.iter().enumerate() {
+            let offset = rodata.append(string.as_bytes());
+            rodata.append(&[0]);
+            labels.push((Data::cstring_label(i), offset, string.len() as u64 + 1));
+        }
+        for (label, offset, size) in labels {
+            self.define(
+                &label,
+                RODATA,
+                offset,
+                size,
+                Binding::Local,
+                SymbolKind::NoType,
+            );
+        }
+
+        // String headers point to their bytes.
+        for (i, string) in data.strings.iter().enumerate() {
+            let bytes = self.symbol(&Data::bytes_label(i));
+            let section = &mut self.object.sections[DATA];
+            let offset = section.align_to(8, 0);
+            section.append(&[0; 8]);
+            section.append(&(string.len() as u64).to_le_bytes());
+            section.relocs.push(Reloc {
+                offset,
+                symbol: bytes,
+                kind: R_X86_64_64,
+                addend: 0,
+            });
+            self.define(
+                &Data::string_label(i),
+                DATA,
+                offset,
+                16,
+                Binding::Local,
+                SymbolKind::NoType,
+            );
+        }
+
+        // Vtables point to their methods.
+        for vtable in &data.vtables {
+            let methods: Vec<_> = vtable.methods.iter().map(|m| self.symbol(m)).collect();
+            let section = &mut self.object.sections[DATA];
+            let offset = section.align_to(8, 0);
+            section.append(&[0; 8]);
+            section.append(&vtable.size.to_le_bytes());
+            section.append(&vtable.align.to_le_bytes());
+            for symbol in methods {
+                let slot = section.append(&[0; 8]);
+                section.relocs.push(Reloc {
+                    offset: slot,
+                    symbol,
+                    kind: R_X86_64_64,
+                    addend: 0,
+                });
+            }
+            let size = 8 * (VTABLE_HEADER.len() + vtable.methods.len()) as u64;
+            self.define(
+                &vtable.symbol,
+                DATA,
+                offset,
+                size,
+                Binding::Local,
+                SymbolKind::NoType,
+            );
+        }
+    }
+}
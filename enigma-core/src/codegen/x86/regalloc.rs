@@ -0,0 +1,672 @@
+//! Register allocation by linear scan, and frame layout.
+//!
+//! Every virtual register gets one live interval, from the first position it
+//! is live at to the last, in the order blocks are laid out; holes are
+//! ignored. Each instruction has two positions: its operands are read at the
+//! first and its results written at the second. Physical registers the
+//! instructions name themselves, such as argument registers and those a call
+//! overwrites, are reserved at the positions they are live or written at,
+//! so no interval overlapping them gets them.
+//!
+//! Intervals are then visited by start. Each gets a register that is free
+//! for all of it, caller-saved ones first so only values live across calls
+//! take the callee-saved ones. When none is free, the interval ending last,
+//! this one or one already allocated, is spilled to the frame for its whole
+//! length: each instruction using it loads it into a scratch register first
+//! and stores it back after.
+
+use super::lir::*;
+use crate::codegen::layout::align_to;
+use std::collections::HashMap;
+
+/// Integer registers to allocate, in order of preference.
+const INT_REGS: [Gpr; 12] = [
+    Gpr::Rax,
+    Gpr::Rcx,
+    Gpr::Rdx,
+    Gpr::Rsi,
+    Gpr::Rdi,
+    Gpr::R8,
+    Gpr::R9,
+    Gpr::Rbx,
+    Gpr::R12,
+    Gpr::R13,
+    Gpr::R14,
+    Gpr::R15,
+];
+
+/// Registers left out of allocation for loading spilled values.
+const INT_SCRATCH: [Gpr; 2] = [Gpr::R10, Gpr::R11];
+
+const FLOAT_REGS: u8 = 14;
+const FLOAT_SCRATCH: [u8; 2] = [14, 15];
+
+/// How an instruction accesses a register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Use,
+    Def,
+    UseDef,
+}
+
+impl Access {
+    fn reads(self) -> bool {
+        self != Access::Def
+    }
+
+    fn writes(self) -> bool {
+        self != Access::Use
+    }
+}
+
+/// Calls `f` on every register `inst` names, and how it is accessed.
+fn visit(inst: &mut Inst, f: &mut impl FnMut(&mut Reg, Access)) {
+    let mem = |mem: &mut Mem, f: &mut dyn FnMut(&mut Reg, Access)| {
+        if let Base::Reg(reg) = &mut mem.base {
+            f(reg, Access::Use);
+        }
+    };
+    let operand = |operand: &mut Operand, access: Access, f: &mut dyn FnMut(&mut Reg, Access)| {
+        match operand {
+            Operand::Reg(reg) => f(reg, access),
+            Operand::Mem(m) => mem(m, f),
+            Operand::Imm(_) => {}
+        }
+    };
+    match inst {
+        Inst::Mov { dst, src, .. } => {
+            operand(src, Access::Use, f);
+            operand(dst, Access::Def, f);
+        }
+        Inst::MovZx { dst, src, .. } => {
+            operand(src, Access::Use, f);
+            f(dst, Access::Def);
+        }
+        Inst::Lea { dst, src } => {
+            mem(src, f);
+            f(dst, Access::Def);
+        }
+        Inst::Alu { op, dst, src, .. } => {
+            operand(src, Access::Use, f);
+            let access = if op.compares() {
+                Access::Use
+            } else {
+                Access::UseDef
+            };
+            operand(dst, access, f);
+        }
+        Inst::Imul { dst, src, .. } | Inst::Cmov { dst, src, .. } => {
+            operand(src, Access::Use, f);
+            f(dst, Access::UseDef);
+        }
+        Inst::Neg { dst, .. } | Inst::Not { dst, .. } | Inst::Shift { dst, .. } => {
+            operand(dst, Access::UseDef, f)
+        }
+        Inst::Div { src, .. } => operand(src, Access::Use, f),
+        Inst::Atomic { op, dst, src, .. } => {
+            mem(dst, f);
+            let access = match op {
+                AtomicOp::Cmpxchg => Access::Use,
+                _ => Access::UseDef,
+            };
+            f(src, access);
+        }
+        Inst::Setcc { dst, .. } => f(dst, Access::Def),
+        Inst::Sse { op, dst, src } => {
+            operand(src, Access::Use, f);
+            let access = match op {
+                SseOp::Movsd => Access::Def,
+                SseOp::Ucomisd => Access::Use,
+                _ => Access::UseDef,
+            };
+            operand(dst, access, f);
+        }
+        Inst::Movq { dst, src } => {
+            f(src, Access::Use);
+            f(dst, Access::Def);
+        }
+        Inst::Call {
+            target,
+            args,
+            results,
+        } => {
+            if let CallTarget::Reg(reg) = target {
+                f(reg, Access::Use);
+            }
+            for arg in args {
+                f(arg, Access::Use);
+            }
+            for result in results {
+                f(result, Access::Def);
+            }
+        }
+        Inst::Ret { results } => {
+            for result in results {
+                f(result, Access::Use);
+            }
+        }
+        // Named here rather than as implicit registers so that frame
+        // layout saves the callee-saved ones the assembly overwrites.
+        Inst::Asm {
+            uses,
+            defs,
+            clobbers,
+            ..
+        } => {
+            for reg in uses {
+                f(reg, Access::Use);
+            }
+            for reg in defs.iter_mut().chain(clobbers) {
+                f(reg, Access::Def);
+            }
+        }
+        Inst::Cqo
+        | Inst::Jmp(_)
+        | Inst::Jcc(..)
+        | Inst::Ud2
+        | Inst::Push(_)
+        | Inst::Pop(_)
+        | Inst::In { .. }
+        | Inst::Out { .. }
+        | Inst::Fence(_) => {}
+    }
+}
+
+/// The physical registers `inst` reads and writes without naming them.
+fn implicit(inst: &Inst) -> (Vec<Reg>, Vec<Reg>) {
+    let gprs = |gprs: &[Gpr]| gprs.iter().map(|&gpr| Reg::Gpr(gpr)).collect::<Vec<_>>();
+    let xmms = || (0..16).map(Reg::Xmm);
+    match inst {
+        Inst::Cqo => (gprs(&[Gpr::Rax]), gprs(&[Gpr::Rdx])),
+        Inst::Div { .. } => (gprs(&[Gpr::Rax, Gpr::Rdx]), gprs(&[Gpr::Rax, Gpr::Rdx])),
+        Inst::In { .. } => (gprs(&[Gpr::Rdx]), gprs(&[Gpr::Rax])),
+        Inst::Out { .. } => (gprs(&[Gpr::Rax, Gpr::Rdx]), Vec::new()),
+        Inst::Atomic {
+            op: AtomicOp::Cmpxchg,
+            ..
+        } => (gprs(&[Gpr::Rax]), gprs(&[Gpr::Rax])),
+        Inst::Call { .. } => {
+            let mut clobbers = gprs(&Gpr::CALLER_SAVED);
+            clobbers.extend(xmms());
+            (Vec::new(), clobbers)
+        }
+        _ => (Vec::new(), Vec::new()),
+    }
+}
+
+/// Registers numbered densely: general purpose ones first, then `xmm`
+/// ones, then virtual ones.
+fn index(reg: Reg) -> usize {
+    match reg {
+        Reg::Gpr(gpr) => gpr.number() as usize,
+        Reg::Xmm(n) => 16 + n as usize,
+        Reg::Virt(n, _) => 32 + n as usize,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct BitSet(Vec<u64>);
+
+impl BitSet {
+    fn new(len: usize) -> Self {
+        BitSet(vec![0; len.div_ceil(64)])
+    }
+
+    fn insert(&mut self, i: usize) {
+        self.0[i / 64] |= 1 << (i % 64);
+    }
+
+    fn remove(&mut self, i: usize) {
+        self.0[i / 64] &= !(1 << (i % 64));
+    }
+
+    fn union(&mut self, other: &BitSet) {
+        for (word, other) in self.0.iter_mut().zip(&other.0) {
+            *word |= other;
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().enumerate().flat_map(|(w, &word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| w * 64 + bit)
+        })
+    }
+}
+
+/// Allocates registers for `function` and lays out its frame, leaving
+/// only physical registers and memory relative to `rbp`, `rsp` and `rip`.
+pub fn allocate(function: &mut MFunction) {
+    let assignment = assign(function);
+    let mut spilled: Vec<u32> = assignment
+        .iter()
+        .filter(|(_, reg)| reg.is_none())
+        .map(|(&vreg, _)| vreg)
+        .collect();
+    spilled.sort();
+    let mut spills = HashMap::new();
+    for vreg in spilled {
+        function.slots.push((8, 8));
+        spills.insert(vreg, function.slots.len() as u32 - 1);
+    }
+    for block in &mut function.blocks {
+        let insts = std::mem::take(&mut block.insts);
+        for inst in insts {
+            rewrite(inst, &assignment, &spills, &mut block.insts);
+        }
+    }
+    if !function.naked {
+        layout_frame(function);
+    }
+    cleanup(function);
+}
+
+/// The register of every virtual register, or `None` if it is spilled.
+fn assign(function: &mut MFunction) -> HashMap<u32, Option<Reg>> {
+    let len = 32 + function.vregs as usize;
+    let labels: HashMap<String, usize> = function
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(b, block)| (block.label.clone(), b))
+        .collect();
+    let mut starts = Vec::new();
+    let mut count = 0;
+    for block in &function.blocks {
+        starts.push(count);
+        count += block.insts.len();
+    }
+    let accesses: Vec<Vec<(Reg, Access)>> = function
+        .blocks
+        .iter_mut()
+        .flat_map(|block| &mut block.insts)
+        .map(|inst| {
+            let mut accesses = Vec::new();
+            visit(inst, &mut |reg, access| accesses.push((*reg, access)));
+            let (uses, defs) = implicit(inst);
+            accesses.extend(uses.into_iter().map(|reg| (reg, Access::Use)));
+            accesses.extend(defs.into_iter().map(|reg| (reg, Access::Def)));
+            accesses
+        })
+        .collect();
+    let successors: Vec<Vec<usize>> = function
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(b, block)| {
+            let mut successors = Vec::new();
+            for inst in &block.insts {
+                if let Inst::Jmp(label) | Inst::Jcc(_, label) = inst {
+                    successors.push(labels[label]);
+                }
+            }
+            let falls_through = block.insts.last().is_none_or(|inst| !inst.ends_block());
+            if falls_through && b + 1 < function.blocks.len() {
+                successors.push(b + 1);
+            }
+            successors
+        })
+        .collect();
+
+    // Registers live on entry to each block, to a fixed point.
+    let transfer = |b: usize, live: &mut BitSet, mark: &mut dyn FnMut(usize, usize)| {
+        let block = &function.blocks[b];
+        for i in (0..block.insts.len()).rev() {
+            let at = starts[b] + i;
+            for reg in live.iter() {
+                mark(reg, 2 * at + 1);
+                mark(reg, 2 * at + 2);
+            }
+            for &(reg, access) in &accesses[at] {
+                if access.writes() {
+                    mark(index(reg), 2 * at + 1);
+                    live.remove(index(reg));
+                }
+            }
+            for &(reg, access) in &accesses[at] {
+                if access.reads() {
+                    mark(index(reg), 2 * at);
+                    live.insert(index(reg));
+                }
+            }
+        }
+    };
+    let mut live_in = vec![BitSet::new(len); function.blocks.len()];
+    loop {
+        let mut changed = false;
+        for b in (0..function.blocks.len()).rev() {
+            let mut live = BitSet::new(len);
+            for &succ in &successors[b] {
+                live.union(&live_in[succ]);
+            }
+            transfer(b, &mut live, &mut |_, _| {});
+            if live != live_in[b] {
+                live_in[b] = live;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    // Intervals of virtual registers, and where physical ones are taken.
+    let positions = 2 * count + 2;
+    let mut intervals: Vec<Option<(usize, usize)>> = vec![None; function.vregs as usize];
+    let mut taken = vec![vec![false; positions]; 32];
+    for (b, block_successors) in successors.iter().enumerate() {
+        let mut live = BitSet::new(len);
+        for &succ in block_successors {
+            live.union(&live_in[succ]);
+        }
+        transfer(b, &mut live, &mut |reg, position| {
+            if reg < 32 {
+                taken[reg][position] = true;
+            } else {
+                let interval = &mut intervals[reg - 32];
+                *interval = Some(match *interval {
+                    Some((start, end)) => (start.min(position), end.max(position)),
+                    None => (position, position),
+                });
+            }
+        });
+    }
+    // How many positions up to each one a register is taken at.
+    let taken: Vec<Vec<usize>> = taken
+        .into_iter()
+        .map(|taken| {
+            let mut sum = 0;
+            let mut prefix = vec![0];
+            for taken in taken {
+                sum += taken as usize;
+                prefix.push(sum);
+            }
+            prefix
+        })
+        .collect();
+    let is_free = |reg: Reg, start: usize, end: usize| {
+        let taken = &taken[index(reg)];
+        taken[end + 1] == taken[start]
+    };
+
+    let mut order: Vec<(usize, usize, u32)> = intervals
+        .iter()
+        .enumerate()
+        .filter_map(|(v, interval)| interval.map(|(start, end)| (start, end, v as u32)))
+        .collect();
+    order.sort();
+    let classes: HashMap<u32, RegClass> = function
+        .blocks
+        .iter_mut()
+        .flat_map(|block| &mut block.insts)
+        .flat_map(|inst| {
+            let mut vregs = Vec::new();
+            visit(inst, &mut |reg, _| {
+                if let Reg::Virt(n, class) = *reg {
+                    vregs.push((n, class));
+                }
+            });
+            vregs
+        })
+        .collect();
+    let mut assignment = HashMap::new();
+    let mut active: Vec<(usize, u32, Reg)> = Vec::new();
+    for (start, end, vreg) in order {
+        active.retain(|&(active_end, _, _)| active_end >= start);
+        let class = classes[&vreg];
+        let candidates: Vec<Reg> = match class {
+            RegClass::Int => INT_REGS.iter().map(|&gpr| Reg::Gpr(gpr)).collect(),
+            RegClass::Float => (0..FLOAT_REGS).map(Reg::Xmm).collect(),
+        };
+        let free = candidates.iter().copied().find(|&reg| {
+            is_free(reg, start, end) && active.iter().all(|&(_, _, used)| used != reg)
+        });
+        if let Some(reg) = free {
+            assignment.insert(vreg, Some(reg));
+            active.push((end, vreg, reg));
+            continue;
+        }
+        // Spill whichever ends last, this interval or an active one whose
+        // register it could take.
+        let victim = active
+            .iter()
+            .enumerate()
+            .filter(|(_, (active_end, _, reg))| {
+                *active_end > end && reg.class() == class && is_free(*reg, start, end)
+            })
+            .max_by_key(|(_, (active_end, _, _))| *active_end)
+            .map(|(i, _)| i);
+        match victim {
+            Some(i) => {
+                let (_, spilled, reg) = active.remove(i);
+                assignment.insert(spilled, None);
+                assignment.insert(vreg, Some(reg));
+                active.push((end, vreg, reg));
+            }
+            None => {
+                assignment.insert(vreg, None);
+            }
+        }
+    }
+    assignment
+}
+
+/// Replaces the virtual registers of `inst` by their registers, loading
+/// spilled ones into scratch registers before it and storing them after.
+fn rewrite(
+    mut inst: Inst,
+    assignment: &HashMap<u32, Option<Reg>>,
+    spills: &HashMap<u32, u32>,
+    out: &mut Vec<Inst>,
+) {
+    let mut scratch: Vec<(u32, Reg)> = Vec::new();
+    let mut loads = Vec::new();
+    let mut stores = Vec::new();
+    visit(&mut inst, &mut |reg, access| {
+        let Reg::Virt(n, class) = *reg else {
+            return;
+        };
+        if let Some(Some(assigned)) = assignment.get(&n) {
+            *reg = *assigned;
+            return;
+        }
+        let Some(&slot) = spills.get(&n) else {
+            // Never live, so never read: any scratch register will do.
+            *reg = match class {
+                RegClass::Int => Reg::Gpr(INT_SCRATCH[0]),
+                RegClass::Float => Reg::Xmm(FLOAT_SCRATCH[0]),
+            };
+            return;
+        };
+        let assigned = match scratch.iter().find(|(vreg, _)| *vreg == n) {
+            Some(&(_, assigned)) => assigned,
+            None => {
+                let used = scratch
+                    .iter()
+                    .filter(|(_, reg)| reg.class() == class)
+                    .count();
+                let assigned = match class {
+                    RegClass::Int => Reg::Gpr(INT_SCRATCH[used]),
+                    RegClass::Float => Reg::Xmm(FLOAT_SCRATCH[used]),
+                };
+                scratch.push((n, assigned));
+                assigned
+            }
+        };
+        *reg = assigned;
+        let transfer = |dst: Operand, src: Operand| match class {
+            RegClass::Int => Inst::mov(Size::Q, dst, src),
+            RegClass::Float => Inst::Sse {
+                op: SseOp::Movsd,
+                dst,
+                src,
+            },
+        };
+        if access.reads() {
+            loads.push(transfer(assigned.into(), Mem::slot(slot).into()));
+        }
+        if access.writes() {
+            stores.push(transfer(Mem::slot(slot).into(), assigned.into()));
+        }
+    });
+    loads.dedup();
+    stores.dedup();
+    out.extend(loads);
+    out.push(inst);
+    out.extend(stores);
+}
+
+/// Places the frame slots below the saved registers, and the stack
+/// arguments of calls below them, then adds the prologue and epilogues.
+///
+/// ```text
+///   incoming stack arguments    rbp + 16
+///   return address              rbp + 8
+///   saved rbp                   rbp
+///   saved callee-saved registers
+///   slots
+///   outgoing stack arguments    rsp
+/// ```
+fn layout_frame(function: &mut MFunction) {
+    let mut saved = Vec::new();
+    for inst in function
+        .blocks
+        .iter_mut()
+        .flat_map(|block| &mut block.insts)
+    {
+        visit(inst, &mut |reg, access| {
+            if let Reg::Gpr(gpr) = *reg
+                && access.writes()
+                && Gpr::CALLEE_SAVED.contains(&gpr)
+                && !saved.contains(&gpr)
+            {
+                saved.push(gpr);
+            }
+        });
+    }
+    saved.sort();
+    let mut offset = 8 * saved.len() as u64;
+    let mut order: Vec<usize> = (0..function.slots.len()).collect();
+    order.sort_by_key(|&slot| std::cmp::Reverse(function.slots[slot].1));
+    let mut offsets = vec![0; function.slots.len()];
+    for slot in order {
+        let (size, align) = function.slots[slot];
+        offset = align_to(offset + size, align);
+        offsets[slot] = -(offset as i64);
+    }
+    let frame_size = align_to(offset + function.outgoing, 16);
+
+    let resolve = |mem: &mut Mem| match mem.base {
+        Base::Slot(slot) => {
+            mem.base = Base::Reg(Reg::Gpr(Gpr::Rbp));
+            mem.disp += offsets[slot as usize];
+        }
+        Base::Incoming => {
+            mem.base = Base::Reg(Reg::Gpr(Gpr::Rbp));
+            mem.disp += 16;
+        }
+        Base::Outgoing => mem.base = Base::Reg(Reg::Gpr(Gpr::Rsp)),
+        Base::Reg(_) | Base::Symbol(_) => {}
+    };
+    for block in &mut function.blocks {
+        let mut insts = Vec::new();
+        for mut inst in std::mem::take(&mut block.insts) {
+            for mem in mems(&mut inst) {
+                resolve(mem);
+            }
+            if let Inst::Ret { .. } = inst {
+                insts.extend(epilogue(&saved));
+            }
+            insts.push(inst);
+        }
+        block.insts = insts;
+    }
+    let mut prologue = vec![Inst::Push(Gpr::Rbp), Inst::mov(Size::Q, Gpr::Rbp, Gpr::Rsp)];
+    prologue.extend(saved.iter().map(|&gpr| Inst::Push(gpr)));
+    let below = frame_size - 8 * saved.len() as u64;
+    if below > 0 {
+        prologue.push(Inst::alu(
+            AluOp::Sub,
+            Size::Q,
+            Gpr::Rsp,
+            Operand::Imm(below as i64),
+        ));
+    }
+    let entry = &mut function.blocks[0].insts;
+    entry.splice(0..0, prologue);
+    function.frame_size = frame_size;
+    function.saved = saved;
+}
+
+fn epilogue(saved: &[Gpr]) -> Vec<Inst> {
+    let mut insts = vec![match saved.len() {
+        0 => Inst::mov(Size::Q, Gpr::Rsp, Gpr::Rbp),
+        n => Inst::Lea {
+            dst: Reg::Gpr(Gpr::Rsp),
+            src: Mem::reg(Gpr::Rbp, -8 * n as i64),
+        },
+    }];
+    insts.extend(saved.iter().rev().map(|&gpr| Inst::Pop(gpr)));
+    insts.push(Inst::Pop(Gpr::Rbp));
+    insts
+}
+
+/// The memory operands of `inst`.
+fn mems(inst: &mut Inst) -> Vec<&mut Mem> {
+    let operands: Vec<&mut Operand> = match inst {
+        Inst::Mov { dst, src, .. } | Inst::Alu { dst, src, .. } | Inst::Sse { dst, src, .. } => {
+            vec![dst, src]
+        }
+        Inst::MovZx { src, .. }
+        | Inst::Imul { src, .. }
+        | Inst::Cmov { src, .. }
+        | Inst::Div { src, .. } => vec![src],
+        Inst::Neg { dst, .. } | Inst::Not { dst, .. } | Inst::Shift { dst, .. } => vec![dst],
+        Inst::Lea { src, .. } | Inst::Atomic { dst: src, .. } => return vec![src],
+        _ => Vec::new(),
+    };
+    operands
+        .into_iter()
+        .filter_map(|operand| match operand {
+            Operand::Mem(mem) => Some(mem),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Drops moves of a register to itself and jumps to the next block, and
+/// turns a conditional jump over an unconditional one into a single jump.
+fn cleanup(function: &mut MFunction) {
+    for block in &mut function.blocks {
+        block.insts.retain(|inst| match inst {
+            Inst::Mov {
+                size: Size::Q,
+                dst: Operand::Reg(dst),
+                src: Operand::Reg(src),
+            } => dst != src,
+            Inst::Sse {
+                op: SseOp::Movsd,
+                dst: Operand::Reg(dst),
+                src: Operand::Reg(src),
+            } => dst != src,
+            _ => true,
+        });
+    }
+    for b in 0..function.blocks.len().saturating_sub(1) {
+        let next = function.blocks[b + 1].label.clone();
+        let insts = &mut function.blocks[b].insts;
+        if let Some(Inst::Jmp(target)) = insts.last()
+            && *target == next
+        {
+            insts.pop();
+        }
+        if let [.., Inst::Jcc(cond, over), Inst::Jmp(target)] = insts.as_mut_slice()
+            && *over == next
+        {
+            let jump = Inst::Jcc(cond.negate(), target.clone());
+            insts.truncate(insts.len() - 2);
+            insts.push(jump);
+        }
+    }
+}
@@ -1,8 +1,265 @@
-pub struct ErrorHandler{
+use crate::lexer::size::Span;
+use std::fmt::Write;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
 }
 
-impl ErrorHandler{
-    
+/// A secondary span pointing at something related to the main error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
 }
 
+/// A machine applicable edit: replace `span` with `replacement`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FixIt {
+    pub span: Span,
+    pub replacement: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub fixits: Vec<FixIt>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Span) -> Self {
+        Self::new(Severity::Error, message.into(), span)
+    }
+
+    pub fn warning(message: impl Into<String>, span: Span) -> Self {
+        Self::new(Severity::Warning, message.into(), span)
+    }
+
+    fn new(severity: Severity, message: String, span: Span) -> Self {
+        Self {
+            severity,
+            message,
+            span,
+            labels: Vec::new(),
+            notes: Vec::new(),
+            fixits: Vec::new(),
+        }
+    }
+
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_fixit(
+        mut self,
+        span: Span,
+        replacement: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        self.fixits.push(FixIt {
+            span,
+            replacement: replacement.into(),
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+/// Collects diagnostics from every phase so a single run can report as many
+/// problems as possible instead of stopping at the first one.
+#[derive(Debug, Default)]
+pub struct ErrorHandler {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl ErrorHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn emit(&mut self, diagnostic: Diagnostic) {
+        self.diagnostics.push(diagnostic);
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(Diagnostic::is_error)
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.diagnostics.iter().filter(|d| d.is_error()).count()
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn take(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    /// Renders every diagnostic against `source` in a rustc-like layout.
+    pub fn render(&self, source: &str, file_name: &str) -> String {
+        let mut out = String::new();
+        for diagnostic in &self.diagnostics {
+            render_diagnostic(&mut out, diagnostic, source, file_name);
+        }
+        out
+    }
+
+    /// Like `render`, for diagnostics whose spans point into several files.
+    /// Each diagnostic is shown against the file its primary span is in.
+    pub fn render_with(&self, sources: &SourceMap) -> String {
+        let mut out = String::new();
+        for diagnostic in &self.diagnostics {
+            let Some(file) = sources.lookup(diagnostic.span.start) else {
+                continue;
+            };
+            let mut local = diagnostic.clone();
+            local.span = file.local(local.span);
+            // Labels in other files can't be drawn in this snippet.
+            local.labels.retain(|label| file.contains(label.span.start));
+            for label in &mut local.labels {
+                label.span = file.local(label.span);
+            }
+            for fixit in &mut local.fixits {
+                fixit.span = file.local(fixit.span);
+            }
+            render_diagnostic(&mut out, &local, &file.source, &file.name);
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub name: String,
+    pub source: String,
+    /// Offset of the first byte of this file in the `SourceMap`.
+    pub base: usize,
+}
+
+impl SourceFile {
+    pub fn contains(&self, offset: usize) -> bool {
+        offset >= self.base && offset <= self.base + self.source.len()
+    }
+
+    /// `span` relative to the start of this file.
+    pub fn local(&self, span: Span) -> Span {
+        Span::new(
+            span.start.saturating_sub(self.base),
+            span.end.saturating_sub(self.base),
+        )
+    }
+}
+
+/// Every loaded file laid end to end in one offset space, so a `Span` alone
+/// identifies the file it points into.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file and returns its base offset.
+    pub fn add(&mut self, name: impl Into<String>, source: impl Into<String>) -> usize {
+        // One byte of padding keeps an end-of-file span inside its own file.
+        let base = self
+            .files
+            .last()
+            .map_or(0, |file| file.base + file.source.len() + 1);
+        self.files.push(SourceFile {
+            name: name.into(),
+            source: source.into(),
+            base,
+        });
+        base
+    }
+
+    pub fn lookup(&self, offset: usize) -> Option<&SourceFile> {
+        self.files.iter().rev().find(|file| file.base <= offset)
+    }
+
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+}
+
+/// 1-based (line, column) of a byte offset.
+pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let offset = offset.min(source.len());
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let col = source[line_start..offset].chars().count() + 1;
+    (line, col)
+}
+
+fn render_diagnostic(out: &mut String, diagnostic: &Diagnostic, source: &str, file_name: &str) {
+    let severity = match diagnostic.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+    };
+    let (line, col) = line_col(source, diagnostic.span.start);
+    let widest = diagnostic
+        .labels
+        .iter()
+        .map(|label| line_col(source, label.span.start).0)
+        .fold(line, usize::max);
+    let gutter = " ".repeat(widest.to_string().len());
+    let _ = writeln!(out, "{}: {}", severity, diagnostic.message);
+    let _ = writeln!(out, "{}--> {}:{}:{}", gutter, file_name, line, col);
+    let _ = writeln!(out, "{} |", gutter);
+    render_snippet(out, &gutter, source, diagnostic.span, "");
+    for label in &diagnostic.labels {
+        render_snippet(out, &gutter, source, label.span, &label.message);
+    }
+    for note in &diagnostic.notes {
+        let _ = writeln!(out, "{} = note: {}", gutter, note);
+    }
+    for fixit in &diagnostic.fixits {
+        let (fix_line, fix_col) = line_col(source, fixit.span.start);
+        let _ = writeln!(
+            out,
+            "{} = help: {} ({}:{})",
+            gutter, fixit.message, fix_line, fix_col
+        );
+    }
+    out.push('\n');
+}
+
+fn render_snippet(out: &mut String, gutter: &str, source: &str, span: Span, message: &str) {
+    let (line, col) = line_col(source, span.start);
+    let text = source.lines().nth(line - 1).unwrap_or("");
+    let width = text.chars().count().saturating_sub(col - 1).max(1);
+    let len = source
+        .get(span.start..span.end.min(source.len()))
+        .map_or(1, |s| s.lines().next().unwrap_or("").chars().count())
+        .clamp(1, width);
+    let line_no = format!("{:>width$}", line, width = gutter.len());
+    let _ = writeln!(out, "{} | {}", line_no, text);
+    let marker = format!("{}{} {}", " ".repeat(col - 1), "^".repeat(len), message);
+    let _ = writeln!(out, "{} | {}", gutter, marker.trim_end());
+}
@@ -0,0 +1,1313 @@
+use super::*;
+use crate::checker::items::{FnSig, ItemTable, ParamSig, join_path};
+use crate::checker::resolve::Res;
+use crate::checker::{FnOwner, TypeckResults};
+use crate::loader::ModuleGraph;
+use crate::parser::ast::{self, AssignOp, ExprKind as Ast, FnBody, Ident, ItemKind, NodeId};
+use crate::prelude;
+use std::collections::HashMap;
+
+/// Lowers every module of a checked program, dependencies first.
+pub fn lower_graph(graph: &ModuleGraph, checked: &[(ItemTable, TypeckResults)]) -> Vec<Module> {
+    graph
+        .order
+        .iter()
+        .map(|&id| {
+            let (items, results) = &checked[id];
+            lower_module(&graph.modules[id].program, items, results)
+        })
+        .collect()
+}
+
+/// Lowers one checked module. The checker must have reported no errors.
+pub fn lower_module(program: &ast::Program, items: &ItemTable, results: &TypeckResults) -> Module {
+    let mut functions = Vec::new();
+    for item in &program.items {
+        match &item.kind {
+            ItemKind::Function(function) => {
+                let name = items.canonical(&function.name.name);
+                let Some(sig) = items.functions.get(&name) else {
+                    continue;
+                };
+                let func = FnRef {
+                    owner: FnOwner::Free,
+                    name,
+                };
+                functions.extend(Lowerer::new(items, results).function(func, sig, function));
+            }
+            ItemKind::Impl(imp) => {
+                let Some((type_name, _)) = items.impl_target(&imp.target) else {
+                    continue;
+                };
+                for method in &imp.methods {
+                    let function = &method.function;
+                    let Some(sig) = items.method(&type_name, &function.name.name) else {
+                        continue;
+                    };
+                    let func = FnRef {
+                        owner: FnOwner::Type(type_name.clone()),
+                        name: function.name.name.clone(),
+                    };
+                    functions.extend(Lowerer::new(items, results).function(func, sig, function));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Top level `let`s set globals; everything else runs in order.
+    let mut lowerer = Lowerer::new(items, results);
+    let mut globals = Vec::new();
+    let mut stmts = Vec::new();
+    let mut span = None;
+    for item in &program.items {
+        let ItemKind::Stmt(stmt) = &item.kind else {
+            continue;
+        };
+        span = Some(span.map_or(stmt.span, |s: Span| s.to(stmt.span)));
+        match &stmt.kind {
+            ast::StmtKind::Let { binding, init } => {
+                let name = items.canonical(&binding.name.name);
+                let ty = items
+                    .globals
+                    .get(&name)
+                    .map_or(Ty::Error, |global| global.ty.clone());
+                let place = Expr::new(
+                    ExprKind::Global(name.clone()),
+                    ty.clone(),
+                    binding.name.span,
+                );
+                let init = lowerer.expr(init);
+                stmts.push(Stmt::Expr(assign(place, init, stmt.span)));
+                globals.push((name, ty));
+            }
+            _ => lowerer.stmt(stmt, &mut stmts),
+        }
+    }
+    let span = span.unwrap_or_default();
+    let init = Body {
+        owner: BodyOwner::Init(items.module.clone()),
+        generics: Vec::new(),
+        params: Vec::new(),
+        ret: Ty::Unit,
+        attrs: FnAttrs::default(),
+        locals: lowerer.locals,
+        value: Expr::new(
+            ExprKind::Block(Block {
+                stmts,
+                value: Box::new(Expr::unit(span)),
+            }),
+            Ty::Unit,
+            span,
+        ),
+        span,
+    };
+    Module {
+        name: items.module.clone(),
+        globals,
+        functions,
+        init,
+    }
+}
+
+struct Lowerer<'a> {
+    items: &'a ItemTable,
+    results: &'a TypeckResults,
+    locals: Vec<Local>,
+    /// The local each binding declaration was lowered to.
+    bindings: HashMap<NodeId, LocalId>,
+    self_local: Option<LocalId>,
+    /// Return types of the function and of the closures being lowered,
+    /// innermost last; `?` returns to the innermost.
+    returns: Vec<Ty>,
+}
+
+impl<'a> Lowerer<'a> {
+    fn new(items: &'a ItemTable, results: &'a TypeckResults) -> Self {
+        Self {
+            items,
+            results,
+            locals: Vec::new(),
+            bindings: HashMap::new(),
+            self_local: None,
+            returns: Vec::new(),
+        }
+    }
+
+    fn function(mut self, func: FnRef, sig: &FnSig, function: &ast::Function) -> Option<Body> {
+        let body = function.body.as_ref()?;
+        let mut params = Vec::new();
+        if let (Some(kind), FnOwner::Type(ty)) = (sig.self_kind, &func.owner) {
+            let self_ty = Ty::Adt {
+                name: ty.clone(),
+                args: sig.impl_generics.iter().cloned().map(Ty::Param).collect(),
+            };
+            let (ty, mutable) = match kind {
+                ast::SelfKind::Value => (self_ty, false),
+                ast::SelfKind::MutValue => (self_ty, true),
+                ast::SelfKind::Ref | ast::SelfKind::RefMut => (
+                    Ty::Ref {
+                        mutable: kind == ast::SelfKind::RefMut,
+                        inner: Box::new(self_ty),
+                    },
+                    false,
+                ),
+            };
+            let span = function
+                .self_param
+                .as_ref()
+                .map_or(function.span, |p| p.span);
+            let local = self.push_local("self", ty, mutable, span);
+            self.self_local = Some(local);
+            params.push(local);
+        }
+        for (param, param_sig) in function.params.iter().zip(&sig.params) {
+            let local = self.push_local(
+                &param.name.name,
+                param_sig.ty.clone(),
+                param.mutable,
+                param.name.span,
+            );
+            self.bindings.insert(param.id, local);
+            params.push(local);
+        }
+        self.returns.push(sig.ret.clone());
+        let value = match body {
+            FnBody::Block(block) => {
+                let block = self.block(block);
+                Expr::new(ExprKind::Block(block), sig.ret.clone(), function.span)
+            }
+            // `-> expr;` is a block holding only its value.
+            FnBody::Inline(expr) => {
+                let value = self.expr(expr);
+                Expr::new(
+                    ExprKind::Block(Block {
+                        stmts: Vec::new(),
+                        value: Box::new(value),
+                    }),
+                    sig.ret.clone(),
+                    function.span,
+                )
+            }
+        };
+        let mut generics = sig.impl_generics.clone();
+        generics.extend(sig.generics.iter().cloned());
+        Some(Body {
+            owner: BodyOwner::Function(func),
+            generics,
+            params,
+            ret: sig.ret.clone(),
+            attrs: sig.attrs.clone(),
+            locals: self.locals,
+            value,
+            span: function.span,
+        })
+    }
+
+    // ---------------------------------------------------------------------
+    // Locals
+    // ---------------------------------------------------------------------
+
+    fn push_local(&mut self, name: &str, ty: Ty, mutable: bool, span: Span) -> LocalId {
+        let id = LocalId(self.locals.len() as u32);
+        self.locals.push(Local {
+            name: name.to_string(),
+            ty,
+            mutable,
+            span,
+        });
+        id
+    }
+
+    /// A local for the binding declared by node `id`.
+    fn declare(&mut self, id: NodeId, name: &Ident, mutable: bool) -> LocalId {
+        let ty = self.binding_ty(id);
+        let local = self.push_local(&name.name, ty, mutable, name.span);
+        self.bindings.insert(id, local);
+        local
+    }
+
+    /// A compiler-introduced local holding `init`, declared by a `let`
+    /// pushed onto `stmts`.
+    fn temp(&mut self, name: &str, init: Expr, mutable: bool, stmts: &mut Vec<Stmt>) -> Expr {
+        let (ty, span) = (init.ty.clone(), init.span);
+        let local = self.push_local(name, ty.clone(), mutable, span);
+        stmts.push(Stmt::Let { local, init });
+        Expr::new(ExprKind::Local(local), ty, span)
+    }
+
+    fn binding_ty(&self, id: NodeId) -> Ty {
+        self.results
+            .binding_types
+            .get(&id)
+            .cloned()
+            .unwrap_or(Ty::Error)
+    }
+
+    fn local(&self, local: LocalId, span: Span) -> Expr {
+        let ty = self.locals[local.0 as usize].ty.clone();
+        Expr::new(ExprKind::Local(local), ty, span)
+    }
+
+    // ---------------------------------------------------------------------
+    // Statements
+    // ---------------------------------------------------------------------
+
+    fn block(&mut self, block: &ast::Block) -> Block {
+        let mut stmts = Vec::new();
+        let tail = block.tail();
+        let body = match tail {
+            Some(_) => &block.stmts[..block.stmts.len() - 1],
+            None => &block.stmts[..],
+        };
+        for stmt in body {
+            self.stmt(stmt, &mut stmts);
+        }
+        let value = match tail {
+            Some(tail) => self.expr(tail),
+            None => Expr::unit(Span::new(block.span.end, block.span.end)),
+        };
+        Block {
+            stmts,
+            value: Box::new(value),
+        }
+    }
+
+    /// A block used as an expression, typed by its value.
+    fn block_expr(&mut self, block: &ast::Block) -> Expr {
+        let block = self.block(block);
+        let ty = block.value.ty.clone();
+        let span = block.value.span;
+        Expr::new(ExprKind::Block(block), ty, span)
+    }
+
+    fn stmt(&mut self, stmt: &ast::Stmt, stmts: &mut Vec<Stmt>) {
+        match &stmt.kind {
+            ast::StmtKind::Let { binding, init } => {
+                let init = self.expr(init);
+                let local = self.declare(binding.id, &binding.name, binding.mutable);
+                stmts.push(Stmt::Let { local, init });
+            }
+            // `(int a, string b) $= t` is a `let` per element of `t`.
+            ast::StmtKind::Destructure { bindings, init } => {
+                let init = self.expr(init);
+                let tuple = self.temp("tuple", init, false, stmts);
+                for (index, binding) in bindings.iter().enumerate() {
+                    let name = index.to_string();
+                    let init = self.field_index(tuple.clone(), &name, binding.name.span);
+                    let local = self.declare(binding.id, &binding.name, binding.mutable);
+                    stmts.push(Stmt::Let { local, init });
+                }
+            }
+            ast::StmtKind::Assign { target, op, value } => {
+                let place = self.expr(target);
+                let value = self.expr(value);
+                let op = match op {
+                    AssignOp::Set => {
+                        stmts.push(Stmt::Expr(assign(place, value, stmt.span)));
+                        return;
+                    }
+                    AssignOp::Add => BinOp::Add,
+                    AssignOp::Sub => BinOp::Sub,
+                    AssignOp::Mul => BinOp::Mul,
+                    AssignOp::Div => BinOp::Div,
+                };
+                self.compound(place, op, value, stmt.span, stmts);
+            }
+            // `x++` is `x = x + 1`.
+            ast::StmtKind::Step { target, increment } => {
+                let place = self.expr(target);
+                let one = Expr::new(
+                    ExprKind::Literal(Literal::Int(1)),
+                    place.ty.clone(),
+                    stmt.span,
+                );
+                let op = if *increment { BinOp::Add } else { BinOp::Sub };
+                self.compound(place, op, one, stmt.span, stmts);
+            }
+            ast::StmtKind::Expr(expr) => {
+                let expr = self.expr(expr);
+                stmts.push(Stmt::Expr(expr));
+            }
+        }
+    }
+
+    /// `place = place op value`. A place whose evaluation could have side
+    /// effects is borrowed once, so it is only evaluated once.
+    fn compound(&mut self, place: Expr, op: BinOp, value: Expr, span: Span, stmts: &mut Vec<Stmt>) {
+        let place = if is_pure_place(&place) {
+            place
+        } else {
+            let ty = place.ty.clone();
+            let reference = Expr::new(
+                ExprKind::Ref {
+                    mutable: true,
+                    expr: Box::new(place),
+                },
+                Ty::Ref {
+                    mutable: true,
+                    inner: Box::new(ty.clone()),
+                },
+                span,
+            );
+            let reference = self.temp("place", reference, false, stmts);
+            Expr::new(ExprKind::Deref(Box::new(reference)), ty, span)
+        };
+        let ty = place.ty.clone();
+        let sum = Expr::new(
+            ExprKind::Binary {
+                op,
+                lhs: Box::new(place.clone()),
+                rhs: Box::new(value),
+            },
+            ty,
+            span,
+        );
+        stmts.push(Stmt::Expr(assign(place, sum, span)));
+    }
+
+    // ---------------------------------------------------------------------
+    // Expressions
+    // ---------------------------------------------------------------------
+
+    fn expr(&mut self, expr: &ast::Expr) -> Expr {
+        let lowered = self.expr_kind(expr);
+        match self.results.coercions.get(&expr.id) {
+            Some(coercion) => {
+                let mutable = matches!(lowered.ty, Ty::Ref { mutable: true, .. });
+                Expr::new(
+                    ExprKind::ToDyn {
+                        expr: Box::new(lowered),
+                        protocol: coercion.protocol.clone(),
+                    },
+                    Ty::Ref {
+                        mutable,
+                        inner: Box::new(Ty::Dyn(coercion.protocol.clone())),
+                    },
+                    expr.span,
+                )
+            }
+            None => lowered,
+        }
+    }
+
+    fn expr_kind(&mut self, expr: &ast::Expr) -> Expr {
+        let ty = self.ty_of(expr.id);
+        let span = expr.span;
+        let kind = match &expr.kind {
+            Ast::Literal(literal) => ExprKind::Literal(literal.clone()),
+            Ast::Path(segments) => return self.path(expr, segments),
+            Ast::Tuple(elems) => ExprKind::Tuple(elems.iter().map(|e| self.expr(e)).collect()),
+            Ast::Unary { op, expr: inner } => ExprKind::Unary {
+                op: *op,
+                expr: Box::new(self.expr(inner)),
+            },
+            // `a && b` is `if a { b } else { false }`, `a || b` is
+            // `if a { true } else { b }`.
+            Ast::Binary { op, lhs, rhs } if op.is_logical() => {
+                let lhs = self.expr(lhs);
+                let rhs = self.expr(rhs);
+                let constant = Expr::new(
+                    ExprKind::Literal(Literal::Bool(*op == BinOp::Or)),
+                    Ty::Bool,
+                    rhs.span,
+                );
+                let (then_branch, else_branch) = if *op == BinOp::And {
+                    (rhs, constant)
+                } else {
+                    (constant, rhs)
+                };
+                ExprKind::If {
+                    cond: Box::new(lhs),
+                    then_branch: Box::new(then_branch),
+                    else_branch: Box::new(else_branch),
+                }
+            }
+            Ast::Binary { op, lhs, rhs } => ExprKind::Binary {
+                op: *op,
+                lhs: Box::new(self.expr(lhs)),
+                rhs: Box::new(self.expr(rhs)),
+            },
+            Ast::Range { start, end } => ExprKind::Range {
+                start: Box::new(self.expr(start)),
+                end: Box::new(self.expr(end)),
+            },
+            Ast::Call { callee, args, .. } => return self.call(expr, callee, args),
+            Ast::Field { base, name } => {
+                let base = self.expr(base);
+                return self.field(base, name);
+            }
+            Ast::RecordLit { fields, .. } => return self.record(expr, fields),
+            Ast::Block(block) | Ast::Unsafe(block) => ExprKind::Block(self.block(block)),
+            Ast::If {
+                cond,
+                then_block,
+                else_branch,
+            } => ExprKind::If {
+                cond: Box::new(self.expr(cond)),
+                then_branch: Box::new(self.block_expr(then_block)),
+                else_branch: Box::new(match else_branch {
+                    Some(else_branch) => self.expr(else_branch),
+                    None => Expr::unit(Span::new(span.end, span.end)),
+                }),
+            },
+            // `while c { .. }` is `loop { if c { .. } else { break } }`.
+            Ast::While { cond, body } => {
+                let cond = self.expr(cond);
+                let body = self.block_expr(body);
+                let exit = Expr::new(
+                    ExprKind::Break(Box::new(Expr::unit(cond.span))),
+                    Ty::Never,
+                    cond.span,
+                );
+                let step = Expr::new(
+                    ExprKind::If {
+                        cond: Box::new(cond),
+                        then_branch: Box::new(body),
+                        else_branch: Box::new(exit),
+                    },
+                    Ty::Unit,
+                    span,
+                );
+                ExprKind::Loop(Block {
+                    stmts: Vec::new(),
+                    value: Box::new(step),
+                })
+            }
+            Ast::For {
+                binding,
+                binding_id,
+                iter,
+                body,
+            } => return self.for_loop(expr, binding, *binding_id, iter, body),
+            Ast::Loop(body) => ExprKind::Loop(self.block(body)),
+            Ast::Match { scrutinee, arms } => {
+                let scrutinee = self.expr(scrutinee);
+                let arms = arms
+                    .iter()
+                    .map(|arm| Arm {
+                        pattern: self.pattern(&arm.pattern, &scrutinee.ty),
+                        body: self.expr(&arm.body),
+                    })
+                    .collect();
+                ExprKind::Match {
+                    scrutinee: Box::new(scrutinee),
+                    arms,
+                }
+            }
+            Ast::Break(value) => ExprKind::Break(Box::new(self.value_or_unit(value, span))),
+            Ast::Continue => ExprKind::Continue,
+            Ast::Return(value) => ExprKind::Return(Box::new(self.value_or_unit(value, span))),
+            Ast::Ref {
+                mutable,
+                expr: inner,
+            } => ExprKind::Ref {
+                mutable: *mutable,
+                expr: Box::new(self.expr(inner)),
+            },
+            Ast::RawRef {
+                mutable,
+                expr: inner,
+            } => ExprKind::RawRef {
+                mutable: *mutable,
+                expr: Box::new(self.expr(inner)),
+            },
+            Ast::Deref(inner) => ExprKind::Deref(Box::new(self.expr(inner))),
+            Ast::Try(inner) => return self.try_expr(expr, inner),
+            Ast::Asm(block) => ExprKind::Asm(Box::new(self.asm(block))),
+            Ast::Closure(closure) => {
+                let params = closure
+                    .params
+                    .iter()
+                    .map(|param| self.declare(param.id, &param.name, param.mutable))
+                    .collect();
+                let ret = match &ty {
+                    Ty::Fn { ret, .. } => ret.as_ref().clone(),
+                    _ => Ty::Error,
+                };
+                self.returns.push(ret);
+                let body = self.expr(&closure.body);
+                self.returns.pop();
+                ExprKind::Closure {
+                    params,
+                    body: Box::new(body),
+                }
+            }
+        };
+        Expr::new(kind, ty, span)
+    }
+
+    fn ty_of(&self, id: NodeId) -> Ty {
+        self.results.type_of(id).cloned().unwrap_or(Ty::Error)
+    }
+
+    fn value_or_unit(&mut self, value: &Option<Box<ast::Expr>>, span: Span) -> Expr {
+        match value {
+            Some(value) => self.expr(value),
+            None => Expr::unit(span),
+        }
+    }
+
+    fn path(&mut self, expr: &ast::Expr, segments: &[Ident]) -> Expr {
+        let ty = self.ty_of(expr.id);
+        let kind = match self.results.resolutions.get(expr.id) {
+            Some(Res::Local(_) | Res::SelfValue | Res::Global(_)) => {
+                return self.place(expr.id, segments, expr.span);
+            }
+            Some(Res::Function(name)) => ExprKind::FnItem {
+                func: FnRef {
+                    owner: FnOwner::Free,
+                    name: name.clone(),
+                },
+                args: Vec::new(),
+            },
+            Some(Res::AssocFn { ty, name }) => ExprKind::FnItem {
+                func: FnRef {
+                    owner: FnOwner::Type(ty.clone()),
+                    name: name.clone(),
+                },
+                args: Vec::new(),
+            },
+            Some(Res::Variant { union, variant }) if matches!(ty, Ty::Fn { .. }) => {
+                ExprKind::VariantCtor {
+                    union: union.clone(),
+                    variant: variant.clone(),
+                }
+            }
+            Some(Res::Variant { union, variant }) => ExprKind::Variant {
+                union: union.clone(),
+                variant: variant.clone(),
+                fields: Vec::new(),
+            },
+            _ => ExprKind::Global(join_path(segments)),
+        };
+        Expr::new(kind, ty, expr.span)
+    }
+
+    /// The place named by `segments`: a local, `self` or a global, then
+    /// fields. `id` is the path the segments come from, which may continue
+    /// with a method name.
+    fn place(&mut self, id: NodeId, segments: &[Ident], span: Span) -> Expr {
+        let (base, fields) = match self.results.resolutions.get(id) {
+            Some(Res::Local(decl)) => {
+                let local = self.bindings[decl];
+                (self.local(local, segments[0].span), &segments[1..])
+            }
+            Some(Res::SelfValue) => {
+                let local = self.self_local.expect("`self` outside a method");
+                (self.local(local, segments[0].span), &segments[1..])
+            }
+            Some(Res::Global(name)) => {
+                let ty = self
+                    .items
+                    .globals
+                    .get(name)
+                    .map_or(Ty::Error, |global| global.ty.clone());
+                // `util::base` names another module's global outright;
+                // only a global named by its first segment has fields after
+                // it.
+                let fields = if self.items.lookup_value(&segments[0].name) == Ok(name.as_str()) {
+                    &segments[1..]
+                } else {
+                    &[][..]
+                };
+                let base = Expr::new(ExprKind::Global(name.clone()), ty, span);
+                (base, fields)
+            }
+            _ => unreachable!("not a place path"),
+        };
+        fields
+            .iter()
+            .fold(base, |base, field| self.field(base, field))
+    }
+
+    /// `base::name`, dereferencing `base` as often as it takes to reach the
+    /// record or tuple.
+    fn field(&mut self, base: Expr, name: &Ident) -> Expr {
+        self.field_index(base, &name.name, name.span)
+    }
+
+    fn field_index(&mut self, mut base: Expr, name: &str, span: Span) -> Expr {
+        while let Ty::Ref { inner, .. } = &base.ty {
+            let inner = inner.as_ref().clone();
+            let span = base.span;
+            base = Expr::new(ExprKind::Deref(Box::new(base)), inner, span);
+        }
+        let found = match &base.ty {
+            Ty::Adt { name: adt, args } => self.items.record_fields(adt, args).and_then(|fields| {
+                fields
+                    .into_iter()
+                    .enumerate()
+                    .find(|(_, (field, _))| field == name)
+                    .map(|(index, (_, ty))| (index, ty))
+            }),
+            Ty::Tuple(elems) => name
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| Some((index, elems.get(index)?.clone()))),
+            _ => None,
+        };
+        let (index, ty) = found.unwrap_or((0, Ty::Error));
+        let span = base.span.to(span);
+        Expr::new(
+            ExprKind::Field {
+                base: Box::new(base),
+                name: name.to_string(),
+                index,
+            },
+            ty,
+            span,
+        )
+    }
+
+    fn record(&mut self, expr: &ast::Expr, fields: &[ast::FieldInit]) -> Expr {
+        let ty = self.ty_of(expr.id);
+        let Ty::Adt { name, args } = &ty else {
+            return Expr::new(ExprKind::Tuple(Vec::new()), Ty::Error, expr.span);
+        };
+        let declared = self.items.record_fields(name, args).unwrap_or_default();
+        let written: Vec<(Option<usize>, Expr)> = fields
+            .iter()
+            .map(|field| {
+                let index = declared.iter().position(|(n, _)| *n == field.name.name);
+                (index, self.expr(&field.value))
+            })
+            .collect();
+        let mut stmts = Vec::new();
+        let mut values = self.in_order(written, declared.len(), &mut stmts);
+        // Fields left out are `Option`s starting as `None`.
+        let fields = declared
+            .iter()
+            .zip(&mut values)
+            .map(|((field, field_ty), value)| {
+                let value = value.take().unwrap_or_else(|| {
+                    Expr::new(
+                        ExprKind::Variant {
+                            union: prelude::OPTION.to_string(),
+                            variant: "None".to_string(),
+                            fields: Vec::new(),
+                        },
+                        field_ty.clone(),
+                        expr.span,
+                    )
+                });
+                (field.clone(), value)
+            })
+            .collect();
+        let record = Expr::new(
+            ExprKind::Record {
+                name: name.clone(),
+                fields,
+            },
+            ty.clone(),
+            expr.span,
+        );
+        wrap(stmts, record)
+    }
+
+    /// Puts `values`, written in source order, into the slots their
+    /// indices name. Source order is evaluation order, so values that would
+    /// move past one another are stored in temporaries first, unless
+    /// evaluating them can't have side effects.
+    fn in_order(
+        &mut self,
+        values: Vec<(Option<usize>, Expr)>,
+        len: usize,
+        stmts: &mut Vec<Stmt>,
+    ) -> Vec<Option<Expr>> {
+        let sorted = values
+            .windows(2)
+            .all(|pair| pair[0].0.unwrap_or(0) <= pair[1].0.unwrap_or(0));
+        let simple = values.iter().all(|(_, value)| is_simple(value));
+        let mut slots: Vec<Option<Expr>> = (0..len).map(|_| None).collect();
+        for (index, value) in values {
+            let value = if sorted || simple {
+                value
+            } else {
+                self.temp("arg", value, false, stmts)
+            };
+            if let Some(slot) = index.and_then(|index| slots.get_mut(index)) {
+                *slot = Some(value);
+            }
+        }
+        slots
+    }
+
+    // ---------------------------------------------------------------------
+    // Calls
+    // ---------------------------------------------------------------------
+
+    fn call(&mut self, expr: &ast::Expr, callee: &ast::Expr, args: &[ast::Arg]) -> Expr {
+        let ty = self.ty_of(expr.id);
+        let span = expr.span;
+        let resolution = self.results.resolutions.get(callee.id);
+        // `size_of`, `align_of` and `offset_of` were evaluated by the checker.
+        if let Some(&value) = self.results.constants.get(&expr.id) {
+            return Expr::new(ExprKind::Literal(Literal::Int(value as usize)), ty, span);
+        }
+        if let Some(Res::Builtin(name)) = resolution {
+            let args = args.iter().map(|arg| self.expr(&arg.value)).collect();
+            return call(Callee::Builtin(name.clone()), args, ty, span);
+        }
+
+        // `value::method(..)` and `(expr)::method(..)`.
+        let receiver = match &callee.kind {
+            Ast::Path(segments)
+                if segments.len() >= 2
+                    && matches!(
+                        resolution,
+                        Some(Res::Local(_) | Res::SelfValue | Res::Global(_))
+                    ) =>
+            {
+                let (method, receiver) = segments.split_last().unwrap();
+                Some((self.place(callee.id, receiver, callee.span), method))
+            }
+            Ast::Field { base, name } => Some((self.expr(base), name)),
+            _ => None,
+        };
+        let target = self.results.callees.get(&expr.id);
+        if let Some((receiver, method)) = receiver {
+            let Some(target) = target else {
+                // A closure stored in a field.
+                let field = self.field(receiver, method);
+                let args = args.iter().map(|arg| self.expr(&arg.value)).collect();
+                return call(Callee::Value(Box::new(field)), args, ty, span);
+            };
+            let sig = self.sig(&target.func);
+            let receiver = adjust_receiver(receiver, sig.and_then(|sig| sig.self_kind));
+            let mut stmts = Vec::new();
+            let mut values = vec![receiver];
+            values.extend(self.args(sig, args, &mut stmts));
+            let callee = Callee::Fn {
+                func: target.func.clone(),
+                args: target.args.clone(),
+                self_ty: target.self_ty.clone(),
+            };
+            return wrap(stmts, call(callee, values, ty, span));
+        }
+
+        if let Some(Res::Variant { union, variant }) = resolution {
+            let fields = args.iter().map(|arg| self.expr(&arg.value)).collect();
+            let kind = ExprKind::Variant {
+                union: union.clone(),
+                variant: variant.clone(),
+                fields,
+            };
+            return Expr::new(kind, ty, span);
+        }
+        if let Some(target) = target {
+            let sig = self.sig(&target.func);
+            let mut stmts = Vec::new();
+            let values = self.args(sig, args, &mut stmts);
+            let callee = match sig {
+                Some(sig) if sig.is_extern => Callee::Extern {
+                    symbol: sig.name.clone(),
+                    variadic: sig.variadic,
+                },
+                _ => Callee::Fn {
+                    func: target.func.clone(),
+                    args: target.args.clone(),
+                    self_ty: target.self_ty.clone(),
+                },
+            };
+            return wrap(stmts, call(callee, values, ty, span));
+        }
+        let callee = self.expr(callee);
+        let args = args.iter().map(|arg| self.expr(&arg.value)).collect();
+        call(Callee::Value(Box::new(callee)), args, ty, span)
+    }
+
+    fn sig(&self, func: &FnRef) -> Option<&'a FnSig> {
+        match &func.owner {
+            FnOwner::Free => self.items.functions.get(&func.name),
+            FnOwner::Type(ty) => self.items.method(ty, &func.name),
+            FnOwner::Protocol(protocol) => self
+                .items
+                .protocols
+                .get(protocol)?
+                .iter()
+                .find(|sig| sig.name == func.name),
+        }
+    }
+
+    /// Call arguments in parameter order, matching labels like the checker.
+    fn args(&mut self, sig: Option<&FnSig>, args: &[ast::Arg], stmts: &mut Vec<Stmt>) -> Vec<Expr> {
+        let params: &[ParamSig] = sig.map_or(&[], |sig| &sig.params);
+        let mut taken = vec![false; params.len().max(args.len())];
+        let mut next = 0;
+        let mut values = Vec::new();
+        for arg in args {
+            let index = match &arg.label {
+                Some(label) => params
+                    .iter()
+                    .position(|p| p.label.as_ref().unwrap_or(&p.name) == &label.name),
+                None => {
+                    while next < taken.len() && taken[next] {
+                        next += 1;
+                    }
+                    Some(next)
+                }
+            };
+            if let Some(index) = index
+                && let Some(taken) = taken.get_mut(index)
+            {
+                *taken = true;
+            }
+            values.push((index, self.expr(&arg.value)));
+        }
+        let len = taken.len();
+        self.in_order(values, len, stmts)
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    // ---------------------------------------------------------------------
+    // Desugared control flow
+    // ---------------------------------------------------------------------
+
+    /// `for x in iter { body }` is
+    ///
+    /// ```text
+    /// {
+    ///     mut iter := iter
+    ///     loop {
+    ///         match core::iterator::next(ref mut iter) {
+    ///             Option::Some(x): body
+    ///             Option::None: break
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    fn for_loop(
+        &mut self,
+        expr: &ast::Expr,
+        binding: &Ident,
+        binding_id: NodeId,
+        iter: &ast::Expr,
+        body: &ast::Block,
+    ) -> Expr {
+        let span = expr.span;
+        let mut stmts = Vec::new();
+        let iter = self.expr(iter);
+        let iter_ty = iter.ty.clone();
+        let iter = self.temp("iter", iter, true, &mut stmts);
+        let elem_ty = self.binding_ty(binding_id);
+        let option_ty = option(elem_ty.clone());
+
+        // Ranges implement `iterator` without a recorded call.
+        let callee = match self.results.callees.get(&expr.id) {
+            Some(target) => Callee::Fn {
+                func: target.func.clone(),
+                args: target.args.clone(),
+                self_ty: target.self_ty.clone(),
+            },
+            None => Callee::Fn {
+                func: FnRef {
+                    owner: FnOwner::Protocol(prelude::ITERATOR.to_string()),
+                    name: prelude::ITERATOR_METHOD.to_string(),
+                },
+                args: vec![elem_ty.clone()],
+                self_ty: Some(iter_ty.clone()),
+            },
+        };
+        let receiver = Expr::new(
+            ExprKind::Ref {
+                mutable: true,
+                expr: Box::new(iter),
+            },
+            Ty::Ref {
+                mutable: true,
+                inner: Box::new(iter_ty),
+            },
+            span,
+        );
+        let next = call(callee, vec![receiver], option_ty.clone(), span);
+
+        let local = self.declare(binding_id, binding, false);
+        let some = Pattern {
+            kind: PatternKind::Variant {
+                union: prelude::OPTION.to_string(),
+                variant: "Some".to_string(),
+                fields: vec![Pattern {
+                    kind: PatternKind::Binding(local),
+                    ty: elem_ty,
+                    span: binding.span,
+                }],
+            },
+            ty: option_ty.clone(),
+            span: binding.span,
+        };
+        let none = Pattern {
+            kind: PatternKind::Variant {
+                union: prelude::OPTION.to_string(),
+                variant: "None".to_string(),
+                fields: Vec::new(),
+            },
+            ty: option_ty,
+            span: binding.span,
+        };
+        let body = self.block_expr(body);
+        let exit = Expr::new(ExprKind::Break(Box::new(Expr::unit(span))), Ty::Never, span);
+        let step = Expr::new(
+            ExprKind::Match {
+                scrutinee: Box::new(next),
+                arms: vec![
+                    Arm {
+                        pattern: some,
+                        body,
+                    },
+                    Arm {
+                        pattern: none,
+                        body: exit,
+                    },
+                ],
+            },
+            Ty::Unit,
+            span,
+        );
+        let looped = Expr::new(
+            ExprKind::Loop(Block {
+                stmts: Vec::new(),
+                value: Box::new(step),
+            }),
+            Ty::Unit,
+            span,
+        );
+        wrap(stmts, looped)
+    }
+
+    /// Resolves the template's operand names to indices.
+    fn asm(&mut self, block: &ast::AsmBlock) -> Asm {
+        let index = |operand: &ast::AsmRef| match operand {
+            ast::AsmRef::Index(i) => *i,
+            ast::AsmRef::Name(name) => block
+                .operands
+                .iter()
+                .position(|o| o.name.as_ref().is_some_and(|n| &n.name == name))
+                .expect("the checker resolved every operand name"),
+        };
+        let template = block
+            .template
+            .iter()
+            .map(|line| {
+                line.iter()
+                    .map(|piece| match piece {
+                        ast::AsmPiece::Operand {
+                            operand,
+                            modifier,
+                            span,
+                        } => ast::AsmPiece::Operand {
+                            operand: ast::AsmRef::Index(index(operand)),
+                            modifier: *modifier,
+                            span: *span,
+                        },
+                        text => text.clone(),
+                    })
+                    .collect()
+            })
+            .collect();
+        Asm {
+            template,
+            operands: block
+                .operands
+                .iter()
+                .map(|operand| AsmOperand {
+                    dir: operand.dir,
+                    reg: operand.reg.clone(),
+                    expr: self.expr(&operand.expr),
+                })
+                .collect(),
+            clobbers: block.clobbers.iter().map(|c| c.name.clone()).collect(),
+            options: block.options,
+        }
+    }
+
+    /// `value?` is
+    ///
+    /// ```text
+    /// match value {
+    ///     Option::Some(v): v              Result::Ok(v): v
+    ///     Option::None: return None       Result::Err(e): return Err(into_error(e))
+    /// }
+    /// ```
+    ///
+    /// where `into_error` is only called when the checker recorded that the
+    /// error type needs converting.
+    fn try_expr(&mut self, expr: &ast::Expr, inner: &ast::Expr) -> Expr {
+        let ty = self.ty_of(expr.id);
+        let span = expr.span;
+        let scrutinee = self.expr(inner);
+        let ret = self.returns.last().cloned().unwrap_or(Ty::Error);
+        let (union, args) = match &scrutinee.ty {
+            Ty::Adt { name, args } => (name.clone(), args.clone()),
+            _ => (prelude::OPTION.to_string(), vec![Ty::Error]),
+        };
+        let is_result = union == prelude::RESULT;
+        let (success, failure) = if is_result {
+            ("Ok", "Err")
+        } else {
+            ("Some", "None")
+        };
+
+        let value = self.push_local("value", ty.clone(), false, span);
+        let success = Arm {
+            pattern: variant_pattern(
+                &union,
+                success,
+                vec![binding_pattern(value, ty.clone(), span)],
+                &scrutinee.ty,
+                span,
+            ),
+            body: self.local(value, span),
+        };
+
+        let (fields, returned) = if is_result {
+            let error_ty = args.get(1).cloned().unwrap_or(Ty::Error);
+            let error = self.push_local("error", error_ty.clone(), false, span);
+            let mut returned = self.local(error, span);
+            if let Some(target) = self.results.callees.get(&expr.id) {
+                let to = match &ret {
+                    Ty::Adt { args, .. } => args.get(1).cloned().unwrap_or(Ty::Error),
+                    _ => Ty::Error,
+                };
+                let callee = Callee::Fn {
+                    func: target.func.clone(),
+                    args: target.args.clone(),
+                    self_ty: target.self_ty.clone(),
+                };
+                returned = call(callee, vec![returned], to, span);
+            }
+            (vec![binding_pattern(error, error_ty, span)], vec![returned])
+        } else {
+            (Vec::new(), Vec::new())
+        };
+        let returned = Expr::new(
+            ExprKind::Variant {
+                union: union.clone(),
+                variant: failure.to_string(),
+                fields: returned,
+            },
+            ret,
+            span,
+        );
+        let failure = Arm {
+            pattern: variant_pattern(&union, failure, fields, &scrutinee.ty, span),
+            body: Expr::new(ExprKind::Return(Box::new(returned)), Ty::Never, span),
+        };
+        Expr::new(
+            ExprKind::Match {
+                scrutinee: Box::new(scrutinee),
+                arms: vec![success, failure],
+            },
+            ty,
+            span,
+        )
+    }
+
+    // ---------------------------------------------------------------------
+    // Patterns
+    // ---------------------------------------------------------------------
+
+    fn pattern(&mut self, pattern: &ast::Pattern, ty: &Ty) -> Pattern {
+        let kind = match &pattern.kind {
+            ast::PatternKind::Wildcard => PatternKind::Wildcard,
+            ast::PatternKind::Literal(literal) => PatternKind::Literal(literal.clone()),
+            ast::PatternKind::Binding(name) => {
+                // A bare variant name, like `None`, matches that variant.
+                if let Ty::Adt { name: adt, .. } = ty
+                    && let Some(union) = self.items.unions.get(adt)
+                    && union.variant(&name.name).is_some()
+                {
+                    PatternKind::Variant {
+                        union: adt.clone(),
+                        variant: name.name.clone(),
+                        fields: Vec::new(),
+                    }
+                } else {
+                    PatternKind::Binding(self.declare(pattern.id, name, false))
+                }
+            }
+            ast::PatternKind::Variant { fields, .. } => {
+                let (union, variant) = match self.results.resolutions.get(pattern.id) {
+                    Some(Res::Variant { union, variant }) => (union.clone(), variant.clone()),
+                    _ => (String::new(), String::new()),
+                };
+                let field_tys = match ty {
+                    Ty::Adt { args, .. } => self
+                        .items
+                        .unions
+                        .get(&union)
+                        .and_then(|def| {
+                            let fields = def.variant(&variant)?;
+                            Some(
+                                fields
+                                    .iter()
+                                    .map(|t| t.subst(&def.generics, args))
+                                    .collect(),
+                            )
+                        })
+                        .unwrap_or_default(),
+                    _ => Vec::new(),
+                };
+                let fields = fields
+                    .iter()
+                    .enumerate()
+                    .map(|(i, field)| {
+                        let ty = field_tys.get(i).cloned().unwrap_or(Ty::Error);
+                        self.pattern(field, &ty)
+                    })
+                    .collect();
+                PatternKind::Variant {
+                    union,
+                    variant,
+                    fields,
+                }
+            }
+            ast::PatternKind::Tuple(elems) => {
+                let tys = match ty {
+                    Ty::Tuple(tys) => tys.clone(),
+                    _ => Vec::new(),
+                };
+                PatternKind::Tuple(
+                    elems
+                        .iter()
+                        .enumerate()
+                        .map(|(i, elem)| {
+                            let ty = tys.get(i).cloned().unwrap_or(Ty::Error);
+                            self.pattern(elem, &ty)
+                        })
+                        .collect(),
+                )
+            }
+        };
+        Pattern {
+            kind,
+            ty: ty.clone(),
+            span: pattern.span,
+        }
+    }
+}
+
+/// Passes a receiver the way the method takes `self`: a `ref self` method
+/// gets a reference to the value, a `self` method the value itself.
+fn adjust_receiver(mut receiver: Expr, kind: Option<ast::SelfKind>) -> Expr {
+    let by_ref = match kind {
+        Some(ast::SelfKind::Ref) => Some(false),
+        Some(ast::SelfKind::RefMut) => Some(true),
+        Some(ast::SelfKind::Value | ast::SelfKind::MutValue) => None,
+        None => return receiver,
+    };
+    // Keep at most the one reference a `ref self` method wants.
+    loop {
+        let inner = match &receiver.ty {
+            Ty::Ref { inner, .. } if by_ref.is_none() || matches!(**inner, Ty::Ref { .. }) => {
+                inner.as_ref().clone()
+            }
+            _ => break,
+        };
+        let span = receiver.span;
+        receiver = Expr::new(ExprKind::Deref(Box::new(receiver)), inner, span);
+    }
+    match by_ref {
+        Some(mutable) if !matches!(receiver.ty, Ty::Ref { .. }) => {
+            let ty = Ty::Ref {
+                mutable,
+                inner: Box::new(receiver.ty.clone()),
+            };
+            let span = receiver.span;
+            Expr::new(
+                ExprKind::Ref {
+                    mutable,
+                    expr: Box::new(receiver),
+                },
+                ty,
+                span,
+            )
+        }
+        _ => receiver,
+    }
+}
+
+/// Whether evaluating `place` twice gives the same place without side
+/// effects.
+fn is_pure_place(place: &Expr) -> bool {
+    match &place.kind {
+        ExprKind::Local(_) | ExprKind::Global(_) => true,
+        ExprKind::Field { base, .. } | ExprKind::Deref(base) => is_pure_place(base),
+        _ => false,
+    }
+}
+
+/// Whether evaluating `expr` can't have side effects or observe any.
+fn is_simple(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Literal(_) | ExprKind::FnItem { .. } | ExprKind::VariantCtor { .. } => true,
+        ExprKind::Ref { expr, .. } => is_pure_place(expr),
+        _ => is_pure_place(expr),
+    }
+}
+
+fn assign(place: Expr, value: Expr, span: Span) -> Expr {
+    Expr::new(
+        ExprKind::Assign {
+            place: Box::new(place),
+            value: Box::new(value),
+        },
+        Ty::Unit,
+        span,
+    )
+}
+
+fn call(callee: Callee, args: Vec<Expr>, ty: Ty, span: Span) -> Expr {
+    Expr::new(ExprKind::Call { callee, args }, ty, span)
+}
+
+/// `value`, preceded by `stmts` if there are any.
+fn wrap(stmts: Vec<Stmt>, value: Expr) -> Expr {
+    if stmts.is_empty() {
+        return value;
+    }
+    let (ty, span) = (value.ty.clone(), value.span);
+    Expr::new(
+        ExprKind::Block(Block {
+            stmts,
+            value: Box::new(value),
+        }),
+        ty,
+        span,
+    )
+}
+
+fn option(ty: Ty) -> Ty {
+    Ty::Adt {
+        name: prelude::OPTION.to_string(),
+        args: vec![ty],
+    }
+}
+
+fn binding_pattern(local: LocalId, ty: Ty, span: Span) -> Pattern {
+    Pattern {
+        kind: PatternKind::Binding(local),
+        ty,
+        span,
+    }
+}
+
+fn variant_pattern(
+    union: &str,
+    variant: &str,
+    fields: Vec<Pattern>,
+    ty: &Ty,
+    span: Span,
+) -> Pattern {
+    Pattern {
+        kind: PatternKind::Variant {
+            union: union.to_string(),
+            variant: variant.to_string(),
+            fields,
+        },
+        ty: ty.clone(),
+        span,
+    }
+}
@@ -0,0 +1,277 @@
+//! The high-level IR: a checked program with its syntactic sugar removed.
+//!
+//! Lowering runs after the checker and uses what it recorded, so every
+//! expression carries its type and every path is resolved. The sugar of the
+//! surface syntax is rewritten into a small core:
+//!
+//! * inline bodies (`-> expr;`) become blocks, and `unsafe { }` a plain block;
+//! * `while` and `for` become `loop`s, `for` calling `core::iterator::next`;
+//! * `?` becomes a `match` that returns `None`/`Err`, converting the error
+//!   with `into_error` when the checker asked for it;
+//! * `&&` and `||` become `if`s;
+//! * `x += y` and `x++` become assignments, and `(a, b) $= t` a `let` per
+//!   element;
+//! * `self::field` and other paths through values become field accesses,
+//!   with the dereferences the checker inserted written out;
+//! * method calls take their receiver as the first argument, referenced or
+//!   dereferenced to match the method's `self`, and labelled arguments are
+//!   put in parameter order;
+//! * record literals list every field, `Option` fields left out being `None`.
+//!
+//! Later phases only need to handle what is left.
+
+mod lower;
+mod print;
+
+pub use lower::{lower_graph, lower_module};
+
+use crate::checker::FnRef;
+use crate::checker::items::FnAttrs;
+use crate::checker::types::Ty;
+use crate::lexer::size::Span;
+use crate::lexer::tokens::Literal;
+use crate::parser::ast::{AsmDir, AsmOptions, AsmPiece, AsmReg, BinOp, UnaryOp};
+
+/// A lowered module: its globals and the code it runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    /// Dotted path of the module, empty for the entry module.
+    pub name: String,
+    /// Globals by canonical name, in declaration order. They are set by the
+    /// module's `init` body.
+    pub globals: Vec<(String, Ty)>,
+    /// Every function and method with a body, in source order.
+    pub functions: Vec<Body>,
+    /// The module's top level statements.
+    pub init: Body,
+}
+
+/// Whose code a body is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BodyOwner {
+    Function(FnRef),
+    /// The top level statements of the named module.
+    Init(String),
+}
+
+/// A local, parameter, closure parameter or temporary, by its index in the
+/// body's `locals`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LocalId(pub u32);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Local {
+    pub name: String,
+    pub ty: Ty,
+    pub mutable: bool,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Body {
+    pub owner: BodyOwner,
+    /// Generic parameters in scope: the impl's, then the function's own.
+    pub generics: Vec<String>,
+    /// `self` first, if the function has it.
+    pub params: Vec<LocalId>,
+    pub ret: Ty,
+    pub attrs: FnAttrs,
+    /// Every local declared in the body, closures included.
+    pub locals: Vec<Local>,
+    pub value: Expr,
+    pub span: Span,
+}
+
+impl Body {
+    pub fn local(&self, id: LocalId) -> &Local {
+        &self.locals[id.0 as usize]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub ty: Ty,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Literal(Literal),
+    Local(LocalId),
+    /// A global, by canonical name.
+    Global(String),
+    /// A function or method used as a value.
+    FnItem {
+        func: FnRef,
+        args: Vec<Ty>,
+    },
+    /// An empty tuple is the unit value.
+    Tuple(Vec<Expr>),
+    /// A record value with every field, in declaration order.
+    Record {
+        name: String,
+        fields: Vec<(String, Expr)>,
+    },
+    Variant {
+        union: String,
+        variant: String,
+        fields: Vec<Expr>,
+    },
+    /// A variant with fields used as a function value, like `Option::Some`.
+    VariantCtor {
+        union: String,
+        variant: String,
+    },
+    Unary {
+        op: UnaryOp,
+        expr: Box<Expr>,
+    },
+    /// Never `&&` or `||`; those are lowered to `if`.
+    Binary {
+        op: BinOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Range {
+        start: Box<Expr>,
+        end: Box<Expr>,
+    },
+    /// Arguments in parameter order; a method's receiver comes first.
+    Call {
+        callee: Callee,
+        args: Vec<Expr>,
+    },
+    /// A field of a record or an element of a tuple. The base is never a
+    /// reference.
+    Field {
+        base: Box<Expr>,
+        name: String,
+        index: usize,
+    },
+    Block(Block),
+    If {
+        cond: Box<Expr>,
+        then_branch: Box<Expr>,
+        else_branch: Box<Expr>,
+    },
+    Loop(Block),
+    Match {
+        scrutinee: Box<Expr>,
+        arms: Vec<Arm>,
+    },
+    Break(Box<Expr>),
+    Continue,
+    Return(Box<Expr>),
+    Ref {
+        mutable: bool,
+        expr: Box<Expr>,
+    },
+    RawRef {
+        mutable: bool,
+        expr: Box<Expr>,
+    },
+    Deref(Box<Expr>),
+    /// A `ref T` used as a `ref protocol` object.
+    ToDyn {
+        expr: Box<Expr>,
+        protocol: String,
+    },
+    Assign {
+        place: Box<Expr>,
+        value: Box<Expr>,
+    },
+    Asm(Box<Asm>),
+    /// Closure parameters live in the enclosing body's `locals`; the
+    /// closure sees every local around it.
+    Closure {
+        params: Vec<LocalId>,
+        body: Box<Expr>,
+    },
+}
+
+/// An `asm` block. Template references name operands by index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Asm {
+    pub template: Vec<Vec<AsmPiece>>,
+    pub operands: Vec<AsmOperand>,
+    pub clobbers: Vec<String>,
+    pub options: AsmOptions,
+}
+
+/// An input's value, or the place an output is written to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmOperand {
+    pub dir: AsmDir,
+    pub reg: AsmReg,
+    pub expr: Expr,
+}
+
+/// What a call invokes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Callee {
+    /// A function or method known at compile time, with the impl's generic
+    /// arguments followed by its own. Protocol methods run the
+    /// implementation of `self_ty`.
+    Fn {
+        func: FnRef,
+        args: Vec<Ty>,
+        self_ty: Option<Ty>,
+    },
+    /// `print` or `exit`.
+    Builtin(String),
+    /// A function declared `extern`, called through its symbol as C code
+    /// calls it.
+    Extern { symbol: String, variadic: bool },
+    /// A closure or function value.
+    Value(Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub stmts: Vec<Stmt>,
+    /// The block's value; unit if the source had none.
+    pub value: Box<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Let { local: LocalId, init: Expr },
+    Expr(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arm {
+    pub pattern: Pattern,
+    pub body: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    pub kind: PatternKind,
+    pub ty: Ty,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatternKind {
+    Wildcard,
+    Literal(Literal),
+    Binding(LocalId),
+    Variant {
+        union: String,
+        variant: String,
+        fields: Vec<Pattern>,
+    },
+    Tuple(Vec<Pattern>),
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, ty: Ty, span: Span) -> Self {
+        Self { kind, ty, span }
+    }
+
+    pub fn unit(span: Span) -> Self {
+        Self::new(ExprKind::Tuple(Vec::new()), Ty::Unit, span)
+    }
+}
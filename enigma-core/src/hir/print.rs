@@ -0,0 +1,404 @@
+//! A readable dump of the HIR, printed by `--emit=hir`. Locals are written
+//! `name#index` so shadowed names and temporaries stay distinguishable.
+
+use super::*;
+use crate::checker::FnOwner;
+use std::fmt::{self, Write};
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = if self.name.is_empty() {
+            "(root)"
+        } else {
+            &self.name
+        };
+        writeln!(f, "module {}", name)?;
+        for (global, ty) in &self.globals {
+            writeln!(f, "global {}: {}", global, ty)?;
+        }
+        for body in &self.functions {
+            write!(f, "\n{}", body)?;
+        }
+        write!(f, "\n{}", self.init)
+    }
+}
+
+impl fmt::Display for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut printer = Printer {
+            body: self,
+            out: String::new(),
+            indent: 0,
+        };
+        match &self.owner {
+            BodyOwner::Function(func) => {
+                write!(printer.out, "{}@{}", self.attrs, fn_name(func))?;
+                if !self.generics.is_empty() {
+                    write!(printer.out, "[{}]", self.generics.join(", "))?;
+                }
+                let params: Vec<String> = self
+                    .params
+                    .iter()
+                    .map(|&param| {
+                        let local = self.local(param);
+                        let mutable = if local.mutable { "mut " } else { "" };
+                        format!("{}{}: {}", mutable, printer.local(param), local.ty)
+                    })
+                    .collect();
+                write!(printer.out, "({})::{} ", params.join(", "), self.ret)?;
+            }
+            BodyOwner::Init(_) => printer.out.push_str("init "),
+        }
+        printer.expr(&self.value)?;
+        writeln!(f, "{}", printer.out)
+    }
+}
+
+fn fn_name(func: &FnRef) -> String {
+    match &func.owner {
+        FnOwner::Free => func.name.clone(),
+        FnOwner::Type(ty) | FnOwner::Protocol(ty) => format!("{}::{}", ty, func.name),
+    }
+}
+
+struct Printer<'a> {
+    body: &'a Body,
+    out: String,
+    indent: usize,
+}
+
+impl Printer<'_> {
+    fn local(&self, id: LocalId) -> String {
+        format!("{}#{}", self.body.local(id).name, id.0)
+    }
+
+    fn newline(&mut self) {
+        self.out.push('\n');
+        for _ in 0..self.indent {
+            self.out.push_str("    ");
+        }
+    }
+
+    fn block(&mut self, block: &Block) -> fmt::Result {
+        self.out.push('{');
+        self.indent += 1;
+        for stmt in &block.stmts {
+            self.newline();
+            match stmt {
+                Stmt::Let { local, init } => {
+                    let decl = self.body.local(*local);
+                    let mutable = if decl.mutable { "mut " } else { "" };
+                    write!(
+                        self.out,
+                        "let {}{}: {} := ",
+                        mutable,
+                        self.local(*local),
+                        decl.ty
+                    )?;
+                    self.expr(init)?;
+                }
+                Stmt::Expr(expr) => self.expr(expr)?,
+            }
+        }
+        if !matches!(&block.value.kind, ExprKind::Tuple(elems) if elems.is_empty()) {
+            self.newline();
+            self.expr(&block.value)?;
+        }
+        self.indent -= 1;
+        self.newline();
+        self.out.push('}');
+        Ok(())
+    }
+
+    /// A branch of an `if` or a loop body, always in braces.
+    fn braced(&mut self, expr: &Expr) -> fmt::Result {
+        match &expr.kind {
+            ExprKind::Block(block) => self.block(block),
+            _ => self.block(&Block {
+                stmts: Vec::new(),
+                value: Box::new(expr.clone()),
+            }),
+        }
+    }
+
+    fn list(&mut self, exprs: &[Expr]) -> fmt::Result {
+        for (i, expr) in exprs.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            self.expr(expr)?;
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, expr: &Expr) -> fmt::Result {
+        match &expr.kind {
+            ExprKind::Literal(literal) => match literal {
+                Literal::Int(n) => write!(self.out, "{}", n),
+                Literal::Float(x) => write!(self.out, "{:?}", x),
+                Literal::Str(s) => write!(self.out, "{:?}", s),
+                Literal::Bool(b) => write!(self.out, "{}", b),
+                Literal::Char(c) => write!(self.out, "{:?}", c),
+            },
+            ExprKind::Local(id) => {
+                let name = self.local(*id);
+                self.out.push_str(&name);
+                Ok(())
+            }
+            ExprKind::Global(name) => write!(self.out, "{}", name),
+            ExprKind::FnItem { func, args } => {
+                write!(self.out, "@{}", fn_name(func))?;
+                self.ty_args(args)
+            }
+            ExprKind::Tuple(elems) => {
+                self.out.push('(');
+                self.list(elems)?;
+                self.out.push(')');
+                Ok(())
+            }
+            ExprKind::Record { name, fields } => {
+                write!(self.out, "{} {{ ", name)?;
+                for (i, (field, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str(", ");
+                    }
+                    write!(self.out, "{}: ", field)?;
+                    self.expr(value)?;
+                }
+                self.out.push_str(" }");
+                Ok(())
+            }
+            ExprKind::Variant {
+                union,
+                variant,
+                fields,
+            } => {
+                write!(self.out, "{}::{}", union, variant)?;
+                if !fields.is_empty() {
+                    self.out.push('(');
+                    self.list(fields)?;
+                    self.out.push(')');
+                }
+                Ok(())
+            }
+            ExprKind::VariantCtor { union, variant } => {
+                write!(self.out, "@{}::{}", union, variant)
+            }
+            ExprKind::Unary { op, expr } => {
+                self.out.push(if *op == UnaryOp::Neg { '-' } else { '!' });
+                self.expr(expr)
+            }
+            ExprKind::Binary { op, lhs, rhs } => {
+                self.out.push('(');
+                self.expr(lhs)?;
+                write!(self.out, " {} ", op.symbol())?;
+                self.expr(rhs)?;
+                self.out.push(')');
+                Ok(())
+            }
+            ExprKind::Range { start, end } => {
+                self.expr(start)?;
+                self.out.push_str("..");
+                self.expr(end)
+            }
+            ExprKind::Call { callee, args } => {
+                match callee {
+                    Callee::Fn {
+                        func,
+                        args: ty_args,
+                        self_ty,
+                    } => {
+                        match (&func.owner, self_ty) {
+                            (FnOwner::Protocol(protocol), Some(self_ty)) => {
+                                write!(self.out, "<{} as {}>::{}", self_ty, protocol, func.name)?
+                            }
+                            _ => self.out.push_str(&fn_name(func)),
+                        }
+                        self.ty_args(ty_args)?;
+                    }
+                    Callee::Builtin(name) => self.out.push_str(name),
+                    Callee::Extern { symbol, .. } => write!(self.out, "extern {}", symbol)?,
+                    Callee::Value(value) => {
+                        self.out.push('(');
+                        self.expr(value)?;
+                        self.out.push(')');
+                    }
+                }
+                self.out.push('(');
+                self.list(args)?;
+                self.out.push(')');
+                Ok(())
+            }
+            ExprKind::Field { base, name, .. } => {
+                self.expr(base)?;
+                write!(self.out, "::{}", name)
+            }
+            ExprKind::Block(block) => self.block(block),
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                self.out.push_str("if ");
+                self.expr(cond)?;
+                self.out.push(' ');
+                self.braced(then_branch)?;
+                match &else_branch.kind {
+                    ExprKind::Tuple(elems) if elems.is_empty() => Ok(()),
+                    ExprKind::If { .. } => {
+                        self.out.push_str(" else ");
+                        self.expr(else_branch)
+                    }
+                    _ => {
+                        self.out.push_str(" else ");
+                        self.braced(else_branch)
+                    }
+                }
+            }
+            ExprKind::Loop(block) => {
+                self.out.push_str("loop ");
+                self.block(block)
+            }
+            ExprKind::Match { scrutinee, arms } => {
+                self.out.push_str("match ");
+                self.expr(scrutinee)?;
+                self.out.push_str(" {");
+                self.indent += 1;
+                for arm in arms {
+                    self.newline();
+                    self.pattern(&arm.pattern)?;
+                    self.out.push_str(": ");
+                    self.expr(&arm.body)?;
+                }
+                self.indent -= 1;
+                self.newline();
+                self.out.push('}');
+                Ok(())
+            }
+            ExprKind::Break(value) => self.jump("break", value),
+            ExprKind::Continue => write!(self.out, "continue"),
+            ExprKind::Return(value) => self.jump("return", value),
+            ExprKind::Ref { mutable, expr } => {
+                self.out
+                    .push_str(if *mutable { "ref mut " } else { "ref " });
+                self.expr(expr)
+            }
+            ExprKind::RawRef { mutable, expr } => {
+                self.out
+                    .push_str(if *mutable { "raw_ref mut " } else { "raw_ref " });
+                self.expr(expr)
+            }
+            ExprKind::Deref(inner) => {
+                self.out.push_str("(deref ");
+                self.expr(inner)?;
+                self.out.push(')');
+                Ok(())
+            }
+            ExprKind::ToDyn { expr: inner, .. } => {
+                self.out.push('(');
+                self.expr(inner)?;
+                write!(self.out, " as {})", expr.ty)
+            }
+            ExprKind::Assign { place, value } => {
+                self.expr(place)?;
+                self.out.push_str(" = ");
+                self.expr(value)
+            }
+            ExprKind::Asm(asm) => {
+                self.out.push_str("asm {");
+                self.indent += 1;
+                for line in &asm.template {
+                    self.newline();
+                    let line: String = line.iter().map(ToString::to_string).collect();
+                    write!(self.out, "{:?}", line)?;
+                }
+                for operand in &asm.operands {
+                    self.newline();
+                    write!(self.out, "{}({}) ", operand.dir, operand.reg)?;
+                    self.expr(&operand.expr)?;
+                }
+                if !asm.clobbers.is_empty() {
+                    self.newline();
+                    write!(self.out, "clobber({})", asm.clobbers.join(", "))?;
+                }
+                let options = asm.options.names();
+                if !options.is_empty() {
+                    self.newline();
+                    write!(self.out, "options({})", options.join(", "))?;
+                }
+                self.indent -= 1;
+                self.newline();
+                self.out.push('}');
+                Ok(())
+            }
+            ExprKind::Closure { params, body } => {
+                let params: Vec<String> = params.iter().map(|&p| self.local(p)).collect();
+                write!(self.out, "@({}) -> ", params.join(", "))?;
+                self.expr(body)
+            }
+        }
+    }
+
+    fn jump(&mut self, keyword: &str, value: &Expr) -> fmt::Result {
+        self.out.push_str(keyword);
+        if !matches!(&value.kind, ExprKind::Tuple(elems) if elems.is_empty()) {
+            self.out.push(' ');
+            self.expr(value)?;
+        }
+        Ok(())
+    }
+
+    fn ty_args(&mut self, args: &[Ty]) -> fmt::Result {
+        if !args.is_empty() {
+            let args: Vec<String> = args.iter().map(Ty::to_string).collect();
+            write!(self.out, "[{}]", args.join(", "))?;
+        }
+        Ok(())
+    }
+
+    fn pattern(&mut self, pattern: &Pattern) -> fmt::Result {
+        match &pattern.kind {
+            PatternKind::Wildcard => self.out.push('_'),
+            PatternKind::Literal(literal) => {
+                let literal = Expr::new(
+                    ExprKind::Literal(literal.clone()),
+                    pattern.ty.clone(),
+                    pattern.span,
+                );
+                self.expr(&literal)?;
+            }
+            PatternKind::Binding(id) => {
+                let name = self.local(*id);
+                self.out.push_str(&name);
+            }
+            PatternKind::Variant {
+                union,
+                variant,
+                fields,
+            } => {
+                write!(self.out, "{}::{}", union, variant)?;
+                if !fields.is_empty() {
+                    self.out.push('(');
+                    self.patterns(fields)?;
+                    self.out.push(')');
+                }
+            }
+            PatternKind::Tuple(elems) => {
+                self.out.push('(');
+                self.patterns(elems)?;
+                self.out.push(')');
+            }
+        }
+        Ok(())
+    }
+
+    fn patterns(&mut self, patterns: &[Pattern]) -> fmt::Result {
+        for (i, pattern) in patterns.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            self.pattern(pattern)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1314 @@
+//! A tree-walking interpreter for checked programs, behind `enigma run`.
+//!
+//! The interpreter evaluates the AST directly, using the type checker's
+//! results to resolve names, pick methods and give literals their types. It
+//! runs the top level statements of every module in dependency order, the
+//! `core` prelude first and the entry file last.
+//!
+//! Every local, global and temporary lives in its own `Slot`; a reference is
+//! a slot plus a path of field indices, so `ref mut h::age` writes into `h`.
+//! Records, unions and tuples are copied when they move, which is
+//! indistinguishable from moving them once the borrow checker has run.
+//!
+//! Mistakes the checker can't rule out, such as arithmetic overflow or
+//! `extract` on `None`, stop the program with a `RuntimeError` carrying the
+//! span of the failing expression and the calls that led to it.
+
+mod value;
+
+use value::truncate;
+pub use value::{Callable, ClosureValue, Pointer, Slot, Value};
+
+use crate::checker::items::{ItemTable, join_path};
+use crate::checker::resolve::Res;
+use crate::checker::types::Ty;
+use crate::checker::{FnOwner, TypeckResults};
+use crate::errorhandler::{Diagnostic, SourceMap, line_col};
+use crate::lexer::size::Span;
+use crate::lexer::tokens::Literal;
+use crate::loader::{ModuleGraph, ModuleId};
+use crate::parser::ast::*;
+use crate::prelude;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+
+/// How many calls may be active at once before the program is stopped.
+pub const CALL_DEPTH_LIMIT: usize = 1000;
+
+/// A failure while running the program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub span: Span,
+    /// The active calls, innermost first.
+    pub stack: Vec<Frame>,
+}
+
+/// One active call: the function and where it was called from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub function: String,
+    pub call_site: Span,
+    /// The module defining the function.
+    pub module: ModuleId,
+}
+
+impl RuntimeError {
+    /// The error as a diagnostic whose notes list the call stack.
+    pub fn to_diagnostic(&self, sources: &SourceMap) -> Diagnostic {
+        let mut diagnostic = Diagnostic::error(self.message.clone(), self.span);
+        for frame in &self.stack {
+            let place = match sources.lookup(frame.call_site.start) {
+                Some(file) => {
+                    let (line, col) = line_col(&file.source, file.local(frame.call_site).start);
+                    format!("{}:{}:{}", file.name, line, col)
+                }
+                None => "an unknown location".to_string(),
+            };
+            diagnostic =
+                diagnostic.with_note(format!("in `{}`, called from {}", frame.function, place));
+        }
+        diagnostic
+    }
+}
+
+/// Runs a checked program, writing what it prints to `out`. Returns the
+/// exit code: the argument of `exit`, or 0 when the program runs to the end.
+pub fn run(
+    graph: &ModuleGraph,
+    checked: &[(ItemTable, TypeckResults)],
+    out: &mut dyn Write,
+) -> Result<i32, RuntimeError> {
+    let mut interp = Interpreter::new(graph, checked, out);
+    for &id in &graph.order {
+        match interp.run_module(id) {
+            Ok(()) => {}
+            Err(Unwind::Exit(code)) => return Ok(code),
+            Err(Unwind::Error(error)) => return Err(error),
+            // The checker rejects `break`, `continue` and `return` outside
+            // of loops and functions.
+            Err(Unwind::Break(_) | Unwind::Continue | Unwind::Return(_)) => {}
+        }
+    }
+    Ok(0)
+}
+
+/// Why evaluation stopped before producing a value.
+enum Unwind {
+    Break(Value),
+    Continue,
+    Return(Value),
+    Exit(i32),
+    Error(RuntimeError),
+}
+
+type Eval<T = Value> = Result<T, Unwind>;
+
+/// The locals of the function or top level code being run.
+struct Env {
+    module: ModuleId,
+    locals: HashMap<NodeId, Slot>,
+    self_slot: Option<Slot>,
+}
+
+impl Env {
+    fn new(module: ModuleId) -> Self {
+        Self {
+            module,
+            locals: HashMap::new(),
+            self_slot: None,
+        }
+    }
+}
+
+struct Interpreter<'a> {
+    checked: &'a [(ItemTable, TypeckResults)],
+    /// Free functions by canonical name, with their module.
+    functions: HashMap<String, (ModuleId, &'a Function)>,
+    /// Methods by implementing type and name.
+    methods: HashMap<(String, String), (ModuleId, &'a Function)>,
+    closures: HashMap<(ModuleId, NodeId), &'a Closure>,
+    programs: Vec<&'a Program>,
+    globals: HashMap<String, Slot>,
+    stack: Vec<Frame>,
+    prelude: Option<ModuleId>,
+    out: &'a mut dyn Write,
+}
+
+fn slot(value: Value) -> Slot {
+    Rc::new(RefCell::new(value))
+}
+
+impl<'a> Interpreter<'a> {
+    fn new(
+        graph: &'a ModuleGraph,
+        checked: &'a [(ItemTable, TypeckResults)],
+        out: &'a mut dyn Write,
+    ) -> Self {
+        let mut functions = HashMap::new();
+        let mut methods = HashMap::new();
+        for module in &graph.modules {
+            let table = &checked[module.id].0;
+            for item in &module.program.items {
+                match &item.kind {
+                    ItemKind::Function(function) => {
+                        functions
+                            .insert(table.canonical(&function.name.name), (module.id, function));
+                    }
+                    ItemKind::Impl(imp) => {
+                        let Some((ty, _)) = table.impl_target(&imp.target) else {
+                            continue;
+                        };
+                        for method in &imp.methods {
+                            let key = (ty.clone(), method.function.name.name.clone());
+                            methods.insert(key, (module.id, &method.function));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Self {
+            checked,
+            functions,
+            methods,
+            closures: HashMap::new(),
+            programs: graph.modules.iter().map(|m| &m.program).collect(),
+            globals: HashMap::new(),
+            stack: Vec::new(),
+            prelude: graph.by_name(prelude::MODULE).map(|m| m.id),
+            out,
+        }
+    }
+
+    fn items(&self, env: &Env) -> &'a ItemTable {
+        &self.checked[env.module].0
+    }
+
+    fn results(&self, env: &Env) -> &'a TypeckResults {
+        &self.checked[env.module].1
+    }
+
+    fn res(&self, env: &Env, id: NodeId) -> Option<&'a Res> {
+        self.results(env).resolutions.get(id)
+    }
+
+    /// Stops the program at `span`. Inside the prelude the error is
+    /// reported at the call that led into it, like `extract` on `None`.
+    fn error<T>(&self, message: impl Into<String>, span: Span) -> Eval<T> {
+        let mut span = span;
+        let mut stack: Vec<Frame> = self.stack.iter().rev().cloned().collect();
+        while let Some(frame) = stack.first()
+            && Some(frame.module) == self.prelude
+        {
+            span = frame.call_site;
+            stack.remove(0);
+        }
+        Err(Unwind::Error(RuntimeError {
+            message: message.into(),
+            span,
+            stack,
+        }))
+    }
+
+    // ---------------------------------------------------------------------
+    // Items
+    // ---------------------------------------------------------------------
+
+    fn run_module(&mut self, id: ModuleId) -> Eval<()> {
+        let mut env = Env::new(id);
+        let table = &self.checked[id].0;
+        for item in &self.programs[id].items {
+            let ItemKind::Stmt(stmt) = &item.kind else {
+                continue;
+            };
+            // Top level `let`s are globals, visible from functions.
+            if let StmtKind::Let { binding, init } = &stmt.kind {
+                let value = self.eval(&mut env, init)?;
+                self.globals
+                    .insert(table.canonical(&binding.name.name), slot(value));
+                continue;
+            }
+            self.stmt(&mut env, stmt)?;
+        }
+        Ok(())
+    }
+
+    /// Calls `function` with its arguments already in parameter order.
+    fn call_function(
+        &mut self,
+        (module, function): (ModuleId, &'a Function),
+        name: String,
+        self_value: Option<Value>,
+        args: Vec<Value>,
+        span: Span,
+    ) -> Eval {
+        if self.stack.len() >= CALL_DEPTH_LIMIT {
+            return self.error(
+                format!(
+                    "stack overflow: more than {} calls are active",
+                    CALL_DEPTH_LIMIT
+                ),
+                span,
+            );
+        }
+        if function.abi.is_some() && function.body.is_none() {
+            return self.error(
+                format!(
+                    "`{}` is defined by foreign code, which only compiled programs can call",
+                    name
+                ),
+                span,
+            );
+        }
+        let mut env = Env::new(module);
+        env.self_slot = self_value.map(slot);
+        for (param, value) in function.params.iter().zip(args) {
+            env.locals.insert(param.id, slot(value));
+        }
+        self.stack.push(Frame {
+            function: name,
+            call_site: span,
+            module,
+        });
+        let result = match &function.body {
+            Some(FnBody::Block(block)) => self.block(&mut env, block),
+            Some(FnBody::Inline(expr)) => self.eval(&mut env, expr),
+            None => Ok(Value::Unit),
+        };
+        self.stack.pop();
+        match result {
+            Err(Unwind::Return(value)) => Ok(value),
+            other => other,
+        }
+    }
+
+    fn call_closure(&mut self, closure: &ClosureValue, args: Vec<Value>, span: Span) -> Eval {
+        if self.stack.len() >= CALL_DEPTH_LIMIT {
+            return self.error(
+                format!(
+                    "stack overflow: more than {} calls are active",
+                    CALL_DEPTH_LIMIT
+                ),
+                span,
+            );
+        }
+        let ast = self.closures[&(closure.module, closure.id)];
+        let mut env = Env {
+            module: closure.module,
+            locals: closure.locals.clone(),
+            self_slot: closure.self_slot.clone(),
+        };
+        for (param, value) in ast.params.iter().zip(args) {
+            env.locals.insert(param.id, slot(value));
+        }
+        self.stack.push(Frame {
+            function: "closure".to_string(),
+            call_site: span,
+            module: closure.module,
+        });
+        let result = self.eval(&mut env, &ast.body);
+        self.stack.pop();
+        match result {
+            Err(Unwind::Return(value)) => Ok(value),
+            other => other,
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, args: Vec<Value>, span: Span) -> Eval {
+        match callee {
+            Value::Closure(closure) => self.call_closure(&closure, args, span),
+            Value::Function(Callable::Function(name)) => match self.functions.get(&name) {
+                Some(&target) => self.call_function(target, name, None, args, span),
+                None => self.error(format!("function `{}` has no body", name), span),
+            },
+            Value::Function(Callable::Method { ty, name }) => {
+                match self.methods.get(&(ty.clone(), name.clone())) {
+                    Some(&target) => {
+                        let display = format!("{}::{}", ty, name);
+                        self.call_function(target, display, None, args, span)
+                    }
+                    None => self.error(format!("method `{}::{}` has no body", ty, name), span),
+                }
+            }
+            Value::Function(Callable::Variant { union, variant }) => Ok(Value::Variant {
+                union,
+                variant,
+                fields: args,
+            }),
+            other => self.error(format!("`{}` is not a function", other), span),
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Statements and blocks
+    // ---------------------------------------------------------------------
+
+    fn block(&mut self, env: &mut Env, block: &'a Block) -> Eval {
+        let mut value = Value::Unit;
+        for stmt in &block.stmts {
+            value = self.stmt(env, stmt)?;
+        }
+        // Only a trailing expression gives the block a value.
+        if block.tail().is_none() {
+            value = Value::Unit;
+        }
+        Ok(value)
+    }
+
+    fn stmt(&mut self, env: &mut Env, stmt: &'a Stmt) -> Eval {
+        match &stmt.kind {
+            StmtKind::Let { binding, init } => {
+                let value = self.eval(env, init)?;
+                env.locals.insert(binding.id, slot(value));
+            }
+            StmtKind::Destructure { bindings, init } => {
+                let value = self.eval(env, init)?;
+                let Value::Tuple(elems) = deref(value) else {
+                    return self.error("only tuples can be destructured", init.span);
+                };
+                for (binding, elem) in bindings.iter().zip(elems) {
+                    env.locals.insert(binding.id, slot(elem));
+                }
+            }
+            StmtKind::Assign { target, op, value } => {
+                let value = self.eval(env, value)?;
+                let place = self.place(env, target)?;
+                let value = match op {
+                    AssignOp::Set => value,
+                    AssignOp::Add => self.binary(BinOp::Add, place.read(), value, stmt.span)?,
+                    AssignOp::Sub => self.binary(BinOp::Sub, place.read(), value, stmt.span)?,
+                    AssignOp::Mul => self.binary(BinOp::Mul, place.read(), value, stmt.span)?,
+                    AssignOp::Div => self.binary(BinOp::Div, place.read(), value, stmt.span)?,
+                };
+                place.write(value);
+            }
+            StmtKind::Step { target, increment } => {
+                let place = self.place(env, target)?;
+                let current = place.read();
+                let one = match current {
+                    Value::Byte(_) => Value::Byte(1),
+                    Value::Float(_) => Value::Float(1.0),
+                    _ => Value::Int(1),
+                };
+                let op = if *increment { BinOp::Add } else { BinOp::Sub };
+                let value = self.binary(op, current, one, stmt.span)?;
+                place.write(value);
+            }
+            StmtKind::Expr(expr) => return self.eval(env, expr),
+        }
+        Ok(Value::Unit)
+    }
+
+    // ---------------------------------------------------------------------
+    // Expressions
+    // ---------------------------------------------------------------------
+
+    fn eval(&mut self, env: &mut Env, expr: &'a Expr) -> Eval {
+        match &expr.kind {
+            ExprKind::Literal(literal) => self.literal(env, expr, literal),
+            ExprKind::Path(segments) => self.path(env, expr, segments),
+            ExprKind::Tuple(elems) if elems.is_empty() => Ok(Value::Unit),
+            ExprKind::Tuple(elems) => {
+                let mut values = Vec::with_capacity(elems.len());
+                for elem in elems {
+                    values.push(self.eval(env, elem)?);
+                }
+                Ok(Value::Tuple(values))
+            }
+            ExprKind::Unary { op, expr: inner } => {
+                let value = self.eval(env, inner)?;
+                self.unary(*op, value, expr.span)
+            }
+            ExprKind::Binary { op, lhs, rhs } => {
+                let lhs = self.eval(env, lhs)?;
+                // `&&` and `||` only evaluate what they need.
+                match (op, &lhs) {
+                    (BinOp::And, Value::Bool(false)) => return Ok(Value::Bool(false)),
+                    (BinOp::Or, Value::Bool(true)) => return Ok(Value::Bool(true)),
+                    // Otherwise the right side is the result.
+                    (BinOp::And | BinOp::Or, Value::Bool(_)) => {
+                        return match self.eval(env, rhs)? {
+                            Value::Bool(rhs) => Ok(Value::Bool(rhs)),
+                            rhs => self.error(
+                                format!(
+                                    "cannot apply `{}` to `{}` and `{}`",
+                                    op.symbol(),
+                                    lhs,
+                                    rhs
+                                ),
+                                expr.span,
+                            ),
+                        };
+                    }
+                    _ => {}
+                }
+                let rhs = self.eval(env, rhs)?;
+                self.binary(*op, lhs, rhs, expr.span)
+            }
+            ExprKind::Range { start, end } => {
+                let start = self.eval(env, start)?;
+                let end = self.eval(env, end)?;
+                match (integer(&start), integer(&end)) {
+                    (Some(start), Some(end)) => Ok(Value::Range(start, end)),
+                    _ => self.error("range bounds must be integers", expr.span),
+                }
+            }
+            ExprKind::Call { callee, args, .. } => self.call(env, expr, callee, args),
+            ExprKind::Field { .. } => Ok(self.place(env, expr)?.read()),
+            ExprKind::RecordLit { fields, .. } => self.record(env, expr, fields),
+            ExprKind::Block(block) | ExprKind::Unsafe(block) => self.block(env, block),
+            ExprKind::If {
+                cond,
+                then_block,
+                else_branch,
+            } => {
+                if self.condition(env, cond)? {
+                    self.block(env, then_block)
+                } else if let Some(else_branch) = else_branch {
+                    self.eval(env, else_branch)
+                } else {
+                    Ok(Value::Unit)
+                }
+            }
+            ExprKind::While { cond, body } => {
+                while self.condition(env, cond)? {
+                    match self.block(env, body) {
+                        Ok(_) | Err(Unwind::Continue) => {}
+                        Err(Unwind::Break(_)) => break,
+                        Err(other) => return Err(other),
+                    }
+                }
+                Ok(Value::Unit)
+            }
+            ExprKind::For {
+                binding_id,
+                iter,
+                body,
+                ..
+            } => {
+                let range = self.eval(env, iter)?;
+                let Value::Range(start, end) = range else {
+                    return self.iterate(env, expr, range, *binding_id, body);
+                };
+                let is_byte = self.results(env).binding_types.get(binding_id) == Some(&Ty::Byte);
+                for i in start..end {
+                    // A fresh slot per iteration, so closures keep the value
+                    // they saw.
+                    let value = if is_byte {
+                        Value::Byte(i as u8)
+                    } else {
+                        Value::Int(i)
+                    };
+                    env.locals.insert(*binding_id, slot(value));
+                    match self.block(env, body) {
+                        Ok(_) | Err(Unwind::Continue) => {}
+                        Err(Unwind::Break(_)) => break,
+                        Err(other) => return Err(other),
+                    }
+                }
+                Ok(Value::Unit)
+            }
+            ExprKind::Loop(body) => loop {
+                match self.block(env, body) {
+                    Ok(_) | Err(Unwind::Continue) => {}
+                    Err(Unwind::Break(value)) => return Ok(value),
+                    Err(other) => return Err(other),
+                }
+            },
+            ExprKind::Match { scrutinee, arms } => self.eval_match(env, expr, scrutinee, arms),
+            ExprKind::Break(value) => {
+                let value = match value {
+                    Some(value) => self.eval(env, value)?,
+                    None => Value::Unit,
+                };
+                Err(Unwind::Break(value))
+            }
+            ExprKind::Continue => Err(Unwind::Continue),
+            ExprKind::Return(value) => {
+                let value = match value {
+                    Some(value) => self.eval(env, value)?,
+                    None => Value::Unit,
+                };
+                Err(Unwind::Return(value))
+            }
+            ExprKind::Ref { expr: inner, .. } | ExprKind::RawRef { expr: inner, .. } => {
+                Ok(Value::Ref(self.place(env, inner)?))
+            }
+            ExprKind::Deref(inner) => match self.eval(env, inner)? {
+                Value::Ref(pointer) => Ok(pointer.read()),
+                other => self.error(format!("`{}` cannot be dereferenced", other), expr.span),
+            },
+            ExprKind::Try(inner) => self.try_expr(env, expr, inner),
+            ExprKind::Asm(_) => {
+                self.error("inline assembly can't be run by the interpreter", expr.span)
+            }
+            ExprKind::Closure(closure) => {
+                self.closures.insert((env.module, expr.id), closure);
+                Ok(Value::Closure(Rc::new(ClosureValue {
+                    id: expr.id,
+                    module: env.module,
+                    locals: env.locals.clone(),
+                    self_slot: env.self_slot.clone(),
+                })))
+            }
+        }
+    }
+
+    fn condition(&mut self, env: &mut Env, cond: &'a Expr) -> Eval<bool> {
+        match self.eval(env, cond)? {
+            Value::Bool(b) => Ok(b),
+            other => self.error(format!("expected a `bool`, found `{}`", other), cond.span),
+        }
+    }
+
+    fn literal(&self, env: &Env, expr: &Expr, literal: &Literal) -> Eval {
+        Ok(match literal {
+            Literal::Int(n) => {
+                if self.results(env).type_of(expr.id) == Some(&Ty::Byte) {
+                    match u8::try_from(*n) {
+                        Ok(b) => Value::Byte(b),
+                        Err(_) => {
+                            return self.error(
+                                format!("literal `{}` is out of range for `byte`", n),
+                                expr.span,
+                            );
+                        }
+                    }
+                } else {
+                    match i64::try_from(*n) {
+                        Ok(n) => Value::Int(n),
+                        Err(_) => {
+                            return self.error(
+                                format!("literal `{}` is out of range for `int`", n),
+                                expr.span,
+                            );
+                        }
+                    }
+                }
+            }
+            Literal::Float(x) => Value::Float(*x),
+            Literal::Str(s) => Value::Str(s.clone()),
+            Literal::Bool(b) => Value::Bool(*b),
+            Literal::Char(c) => Value::Char(*c),
+        })
+    }
+
+    fn path(&mut self, env: &mut Env, expr: &'a Expr, segments: &[Ident]) -> Eval {
+        match self.res(env, expr.id) {
+            Some(Res::Local(_) | Res::SelfValue | Res::Global(_)) => {
+                Ok(self.place(env, expr)?.read())
+            }
+            Some(Res::Function(name)) => Ok(Value::Function(Callable::Function(name.clone()))),
+            Some(Res::AssocFn { ty, name }) => Ok(Value::Function(Callable::Method {
+                ty: ty.clone(),
+                name: name.clone(),
+            })),
+            Some(Res::Variant { union, variant }) => {
+                let has_fields = self
+                    .items(env)
+                    .unions
+                    .get(union)
+                    .and_then(|def| def.variant(variant))
+                    .is_some_and(|fields| !fields.is_empty());
+                Ok(if has_fields {
+                    Value::Function(Callable::Variant {
+                        union: union.clone(),
+                        variant: variant.clone(),
+                    })
+                } else {
+                    Value::Variant {
+                        union: union.clone(),
+                        variant: variant.clone(),
+                        fields: Vec::new(),
+                    }
+                })
+            }
+            _ => self.error(
+                format!("cannot evaluate `{}`", join_path(segments)),
+                expr.span,
+            ),
+        }
+    }
+
+    /// Where the value of a place expression lives. Other expressions are
+    /// evaluated into a fresh temporary.
+    fn place(&mut self, env: &mut Env, expr: &'a Expr) -> Eval<Pointer> {
+        match &expr.kind {
+            ExprKind::Path(segments)
+                if matches!(
+                    self.res(env, expr.id),
+                    Some(Res::Local(_) | Res::SelfValue | Res::Global(_))
+                ) =>
+            {
+                self.path_place(env, expr, segments)
+            }
+            ExprKind::Field { base, name } => {
+                let base = self.place(env, base)?;
+                self.project(base, name)
+            }
+            ExprKind::Deref(inner) => match self.eval(env, inner)? {
+                Value::Ref(pointer) => Ok(pointer),
+                other => self.error(format!("`{}` cannot be dereferenced", other), expr.span),
+            },
+            _ => {
+                let value = self.eval(env, expr)?;
+                Ok(Pointer::new(slot(value)))
+            }
+        }
+    }
+
+    /// The field `name` of the record or tuple at `pointer`, looking through
+    /// references like the checker does.
+    fn project(&self, mut pointer: Pointer, name: &Ident) -> Eval<Pointer> {
+        loop {
+            match pointer.read() {
+                Value::Ref(inner) => pointer = inner,
+                Value::Record {
+                    name: record,
+                    fields,
+                } => {
+                    let index = fields.iter().position(|(n, _)| *n == name.name);
+                    return match index {
+                        Some(index) => {
+                            let mut field = pointer.project(index);
+                            field.bits = self.bitfield(&record, index);
+                            Ok(field)
+                        }
+                        None => self.error(
+                            format!("no field `{}` on `{}`", name.name, record),
+                            name.span,
+                        ),
+                    };
+                }
+                Value::Tuple(elems) => {
+                    return match name.name.parse::<usize>() {
+                        Ok(index) if index < elems.len() => Ok(pointer.project(index)),
+                        _ => self.error(
+                            format!(
+                                "index {} is out of bounds for a tuple of {} element(s)",
+                                name.name,
+                                elems.len()
+                            ),
+                            name.span,
+                        ),
+                    };
+                }
+                other => {
+                    return self.error(
+                        format!("`{}` has no field `{}`", other, name.name),
+                        name.span,
+                    );
+                }
+            }
+        }
+    }
+
+    /// The width of field `index` of `record` if it is a bitfield.
+    fn bitfield(&self, record: &str, index: usize) -> Option<u32> {
+        self.checked
+            .iter()
+            .find_map(|(items, _)| items.records.get(record))
+            .and_then(|def| def.bits(index))
+    }
+
+    fn record(&mut self, env: &mut Env, expr: &'a Expr, inits: &'a [FieldInit]) -> Eval {
+        let Some(Res::Record(name)) = self.res(env, expr.id) else {
+            return self.error("cannot evaluate this record literal", expr.span);
+        };
+        let mut values = HashMap::new();
+        for init in inits {
+            let value = self.eval(env, &init.value)?;
+            values.insert(init.name.name.as_str(), value);
+        }
+        let Some(def) = self.items(env).records.get(name) else {
+            return self.error(format!("cannot find record `{}`", name), expr.span);
+        };
+        // Only `Option` fields may be left out; they start as `None`.
+        let fields = def
+            .fields
+            .iter()
+            .enumerate()
+            .map(|(index, (field, _))| {
+                let value = values
+                    .remove(field.as_str())
+                    .unwrap_or_else(|| Value::Variant {
+                        union: prelude::OPTION.to_string(),
+                        variant: "None".to_string(),
+                        fields: Vec::new(),
+                    });
+                let value = match def.bits(index) {
+                    Some(width) => truncate(value, width),
+                    None => value,
+                };
+                (field.clone(), value)
+            })
+            .collect();
+        Ok(Value::Record {
+            name: name.clone(),
+            fields,
+        })
+    }
+
+    // ---------------------------------------------------------------------
+    // Calls
+    // ---------------------------------------------------------------------
+
+    fn call(&mut self, env: &mut Env, expr: &'a Expr, callee: &'a Expr, args: &'a [Arg]) -> Eval {
+        if let Some(Res::Builtin(name)) = self.res(env, callee.id) {
+            return self.builtin(env, expr, name, args);
+        }
+        // `value::method(..)` and `(expr)::method(..)`.
+        let receiver = match &callee.kind {
+            ExprKind::Path(segments)
+                if segments.len() >= 2
+                    && matches!(
+                        self.res(env, callee.id),
+                        Some(Res::Local(_) | Res::SelfValue | Res::Global(_))
+                    ) =>
+            {
+                let base = self.path_place(env, callee, &segments[..segments.len() - 1])?;
+                Some((base, segments.last().unwrap()))
+            }
+            ExprKind::Field { base, name } => Some((self.place(env, base)?, name)),
+            _ => None,
+        };
+        if let Some((receiver, method)) = receiver {
+            return self.method_call(env, expr, receiver, method, args);
+        }
+
+        let target = match self.res(env, callee.id) {
+            Some(Res::Function(name)) => self
+                .functions
+                .get(name)
+                .map(|&target| (target, name.clone())),
+            Some(Res::AssocFn { ty, name }) => self
+                .methods
+                .get(&(ty.clone(), name.clone()))
+                .map(|&target| (target, format!("{}::{}", ty, name))),
+            _ => None,
+        };
+        if let Some((target, name)) = target {
+            let args = self.args(env, target.1, args)?;
+            return self.call_function(target, name, None, args, expr.span);
+        }
+        let callee_value = self.eval(env, callee)?;
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(self.eval(env, &arg.value)?);
+        }
+        self.call_value(callee_value, values, expr.span)
+    }
+
+    /// The place named by `segments`, a path starting at a local, `self` or
+    /// a global, possibly only the receiver part of the path `path`.
+    fn path_place(&mut self, env: &mut Env, path: &Expr, segments: &[Ident]) -> Eval<Pointer> {
+        let base = match self.res(env, path.id) {
+            Some(Res::Local(id)) => env.locals.get(id).cloned(),
+            Some(Res::SelfValue) => env.self_slot.clone(),
+            Some(Res::Global(name)) => self.globals.get(name).cloned(),
+            _ => None,
+        };
+        let Some(base) = base else {
+            return self.error(
+                format!("cannot evaluate `{}`", join_path(segments)),
+                path.span,
+            );
+        };
+        // `util::base` names a global of another module outright; only a
+        // global named by its first segment can be followed by fields.
+        let fields = match self.res(env, path.id) {
+            Some(Res::Global(name))
+                if self.items(env).lookup_value(&segments[0].name) != Ok(name.as_str()) =>
+            {
+                &[][..]
+            }
+            _ => &segments[1..],
+        };
+        let mut pointer = Pointer::new(base);
+        for segment in fields {
+            pointer = self.project(pointer, segment)?;
+        }
+        Ok(pointer)
+    }
+
+    fn method_call(
+        &mut self,
+        env: &mut Env,
+        expr: &'a Expr,
+        mut receiver: Pointer,
+        method: &Ident,
+        args: &'a [Arg],
+    ) -> Eval {
+        // Methods take the record or union itself, or a reference to it.
+        while let Value::Ref(inner) = receiver.read() {
+            receiver = inner;
+        }
+        let value = receiver.read();
+        let ty = match self
+            .results(env)
+            .callees
+            .get(&expr.id)
+            .map(|c| &c.func.owner)
+        {
+            Some(FnOwner::Type(ty)) => Some(ty.clone()),
+            // Protocol methods run the receiver's implementation.
+            Some(FnOwner::Protocol(_)) => value.type_name(),
+            _ => None,
+        };
+        let target = ty.and_then(|ty| {
+            let target = self.methods.get(&(ty.clone(), method.name.clone()))?;
+            Some((*target, ty))
+        });
+        let Some((target, ty)) = target else {
+            // A closure stored in a field.
+            if let Value::Closure(closure) = self.project(receiver, method)?.read() {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(self.eval(env, &arg.value)?);
+                }
+                return self.call_closure(&closure, values, expr.span);
+            }
+            return self.error(
+                format!("no method `{}` on `{}`", method.name, value),
+                method.span,
+            );
+        };
+        let args = self.args(env, target.1, args)?;
+        let self_value = match target.1.self_param.as_ref().map(|param| param.kind) {
+            Some(SelfKind::Ref | SelfKind::RefMut) => Some(Value::Ref(receiver)),
+            Some(SelfKind::Value | SelfKind::MutValue) => Some(value),
+            None => None,
+        };
+        let name = format!("{}::{}", ty, method.name);
+        self.call_function(target, name, self_value, args, expr.span)
+    }
+
+    /// Evaluates call arguments in source order and arranges them in the
+    /// order of `function`'s parameters, matching labels.
+    fn args(&mut self, env: &mut Env, function: &Function, args: &'a [Arg]) -> Eval<Vec<Value>> {
+        let mut slots: Vec<Option<Value>> = vec![None; function.params.len()];
+        let mut next_positional = 0;
+        for arg in args {
+            let value = self.eval(env, &arg.value)?;
+            let index = match &arg.label {
+                Some(label) => function
+                    .params
+                    .iter()
+                    .position(|p| p.label.as_ref().unwrap_or(&p.name).name == label.name),
+                None => {
+                    while next_positional < slots.len() && slots[next_positional].is_some() {
+                        next_positional += 1;
+                    }
+                    Some(next_positional)
+                }
+            };
+            if let Some(slot) = index.and_then(|i| slots.get_mut(i)) {
+                *slot = Some(value);
+            }
+        }
+        Ok(slots
+            .into_iter()
+            .map(|value| value.unwrap_or(Value::Unit))
+            .collect())
+    }
+
+    /// `print` writes its argument and a newline; `exit` stops the program
+    /// with an integer exit code, or with an error when given a message.
+    fn builtin(&mut self, env: &mut Env, expr: &'a Expr, name: &str, args: &'a [Arg]) -> Eval {
+        if let Some(&value) = self.results(env).constants.get(&expr.id) {
+            return Ok(Value::Int(value));
+        }
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(self.eval(env, &arg.value)?);
+        }
+        let value = values.first().cloned().unwrap_or(Value::Unit);
+        match name {
+            "print" => {
+                if writeln!(self.out, "{}", value).is_err() {
+                    return self.error("failed to write the output", expr.span);
+                }
+                Ok(Value::Unit)
+            }
+            "exit" => match value {
+                Value::Int(code) => Err(Unwind::Exit(code as i32)),
+                Value::Byte(code) => Err(Unwind::Exit(code as i32)),
+                message => self.error(message.to_string(), expr.span),
+            },
+            // Every access is volatile here, and nothing is reordered. With
+            // a single thread, every access is atomic too.
+            "volatile_read" | "atomic_load" => match value {
+                Value::Ref(pointer) => Ok(pointer.read()),
+                other => self.error(format!("`{}` cannot be dereferenced", other), expr.span),
+            },
+            "volatile_write" | "atomic_store" => match (value, values.pop()) {
+                (Value::Ref(pointer), Some(new)) => {
+                    pointer.write(new);
+                    Ok(Value::Unit)
+                }
+                (other, _) => self.error(format!("`{}` cannot be dereferenced", other), expr.span),
+            },
+            "atomic_swap" | "atomic_compare_exchange" | "atomic_fetch_add" | "atomic_fetch_sub" => {
+                let Value::Ref(pointer) = value else {
+                    return self.error(format!("`{}` cannot be dereferenced", value), expr.span);
+                };
+                let old = pointer.read();
+                let new = match (name, &old, &values[1..]) {
+                    ("atomic_swap", _, [new]) => new.clone(),
+                    ("atomic_compare_exchange", _, [current, new]) if old == *current => {
+                        new.clone()
+                    }
+                    ("atomic_compare_exchange", _, _) => old.clone(),
+                    ("atomic_fetch_add", Value::Int(a), [Value::Int(b)]) => {
+                        Value::Int(a.wrapping_add(*b))
+                    }
+                    ("atomic_fetch_add", Value::Byte(a), [Value::Byte(b)]) => {
+                        Value::Byte(a.wrapping_add(*b))
+                    }
+                    ("atomic_fetch_sub", Value::Int(a), [Value::Int(b)]) => {
+                        Value::Int(a.wrapping_sub(*b))
+                    }
+                    ("atomic_fetch_sub", Value::Byte(a), [Value::Byte(b)]) => {
+                        Value::Byte(a.wrapping_sub(*b))
+                    }
+                    _ => return self.error(format!("bad arguments to `{}`", name), expr.span),
+                };
+                pointer.write(new);
+                Ok(old)
+            }
+            "fence" | "load_fence" | "store_fence" => Ok(Value::Unit),
+            "inb" | "inw" | "inl" | "outb" | "outw" | "outl" => {
+                self.error("port I/O can't be run by the interpreter", expr.span)
+            }
+            _ => self.error(format!("unknown builtin `{}`", name), expr.span),
+        }
+    }
+
+    /// A `for` loop over a user-defined iterator: calls its `next` method
+    /// until it returns `None`.
+    fn iterate(
+        &mut self,
+        env: &mut Env,
+        expr: &'a Expr,
+        iterator: Value,
+        binding: NodeId,
+        body: &'a Block,
+    ) -> Eval {
+        let method = prelude::ITERATOR_METHOD.to_string();
+        let target = iterator
+            .type_name()
+            .and_then(|ty| Some((*self.methods.get(&(ty.clone(), method.clone()))?, ty)));
+        let Some((target, ty)) = target else {
+            return self.error(format!("`{}` cannot be iterated over", iterator), expr.span);
+        };
+        let name = format!("{}::{}", ty, method);
+        let iterator = slot(iterator);
+        loop {
+            let receiver = Value::Ref(Pointer::new(iterator.clone()));
+            let next =
+                self.call_function(target, name.clone(), Some(receiver), Vec::new(), expr.span)?;
+            let Value::Variant {
+                variant,
+                mut fields,
+                ..
+            } = next
+            else {
+                return self.error(format!("`{}` returned `{}`", name, next), expr.span);
+            };
+            if variant != "Some" || fields.is_empty() {
+                return Ok(Value::Unit);
+            }
+            env.locals.insert(binding, slot(fields.remove(0)));
+            match self.block(env, body) {
+                Ok(_) | Err(Unwind::Continue) => {}
+                Err(Unwind::Break(_)) => return Ok(Value::Unit),
+                Err(other) => return Err(other),
+            }
+        }
+    }
+
+    /// `value?`: the value inside `Some`/`Ok`, or an early return of
+    /// `None`/`Err`, converting the error when the checker asked for it.
+    fn try_expr(&mut self, env: &mut Env, expr: &'a Expr, inner: &'a Expr) -> Eval {
+        let Value::Variant {
+            union,
+            variant,
+            mut fields,
+        } = deref(self.eval(env, inner)?)
+        else {
+            return self.error("`?` needs an `Option` or a `Result`", expr.span);
+        };
+        match variant.as_str() {
+            "Some" | "Ok" if !fields.is_empty() => Ok(fields.remove(0)),
+            "Err" if !fields.is_empty() => {
+                let mut error = fields.remove(0);
+                if self.results(env).callees.contains_key(&expr.id) {
+                    let Some(ty) = error.type_name() else {
+                        return self.error("cannot convert this error", expr.span);
+                    };
+                    let method = prelude::INTO_ERROR_METHOD.to_string();
+                    let Some(&target) = self.methods.get(&(ty.clone(), method.clone())) else {
+                        return self
+                            .error(format!("`{}` has no `{}` method", ty, method), expr.span);
+                    };
+                    let name = format!("{}::{}", ty, method);
+                    error = self.call_function(target, name, Some(error), Vec::new(), expr.span)?;
+                }
+                Err(Unwind::Return(Value::Variant {
+                    union,
+                    variant,
+                    fields: vec![error],
+                }))
+            }
+            _ => Err(Unwind::Return(Value::Variant {
+                union,
+                variant,
+                fields,
+            })),
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Patterns
+    // ---------------------------------------------------------------------
+
+    fn eval_match(
+        &mut self,
+        env: &mut Env,
+        expr: &'a Expr,
+        scrutinee: &'a Expr,
+        arms: &'a [Arm],
+    ) -> Eval {
+        let value = self.eval(env, scrutinee)?;
+        for arm in arms {
+            let mut bindings = Vec::new();
+            if self.matches(env, &arm.pattern, &value, &mut bindings) {
+                for (id, value) in bindings {
+                    env.locals.insert(id, slot(value));
+                }
+                return self.eval(env, &arm.body);
+            }
+        }
+        self.error(format!("no `match` arm matches `{}`", value), expr.span)
+    }
+
+    fn matches(
+        &self,
+        env: &Env,
+        pattern: &Pattern,
+        value: &Value,
+        bindings: &mut Vec<(NodeId, Value)>,
+    ) -> bool {
+        if let Value::Ref(pointer) = value
+            && !matches!(
+                pattern.kind,
+                PatternKind::Wildcard | PatternKind::Binding(_)
+            )
+        {
+            return self.matches(env, pattern, &pointer.read(), bindings);
+        }
+        match &pattern.kind {
+            PatternKind::Wildcard => true,
+            PatternKind::Literal(literal) => literal_matches(literal, value),
+            PatternKind::Binding(name) => {
+                // A bare variant name such as `None`, as in the resolver.
+                let is_variant = self
+                    .items(env)
+                    .unions
+                    .values()
+                    .any(|union| union.variant(&name.name).is_some());
+                if is_variant {
+                    return matches!(deref(value.clone()), Value::Variant { variant, .. } if variant == name.name);
+                }
+                bindings.push((pattern.id, value.clone()));
+                true
+            }
+            PatternKind::Variant { path, fields } => {
+                let Value::Variant {
+                    union,
+                    variant,
+                    fields: values,
+                } = value
+                else {
+                    return false;
+                };
+                if let Some(Res::Variant {
+                    union: expected, ..
+                }) = self.res(env, pattern.id)
+                    && expected != union
+                {
+                    return false;
+                }
+                *variant == path.last().unwrap().name
+                    && fields.len() == values.len()
+                    && fields
+                        .iter()
+                        .zip(values)
+                        .all(|(field, value)| self.matches(env, field, value, bindings))
+            }
+            PatternKind::Tuple(elems) => match value {
+                Value::Tuple(values) => {
+                    elems.len() == values.len()
+                        && elems
+                            .iter()
+                            .zip(values)
+                            .all(|(elem, value)| self.matches(env, elem, value, bindings))
+                }
+                Value::Unit => elems.is_empty(),
+                _ => false,
+            },
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Operators
+    // ---------------------------------------------------------------------
+
+    fn unary(&self, op: UnaryOp, value: Value, span: Span) -> Eval {
+        match (op, value) {
+            (UnaryOp::Neg, Value::Int(n)) => match n.checked_neg() {
+                Some(n) => Ok(Value::Int(n)),
+                None => self.error("attempt to negate with overflow", span),
+            },
+            (UnaryOp::Neg, Value::Float(x)) => Ok(Value::Float(-x)),
+            (UnaryOp::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+            (UnaryOp::Not, Value::Int(n)) => Ok(Value::Int(!n)),
+            (UnaryOp::Not, Value::Byte(b)) => Ok(Value::Byte(!b)),
+            (_, value) => self.error(format!("cannot apply this operator to `{}`", value), span),
+        }
+    }
+
+    fn binary(&self, op: BinOp, lhs: Value, rhs: Value, span: Span) -> Eval {
+        let (lhs, rhs) = (deref(lhs), deref(rhs));
+        match op {
+            BinOp::Eq => return Ok(Value::Bool(lhs == rhs)),
+            BinOp::Ne => return Ok(Value::Bool(lhs != rhs)),
+            BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge => {
+                let Some(ordering) = compare(&lhs, &rhs) else {
+                    return self.error(format!("cannot compare `{}` with `{}`", lhs, rhs), span);
+                };
+                let result = match op {
+                    BinOp::Lt => ordering.is_lt(),
+                    BinOp::Gt => ordering.is_gt(),
+                    BinOp::Le => ordering.is_le(),
+                    _ => ordering.is_ge(),
+                };
+                return Ok(Value::Bool(result));
+            }
+            _ => {}
+        }
+        match (lhs, rhs) {
+            (Value::Int(a), Value::Int(b)) => {
+                let result = match op {
+                    BinOp::Add => a.checked_add(b),
+                    BinOp::Sub => a.checked_sub(b),
+                    BinOp::Mul => a.checked_mul(b),
+                    BinOp::Div | BinOp::Rem if b == 0 => return self.division_by_zero(op, span),
+                    BinOp::Div => a.checked_div(b),
+                    BinOp::Rem => a.checked_rem(b),
+                    BinOp::BitAnd => Some(a & b),
+                    BinOp::BitOr => Some(a | b),
+                    BinOp::BitXor => Some(a ^ b),
+                    _ => return self.error("unsupported operator for `int`", span),
+                };
+                match result {
+                    Some(n) => Ok(Value::Int(n)),
+                    None => self.overflow(op, span),
+                }
+            }
+            (Value::Byte(a), Value::Byte(b)) => {
+                let result = match op {
+                    BinOp::Add => a.checked_add(b),
+                    BinOp::Sub => a.checked_sub(b),
+                    BinOp::Mul => a.checked_mul(b),
+                    BinOp::Div | BinOp::Rem if b == 0 => return self.division_by_zero(op, span),
+                    BinOp::Div => a.checked_div(b),
+                    BinOp::Rem => a.checked_rem(b),
+                    BinOp::BitAnd => Some(a & b),
+                    BinOp::BitOr => Some(a | b),
+                    BinOp::BitXor => Some(a ^ b),
+                    _ => return self.error("unsupported operator for `byte`", span),
+                };
+                match result {
+                    Some(b) => Ok(Value::Byte(b)),
+                    None => self.overflow(op, span),
+                }
+            }
+            (Value::Float(a), Value::Float(b)) => Ok(Value::Float(match op {
+                BinOp::Add => a + b,
+                BinOp::Sub => a - b,
+                BinOp::Mul => a * b,
+                BinOp::Div => a / b,
+                _ => return self.error("unsupported operator for `float`", span),
+            })),
+            (Value::Str(a), Value::Str(b)) if op == BinOp::Add => Ok(Value::Str(a + &b)),
+            (lhs, rhs) => self.error(
+                format!("cannot apply `{}` to `{}` and `{}`", op.symbol(), lhs, rhs),
+                span,
+            ),
+        }
+    }
+
+    fn overflow(&self, op: BinOp, span: Span) -> Eval {
+        let verb = match op {
+            BinOp::Add => "add",
+            BinOp::Sub => "subtract",
+            BinOp::Mul => "multiply",
+            BinOp::Div => "divide",
+            _ => "calculate the remainder",
+        };
+        self.error(format!("attempt to {} with overflow", verb), span)
+    }
+
+    fn division_by_zero(&self, op: BinOp, span: Span) -> Eval {
+        if op == BinOp::Div {
+            self.error("attempt to divide by zero", span)
+        } else {
+            self.error(
+                "attempt to calculate the remainder with a divisor of zero",
+                span,
+            )
+        }
+    }
+}
+
+/// The value a reference points to; other values as they are.
+fn deref(value: Value) -> Value {
+    match value {
+        Value::Ref(pointer) => deref(pointer.read()),
+        other => other,
+    }
+}
+
+fn integer(value: &Value) -> Option<i64> {
+    match value {
+        Value::Int(n) => Some(*n),
+        Value::Byte(b) => Some(i64::from(*b)),
+        _ => None,
+    }
+}
+
+fn compare(lhs: &Value, rhs: &Value) -> Option<std::cmp::Ordering> {
+    match (lhs, rhs) {
+        (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+        (Value::Byte(a), Value::Byte(b)) => Some(a.cmp(b)),
+        (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+        (Value::Char(a), Value::Char(b)) => Some(a.cmp(b)),
+        (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+fn literal_matches(literal: &Literal, value: &Value) -> bool {
+    match (literal, value) {
+        (Literal::Int(n), value) => integer(value).is_some_and(|v| i64::try_from(*n) == Ok(v)),
+        (Literal::Float(x), Value::Float(y)) => x == y,
+        (Literal::Str(s), Value::Str(t)) => s == t,
+        (Literal::Bool(a), Value::Bool(b)) => a == b,
+        (Literal::Char(a), Value::Char(b)) => a == b,
+        _ => false,
+    }
+}
@@ -0,0 +1,275 @@
+use crate::loader::ModuleId;
+use crate::parser::ast::NodeId;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// Storage for one local, global or temporary. References point into a slot,
+/// so writes through them are seen by every other use of the slot.
+pub type Slot = Rc<RefCell<Value>>;
+
+/// A runtime value. Records, unions and tuples are stored inline and copied
+/// on assignment, like the values they model; only references share.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Int(i64),
+    Byte(u8),
+    Float(f64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    Unit,
+    Tuple(Vec<Value>),
+    /// Fields in declaration order.
+    Record {
+        name: String,
+        fields: Vec<(String, Value)>,
+    },
+    Variant {
+        union: String,
+        variant: String,
+        fields: Vec<Value>,
+    },
+    /// `ref x`, `raw_ref x` and protocol objects.
+    Ref(Pointer),
+    /// `start..end`, iterated by `for`.
+    Range(i64, i64),
+    Function(Callable),
+    Closure(Rc<ClosureValue>),
+}
+
+/// A place inside a slot: the slot's value, then a field or tuple element
+/// index per step.
+#[derive(Debug, Clone)]
+pub struct Pointer {
+    pub slot: Slot,
+    pub path: Vec<usize>,
+    /// The width of the bitfield pointed to; writes keep its low bits.
+    pub bits: Option<u32>,
+}
+
+impl Pointer {
+    pub fn new(slot: Slot) -> Self {
+        Self {
+            slot,
+            path: Vec::new(),
+            bits: None,
+        }
+    }
+
+    pub fn project(&self, index: usize) -> Self {
+        let mut path = self.path.clone();
+        path.push(index);
+        Self {
+            slot: self.slot.clone(),
+            path,
+            bits: None,
+        }
+    }
+
+    pub fn read(&self) -> Value {
+        let value = self.slot.borrow();
+        let mut value = &*value;
+        for &index in &self.path {
+            value = value.part(index).expect("pointer into a missing field");
+        }
+        value.clone()
+    }
+
+    pub fn write(&self, new: Value) {
+        let mut value = self.slot.borrow_mut();
+        let mut value = &mut *value;
+        for &index in &self.path {
+            value = value.part_mut(index).expect("pointer into a missing field");
+        }
+        *value = match self.bits {
+            Some(width) => truncate(new, width),
+            None => new,
+        };
+    }
+
+    fn same(&self, other: &Pointer) -> bool {
+        Rc::ptr_eq(&self.slot, &other.slot) && self.path == other.path
+    }
+}
+
+/// The low `width` bits of an integer stored in a bitfield.
+pub fn truncate(value: Value, width: u32) -> Value {
+    let mask = u64::MAX.checked_shr(64 - width).unwrap_or(0);
+    match value {
+        Value::Int(n) => Value::Int((n as u64 & mask) as i64),
+        Value::Byte(b) => Value::Byte((b as u64 & mask) as u8),
+        other => other,
+    }
+}
+
+/// Something a call expression can invoke by name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Callable {
+    /// A free function, by canonical name.
+    Function(String),
+    /// A method or associated function of a record or union.
+    Method { ty: String, name: String },
+    /// A union variant with fields, used as a constructor.
+    Variant { union: String, variant: String },
+}
+
+/// A closure, by the id of its expression, and the locals it can see.
+/// Captured locals share their slots with the enclosing function, so
+/// changes are visible both ways.
+#[derive(Debug)]
+pub struct ClosureValue {
+    pub id: NodeId,
+    pub module: ModuleId,
+    pub locals: HashMap<NodeId, Slot>,
+    pub self_slot: Option<Slot>,
+}
+
+impl Value {
+    /// The field or tuple element at `index`.
+    pub fn part(&self, index: usize) -> Option<&Value> {
+        match self {
+            Value::Tuple(elems) | Value::Variant { fields: elems, .. } => elems.get(index),
+            Value::Record { fields, .. } => fields.get(index).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn part_mut(&mut self, index: usize) -> Option<&mut Value> {
+        match self {
+            Value::Tuple(elems) | Value::Variant { fields: elems, .. } => elems.get_mut(index),
+            Value::Record { fields, .. } => fields.get_mut(index).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// The canonical name of the record or union this value belongs to,
+    /// looking through references.
+    pub fn type_name(&self) -> Option<String> {
+        match self {
+            Value::Record { name, .. } => Some(name.clone()),
+            Value::Variant { union, .. } => Some(union.clone()),
+            Value::Ref(pointer) => pointer.read().type_name(),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Byte(a), Value::Byte(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Char(a), Value::Char(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Unit, Value::Unit) => true,
+            (Value::Tuple(a), Value::Tuple(b)) => a == b,
+            (
+                Value::Record { name, fields },
+                Value::Record {
+                    name: other,
+                    fields: other_fields,
+                },
+            ) => name == other && fields == other_fields,
+            (
+                Value::Variant {
+                    union,
+                    variant,
+                    fields,
+                },
+                Value::Variant {
+                    union: other_union,
+                    variant: other_variant,
+                    fields: other_fields,
+                },
+            ) => union == other_union && variant == other_variant && fields == other_fields,
+            // References compare what they point to.
+            (Value::Ref(a), Value::Ref(b)) => a.same(b) || a.read() == b.read(),
+            (Value::Range(a, b), Value::Range(c, d)) => a == c && b == d,
+            (Value::Function(a), Value::Function(b)) => a == b,
+            (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+/// How `print` shows a value. Strings and chars are written as is; inside
+/// compound values they are quoted.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Char(c) => write!(f, "{}", c),
+            other => write_nested(f, other),
+        }
+    }
+}
+
+fn write_nested(f: &mut fmt::Formatter<'_>, value: &Value) -> fmt::Result {
+    match value {
+        Value::Int(n) => write!(f, "{}", n),
+        Value::Byte(b) => write!(f, "{}", b),
+        Value::Float(x) => write!(f, "{:?}", x),
+        Value::Bool(b) => write!(f, "{}", b),
+        Value::Char(c) => write!(f, "{:?}", c),
+        Value::Str(s) => write!(f, "{:?}", s),
+        Value::Unit => write!(f, "()"),
+        Value::Tuple(elems) => {
+            write!(f, "(")?;
+            write_list(f, elems)?;
+            write!(f, ")")
+        }
+        Value::Record { name, fields } => {
+            write!(f, "{} {{ ", short_name(name))?;
+            for (i, (field, value)) in fields.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}: ", field)?;
+                write_nested(f, value)?;
+            }
+            write!(f, " }}")
+        }
+        Value::Variant {
+            variant, fields, ..
+        } => {
+            write!(f, "{}", variant)?;
+            if !fields.is_empty() {
+                write!(f, "(")?;
+                write_list(f, fields)?;
+                write!(f, ")")?;
+            }
+            Ok(())
+        }
+        Value::Ref(pointer) => write_nested(f, &pointer.read()),
+        Value::Range(start, end) => write!(f, "{}..{}", start, end),
+        Value::Function(Callable::Function(name)) => write!(f, "@{}", short_name(name)),
+        Value::Function(Callable::Method { ty, name }) => {
+            write!(f, "@{}::{}", short_name(ty), name)
+        }
+        Value::Function(Callable::Variant { union, variant }) => {
+            write!(f, "@{}::{}", short_name(union), variant)
+        }
+        Value::Closure(_) => write!(f, "@closure"),
+    }
+}
+
+fn write_list(f: &mut fmt::Formatter<'_>, values: &[Value]) -> fmt::Result {
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write_nested(f, value)?;
+    }
+    Ok(())
+}
+
+/// `human` for `shapes.people::human`.
+fn short_name(canonical: &str) -> &str {
+    canonical
+        .rsplit_once("::")
+        .map_or(canonical, |(_, name)| name)
+}
@@ -1,8 +1,11 @@
 pub mod size;
 pub mod tokens;
+use std::collections::VecDeque;
 use std::iter::Peekable;
 use std::str::CharIndices;
 
+use crate::errorhandler::Diagnostic;
+use size::Span;
 use tokens::{Literal, Token, TokenType};
 
 // Use Peekable to avoid O(n) cost of chars().nth(0) and to peek without consuming.
@@ -13,15 +16,27 @@ pub struct Lexer<'l> {
     // program has a lifetime tied to the lexer, ensuring it lives as long as the lexer safe to reference throughout.
     program: &'l str,
     chars: Peekable<CharIndices<'l>>,
+    /// Malformed literals, lexed as `0` so that parsing can go on, and
+    /// stray characters, which are skipped.
+    diagnostics: Vec<Diagnostic>,
+    /// The rest of an interpolated string, lexed with its first segment.
+    pending: VecDeque<Token>,
 }
 
 impl<'l> Lexer<'l> {
     pub fn new(program: &'l str) -> Self {
         Self {
-            program: program,
+            program,
             chars: program.char_indices().peekable(),
+            diagnostics: Vec::new(),
+            pending: VecDeque::new(),
         }
     }
+
+    /// The problems found in the tokens lexed so far.
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
     fn peek(&mut self) -> Option<(usize, char)> {
         self.chars.peek().copied()
     }
@@ -46,13 +61,61 @@ impl<'l> Lexer<'l> {
             self.advance();
         }
     }
+    /// Lexes a string. One with `${expr}` in it becomes its segments with
+    /// the tokens of each expression between `Interpolate` and
+    /// `InterpolEnd`: `"a${x}b"` is `"a"`, `${`, `x`, `}`, `"b"`.
     fn read_string_literal(&mut self, start: usize) -> Token {
         let mut end = start;
         let mut value = String::new();
+        let mut tokens = Vec::new();
+        let mut segment = start;
+        let mut closed = false;
         self.advance(); // Consume opening quote
 
         while let Some((idx, ch)) = self.advance() {
             match ch {
+                '$' if matches!(self.peek(), Some((_, '{'))) => {
+                    self.advance();
+                    let text = std::mem::take(&mut value);
+                    tokens.push(Token::new(
+                        segment,
+                        idx - segment,
+                        TokenType::Literal(Literal::Str(text)),
+                    ));
+                    tokens.push(Token::new(idx, 2, TokenType::Interpolate));
+                    // The expression may hold strings of its own, whose
+                    // tokens are queued while they are lexed.
+                    let outer = std::mem::take(&mut self.pending);
+                    let mut depth = 0;
+                    loop {
+                        let token = self.advance_token();
+                        match token.token_type {
+                            TokenType::Eof => {
+                                self.diagnostics.push(Diagnostic::error(
+                                    "`${` is never closed",
+                                    Span::new(idx, idx + 2),
+                                ));
+                                self.pending = outer;
+                                return tokens.remove(0);
+                            }
+                            TokenType::LCurly => depth += 1,
+                            TokenType::RCurly if depth == 0 => {
+                                end = token.size.start + 1;
+                                tokens.push(Token::new(
+                                    token.size.start,
+                                    1,
+                                    TokenType::InterpolEnd,
+                                ));
+                                break;
+                            }
+                            TokenType::RCurly => depth -= 1,
+                            _ => {}
+                        }
+                        tokens.push(token);
+                    }
+                    self.pending = outer;
+                    segment = end;
+                }
                 '\\' => {
                     if let Some((_, esc)) = self.advance() {
                         end = idx + 1;
@@ -62,6 +125,7 @@ impl<'l> Lexer<'l> {
                             'r' => value.push('\r'),
                             '"' => value.push('"'),
                             '\\' => value.push('\\'),
+                            '$' => value.push('$'),
                             _ => {
                                 value.push('\\');
                                 value.push(esc);
@@ -73,6 +137,7 @@ impl<'l> Lexer<'l> {
                 }
                 '"' => {
                     end = idx + 1;
+                    closed = true;
                     break;
                 }
                 _ => {
@@ -81,38 +146,67 @@ impl<'l> Lexer<'l> {
                 }
             }
         }
+        if !closed {
+            let line = self.program[start..]
+                .find('\n')
+                .map_or(end, |len| start + len);
+            self.diagnostics.push(Diagnostic::error(
+                "this string is never closed",
+                Span::new(start, line),
+            ));
+        }
 
-        Token::new(start, end - start, TokenType::Literal(Literal::Str(value)))
+        tokens.push(Token::new(
+            segment,
+            end - segment,
+            TokenType::Literal(Literal::Str(value)),
+        ));
+        let first = tokens.remove(0);
+        self.pending.extend(tokens);
+        first
     }
 
+    /// Lexes a character literal. A malformed one is reported and lexed as
+    /// `'\0'`; one never closed ends with its line.
     fn read_char_literal(&mut self, start: usize) -> Token {
         self.advance(); // Consume opening quote
-        let value = match self.advance() {
-            Some((_, '\\')) => {
-                if let Some((_, esc)) = self.advance() {
-                    match esc {
-                        'n' => '\n',
-                        't' => '\t',
-                        'r' => '\r',
-                        '\'' => '\'',
-                        '\\' => '\\',
-                        _ => esc,
+        let mut chars = Vec::new();
+        let mut closed = None;
+        while let Some((idx, ch)) = self.peek() {
+            if ch == '\n' {
+                break;
+            }
+            self.advance();
+            match ch {
+                '\'' => {
+                    closed = Some(idx + 1);
+                    break;
+                }
+                '\\' => {
+                    if let Some((_, esc)) = self.peek().filter(|&(_, c)| c != '\n') {
+                        self.advance();
+                        chars.push(match esc {
+                            'n' => '\n',
+                            't' => '\t',
+                            'r' => '\r',
+                            _ => esc,
+                        });
                     }
-                } else {
-                    panic!("Unterminated char literal");
                 }
+                _ => chars.push(ch),
             }
-            Some((_, ch)) => ch,
-            None => panic!("Unterminated char literal"),
-        };
-
-        let end = if let Some((idx, '\'')) = self.advance() {
-            idx + 1
-        } else {
-            panic!("Expected closing quote");
+        }
+        let end = closed.unwrap_or_else(|| self.peek().map_or(self.program.len(), |(idx, _)| idx));
+        let message = match (closed, chars.as_slice()) {
+            (Some(_), [ch]) => {
+                return Token::new(start, end - start, TokenType::Literal(Literal::Char(*ch)));
+            }
+            (Some(_), _) => "a character literal holds exactly one character",
+            (None, _) => "this character literal is never closed",
         };
-
-        Token::new(start, end - start, TokenType::Literal(Literal::Char(value)))
+        self.diagnostics
+            .push(Diagnostic::error(message, Span::new(start, end)));
+        Token::new(start, end - start, TokenType::Literal(Literal::Char('\0')))
     }
 
     fn read_identifier(&mut self, start: usize) -> &str {
@@ -127,11 +221,52 @@ impl<'l> Lexer<'l> {
         }
         &self.program[start..end]
     }
+    fn read_hex_literal(&mut self, start: usize) -> Token {
+        // Consume the `0x` / `0X` prefix
+        self.advance();
+        self.advance();
+        let mut end = start + 2;
+        while let Some((idx, ch)) = self.peek() {
+            if ch.is_ascii_hexdigit() {
+                self.advance();
+                end = idx + 1;
+            } else {
+                break;
+            }
+        }
+        let digits = &self.program[start + 2..end];
+        let value = if digits.is_empty() {
+            self.diagnostics.push(Diagnostic::error(
+                "`0x` must be followed by hex digits",
+                Span::new(start, end),
+            ));
+            0
+        } else {
+            self.int_value(usize::from_str_radix(digits, 16).ok(), start, end)
+        };
+        Token::new(start, end - start, TokenType::Literal(Literal::Int(value)))
+    }
+
+    /// `value`, or `0` after reporting the literal at `start..end` as too
+    /// large.
+    fn int_value(&mut self, value: Option<usize>, start: usize, end: usize) -> usize {
+        value.unwrap_or_else(|| {
+            self.diagnostics.push(Diagnostic::error(
+                format!("the literal `{}` is too large", &self.program[start..end]),
+                Span::new(start, end),
+            ));
+            0
+        })
+    }
+
     fn read_number_literal(&mut self, start: usize) -> Token {
+        if self.program[start..].starts_with("0x") || self.program[start..].starts_with("0X") {
+            return self.read_hex_literal(start);
+        }
         let mut end = start;
         let mut is_float = false;
         let mut has_exponent = false;
-        let mut valid = true;
+        let mut exponents = 0;
 
         while let Some((idx, ch)) = self.peek() {
             match ch {
@@ -159,18 +294,16 @@ impl<'l> Lexer<'l> {
                     }
                 }
                 'e' | 'E' => {
-                    if has_exponent {
-                        valid = false;
-                    }
+                    exponents += 1;
                     has_exponent = true;
                     self.advance();
                     end = idx + 1;
 
-                    if let Some((_, sign)) = self.peek() {
-                        if sign == '+' || sign == '-' {
-                            self.advance();
-                            end += 1;
-                        }
+                    if let Some((_, sign)) = self.peek()
+                        && (sign == '+' || sign == '-')
+                    {
+                        self.advance();
+                        end += 1;
                     }
                 }
                 _ => break,
@@ -178,19 +311,29 @@ impl<'l> Lexer<'l> {
         }
 
         let literal_str = &self.program[start..end];
-        if !valid {
-            panic!("Invalid number format: {}", literal_str);
-        }
-
         if is_float || has_exponent {
-            let value = literal_str.parse().unwrap();
+            let message = if exponents > 1 {
+                Some(format!("`{}` has more than one exponent", literal_str))
+            } else if literal_str.ends_with(['e', 'E', '+', '-']) {
+                Some(format!("the exponent of `{}` has no digits", literal_str))
+            } else {
+                None
+            };
+            let value = match message {
+                Some(message) => {
+                    self.diagnostics
+                        .push(Diagnostic::error(message, Span::new(start, end)));
+                    0.0
+                }
+                None => literal_str.parse().unwrap_or(0.0),
+            };
             Token::new(
                 start,
                 end - start,
                 TokenType::Literal(Literal::Float(value)),
             )
         } else {
-            let value = literal_str.parse().unwrap();
+            let value = self.int_value(literal_str.parse().ok(), start, end);
             Token::new(start, end - start, TokenType::Literal(Literal::Int(value)))
         }
     }
@@ -216,17 +359,6 @@ impl<'l> Lexer<'l> {
         }
     }
 
-    fn consume_double_with_panic(&mut self, second_char: char, default_type: TokenType) -> Token {
-        let (start, _) = self.advance().unwrap();
-        match self.peek() {
-            Some((_, c)) if c == second_char => {
-                self.advance();
-                Token::new(start, 2, default_type)
-            }
-            _ => panic!("Undetermined token"),
-        }
-    }
-
     fn consume_triple(
         &mut self,
         second_char: char,
@@ -249,6 +381,7 @@ impl<'l> Lexer<'l> {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn consume_quad(
         &mut self,
         second_char: char,
@@ -306,6 +439,7 @@ impl<'l> Lexer<'l> {
             "asm" => Asm,
             "continue" => Continue,
             "break" => Break,
+            "extern" => Extern,
             "true" => TokenType::Literal(Literal::Bool(true)),
             "false" => TokenType::Literal(Literal::Bool(false)),
             _ => Identifier,
@@ -315,6 +449,9 @@ impl<'l> Lexer<'l> {
 
     pub fn advance_token(&mut self) -> Token {
         use TokenType::*;
+        if let Some(token) = self.pending.pop_front() {
+            return token;
+        }
         // skip the white spaces in the code
         self.skip_whitespace();
 
@@ -324,9 +461,15 @@ impl<'l> Lexer<'l> {
         };
 
         match ch {
+            // `#[` opens an attribute; any other `#` a comment.
+            '#' if self.program[start + 1..].starts_with('[') => {
+                self.advance();
+                self.advance();
+                Token::new(start, 2, HashSquare)
+            }
             '#' => {
                 self.skip_comment();
-                return self.advance_token();
+                self.advance_token()
             }
             '@' => self.consume_single(Func),
             '{' => self.consume_single(LCurly),
@@ -350,16 +493,33 @@ impl<'l> Lexer<'l> {
             '|' => self.consume_double('|', PipePipe, Pipe),
             '/' => self.consume_double('=', SlashEqual, Slash),
             '!' => self.consume_double('=', ExclaimEqual, Exclaim),
+            '.' if self.program[start..].starts_with("...") => {
+                self.advance();
+                self.advance();
+                self.advance();
+                Token::new(start, 3, Ellipsis)
+            }
             '.' => self.consume_double('.', DotDot, Dot),
             '<' => self.consume_double('=', LessThanEqual, LessThan),
             '>' => self.consume_double('=', GreaterThanEqual, GreaterThan),
-            '$' => self.consume_double_with_panic('=', Destructure),
-            '=' => self.consume_double_with_panic('=', EqualEqual),
+            '$' if self.program[start..].starts_with("$=") => {
+                self.advance();
+                self.advance();
+                Token::new(start, 2, Destructure)
+            }
+            '=' => self.consume_double('=', EqualEqual, Equal),
             '0'..='9' => self.read_number_literal(start),
             // No identifier starts with _ for good sake
             '_' => self.consume_single(UnderScore),
             'a'..='z' | 'A'..='Z' => self.handle_identifier(start),
-            _ => panic!("Unexpected character: '{}' at position {}", ch, start),
+            _ => {
+                self.advance();
+                self.diagnostics.push(Diagnostic::error(
+                    format!("unexpected character `{}`", ch),
+                    Span::new(start, start + ch.len_utf8()),
+                ));
+                self.advance_token()
+            }
         }
     }
 }
@@ -446,6 +606,11 @@ mod tests {
                 input: " ",
                 expected_token: Token::new(0, 0, Eof),
             },
+            LexerTestCase {
+                name: "comment starting with a bracket after a space",
+                input: "# [not an attribute]",
+                expected_token: Token::new(0, 0, Eof),
+            },
         ];
         for case in test_cases {
             run_test_case(case);
@@ -488,6 +653,11 @@ mod tests {
                 input: "..",
                 expected_token: Token::new(0, 2, DotDot),
             },
+            LexerTestCase {
+                name: "ellipsis",
+                input: "...",
+                expected_token: Token::new(0, 3, Ellipsis),
+            },
             LexerTestCase {
                 name: "dot token .",
                 input: ".",
@@ -498,12 +668,109 @@ mod tests {
                 input: "$=",
                 expected_token: Token::new(0, 2, Destructure),
             },
+            LexerTestCase {
+                name: "attribute opener",
+                input: "#[",
+                expected_token: Token::new(0, 2, HashSquare),
+            },
         ];
         for case in test_cases {
             run_test_case(case);
         }
     }
 
+    #[test]
+    fn test_malformed_int_literals_are_reported() {
+        use tokens::Literal::*;
+        use tokens::TokenType::*;
+
+        let cases = [
+            ("0x", "`0x` must be followed by hex digits"),
+            (
+                "0x10000000000000000",
+                "the literal `0x10000000000000000` is too large",
+            ),
+            (
+                "99999999999999999999",
+                "the literal `99999999999999999999` is too large",
+            ),
+        ];
+        for (input, message) in cases {
+            let mut lexer = Lexer::new(input);
+            let token = lexer.advance_token();
+            let diagnostics = lexer.take_diagnostics();
+            assert_eq!(
+                token,
+                Token::new(0, input.len(), Literal(Int(0))),
+                "{}",
+                input
+            );
+            let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+            assert_eq!(messages, vec![message], "{}", input);
+            assert_eq!(diagnostics[0].span, Span::new(0, input.len()));
+        }
+    }
+
+    #[test]
+    fn test_malformed_tokens_are_reported() {
+        use tokens::Literal::*;
+        use tokens::TokenType::*;
+
+        let cases = [
+            (
+                "'a",
+                "this character literal is never closed",
+                Span::new(0, 2),
+                vec![Token::new(0, 2, Literal(Char('\0')))],
+            ),
+            (
+                "''",
+                "a character literal holds exactly one character",
+                Span::new(0, 2),
+                vec![Token::new(0, 2, Literal(Char('\0')))],
+            ),
+            (
+                "1e",
+                "the exponent of `1e` has no digits",
+                Span::new(0, 2),
+                vec![Token::new(0, 2, Literal(Float(0.0)))],
+            ),
+            (
+                "1e5e5",
+                "`1e5e5` has more than one exponent",
+                Span::new(0, 5),
+                vec![Token::new(0, 5, Literal(Float(0.0)))],
+            ),
+            (
+                "a $ b",
+                "unexpected character `$`",
+                Span::new(2, 3),
+                vec![Token::new(0, 1, Identifier), Token::new(4, 1, Identifier)],
+            ),
+            (
+                "~1",
+                "unexpected character `~`",
+                Span::new(0, 1),
+                vec![Token::new(1, 1, Literal(Int(1)))],
+            ),
+            (
+                "\"ab\ncd",
+                "this string is never closed",
+                Span::new(0, 3),
+                vec![Token::new(0, 6, Literal(Str("ab\ncd".into())))],
+            ),
+        ];
+        for (input, message, span, tokens) in cases {
+            let mut lexer = Lexer::new(input);
+            let lexed: Vec<Token> = lexer.by_ref().collect();
+            assert_eq!(lexed, tokens, "{}", input);
+            let diagnostics = lexer.take_diagnostics();
+            let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+            assert_eq!(messages, [message], "{}", input);
+            assert_eq!(diagnostics[0].span, span, "{}", input);
+        }
+    }
+
     #[test]
     fn test_stream_of_tokens() {
         use tokens::Literal::*;
@@ -544,6 +811,17 @@ mod tests {
                 input: "\"hello world\"",
                 expected_tokens: vec![Token::new(0, 13, Literal(Str("hello world".into())))],
             },
+            LexerMultiTokenCase {
+                name: "Interpolated string",
+                input: "\"a${x}b\"",
+                expected_tokens: vec![
+                    Token::new(0, 2, Literal(Str("a".into()))), // "a
+                    Token::new(2, 2, Interpolate),              // ${
+                    Token::new(4, 1, Identifier),               // x
+                    Token::new(5, 1, InterpolEnd),              // }
+                    Token::new(6, 2, Literal(Str("b".into()))), // b"
+                ],
+            },
             // If-else statement
             LexerMultiTokenCase {
                 name: "If-else statement",
@@ -646,6 +924,19 @@ mod tests {
                     Token::new(16, 1, Identifier), // e
                 ],
             },
+            LexerMultiTokenCase {
+                name: "Assignment operators",
+                input: "a = b == c := d",
+                expected_tokens: vec![
+                    Token::new(0, 1, Identifier),  // a
+                    Token::new(2, 1, Equal),       // =
+                    Token::new(4, 1, Identifier),  // b
+                    Token::new(6, 2, EqualEqual),  // ==
+                    Token::new(9, 1, Identifier),  // c
+                    Token::new(11, 2, Assign),     // :=
+                    Token::new(14, 1, Identifier), // d
+                ],
+            },
             LexerMultiTokenCase {
                 name: "Comparison operators",
                 input: "a <= b >= c != d",
@@ -667,22 +958,34 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_literals() {
         use tokens::Literal::*;
         use tokens::TokenType::*;
 
-        let test_cases = vec![LexerMultiTokenCase {
-            name: "Various literals",
-            input: "42 3.14 \"hello\" 'c' true false",
-            expected_tokens: vec![
-                Token::new(0, 2, Literal(Int(42))),             // 42
-                Token::new(3, 4, Literal(Float(3.14))),         // 3.14
-                Token::new(8, 7, Literal(Str("hello".into()))), // "hello"
-                Token::new(16, 3, Literal(Char('c'))),          // 'c'
-                Token::new(20, 4, Literal(Bool(true))),         // true
-                Token::new(25, 5, Literal(Bool(false))),        // false
-            ],
-        }];
+        let test_cases = vec![
+            LexerMultiTokenCase {
+                name: "Various literals",
+                input: "42 3.14 \"hello\" 'c' true false",
+                expected_tokens: vec![
+                    Token::new(0, 2, Literal(Int(42))),             // 42
+                    Token::new(3, 4, Literal(Float(3.14))),         // 3.14
+                    Token::new(8, 7, Literal(Str("hello".into()))), // "hello"
+                    Token::new(16, 3, Literal(Char('c'))),          // 'c'
+                    Token::new(20, 4, Literal(Bool(true))),         // true
+                    Token::new(25, 5, Literal(Bool(false))),        // false
+                ],
+            },
+            LexerMultiTokenCase {
+                name: "Hex literals",
+                input: "0xFF 0XAB 0x0",
+                expected_tokens: vec![
+                    Token::new(0, 4, Literal(Int(255))), // 0xFF
+                    Token::new(5, 4, Literal(Int(171))), // 0XAB
+                    Token::new(10, 3, Literal(Int(0))),  // 0x0
+                ],
+            },
+        ];
 
         for case in test_cases {
             run_multiple_token_test(case);
@@ -3,3 +3,34 @@ pub struct Size {
     pub start: usize,
     pub end: usize,
 }
+
+// Tokens record their size as (start, length); everything after the lexer works
+// with absolute byte ranges so spans can be merged and sliced directly.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// Smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+impl From<&Size> for Span {
+    fn from(size: &Size) -> Self {
+        Span {
+            start: size.start,
+            end: size.start + size.end,
+        }
+    }
+}
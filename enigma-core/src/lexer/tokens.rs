@@ -37,15 +37,18 @@ pub enum TokenType {
     Asm,      // asm
     Continue, // continue
     Break,    // break
+    Extern,   // extern
 
     // Symbols & Operators
     Assign,      // :=
+    Equal,       // =
     Colon,       // :
     DoubleColon, // ::
     Arrow,       // ->
     Comma,       // ,
     Dot,         // .
     DotDot,      // ..
+    Ellipsis,    // ...
     Percent,     // %
     LParen,      // (
     RParen,      // )
@@ -82,8 +85,11 @@ pub enum TokenType {
     MinusMinus,         // --
     Dollar,             // $=
     // Special
-    Func,       // @
-    ReturnSemi, // shorthand return `val;`
+    Func,        // @
+    HashSquare,  // #[ opening an attribute
+    ReturnSemi,  // shorthand return `val;`
+    Interpolate, // ${ inside a string
+    InterpolEnd, // } closing a ${
 
     // Values
     Identifier,
@@ -105,3 +111,87 @@ impl Token {
         }
     }
 }
+
+impl std::fmt::Display for TokenType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use TokenType::*;
+        let text = match self {
+            Get => "get",
+            Module => "module",
+            As => "as",
+            Mut => "mut",
+            Return => "return",
+            If => "if",
+            Else => "else",
+            For => "for",
+            In => "in",
+            Loop => "loop",
+            While => "while",
+            Match => "match",
+            Case => "case",
+            Pub => "pub",
+            Impl => "implement",
+            Record => "record",
+            Union => "union",
+            Ref => "ref",
+            Deref => "deref",
+            RawRef => "raw_ref",
+            Unsafe => "unsafe",
+            Protoc => "protoc",
+            Asm => "asm",
+            Continue => "continue",
+            Break => "break",
+            Extern => "extern",
+            Assign => ":=",
+            Equal => "=",
+            Colon => ":",
+            DoubleColon => "::",
+            Arrow => "->",
+            Comma => ",",
+            Dot => ".",
+            DotDot => "..",
+            Ellipsis => "...",
+            Percent => "%",
+            LParen => "(",
+            RParen => ")",
+            LCurly => "{",
+            RCurly => "}",
+            LSquare => "[",
+            RSquare => "]",
+            UnderScore => "_",
+            Destructure | Dollar => "$=",
+            Question => "?",
+            Plus => "+",
+            Minus => "-",
+            PlusEqual => "+=",
+            MinusEqual => "-=",
+            Asterisk => "*",
+            AsteriskEqual => "*=",
+            Slash => "/",
+            SlashEqual => "/=",
+            Ampersand => "&",
+            AmpersandAmpersand => "&&",
+            Pipe => "|",
+            PipePipe => "||",
+            Carrot => "^",
+            EqualEqual => "==",
+            Exclaim => "!",
+            ExclaimEqual => "!=",
+            LessThan => "<",
+            GreaterThan => ">",
+            LessThanEqual => "<=",
+            GreaterThanEqual => ">=",
+            PlusPlus => "++",
+            MinusMinus => "--",
+            Func => "@",
+            HashSquare => "#[",
+            ReturnSemi => ";",
+            Interpolate => "${",
+            InterpolEnd => "}",
+            Identifier => return write!(f, "identifier"),
+            Literal(_) => return write!(f, "literal"),
+            Eof => return write!(f, "end of file"),
+        };
+        write!(f, "`{}`", text)
+    }
+}
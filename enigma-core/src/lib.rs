@@ -1,7 +1,16 @@
 #![allow(dead_code)]
-mod checker;
-mod lexer;
-mod parser;
+pub mod checker;
+pub mod codegen;
+pub mod errorhandler;
+pub mod hir;
+pub mod interp;
+pub mod lexer;
+pub mod loader;
+pub mod mir;
+pub mod mono;
+pub mod parser;
+pub mod prelude;
+pub mod target;
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,285 @@
+//! Maps `get module a.b` onto files and loads every module a program needs.
+//!
+//! A dotted path `a.b` names `a/b.en` or `a/b/mod.en`, looked up first
+//! relative to the project root (the directory of the entry file) and then in
+//! each search path, in order. Loading produces a `ModuleGraph` whose modules
+//! are ordered so that every module comes after the modules it imports. A
+//! module is its file: two paths reaching the same file, or an import of the
+//! entry file, name the module already loaded. The `core` prelude is always
+//! the first module. A `#freestanding` line at the
+//! top of the entry file makes the whole program freestanding.
+
+use crate::errorhandler::{Diagnostic, ErrorHandler, SourceMap};
+use crate::lexer::size::Span;
+use crate::parser::Parser;
+use crate::parser::ast::{ItemKind, NodeId, Program};
+use crate::prelude;
+use crate::target::Target;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+pub const EXTENSION: &str = "en";
+
+/// Where module sources come from. Tests use an in-memory map instead of the
+/// file system.
+pub trait SourceProvider {
+    fn read(&self, path: &Path) -> Option<String>;
+
+    /// The path identifying the file at `path`, the same for every path
+    /// reaching it. By default `.` and `a/..` components are dropped.
+    fn canonical(&self, path: &Path) -> PathBuf {
+        let mut canonical = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir if canonical.file_name().is_some() => {
+                    canonical.pop();
+                }
+                _ => canonical.push(component),
+            }
+        }
+        canonical
+    }
+}
+
+pub struct FileSystem;
+
+impl SourceProvider for FileSystem {
+    fn read(&self, path: &Path) -> Option<String> {
+        fs::read_to_string(path).ok()
+    }
+
+    fn canonical(&self, path: &Path) -> PathBuf {
+        fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+    }
+}
+
+impl SourceProvider for HashMap<PathBuf, String> {
+    fn read(&self, path: &Path) -> Option<String> {
+        self.get(path).cloned()
+    }
+}
+
+pub type ModuleId = usize;
+
+#[derive(Debug)]
+pub struct Module {
+    pub id: ModuleId,
+    /// Dotted path the module was imported by; empty for the entry file.
+    pub name: String,
+    pub path: PathBuf,
+    pub program: Program,
+    /// The local name each import is bound to and the module it refers to.
+    pub imports: Vec<(String, ModuleId)>,
+}
+
+#[derive(Debug, Default)]
+pub struct ModuleGraph {
+    pub modules: Vec<Module>,
+    /// Dependencies before dependents; the entry module is last.
+    pub order: Vec<ModuleId>,
+    pub sources: SourceMap,
+    /// What the program is compiled for, as the entry file asks.
+    pub target: Target,
+}
+
+impl ModuleGraph {
+    /// The entry module.
+    pub fn root(&self) -> &Module {
+        self.by_name("").expect("the entry module is always loaded")
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&Module> {
+        self.modules.iter().find(|module| module.name == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Loading,
+    Done,
+}
+
+pub struct Loader<'a, P: SourceProvider> {
+    provider: &'a P,
+    root: PathBuf,
+    search_paths: Vec<PathBuf>,
+    graph: ModuleGraph,
+    states: Vec<State>,
+    /// Modules currently being loaded, innermost last, for cycle reports.
+    stack: Vec<ModuleId>,
+    /// Each loaded module by the canonical path of its file.
+    files: HashMap<PathBuf, ModuleId>,
+    next_id: NodeId,
+}
+
+impl<'a, P: SourceProvider> Loader<'a, P> {
+    pub fn new(provider: &'a P, root: impl Into<PathBuf>, search_paths: Vec<PathBuf>) -> Self {
+        Self {
+            provider,
+            root: root.into(),
+            search_paths,
+            graph: ModuleGraph::default(),
+            states: Vec::new(),
+            stack: Vec::new(),
+            files: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Loads the prelude, then `entry` and everything it imports,
+    /// transitively.
+    pub fn load(mut self, entry: &Path, handler: &mut ErrorHandler) -> Option<ModuleGraph> {
+        let source = self.provider.read(entry)?;
+        self.graph.target = Target::of_source(&source);
+        self.load_module(
+            prelude::MODULE.to_string(),
+            PathBuf::from(prelude::PATH),
+            prelude::SOURCE.to_string(),
+            handler,
+        );
+        self.load_module(String::new(), entry.to_path_buf(), source, handler);
+        Some(self.graph)
+    }
+
+    /// Candidate files for a dotted module path, in lookup order.
+    pub fn candidates(&self, dotted: &str) -> Vec<PathBuf> {
+        let relative: PathBuf = dotted.split('.').collect();
+        std::iter::once(&self.root)
+            .chain(&self.search_paths)
+            .flat_map(|dir| {
+                let base = dir.join(&relative);
+                [
+                    base.with_extension(EXTENSION),
+                    base.join("mod").with_extension(EXTENSION),
+                ]
+            })
+            .collect()
+    }
+
+    fn load_module(
+        &mut self,
+        name: String,
+        path: PathBuf,
+        source: String,
+        handler: &mut ErrorHandler,
+    ) -> ModuleId {
+        let base = self
+            .graph
+            .sources
+            .add(path.display().to_string(), source.clone());
+        let mut parser = Parser::with_offset(&source, base, self.next_id);
+        let program = parser.parse_program(handler);
+        self.next_id = parser.next_id();
+
+        let id = self.graph.modules.len();
+        self.files.insert(self.provider.canonical(&path), id);
+        self.graph.modules.push(Module {
+            id,
+            name,
+            path,
+            program,
+            imports: Vec::new(),
+        });
+        self.states.push(State::Loading);
+        self.stack.push(id);
+
+        let imports: Vec<_> = self.graph.modules[id]
+            .program
+            .items
+            .iter()
+            .filter_map(|item| match &item.kind {
+                ItemKind::Import(import) => Some((import.clone(), item.span)),
+                _ => None,
+            })
+            .collect();
+        for (import, span) in imports {
+            let dotted = import.dotted();
+            if let Some(dep) = self.import(&dotted, span, handler) {
+                self.graph.modules[id]
+                    .imports
+                    .push((import.name().name.clone(), dep));
+            }
+        }
+
+        self.stack.pop();
+        self.states[id] = State::Done;
+        self.graph.order.push(id);
+        id
+    }
+
+    fn import(&mut self, dotted: &str, span: Span, handler: &mut ErrorHandler) -> Option<ModuleId> {
+        let id = match self.graph.by_name(dotted) {
+            Some(module) => module.id,
+            None => {
+                let (path, source) = self.find(dotted, span, handler)?;
+                match self.files.get(&self.provider.canonical(&path)) {
+                    // Another path to a file already loaded.
+                    Some(&id) => id,
+                    None => {
+                        return Some(self.load_module(dotted.to_string(), path, source, handler));
+                    }
+                }
+            }
+        };
+        if self.states[id] == State::Loading {
+            handler.emit(self.cycle_error(id, dotted, span));
+            return None;
+        }
+        Some(id)
+    }
+
+    /// The first candidate file for `dotted` and its source.
+    fn find(
+        &self,
+        dotted: &str,
+        span: Span,
+        handler: &mut ErrorHandler,
+    ) -> Option<(PathBuf, String)> {
+        let candidates = self.candidates(dotted);
+        let found = candidates
+            .iter()
+            .find_map(|path| Some((path.clone(), self.provider.read(path)?)));
+        if found.is_none() {
+            let mut diagnostic = Diagnostic::error(format!("unresolved module `{}`", dotted), span);
+            for candidate in &candidates {
+                diagnostic = diagnostic.with_note(format!("searched {}", candidate.display()));
+            }
+            handler.emit(diagnostic);
+        }
+        found
+    }
+
+    fn cycle_error(&self, target: ModuleId, dotted: &str, span: Span) -> Diagnostic {
+        let start = self.stack.iter().position(|&id| id == target).unwrap();
+        let mut chain: Vec<String> = self.stack[start..]
+            .iter()
+            .map(|&id| self.display_name(id))
+            .collect();
+        chain.push(self.display_name(target));
+        Diagnostic::error(format!("import cycle involving module `{}`", dotted), span)
+            .with_note(format!("cycle: {}", chain.join(" -> ")))
+    }
+
+    fn display_name(&self, id: ModuleId) -> String {
+        let module = &self.graph.modules[id];
+        if module.name.is_empty() {
+            module.path.display().to_string()
+        } else {
+            module.name.clone()
+        }
+    }
+}
+
+/// Loads the program rooted at `entry`, resolving imports relative to its
+/// directory and then `search_paths`. Returns `None` when `entry` can't be read.
+pub fn load<P: SourceProvider>(
+    entry: &Path,
+    search_paths: &[PathBuf],
+    provider: &P,
+    handler: &mut ErrorHandler,
+) -> Option<ModuleGraph> {
+    let root = entry.parent().map(Path::to_path_buf).unwrap_or_default();
+    Loader::new(provider, root, search_paths.to_vec()).load(entry, handler)
+}
@@ -1,36 +1,338 @@
-pub mod lexer;
-pub mod  parser;
-use lexer::Lexer;
-use lexer::tokens::{Token,TokenType};
-use parser::Parser;
-use std::fs;
+use enigma_core::checker;
+use enigma_core::codegen::elf;
+use enigma_core::codegen::link::{self, Format, Script};
+use enigma_core::codegen::x86::{self, Syntax};
+use enigma_core::errorhandler::ErrorHandler;
+use enigma_core::hir;
+use enigma_core::interp;
+use enigma_core::loader::{self, FileSystem};
+use enigma_core::mir::{self, opt, opt::OptLevel};
+use enigma_core::mono::{self, MonoItems};
+use enigma_core::prelude;
+use enigma_core::target::Target;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-fn get_token_stream(lex:Lexer){
-    
-   loop{
+const USAGE: &str = "usage: enigma (check | build | run) [-L <dir>]... [-O0|-O1|-O2] \
+                     [--target x86_64-linux|x86_64-none] [--emit=hir|mir|mir-passes|asm|obj] \
+                     [--syntax=att|intel] [-o <file>] <file.en>\n       \
+                     enigma link [-T <script>] [--format elf|bin] [-o <file>] <file.o>...";
 
-    let mut token_type:TokenType;
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let Some(command) = args.next() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    if command == "link" {
+        return link(args);
+    }
+    let mut search_paths = Vec::new();
+    let mut file_path = None;
+    let mut emit = None;
+    let mut level = OptLevel::O0;
+    let mut syntax = Syntax::Att;
+    let mut output = None;
+    let mut target = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--emit=hir" | "--emit=mir" | "--emit=mir-passes" | "--emit=asm" | "--emit=obj" => {
+                emit = Some(arg)
+            }
+            "-o" => match args.next() {
+                Some(path) => output = Some(PathBuf::from(path)),
+                None => {
+                    eprintln!("error: `-o` expects a file");
+                    return ExitCode::FAILURE;
+                }
+            },
+            "--target" => match args.next().as_deref().map(Target::parse) {
+                Some(Some(parsed)) => target = Some(parsed),
+                Some(None) => {
+                    eprintln!("error: unknown target; expected `x86_64-linux` or `x86_64-none`");
+                    return ExitCode::FAILURE;
+                }
+                None => {
+                    eprintln!("error: `--target` expects a target");
+                    return ExitCode::FAILURE;
+                }
+            },
+            "-L" => match args.next() {
+                Some(dir) => search_paths.push(PathBuf::from(dir)),
+                None => {
+                    eprintln!("error: `-L` expects a directory");
+                    return ExitCode::FAILURE;
+                }
+            },
+            _ if arg.starts_with("-O") => match OptLevel::parse(&arg[2..]) {
+                Some(parsed) => level = parsed,
+                None => {
+                    eprintln!("error: unknown optimization level `{}`", arg);
+                    return ExitCode::FAILURE;
+                }
+            },
+            _ if arg.starts_with("--syntax=") => match Syntax::parse(&arg["--syntax=".len()..]) {
+                Some(parsed) => syntax = parsed,
+                None => {
+                    eprintln!("error: unknown syntax `{}`", &arg["--syntax=".len()..]);
+                    return ExitCode::FAILURE;
+                }
+            },
+            _ if arg.starts_with("--emit=") => {
+                eprintln!("error: unknown output `{}`", &arg["--emit=".len()..]);
+                return ExitCode::FAILURE;
+            }
+            _ => file_path = Some(arg),
+        }
+    }
+    let Some(file_path) = file_path else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
 
-    let token=  lex.next();
+    if !matches!(command.as_str(), "check" | "build" | "run") {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    }
+    let mut handler = ErrorHandler::new();
+    let Some(mut graph) = loader::load(
+        Path::new(&file_path),
+        &search_paths,
+        &FileSystem,
+        &mut handler,
+    ) else {
+        eprintln!("error: could not read `{}`", file_path);
+        return ExitCode::FAILURE;
+    };
+    // `#freestanding` can't be overridden, as the program depends on it.
+    match target {
+        Some(Target::Hosted) if graph.target == Target::Freestanding => {
+            eprintln!(
+                "error: `{}` is freestanding and can't be compiled for `{}`",
+                file_path,
+                Target::Hosted.triple()
+            );
+            return ExitCode::FAILURE;
+        }
+        Some(target) => graph.target = target,
+        None => {}
+    }
+    if command == "run" && graph.target == Target::Freestanding {
+        eprintln!("error: freestanding programs can't be run; build an object and link it instead");
+        return ExitCode::FAILURE;
+    }
+    let mut checked = Vec::new();
+    let mut items = MonoItems::default();
+    if !handler.has_errors() {
+        checked = checker::check_graph(&graph, &mut handler);
+        if !handler.has_errors() {
+            items = mono::collect(&checked, &mut handler);
+        }
+    }
 
-    match token.{
-        TokenType::Eof => None,
-        _ => Some(token),
+    eprint!("{}", handler.render_with(&graph.sources));
+    if handler.has_errors() {
+        eprintln!(
+            "error: could not compile `{}` due to {} previous error(s)",
+            file_path,
+            handler.error_count()
+        );
+        return ExitCode::FAILURE;
+    }
+    // The prelude is left out of dumps; it is the same in every program.
+    if command == "build" || emit.is_some() {
+        let modules = hir::lower_graph(&graph, &checked);
+        if emit.as_deref() == Some("--emit=hir") {
+            for module in modules.iter().filter(|m| m.name != prelude::MODULE) {
+                print!("{}", module);
+            }
+        }
+        let tables: Vec<_> = checked.iter().map(|(items, _)| items).collect();
+        let mut program = mir::lower_program(&modules, &tables, items);
+        if let Err(error) = mir::verify(&program) {
+            eprintln!("internal compiler error: {}", error);
+            return ExitCode::from(101);
+        }
+        let passes = emit.as_deref() == Some("--emit=mir-passes");
+        if passes {
+            println!("# lowered");
+            print_mir(&program);
+        }
+        let optimized = opt::optimize(&mut program, level, &mut |pass, program| {
+            if passes {
+                println!("# after {}", pass);
+                print_mir(program);
+            }
+        });
+        if let Err(error) = optimized {
+            eprintln!("internal compiler error: {}", error);
+            return ExitCode::from(101);
+        }
+        if emit.as_deref() == Some("--emit=mir") {
+            print_mir(&program);
+        }
+        if emit.as_deref() == Some("--emit=asm") {
+            let asm = match x86::emit_asm(&program, graph.target, syntax) {
+                Ok(asm) => asm,
+                Err(error) => {
+                    eprintln!("error: {}", error);
+                    return ExitCode::FAILURE;
+                }
+            };
+            match &output {
+                Some(path) => {
+                    if let Err(error) = std::fs::write(path, asm) {
+                        eprintln!("error: could not write `{}`: {}", path.display(), error);
+                        return ExitCode::FAILURE;
+                    }
+                }
+                None => print!("{}", asm),
+            }
+        }
+        // Objects are written next to the source unless `-o` says where.
+        if emit.as_deref() == Some("--emit=obj") {
+            let path = output.unwrap_or_else(|| Path::new(&file_path).with_extension("o"));
+            let object = match x86::emit_object(&program, graph.target) {
+                Ok(object) => object,
+                Err(error) => {
+                    eprintln!("error: {}", error);
+                    return ExitCode::FAILURE;
+                }
+            };
+            if let Err(error) = std::fs::write(&path, object) {
+                eprintln!("error: could not write `{}`: {}", path.display(), error);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if command == "run" {
+        let mut handler = ErrorHandler::new();
+        let code = match interp::run(&graph, &checked, &mut std::io::stdout()) {
+            Ok(code) => code,
+            Err(error) => {
+                handler.emit(error.to_diagnostic(&graph.sources));
+                eprint!("{}", handler.render_with(&graph.sources));
+                eprintln!("error: `{}` stopped with a runtime error", file_path);
+                return ExitCode::from(101);
+            }
+        };
+        // Exit codes are a single byte, as on Unix.
+        return ExitCode::from(code as u8);
+    }
+    ExitCode::SUCCESS
+}
 
+/// Links objects into a program, as `--format` says, laid out by the
+/// linker description `-T` names or by the format's own.
+fn link(mut args: impl Iterator<Item = String>) -> ExitCode {
+    let mut paths = Vec::new();
+    let mut script_path = None;
+    let mut format = Format::Elf;
+    let mut output = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-T" => match args.next() {
+                Some(path) => script_path = Some(path),
+                None => {
+                    eprintln!("error: `-T` expects a linker description");
+                    return ExitCode::FAILURE;
+                }
+            },
+            "--format" => match args.next().as_deref().map(Format::parse) {
+                Some(Some(parsed)) => format = parsed,
+                Some(None) => {
+                    eprintln!("error: unknown format; expected `elf` or `bin`");
+                    return ExitCode::FAILURE;
+                }
+                None => {
+                    eprintln!("error: `--format` expects a format");
+                    return ExitCode::FAILURE;
+                }
+            },
+            "-o" => match args.next() {
+                Some(path) => output = Some(PathBuf::from(path)),
+                None => {
+                    eprintln!("error: `-o` expects a file");
+                    return ExitCode::FAILURE;
+                }
+            },
+            _ => paths.push(arg),
+        }
     }
-    if tok_type == TokenType::Eof{
-        break;
+    if paths.is_empty() {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
     }
-   }
-    while tok_type!= TokenType::Eof {
-        
+
+    let text = match &script_path {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) => {
+                eprintln!("error: could not read `{}`: {}", path, error);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => format.script().to_string(),
+    };
+    let script = match Script::parse(&text) {
+        Ok(script) => script,
+        Err(error) => {
+            let path = script_path
+                .as_deref()
+                .unwrap_or("the default linker description");
+            eprintln!("error: in `{}`, {}", path, error);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut objects = Vec::new();
+    for path in &paths {
+        let object = match std::fs::read(path) {
+            Ok(bytes) => elf::read(&bytes),
+            Err(error) => {
+                eprintln!("error: could not read `{}`: {}", path, error);
+                return ExitCode::FAILURE;
+            }
+        };
+        match object {
+            Ok(object) => objects.push((path.clone(), object)),
+            Err(error) => {
+                eprintln!("error: `{}` is not an object: {}", path, error);
+                return ExitCode::FAILURE;
+            }
+        }
     }
+    let executable = match link::link(&objects, &script) {
+        Ok(executable) => executable,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("error: {}", error);
+            }
+            eprintln!(
+                "error: could not link due to {} previous error(s)",
+                errors.len()
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // The output goes next to the first object unless `-o` says where.
+    let (bytes, extension) = match format {
+        Format::Elf => (elf::write_executable(&executable), "elf"),
+        Format::Binary => (link::flat_binary(&executable), "bin"),
+    };
+    let path = output.unwrap_or_else(|| Path::new(&paths[0]).with_extension(extension));
+    if let Err(error) = std::fs::write(&path, bytes) {
+        eprintln!("error: could not write `{}`: {}", path.display(), error);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
 }
-fn main() {
-    println!("Hello, Mystic!");
-    let contents = fs::read_to_string(file_path)
-    .expect("Should have been able to read the file");
 
-    let lex= Lexer::new(&contents);
-    let parse =  Parser::new(token_stream);
+fn print_mir(program: &mir::Program) {
+    let core = format!("{}::", prelude::MODULE);
+    for function in &program.functions {
+        if !function.name.starts_with(&core) {
+            println!("{}", program.display(function));
+        }
+    }
 }
@@ -0,0 +1,1035 @@
+//! HIR to MIR. Each body is built block by block; an expression lowers to
+//! the operand holding its value, or to `None` once control has left (after
+//! `return`, `break`, or a call that never returns), in which case nothing
+//! more is emitted until the next reachable block.
+
+use super::*;
+use crate::checker::FnOwner;
+use crate::checker::items::ItemTable;
+use crate::hir::{self, ExprKind, PatternKind, Stmt};
+use crate::lexer::tokens::Literal;
+use crate::prelude;
+use std::collections::HashSet;
+
+/// Lowers checked modules, given in dependency order with the item tables
+/// that describe their types and the instances `mono::collect` found.
+pub fn lower_program(modules: &[hir::Module], tables: &[&ItemTable], mono: MonoItems) -> Program {
+    let mut adts = HashMap::new();
+    let mut protocols = HashMap::new();
+    for table in tables {
+        for (name, sigs) in &table.protocols {
+            let methods = sigs.iter().map(|sig| sig.name.clone()).collect();
+            protocols.insert(name.clone(), methods);
+        }
+        for (name, def) in &table.records {
+            adts.insert(name.clone(), AdtDef::Record(def.clone()));
+        }
+        for (name, def) in &table.unions {
+            adts.insert(name.clone(), AdtDef::Union(def.clone()));
+        }
+    }
+    adts.insert("Range".to_string(), AdtDef::Record(RecordDef::range()));
+    let mut program = Program {
+        adts,
+        globals: Vec::new(),
+        functions: Vec::new(),
+        mono,
+        protocols,
+    };
+    for module in modules {
+        program.globals.extend(module.globals.iter().cloned());
+        for body in module.functions.iter().chain([&module.init]) {
+            let name = match &body.owner {
+                hir::BodyOwner::Function(func) => fn_name(func),
+                hir::BodyOwner::Init(module) if module.is_empty() => "{init}".to_string(),
+                hir::BodyOwner::Init(module) => format!("{}::{{init}}", module),
+            };
+            let owner = match &body.owner {
+                hir::BodyOwner::Function(func) => Owner::Function(func.clone()),
+                hir::BodyOwner::Init(module) => Owner::Init(module.clone()),
+            };
+            let mut closures = Vec::new();
+            let function = Builder::new(&program, body, name, &mut closures).function(
+                owner,
+                &body.params,
+                &[],
+                &body.ret,
+                &body.value,
+            );
+            program.functions.push(function);
+            program.functions.append(&mut closures);
+        }
+    }
+    program
+}
+
+pub(super) fn fn_name(func: &FnRef) -> String {
+    match &func.owner {
+        FnOwner::Free => func.name.clone(),
+        FnOwner::Type(ty) | FnOwner::Protocol(ty) => format!("{}::{}", ty, func.name),
+    }
+}
+
+struct Loop {
+    header: BlockId,
+    exit: BlockId,
+    /// Scopes open outside the loop, which `break` and `continue` leave.
+    depth: usize,
+    broken: bool,
+}
+
+struct Builder<'a> {
+    program: &'a Program,
+    body: &'a hir::Body,
+    /// The name of the function being built.
+    name: String,
+    /// Closures lifted out of the function, nested ones after the closure
+    /// containing them.
+    closures: &'a mut Vec<Function>,
+    closure_count: usize,
+    locals: Vec<Local>,
+    values: Vec<Ty>,
+    blocks: Vec<Block>,
+    /// Blocks that already have their terminator.
+    finished: Vec<bool>,
+    current: BlockId,
+    /// Where each HIR local lives.
+    places: HashMap<hir::LocalId, Place>,
+    /// Locals to drop when each open scope ends, innermost last.
+    scopes: Vec<Vec<LocalId>>,
+    loops: Vec<Loop>,
+}
+
+impl<'a> Builder<'a> {
+    fn new(
+        program: &'a Program,
+        body: &'a hir::Body,
+        name: String,
+        closures: &'a mut Vec<Function>,
+    ) -> Self {
+        Self {
+            program,
+            body,
+            name,
+            closures,
+            closure_count: 0,
+            locals: Vec::new(),
+            values: Vec::new(),
+            blocks: Vec::new(),
+            finished: Vec::new(),
+            current: BlockId(0),
+            places: HashMap::new(),
+            scopes: Vec::new(),
+            loops: Vec::new(),
+        }
+    }
+
+    /// Builds a function whose parameters are `params`, after a tuple of
+    /// references to `captures` if it is a closure. Closures take that
+    /// tuple even when it's empty, since their callers can't tell them apart.
+    fn function(
+        mut self,
+        owner: Owner,
+        params: &[hir::LocalId],
+        captures: &[hir::LocalId],
+        ret: &Ty,
+        value: &hir::Expr,
+    ) -> Function {
+        let mut param_tys: Vec<Ty> = params
+            .iter()
+            .map(|&id| self.body.local(id).ty.clone())
+            .collect();
+        let closure = matches!(owner, Owner::Closure { .. });
+        if closure {
+            param_tys.insert(
+                0,
+                Ty::Tuple(captures.iter().map(|&id| self.capture_ty(id)).collect()),
+            );
+        }
+        let entry = self.new_block(param_tys);
+        self.current = entry;
+        self.scopes.push(Vec::new());
+        let mut args = self.blocks[0].params.clone().into_iter();
+        if closure {
+            let env_ty = self.values[0].clone();
+            let env = self.new_local("env", env_ty, false);
+            self.store(Place::local(env), operand(args.next().unwrap()), value.span);
+            for (i, &id) in captures.iter().enumerate() {
+                let place = Place::local(env)
+                    .project(Projection::Field(i))
+                    .project(Projection::Deref);
+                self.places.insert(id, place);
+            }
+        }
+        for (&id, arg) in params.iter().zip(args) {
+            let local = self.declare(id);
+            self.store(Place::local(local), operand(arg), value.span);
+        }
+        if let Some(result) = self.expr(value) {
+            self.leave_scopes(0, value.span);
+            self.terminate(Terminator::Return(result));
+        }
+        self.finish(owner, ret.clone())
+    }
+
+    fn finish(self, owner: Owner, ret: Ty) -> Function {
+        // A closure lifted out of a function doesn't share its attributes.
+        let attrs = match owner {
+            Owner::Function(_) => self.body.attrs.clone(),
+            _ => FnAttrs::default(),
+        };
+        let mut function = Function {
+            name: self.name,
+            owner,
+            generics: self.body.generics.clone(),
+            ret,
+            attrs,
+            locals: self.locals,
+            values: self.values,
+            blocks: self.blocks,
+        };
+        // Lowering leaves blocks without predecessors behind, like the one
+        // after a `return`.
+        function.order_blocks();
+        function
+    }
+
+    // Building blocks.
+
+    fn new_block(&mut self, params: Vec<Ty>) -> BlockId {
+        let params = params.into_iter().map(|ty| self.new_value(ty)).collect();
+        self.blocks.push(Block {
+            params,
+            insts: Vec::new(),
+            term: Terminator::Unreachable,
+        });
+        self.finished.push(false);
+        BlockId(self.blocks.len() as u32 - 1)
+    }
+
+    fn new_value(&mut self, ty: Ty) -> ValueId {
+        self.values.push(ty);
+        ValueId(self.values.len() as u32 - 1)
+    }
+
+    fn new_local(&mut self, name: &str, ty: Ty, mutable: bool) -> LocalId {
+        let id = LocalId(self.locals.len() as u32);
+        if !is_copy(&ty) {
+            self.scopes.last_mut().unwrap().push(id);
+        }
+        self.locals.push(Local {
+            name: name.to_string(),
+            ty,
+            mutable,
+        });
+        id
+    }
+
+    /// Gives a HIR local its slot in the innermost scope.
+    fn declare(&mut self, id: hir::LocalId) -> LocalId {
+        let local = self.body.local(id);
+        let slot = self.new_local(&local.name, local.ty.clone(), local.mutable);
+        self.places.insert(id, Place::local(slot));
+        slot
+    }
+
+    fn emit(&mut self, kind: InstKind, span: Span) {
+        self.push(None, kind, span);
+    }
+
+    fn value(&mut self, kind: InstKind, ty: Ty, span: Span) -> Operand {
+        let id = self.new_value(ty);
+        self.push(Some(id), kind, span);
+        operand(id)
+    }
+
+    fn push(&mut self, result: Option<ValueId>, kind: InstKind, span: Span) {
+        debug_assert!(!self.finished[self.current.0 as usize]);
+        self.blocks[self.current.0 as usize]
+            .insts
+            .push(Inst { result, kind, span });
+    }
+
+    fn terminate(&mut self, term: Terminator) {
+        let index = self.current.0 as usize;
+        debug_assert!(!self.finished[index]);
+        self.blocks[index].term = term;
+        self.finished[index] = true;
+    }
+
+    fn goto(&mut self, block: BlockId, value: Option<Operand>) {
+        // Blocks only take a parameter when they join values.
+        let args = if self.blocks[block.0 as usize].params.is_empty() {
+            Vec::new()
+        } else {
+            value.into_iter().collect()
+        };
+        self.terminate(Terminator::Goto(Target { block, args }));
+    }
+
+    fn branch(&mut self, cond: Operand, then_block: BlockId, else_block: BlockId) {
+        self.terminate(Terminator::Branch {
+            cond,
+            then_target: target(then_block),
+            else_target: target(else_block),
+        });
+    }
+
+    /// A block that `ty` values flow into, taking one as its parameter
+    /// unless they carry nothing.
+    fn join_block(&mut self, ty: &Ty) -> BlockId {
+        if matches!(ty, Ty::Unit | Ty::Never) {
+            self.new_block(Vec::new())
+        } else {
+            self.new_block(vec![ty.clone()])
+        }
+    }
+
+    /// The value a join block received.
+    fn joined(&self, block: BlockId) -> Operand {
+        match self.blocks[block.0 as usize].params.first() {
+            Some(&param) => operand(param),
+            None => Operand::Const(Const::Unit),
+        }
+    }
+
+    fn store(&mut self, place: Place, value: Operand, span: Span) {
+        self.emit(InstKind::Store { place, value }, span);
+    }
+
+    fn read(&mut self, place: Place, ty: Ty, span: Span) -> Operand {
+        let kind = if is_copy(&ty) {
+            InstKind::Copy(place)
+        } else {
+            InstKind::Move(place)
+        };
+        self.value(kind, ty, span)
+    }
+
+    /// Stores `value` in a new temporary of the innermost scope.
+    fn temp(&mut self, name: &str, value: Operand, ty: Ty, span: Span) -> Place {
+        let local = self.new_local(name, ty, false);
+        let place = Place::local(local);
+        self.store(place.clone(), value, span);
+        place
+    }
+
+    /// Drops the locals of every scope above `depth`, innermost first,
+    /// without closing them.
+    fn leave_scopes(&mut self, depth: usize, span: Span) {
+        let locals: Vec<LocalId> = self.scopes[depth..]
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev().copied())
+            .collect();
+        for local in locals {
+            self.emit(InstKind::Drop(Place::local(local)), span);
+        }
+    }
+
+    /// Runs `f` in a new scope, dropping its locals if control reaches the
+    /// end.
+    fn scoped(
+        &mut self,
+        span: Span,
+        f: impl FnOnce(&mut Self) -> Option<Operand>,
+    ) -> Option<Operand> {
+        self.scopes.push(Vec::new());
+        let result = f(self);
+        if result.is_some() {
+            self.leave_scopes(self.scopes.len() - 1, span);
+        }
+        self.scopes.pop();
+        result
+    }
+
+    // Expressions.
+
+    fn expr(&mut self, expr: &hir::Expr) -> Option<Operand> {
+        let span = expr.span;
+        let ty = expr.ty.clone();
+        let value = match &expr.kind {
+            ExprKind::Literal(literal) => Operand::Const(constant(literal, &ty)),
+            ExprKind::Local(_)
+            | ExprKind::Global(_)
+            | ExprKind::Field { .. }
+            | ExprKind::Deref(_) => {
+                let place = self.place(expr)?;
+                self.read(place, ty, span)
+            }
+            ExprKind::FnItem { func, args } => self.value(
+                InstKind::FnItem {
+                    func: func.clone(),
+                    args: args.clone(),
+                },
+                ty,
+                span,
+            ),
+            ExprKind::Tuple(elems) if elems.is_empty() => Operand::Const(Const::Unit),
+            ExprKind::Tuple(elems) => {
+                let fields = self.exprs(elems)?;
+                self.aggregate(AggregateKind::Tuple, fields, ty, span)
+            }
+            ExprKind::Record { name, fields } => {
+                let values: Vec<&hir::Expr> = fields.iter().map(|(_, value)| value).collect();
+                let fields = self.exprs(values)?;
+                self.aggregate(AggregateKind::Record(name.clone()), fields, ty, span)
+            }
+            ExprKind::Variant {
+                union,
+                variant,
+                fields,
+            } => {
+                let fields = self.exprs(fields)?;
+                let kind = AggregateKind::Variant {
+                    union: union.clone(),
+                    variant: self.variant_index(union, variant),
+                };
+                self.aggregate(kind, fields, ty, span)
+            }
+            ExprKind::VariantCtor { union, variant } => {
+                let variant = self.variant_index(union, variant);
+                let kind = InstKind::VariantCtor {
+                    union: union.clone(),
+                    variant,
+                };
+                self.value(kind, ty, span)
+            }
+            ExprKind::Unary { op, expr: inner } => {
+                let operand = self.expr(inner)?;
+                self.value(InstKind::Unary { op: *op, operand }, ty, span)
+            }
+            ExprKind::Binary { op, lhs, rhs } => {
+                let lhs = self.expr(lhs)?;
+                let rhs = self.expr(rhs)?;
+                self.value(InstKind::Binary { op: *op, lhs, rhs }, ty, span)
+            }
+            ExprKind::Range { start, end } => {
+                let start = self.expr(start)?;
+                let end = self.expr(end)?;
+                let kind = AggregateKind::Record("Range".to_string());
+                self.aggregate(kind, vec![start, end], ty, span)
+            }
+            ExprKind::Call { callee, args } => return self.call(callee, args, ty, span),
+            ExprKind::Block(block) => return self.block(block, span),
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let cond = self.expr(cond)?;
+                let then_block = self.new_block(Vec::new());
+                let else_block = self.new_block(Vec::new());
+                let join = self.join_block(&ty);
+                self.branch(cond, then_block, else_block);
+                let mut reached = false;
+                for (block, branch) in [(then_block, then_branch), (else_block, else_branch)] {
+                    self.current = block;
+                    if let Some(value) = self.expr(branch) {
+                        self.goto(join, Some(value));
+                        reached = true;
+                    }
+                }
+                self.current = join;
+                if !reached {
+                    return None;
+                }
+                self.joined(join)
+            }
+            ExprKind::Loop(block) => {
+                let header = self.new_block(Vec::new());
+                let exit = self.join_block(&ty);
+                self.goto(header, None);
+                self.current = header;
+                self.loops.push(Loop {
+                    header,
+                    exit,
+                    depth: self.scopes.len(),
+                    broken: false,
+                });
+                if self.block(block, span).is_some() {
+                    self.goto(header, None);
+                }
+                let lp = self.loops.pop().unwrap();
+                self.current = exit;
+                if !lp.broken {
+                    return None;
+                }
+                self.joined(exit)
+            }
+            ExprKind::Match { scrutinee, arms } => return self.match_expr(scrutinee, arms, ty),
+            ExprKind::Break(value) => {
+                let value = self.expr(value)?;
+                let lp = self.loops.last_mut().unwrap();
+                lp.broken = true;
+                let (exit, depth) = (lp.exit, lp.depth);
+                self.leave_scopes(depth, span);
+                self.goto(exit, Some(value));
+                return None;
+            }
+            ExprKind::Continue => {
+                let lp = self.loops.last().unwrap();
+                let (header, depth) = (lp.header, lp.depth);
+                self.leave_scopes(depth, span);
+                self.goto(header, None);
+                return None;
+            }
+            ExprKind::Return(value) => {
+                let value = self.expr(value)?;
+                self.leave_scopes(0, span);
+                self.terminate(Terminator::Return(value));
+                return None;
+            }
+            ExprKind::Ref {
+                mutable,
+                expr: inner,
+            } => {
+                let place = self.place(inner)?;
+                let kind = InstKind::Ref {
+                    mutable: *mutable,
+                    place,
+                };
+                self.value(kind, ty, span)
+            }
+            ExprKind::RawRef {
+                mutable,
+                expr: inner,
+            } => {
+                let place = self.place(inner)?;
+                let kind = InstKind::RawRef {
+                    mutable: *mutable,
+                    place,
+                };
+                self.value(kind, ty, span)
+            }
+            ExprKind::ToDyn {
+                expr: inner,
+                protocol,
+            } => {
+                let value = self.expr(inner)?;
+                let kind = InstKind::ToDyn {
+                    value,
+                    protocol: protocol.clone(),
+                };
+                self.value(kind, ty, span)
+            }
+            ExprKind::Assign { place, value } => {
+                let value = self.expr(value)?;
+                let target = self.place(place)?;
+                if !is_copy(&place.ty) {
+                    self.emit(InstKind::Drop(target.clone()), span);
+                }
+                self.store(target, value, span);
+                Operand::Const(Const::Unit)
+            }
+            ExprKind::Asm(asm) => return self.asm(asm, span),
+            ExprKind::Closure { params, body } => self.closure(params, body, ty, span),
+        };
+        Some(value)
+    }
+
+    /// Inputs become the instruction's operands and outputs its result,
+    /// a tuple when there are several, which is then stored to the output
+    /// places.
+    fn asm(&mut self, asm: &hir::Asm, span: Span) -> Option<Operand> {
+        let mut operands = Vec::new();
+        let mut outputs = Vec::new();
+        for operand in &asm.operands {
+            let ty = operand.expr.ty.clone();
+            let input = match operand.dir {
+                AsmDir::In => Some(self.expr(&operand.expr)?),
+                AsmDir::Out => {
+                    outputs.push((self.place(&operand.expr)?, ty));
+                    None
+                }
+                AsmDir::InOut => {
+                    let place = self.place(&operand.expr)?;
+                    let value = self.read(place.clone(), ty.clone(), span);
+                    outputs.push((place, ty));
+                    Some(value)
+                }
+            };
+            operands.push(AsmOperand {
+                dir: operand.dir,
+                reg: operand.reg.clone(),
+                input,
+            });
+        }
+        let kind = InstKind::Asm(Box::new(Asm {
+            template: asm.template.clone(),
+            operands,
+            clobbers: asm.clobbers.clone(),
+            options: asm.options,
+        }));
+        if asm.options.noreturn {
+            self.emit(kind, span);
+            self.terminate(Terminator::Unreachable);
+            return None;
+        }
+        match outputs.len() {
+            0 => self.emit(kind, span),
+            1 => {
+                let (place, ty) = outputs.pop().unwrap();
+                let value = self.value(kind, ty, span);
+                self.store(place, value, span);
+            }
+            _ => {
+                let ty = Ty::Tuple(outputs.iter().map(|(_, ty)| ty.clone()).collect());
+                let value = self.value(kind, ty.clone(), span);
+                let tuple = self.temp("asm", value, ty, span);
+                for (i, (place, ty)) in outputs.into_iter().enumerate() {
+                    let value = self.read(tuple.clone().project(Projection::Field(i)), ty, span);
+                    self.store(place, value, span);
+                }
+            }
+        }
+        Some(Operand::Const(Const::Unit))
+    }
+
+    fn exprs<'e>(
+        &mut self,
+        exprs: impl IntoIterator<Item = &'e hir::Expr>,
+    ) -> Option<Vec<Operand>> {
+        exprs.into_iter().map(|expr| self.expr(expr)).collect()
+    }
+
+    fn aggregate(
+        &mut self,
+        kind: AggregateKind,
+        fields: Vec<Operand>,
+        ty: Ty,
+        span: Span,
+    ) -> Operand {
+        self.value(InstKind::Aggregate { kind, fields }, ty, span)
+    }
+
+    fn variant_index(&self, union: &str, variant: &str) -> usize {
+        self.program
+            .variant_index(union, variant)
+            .unwrap_or_else(|| panic!("no variant `{}::{}`", union, variant))
+    }
+
+    /// The place an expression denotes. Values that aren't places are put
+    /// in a temporary.
+    fn place(&mut self, expr: &hir::Expr) -> Option<Place> {
+        let place = match &expr.kind {
+            ExprKind::Local(id) => self.places[id].clone(),
+            ExprKind::Global(name) => Place {
+                base: PlaceBase::Global(name.clone()),
+                projection: Vec::new(),
+            },
+            ExprKind::Field { base, index, .. } => {
+                self.place(base)?.project(Projection::Field(*index))
+            }
+            ExprKind::Deref(inner) => self.place(inner)?.project(Projection::Deref),
+            _ => {
+                let value = self.expr(expr)?;
+                self.temp("tmp", value, expr.ty.clone(), expr.span)
+            }
+        };
+        Some(place)
+    }
+
+    fn block(&mut self, block: &hir::Block, span: Span) -> Option<Operand> {
+        self.scoped(span, |this| {
+            for stmt in &block.stmts {
+                match stmt {
+                    Stmt::Let { local, init } => {
+                        let value = this.expr(init)?;
+                        let slot = this.declare(*local);
+                        this.store(Place::local(slot), value, init.span);
+                    }
+                    Stmt::Expr(expr) => {
+                        this.expr(expr)?;
+                    }
+                }
+            }
+            this.expr(&block.value)
+        })
+    }
+
+    fn call(
+        &mut self,
+        callee: &hir::Callee,
+        args: &[hir::Expr],
+        ty: Ty,
+        span: Span,
+    ) -> Option<Operand> {
+        let callee = match callee {
+            hir::Callee::Fn {
+                func,
+                args: ty_args,
+                self_ty,
+            } => {
+                if let (FnOwner::Protocol(protocol), Some(Ty::Adt { name, args: elem })) =
+                    (&func.owner, self_ty)
+                    && protocol == prelude::ITERATOR
+                    && name == "Range"
+                {
+                    let range = self.expr(&args[0])?;
+                    return Some(self.range_next(range, &args[0].ty, &elem[0], ty, span));
+                }
+                Callee::Fn {
+                    func: func.clone(),
+                    args: ty_args.clone(),
+                    self_ty: self_ty.clone(),
+                }
+            }
+            hir::Callee::Builtin(name) => Callee::Builtin(name.clone()),
+            hir::Callee::Extern { symbol, variadic } => Callee::Extern {
+                symbol: symbol.clone(),
+                variadic: *variadic,
+            },
+            hir::Callee::Value(value) => Callee::Value(self.expr(value)?),
+        };
+        let args = self.exprs(args)?;
+        let kind = InstKind::Call { callee, args };
+        if ty == Ty::Never {
+            self.emit(kind, span);
+            self.terminate(Terminator::Unreachable);
+            return None;
+        }
+        Some(self.value(kind, ty, span))
+    }
+
+    /// `Range::next` through the reference `range`: the current start
+    /// wrapped in `Some` and incremented while it is below the end.
+    fn range_next(
+        &mut self,
+        range: Operand,
+        range_ty: &Ty,
+        elem: &Ty,
+        ty: Ty,
+        span: Span,
+    ) -> Operand {
+        let range = self
+            .temp("range", range, range_ty.clone(), span)
+            .project(Projection::Deref);
+        let start_place = range.project(Projection::Field(0));
+        let start = self.read(start_place.clone(), elem.clone(), span);
+        let end = self.read(range.project(Projection::Field(1)), elem.clone(), span);
+        let lt = InstKind::Binary {
+            op: BinOp::Lt,
+            lhs: start.clone(),
+            rhs: end,
+        };
+        let more = self.value(lt, Ty::Bool, span);
+        let some_block = self.new_block(Vec::new());
+        let none_block = self.new_block(Vec::new());
+        let join = self.join_block(&ty);
+        self.branch(more, some_block, none_block);
+
+        self.current = some_block;
+        let one = if *elem == Ty::Byte {
+            Const::Byte(1)
+        } else {
+            Const::Int(1)
+        };
+        let add = InstKind::Binary {
+            op: BinOp::Add,
+            lhs: start.clone(),
+            rhs: Operand::Const(one),
+        };
+        let next = self.value(add, elem.clone(), span);
+        self.store(start_place, next, span);
+        let some = AggregateKind::Variant {
+            union: prelude::OPTION.to_string(),
+            variant: self.variant_index(prelude::OPTION, "Some"),
+        };
+        let some = self.aggregate(some, vec![start], ty.clone(), span);
+        self.goto(join, Some(some));
+
+        self.current = none_block;
+        let none = AggregateKind::Variant {
+            union: prelude::OPTION.to_string(),
+            variant: self.variant_index(prelude::OPTION, "None"),
+        };
+        let none = self.aggregate(none, Vec::new(), ty, span);
+        self.goto(join, Some(none));
+
+        self.current = join;
+        self.joined(join)
+    }
+
+    fn match_expr(&mut self, scrutinee: &hir::Expr, arms: &[hir::Arm], ty: Ty) -> Option<Operand> {
+        let place = self.place(scrutinee)?;
+        let join = self.join_block(&ty);
+        let mut reached = false;
+        for arm in arms {
+            let next = self.new_block(Vec::new());
+            let value = self.scoped(arm.body.span, |this| {
+                let mut bindings = Vec::new();
+                this.test(&arm.pattern, place.clone(), next, &mut bindings);
+                for (id, place) in bindings {
+                    let value = this.read(place, this.body.local(id).ty.clone(), arm.pattern.span);
+                    let slot = this.declare(id);
+                    this.store(Place::local(slot), value, arm.pattern.span);
+                }
+                this.expr(&arm.body)
+            });
+            if let Some(value) = value {
+                self.goto(join, Some(value));
+                reached = true;
+            }
+            self.current = next;
+        }
+        // No arm matched: a runtime error, as `extract` on `None` is.
+        let message = Const::Str("no `match` arm matches".to_string());
+        let exit = InstKind::Call {
+            callee: Callee::Builtin("exit".to_string()),
+            args: vec![Operand::Const(message)],
+        };
+        self.emit(exit, scrutinee.span);
+        self.terminate(Terminator::Unreachable);
+        self.current = join;
+        if !reached {
+            return None;
+        }
+        Some(self.joined(join))
+    }
+
+    /// Continues in a new block if `place` matches `pattern`, jumping to
+    /// `fail` otherwise. The variables it binds are collected, to be bound
+    /// once the whole pattern has matched.
+    fn test(
+        &mut self,
+        pattern: &hir::Pattern,
+        place: Place,
+        fail: BlockId,
+        bindings: &mut Vec<(hir::LocalId, Place)>,
+    ) {
+        let span = pattern.span;
+        match &pattern.kind {
+            PatternKind::Wildcard => {}
+            PatternKind::Binding(id) => bindings.push((*id, place)),
+            PatternKind::Literal(literal) => {
+                let value = self.read(place, pattern.ty.clone(), span);
+                let eq = InstKind::Binary {
+                    op: BinOp::Eq,
+                    lhs: value,
+                    rhs: Operand::Const(constant(literal, &pattern.ty)),
+                };
+                let eq = self.value(eq, Ty::Bool, span);
+                self.expect(eq, fail);
+            }
+            PatternKind::Variant {
+                union,
+                variant,
+                fields,
+            } => {
+                let index = self.variant_index(union, variant);
+                let discriminant = self.value(InstKind::Discriminant(place.clone()), Ty::Int, span);
+                let eq = InstKind::Binary {
+                    op: BinOp::Eq,
+                    lhs: discriminant,
+                    rhs: Operand::Const(Const::Int(index as i64)),
+                };
+                let eq = self.value(eq, Ty::Bool, span);
+                self.expect(eq, fail);
+                let variant = place.project(Projection::Downcast(index));
+                for (i, field) in fields.iter().enumerate() {
+                    self.test(field, variant.project(Projection::Field(i)), fail, bindings);
+                }
+            }
+            PatternKind::Tuple(elems) => {
+                for (i, elem) in elems.iter().enumerate() {
+                    self.test(elem, place.project(Projection::Field(i)), fail, bindings);
+                }
+            }
+        }
+    }
+
+    /// Continues in a new block if `cond` holds, jumping to `fail` if not.
+    fn expect(&mut self, cond: Operand, fail: BlockId) {
+        let ok = self.new_block(Vec::new());
+        self.branch(cond, ok, fail);
+        self.current = ok;
+    }
+
+    fn capture_ty(&self, id: hir::LocalId) -> Ty {
+        let local = self.body.local(id);
+        Ty::Ref {
+            mutable: local.mutable,
+            inner: Box::new(local.ty.clone()),
+        }
+    }
+
+    /// Lifts a closure into a function of its own and builds its value from
+    /// references to the locals it captures.
+    fn closure(
+        &mut self,
+        params: &[hir::LocalId],
+        body: &hir::Expr,
+        ty: Ty,
+        span: Span,
+    ) -> Operand {
+        let captures = captures(params, body);
+        let mut refs = Vec::new();
+        for &id in &captures {
+            let place = self.places[&id].clone();
+            let kind = InstKind::Ref {
+                mutable: self.body.local(id).mutable,
+                place,
+            };
+            let capture_ty = self.capture_ty(id);
+            refs.push(self.value(kind, capture_ty, span));
+        }
+        let Ty::Fn { ret, .. } = &ty else {
+            panic!("closure of type `{}`", ty);
+        };
+        let index = self.closure_count;
+        self.closure_count += 1;
+        let name = format!("{}::{{closure#{}}}", self.name, index);
+        let owner = Owner::Closure {
+            parent: self.name.clone(),
+            index,
+        };
+        let mut nested = Vec::new();
+        let function = Builder::new(self.program, self.body, name.clone(), &mut nested)
+            .function(owner, params, &captures, ret, body);
+        self.closures.push(function);
+        self.closures.append(&mut nested);
+        self.value(
+            InstKind::Closure {
+                function: name,
+                captures: refs,
+            },
+            ty,
+            span,
+        )
+    }
+}
+
+fn operand(id: ValueId) -> Operand {
+    Operand::Value(id)
+}
+
+fn target(block: BlockId) -> Target {
+    Target {
+        block,
+        args: Vec::new(),
+    }
+}
+
+/// A literal as a constant of the type the checker gave it; integer
+/// literals may be bytes or floats.
+fn constant(literal: &Literal, ty: &Ty) -> Const {
+    match literal {
+        Literal::Int(n) => match ty {
+            Ty::Byte => Const::Byte(*n as u8),
+            Ty::Float => Const::Float(*n as f64),
+            _ => Const::Int(*n as i64),
+        },
+        Literal::Float(x) => Const::Float(*x),
+        Literal::Str(s) => Const::Str(s.clone()),
+        Literal::Bool(b) => Const::Bool(*b),
+        Literal::Char(c) => Const::Char(*c),
+    }
+}
+
+/// The locals a closure uses but doesn't declare, in declaration order.
+fn captures(params: &[hir::LocalId], body: &hir::Expr) -> Vec<hir::LocalId> {
+    let mut used = Vec::new();
+    let mut declared: HashSet<hir::LocalId> = params.iter().copied().collect();
+    collect_locals(body, &mut used, &mut declared);
+    let mut captures: Vec<hir::LocalId> = used
+        .into_iter()
+        .filter(|id| !declared.contains(id))
+        .collect();
+    captures.sort_by_key(|id| id.0);
+    captures.dedup();
+    captures
+}
+
+fn collect_locals(
+    expr: &hir::Expr,
+    used: &mut Vec<hir::LocalId>,
+    declared: &mut HashSet<hir::LocalId>,
+) {
+    let mut visit = |expr: &hir::Expr| collect_locals(expr, used, declared);
+    match &expr.kind {
+        ExprKind::Local(id) => used.push(*id),
+        ExprKind::Literal(_)
+        | ExprKind::Global(_)
+        | ExprKind::FnItem { .. }
+        | ExprKind::VariantCtor { .. }
+        | ExprKind::Continue => {}
+        ExprKind::Asm(asm) => asm.operands.iter().for_each(|o| visit(&o.expr)),
+        ExprKind::Tuple(elems) | ExprKind::Variant { fields: elems, .. } => {
+            elems.iter().for_each(visit)
+        }
+        ExprKind::Record { fields, .. } => fields.iter().for_each(|(_, value)| visit(value)),
+        ExprKind::Unary { expr, .. }
+        | ExprKind::Field { base: expr, .. }
+        | ExprKind::Break(expr)
+        | ExprKind::Return(expr)
+        | ExprKind::Ref { expr, .. }
+        | ExprKind::RawRef { expr, .. }
+        | ExprKind::Deref(expr)
+        | ExprKind::ToDyn { expr, .. } => visit(expr),
+        ExprKind::Binary { lhs, rhs, .. }
+        | ExprKind::Range {
+            start: lhs,
+            end: rhs,
+        }
+        | ExprKind::Assign {
+            place: lhs,
+            value: rhs,
+        } => {
+            visit(lhs);
+            visit(rhs);
+        }
+        ExprKind::Call { callee, args } => {
+            if let hir::Callee::Value(value) = callee {
+                visit(value);
+            }
+            args.iter().for_each(visit);
+        }
+        ExprKind::Block(block) | ExprKind::Loop(block) => {
+            for stmt in &block.stmts {
+                match stmt {
+                    Stmt::Let { local, init } => {
+                        declared.insert(*local);
+                        collect_locals(init, used, declared);
+                    }
+                    Stmt::Expr(expr) => collect_locals(expr, used, declared),
+                }
+            }
+            collect_locals(&block.value, used, declared);
+        }
+        ExprKind::If {
+            cond,
+            then_branch,
+            else_branch,
+        } => {
+            visit(cond);
+            visit(then_branch);
+            visit(else_branch);
+        }
+        ExprKind::Match { scrutinee, arms } => {
+            collect_locals(scrutinee, used, declared);
+            for arm in arms {
+                declare_bindings(&arm.pattern, declared);
+                collect_locals(&arm.body, used, declared);
+            }
+        }
+        ExprKind::Closure { params, body } => {
+            declared.extend(params.iter().copied());
+            collect_locals(body, used, declared);
+        }
+    }
+}
+
+fn declare_bindings(pattern: &hir::Pattern, declared: &mut HashSet<hir::LocalId>) {
+    match &pattern.kind {
+        PatternKind::Binding(id) => {
+            declared.insert(*id);
+        }
+        PatternKind::Variant { fields: elems, .. } | PatternKind::Tuple(elems) => {
+            for elem in elems {
+                declare_bindings(elem, declared);
+            }
+        }
+        PatternKind::Wildcard | PatternKind::Literal(_) => {}
+    }
+}
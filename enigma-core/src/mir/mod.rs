@@ -0,0 +1,746 @@
+//! The mid-level IR: every function as a control-flow graph.
+//!
+//! A function keeps its variables in explicit `locals`, memory that is read
+//! with `copy`/`move` and written with stores. Everything computed in
+//! between is an SSA value, defined exactly once by a block parameter or an
+//! instruction and usable wherever its definition dominates. Blocks end in a
+//! single terminator, and values flowing into a join point are passed as
+//! block arguments instead of phi nodes.
+//!
+//! Lowering from the HIR makes control flow and ownership explicit:
+//!
+//! * `if`, `loop` and `match` become branches, `match` testing each arm in
+//!   turn before binding its variables;
+//! * locals holding values that aren't `copy` are dropped where their scope
+//!   ends, including on `break`, `continue` and `return`;
+//! * closures become functions of their own, taking a tuple of references to
+//!   the locals they capture as their first parameter;
+//! * `next` on a `Range` is expanded in place, since it has no body.
+//!
+//! [`verify`] checks the invariants every pass must keep.
+
+mod lower;
+pub mod opt;
+mod print;
+mod verify;
+
+pub use lower::lower_program;
+pub use verify::{VerifyError, verify};
+
+use crate::checker::FnRef;
+use crate::checker::items::{FnAttrs, RecordDef, UnionDef};
+use crate::checker::types::Ty;
+use crate::lexer::size::Span;
+use crate::mono::MonoItems;
+use crate::parser::ast::{AsmDir, AsmOptions, AsmPiece, AsmReg, BinOp, UnaryOp};
+use std::collections::HashMap;
+
+/// Every lowered module of a program, dependencies first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    /// Records and unions by canonical name, including the builtin `Range`.
+    pub adts: HashMap<String, AdtDef>,
+    pub globals: Vec<(String, Ty)>,
+    /// Functions, closures and module initializers. A module's closures
+    /// follow the function containing them and its `init` comes last.
+    pub functions: Vec<Function>,
+    /// The instances and vtables the checked program needs.
+    pub mono: MonoItems,
+    /// The methods of each protocol in declaration order, which their
+    /// vtable slots follow.
+    pub protocols: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdtDef {
+    Record(RecordDef),
+    Union(UnionDef),
+}
+
+/// Whose code a function is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Owner {
+    Function(FnRef),
+    /// The top level statements of the named module.
+    Init(String),
+    /// The `index`th closure inside the function named `parent`. It shares
+    /// the parent's generic parameters.
+    Closure {
+        parent: String,
+        index: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ValueId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LocalId(pub u32);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    /// How the function is referred to: `name`, `type::method`,
+    /// `parent::{closure#n}` or `module::{init}`.
+    pub name: String,
+    pub owner: Owner,
+    /// Generic parameters in scope: the impl's, then the function's own.
+    pub generics: Vec<String>,
+    pub ret: Ty,
+    /// The attributes of a function; closures and initializers have none.
+    pub attrs: FnAttrs,
+    pub locals: Vec<Local>,
+    /// The type of every SSA value, by `ValueId`.
+    pub values: Vec<Ty>,
+    /// The entry block comes first; its parameters are the function's.
+    pub blocks: Vec<Block>,
+}
+
+impl Function {
+    pub fn params(&self) -> &[ValueId] {
+        &self.blocks[0].params
+    }
+
+    pub fn block(&self, id: BlockId) -> &Block {
+        &self.blocks[id.0 as usize]
+    }
+
+    pub fn local(&self, id: LocalId) -> &Local {
+        &self.locals[id.0 as usize]
+    }
+
+    pub fn value_ty(&self, id: ValueId) -> &Ty {
+        &self.values[id.0 as usize]
+    }
+
+    /// The blocks reachable from the entry, each before its successors
+    /// except along back edges.
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut order = Vec::new();
+        let mut visited = vec![false; self.blocks.len()];
+        visited[0] = true;
+        let mut stack = vec![(BlockId(0), 0)];
+        // Successors are visited last to first, so that the first comes
+        // right after its predecessor in the result.
+        while let Some((block, next)) = stack.pop() {
+            match self.block(block).successors().iter().rev().nth(next) {
+                Some(&succ) => {
+                    stack.push((block, next + 1));
+                    if let Some(seen) = visited.get_mut(succ.0 as usize)
+                        && !*seen
+                    {
+                        *seen = true;
+                        stack.push((succ, 0));
+                    }
+                }
+                None => order.push(block),
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    /// Drops the blocks unreachable from the entry and numbers the rest in
+    /// reverse postorder, so a dump reads top to bottom.
+    pub fn order_blocks(&mut self) {
+        let order = self.reverse_postorder();
+        let mut renumbered = vec![None; self.blocks.len()];
+        for (i, block) in order.iter().enumerate() {
+            renumbered[block.0 as usize] = Some(BlockId(i as u32));
+        }
+        let mut blocks: Vec<Option<Block>> = std::mem::take(&mut self.blocks)
+            .into_iter()
+            .map(Some)
+            .collect();
+        for id in order {
+            let mut block = blocks[id.0 as usize].take().unwrap();
+            for target in block.term.targets_mut() {
+                target.block = renumbered[target.block.0 as usize].unwrap();
+            }
+            self.blocks.push(block);
+        }
+    }
+
+    /// The predecessors of each block, once per edge.
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut preds = vec![Vec::new(); self.blocks.len()];
+        for (i, block) in self.blocks.iter().enumerate() {
+            for succ in block.successors() {
+                preds[succ.0 as usize].push(BlockId(i as u32));
+            }
+        }
+        preds
+    }
+
+    /// Replaces every use of a value in `map` by what it maps to, following
+    /// chains of replacements.
+    pub fn replace_uses(&mut self, map: &HashMap<ValueId, Operand>) {
+        if map.is_empty() {
+            return;
+        }
+        let resolve = |operand: &mut Operand| {
+            while let Operand::Value(id) = operand
+                && let Some(replacement) = map.get(id)
+            {
+                *operand = replacement.clone();
+            }
+        };
+        for block in &mut self.blocks {
+            for inst in &mut block.insts {
+                inst.kind.operands_mut().into_iter().for_each(resolve);
+            }
+            block.term.operands_mut().into_iter().for_each(resolve);
+        }
+    }
+
+    /// How many times each value is used.
+    pub fn use_counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.values.len()];
+        for block in &self.blocks {
+            let operands = block
+                .insts
+                .iter()
+                .flat_map(|inst| inst.kind.operands())
+                .chain(block.term.operands());
+            for operand in operands {
+                if let Operand::Value(id) = operand {
+                    counts[id.0 as usize] += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    /// A copy of a generic function with its generic parameters replaced by
+    /// `args`, in the order of `generics`.
+    pub fn instantiate(&self, args: &[Ty]) -> Function {
+        let subst = |ty: &Ty| ty.subst(&self.generics, args);
+        let mut function = self.clone();
+        function.generics.clear();
+        function.ret = subst(&self.ret);
+        function.values.iter_mut().for_each(|ty| *ty = subst(ty));
+        for local in &mut function.locals {
+            local.ty = subst(&local.ty);
+        }
+        for inst in function
+            .blocks
+            .iter_mut()
+            .flat_map(|block| &mut block.insts)
+        {
+            inst.kind.map_tys(&subst);
+        }
+        function
+    }
+
+    pub fn operand_ty(&self, operand: &Operand) -> Ty {
+        match operand {
+            Operand::Value(id) => self.value_ty(*id).clone(),
+            Operand::Const(constant) => constant.ty(),
+        }
+    }
+}
+
+/// A slot in the function's frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Local {
+    /// The source name, or what a temporary holds.
+    pub name: String,
+    pub ty: Ty,
+    pub mutable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub params: Vec<ValueId>,
+    pub insts: Vec<Inst>,
+    pub term: Terminator,
+}
+
+impl Block {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match &self.term {
+            Terminator::Goto(target) => vec![target.block],
+            Terminator::Branch {
+                then_target,
+                else_target,
+                ..
+            } => vec![then_target.block, else_target.block],
+            Terminator::Return(_) | Terminator::Unreachable => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inst {
+    /// The value the instruction defines, if it produces one.
+    pub result: Option<ValueId>,
+    pub kind: InstKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InstKind {
+    Unary {
+        op: UnaryOp,
+        operand: Operand,
+    },
+    /// Never `&&` or `||`.
+    Binary {
+        op: BinOp,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    /// Reads a place, leaving it as it was.
+    Copy(Place),
+    /// Reads a place whose type isn't `copy`, leaving it uninitialized.
+    Move(Place),
+    Store {
+        place: Place,
+        value: Operand,
+    },
+    Ref {
+        mutable: bool,
+        place: Place,
+    },
+    RawRef {
+        mutable: bool,
+        place: Place,
+    },
+    /// A tuple, record or variant built from its fields, in order.
+    Aggregate {
+        kind: AggregateKind,
+        fields: Vec<Operand>,
+    },
+    /// The index of the variant held by a union place, as an `int`.
+    Discriminant(Place),
+    /// Has no result if the callee never returns; the block then ends in
+    /// `unreachable`.
+    Call {
+        callee: Callee,
+        args: Vec<Operand>,
+    },
+    /// A function or method used as a value.
+    FnItem {
+        func: FnRef,
+        args: Vec<Ty>,
+    },
+    /// A variant with fields used as a function value.
+    VariantCtor {
+        union: String,
+        variant: usize,
+    },
+    /// A closure value: the named function and references to the places it
+    /// captures, which become its first argument.
+    Closure {
+        function: String,
+        captures: Vec<Operand>,
+    },
+    /// A `ref T` turned into a `ref protocol` object.
+    ToDyn {
+        value: Operand,
+        protocol: String,
+    },
+    /// Ends the life of the value in a place, if it still holds one.
+    Drop(Place),
+    /// Inline assembly. The result holds the outputs: the value of the only
+    /// one, or a tuple of them.
+    Asm(Box<Asm>),
+}
+
+impl InstKind {
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            InstKind::Unary { operand, .. } => vec![operand],
+            InstKind::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            InstKind::Store { value, .. } | InstKind::ToDyn { value, .. } => vec![value],
+            InstKind::Aggregate {
+                fields: operands, ..
+            }
+            | InstKind::Closure {
+                captures: operands, ..
+            } => operands.iter().collect(),
+            InstKind::Call { callee, args } => {
+                let mut operands: Vec<&Operand> = args.iter().collect();
+                if let Callee::Value(value) = callee {
+                    operands.insert(0, value);
+                }
+                operands
+            }
+            InstKind::Asm(asm) => asm.inputs().collect(),
+            InstKind::Copy(_)
+            | InstKind::Move(_)
+            | InstKind::Ref { .. }
+            | InstKind::RawRef { .. }
+            | InstKind::Discriminant(_)
+            | InstKind::FnItem { .. }
+            | InstKind::VariantCtor { .. }
+            | InstKind::Drop(_) => Vec::new(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            InstKind::Unary { operand, .. } => vec![operand],
+            InstKind::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            InstKind::Store { value, .. } | InstKind::ToDyn { value, .. } => vec![value],
+            InstKind::Aggregate {
+                fields: operands, ..
+            }
+            | InstKind::Closure {
+                captures: operands, ..
+            } => operands.iter_mut().collect(),
+            InstKind::Call { callee, args } => {
+                let mut operands: Vec<&mut Operand> = Vec::new();
+                if let Callee::Value(value) = callee {
+                    operands.push(value);
+                }
+                operands.extend(args.iter_mut());
+                operands
+            }
+            InstKind::Asm(asm) => asm
+                .operands
+                .iter_mut()
+                .filter_map(|operand| operand.input.as_mut())
+                .collect(),
+            InstKind::Copy(_)
+            | InstKind::Move(_)
+            | InstKind::Ref { .. }
+            | InstKind::RawRef { .. }
+            | InstKind::Discriminant(_)
+            | InstKind::FnItem { .. }
+            | InstKind::VariantCtor { .. }
+            | InstKind::Drop(_) => Vec::new(),
+        }
+    }
+
+    /// Replaces every type the instruction names by `f` of it.
+    pub fn map_tys(&mut self, f: &impl Fn(&Ty) -> Ty) {
+        match self {
+            InstKind::Call {
+                callee: Callee::Fn { args, self_ty, .. },
+                ..
+            } => {
+                args.iter_mut().for_each(|ty| *ty = f(ty));
+                if let Some(ty) = self_ty {
+                    *ty = f(ty);
+                }
+            }
+            InstKind::FnItem { args, .. } => args.iter_mut().for_each(|ty| *ty = f(ty)),
+            _ => {}
+        }
+    }
+
+    /// The place the instruction reads, writes or borrows, if any.
+    pub fn place(&self) -> Option<&Place> {
+        match self {
+            InstKind::Copy(place)
+            | InstKind::Move(place)
+            | InstKind::Store { place, .. }
+            | InstKind::Ref { place, .. }
+            | InstKind::RawRef { place, .. }
+            | InstKind::Discriminant(place)
+            | InstKind::Drop(place) => Some(place),
+            _ => None,
+        }
+    }
+
+    pub fn place_mut(&mut self) -> Option<&mut Place> {
+        match self {
+            InstKind::Copy(place)
+            | InstKind::Move(place)
+            | InstKind::Store { place, .. }
+            | InstKind::Ref { place, .. }
+            | InstKind::RawRef { place, .. }
+            | InstKind::Discriminant(place)
+            | InstKind::Drop(place) => Some(place),
+            _ => None,
+        }
+    }
+}
+
+/// An `asm` block, as in the HIR.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Asm {
+    pub template: Vec<Vec<AsmPiece>>,
+    pub operands: Vec<AsmOperand>,
+    pub clobbers: Vec<String>,
+    pub options: AsmOptions,
+}
+
+/// Inputs and `inout` operands carry the value going in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmOperand {
+    pub dir: AsmDir,
+    pub reg: AsmReg,
+    pub input: Option<Operand>,
+}
+
+impl Asm {
+    pub fn inputs(&self) -> impl Iterator<Item = &Operand> {
+        self.operands
+            .iter()
+            .filter_map(|operand| operand.input.as_ref())
+    }
+
+    /// Assembly without outputs is only run for its effects; the rest may
+    /// be removed when its outputs go unused unless marked `volatile`.
+    pub fn is_volatile(&self) -> bool {
+        self.options.volatile
+            || self.options.noreturn
+            || !self.operands.iter().any(|operand| operand.dir.writes())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AggregateKind {
+    Tuple,
+    Record(String),
+    Variant { union: String, variant: usize },
+}
+
+/// What a call invokes, as in the HIR.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Callee {
+    Fn {
+        func: FnRef,
+        args: Vec<Ty>,
+        self_ty: Option<Ty>,
+    },
+    Builtin(String),
+    Extern {
+        symbol: String,
+        variadic: bool,
+    },
+    Value(Operand),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Value(ValueId),
+    Const(Const),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Const {
+    Int(i64),
+    Byte(u8),
+    Float(f64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    Unit,
+}
+
+impl Const {
+    pub fn ty(&self) -> Ty {
+        match self {
+            Const::Int(_) => Ty::Int,
+            Const::Byte(_) => Ty::Byte,
+            Const::Float(_) => Ty::Float,
+            Const::Bool(_) => Ty::Bool,
+            Const::Char(_) => Ty::Char,
+            Const::Str(_) => Ty::Str,
+            Const::Unit => Ty::Unit,
+        }
+    }
+}
+
+/// A memory location: a local or global, then a path into it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Place {
+    pub base: PlaceBase,
+    pub projection: Vec<Projection>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlaceBase {
+    Local(LocalId),
+    /// A global, by canonical name.
+    Global(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    /// What a `ref` or `raw_ref` points to.
+    Deref,
+    /// A record field or tuple element, or a field of the variant selected
+    /// by a preceding `Downcast`.
+    Field(usize),
+    /// The union viewed as the variant with this index.
+    Downcast(usize),
+}
+
+impl Place {
+    pub fn local(id: LocalId) -> Self {
+        Self {
+            base: PlaceBase::Local(id),
+            projection: Vec::new(),
+        }
+    }
+
+    pub fn project(&self, projection: Projection) -> Self {
+        let mut place = self.clone();
+        place.projection.push(projection);
+        place
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Goto(Target),
+    Branch {
+        cond: Operand,
+        then_target: Target,
+        else_target: Target,
+    },
+    Return(Operand),
+    /// Control never gets here: it follows calls that don't return, like
+    /// the `exit` a `match` runs when no arm matches. Backends trap.
+    Unreachable,
+}
+
+impl Terminator {
+    pub fn targets_mut(&mut self) -> Vec<&mut Target> {
+        match self {
+            Terminator::Goto(target) => vec![target],
+            Terminator::Branch {
+                then_target,
+                else_target,
+                ..
+            } => vec![then_target, else_target],
+            Terminator::Return(_) | Terminator::Unreachable => Vec::new(),
+        }
+    }
+
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Terminator::Goto(target) => target.args.iter().collect(),
+            Terminator::Branch {
+                cond,
+                then_target,
+                else_target,
+            } => std::iter::once(cond)
+                .chain(&then_target.args)
+                .chain(&else_target.args)
+                .collect(),
+            Terminator::Return(value) => vec![value],
+            Terminator::Unreachable => Vec::new(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Terminator::Goto(target) => target.args.iter_mut().collect(),
+            Terminator::Branch {
+                cond,
+                then_target,
+                else_target,
+            } => std::iter::once(cond)
+                .chain(&mut then_target.args)
+                .chain(&mut else_target.args)
+                .collect(),
+            Terminator::Return(value) => vec![value],
+            Terminator::Unreachable => Vec::new(),
+        }
+    }
+}
+
+/// A jump to a block, passing its parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub block: BlockId,
+    pub args: Vec<Operand>,
+}
+
+impl Program {
+    pub fn function(&self, name: &str) -> Option<&Function> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+
+    /// The type of `place` in `func`, or why it has none.
+    pub fn place_ty(&self, func: &Function, place: &Place) -> Result<Ty, String> {
+        let mut ty = match &place.base {
+            PlaceBase::Local(id) => func
+                .locals
+                .get(id.0 as usize)
+                .map(|local| local.ty.clone())
+                .ok_or_else(|| format!("unknown local _{}", id.0))?,
+            PlaceBase::Global(name) => self
+                .globals
+                .iter()
+                .find(|(global, _)| global == name)
+                .map(|(_, ty)| ty.clone())
+                .ok_or_else(|| format!("unknown global `{}`", name))?,
+        };
+        let mut variant: Option<Vec<Ty>> = None;
+        for projection in &place.projection {
+            ty = match (*projection, variant.take()) {
+                (Projection::Deref, None) => match ty {
+                    Ty::Ref { inner, .. } | Ty::RawRef { inner, .. } => *inner,
+                    _ => return Err(format!("cannot dereference `{}`", ty)),
+                },
+                (Projection::Field(index), Some(fields)) => fields
+                    .get(index)
+                    .cloned()
+                    .ok_or_else(|| format!("the variant has no field {}", index))?,
+                (Projection::Field(index), None) => match &ty {
+                    Ty::Tuple(elems) => elems.get(index).cloned(),
+                    Ty::Adt { name, args } => match self.adts.get(name) {
+                        Some(AdtDef::Record(def)) => def
+                            .fields
+                            .get(index)
+                            .map(|(_, field)| field.subst(&def.generics, args)),
+                        _ => None,
+                    },
+                    _ => None,
+                }
+                .ok_or_else(|| format!("`{}` has no field {}", ty, index))?,
+                (Projection::Downcast(index), None) => {
+                    let fields = match &ty {
+                        Ty::Adt { name, args } => match self.adts.get(name) {
+                            Some(AdtDef::Union(def)) => {
+                                def.variants.get(index).map(|(_, fields)| {
+                                    fields
+                                        .iter()
+                                        .map(|field| field.subst(&def.generics, args))
+                                        .collect()
+                                })
+                            }
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    variant =
+                        Some(fields.ok_or_else(|| format!("`{}` has no variant {}", ty, index))?);
+                    ty
+                }
+                (_, Some(_)) => return Err("a downcast must be followed by a field".to_string()),
+            };
+        }
+        if variant.is_some() {
+            return Err("a downcast must be followed by a field".to_string());
+        }
+        Ok(ty)
+    }
+
+    /// The index of `variant` in the union `union`.
+    pub fn variant_index(&self, union: &str, variant: &str) -> Option<usize> {
+        match self.adts.get(union)? {
+            AdtDef::Union(def) => def.variants.iter().position(|(name, _)| name == variant),
+            AdtDef::Record(_) => None,
+        }
+    }
+}
+
+/// Whether reading a value of this type leaves the original usable. Other
+/// values are moved, and dropped at the end of their scope.
+pub fn is_copy(ty: &Ty) -> bool {
+    match ty {
+        Ty::Adt { .. } | Ty::Param(_) | Ty::Dyn(_) => false,
+        Ty::Ref { mutable, .. } => !mutable,
+        Ty::Tuple(elems) => elems.iter().all(is_copy),
+        _ => true,
+    }
+}
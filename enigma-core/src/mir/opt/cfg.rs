@@ -0,0 +1,135 @@
+//! Control-flow graph simplification: branches on constants become jumps,
+//! jumps through empty blocks go straight to where those lead, a block
+//! entered only from a jump is merged into its predecessor, and blocks no
+//! longer reachable are dropped.
+
+use crate::mir::{Block, BlockId, Const, Function, Operand, Target, Terminator};
+use std::collections::HashMap;
+
+pub(super) fn simplify(function: &mut Function) -> bool {
+    let mut changed = false;
+    loop {
+        changed |= reorder(function);
+        let round = fold_branches(function) | thread_jumps(function) | merge_blocks(function);
+        if !round {
+            break;
+        }
+        changed = true;
+    }
+    changed
+}
+
+/// Drops unreachable blocks and renumbers the rest, if they aren't already
+/// in order.
+fn reorder(function: &mut Function) -> bool {
+    let order = function.reverse_postorder();
+    let ordered = order.len() == function.blocks.len()
+        && order
+            .iter()
+            .enumerate()
+            .all(|(i, block)| block.0 as usize == i);
+    if !ordered {
+        function.order_blocks();
+    }
+    !ordered
+}
+
+fn fold_branches(function: &mut Function) -> bool {
+    let mut changed = false;
+    for block in &mut function.blocks {
+        let Terminator::Branch {
+            cond,
+            then_target,
+            else_target,
+        } = &block.term
+        else {
+            continue;
+        };
+        let taken = match cond {
+            Operand::Const(Const::Bool(true)) => then_target,
+            Operand::Const(Const::Bool(false)) => else_target,
+            _ if then_target == else_target => then_target,
+            _ => continue,
+        };
+        block.term = Terminator::Goto(taken.clone());
+        changed = true;
+    }
+    changed
+}
+
+/// Retargets jumps to blocks that do nothing but jump on. Such a block has
+/// no parameters, so the arguments it passes are defined before it and
+/// therefore before each of its predecessors too.
+fn thread_jumps(function: &mut Function) -> bool {
+    let forwards: HashMap<BlockId, Target> = function
+        .blocks
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter_map(|(i, block)| match &block.term {
+            Terminator::Goto(target)
+                if block.params.is_empty()
+                    && block.insts.is_empty()
+                    && target.block.0 as usize != i =>
+            {
+                Some((BlockId(i as u32), target.clone()))
+            }
+            _ => None,
+        })
+        .collect();
+    let mut changed = false;
+    for block in &mut function.blocks {
+        for target in block.term.targets_mut() {
+            let mut seen = vec![target.block];
+            let mut next = target.clone();
+            while let Some(forward) = forwards.get(&next.block) {
+                if seen.contains(&forward.block) {
+                    // An empty infinite loop: leave it be.
+                    break;
+                }
+                seen.push(forward.block);
+                next = forward.clone();
+            }
+            if !forwards.contains_key(&next.block) && next != *target {
+                *target = next;
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// Merges each block whose only predecessor jumps straight to it into that
+/// predecessor, its parameters replaced by the jump's arguments.
+fn merge_blocks(function: &mut Function) -> bool {
+    let mut changed = false;
+    loop {
+        let preds = function.predecessors();
+        let candidate = (1..function.blocks.len()).find_map(|b| match preds[b][..] {
+            [pred] if pred.0 as usize != b => match &function.blocks[pred.0 as usize].term {
+                Terminator::Goto(target) if target.block.0 as usize == b => Some((pred, b)),
+                _ => None,
+            },
+            _ => None,
+        });
+        let Some((pred, b)) = candidate else {
+            return changed;
+        };
+        let block = std::mem::replace(
+            &mut function.blocks[b],
+            Block {
+                params: Vec::new(),
+                insts: Vec::new(),
+                term: Terminator::Unreachable,
+            },
+        );
+        let pred = &mut function.blocks[pred.0 as usize];
+        let Terminator::Goto(target) = std::mem::replace(&mut pred.term, block.term) else {
+            unreachable!("the predecessor ends in a jump");
+        };
+        pred.insts.extend(block.insts);
+        let params = block.params.into_iter().zip(target.args).collect();
+        function.replace_uses(&params);
+        changed = true;
+    }
+}
@@ -0,0 +1,154 @@
+//! Dead code elimination: instructions whose result is unused and that
+//! have no other effect, block parameters no one reads, and stores to
+//! locals that are never read. Locals left without any use are removed.
+
+use super::fold::may_fail;
+use crate::mir::{Function, InstKind, LocalId, PlaceBase, is_copy};
+use std::collections::HashMap;
+
+pub(super) fn eliminate(function: &mut Function) -> bool {
+    let mut changed = false;
+    loop {
+        let round = remove_unused_results(function)
+            | remove_unused_params(function)
+            | remove_dead_stores(function);
+        if !round {
+            break;
+        }
+        changed = true;
+    }
+    changed | remove_unused_locals(function)
+}
+
+/// Whether the instruction does nothing besides producing its result.
+fn is_pure(kind: &InstKind, function: &Function) -> bool {
+    match kind {
+        InstKind::Unary { .. } | InstKind::Binary { .. } => !may_fail(kind, function),
+        InstKind::Copy(_)
+        | InstKind::Ref { .. }
+        | InstKind::RawRef { .. }
+        | InstKind::Aggregate { .. }
+        | InstKind::Discriminant(_)
+        | InstKind::FnItem { .. }
+        | InstKind::VariantCtor { .. }
+        | InstKind::Closure { .. }
+        | InstKind::ToDyn { .. } => true,
+        InstKind::Asm(asm) => !asm.is_volatile(),
+        // A move leaves its place uninitialized, which decides whether the
+        // place is dropped later.
+        InstKind::Move(_) | InstKind::Store { .. } | InstKind::Call { .. } | InstKind::Drop(_) => {
+            false
+        }
+    }
+}
+
+fn remove_unused_results(function: &mut Function) -> bool {
+    let uses = function.use_counts();
+    let mut dead = Vec::new();
+    for (b, block) in function.blocks.iter().enumerate() {
+        for (i, inst) in block.insts.iter().enumerate() {
+            if let Some(result) = inst.result
+                && uses[result.0 as usize] == 0
+                && is_pure(&inst.kind, function)
+            {
+                dead.push((b, i));
+            }
+        }
+    }
+    for &(b, i) in dead.iter().rev() {
+        function.blocks[b].insts.remove(i);
+    }
+    !dead.is_empty()
+}
+
+fn remove_unused_params(function: &mut Function) -> bool {
+    let uses = function.use_counts();
+    let mut changed = false;
+    // The entry block's parameters are the function's.
+    for b in 1..function.blocks.len() {
+        let unused: Vec<usize> = function.blocks[b]
+            .params
+            .iter()
+            .enumerate()
+            .filter(|(_, param)| uses[param.0 as usize] == 0)
+            .map(|(i, _)| i)
+            .collect();
+        if unused.is_empty() {
+            continue;
+        }
+        changed = true;
+        for &i in unused.iter().rev() {
+            function.blocks[b].params.remove(i);
+        }
+        for block in &mut function.blocks {
+            for target in block.term.targets_mut() {
+                if target.block.0 as usize == b {
+                    for &i in unused.iter().rev() {
+                        target.args.remove(i);
+                    }
+                }
+            }
+        }
+    }
+    changed
+}
+
+/// Removes stores to locals of `copy` types that nothing reads. Other
+/// locals keep their stores, since their values still have to be dropped.
+fn remove_dead_stores(function: &mut Function) -> bool {
+    let mut read = vec![false; function.locals.len()];
+    for inst in function.blocks.iter().flat_map(|block| &block.insts) {
+        if let Some(place) = inst.kind.place()
+            && let PlaceBase::Local(local) = place.base
+            && !matches!(inst.kind, InstKind::Store { .. } | InstKind::Drop(_))
+        {
+            read[local.0 as usize] = true;
+        }
+    }
+    let mut changed = false;
+    let locals = &function.locals;
+    for block in &mut function.blocks {
+        block.insts.retain(|inst| {
+            let dead = matches!(&inst.kind, InstKind::Store { place, .. }
+                if matches!(place.base, PlaceBase::Local(local)
+                    if !read[local.0 as usize] && is_copy(&locals[local.0 as usize].ty)));
+            changed |= dead;
+            !dead
+        });
+    }
+    changed
+}
+
+fn remove_unused_locals(function: &mut Function) -> bool {
+    let mut used = vec![false; function.locals.len()];
+    for inst in function.blocks.iter().flat_map(|block| &block.insts) {
+        if let Some(place) = inst.kind.place()
+            && let PlaceBase::Local(local) = place.base
+        {
+            used[local.0 as usize] = true;
+        }
+    }
+    if used.iter().all(|&used| used) {
+        return false;
+    }
+    let mut renumbered = HashMap::new();
+    let locals = std::mem::take(&mut function.locals);
+    for (i, local) in locals.into_iter().enumerate() {
+        if used[i] {
+            renumbered.insert(LocalId(i as u32), LocalId(function.locals.len() as u32));
+            function.locals.push(local);
+        }
+    }
+    for inst in function
+        .blocks
+        .iter_mut()
+        .flat_map(|block| &mut block.insts)
+    {
+        if let Some(place) = inst.kind.place_mut()
+            && let PlaceBase::Local(local) = &mut place.base
+        {
+            *local = renumbered[local];
+        }
+    }
+    true
+}
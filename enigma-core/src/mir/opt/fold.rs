@@ -0,0 +1,146 @@
+//! Constant folding: operators whose operands are all constants are
+//! evaluated at compile time, and their results replaced by the constant.
+//! An operation that would fail at runtime is left for the program to fail.
+
+use crate::checker::types::Ty;
+use crate::mir::{Const, Function, InstKind, Operand, ValueId};
+use crate::parser::ast::{BinOp, UnaryOp};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+pub(super) fn fold(function: &mut Function) -> bool {
+    let mut folded: HashMap<ValueId, Operand> = HashMap::new();
+    for block in &mut function.blocks {
+        block.insts.retain_mut(|inst| {
+            // Results folded earlier in this walk feed the operators after.
+            for operand in inst.kind.operands_mut() {
+                if let Operand::Value(id) = operand
+                    && let Some(constant) = folded.get(id)
+                {
+                    *operand = constant.clone();
+                }
+            }
+            let constant = match &inst.kind {
+                InstKind::Unary {
+                    op,
+                    operand: Operand::Const(operand),
+                } => unary(*op, operand),
+                InstKind::Binary {
+                    op,
+                    lhs: Operand::Const(lhs),
+                    rhs: Operand::Const(rhs),
+                } => binary(*op, lhs, rhs),
+                _ => None,
+            };
+            match (inst.result, constant) {
+                (Some(result), Some(constant)) => {
+                    folded.insert(result, Operand::Const(constant));
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+    function.replace_uses(&folded);
+    !folded.is_empty()
+}
+
+fn unary(op: UnaryOp, operand: &Const) -> Option<Const> {
+    Some(match (op, operand) {
+        (UnaryOp::Neg, Const::Int(n)) => Const::Int(n.checked_neg()?),
+        (UnaryOp::Neg, Const::Float(x)) => Const::Float(-x),
+        (UnaryOp::Not, Const::Bool(b)) => Const::Bool(!b),
+        (UnaryOp::Not, Const::Int(n)) => Const::Int(!n),
+        (UnaryOp::Not, Const::Byte(b)) => Const::Byte(!b),
+        _ => return None,
+    })
+}
+
+fn binary(op: BinOp, lhs: &Const, rhs: &Const) -> Option<Const> {
+    let ordering = || compare(lhs, rhs);
+    let result = match op {
+        BinOp::Eq => Const::Bool(lhs == rhs),
+        BinOp::Ne => Const::Bool(lhs != rhs),
+        BinOp::Lt => Const::Bool(ordering()?.is_lt()),
+        BinOp::Gt => Const::Bool(ordering()?.is_gt()),
+        BinOp::Le => Const::Bool(ordering()?.is_le()),
+        BinOp::Ge => Const::Bool(ordering()?.is_ge()),
+        _ => match (lhs, rhs) {
+            (Const::Int(a), Const::Int(b)) => Const::Int(match op {
+                BinOp::Add => a.checked_add(*b)?,
+                BinOp::Sub => a.checked_sub(*b)?,
+                BinOp::Mul => a.checked_mul(*b)?,
+                BinOp::Div => a.checked_div(*b)?,
+                BinOp::Rem => a.checked_rem(*b)?,
+                BinOp::BitAnd => a & b,
+                BinOp::BitOr => a | b,
+                BinOp::BitXor => a ^ b,
+                _ => return None,
+            }),
+            (Const::Byte(a), Const::Byte(b)) => Const::Byte(match op {
+                BinOp::Add => a.checked_add(*b)?,
+                BinOp::Sub => a.checked_sub(*b)?,
+                BinOp::Mul => a.checked_mul(*b)?,
+                BinOp::Div => a.checked_div(*b)?,
+                BinOp::Rem => a.checked_rem(*b)?,
+                BinOp::BitAnd => a & b,
+                BinOp::BitOr => a | b,
+                BinOp::BitXor => a ^ b,
+                _ => return None,
+            }),
+            (Const::Float(a), Const::Float(b)) => Const::Float(match op {
+                BinOp::Add => a + b,
+                BinOp::Sub => a - b,
+                BinOp::Mul => a * b,
+                BinOp::Div => a / b,
+                _ => return None,
+            }),
+            (Const::Str(a), Const::Str(b)) if op == BinOp::Add => Const::Str(format!("{}{}", a, b)),
+            _ => return None,
+        },
+    };
+    Some(result)
+}
+
+fn compare(lhs: &Const, rhs: &Const) -> Option<Ordering> {
+    match (lhs, rhs) {
+        (Const::Int(a), Const::Int(b)) => Some(a.cmp(b)),
+        (Const::Byte(a), Const::Byte(b)) => Some(a.cmp(b)),
+        (Const::Float(a), Const::Float(b)) => a.partial_cmp(b),
+        (Const::Char(a), Const::Char(b)) => Some(a.cmp(b)),
+        (Const::Str(a), Const::Str(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// Whether an operator might stop the program with a runtime error:
+/// integer arithmetic that can overflow or divide by zero. Generic operands
+/// may turn out to be integers.
+pub(super) fn may_fail(kind: &InstKind, function: &Function) -> bool {
+    let maybe_integer = |operand: &Operand| {
+        matches!(
+            function.operand_ty(operand),
+            Ty::Int | Ty::Byte | Ty::Param(_)
+        )
+    };
+    match kind {
+        InstKind::Unary {
+            op: UnaryOp::Neg,
+            operand,
+        } => maybe_integer(operand),
+        InstKind::Binary { op, lhs, rhs } => {
+            maybe_integer(lhs)
+                && match op {
+                    BinOp::Add | BinOp::Sub | BinOp::Mul => true,
+                    // Only `MIN / -1` overflows.
+                    BinOp::Div | BinOp::Rem => !matches!(
+                        rhs,
+                        Operand::Const(Const::Int(2..) | Const::Int(..=-2) | Const::Int(1))
+                            | Operand::Const(Const::Byte(1..))
+                    ),
+                    _ => false,
+                }
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,173 @@
+//! Inlining: calls to small functions are replaced by a copy of the
+//! callee's body. The calling block is split at the call; its first half
+//! jumps into the copy, passing the arguments as the parameters of the
+//! copied entry block, and every `return` of the copy jumps to the second
+//! half with the returned value as its parameter.
+
+use crate::checker::types::Ty;
+use crate::checker::{FnOwner, FnRef};
+use crate::mir::{
+    Block, BlockId, Callee, Function, InstKind, LocalId, Operand, Owner, PlaceBase, Program,
+    Target, Terminator, ValueId,
+};
+
+/// Callees with more instructions than this are left alone.
+const INLINE_LIMIT: usize = 24;
+
+pub(super) fn inline(program: &mut Program) -> bool {
+    // Callees are copied as they were before this pass, so one round never
+    // inlines into a body that is itself being inlined.
+    let callees = program.functions.clone();
+    let mut changed = false;
+    for function in &mut program.functions {
+        changed |= inline_calls(function, &callees);
+    }
+    changed
+}
+
+fn inline_calls(function: &mut Function, callees: &[Function]) -> bool {
+    let mut changed = false;
+    let mut work: Vec<usize> = (0..function.blocks.len()).collect();
+    while let Some(b) = work.pop() {
+        let found = function.blocks[b]
+            .insts
+            .iter()
+            .enumerate()
+            .find_map(|(i, inst)| match &inst.kind {
+                InstKind::Call { callee, .. } => resolve(callees, callee)
+                    .filter(|(callee, _)| callee.name != function.name && inlinable(callee))
+                    .map(|(callee, ty_args)| (i, callee, ty_args)),
+                _ => None,
+            });
+        let Some((i, callee, ty_args)) = found else {
+            continue;
+        };
+        // The rest of the block continues after the call.
+        let rest = function.blocks[b].insts.split_off(i + 1);
+        let call = function.blocks[b].insts.pop().unwrap();
+        let InstKind::Call { args, .. } = call.kind else {
+            unreachable!("the split is at a call");
+        };
+        let after = BlockId(function.blocks.len() as u32);
+        let term = std::mem::replace(
+            &mut function.blocks[b].term,
+            Terminator::Goto(Target {
+                block: BlockId(after.0 + 1),
+                args,
+            }),
+        );
+        function.blocks.push(Block {
+            params: call.result.into_iter().collect(),
+            insts: rest,
+            term,
+        });
+        copy_body(function, callee, &ty_args, after);
+        work.push(after.0 as usize);
+        changed = true;
+    }
+    if changed {
+        function.order_blocks();
+    }
+    changed
+}
+
+/// The function a call runs and the type arguments to instantiate it with,
+/// if known. A protocol method is known when the receiver is a record or
+/// union whose implementation isn't generic.
+fn resolve<'f>(functions: &'f [Function], callee: &Callee) -> Option<(&'f Function, Vec<Ty>)> {
+    let Callee::Fn {
+        func,
+        args,
+        self_ty,
+    } = callee
+    else {
+        return None;
+    };
+    let (func, args) = match (&func.owner, self_ty) {
+        (FnOwner::Protocol(_), Some(Ty::Adt { name, .. })) => (
+            FnRef {
+                owner: FnOwner::Type(name.clone()),
+                name: func.name.clone(),
+            },
+            Vec::new(),
+        ),
+        (FnOwner::Protocol(_), _) => return None,
+        _ => (func.clone(), args.clone()),
+    };
+    let target = functions
+        .iter()
+        .find(|f| f.owner == Owner::Function(func.clone()))?;
+    if args.len() != target.generics.len() {
+        return None;
+    }
+    Some((target, args))
+}
+
+fn inlinable(callee: &Function) -> bool {
+    let Owner::Function(own) = &callee.owner else {
+        return false;
+    };
+    let insts = || callee.blocks.iter().flat_map(|block| &block.insts);
+    insts().count() <= INLINE_LIMIT
+        && insts().all(|inst| match &inst.kind {
+            // Lifted closures share the callee's generic parameters, which
+            // mean nothing in the caller.
+            InstKind::Closure { .. } => callee.generics.is_empty(),
+            InstKind::Call {
+                callee: Callee::Fn { func, .. },
+                ..
+            } => func != own,
+            InstKind::Asm(_) => false,
+            _ => true,
+        })
+}
+
+/// Appends the blocks of `callee` to `function`, renumbered past what the
+/// function has and with `return` jumping to `after`.
+fn copy_body(function: &mut Function, callee: &Function, ty_args: &[Ty], after: BlockId) {
+    let subst = |ty: &Ty| ty.subst(&callee.generics, ty_args);
+    let values = function.values.len() as u32;
+    let locals = function.locals.len() as u32;
+    let blocks = function.blocks.len() as u32;
+    function.values.extend(callee.values.iter().map(subst));
+    function.locals.extend(callee.locals.iter().map(|local| {
+        let mut local = local.clone();
+        local.ty = subst(&local.ty);
+        local
+    }));
+    let value = |id: ValueId| ValueId(id.0 + values);
+    let operand = |operand: &mut Operand| {
+        if let Operand::Value(id) = operand {
+            *id = value(*id);
+        }
+    };
+    for block in &callee.blocks {
+        let mut block = block.clone();
+        for param in &mut block.params {
+            *param = value(*param);
+        }
+        for inst in &mut block.insts {
+            inst.result = inst.result.map(value);
+            inst.kind.operands_mut().into_iter().for_each(operand);
+            if let Some(place) = inst.kind.place_mut()
+                && let PlaceBase::Local(local) = &mut place.base
+            {
+                *local = LocalId(local.0 + locals);
+            }
+            inst.kind.map_tys(&subst);
+        }
+        block.term.operands_mut().into_iter().for_each(operand);
+        for target in block.term.targets_mut() {
+            target.block = BlockId(target.block.0 + blocks);
+        }
+        if let Terminator::Return(result) = block.term {
+            // A call without a result never returns, so neither does this.
+            let args = match function.blocks[after.0 as usize].params[..] {
+                [] => Vec::new(),
+                _ => vec![result],
+            };
+            block.term = Terminator::Goto(Target { block: after, args });
+        }
+        function.blocks.push(block);
+    }
+}
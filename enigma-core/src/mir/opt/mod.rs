@@ -0,0 +1,155 @@
+//! Optimizations over the MIR, run as a pipeline of passes chosen by the
+//! optimization level. The program is verified after every pass that
+//! changes it, so a pass that breaks an invariant is named in the error
+//! rather than surfacing as a miscompile further down.
+//!
+//! Arithmetic on `int` and `byte` is checked: overflow and division by zero
+//! are runtime errors, as in the interpreter. Passes only fold what cannot
+//! fail and never remove an instruction that might.
+//!
+//! Volatile and atomic accesses, port I/O and fences are calls to builtins.
+//! No pass removes, duplicates or reorders calls, and locals whose address
+//! is taken aren't tracked, so every such access happens as written.
+
+mod cfg;
+mod dce;
+mod fold;
+mod inline;
+mod propagate;
+
+use super::{Function, Program, VerifyError, verify};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// No optimization: the MIR as lowered.
+    O0,
+    /// Cleanups within each function.
+    O1,
+    /// Also inlines small functions into their callers.
+    O2,
+}
+
+impl OptLevel {
+    /// Parses the digit of `-O0`, `-O1` or `-O2`.
+    pub fn parse(level: &str) -> Option<Self> {
+        match level {
+            "0" => Some(OptLevel::O0),
+            "1" => Some(OptLevel::O1),
+            "2" => Some(OptLevel::O2),
+            _ => None,
+        }
+    }
+}
+
+/// A transformation of the whole program, returning whether it changed
+/// anything.
+#[derive(Clone, Copy)]
+pub struct Pass {
+    pub name: &'static str,
+    pub run: fn(&mut Program) -> bool,
+}
+
+pub const SIMPLIFY_CFG: Pass = Pass {
+    name: "simplify-cfg",
+    run: |program| each_function(program, cfg::simplify),
+};
+pub const CONST_PROP: Pass = Pass {
+    name: "const-prop",
+    run: |program| each_function(program, propagate::constants),
+};
+pub const FOLD: Pass = Pass {
+    name: "fold",
+    run: |program| each_function(program, fold::fold),
+};
+pub const COPY_PROP: Pass = Pass {
+    name: "copy-prop",
+    run: |program| each_function(program, propagate::copies),
+};
+pub const DCE: Pass = Pass {
+    name: "dce",
+    run: |program| each_function(program, dce::eliminate),
+};
+pub const INLINE: Pass = Pass {
+    name: "inline",
+    run: inline::inline,
+};
+
+/// Cleanups within each function. Each pass can expose work for the
+/// others, such as a constant folded from propagated ones.
+pub const CLEANUP: &[Pass] = &[SIMPLIFY_CFG, CONST_PROP, FOLD, COPY_PROP, DCE, SIMPLIFY_CFG];
+
+/// How many times a group of passes is repeated at most while it still
+/// changes something.
+const MAX_ROUNDS: usize = 4;
+
+/// The groups of passes run at `level`, in order.
+pub fn pipeline(level: OptLevel) -> Vec<&'static [Pass]> {
+    match level {
+        OptLevel::O0 => Vec::new(),
+        OptLevel::O1 => vec![CLEANUP],
+        // Inlining exposes constant arguments to the callee's body, so the
+        // cleanups run again after it.
+        OptLevel::O2 => vec![CLEANUP, &[INLINE], CLEANUP],
+    }
+}
+
+/// A pass that left the program invalid.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PassError {
+    pub pass: &'static str,
+    pub error: VerifyError,
+}
+
+impl fmt::Display for PassError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "after `{}`: {}", self.pass, self.error)
+    }
+}
+
+/// Runs the pipeline of `level`, handing the program to `after` with the
+/// pass's name whenever a pass changes it.
+pub fn optimize(
+    program: &mut Program,
+    level: OptLevel,
+    after: &mut dyn FnMut(&'static str, &Program),
+) -> Result<(), PassError> {
+    for group in pipeline(level) {
+        for _ in 0..MAX_ROUNDS {
+            if !run_passes(program, group, after)? {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs `passes` once in order, verifying the program after each one that
+/// changes it. Returns whether any did.
+pub fn run_passes(
+    program: &mut Program,
+    passes: &[Pass],
+    after: &mut dyn FnMut(&'static str, &Program),
+) -> Result<bool, PassError> {
+    let mut changed = false;
+    for pass in passes {
+        if !(pass.run)(program) {
+            continue;
+        }
+        changed = true;
+        verify(program).map_err(|error| PassError {
+            pass: pass.name,
+            error,
+        })?;
+        after(pass.name, program);
+    }
+    Ok(changed)
+}
+
+fn each_function(program: &mut Program, pass: fn(&mut Function) -> bool) -> bool {
+    let mut changed = false;
+    for function in &mut program.functions {
+        changed |= pass(function);
+    }
+    changed
+}
@@ -0,0 +1,143 @@
+//! Constant and copy propagation through locals. A forward dataflow
+//! analysis tracks which operand each local is known to hold; a `copy` of
+//! the whole local where that is known is replaced by the operand itself.
+//!
+//! Only locals that are never borrowed are tracked, since a store through
+//! a reference could change them unseen. A local holding a value stops being
+//! known when the value's instruction runs again, as it does in a loop.
+
+use crate::mir::{Block, Function, InstKind, LocalId, Operand, PlaceBase, ValueId};
+use std::collections::{HashMap, HashSet};
+
+/// What each tracked local is known to hold at some point.
+type Facts = HashMap<LocalId, Operand>;
+
+/// Replaces reads of locals known to hold a constant.
+pub(super) fn constants(function: &mut Function) -> bool {
+    propagate(function, |operand| matches!(operand, Operand::Const(_)))
+}
+
+/// Replaces reads of locals known to hold an SSA value by the value.
+pub(super) fn copies(function: &mut Function) -> bool {
+    propagate(function, |operand| matches!(operand, Operand::Value(_)))
+}
+
+fn propagate(function: &mut Function, wanted: fn(&Operand) -> bool) -> bool {
+    let tracked = unborrowed_locals(function);
+    let entry_facts = analyze(function, &tracked);
+    let mut replaced: HashMap<ValueId, Operand> = HashMap::new();
+    for (block, facts) in function.blocks.iter_mut().zip(entry_facts) {
+        let Some(mut facts) = facts else {
+            continue;
+        };
+        kill_defined(&mut facts, &block.params);
+        block.insts.retain(|inst| {
+            if let (InstKind::Copy(place), Some(result)) = (&inst.kind, inst.result)
+                && place.projection.is_empty()
+                && let PlaceBase::Local(local) = place.base
+                && let Some(known) = facts.get(&local)
+                && wanted(known)
+            {
+                replaced.insert(result, known.clone());
+                return false;
+            }
+            step(&mut facts, &inst.kind, inst.result, &tracked);
+            true
+        });
+    }
+    function.replace_uses(&replaced);
+    !replaced.is_empty()
+}
+
+/// Locals that no `ref` or `raw_ref` points into.
+fn unborrowed_locals(function: &Function) -> HashSet<LocalId> {
+    let mut tracked: HashSet<LocalId> = (0..function.locals.len() as u32).map(LocalId).collect();
+    for inst in function.blocks.iter().flat_map(|block| &block.insts) {
+        if let InstKind::Ref { place, .. } | InstKind::RawRef { place, .. } = &inst.kind
+            && let PlaceBase::Local(local) = place.base
+        {
+            tracked.remove(&local);
+        }
+    }
+    tracked
+}
+
+/// The facts holding on entry to each block reachable from the entry.
+fn analyze(function: &Function, tracked: &HashSet<LocalId>) -> Vec<Option<Facts>> {
+    let order = function.reverse_postorder();
+    let preds = function.predecessors();
+    let mut entry: Vec<Option<Facts>> = vec![None; function.blocks.len()];
+    let mut exit: Vec<Option<Facts>> = vec![None; function.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &id in &order {
+            let index = id.0 as usize;
+            let facts = if index == 0 {
+                Some(Facts::new())
+            } else {
+                meet(
+                    preds[index]
+                        .iter()
+                        .filter_map(|p| exit[p.0 as usize].as_ref()),
+                )
+            };
+            let Some(facts) = facts else {
+                continue;
+            };
+            let out = transfer(facts.clone(), &function.blocks[index], tracked);
+            if entry[index].as_ref() != Some(&facts) || exit[index].as_ref() != Some(&out) {
+                entry[index] = Some(facts);
+                exit[index] = Some(out);
+                changed = true;
+            }
+        }
+    }
+    entry
+}
+
+/// The facts every one of `incoming` agrees on; `None` if there are none
+/// yet.
+fn meet<'f>(mut incoming: impl Iterator<Item = &'f Facts>) -> Option<Facts> {
+    let mut facts = incoming.next()?.clone();
+    for other in incoming {
+        facts.retain(|local, operand| other.get(local) == Some(operand));
+    }
+    Some(facts)
+}
+
+fn transfer(mut facts: Facts, block: &Block, tracked: &HashSet<LocalId>) -> Facts {
+    kill_defined(&mut facts, &block.params);
+    for inst in &block.insts {
+        step(&mut facts, &inst.kind, inst.result, tracked);
+    }
+    facts
+}
+
+fn step(facts: &mut Facts, kind: &InstKind, result: Option<ValueId>, tracked: &HashSet<LocalId>) {
+    if let Some(result) = result {
+        kill_defined(facts, &[result]);
+    }
+    let Some(place) = kind.place() else {
+        return;
+    };
+    let PlaceBase::Local(local) = place.base else {
+        return;
+    };
+    match kind {
+        InstKind::Store { value, .. }
+            if place.projection.is_empty() && tracked.contains(&local) =>
+        {
+            facts.insert(local, value.clone());
+        }
+        InstKind::Store { .. } | InstKind::Move(_) | InstKind::Drop(_) => {
+            facts.remove(&local);
+        }
+        _ => {}
+    }
+}
+
+/// Forgets locals holding values that are being defined anew.
+fn kill_defined(facts: &mut Facts, defined: &[ValueId]) {
+    facts.retain(|_, operand| !matches!(operand, Operand::Value(id) if defined.contains(id)));
+}
@@ -0,0 +1,299 @@
+//! A readable dump of the MIR, printed by `--emit=mir`. Locals are written
+//! `_index` with their source name in a comment, SSA values `%index`, and
+//! variants by name, which is why printing needs the whole program.
+
+use super::lower::fn_name;
+use super::*;
+use crate::checker::FnOwner;
+use std::fmt::{self, Write};
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (global, ty) in &self.globals {
+            writeln!(f, "global {}: {}", global, ty)?;
+        }
+        for (i, function) in self.functions.iter().enumerate() {
+            if i > 0 || !self.globals.is_empty() {
+                writeln!(f)?;
+            }
+            write!(f, "{}", self.display(function))?;
+        }
+        Ok(())
+    }
+}
+
+impl Program {
+    /// Prints one function of the program.
+    pub fn display<'a>(&'a self, function: &'a Function) -> impl fmt::Display + 'a {
+        FunctionDisplay {
+            program: self,
+            function,
+        }
+    }
+
+    fn variant_name(&self, union: &str, index: usize) -> &str {
+        match self.adts.get(union) {
+            Some(AdtDef::Union(def)) => def.variants.get(index).map_or("?", |(name, _)| name),
+            _ => "?",
+        }
+    }
+}
+
+struct FunctionDisplay<'a> {
+    program: &'a Program,
+    function: &'a Function,
+}
+
+impl fmt::Display for FunctionDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let function = self.function;
+        match &function.owner {
+            Owner::Init(module) if module.is_empty() => write!(f, "init")?,
+            Owner::Init(module) => write!(f, "init {}", module)?,
+            _ => {
+                write!(f, "{}@{}", function.attrs, function.name)?;
+                if !function.generics.is_empty() {
+                    write!(f, "[{}]", function.generics.join(", "))?;
+                }
+                let params: Vec<String> = function
+                    .params()
+                    .iter()
+                    .map(|&param| function.value_ty(param).to_string())
+                    .collect();
+                write!(f, "({})::{}", params.join(", "), function.ret)?;
+            }
+        }
+        writeln!(f, " {{")?;
+        for (i, local) in function.locals.iter().enumerate() {
+            let mutable = if local.mutable { "mut " } else { "" };
+            writeln!(f, "    {}_{}: {}  # {}", mutable, i, local.ty, local.name)?;
+        }
+        for (i, block) in function.blocks.iter().enumerate() {
+            if i > 0 || !function.locals.is_empty() {
+                writeln!(f)?;
+            }
+            write!(f, "  bb{}", i)?;
+            if !block.params.is_empty() {
+                let params: Vec<String> = block
+                    .params
+                    .iter()
+                    .map(|&param| format!("%{}: {}", param.0, function.value_ty(param)))
+                    .collect();
+                write!(f, "({})", params.join(", "))?;
+            }
+            writeln!(f, ":")?;
+            for inst in &block.insts {
+                writeln!(f, "    {}", self.inst(inst)?)?;
+            }
+            writeln!(f, "    {}", self.terminator(&block.term)?)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl FunctionDisplay<'_> {
+    fn inst(&self, inst: &Inst) -> Result<String, fmt::Error> {
+        let mut out = String::new();
+        if let Some(result) = inst.result {
+            write!(out, "%{}: {} = ", result.0, self.function.value_ty(result))?;
+        }
+        match &inst.kind {
+            InstKind::Unary { op, operand } => {
+                let symbol = if *op == UnaryOp::Neg { '-' } else { '!' };
+                write!(out, "{}{}", symbol, operand)?
+            }
+            InstKind::Binary { op, lhs, rhs } => write!(out, "{} {} {}", lhs, op.symbol(), rhs)?,
+            InstKind::Copy(place) => write!(out, "copy {}", self.place(place))?,
+            InstKind::Move(place) => write!(out, "move {}", self.place(place))?,
+            InstKind::Store { place, value } => write!(out, "{} = {}", self.place(place), value)?,
+            InstKind::Ref { mutable, place } => write!(
+                out,
+                "ref {}{}",
+                if *mutable { "mut " } else { "" },
+                self.place(place)
+            )?,
+            InstKind::RawRef { mutable, place } => write!(
+                out,
+                "raw_ref {}{}",
+                if *mutable { "mut " } else { "" },
+                self.place(place)
+            )?,
+            InstKind::Aggregate { kind, fields } => {
+                match kind {
+                    AggregateKind::Tuple => {}
+                    AggregateKind::Record(name) => out.push_str(name),
+                    AggregateKind::Variant { union, variant } => write!(
+                        out,
+                        "{}::{}",
+                        union,
+                        self.program.variant_name(union, *variant)
+                    )?,
+                }
+                if !fields.is_empty() || *kind == AggregateKind::Tuple {
+                    write!(out, "({})", list(fields))?;
+                }
+            }
+            InstKind::Discriminant(place) => write!(out, "discriminant {}", self.place(place))?,
+            InstKind::Call { callee, args } => {
+                out.push_str("call ");
+                match callee {
+                    Callee::Fn {
+                        func,
+                        args: ty_args,
+                        self_ty,
+                    } => {
+                        match (&func.owner, self_ty) {
+                            (FnOwner::Protocol(protocol), Some(self_ty)) => {
+                                write!(out, "<{} as {}>::{}", self_ty, protocol, func.name)?
+                            }
+                            _ => out.push_str(&fn_name(func)),
+                        }
+                        out.push_str(&ty_args_list(ty_args));
+                    }
+                    Callee::Builtin(name) => out.push_str(name),
+                    Callee::Extern { symbol, .. } => write!(out, "extern {}", symbol)?,
+                    Callee::Value(value) => write!(out, "{}", value)?,
+                }
+                write!(out, "({})", list(args))?;
+            }
+            InstKind::FnItem { func, args } => {
+                write!(out, "@{}{}", fn_name(func), ty_args_list(args))?
+            }
+            InstKind::VariantCtor { union, variant } => write!(
+                out,
+                "@{}::{}",
+                union,
+                self.program.variant_name(union, *variant)
+            )?,
+            InstKind::Closure { function, captures } => {
+                write!(out, "closure {}({})", function, list(captures))?
+            }
+            InstKind::ToDyn { value, protocol } => write!(out, "{} as ref {}", value, protocol)?,
+            InstKind::Drop(place) => write!(out, "drop {}", self.place(place))?,
+            InstKind::Asm(asm) => {
+                let mut parts: Vec<String> = asm
+                    .template
+                    .iter()
+                    .map(|line| {
+                        format!(
+                            "{:?}",
+                            line.iter().map(ToString::to_string).collect::<String>()
+                        )
+                    })
+                    .collect();
+                for operand in &asm.operands {
+                    let mut part = format!("{}({})", operand.dir, operand.reg);
+                    if let Some(input) = &operand.input {
+                        write!(part, " {}", input)?;
+                    }
+                    parts.push(part);
+                }
+                if !asm.clobbers.is_empty() {
+                    parts.push(format!("clobber({})", asm.clobbers.join(", ")));
+                }
+                let options = asm.options.names();
+                if !options.is_empty() {
+                    parts.push(format!("options({})", options.join(", ")));
+                }
+                write!(out, "asm {{ {} }}", parts.join(" "))?
+            }
+        }
+        Ok(out)
+    }
+
+    fn terminator(&self, term: &Terminator) -> Result<String, fmt::Error> {
+        let mut out = String::new();
+        match term {
+            Terminator::Goto(target) => write!(out, "goto {}", target)?,
+            Terminator::Branch {
+                cond,
+                then_target,
+                else_target,
+            } => write!(out, "branch {}, {}, {}", cond, then_target, else_target)?,
+            Terminator::Return(value) => write!(out, "return {}", value)?,
+            Terminator::Unreachable => out.push_str("unreachable"),
+        }
+        Ok(out)
+    }
+
+    fn place(&self, place: &Place) -> String {
+        let mut out = match &place.base {
+            PlaceBase::Local(id) => format!("_{}", id.0),
+            PlaceBase::Global(name) => name.clone(),
+        };
+        let mut ty = self.program.place_ty(
+            self.function,
+            &Place {
+                base: place.base.clone(),
+                projection: Vec::new(),
+            },
+        );
+        for (i, projection) in place.projection.iter().enumerate() {
+            match projection {
+                Projection::Deref => out = format!("(deref {})", out),
+                Projection::Field(index) => out = format!("{}.{}", out, index),
+                Projection::Downcast(index) => {
+                    let variant = match &ty {
+                        Ok(Ty::Adt { name, .. }) => self.program.variant_name(name, *index),
+                        _ => "?",
+                    };
+                    out = format!("({} as {})", out, variant);
+                }
+            }
+            let prefix = Place {
+                base: place.base.clone(),
+                projection: place.projection[..=i].to_vec(),
+            };
+            if !matches!(projection, Projection::Downcast(_)) {
+                ty = self.program.place_ty(self.function, &prefix);
+            }
+        }
+        out
+    }
+}
+
+fn list(operands: &[Operand]) -> String {
+    let operands: Vec<String> = operands.iter().map(Operand::to_string).collect();
+    operands.join(", ")
+}
+
+fn ty_args_list(args: &[Ty]) -> String {
+    if args.is_empty() {
+        return String::new();
+    }
+    let args: Vec<String> = args.iter().map(Ty::to_string).collect();
+    format!("[{}]", args.join(", "))
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bb{}", self.block.0)?;
+        if !self.args.is_empty() {
+            write!(f, "({})", list(&self.args))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Value(id) => write!(f, "%{}", id.0),
+            Operand::Const(constant) => write!(f, "{}", constant),
+        }
+    }
+}
+
+impl fmt::Display for Const {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Const::Int(n) => write!(f, "{}", n),
+            Const::Byte(n) => write!(f, "{}b", n),
+            Const::Float(x) => write!(f, "{:?}", x),
+            Const::Bool(b) => write!(f, "{}", b),
+            Const::Char(c) => write!(f, "{:?}", c),
+            Const::Str(s) => write!(f, "{:?}", s),
+            Const::Unit => write!(f, "()"),
+        }
+    }
+}
@@ -0,0 +1,549 @@
+//! Checks that a MIR program is well formed: every value is defined once
+//! and its definition dominates each use, jumps go to blocks that exist with
+//! the arguments they expect, and every instruction's operands and result
+//! have consistent types. Lowering and every pass must leave a program that
+//! passes, so a failure points at the phase that just ran.
+
+use super::*;
+use crate::checker::FnOwner;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    pub function: String,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid MIR in `{}`: {}", self.function, self.message)
+    }
+}
+
+pub fn verify(program: &Program) -> Result<(), VerifyError> {
+    for function in &program.functions {
+        Verifier::new(program, function)
+            .verify()
+            .map_err(|message| VerifyError {
+                function: function.name.clone(),
+                message,
+            })?;
+    }
+    Ok(())
+}
+
+/// Where a value is defined: its block and position, block parameters
+/// coming before the first instruction.
+#[derive(Clone, Copy)]
+struct Def {
+    block: usize,
+    position: usize,
+}
+
+struct Verifier<'a> {
+    program: &'a Program,
+    function: &'a Function,
+    defs: Vec<Option<Def>>,
+    /// The immediate dominator of each reachable block; the entry is its
+    /// own.
+    idom: Vec<Option<usize>>,
+}
+
+/// A check's outcome, with what is wrong on failure.
+type Check<T = ()> = Result<T, String>;
+
+impl<'a> Verifier<'a> {
+    fn new(program: &'a Program, function: &'a Function) -> Self {
+        Self {
+            program,
+            function,
+            defs: vec![None; function.values.len()],
+            idom: Vec::new(),
+        }
+    }
+
+    fn verify(mut self) -> Check {
+        if self.function.blocks.is_empty() {
+            return Err("the function has no blocks".to_string());
+        }
+        self.collect_defs()?;
+        self.idom = dominators(self.function);
+        for (b, block) in self.function.blocks.iter().enumerate() {
+            for (i, inst) in block.insts.iter().enumerate() {
+                self.inst(inst, b, i + 1)
+                    .map_err(|message| format!("bb{}: {}", b, message))?;
+            }
+            self.terminator(&block.term, b, block.insts.len() + 1)
+                .map_err(|message| format!("bb{}: {}", b, message))?;
+        }
+        Ok(())
+    }
+
+    fn collect_defs(&mut self) -> Check {
+        for (b, block) in self.function.blocks.iter().enumerate() {
+            let results = block.insts.iter().enumerate().filter_map(|(i, inst)| {
+                let result = inst.result?;
+                Some((result, i + 1))
+            });
+            let params = block.params.iter().map(|&param| (param, 0));
+            for (value, position) in params.chain(results) {
+                let Some(def) = self.defs.get_mut(value.0 as usize) else {
+                    return Err(format!("`%{}` has no type", value.0));
+                };
+                if def.is_some() {
+                    return Err(format!("`%{}` is defined more than once", value.0));
+                }
+                *def = Some(Def { block: b, position });
+            }
+        }
+        Ok(())
+    }
+
+    /// The type of an operand used at `position` in block `block`.
+    fn operand(&self, operand: &Operand, block: usize, position: usize) -> Check<Ty> {
+        let Operand::Value(value) = operand else {
+            return Ok(self.function.operand_ty(operand));
+        };
+        let Some(Some(def)) = self.defs.get(value.0 as usize) else {
+            return Err(format!("`%{}` is never defined", value.0));
+        };
+        let dominated = if def.block == block {
+            def.position < position
+        } else {
+            self.dominates(def.block, block)
+        };
+        // Uses in unreachable blocks can't run, whatever they refer to.
+        if !dominated && self.idom[block].is_some() {
+            return Err(format!(
+                "`%{}` is used where its definition doesn't dominate",
+                value.0
+            ));
+        }
+        Ok(self.function.value_ty(*value).clone())
+    }
+
+    fn operands(&self, operands: &[Operand], block: usize, position: usize) -> Check<Vec<Ty>> {
+        operands
+            .iter()
+            .map(|operand| self.operand(operand, block, position))
+            .collect()
+    }
+
+    fn dominates(&self, a: usize, mut b: usize) -> bool {
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.idom[b] {
+                Some(idom) if idom != b => b = idom,
+                _ => return false,
+            }
+        }
+    }
+
+    fn place(&self, place: &Place) -> Check<Ty> {
+        self.program.place_ty(self.function, place)
+    }
+
+    fn inst(&self, inst: &Inst, block: usize, position: usize) -> Check {
+        let result = inst.result.map(|id| self.function.value_ty(id).clone());
+        let produced = match &inst.kind {
+            InstKind::Unary { operand, .. } => Some(self.operand(operand, block, position)?),
+            InstKind::Binary { op, lhs, rhs } => {
+                let lhs = self.operand(lhs, block, position)?;
+                let rhs = self.operand(rhs, block, position)?;
+                if lhs != rhs {
+                    return Err(format!(
+                        "`{}` applied to `{}` and `{}`",
+                        op.symbol(),
+                        lhs,
+                        rhs
+                    ));
+                }
+                Some(match op {
+                    BinOp::And | BinOp::Or => {
+                        return Err(format!("`{}` must be lowered to branches", op.symbol()));
+                    }
+                    _ if op.is_comparison() => Ty::Bool,
+                    _ => lhs,
+                })
+            }
+            InstKind::Copy(place) | InstKind::Move(place) => Some(self.place(place)?),
+            InstKind::Store { place, value } => {
+                let target = self.place(place)?;
+                let value = self.operand(value, block, position)?;
+                expect_assignable(&value, &target, "store")?;
+                None
+            }
+            InstKind::Ref { mutable, place } | InstKind::RawRef { mutable, place } => {
+                let inner = Box::new(self.place(place)?);
+                let mutable = *mutable;
+                Some(match &inst.kind {
+                    InstKind::Ref { .. } => Ty::Ref { mutable, inner },
+                    _ => Ty::RawRef { mutable, inner },
+                })
+            }
+            InstKind::Aggregate { kind, fields } => {
+                let fields = self.operands(fields, block, position)?;
+                let result = result.as_ref().ok_or("an aggregate without a result")?;
+                self.aggregate(kind, &fields, result)?;
+                None
+            }
+            InstKind::Discriminant(place) => match self.place(place)? {
+                Ty::Adt { name, .. }
+                    if matches!(self.program.adts.get(&name), Some(AdtDef::Union(_))) =>
+                {
+                    Some(Ty::Int)
+                }
+                ty => return Err(format!("discriminant of `{}`, which isn't a union", ty)),
+            },
+            InstKind::Call { callee, args } => {
+                let args = self.operands(args, block, position)?;
+                self.call(callee, &args, result.as_ref(), block, position)?;
+                None
+            }
+            InstKind::FnItem { .. } | InstKind::VariantCtor { .. } => match &result {
+                Some(Ty::Fn { .. }) => None,
+                _ => return Err("a function value must have a function type".to_string()),
+            },
+            InstKind::Closure { function, captures } => {
+                let captures = self.operands(captures, block, position)?;
+                self.closure(function, captures, result.as_ref())?;
+                None
+            }
+            InstKind::ToDyn { value, protocol } => {
+                match self.operand(value, block, position)? {
+                    Ty::Ref { .. } => {}
+                    ty => return Err(format!("`{}` converted to a protocol object", ty)),
+                }
+                match &result {
+                    Some(Ty::Ref { inner, .. }) if **inner == Ty::Dyn(protocol.clone()) => None,
+                    _ => {
+                        return Err(format!(
+                            "a `{}` object must be a `ref {}`",
+                            protocol, protocol
+                        ));
+                    }
+                }
+            }
+            InstKind::Drop(place) => {
+                self.place(place)?;
+                None
+            }
+            InstKind::Asm(asm) => {
+                for input in asm.inputs() {
+                    self.operand(input, block, position)?;
+                }
+                let outputs = asm.operands.iter().filter(|o| o.dir.writes()).count();
+                match (outputs, &result) {
+                    (0, _) => None,
+                    (1, Some(ty)) => Some(ty.clone()),
+                    (n, Some(Ty::Tuple(elems))) if elems.len() == n => result.clone(),
+                    (n, Some(ty)) => {
+                        return Err(format!("assembly with {} outputs produces `{}`", n, ty));
+                    }
+                    // Reported as a missing result.
+                    (_, None) => Some(Ty::Unit),
+                }
+            }
+        };
+        match (produced, result) {
+            (Some(produced), Some(result)) if !assignable(&produced, &result) => Err(format!(
+                "`%{}` has type `{}` but its instruction produces `{}`",
+                inst.result.unwrap().0,
+                result,
+                produced
+            )),
+            (Some(_), None) => Err("the instruction's result is missing".to_string()),
+            (None, Some(_))
+                if matches!(
+                    inst.kind,
+                    InstKind::Store { .. } | InstKind::Drop(_) | InstKind::Asm(_)
+                ) =>
+            {
+                Err("the instruction has no result".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn aggregate(&self, kind: &AggregateKind, fields: &[Ty], result: &Ty) -> Check {
+        let expected: Vec<Ty> = match (kind, result) {
+            (AggregateKind::Tuple, Ty::Tuple(elems)) => elems.clone(),
+            (AggregateKind::Record(name), Ty::Adt { name: ty, args }) if name == ty => {
+                match self.program.adts.get(name) {
+                    Some(AdtDef::Record(def)) => def
+                        .fields
+                        .iter()
+                        .map(|(_, field)| field.subst(&def.generics, args))
+                        .collect(),
+                    _ => return Err(format!("`{}` isn't a record", name)),
+                }
+            }
+            (AggregateKind::Variant { union, variant }, Ty::Adt { name, args })
+                if union == name =>
+            {
+                match self.program.adts.get(union) {
+                    Some(AdtDef::Union(def)) => def
+                        .variants
+                        .get(*variant)
+                        .ok_or_else(|| format!("`{}` has no variant {}", union, variant))?
+                        .1
+                        .iter()
+                        .map(|field| field.subst(&def.generics, args))
+                        .collect(),
+                    _ => return Err(format!("`{}` isn't a union", union)),
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "an aggregate of type `{}` built as {:?}",
+                    result, kind
+                ));
+            }
+        };
+        if fields.len() != expected.len() {
+            return Err(format!(
+                "`{}` has {} fields but {} were given",
+                result,
+                expected.len(),
+                fields.len()
+            ));
+        }
+        for (field, expected) in fields.iter().zip(&expected) {
+            expect_assignable(field, expected, "field")?;
+        }
+        Ok(())
+    }
+
+    fn call(
+        &self,
+        callee: &Callee,
+        args: &[Ty],
+        result: Option<&Ty>,
+        block: usize,
+        position: usize,
+    ) -> Check {
+        let (params, ret) = match callee {
+            Callee::Fn {
+                func,
+                args: ty_args,
+                self_ty,
+            } => {
+                // Protocol methods are checked against the implementation
+                // they run, when it is known and not generic.
+                let (func, generic) = match (&func.owner, self_ty) {
+                    (FnOwner::Protocol(_), Some(Ty::Adt { name, .. })) => (
+                        FnRef {
+                            owner: FnOwner::Type(name.clone()),
+                            name: func.name.clone(),
+                        },
+                        false,
+                    ),
+                    (FnOwner::Protocol(_), _) => return Ok(()),
+                    _ => (func.clone(), true),
+                };
+                let Some(target) = self
+                    .program
+                    .functions
+                    .iter()
+                    .find(|f| f.owner == Owner::Function(func.clone()))
+                else {
+                    // Functions of modules lowered separately.
+                    return Ok(());
+                };
+                if !generic && !target.generics.is_empty() {
+                    return Ok(());
+                }
+                let subst = |ty: &Ty| ty.subst(&target.generics, ty_args);
+                let params: Vec<Ty> = target
+                    .params()
+                    .iter()
+                    .map(|&param| subst(target.value_ty(param)))
+                    .collect();
+                (params, subst(&target.ret))
+            }
+            Callee::Builtin(_) | Callee::Extern { .. } => return Ok(()),
+            Callee::Value(value) => match self.operand(value, block, position)? {
+                Ty::Fn { params, ret } => (params, *ret),
+                ty => return Err(format!("call through `{}`, which isn't a function", ty)),
+            },
+        };
+        if args.len() != params.len() {
+            return Err(format!(
+                "a call passing {} arguments to a function taking {}",
+                args.len(),
+                params.len()
+            ));
+        }
+        for (arg, param) in args.iter().zip(&params) {
+            expect_assignable(arg, param, "argument")?;
+        }
+        match result {
+            Some(_) if ret == Ty::Never => {
+                Err("a call that never returns has a result".to_string())
+            }
+            Some(result) if !assignable(&ret, result) => Err(format!(
+                "a call returning `{}` has a result of type `{}`",
+                ret, result
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn closure(&self, name: &str, captures: Vec<Ty>, result: Option<&Ty>) -> Check {
+        let Some(closure) = self.program.function(name) else {
+            return Err(format!("no closure `{}`", name));
+        };
+        let mut params: Vec<Ty> = closure
+            .params()
+            .iter()
+            .map(|&param| closure.value_ty(param).clone())
+            .collect();
+        if params.is_empty() || params.remove(0) != Ty::Tuple(captures) {
+            return Err(format!("`{}` is given the wrong captures", name));
+        }
+        let ty = Ty::Fn {
+            params,
+            ret: Box::new(closure.ret.clone()),
+        };
+        match result {
+            Some(result) if *result == ty => Ok(()),
+            _ => Err(format!("the closure `{}` must have type `{}`", name, ty)),
+        }
+    }
+
+    fn terminator(&self, term: &Terminator, block: usize, position: usize) -> Check {
+        match term {
+            Terminator::Goto(target) => self.target(target, block, position),
+            Terminator::Branch {
+                cond,
+                then_target,
+                else_target,
+            } => {
+                let cond = self.operand(cond, block, position)?;
+                if cond != Ty::Bool {
+                    return Err(format!("branch on `{}`", cond));
+                }
+                self.target(then_target, block, position)?;
+                self.target(else_target, block, position)
+            }
+            Terminator::Return(value) => {
+                let value = self.operand(value, block, position)?;
+                expect_assignable(&value, &self.function.ret, "return value")
+            }
+            Terminator::Unreachable => Ok(()),
+        }
+    }
+
+    fn target(&self, target: &Target, block: usize, position: usize) -> Check {
+        let index = target.block.0 as usize;
+        if index == 0 {
+            return Err("jump to the entry block".to_string());
+        }
+        let Some(to) = self.function.blocks.get(index) else {
+            return Err(format!("jump to missing block bb{}", index));
+        };
+        let args = self.operands(&target.args, block, position)?;
+        if args.len() != to.params.len() {
+            return Err(format!(
+                "bb{} takes {} arguments but {} were given",
+                index,
+                to.params.len(),
+                args.len()
+            ));
+        }
+        for (arg, &param) in args.iter().zip(&to.params) {
+            expect_assignable(arg, self.function.value_ty(param), "block argument")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a value of type `from` may be stored where `to` is expected:
+/// `ref mut T` is also a `ref T`.
+fn assignable(from: &Ty, to: &Ty) -> bool {
+    match (from, to) {
+        (
+            Ty::Ref {
+                mutable: true,
+                inner: from,
+            },
+            Ty::Ref {
+                mutable: false,
+                inner: to,
+            },
+        ) => from == to,
+        _ => from == to,
+    }
+}
+
+fn expect_assignable(from: &Ty, to: &Ty, what: &str) -> Check {
+    if assignable(from, to) {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} of type `{}` where `{}` is expected",
+            what, from, to
+        ))
+    }
+}
+
+/// Immediate dominators of the blocks reachable from the entry, by the
+/// iterative algorithm of Cooper, Harvey and Kennedy.
+fn dominators(function: &Function) -> Vec<Option<usize>> {
+    let n = function.blocks.len();
+    let order: Vec<usize> = function
+        .reverse_postorder()
+        .into_iter()
+        .map(|block| block.0 as usize)
+        .collect();
+    let mut rpo = vec![usize::MAX; n];
+    for (i, &block) in order.iter().enumerate() {
+        rpo[block] = i;
+    }
+    let mut preds = vec![Vec::new(); n];
+    for &block in &order {
+        for succ in function.blocks[block].successors() {
+            if (succ.0 as usize) < n {
+                preds[succ.0 as usize].push(block);
+            }
+        }
+    }
+
+    let mut idom = vec![None; n];
+    idom[0] = Some(0);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in order.iter().skip(1) {
+            let mut new = None;
+            for &pred in &preds[block] {
+                if idom[pred].is_none() {
+                    continue;
+                }
+                new = Some(match new {
+                    None => pred,
+                    Some(other) => intersect(&idom, &rpo, pred, other),
+                });
+            }
+            if new.is_some() && idom[block] != new {
+                idom[block] = new;
+                changed = true;
+            }
+        }
+    }
+    idom
+}
+
+fn intersect(idom: &[Option<usize>], rpo: &[usize], mut a: usize, mut b: usize) -> usize {
+    while a != b {
+        while rpo[a] > rpo[b] {
+            a = idom[a].unwrap();
+        }
+        while rpo[b] > rpo[a] {
+            b = idom[b].unwrap();
+        }
+    }
+    a
+}
@@ -0,0 +1,342 @@
+//! Monomorphization: finds every function instance a checked program needs.
+//!
+//! Starting from the top level statements and every non-generic function
+//! outside the prelude, the collector follows the calls recorded by the type
+//! checker, substituting the caller's generic arguments into each callee's. A call through a protocol
+//! method is resolved to the receiver type's implementation once the receiver
+//! is concrete. Each distinct instance is collected once, so `id[int]` called
+//! from ten places is still one instance.
+//!
+//! Converting a `ref human` into a `ref live` protocol object needs the
+//! vtable of `human` for `live`, so every such conversion adds a `Vtable` and
+//! the methods filling it.
+//!
+//! A generic function that calls itself with ever larger arguments
+//! (`f[T]` calling `f[Option[T]]`) would need infinitely many instances, so
+//! instantiation chains deeper than `RECURSION_LIMIT` are reported instead.
+
+use crate::checker::items::{FnSig, ItemTable};
+use crate::checker::types::Ty;
+use crate::checker::{Callee, Coercion, FnOwner, FnRef, TypeckResults};
+use crate::errorhandler::{Diagnostic, ErrorHandler};
+use crate::lexer::size::Span;
+use crate::prelude;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// How many generic instantiations may be nested inside one another.
+pub const RECURSION_LIMIT: usize = 64;
+
+/// A function together with the generic arguments it is compiled for.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Instance {
+    pub func: FnRef,
+    /// The impl's generic arguments followed by the function's own.
+    pub args: Vec<Ty>,
+}
+
+impl fmt::Display for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.func.owner {
+            FnOwner::Free => write!(f, "{}", self.func.name)?,
+            FnOwner::Type(ty) | FnOwner::Protocol(ty) => write!(f, "{}::{}", ty, self.func.name)?,
+        }
+        if !self.args.is_empty() {
+            let args: Vec<String> = self.args.iter().map(Ty::to_string).collect();
+            write!(f, "[{}]", args.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Entries at the start of every vtable, before the protocol's methods.
+pub const VTABLE_HEADER: [&str; 3] = ["drop", "size", "align"];
+
+/// The vtable of `ty` for `protocol`. A `ref protocol` is a pair of
+/// pointers, one to the value and one to its vtable. The vtable holds a
+/// pointer-sized slot for each entry of `VTABLE_HEADER`, followed by one
+/// for each of the protocol's methods in declaration order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vtable {
+    pub ty: Ty,
+    pub protocol: String,
+    /// The implementations filling the method slots.
+    pub methods: Vec<Instance>,
+}
+
+/// The slot holding `method` in any vtable for `protocol`.
+pub fn vtable_slot(items: &ItemTable, protocol: &str, method: &str) -> Option<usize> {
+    let index = items
+        .protocols
+        .get(protocol)?
+        .iter()
+        .position(|sig| sig.name == method)?;
+    Some(VTABLE_HEADER.len() + index)
+}
+
+/// Every instance the program needs, in the order they were discovered.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MonoItems {
+    pub instances: Vec<Instance>,
+    pub vtables: Vec<Vtable>,
+}
+
+impl MonoItems {
+    pub fn contains(&self, instance: &Instance) -> bool {
+        self.instances.contains(instance)
+    }
+}
+
+struct Collector<'a> {
+    tables: Vec<&'a ItemTable>,
+    /// Recorded calls, grouped by the function containing them.
+    calls: HashMap<Option<FnRef>, Vec<&'a Callee>>,
+    coercions: HashMap<Option<FnRef>, Vec<&'a Coercion>>,
+    seen: HashSet<Instance>,
+    items: MonoItems,
+}
+
+impl<'a> Collector<'a> {
+    fn sig(&self, func: &FnRef) -> Option<&'a FnSig> {
+        self.tables.iter().find_map(|table| match &func.owner {
+            FnOwner::Free => table.functions.get(&func.name),
+            FnOwner::Type(ty) => table.method(ty, &func.name),
+            FnOwner::Protocol(_) => None,
+        })
+    }
+
+    /// Non-generic functions and methods, which are compiled whether or not
+    /// anything calls them. The prelude's are only compiled once called.
+    fn roots(&self) -> Vec<FnRef> {
+        let mut roots = Vec::new();
+        for table in &self.tables {
+            if table.module == prelude::MODULE {
+                continue;
+            }
+            for (name, sig) in &table.functions {
+                if sig.generics.is_empty() && sig.module == table.module {
+                    roots.push(FnRef {
+                        owner: FnOwner::Free,
+                        name: name.clone(),
+                    });
+                }
+            }
+            for (ty, methods) in &table.methods {
+                for (name, sig) in methods {
+                    if sig.impl_generics.is_empty()
+                        && sig.generics.is_empty()
+                        && sig.module == table.module
+                    {
+                        roots.push(FnRef {
+                            owner: FnOwner::Type(ty.clone()),
+                            name: name.clone(),
+                        });
+                    }
+                }
+            }
+        }
+        // Map iteration order is arbitrary; keep the output stable.
+        roots.sort_by(|a, b| a.name.cmp(&b.name));
+        roots
+    }
+
+    /// The instance a call inside `caller` (instantiated with `args`)
+    /// refers to, or `None` if it can't be pinned down, which only happens
+    /// after an error was already reported.
+    fn instantiate(&self, callee: &Callee, names: &[String], args: &[Ty]) -> Option<Instance> {
+        let callee_args: Vec<Ty> = callee.args.iter().map(|t| t.subst(names, args)).collect();
+        let instance = match &callee.func.owner {
+            FnOwner::Protocol(protocol) => {
+                let mut self_ty = callee.self_ty.as_ref()?.subst(names, args);
+                while let Ty::Ref { inner, .. } = self_ty {
+                    self_ty = *inner;
+                }
+                let Ty::Adt {
+                    name,
+                    args: type_args,
+                } = self_ty
+                else {
+                    return None;
+                };
+                // The protocol's own generic arguments pick the impl, but
+                // aren't parameters of the method found.
+                let protocol_generics = self
+                    .tables
+                    .iter()
+                    .find_map(|table| table.protocol_generics.get(protocol))
+                    .map_or(0, Vec::len);
+                let mut all = type_args;
+                all.extend(callee_args.into_iter().skip(protocol_generics));
+                Instance {
+                    func: FnRef {
+                        owner: FnOwner::Type(name),
+                        name: callee.func.name.clone(),
+                    },
+                    args: all,
+                }
+            }
+            _ => Instance {
+                func: callee.func.clone(),
+                args: callee_args,
+            },
+        };
+        instance.args.iter().all(is_concrete).then_some(instance)
+    }
+
+    fn collect(&mut self, handler: &mut ErrorHandler) {
+        // Instances still to scan, with how deeply they are nested in other
+        // generic instances. `None` is the top level.
+        let mut worklist: Vec<(Option<Instance>, usize)> = vec![(None, 0)];
+        for func in self.roots() {
+            let instance = Instance {
+                func,
+                args: Vec::new(),
+            };
+            if self.seen.insert(instance.clone()) {
+                self.items.instances.push(instance.clone());
+                worklist.push((Some(instance), 0));
+            }
+        }
+        worklist.reverse();
+
+        while let Some((instance, depth)) = worklist.pop() {
+            let caller = instance.as_ref().map(|i| i.func.clone());
+            let (names, args) = match &instance {
+                Some(instance) => match self.sig(&instance.func) {
+                    Some(sig) => {
+                        let mut names = sig.impl_generics.clone();
+                        names.extend(sig.generics.iter().cloned());
+                        (names, instance.args.clone())
+                    }
+                    None => continue,
+                },
+                None => (Vec::new(), Vec::new()),
+            };
+            let mut needed: Vec<(Instance, Span)> = Vec::new();
+            for callee in self.calls.get(&caller).into_iter().flatten() {
+                if let Some(next) = self.instantiate(callee, &names, &args) {
+                    needed.push((next, callee.span));
+                }
+            }
+            let coercions = self.coercions.get(&caller).cloned().unwrap_or_default();
+            for coercion in coercions {
+                let ty = coercion.ty.subst(&names, &args);
+                for method in self.vtable(ty, &coercion.protocol) {
+                    needed.push((method, coercion.span));
+                }
+            }
+            for (next, span) in needed {
+                if self.seen.contains(&next) {
+                    continue;
+                }
+                let next_depth = if next.args.is_empty() { 0 } else { depth + 1 };
+                if next_depth > RECURSION_LIMIT {
+                    handler.emit(
+                        Diagnostic::error(
+                            format!("reached the recursion limit while instantiating `{}`", next),
+                            span,
+                        )
+                        .with_note(format!(
+                            "each instance calls `{}` with larger generic arguments, \
+                             so instantiating it never ends",
+                            next.func.name
+                        ))
+                        .with_note(format!(
+                            "generic instances may nest at most {} deep",
+                            RECURSION_LIMIT
+                        )),
+                    );
+                    return;
+                }
+                self.seen.insert(next.clone());
+                self.items.instances.push(next.clone());
+                worklist.push((Some(next), next_depth));
+            }
+        }
+    }
+
+    /// Adds the vtable of `ty` for `protocol` unless it exists already, and
+    /// returns the methods it needs.
+    fn vtable(&mut self, ty: Ty, protocol: &str) -> Vec<Instance> {
+        let Ty::Adt { name, args } = &ty else {
+            return Vec::new();
+        };
+        let exists = self
+            .items
+            .vtables
+            .iter()
+            .any(|vtable| vtable.ty == ty && vtable.protocol == protocol);
+        if exists || !is_concrete(&ty) {
+            return Vec::new();
+        }
+        let Some(sigs) = self
+            .tables
+            .iter()
+            .find_map(|table| table.protocols.get(protocol))
+        else {
+            return Vec::new();
+        };
+        let methods: Vec<Instance> = sigs
+            .iter()
+            .map(|sig| Instance {
+                func: FnRef {
+                    owner: FnOwner::Type(name.clone()),
+                    name: sig.name.clone(),
+                },
+                args: args.clone(),
+            })
+            .collect();
+        self.items.vtables.push(Vtable {
+            ty: ty.clone(),
+            protocol: protocol.to_string(),
+            methods: methods.clone(),
+        });
+        methods
+    }
+}
+
+/// Collects the instances needed by a checked program, given the item tables
+/// and results of all of its modules.
+pub fn collect(checked: &[(ItemTable, TypeckResults)], handler: &mut ErrorHandler) -> MonoItems {
+    let mut calls: HashMap<Option<FnRef>, Vec<&Callee>> = HashMap::new();
+    for (_, results) in checked {
+        for callee in results.callees.values() {
+            calls.entry(callee.caller.clone()).or_default().push(callee);
+        }
+    }
+    for callees in calls.values_mut() {
+        callees.sort_by_key(|callee| callee.span.start);
+    }
+    let mut coercions: HashMap<Option<FnRef>, Vec<&Coercion>> = HashMap::new();
+    for (_, results) in checked {
+        for coercion in results.coercions.values() {
+            coercions
+                .entry(coercion.caller.clone())
+                .or_default()
+                .push(coercion);
+        }
+    }
+    for list in coercions.values_mut() {
+        list.sort_by_key(|coercion| coercion.span.start);
+    }
+    let mut collector = Collector {
+        tables: checked.iter().map(|(items, _)| items).collect(),
+        calls,
+        coercions,
+        seen: HashSet::new(),
+        items: MonoItems::default(),
+    };
+    collector.collect(handler);
+    collector.items
+}
+
+fn is_concrete(ty: &Ty) -> bool {
+    match ty {
+        Ty::Param(_) | Ty::Infer(_) | Ty::Error => false,
+        Ty::Tuple(elems) => elems.iter().all(is_concrete),
+        Ty::Ref { inner, .. } | Ty::RawRef { inner, .. } => is_concrete(inner),
+        Ty::Adt { args, .. } => args.iter().all(is_concrete),
+        Ty::Fn { params, ret } => params.iter().all(is_concrete) && is_concrete(ret),
+        _ => true,
+    }
+}
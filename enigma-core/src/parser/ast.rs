@@ -0,0 +1,673 @@
+use crate::lexer::size::Span;
+use crate::lexer::tokens::Literal;
+
+/// Every expression, block and pattern gets a unique id so later passes can
+/// attach information (resolutions, types) without mutating the tree.
+pub type NodeId = u32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ident {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub items: Vec<Item>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub kind: ItemKind,
+    pub is_pub: bool,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ItemKind {
+    Function(Function),
+    Record(Record),
+    Union(Union),
+    Protocol(Protocol),
+    Impl(Impl),
+    /// Top level statements: globals and script style code.
+    Stmt(Stmt),
+    Import(Import),
+}
+
+/// `get module a.b as x`; without `as` the module is named by its last segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    pub path: Vec<Ident>,
+    pub alias: Option<Ident>,
+}
+
+impl Import {
+    pub fn dotted(&self) -> String {
+        self.path
+            .iter()
+            .map(|segment| segment.name.as_str())
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    /// The name the module is referred to by in the importing file.
+    pub fn name(&self) -> &Ident {
+        self.alias
+            .as_ref()
+            .unwrap_or_else(|| self.path.last().unwrap())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenericParam {
+    pub name: Ident,
+    pub bounds: Vec<Ident>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfKind {
+    Value,
+    MutValue,
+    Ref,
+    RefMut,
+}
+
+impl SelfKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SelfKind::Value => "self",
+            SelfKind::MutValue => "mut self",
+            SelfKind::Ref => "ref self",
+            SelfKind::RefMut => "ref mut self",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfParam {
+    pub kind: SelfKind,
+    pub span: Span,
+}
+
+/// `type label%name` - `label` is what callers use, `name` is the binding
+/// inside the body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub id: NodeId,
+    pub mutable: bool,
+    pub ty: TypeExpr,
+    pub label: Option<Ident>,
+    pub name: Ident,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    /// `#[..]` attributes written before the function.
+    pub attrs: Vec<Attribute>,
+    /// `extern "C" @name(..)`: the function follows the platform's C calling
+    /// convention under its unmangled name. Without a body it's a declaration
+    /// of a symbol defined by a linked object.
+    pub abi: Option<Abi>,
+    /// `unsafe @name(..)`: callers must be inside `unsafe { }`.
+    pub is_unsafe: bool,
+    pub name: Ident,
+    pub generics: Vec<GenericParam>,
+    pub self_param: Option<SelfParam>,
+    pub params: Vec<Param>,
+    /// A trailing `...` in an `extern` declaration: callers may pass any
+    /// number of extra arguments.
+    pub variadic: bool,
+    pub ret: Option<TypeExpr>,
+    /// `None` for protocol method signatures and `extern` declarations.
+    pub body: Option<FnBody>,
+    pub span: Span,
+}
+
+/// The `"C"` in `extern "C"`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Abi {
+    pub name: String,
+    pub span: Span,
+}
+
+/// `#[name]` or `#[name(args)]`, e.g. `#[section(".boot")]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub name: Ident,
+    pub args: Vec<AttrArg>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttrArg {
+    Ident(Ident),
+    Str(String, Span),
+    Int(usize, Span),
+}
+
+impl AttrArg {
+    pub fn span(&self) -> Span {
+        match self {
+            AttrArg::Ident(ident) => ident.span,
+            AttrArg::Str(_, span) | AttrArg::Int(_, span) => *span,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FnBody {
+    Block(Block),
+    /// `-> expr;`
+    Inline(Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: Ident,
+    pub ty: TypeExpr,
+    /// The width of a bitfield, as in `present: bool : 1`.
+    pub bits: Option<(usize, Span)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub attrs: Vec<Attribute>,
+    pub name: Ident,
+    pub generics: Vec<GenericParam>,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub name: Ident,
+    pub fields: Vec<TypeExpr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Union {
+    pub name: Ident,
+    pub generics: Vec<GenericParam>,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Protocol {
+    pub name: Ident,
+    /// `protoc into_error[E]`: implementations choose the arguments.
+    pub generics: Vec<GenericParam>,
+    pub methods: Vec<Function>,
+}
+
+/// `implement Target { .. }` or `implement protoc for Target { .. }`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Impl {
+    /// `unsafe implement send for Target {}` vouches for what the checker
+    /// can't prove.
+    pub is_unsafe: bool,
+    pub protocol: Option<Ident>,
+    /// Arguments of a generic protocol: `implement into_error[io] for parse`.
+    pub protocol_args: Vec<TypeExpr>,
+    pub target: TypeExpr,
+    pub methods: Vec<ImplMethod>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImplMethod {
+    pub is_pub: bool,
+    pub function: Function,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeExpr {
+    pub kind: TypeExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeExprKind {
+    /// `int`, `human`, `Option[int]`, `io::File`
+    Named {
+        path: Vec<Ident>,
+        args: Vec<TypeExpr>,
+    },
+    Tuple(Vec<TypeExpr>),
+    Ref {
+        mutable: bool,
+        inner: Box<TypeExpr>,
+    },
+    RawRef {
+        mutable: bool,
+        inner: Box<TypeExpr>,
+    },
+    /// `@(int, string)::bool`; without `::` the function returns `unit`.
+    Fn {
+        params: Vec<TypeExpr>,
+        ret: Option<Box<TypeExpr>>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub id: NodeId,
+    pub stmts: Vec<Stmt>,
+    pub span: Span,
+}
+
+impl Block {
+    /// The trailing expression that gives the block its value, if any.
+    pub fn tail(&self) -> Option<&Expr> {
+        match self.stmts.last() {
+            Some(Stmt {
+                kind: StmtKind::Expr(expr),
+                ..
+            }) => Some(expr),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+    /// A `;` written after the statement. `expr;` is shorthand for
+    /// `return expr` and is already desugared into a `Return` expression; on
+    /// any other statement the `;` is an error reported by the checker.
+    pub semi: Option<Span>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssignOp {
+    Set,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+    pub id: NodeId,
+    pub mutable: bool,
+    pub ty: TypeExpr,
+    pub name: Ident,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    /// `mut int x := 4`
+    Let {
+        binding: Binding,
+        init: Expr,
+    },
+    /// `(int a, string b) $= x`
+    Destructure {
+        bindings: Vec<Binding>,
+        init: Expr,
+    },
+    /// `x = y`, `x += y`
+    Assign {
+        target: Expr,
+        op: AssignOp,
+        value: Expr,
+    },
+    /// `x++` / `x--`
+    Step {
+        target: Expr,
+        increment: bool,
+    },
+    Expr(Expr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    BitAnd,
+    BitOr,
+    BitXor,
+    And,
+    Or,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+impl BinOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Rem => "%",
+            BinOp::BitAnd => "&",
+            BinOp::BitOr => "|",
+            BinOp::BitXor => "^",
+            BinOp::And => "&&",
+            BinOp::Or => "||",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Lt => "<",
+            BinOp::Gt => ">",
+            BinOp::Le => "<=",
+            BinOp::Ge => ">=",
+        }
+    }
+
+    pub fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge
+        )
+    }
+
+    pub fn is_logical(self) -> bool {
+        matches!(self, BinOp::And | BinOp::Or)
+    }
+}
+
+/// A call argument, optionally using the parameter's call-site label.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arg {
+    pub label: Option<Ident>,
+    pub value: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldInit {
+    pub name: Ident,
+    pub value: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arm {
+    pub pattern: Pattern,
+    pub body: Expr,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    pub id: NodeId,
+    pub kind: PatternKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatternKind {
+    Wildcard,
+    Literal(Literal),
+    Binding(Ident),
+    /// `Option::None`, `Option::Some(x)`
+    Variant {
+        path: Vec<Ident>,
+        fields: Vec<Pattern>,
+    },
+    Tuple(Vec<Pattern>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub id: NodeId,
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Literal(Literal),
+    /// `x`, `human::new`, `Option::Some`, `self::health`
+    Path(Vec<Ident>),
+    Tuple(Vec<Expr>),
+    Unary {
+        op: UnaryOp,
+        expr: Box<Expr>,
+    },
+    Binary {
+        op: BinOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Range {
+        start: Box<Expr>,
+        end: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        generics: Vec<TypeExpr>,
+        args: Vec<Arg>,
+    },
+    /// `expr::name` where `expr` is not a plain path.
+    Field {
+        base: Box<Expr>,
+        name: Ident,
+    },
+    RecordLit {
+        path: Vec<Ident>,
+        generics: Vec<TypeExpr>,
+        fields: Vec<FieldInit>,
+    },
+    Block(Block),
+    If {
+        cond: Box<Expr>,
+        then_block: Block,
+        /// Either a `Block` or a nested `If` expression.
+        else_branch: Option<Box<Expr>>,
+    },
+    While {
+        cond: Box<Expr>,
+        body: Block,
+    },
+    For {
+        binding: Ident,
+        binding_id: NodeId,
+        iter: Box<Expr>,
+        body: Block,
+    },
+    Loop(Block),
+    Match {
+        scrutinee: Box<Expr>,
+        arms: Vec<Arm>,
+    },
+    Break(Option<Box<Expr>>),
+    Continue,
+    Return(Option<Box<Expr>>),
+    Ref {
+        mutable: bool,
+        expr: Box<Expr>,
+    },
+    Deref(Box<Expr>),
+    RawRef {
+        mutable: bool,
+        expr: Box<Expr>,
+    },
+    Try(Box<Expr>),
+    Unsafe(Block),
+    /// `asm { "mov {0}, 1" out(reg) x }`
+    Asm(Box<AsmBlock>),
+    /// `@(int a, b) -> a + b` or `@(x)::int { .. }`
+    Closure(Closure),
+}
+
+/// A closure parameter; its type may be left for inference.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClosureParam {
+    pub id: NodeId,
+    pub mutable: bool,
+    pub ty: Option<TypeExpr>,
+    pub name: Ident,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Closure {
+    pub params: Vec<ClosureParam>,
+    pub ret: Option<TypeExpr>,
+    pub body: Box<Expr>,
+}
+
+/// An inline assembly block: template lines followed by operands, clobbers
+/// and options.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmBlock {
+    pub template: Vec<Vec<AsmPiece>>,
+    pub operands: Vec<AsmOperand>,
+    pub clobbers: Vec<Ident>,
+    pub options: AsmOptions,
+}
+
+/// A piece of a template line. Operand references are resolved to indices
+/// by the time the block reaches the HIR.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsmPiece {
+    Text(String),
+    /// `{0}`, `{name}` or `{name:d}`
+    Operand {
+        operand: AsmRef,
+        modifier: Option<char>,
+        span: Span,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsmRef {
+    Index(usize),
+    Name(String),
+}
+
+/// `name = in(reg) expr`; outputs take a place expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmOperand {
+    pub name: Option<Ident>,
+    pub dir: AsmDir,
+    pub reg: AsmReg,
+    pub reg_span: Span,
+    pub expr: Expr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsmDir {
+    In,
+    Out,
+    InOut,
+}
+
+impl AsmDir {
+    pub fn reads(self) -> bool {
+        self != AsmDir::Out
+    }
+
+    pub fn writes(self) -> bool {
+        self != AsmDir::In
+    }
+}
+
+/// A register constraint: any general purpose register, any SSE register
+/// or a named one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmReg {
+    Gpr,
+    Xmm,
+    Explicit(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AsmOptions {
+    pub volatile: bool,
+    pub noreturn: bool,
+}
+
+impl AsmOptions {
+    /// The options as written in `options(..)`.
+    pub fn names(self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.volatile {
+            names.push("volatile");
+        }
+        if self.noreturn {
+            names.push("noreturn");
+        }
+        names
+    }
+}
+
+/// A register an operand or clobber can name, in encoding order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhysReg {
+    Gpr(u8),
+    Xmm(u8),
+}
+
+impl PhysReg {
+    pub const GPRS: [&'static str; 16] = [
+        "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12",
+        "r13", "r14", "r15",
+    ];
+
+    pub fn parse(name: &str) -> Option<PhysReg> {
+        if let Some(i) = Self::GPRS.iter().position(|&gpr| gpr == name) {
+            return Some(PhysReg::Gpr(i as u8));
+        }
+        let n: u8 = name.strip_prefix("xmm")?.parse().ok()?;
+        (n < 16 && name.len() == if n < 10 { 4 } else { 5 }).then_some(PhysReg::Xmm(n))
+    }
+
+    /// `rsp` and `rbp` hold the frame and can't be handed to assembly.
+    pub fn is_frame(self) -> bool {
+        matches!(self, PhysReg::Gpr(4 | 5))
+    }
+}
+
+impl std::fmt::Display for AsmPiece {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AsmPiece::Text(text) => write!(f, "{}", text.replace('{', "{{").replace('}', "}}")),
+            AsmPiece::Operand {
+                operand, modifier, ..
+            } => {
+                match operand {
+                    AsmRef::Index(i) => write!(f, "{{{}", i)?,
+                    AsmRef::Name(name) => write!(f, "{{{}", name)?,
+                }
+                if let Some(modifier) = modifier {
+                    write!(f, ":{}", modifier)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for AsmDir {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AsmDir::In => "in",
+            AsmDir::Out => "out",
+            AsmDir::InOut => "inout",
+        })
+    }
+}
+
+impl std::fmt::Display for AsmReg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AsmReg::Gpr => f.write_str("reg"),
+            AsmReg::Xmm => f.write_str("xreg"),
+            AsmReg::Explicit(name) => f.write_str(name),
+        }
+    }
+}
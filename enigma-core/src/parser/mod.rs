@@ -1,10 +1,2105 @@
-use crate::lexer::tokens::{Token};
-pub struct Parser{
+pub mod ast;
 
+use crate::errorhandler::{Diagnostic, ErrorHandler};
+use crate::lexer::Lexer;
+use crate::lexer::size::Span;
+use crate::lexer::tokens::{Literal, Token, TokenType};
+use ast::*;
+
+type PResult<T> = Result<T, Diagnostic>;
+
+// Recursive descent parser over the full token stream. The language has no
+// statement terminators, so a few decisions (calls, record literals) look at
+// the source to see whether a newline separates two tokens.
+pub struct Parser<'p> {
+    source: &'p str,
+    /// Offset of `source` in the `SourceMap`; every span is shifted by it so
+    /// spans from different files never overlap.
+    base: usize,
+    tokens: Vec<Token>,
+    pos: usize,
+    next_id: NodeId,
+    prev_end: usize,
+    /// Problems that don't stop parsing, reported with the rest at the end.
+    diagnostics: Vec<Diagnostic>,
 }
 
-impl Parser{
-    pub fn new(token_stream:&[Token]){
-        // Process token_stream
+impl<'p> Parser<'p> {
+    pub fn new(source: &'p str) -> Self {
+        Self::with_offset(source, 0, 0)
+    }
+
+    /// A parser for one file of a multi-file program: spans start at `base`
+    /// and node ids at `first_id`.
+    pub fn with_offset(source: &'p str, base: usize, first_id: NodeId) -> Self {
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer
+            .by_ref()
+            .map(|mut token| {
+                token.size.start += base;
+                token
+            })
+            .collect();
+        let diagnostics = lexer
+            .take_diagnostics()
+            .into_iter()
+            .map(|mut diagnostic| {
+                diagnostic.span =
+                    Span::new(diagnostic.span.start + base, diagnostic.span.end + base);
+                diagnostic
+            })
+            .collect();
+        Self {
+            source,
+            base,
+            tokens,
+            pos: 0,
+            next_id: first_id,
+            prev_end: base,
+            diagnostics,
+        }
+    }
+
+    /// The first node id not used by this parser.
+    pub fn next_id(&self) -> NodeId {
+        self.next_id
+    }
+
+    pub fn parse_program(&mut self, handler: &mut ErrorHandler) -> Program {
+        let mut items = Vec::new();
+        while !self.at_eof() {
+            if self.skip_stray_semis() {
+                continue;
+            }
+            if self.at(&TokenType::Extern) && self.peek_nth(2) == &TokenType::LCurly {
+                match self.parse_extern_block() {
+                    Ok(block) => items.extend(block),
+                    Err(diagnostic) => {
+                        handler.emit(diagnostic);
+                        self.recover_to_item();
+                    }
+                }
+                continue;
+            }
+            match self.parse_item() {
+                Ok(item) => items.push(item),
+                Err(diagnostic) => {
+                    handler.emit(diagnostic);
+                    self.recover_to_item();
+                }
+            }
+        }
+        for diagnostic in self.diagnostics.drain(..) {
+            handler.emit(diagnostic);
+        }
+        Program { items }
+    }
+
+    /// Skips `;` tokens that don't follow anything, e.g. `;;` or `record r {};`.
+    fn skip_stray_semis(&mut self) -> bool {
+        let mut skipped = false;
+        while self.at(&TokenType::ReturnSemi) {
+            let span = self.current_span();
+            self.diagnostics.push(stray_semi(span));
+            self.advance();
+            skipped = true;
+        }
+        skipped
+    }
+
+    // ---------------------------------------------------------------------
+    // Token helpers
+    // ---------------------------------------------------------------------
+
+    fn peek(&self) -> &TokenType {
+        self.peek_nth(0)
+    }
+
+    fn peek_nth(&self, n: usize) -> &TokenType {
+        self.tokens
+            .get(self.pos + n)
+            .map_or(&TokenType::Eof, |t| &t.token_type)
+    }
+
+    fn at(&self, token_type: &TokenType) -> bool {
+        self.peek() == token_type
+    }
+
+    fn at_eof(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn current_span(&self) -> Span {
+        match self.tokens.get(self.pos) {
+            Some(token) => Span::from(&token.size),
+            None => Span::new(self.end(), self.end()),
+        }
+    }
+
+    fn advance(&mut self) -> Token {
+        let token =
+            self.tokens
+                .get(self.pos)
+                .cloned()
+                .unwrap_or(Token::new(self.end(), 0, TokenType::Eof));
+        if self.pos < self.tokens.len() {
+            self.prev_end = token.size.start + token.size.end;
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, token_type: &TokenType) -> bool {
+        if self.at(token_type) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token_type: TokenType, context: &str) -> PResult<Token> {
+        if self.at(&token_type) {
+            Ok(self.advance())
+        } else {
+            let expected = if context.is_empty() {
+                token_type.to_string()
+            } else {
+                format!("{} {}", token_type, context)
+            };
+            Err(self.unexpected(&expected))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> Diagnostic {
+        Diagnostic::error(
+            format!("expected {}, found {}", expected, self.peek()),
+            self.current_span(),
+        )
+    }
+
+    fn expect_ident(&mut self, context: &str) -> PResult<Ident> {
+        if self.at(&TokenType::Identifier) {
+            let token = self.advance();
+            Ok(self.ident_from(&token))
+        } else {
+            Err(self.unexpected(&format!("identifier {}", context)))
+        }
+    }
+
+    fn end(&self) -> usize {
+        self.base + self.source.len()
+    }
+
+    fn text(&self, span: Span) -> &'p str {
+        &self.source[span.start - self.base..span.end - self.base]
+    }
+
+    fn ident_from(&self, token: &Token) -> Ident {
+        let span = Span::from(&token.size);
+        Ident {
+            name: self.text(span).to_string(),
+            span,
+        }
+    }
+
+    /// True when the current token starts on a later line than the previous one.
+    fn newline_before(&self) -> bool {
+        let start = self.current_span().start;
+        self.prev_end <= start && self.text(Span::new(self.prev_end, start)).contains('\n')
+    }
+
+    fn span_from(&self, start: Span) -> Span {
+        Span::new(start.start, self.prev_end.max(start.end))
+    }
+
+    fn fresh_id(&mut self) -> NodeId {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn mk_expr(&mut self, kind: ExprKind, span: Span) -> Expr {
+        Expr {
+            id: self.fresh_id(),
+            kind,
+            span,
+        }
     }
-}
\ No newline at end of file
+
+    fn recover_to_item(&mut self) {
+        let mut depth = 0usize;
+        let start = self.pos;
+        while !self.at_eof() {
+            match self.peek() {
+                TokenType::LCurly => depth += 1,
+                TokenType::RCurly => depth = depth.saturating_sub(1),
+                TokenType::Func
+                | TokenType::HashSquare
+                | TokenType::Record
+                | TokenType::Union
+                | TokenType::Protoc
+                | TokenType::Impl
+                | TokenType::Get
+                | TokenType::Pub
+                | TokenType::Extern
+                    if depth == 0 && self.pos > start =>
+                {
+                    return;
+                }
+                _ => {}
+            }
+            self.advance();
+        }
+    }
+
+    // ---------------------------------------------------------------------
+    // Items
+    // ---------------------------------------------------------------------
+
+    fn parse_item(&mut self) -> PResult<Item> {
+        let start = self.current_span();
+        let attrs = self.parse_attributes()?;
+        let is_pub = self.eat(&TokenType::Pub);
+        let kind = match self.peek() {
+            // `@(..)` starts a closure or a function type, not an item.
+            TokenType::Func if self.peek_nth(1) != &TokenType::LParen => {
+                ItemKind::Function(self.parse_function(attrs, None, true)?)
+            }
+            TokenType::Unsafe if self.peek_nth(1) == &TokenType::Func => {
+                ItemKind::Function(self.parse_function(attrs, None, true)?)
+            }
+            TokenType::Extern => ItemKind::Function(self.parse_function(attrs, None, true)?),
+            TokenType::Unsafe if self.peek_nth(1) == &TokenType::Impl => {
+                ItemKind::Impl(self.parse_impl()?)
+            }
+            TokenType::Record => ItemKind::Record(self.parse_record(attrs)?),
+            _ if !attrs.is_empty() => {
+                return Err(Diagnostic::error(
+                    "attributes can only be applied to functions and records",
+                    attrs[0].span,
+                ));
+            }
+            TokenType::Union => ItemKind::Union(self.parse_union()?),
+            TokenType::Protoc => ItemKind::Protocol(self.parse_protocol()?),
+            TokenType::Impl => ItemKind::Impl(self.parse_impl()?),
+            TokenType::Get => ItemKind::Import(self.parse_import()?),
+            _ => ItemKind::Stmt(self.parse_stmt()?),
+        };
+        Ok(Item {
+            kind,
+            is_pub,
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_import(&mut self) -> PResult<Import> {
+        self.expect(TokenType::Get, "")?;
+        self.expect(TokenType::Module, "after `get`")?;
+        let mut path = vec![self.expect_ident("for a module path")?];
+        while self.eat(&TokenType::Dot) {
+            path.push(self.expect_ident("after `.` in a module path")?);
+        }
+        let alias = if self.eat(&TokenType::As) {
+            Some(self.expect_ident("after `as`")?)
+        } else {
+            None
+        };
+        Ok(Import { path, alias })
+    }
+
+    fn parse_generic_params(&mut self) -> PResult<Vec<GenericParam>> {
+        let mut params = Vec::new();
+        if !self.eat(&TokenType::LSquare) {
+            return Ok(params);
+        }
+        while !self.at(&TokenType::RSquare) {
+            let name = self.expect_ident("for a generic parameter")?;
+            let mut bounds = Vec::new();
+            if self.eat(&TokenType::Colon) {
+                bounds.push(self.expect_ident("for a protocol bound")?);
+                while self.eat(&TokenType::Plus) {
+                    bounds.push(self.expect_ident("for a protocol bound")?);
+                }
+            }
+            params.push(GenericParam { name, bounds });
+            if !self.eat(&TokenType::Comma) {
+                break;
+            }
+        }
+        self.expect(TokenType::RSquare, "to close the generic parameters")?;
+        Ok(params)
+    }
+
+    /// Parses the `#[name]` and `#[name(args)]` attributes before an item.
+    /// Arguments are identifiers, strings or integers.
+    fn parse_attributes(&mut self) -> PResult<Vec<Attribute>> {
+        let mut attrs = Vec::new();
+        while self.at(&TokenType::HashSquare) {
+            let start = self.current_span();
+            self.advance();
+            let name = self.expect_ident("for an attribute name")?;
+            let mut args = Vec::new();
+            if self.eat(&TokenType::LParen) {
+                while !self.at(&TokenType::RParen) {
+                    let span = self.current_span();
+                    let arg = match self.peek().clone() {
+                        TokenType::Identifier => AttrArg::Ident(self.expect_ident("")?),
+                        TokenType::Literal(Literal::Str(text)) => {
+                            self.advance();
+                            AttrArg::Str(text, span)
+                        }
+                        TokenType::Literal(Literal::Int(n)) => {
+                            self.advance();
+                            AttrArg::Int(n, span)
+                        }
+                        _ => return Err(self.unexpected("an identifier, string or integer")),
+                    };
+                    args.push(arg);
+                    if !self.eat(&TokenType::Comma) {
+                        break;
+                    }
+                }
+                self.expect(TokenType::RParen, "to close the attribute arguments")?;
+            }
+            self.expect(TokenType::RSquare, "to close the attribute")?;
+            attrs.push(Attribute {
+                name,
+                args,
+                span: self.span_from(start),
+            });
+        }
+        Ok(attrs)
+    }
+
+    /// Parses `extern "C" { .. }`: declarations written like protocol
+    /// signatures, each of which becomes its own `extern "C"` function item.
+    fn parse_extern_block(&mut self) -> PResult<Vec<Item>> {
+        let abi = self.parse_abi()?;
+        self.expect(TokenType::LCurly, "to open the `extern` block")?;
+        let mut items = Vec::new();
+        while !self.at(&TokenType::RCurly) && !self.at_eof() {
+            let start = self.current_span();
+            let attrs = self.parse_attributes()?;
+            let is_pub = self.eat(&TokenType::Pub);
+            if self.at(&TokenType::Extern) {
+                return Err(Diagnostic::error(
+                    "functions in an `extern` block already use its ABI",
+                    self.current_span(),
+                ));
+            }
+            let function = self.parse_function(attrs, Some(abi.clone()), false)?;
+            if let Some(body) = &function.body {
+                let span = match body {
+                    FnBody::Block(block) => block.span,
+                    FnBody::Inline(expr) => expr.span,
+                };
+                return Err(Diagnostic::error(
+                    "functions in an `extern` block can't have a body",
+                    span,
+                ));
+            }
+            items.push(Item {
+                kind: ItemKind::Function(function),
+                is_pub,
+                span: self.span_from(start),
+            });
+        }
+        self.expect(TokenType::RCurly, "to close the `extern` block")?;
+        Ok(items)
+    }
+
+    /// Parses `extern "name"`.
+    fn parse_abi(&mut self) -> PResult<Abi> {
+        self.expect(TokenType::Extern, "")?;
+        let span = self.current_span();
+        match self.peek().clone() {
+            TokenType::Literal(Literal::Str(name)) => {
+                self.advance();
+                Ok(Abi { name, span })
+            }
+            _ => Err(self.unexpected("an ABI string like \"C\" after `extern`")),
+        }
+    }
+
+    /// Parses `@name[T](params)::ret` followed by a body, optionally marked
+    /// `extern "C"` and `unsafe`, after its attributes. `abi` is that of an
+    /// enclosing `extern` block. Protocol signatures (`require_body ==
+    /// false`) and `extern` declarations may omit the body.
+    fn parse_function(
+        &mut self,
+        attrs: Vec<Attribute>,
+        abi: Option<Abi>,
+        require_body: bool,
+    ) -> PResult<Function> {
+        let start = attrs
+            .first()
+            .map_or_else(|| self.current_span(), |attr| attr.span);
+        let abi = match abi {
+            None if self.at(&TokenType::Extern) => Some(self.parse_abi()?),
+            abi => abi,
+        };
+        let is_unsafe = self.eat(&TokenType::Unsafe);
+        self.expect(TokenType::Func, "to start a function")?;
+        let name = self.expect_ident("after `@`")?;
+        let generics = self.parse_generic_params()?;
+        self.expect(TokenType::LParen, "to open the parameter list")?;
+
+        let mut self_param = None;
+        let mut params = Vec::new();
+        let mut variadic = None;
+        while !self.at(&TokenType::RParen) {
+            if self.at(&TokenType::Ellipsis) {
+                variadic = Some(self.advance());
+                break;
+            }
+            if let Some(param) = self.parse_self_param() {
+                if self_param.is_some() || !params.is_empty() {
+                    return Err(Diagnostic::error(
+                        "`self` must be the first parameter",
+                        param.span,
+                    ));
+                }
+                self_param = Some(param);
+            } else {
+                params.push(self.parse_param()?);
+            }
+            if !self.eat(&TokenType::Comma) {
+                break;
+            }
+        }
+        self.expect(TokenType::RParen, "to close the parameter list")?;
+
+        let ret = if self.eat(&TokenType::DoubleColon) {
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+
+        let body = match self.peek() {
+            TokenType::LCurly => Some(FnBody::Block(self.parse_block()?)),
+            TokenType::Arrow => {
+                self.advance();
+                let expr = self.parse_expr()?;
+                let semi = self.expect(TokenType::ReturnSemi, "to end the inline function")?;
+                let body = self.mk_return(expr, Span::from(&semi.size));
+                Some(FnBody::Inline(Box::new(body)))
+            }
+            _ if require_body && abi.is_none() => {
+                return Err(self.unexpected("`{` or `->` to start the function body"));
+            }
+            _ => None,
+        };
+
+        if let Some(token) = &variadic
+            && (abi.is_none() || body.is_some())
+        {
+            return Err(Diagnostic::error(
+                "only `extern` declarations can take variadic arguments",
+                Span::from(&token.size),
+            ));
+        }
+
+        Ok(Function {
+            attrs,
+            abi,
+            is_unsafe,
+            name,
+            generics,
+            self_param,
+            params,
+            variadic: variadic.is_some(),
+            ret,
+            body,
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_self_param(&mut self) -> Option<SelfParam> {
+        let (kind, len) = match (self.peek(), self.peek_nth(1)) {
+            (TokenType::Mut, _) => (SelfKind::MutValue, 2),
+            (TokenType::Ref, TokenType::Mut) => (SelfKind::RefMut, 3),
+            (TokenType::Ref, _) => (SelfKind::Ref, 2),
+            _ => (SelfKind::Value, 1),
+        };
+        let token = self.tokens.get(self.pos + len - 1)?;
+        let span = Span::from(&token.size);
+        if token.token_type != TokenType::Identifier || self.text(span) != "self" {
+            return None;
+        }
+        let start = self.current_span();
+        for _ in 0..len {
+            self.advance();
+        }
+        Some(SelfParam {
+            kind,
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_param(&mut self) -> PResult<Param> {
+        let start = self.current_span();
+        let mutable = self.eat(&TokenType::Mut);
+        let ty = self.parse_type()?;
+        let first = self.expect_ident("for the parameter name")?;
+        let (label, name) = if self.eat(&TokenType::Percent) {
+            (Some(first), self.expect_ident("after `%`")?)
+        } else {
+            (None, first)
+        };
+        Ok(Param {
+            id: self.fresh_id(),
+            mutable,
+            ty,
+            label,
+            name,
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_record(&mut self, attrs: Vec<Attribute>) -> PResult<Record> {
+        self.expect(TokenType::Record, "")?;
+        let name = self.expect_ident("for the record name")?;
+        let generics = self.parse_generic_params()?;
+        self.expect(TokenType::LCurly, "to open the record body")?;
+        let mut fields = Vec::new();
+        while !self.at(&TokenType::RCurly) && !self.at_eof() {
+            let name = self.expect_ident("for a field name")?;
+            self.expect(TokenType::Colon, "after the field name")?;
+            let ty = self.parse_type()?;
+            let bits = if self.eat(&TokenType::Colon) {
+                let span = self.current_span();
+                match self.peek().clone() {
+                    TokenType::Literal(Literal::Int(width)) => {
+                        self.advance();
+                        Some((width, span))
+                    }
+                    _ => return Err(self.unexpected("a bitfield width")),
+                }
+            } else {
+                None
+            };
+            fields.push(Field { name, ty, bits });
+            self.eat(&TokenType::Comma);
+        }
+        self.expect(TokenType::RCurly, "to close the record body")?;
+        Ok(Record {
+            attrs,
+            name,
+            generics,
+            fields,
+        })
+    }
+
+    fn parse_union(&mut self) -> PResult<Union> {
+        self.expect(TokenType::Union, "")?;
+        let name = self.expect_ident("for the union name")?;
+        let generics = self.parse_generic_params()?;
+        self.expect(TokenType::LCurly, "to open the union body")?;
+        let mut variants = Vec::new();
+        while !self.at(&TokenType::RCurly) && !self.at_eof() {
+            let name = self.expect_ident("for a variant name")?;
+            let mut fields = Vec::new();
+            if self.eat(&TokenType::LParen) {
+                while !self.at(&TokenType::RParen) {
+                    fields.push(self.parse_type()?);
+                    if !self.eat(&TokenType::Comma) {
+                        break;
+                    }
+                }
+                self.expect(TokenType::RParen, "to close the variant fields")?;
+            }
+            variants.push(Variant { name, fields });
+            self.eat(&TokenType::Comma);
+        }
+        self.expect(TokenType::RCurly, "to close the union body")?;
+        Ok(Union {
+            name,
+            generics,
+            variants,
+        })
+    }
+
+    fn parse_protocol(&mut self) -> PResult<Protocol> {
+        self.expect(TokenType::Protoc, "")?;
+        let name = self.expect_ident("for the protocol name")?;
+        let generics = self.parse_generic_params()?;
+        self.expect(TokenType::LCurly, "to open the protocol body")?;
+        let mut methods = Vec::new();
+        while !self.at(&TokenType::RCurly) && !self.at_eof() {
+            methods.push(self.parse_function(Vec::new(), None, false)?);
+        }
+        self.expect(TokenType::RCurly, "to close the protocol body")?;
+        Ok(Protocol {
+            name,
+            generics,
+            methods,
+        })
+    }
+
+    fn parse_impl(&mut self) -> PResult<Impl> {
+        let is_unsafe = self.eat(&TokenType::Unsafe);
+        self.expect(TokenType::Impl, "")?;
+        let first = self.parse_type()?;
+        let mut protocol_args = Vec::new();
+        let (protocol, target) = if self.eat(&TokenType::For) {
+            let protocol = match first.kind {
+                // A protocol from another module is written `alias::protocol`
+                // and looked up by its joined path, like types are.
+                TypeExprKind::Named { path, args } => {
+                    protocol_args = args;
+                    Some(Ident {
+                        name: path
+                            .iter()
+                            .map(|segment| segment.name.as_str())
+                            .collect::<Vec<_>>()
+                            .join("::"),
+                        span: path[0].span.to(path.last().unwrap().span),
+                    })
+                }
+                _ => {
+                    return Err(Diagnostic::error(
+                        "expected a protocol name before `for`",
+                        first.span,
+                    ));
+                }
+            };
+            (protocol, self.parse_type()?)
+        } else {
+            (None, first)
+        };
+        self.expect(TokenType::LCurly, "to open the implementation body")?;
+        let mut methods = Vec::new();
+        while !self.at(&TokenType::RCurly) && !self.at_eof() {
+            let attrs = self.parse_attributes()?;
+            let is_pub = self.eat(&TokenType::Pub);
+            methods.push(ImplMethod {
+                is_pub,
+                function: self.parse_function(attrs, None, true)?,
+            });
+        }
+        self.expect(TokenType::RCurly, "to close the implementation body")?;
+        Ok(Impl {
+            is_unsafe,
+            protocol,
+            protocol_args,
+            target,
+            methods,
+        })
+    }
+
+    // ---------------------------------------------------------------------
+    // Types
+    // ---------------------------------------------------------------------
+
+    fn parse_type(&mut self) -> PResult<TypeExpr> {
+        let start = self.current_span();
+        let kind = match self.peek() {
+            TokenType::Ref | TokenType::Ampersand => {
+                self.advance();
+                let mutable = self.eat(&TokenType::Mut);
+                TypeExprKind::Ref {
+                    mutable,
+                    inner: Box::new(self.parse_type()?),
+                }
+            }
+            TokenType::RawRef => {
+                self.advance();
+                let mutable = self.eat(&TokenType::Mut);
+                TypeExprKind::RawRef {
+                    mutable,
+                    inner: Box::new(self.parse_type()?),
+                }
+            }
+            TokenType::LParen => {
+                self.advance();
+                let mut elems = Vec::new();
+                while !self.at(&TokenType::RParen) {
+                    elems.push(self.parse_type()?);
+                    if !self.eat(&TokenType::Comma) {
+                        break;
+                    }
+                }
+                self.expect(TokenType::RParen, "to close the tuple type")?;
+                TypeExprKind::Tuple(elems)
+            }
+            TokenType::Func => {
+                self.advance();
+                self.expect(TokenType::LParen, "after `@` in a function type")?;
+                let mut params = Vec::new();
+                while !self.at(&TokenType::RParen) {
+                    params.push(self.parse_type()?);
+                    if !self.eat(&TokenType::Comma) {
+                        break;
+                    }
+                }
+                self.expect(TokenType::RParen, "to close the parameter types")?;
+                let ret = if self.eat(&TokenType::DoubleColon) {
+                    Some(Box::new(self.parse_type()?))
+                } else {
+                    None
+                };
+                TypeExprKind::Fn { params, ret }
+            }
+            TokenType::Identifier => {
+                let mut path = vec![self.expect_ident("for a type")?];
+                while self.at(&TokenType::DoubleColon) && self.peek_nth(1) == &TokenType::Identifier
+                {
+                    self.advance();
+                    path.push(self.expect_ident("in the type path")?);
+                }
+                let args = self.parse_type_args()?;
+                TypeExprKind::Named { path, args }
+            }
+            _ => return Err(self.unexpected("a type")),
+        };
+        Ok(TypeExpr {
+            kind,
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_type_args(&mut self) -> PResult<Vec<TypeExpr>> {
+        let mut args = Vec::new();
+        if self.at(&TokenType::LSquare) && !self.newline_before() {
+            self.advance();
+            while !self.at(&TokenType::RSquare) {
+                args.push(self.parse_type()?);
+                if !self.eat(&TokenType::Comma) {
+                    break;
+                }
+            }
+            self.expect(TokenType::RSquare, "to close the type arguments")?;
+        }
+        Ok(args)
+    }
+
+    // ---------------------------------------------------------------------
+    // Statements
+    // ---------------------------------------------------------------------
+
+    fn parse_block(&mut self) -> PResult<Block> {
+        let start = Span::from(&self.expect(TokenType::LCurly, "to open a block")?.size);
+        let mut stmts = Vec::new();
+        while !self.at(&TokenType::RCurly) && !self.at_eof() {
+            if !self.skip_stray_semis() {
+                stmts.push(self.parse_stmt()?);
+            }
+        }
+        self.expect(TokenType::RCurly, "to close the block")?;
+        Ok(Block {
+            id: self.fresh_id(),
+            stmts,
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_stmt(&mut self) -> PResult<Stmt> {
+        let start = self.current_span();
+        if self.at(&TokenType::ReturnSemi) {
+            return Err(stray_semi(self.current_span()));
+        }
+        let kind = match self.try_parse_declaration()? {
+            Some(kind) => kind,
+            None => self.parse_expr_stmt()?,
+        };
+
+        let semi = self
+            .at(&TokenType::ReturnSemi)
+            .then(|| Span::from(&self.advance().size));
+        let kind = match (kind, semi) {
+            (StmtKind::Expr(expr), Some(semi)) if !is_control_flow(&expr) => {
+                StmtKind::Expr(self.mk_return(expr, semi))
+            }
+            (kind, _) => kind,
+        };
+        Ok(Stmt {
+            kind,
+            span: self.span_from(start),
+            semi,
+        })
+    }
+
+    fn parse_expr_stmt(&mut self) -> PResult<StmtKind> {
+        let expr = self.parse_expr()?;
+        let op = match self.peek() {
+            TokenType::Equal => Some(AssignOp::Set),
+            TokenType::PlusEqual => Some(AssignOp::Add),
+            TokenType::MinusEqual => Some(AssignOp::Sub),
+            TokenType::AsteriskEqual => Some(AssignOp::Mul),
+            TokenType::SlashEqual => Some(AssignOp::Div),
+            _ => None,
+        };
+        let kind = if let Some(op) = op {
+            self.advance();
+            let value = self.parse_expr()?;
+            StmtKind::Assign {
+                target: expr,
+                op,
+                value,
+            }
+        } else if matches!(self.peek(), TokenType::PlusPlus | TokenType::MinusMinus)
+            && !self.newline_before()
+        {
+            let increment = self.advance().token_type == TokenType::PlusPlus;
+            StmtKind::Step {
+                target: expr,
+                increment,
+            }
+        } else {
+            StmtKind::Expr(expr)
+        };
+        Ok(kind)
+    }
+
+    /// Desugars `value;` into `return value`.
+    fn mk_return(&mut self, value: Expr, semi: Span) -> Expr {
+        let span = value.span.to(semi);
+        self.mk_expr(ExprKind::Return(Some(Box::new(value))), span)
+    }
+
+    /// Declarations start with a type, which is only known after parsing it,
+    /// so this speculatively parses `[mut] type name :=` and rewinds otherwise.
+    fn try_parse_declaration(&mut self) -> PResult<Option<StmtKind>> {
+        let starts_declaration = matches!(
+            self.peek(),
+            TokenType::Mut
+                | TokenType::Identifier
+                | TokenType::Ref
+                | TokenType::RawRef
+                | TokenType::LParen
+                | TokenType::Ampersand
+                | TokenType::Func
+        );
+        if !starts_declaration {
+            return Ok(None);
+        }
+        let checkpoint = (self.pos, self.prev_end);
+
+        if self.at(&TokenType::LParen) {
+            if let Some(bindings) = self.try_parse_destructure_pattern()
+                && self.eat(&TokenType::Destructure)
+            {
+                let init = self.parse_expr()?;
+                return Ok(Some(StmtKind::Destructure { bindings, init }));
+            }
+            (self.pos, self.prev_end) = checkpoint;
+        }
+
+        let mutable = self.eat(&TokenType::Mut);
+        let declared = match self.parse_type() {
+            Ok(ty) if self.at(&TokenType::Identifier) => {
+                let name = self.expect_ident("")?;
+                Some((ty, name))
+            }
+            _ => None,
+        };
+        match declared {
+            Some((ty, name)) if self.at(&TokenType::Assign) => {
+                self.advance();
+                let init = self.parse_expr()?;
+                let binding = Binding {
+                    id: self.fresh_id(),
+                    mutable,
+                    ty,
+                    name,
+                };
+                Ok(Some(StmtKind::Let { binding, init }))
+            }
+            Some((_, name)) if self.at(&TokenType::Equal) => Err(Diagnostic::error(
+                format!("declarations use `:=`, found `=` after `{}`", name.name),
+                self.current_span(),
+            )
+            .with_fixit(self.current_span(), ":=", "use `:=` to declare a variable")),
+            _ if mutable => Err(self.unexpected("a declaration after `mut`")),
+            _ => {
+                (self.pos, self.prev_end) = checkpoint;
+                Ok(None)
+            }
+        }
+    }
+
+    /// `(int a, mut string b)` on the left of `$=`.
+    fn try_parse_destructure_pattern(&mut self) -> Option<Vec<Binding>> {
+        self.advance();
+        let mut bindings = Vec::new();
+        while !self.at(&TokenType::RParen) {
+            let mutable = self.eat(&TokenType::Mut);
+            let ty = self.parse_type().ok()?;
+            let name = self.expect_ident("").ok()?;
+            bindings.push(Binding {
+                id: self.fresh_id(),
+                mutable,
+                ty,
+                name,
+            });
+            if !self.eat(&TokenType::Comma) {
+                break;
+            }
+        }
+        self.eat(&TokenType::RParen).then_some(bindings)
+    }
+
+    // ---------------------------------------------------------------------
+    // Expressions
+    // ---------------------------------------------------------------------
+
+    pub fn parse_expr(&mut self) -> PResult<Expr> {
+        self.parse_binary(0, true)
+    }
+
+    /// Conditions of `if`/`while`/`match`/`for` can't contain record literals,
+    /// otherwise `if x { .. }` would read the block as a literal.
+    fn parse_expr_no_record(&mut self) -> PResult<Expr> {
+        self.parse_binary(0, false)
+    }
+
+    fn binary_op(&self) -> Option<(BinOp, u8)> {
+        let op = match self.peek() {
+            TokenType::PipePipe => (BinOp::Or, 1),
+            TokenType::AmpersandAmpersand => (BinOp::And, 2),
+            TokenType::EqualEqual => (BinOp::Eq, 3),
+            TokenType::ExclaimEqual => (BinOp::Ne, 3),
+            TokenType::LessThan => (BinOp::Lt, 3),
+            TokenType::GreaterThan => (BinOp::Gt, 3),
+            TokenType::LessThanEqual => (BinOp::Le, 3),
+            TokenType::GreaterThanEqual => (BinOp::Ge, 3),
+            TokenType::Pipe => (BinOp::BitOr, 5),
+            TokenType::Carrot => (BinOp::BitXor, 6),
+            TokenType::Ampersand => (BinOp::BitAnd, 7),
+            TokenType::Plus => (BinOp::Add, 8),
+            TokenType::Minus => (BinOp::Sub, 8),
+            TokenType::Asterisk => (BinOp::Mul, 9),
+            TokenType::Slash => (BinOp::Div, 9),
+            TokenType::Percent => (BinOp::Rem, 9),
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    // Precedence climbing; ranges sit between comparisons and bitwise ops.
+    fn parse_binary(&mut self, min_prec: u8, allow_record: bool) -> PResult<Expr> {
+        let mut lhs = self.parse_unary(allow_record)?;
+        loop {
+            if self.at(&TokenType::DotDot) && min_prec <= 4 {
+                self.advance();
+                let rhs = self.parse_binary(5, allow_record)?;
+                let span = lhs.span.to(rhs.span);
+                lhs = self.mk_expr(
+                    ExprKind::Range {
+                        start: Box::new(lhs),
+                        end: Box::new(rhs),
+                    },
+                    span,
+                );
+                continue;
+            }
+            let Some((op, prec)) = self.binary_op() else {
+                break;
+            };
+            if prec < min_prec {
+                break;
+            }
+            self.advance();
+            let rhs = self.parse_binary(prec + 1, allow_record)?;
+            let span = lhs.span.to(rhs.span);
+            lhs = self.mk_expr(
+                ExprKind::Binary {
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+                span,
+            );
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self, allow_record: bool) -> PResult<Expr> {
+        let start = self.current_span();
+        let kind = match self.peek() {
+            TokenType::Minus | TokenType::Exclaim => {
+                let op = if self.advance().token_type == TokenType::Minus {
+                    UnaryOp::Neg
+                } else {
+                    UnaryOp::Not
+                };
+                let expr = self.parse_unary(allow_record)?;
+                ExprKind::Unary {
+                    op,
+                    expr: Box::new(expr),
+                }
+            }
+            TokenType::Ref => {
+                self.advance();
+                let mutable = self.eat(&TokenType::Mut);
+                let expr = self.parse_unary(allow_record)?;
+                ExprKind::Ref {
+                    mutable,
+                    expr: Box::new(expr),
+                }
+            }
+            TokenType::RawRef => {
+                self.advance();
+                let mutable = self.eat(&TokenType::Mut);
+                let expr = self.parse_unary(allow_record)?;
+                ExprKind::RawRef {
+                    mutable,
+                    expr: Box::new(expr),
+                }
+            }
+            TokenType::Deref => {
+                self.advance();
+                let expr = self.parse_unary(allow_record)?;
+                ExprKind::Deref(Box::new(expr))
+            }
+            _ => return self.parse_postfix(allow_record),
+        };
+        let span = self.span_from(start);
+        Ok(self.mk_expr(kind, span))
+    }
+
+    fn parse_postfix(&mut self, allow_record: bool) -> PResult<Expr> {
+        let mut expr = self.parse_primary()?;
+        loop {
+            match self.peek() {
+                TokenType::LParen | TokenType::LSquare if !self.newline_before() => {
+                    let generics = self.parse_type_args()?;
+                    if allow_record && self.at(&TokenType::LCurly) && self.at_record_literal(&expr)
+                    {
+                        expr = self.parse_record_literal(expr, generics)?;
+                        continue;
+                    }
+                    let args = self.parse_call_args()?;
+                    let span = self.span_from(expr.span);
+                    expr = self.mk_expr(
+                        ExprKind::Call {
+                            callee: Box::new(expr),
+                            generics,
+                            args,
+                        },
+                        span,
+                    );
+                }
+                TokenType::DoubleColon => {
+                    self.advance();
+                    let name = self.expect_ident("after `::`")?;
+                    let span = self.span_from(expr.span);
+                    expr = match expr.kind {
+                        ExprKind::Path(mut path) => {
+                            path.push(name);
+                            Expr {
+                                kind: ExprKind::Path(path),
+                                span,
+                                ..expr
+                            }
+                        }
+                        _ => self.mk_expr(
+                            ExprKind::Field {
+                                base: Box::new(expr),
+                                name,
+                            },
+                            span,
+                        ),
+                    };
+                }
+                TokenType::Question if !self.newline_before() => {
+                    self.advance();
+                    let span = self.span_from(expr.span);
+                    expr = self.mk_expr(ExprKind::Try(Box::new(expr)), span);
+                }
+                TokenType::LCurly
+                    if allow_record && !self.newline_before() && self.at_record_literal(&expr) =>
+                {
+                    expr = self.parse_record_literal(expr, Vec::new())?;
+                }
+                _ => break,
+            }
+        }
+        Ok(expr)
+    }
+
+    fn at_record_literal(&self, expr: &Expr) -> bool {
+        matches!(expr.kind, ExprKind::Path(_))
+            && matches!(
+                (self.peek_nth(1), self.peek_nth(2)),
+                (TokenType::RCurly, _) | (TokenType::Identifier, TokenType::Colon)
+            )
+    }
+
+    fn parse_record_literal(&mut self, path: Expr, generics: Vec<TypeExpr>) -> PResult<Expr> {
+        let ExprKind::Path(path_segments) = path.kind else {
+            return Err(Diagnostic::error("expected a record name", path.span));
+        };
+        self.expect(TokenType::LCurly, "")?;
+        let mut fields = Vec::new();
+        while !self.at(&TokenType::RCurly) {
+            let name = self.expect_ident("for a field name")?;
+            self.expect(TokenType::Colon, "after the field name")?;
+            let value = self.parse_expr()?;
+            fields.push(FieldInit { name, value });
+            if !self.eat(&TokenType::Comma) {
+                break;
+            }
+        }
+        self.expect(TokenType::RCurly, "to close the record literal")?;
+        let span = self.span_from(path.span);
+        Ok(self.mk_expr(
+            ExprKind::RecordLit {
+                path: path_segments,
+                generics,
+                fields,
+            },
+            span,
+        ))
+    }
+
+    fn parse_call_args(&mut self) -> PResult<Vec<Arg>> {
+        self.expect(TokenType::LParen, "to start the call arguments")?;
+        let mut args = Vec::new();
+        while !self.at(&TokenType::RParen) {
+            let label = if self.at(&TokenType::Identifier) && self.peek_nth(1) == &TokenType::Colon
+            {
+                let label = self.expect_ident("")?;
+                self.advance();
+                Some(label)
+            } else {
+                None
+            };
+            let value = self.parse_expr()?;
+            args.push(Arg { label, value });
+            if !self.eat(&TokenType::Comma) {
+                break;
+            }
+        }
+        self.expect(TokenType::RParen, "to close the call arguments")?;
+        Ok(args)
+    }
+
+    /// Whether the current token can begin an expression on the same line,
+    /// used for the optional operand of `break` and `return`.
+    fn starts_operand(&self) -> bool {
+        !self.newline_before()
+            && !matches!(
+                self.peek(),
+                TokenType::RCurly
+                    | TokenType::RParen
+                    | TokenType::Comma
+                    | TokenType::ReturnSemi
+                    | TokenType::Eof
+            )
+    }
+
+    fn parse_closure(&mut self) -> PResult<Closure> {
+        self.expect(TokenType::Func, "")?;
+        self.expect(TokenType::LParen, "after `@` in a closure")?;
+        let mut params = Vec::new();
+        while !self.at(&TokenType::RParen) {
+            let mutable = self.eat(&TokenType::Mut);
+            // A lone name leaves the parameter's type to inference.
+            let untyped = self.at(&TokenType::Identifier)
+                && matches!(self.peek_nth(1), TokenType::Comma | TokenType::RParen);
+            let ty = if untyped {
+                None
+            } else {
+                Some(self.parse_type()?)
+            };
+            let name = self.expect_ident("for the parameter name")?;
+            params.push(ClosureParam {
+                id: self.fresh_id(),
+                mutable,
+                ty,
+                name,
+            });
+            if !self.eat(&TokenType::Comma) {
+                break;
+            }
+        }
+        self.expect(TokenType::RParen, "to close the closure parameters")?;
+        let ret = if self.eat(&TokenType::DoubleColon) {
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+        let body = if self.eat(&TokenType::Arrow) {
+            self.parse_expr()?
+        } else {
+            let start = self.current_span();
+            let block = self.parse_block()?;
+            self.mk_expr(ExprKind::Block(block), self.span_from(start))
+        };
+        Ok(Closure {
+            params,
+            ret,
+            body: Box::new(body),
+        })
+    }
+
+    /// Parses a string with `${expr}` in it into the concatenation of its
+    /// segments and expressions: `"a${x}b"` is `"a" + x + "b"`. Empty
+    /// segments are left out, except the first, which keeps the result a
+    /// `string` whatever the first expression is.
+    fn parse_interpolation(&mut self) -> PResult<Expr> {
+        let mut result = self.string_segment();
+        while self.eat(&TokenType::Interpolate) {
+            let expr = self.parse_expr()?;
+            self.expect(TokenType::InterpolEnd, "to close the `${`")?;
+            result = self.concat(result, expr);
+            let segment = self.string_segment();
+            if segment.kind != ExprKind::Literal(Literal::Str(String::new())) {
+                result = self.concat(result, segment);
+            }
+        }
+        Ok(result)
+    }
+
+    /// The segment of an interpolated string at the current token; the
+    /// lexer puts one before and after every `${expr}`.
+    fn string_segment(&mut self) -> Expr {
+        let span = self.current_span();
+        let TokenType::Literal(literal @ Literal::Str(_)) = self.advance().token_type else {
+            unreachable!("interpolations are surrounded by string segments")
+        };
+        self.mk_expr(ExprKind::Literal(literal), span)
+    }
+
+    fn concat(&mut self, lhs: Expr, rhs: Expr) -> Expr {
+        let span = lhs.span.to(rhs.span);
+        self.mk_expr(
+            ExprKind::Binary {
+                op: BinOp::Add,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            },
+            span,
+        )
+    }
+
+    fn parse_primary(&mut self) -> PResult<Expr> {
+        let start = self.current_span();
+        let kind = match self.peek().clone() {
+            TokenType::Literal(Literal::Str(_)) if self.peek_nth(1) == &TokenType::Interpolate => {
+                return self.parse_interpolation();
+            }
+            TokenType::Literal(literal) => {
+                self.advance();
+                ExprKind::Literal(literal)
+            }
+            TokenType::Identifier => ExprKind::Path(vec![self.expect_ident("")?]),
+            TokenType::LParen => {
+                self.advance();
+                let mut elems = Vec::new();
+                let mut trailing_comma = false;
+                while !self.at(&TokenType::RParen) {
+                    elems.push(self.parse_expr()?);
+                    trailing_comma = self.eat(&TokenType::Comma);
+                    if !trailing_comma {
+                        break;
+                    }
+                }
+                self.expect(TokenType::RParen, "to close the parenthesis")?;
+                if elems.len() == 1 && !trailing_comma {
+                    let mut inner = elems.pop().unwrap();
+                    inner.span = self.span_from(start);
+                    return Ok(inner);
+                }
+                ExprKind::Tuple(elems)
+            }
+            TokenType::LCurly => ExprKind::Block(self.parse_block()?),
+            TokenType::Func => ExprKind::Closure(self.parse_closure()?),
+            TokenType::If => return self.parse_if(),
+            TokenType::While => {
+                self.advance();
+                let cond = self.parse_expr_no_record()?;
+                let body = self.parse_block()?;
+                ExprKind::While {
+                    cond: Box::new(cond),
+                    body,
+                }
+            }
+            TokenType::For => {
+                self.advance();
+                let binding = self.expect_ident("for the loop variable")?;
+                self.expect(TokenType::In, "after the loop variable")?;
+                let iter = self.parse_expr_no_record()?;
+                let body = self.parse_block()?;
+                ExprKind::For {
+                    binding,
+                    binding_id: self.fresh_id(),
+                    iter: Box::new(iter),
+                    body,
+                }
+            }
+            TokenType::Loop => {
+                self.advance();
+                ExprKind::Loop(self.parse_block()?)
+            }
+            TokenType::Match => self.parse_match()?,
+            TokenType::Break => {
+                self.advance();
+                let value = if self.starts_operand() {
+                    Some(Box::new(self.parse_expr()?))
+                } else {
+                    None
+                };
+                ExprKind::Break(value)
+            }
+            TokenType::Continue => {
+                self.advance();
+                ExprKind::Continue
+            }
+            TokenType::Return => {
+                self.advance();
+                let value = if self.starts_operand() {
+                    Some(Box::new(self.parse_expr()?))
+                } else {
+                    None
+                };
+                ExprKind::Return(value)
+            }
+            TokenType::Unsafe => {
+                self.advance();
+                ExprKind::Unsafe(self.parse_block()?)
+            }
+            TokenType::Asm => {
+                self.advance();
+                ExprKind::Asm(Box::new(self.parse_asm()?))
+            }
+            _ => return Err(self.unexpected("an expression")),
+        };
+        let span = self.span_from(start);
+        Ok(self.mk_expr(kind, span))
+    }
+
+    /// The inside of `asm { .. }`: template strings, then operands,
+    /// `clobber(..)` and `options(..)` in any order.
+    fn parse_asm(&mut self) -> PResult<AsmBlock> {
+        self.expect(TokenType::LCurly, "to open the asm block")?;
+        let mut block = AsmBlock {
+            template: Vec::new(),
+            operands: Vec::new(),
+            clobbers: Vec::new(),
+            options: AsmOptions::default(),
+        };
+        loop {
+            match self.peek().clone() {
+                TokenType::RCurly => break,
+                TokenType::Literal(Literal::Str(line)) => {
+                    let span = self.current_span();
+                    self.advance();
+                    block.template.push(parse_template(&line, span)?);
+                }
+                TokenType::In => block.operands.push(self.parse_asm_operand(None)?),
+                TokenType::Identifier if self.peek_nth(1) == &TokenType::Equal => {
+                    let name = self.expect_ident("")?;
+                    self.advance();
+                    block.operands.push(self.parse_asm_operand(Some(name))?);
+                }
+                TokenType::Identifier if self.peek_nth(1) == &TokenType::LParen => {
+                    let token = self.tokens[self.pos].clone();
+                    match self.ident_from(&token).name.as_str() {
+                        "out" | "inout" => block.operands.push(self.parse_asm_operand(None)?),
+                        "clobber" => {
+                            self.advance();
+                            block.clobbers.extend(self.parse_asm_list()?);
+                        }
+                        "options" => {
+                            self.advance();
+                            for option in self.parse_asm_list()? {
+                                match option.name.as_str() {
+                                    "volatile" => block.options.volatile = true,
+                                    "noreturn" => block.options.noreturn = true,
+                                    _ => {
+                                        return Err(Diagnostic::error(
+                                            format!("unknown assembly option `{}`", option.name),
+                                            option.span,
+                                        )
+                                        .with_note("the options are `volatile` and `noreturn`"));
+                                    }
+                                }
+                            }
+                        }
+                        _ => return Err(self.unexpected("an assembly operand")),
+                    }
+                }
+                _ => return Err(self.unexpected("a template string or an assembly operand")),
+            }
+            self.eat(&TokenType::Comma);
+        }
+        self.expect(TokenType::RCurly, "to close the asm block")?;
+        Ok(block)
+    }
+
+    /// `in(reg) expr`, `out(rax) place` or `inout(xreg) place`.
+    fn parse_asm_operand(&mut self, name: Option<Ident>) -> PResult<AsmOperand> {
+        let dir = if self.eat(&TokenType::In) {
+            AsmDir::In
+        } else {
+            let dir = self.expect_ident("")?;
+            match dir.name.as_str() {
+                "out" => AsmDir::Out,
+                "inout" => AsmDir::InOut,
+                _ => {
+                    return Err(Diagnostic::error(
+                        format!("expected `in`, `out` or `inout`, found `{}`", dir.name),
+                        dir.span,
+                    ));
+                }
+            }
+        };
+        self.expect(TokenType::LParen, "before the register")?;
+        let reg = self.expect_ident("naming a register")?;
+        self.expect(TokenType::RParen, "after the register")?;
+        let expr = self.parse_expr()?;
+        Ok(AsmOperand {
+            name,
+            dir,
+            reg: match reg.name.as_str() {
+                "reg" => AsmReg::Gpr,
+                "xreg" => AsmReg::Xmm,
+                _ => AsmReg::Explicit(reg.name),
+            },
+            reg_span: reg.span,
+            expr,
+        })
+    }
+
+    /// `(a, b, c)` after `clobber` or `options`.
+    fn parse_asm_list(&mut self) -> PResult<Vec<Ident>> {
+        self.expect(TokenType::LParen, "")?;
+        let mut list = Vec::new();
+        while !self.at(&TokenType::RParen) {
+            list.push(self.expect_ident("")?);
+            if !self.eat(&TokenType::Comma) {
+                break;
+            }
+        }
+        self.expect(TokenType::RParen, "")?;
+        Ok(list)
+    }
+
+    fn parse_if(&mut self) -> PResult<Expr> {
+        let start = Span::from(&self.expect(TokenType::If, "")?.size);
+        let cond = self.parse_expr_no_record()?;
+        let then_block = self.parse_block()?;
+        let else_branch = if self.eat(&TokenType::Else) {
+            if self.at(&TokenType::If) {
+                Some(Box::new(self.parse_if()?))
+            } else {
+                let block = self.parse_block()?;
+                let span = block.span;
+                Some(Box::new(self.mk_expr(ExprKind::Block(block), span)))
+            }
+        } else {
+            None
+        };
+        let span = self.span_from(start);
+        Ok(self.mk_expr(
+            ExprKind::If {
+                cond: Box::new(cond),
+                then_block,
+                else_branch,
+            },
+            span,
+        ))
+    }
+
+    fn parse_match(&mut self) -> PResult<ExprKind> {
+        self.expect(TokenType::Match, "")?;
+        let scrutinee = self.parse_expr_no_record()?;
+        self.expect(TokenType::LCurly, "to open the match arms")?;
+        let mut arms = Vec::new();
+        while !self.at(&TokenType::RCurly) && !self.at_eof() {
+            let start = self.current_span();
+            self.eat(&TokenType::Case);
+            let pattern = self.parse_pattern()?;
+            self.expect(TokenType::Colon, "after the match pattern")?;
+            let mut body = self.parse_expr()?;
+            if self.at(&TokenType::ReturnSemi) && !is_control_flow(&body) {
+                let semi = Span::from(&self.advance().size);
+                body = self.mk_return(body, semi);
+            }
+            arms.push(Arm {
+                pattern,
+                body,
+                span: self.span_from(start),
+            });
+            self.eat(&TokenType::Comma);
+        }
+        self.expect(TokenType::RCurly, "to close the match arms")?;
+        Ok(ExprKind::Match {
+            scrutinee: Box::new(scrutinee),
+            arms,
+        })
+    }
+
+    fn parse_pattern(&mut self) -> PResult<Pattern> {
+        let start = self.current_span();
+        let kind = match self.peek().clone() {
+            TokenType::UnderScore => {
+                self.advance();
+                PatternKind::Wildcard
+            }
+            TokenType::Literal(literal) => {
+                self.advance();
+                PatternKind::Literal(literal)
+            }
+            TokenType::LParen => {
+                self.advance();
+                let mut elems = Vec::new();
+                while !self.at(&TokenType::RParen) {
+                    elems.push(self.parse_pattern()?);
+                    if !self.eat(&TokenType::Comma) {
+                        break;
+                    }
+                }
+                self.expect(TokenType::RParen, "to close the tuple pattern")?;
+                PatternKind::Tuple(elems)
+            }
+            TokenType::Identifier => {
+                let mut path = vec![self.expect_ident("")?];
+                while self.eat(&TokenType::DoubleColon) {
+                    path.push(self.expect_ident("in the pattern path")?);
+                }
+                if self.eat(&TokenType::LParen) {
+                    let mut fields = Vec::new();
+                    while !self.at(&TokenType::RParen) {
+                        fields.push(self.parse_pattern()?);
+                        if !self.eat(&TokenType::Comma) {
+                            break;
+                        }
+                    }
+                    self.expect(TokenType::RParen, "to close the variant pattern")?;
+                    PatternKind::Variant { path, fields }
+                } else if path.len() == 1 {
+                    PatternKind::Binding(path.pop().unwrap())
+                } else {
+                    PatternKind::Variant {
+                        path,
+                        fields: Vec::new(),
+                    }
+                }
+            }
+            _ => return Err(self.unexpected("a pattern")),
+        };
+        Ok(Pattern {
+            id: self.fresh_id(),
+            kind,
+            span: self.span_from(start),
+        })
+    }
+}
+
+fn is_control_flow(expr: &Expr) -> bool {
+    matches!(
+        expr.kind,
+        ExprKind::Return(_) | ExprKind::Break(_) | ExprKind::Continue
+    )
+}
+
+fn stray_semi(span: Span) -> Diagnostic {
+    Diagnostic::error("stray `;` with no value to return", span)
+        .with_note("`;` is only used after a value, as shorthand for `return value`")
+        .with_fixit(span, "", "remove the `;`")
+}
+
+/// Lexes and parses `source`, reporting syntax errors to `handler`.
+pub fn parse(source: &str, handler: &mut ErrorHandler) -> Program {
+    Parser::new(source).parse_program(handler)
+}
+
+/// Splits a template line into text and `{operand:modifier}` references;
+/// `{{` and `}}` stand for braces.
+fn parse_template(line: &str, span: Span) -> PResult<Vec<AsmPiece>> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut inner = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => inner.push(c),
+                        None => {
+                            return Err(Diagnostic::error(
+                                "unterminated `{` in the assembly template",
+                                span,
+                            )
+                            .with_note("write `{{` for a literal brace"));
+                        }
+                    }
+                }
+                let (operand, modifier) = match inner.split_once(':') {
+                    Some((operand, modifier)) => {
+                        let mut modifier_chars = modifier.chars();
+                        match (modifier_chars.next(), modifier_chars.next()) {
+                            (Some(m), None) => (operand, Some(m)),
+                            _ => {
+                                return Err(Diagnostic::error(
+                                    format!("invalid register modifier `{}`", modifier),
+                                    span,
+                                ));
+                            }
+                        }
+                    }
+                    None => (inner.as_str(), None),
+                };
+                let operand = if let Ok(index) = operand.parse() {
+                    AsmRef::Index(index)
+                } else if !operand.is_empty()
+                    && operand.chars().all(|c| c.is_alphanumeric() || c == '_')
+                    && !operand.starts_with(|c: char| c.is_ascii_digit())
+                {
+                    AsmRef::Name(operand.to_string())
+                } else {
+                    return Err(Diagnostic::error(
+                        format!("invalid operand reference `{{{}}}`", inner),
+                        span,
+                    ));
+                };
+                if !text.is_empty() {
+                    pieces.push(AsmPiece::Text(std::mem::take(&mut text)));
+                }
+                pieces.push(AsmPiece::Operand {
+                    operand,
+                    modifier,
+                    span,
+                });
+            }
+            '}' => {
+                return Err(
+                    Diagnostic::error("unmatched `}` in the assembly template", span)
+                        .with_note("write `}}` for a literal brace"),
+                );
+            }
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(AsmPiece::Text(text));
+    }
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_ok(input: &str) -> Program {
+        let mut handler = ErrorHandler::new();
+        let program = parse(input, &mut handler);
+        assert!(
+            !handler.has_errors(),
+            "unexpected parse errors for {:?}: {:?}",
+            input,
+            handler.diagnostics()
+        );
+        program
+    }
+
+    fn only_stmt(program: &Program) -> &StmtKind {
+        match &program.items[..] {
+            [
+                Item {
+                    kind: ItemKind::Stmt(stmt),
+                    ..
+                },
+            ] => &stmt.kind,
+            other => panic!("expected a single statement, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_items() {
+        struct ItemCase<'a> {
+            name: &'a str,
+            input: &'a str,
+            check: fn(&ItemKind) -> bool,
+        }
+        let cases = vec![
+            ItemCase {
+                name: "inline function",
+                input: "@sum(int a, int b)::int -> a + b;",
+                check: |item| {
+                    matches!(item, ItemKind::Function(f)
+                        if f.params.len() == 2 && matches!(f.body, Some(FnBody::Inline(_))))
+                },
+            },
+            ItemCase {
+                name: "call-site labels and generics",
+                input: "@log_event[T: live](int trace_id%id, T msg)::unit {}",
+                check: |item| {
+                    matches!(item, ItemKind::Function(f)
+                        if f.generics[0].bounds[0].name == "live"
+                            && f.params[0].label.as_ref().unwrap().name == "trace_id"
+                            && f.params[0].name.name == "id")
+                },
+            },
+            ItemCase {
+                name: "unsafe function",
+                input: "unsafe @poke(raw_ref mut int p) { unsafe { deref p = 1 } }",
+                check: |item| matches!(item, ItemKind::Function(f) if f.is_unsafe),
+            },
+            ItemCase {
+                name: "function attributes",
+                input: "#[no_mangle]\n#[section(\".boot\")] #[align(16)] pub @f() {}",
+                check: |item| {
+                    matches!(item, ItemKind::Function(f)
+                        if f.attrs.len() == 3
+                            && f.attrs[0].name.name == "no_mangle"
+                            && f.attrs[0].args.is_empty()
+                            && matches!(&f.attrs[1].args[..], [AttrArg::Str(s, _)] if s == ".boot")
+                            && matches!(f.attrs[2].args[..], [AttrArg::Int(16, _)]))
+                },
+            },
+            ItemCase {
+                name: "extern declaration",
+                input: "extern \"C\" @printf(raw_ref byte format, ...)::int",
+                check: |item| {
+                    matches!(item, ItemKind::Function(f)
+                        if f.abi.as_ref().unwrap().name == "C"
+                            && f.variadic
+                            && f.params.len() == 1
+                            && f.body.is_none())
+                },
+            },
+            ItemCase {
+                name: "exported function",
+                input: "pub extern \"C\" @kmain(int magic) {}",
+                check: |item| {
+                    matches!(item, ItemKind::Function(f)
+                        if f.abi.is_some() && !f.variadic && f.body.is_some())
+                },
+            },
+            ItemCase {
+                name: "module import with alias",
+                input: "get module std.io as io",
+                check: |item| {
+                    matches!(item, ItemKind::Import(i)
+                        if i.dotted() == "std.io" && i.name().name == "io")
+                },
+            },
+            ItemCase {
+                name: "module import named by its last segment",
+                input: "get module shapes.circle",
+                check: |item| matches!(item, ItemKind::Import(i) if i.name().name == "circle"),
+            },
+            ItemCase {
+                name: "record with generic field",
+                input: "record human[T] { name: string age: int, program: T }",
+                check: |item| matches!(item, ItemKind::Record(r) if r.fields.len() == 3),
+            },
+            ItemCase {
+                name: "record with layout attributes and bitfields",
+                input: "#[repr(C)] #[align(8)] record entry { present: bool : 1 rw: bool : 1, base: int }",
+                check: |item| {
+                    matches!(item, ItemKind::Record(r)
+                        if r.attrs.len() == 2
+                            && matches!(&r.attrs[0].args[..], [AttrArg::Ident(c)] if c.name == "C")
+                            && matches!(r.fields[1].bits, Some((1, _)))
+                            && r.fields[2].bits.is_none())
+                },
+            },
+            ItemCase {
+                name: "union",
+                input: "union Option[T] { Some(T), None }",
+                check: |item| {
+                    matches!(item, ItemKind::Union(u)
+                        if u.variants.len() == 2 && u.variants[1].fields.is_empty())
+                },
+            },
+            ItemCase {
+                name: "protocol implementation with self kinds",
+                input: "implement live for human { @eat(ref self)::string { \"x\" } @cough(mut self) {} }",
+                check: |item| {
+                    matches!(item, ItemKind::Impl(i)
+                        if i.protocol.as_ref().unwrap().name == "live"
+                            && i.methods[0].function.self_param.as_ref().unwrap().kind == SelfKind::Ref
+                            && i.methods[1].function.self_param.as_ref().unwrap().kind == SelfKind::MutValue)
+                },
+            },
+            ItemCase {
+                name: "unsafe protocol implementation",
+                input: "unsafe implement send for Handle[T] {}",
+                check: |item| {
+                    matches!(item, ItemKind::Impl(i)
+                        if i.is_unsafe && i.protocol.as_ref().unwrap().name == "send" && i.methods.is_empty())
+                },
+            },
+        ];
+        for case in cases {
+            let program = parse_ok(case.input);
+            assert!(
+                (case.check)(&program.items[0].kind),
+                "Test {} failed: got {:?}",
+                case.name,
+                program.items[0].kind
+            );
+        }
+    }
+
+    #[test]
+    fn test_extern_block() {
+        let program =
+            parse_ok("extern \"C\" {\n @putchar(int c)::int\n pub unsafe @abort()\n}\n@main() {}");
+        assert_eq!(program.items.len(), 3);
+        let ItemKind::Function(putchar) = &program.items[0].kind else {
+            panic!("expected a function, got {:?}", program.items[0].kind);
+        };
+        assert_eq!(putchar.abi.as_ref().unwrap().name, "C");
+        assert!(putchar.body.is_none() && !program.items[0].is_pub);
+        let ItemKind::Function(abort) = &program.items[1].kind else {
+            panic!("expected a function, got {:?}", program.items[1].kind);
+        };
+        assert!(abort.abi.is_some() && abort.is_unsafe && program.items[1].is_pub);
+        assert!(matches!(&program.items[2].kind, ItemKind::Function(f) if f.abi.is_none()));
+    }
+
+    #[test]
+    fn test_statements() {
+        let program = parse_ok("mut Option[int] x := Option::Some(4)");
+        assert!(matches!(
+            only_stmt(&program),
+            StmtKind::Let { binding, .. } if binding.mutable && binding.name.name == "x"
+        ));
+
+        let program = parse_ok("(int a, string b) $= x");
+        assert!(
+            matches!(only_stmt(&program), StmtKind::Destructure { bindings, .. } if bindings.len() == 2)
+        );
+
+        let program = parse_ok("self::health -= 10");
+        assert!(matches!(
+            only_stmt(&program),
+            StmtKind::Assign { op: AssignOp::Sub, target: Expr { kind: ExprKind::Path(path), .. }, .. }
+                if path.len() == 2
+        ));
+
+        let program = parse_ok("i++");
+        assert!(matches!(
+            only_stmt(&program),
+            StmtKind::Step {
+                increment: true,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_newlines_separate_statements() {
+        // Without terminators, `(` on a new line starts a new statement.
+        let program = parse_ok("print(i)\n(1, 2)");
+        assert_eq!(program.items.len(), 2);
+
+        let program = parse_ok("int k := x + y\nprint(k)");
+        assert_eq!(program.items.len(), 2);
+    }
+
+    #[test]
+    fn test_condition_is_not_a_record_literal() {
+        let program = parse_ok("if ready { go() } else { stop() }");
+        assert!(matches!(
+            only_stmt(&program),
+            StmtKind::Expr(Expr {
+                kind: ExprKind::If {
+                    else_branch: Some(_),
+                    ..
+                },
+                ..
+            })
+        ));
+
+        let program = parse_ok("human h := human { name: \"x\", age: 4 }");
+        assert!(matches!(
+            only_stmt(&program),
+            StmtKind::Let { init: Expr { kind: ExprKind::RecordLit { fields, .. }, .. }, .. }
+                if fields.len() == 2
+        ));
+    }
+
+    #[test]
+    fn test_match_and_loops() {
+        let program = parse_ok(
+            "match drink {\n case Option::Some(\"lemonade\"): print(1),\n case _: print(2)\n}",
+        );
+        assert!(matches!(
+            only_stmt(&program),
+            StmtKind::Expr(Expr { kind: ExprKind::Match { arms, .. }, .. }) if arms.len() == 2
+        ));
+
+        let program = parse_ok("loop {\n if i > 10 {\n break i\n }\n}");
+        assert!(matches!(
+            only_stmt(&program),
+            StmtKind::Expr(Expr {
+                kind: ExprKind::Loop(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_closures_and_function_types() {
+        let program = parse_ok("@(int, int)::int add := @(a, mut int b) -> a + b");
+        let StmtKind::Let { binding, init } = only_stmt(&program) else {
+            panic!("expected a declaration");
+        };
+        assert!(
+            matches!(&binding.ty.kind, TypeExprKind::Fn { params, ret: Some(_) } if params.len() == 2)
+        );
+        let ExprKind::Closure(closure) = &init.kind else {
+            panic!("expected a closure, got {:?}", init.kind);
+        };
+        assert!(closure.params[0].ty.is_none());
+        assert!(closure.params[1].mutable && closure.params[1].ty.is_some());
+
+        let program = parse_ok("@f() {\n print(@(x)::int {\n x\n })\n}");
+        let ItemKind::Function(function) = &program.items[0].kind else {
+            panic!("expected a function");
+        };
+        assert!(function.body.is_some());
+    }
+
+    #[test]
+    fn test_semicolon_desugars_to_return() {
+        let program = parse_ok("@mutate(byte buf)::byte {\n buf = buf + 1\n buf;\n}");
+        let ItemKind::Function(function) = &program.items[0].kind else {
+            panic!("expected a function");
+        };
+        let Some(FnBody::Block(block)) = &function.body else {
+            panic!("expected a block body");
+        };
+        assert!(matches!(
+            &block.stmts[1],
+            Stmt {
+                kind: StmtKind::Expr(Expr {
+                    kind: ExprKind::Return(Some(_)),
+                    ..
+                }),
+                semi: Some(_),
+                ..
+            }
+        ));
+
+        let program = parse_ok("@sum(int a, int b)::int -> a + b;");
+        assert!(matches!(
+            &program.items[0].kind,
+            ItemKind::Function(Function { body: Some(FnBody::Inline(body)), .. })
+                if matches!(body.kind, ExprKind::Return(Some(_)))
+        ));
+
+        // A `;` after a declaration is kept for the checker to reject.
+        let program = parse_ok("int x := 4;");
+        assert!(matches!(
+            &program.items[0].kind,
+            ItemKind::Stmt(Stmt {
+                kind: StmtKind::Let { .. },
+                semi: Some(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_inline_assembly() {
+        let program = parse_ok(
+            "asm {\n \"mov {sum}, {0}\"\n \"add {sum:d}, {{1}}\"\n in(reg) a + 1\n sum = inout(rax) total\n clobber(rcx, memory)\n options(volatile)\n}",
+        );
+        let StmtKind::Expr(Expr {
+            kind: ExprKind::Asm(block),
+            ..
+        }) = only_stmt(&program)
+        else {
+            panic!("expected an asm block");
+        };
+        assert_eq!(block.template.len(), 2);
+        assert!(matches!(
+            &block.template[0][..],
+            [
+                AsmPiece::Text(_),
+                AsmPiece::Operand { operand: AsmRef::Name(name), modifier: None, .. },
+                AsmPiece::Text(_),
+                AsmPiece::Operand { operand: AsmRef::Index(0), .. },
+            ] if name == "sum"
+        ));
+        assert_eq!(
+            block.template[1]
+                .iter()
+                .map(ToString::to_string)
+                .collect::<String>(),
+            "add {sum:d}, {{1}}"
+        );
+        assert_eq!(block.operands.len(), 2);
+        assert!(block.operands[0].name.is_none() && block.operands[0].reg == AsmReg::Gpr);
+        assert!(matches!(
+            block.operands[0].expr.kind,
+            ExprKind::Binary { .. }
+        ));
+        assert_eq!(block.operands[1].dir, AsmDir::InOut);
+        assert_eq!(block.operands[1].reg, AsmReg::Explicit("rax".to_string()));
+        assert_eq!(block.clobbers.len(), 2);
+        assert!(block.options.volatile && !block.options.noreturn);
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let cases = vec![
+            ("int x = 4", "declarations use `:=`"),
+            ("@f(int a {}", "expected `)`"),
+            ("record r { name string }", "expected `:`"),
+            ("record r { name: string };", "stray `;`"),
+            ("@f()::int {\n 4;;\n}", "stray `;`"),
+            ("asm { \"mov {0, 1\" }", "unterminated `{`"),
+            ("asm { \"jmp }\" }", "unmatched `}`"),
+            ("asm { \"nop\" options(pure) }", "unknown assembly option"),
+            (
+                "asm { \"nop\" sideways(reg) x }",
+                "expected an assembly operand",
+            ),
+            (
+                "#[packed] union u { a(int) }",
+                "attributes can only be applied to functions and records",
+            ),
+            ("record r { a: int : b }", "expected a bitfield width"),
+            ("#[align(16 @f() {}", "expected `)`"),
+            (
+                "@f(int a, ...) {}",
+                "only `extern` declarations can take variadic arguments",
+            ),
+            (
+                "extern \"C\" @f(...) {}",
+                "only `extern` declarations can take variadic arguments",
+            ),
+            (
+                "extern \"C\" { @f() {} }",
+                "functions in an `extern` block can't have a body",
+            ),
+            ("extern C @f()", "expected an ABI string"),
+        ];
+        for (input, expected) in cases {
+            let mut handler = ErrorHandler::new();
+            parse(input, &mut handler);
+            let messages: Vec<&str> = handler
+                .diagnostics()
+                .iter()
+                .map(|d| d.message.as_str())
+                .collect();
+            assert!(
+                messages.iter().any(|m| m.contains(expected)),
+                "expected an error containing {:?} for {:?}, got {:?}",
+                expected,
+                input,
+                messages
+            );
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! The `core` prelude: items every module can use without importing them.
+//!
+//! The prelude is an ordinary Enigma module compiled into the binary. The
+//! loader adds it to every module graph ahead of the entry file, and each
+//! other module sees its public items by their plain names (`Option`) and
+//! as `core::Option`. A module may define its own item with the same name,
+//! which then hides the prelude's.
+//!
+//! Freestanding programs get the same prelude: nothing in it needs the C
+//! library or an allocator, and its `exit`s call the program's `@panic`.
+
+/// The prelude's module name.
+pub const MODULE: &str = "core";
+/// The path the prelude is reported under in diagnostics.
+pub const PATH: &str = "<core>";
+pub const SOURCE: &str = include_str!("prelude/core.en");
+
+/// Canonical names of the prelude items the compiler itself relies on.
+pub const OPTION: &str = "core::Option";
+pub const RESULT: &str = "core::Result";
+/// The protocol `?` uses to convert one `Result` error type into another.
+pub const INTO_ERROR: &str = "core::into_error";
+/// Its only method.
+pub const INTO_ERROR_METHOD: &str = "into_error";
+/// The protocol `for` loops step through, and its only method. Ranges
+/// implement it without an `implement` block.
+pub const ITERATOR: &str = "core::iterator";
+pub const ITERATOR_METHOD: &str = "next";
+/// Marker protocols for what may cross between threads. Every type
+/// implements them if its parts do; `unsafe implement` vouches for others.
+pub const SEND: &str = "core::send";
+pub const SHARE: &str = "core::share";
+/// The orderings of atomic operations, and the prelude's types offering
+/// them.
+pub const ORDERING: &str = "core::Ordering";
+pub const ATOMICS: [&str; 2] = ["core::AtomicInt", "core::AtomicRef"];
+
+/// What the `Ordering` parameter `param` of the atomic method `method`
+/// orders, and the orderings it can't have. The checker makes callers write
+/// these orderings out and rejects the ones they can't have, so the methods
+/// never check them. The read-modify-writes take any ordering, and are
+/// always `SeqCst`.
+pub fn forbidden_orderings(
+    method: &str,
+    param: &str,
+) -> Option<(&'static str, &'static [&'static str])> {
+    match (method, param) {
+        ("load", "order") | ("compare_exchange", "failure") => {
+            Some(("load", &["Release", "AcqRel"]))
+        }
+        ("store", "order") => Some(("store", &["Acquire", "AcqRel"])),
+        _ => None,
+    }
+}
@@ -0,0 +1,56 @@
+//! What a program is compiled for.
+//!
+//! Hosted programs run as Linux processes on the C library, which provides
+//! the process entry, `print`, `exit` and the heap. Freestanding programs,
+//! such as kernels, have none of that: the entry module defines `@start`,
+//! which the generated `_start` calls once every module is initialized, and
+//! `@panic`, which runtime errors and `exit` with a message call. Strings
+//! can't be concatenated, as that allocates. The output also carries a
+//! Multiboot2 header and a 32-bit entry, `_start32`, that boots it.
+
+/// The pragma that makes the file it starts freestanding.
+pub const FREESTANDING_PRAGMA: &str = "#freestanding";
+/// The entry and panic handler a freestanding program defines.
+pub const ENTRY: &str = "start";
+pub const PANIC: &str = "panic";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Target {
+    /// `x86_64-linux`, on the C library.
+    #[default]
+    Hosted,
+    /// `x86_64-none`, on bare metal.
+    Freestanding,
+}
+
+impl Target {
+    pub fn parse(triple: &str) -> Option<Target> {
+        match triple {
+            "x86_64-linux" => Some(Target::Hosted),
+            "x86_64-none" => Some(Target::Freestanding),
+            _ => None,
+        }
+    }
+
+    pub fn triple(self) -> &'static str {
+        match self {
+            Target::Hosted => "x86_64-linux",
+            Target::Freestanding => "x86_64-none",
+        }
+    }
+
+    /// The target `source` asks for: freestanding if a `#freestanding` line
+    /// comes before its first line of code.
+    pub fn of_source(source: &str) -> Target {
+        let freestanding = source
+            .lines()
+            .map(str::trim)
+            .take_while(|line| line.is_empty() || line.starts_with('#'))
+            .any(|line| line == FREESTANDING_PRAGMA);
+        if freestanding {
+            Target::Freestanding
+        } else {
+            Target::Hosted
+        }
+    }
+}
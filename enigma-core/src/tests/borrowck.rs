@@ -0,0 +1,293 @@
+use super::{CheckCase, diagnostics_for, run_check_cases};
+
+const HUMAN: &str = "record human {\n name: string\n age: int\n}\n\
+                     implement human {\n\
+                      @older(ref mut self) {\n self::age += 1\n }\n\
+                      @age(ref self)::int -> self::age;\n\
+                      @into_name(self)::string -> self::name;\n\
+                     }\n\
+                     union Option[T] {\n Some(T),\n None\n}\n\
+                     record pair {\n a: human\n b: human\n}\n\
+                     @keep(human h) {\n}\n\
+                     @swap(ref mut int a, ref mut int b) {\n}\n";
+
+fn with_human(body: &str) -> String {
+    format!("{}@main() {{\n{}\n}}", HUMAN, body)
+}
+
+#[test]
+fn test_moves() {
+    let cases = [
+        (
+            "copies are reused",
+            "int a := 1\nint b := a\nint c := a + b",
+            vec![],
+        ),
+        (
+            "records move",
+            "human h := human { name: \"al\", age: 3 }\nkeep(h)\nkeep(h)",
+            vec!["use of moved value: `h`"],
+        ),
+        (
+            "moves into bindings",
+            "human h := human { name: \"al\", age: 3 }\nhuman g := h\nint a := h::age",
+            vec!["use of moved value: `h::age`"],
+        ),
+        (
+            "by value receivers move",
+            "human h := human { name: \"al\", age: 3 }\nstring n := h::into_name()\nint a := h::age()",
+            vec!["borrow of moved value: `h`"],
+        ),
+        (
+            "assigning again reinitializes",
+            "mut human h := human { name: \"al\", age: 3 }\nkeep(h)\n\
+             h = human { name: \"bo\", age: 4 }\nkeep(h)",
+            vec![],
+        ),
+        (
+            "moving a field leaves the others usable",
+            "pair p := pair { a: human { name: \"al\", age: 3 }, b: human { name: \"bo\", age: 4 } }\n\
+             keep(p::a)\nkeep(p::b)\npair q := p",
+            vec!["use of partially moved value: `p`"],
+        ),
+        (
+            "moves in one branch",
+            "human h := human { name: \"al\", age: 3 }\nif 1 > 2 {\n keep(h)\n}\nkeep(h)",
+            vec!["use of moved value: `h`"],
+        ),
+        (
+            "moves in a loop",
+            "human h := human { name: \"al\", age: 3 }\nloop {\n keep(h)\n}",
+            vec!["use of moved value: `h`"],
+        ),
+        (
+            "matching a binding moves the scrutinee",
+            "Option[human] o := Option::Some(human { name: \"al\", age: 3 })\n\
+             match o {\n Option::Some(h): keep(h)\n Option::None: {}\n}\n\
+             match o {\n Option::Some(h): keep(h)\n Option::None: {}\n}",
+            vec!["use of moved value: `o`"],
+        ),
+    ];
+    for (name, body, errors) in cases {
+        let input = with_human(body);
+        run_check_cases(vec![CheckCase {
+            name,
+            input: &input,
+            errors,
+        }]);
+    }
+}
+
+#[test]
+fn test_moves_out_of_references() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "through a reference",
+            input: &format!("{}@take(ref human h)::human -> deref h;", HUMAN),
+            errors: vec!["cannot move out of `h`, which is behind a reference"],
+        },
+        CheckCase {
+            name: "copies through a reference are fine",
+            input: &format!("{}@age(ref human h)::int -> h::age;", HUMAN),
+            errors: vec![],
+        },
+    ]);
+}
+
+#[test]
+fn test_the_script_tracks_its_bindings() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "use after move",
+            input: &format!(
+                "{}human a := human {{ name: \"al\", age: 3 }}\nhuman b := a\nhuman c := a",
+                HUMAN
+            ),
+            errors: vec!["use of moved value: `a`"],
+        },
+        CheckCase {
+            name: "borrow conflicts",
+            input: &format!(
+                "{}mut human a := human {{ name: \"al\", age: 3 }}\n\
+                 ref mut human r := ref mut a\na::older()\nr::older()",
+                HUMAN
+            ),
+            errors: vec!["cannot borrow `a` as mutable more than once at a time"],
+        },
+    ]);
+}
+
+#[test]
+fn test_borrows() {
+    let cases = [
+        (
+            "many shared borrows",
+            "int x := 1\nref int a := ref x\nref int b := ref x\nint c := deref a + deref b",
+            vec![],
+        ),
+        (
+            "two mutable borrows",
+            "mut int x := 1\nref mut int a := ref mut x\nref mut int b := ref mut x\n\
+             deref a = 2",
+            vec!["cannot borrow `x` as mutable more than once at a time"],
+        ),
+        (
+            "shared then mutable",
+            "mut int x := 1\nref int a := ref x\nref mut int b := ref mut x\nint c := deref a",
+            vec!["cannot borrow `x` as mutable because it is also borrowed as immutable"],
+        ),
+        (
+            "borrows end at their last use",
+            "mut int x := 1\nref mut int a := ref mut x\nderef a = 2\n\
+             ref mut int b := ref mut x\nderef b = 3\nint c := x",
+            vec![],
+        ),
+        (
+            "use while mutably borrowed",
+            "mut int x := 1\nref mut int a := ref mut x\nint c := x\nderef a = 2",
+            vec!["cannot use `x` because it was mutably borrowed"],
+        ),
+        (
+            "assign while borrowed",
+            "mut int x := 1\nref int a := ref x\nx = 2\nint c := deref a",
+            vec!["cannot assign to `x` because it is borrowed"],
+        ),
+        (
+            "move while borrowed",
+            "human h := human { name: \"al\", age: 3 }\nref human r := ref h\nkeep(h)\n\
+             int a := r::age",
+            vec!["cannot move out of `h` because it is borrowed"],
+        ),
+        (
+            "disjoint fields",
+            "mut human h := human { name: \"al\", age: 3 }\n\
+             ref mut int a := ref mut h::age\nref string n := ref h::name\nderef a = 4\n\
+             string m := deref n",
+            vec![],
+        ),
+        (
+            "methods borrow their receiver",
+            "mut human h := human { name: \"al\", age: 3 }\nref int a := ref h::age\n\
+             h::older()\nint b := deref a",
+            vec!["cannot borrow `h` as mutable because it is also borrowed as immutable"],
+        ),
+        (
+            "arguments are evaluated before the receiver is borrowed",
+            "mut human h := human { name: \"al\", age: 3 }\nh::older()\nint a := h::age()",
+            vec![],
+        ),
+        (
+            "both borrows in one call",
+            "mut int x := 1\nswap(ref mut x, ref mut x)",
+            vec!["cannot borrow `x` as mutable more than once at a time"],
+        ),
+        (
+            "borrows held across loop iterations",
+            "mut int x := 1\nref mut int a := ref mut x\nmut int i := 0\n\
+             while i < 3 {\n x = i\n deref a = i\n i += 1\n}",
+            vec!["cannot assign to `x` because it is borrowed"],
+        ),
+        (
+            "borrowing again after the last use",
+            "mut int x := 1\nmut ref mut int a := ref mut x\nmut int i := 0\n\
+             while i < 3 {\n deref a = i\n a = ref mut x\n i += 1\n}",
+            vec![],
+        ),
+        (
+            "scoped borrow outlives its value",
+            "mut ref int r := ref 0\n{\n int y := 1\n r = ref y\n}\nint z := deref r",
+            vec!["`y` does not live long enough"],
+        ),
+        (
+            "reborrows through a reference",
+            "mut int x := 1\nref mut int a := ref mut x\nref mut int b := ref mut deref a\n\
+             deref b = 2\nderef a = 3",
+            vec![],
+        ),
+    ];
+    for (name, body, errors) in cases {
+        let input = with_human(body);
+        run_check_cases(vec![CheckCase {
+            name,
+            input: &input,
+            errors,
+        }]);
+    }
+}
+
+#[test]
+fn test_borrow_errors_point_at_the_borrow_and_the_later_use() {
+    let source = with_human("mut int x := 1\nref int a := ref x\nx = 2\nint c := deref a");
+    let diagnostics = diagnostics_for(&source);
+    let error = diagnostics.iter().find(|d| d.is_error()).expect("an error");
+    let labels: Vec<(&str, &str)> = error
+        .labels
+        .iter()
+        .map(|label| {
+            (
+                &source[label.span.start..label.span.end],
+                label.message.as_str(),
+            )
+        })
+        .collect();
+    assert_eq!(&source[error.span.start..error.span.end], "x");
+    assert_eq!(
+        labels,
+        vec![
+            ("ref x", "borrow occurs here"),
+            ("deref a", "borrow later used here")
+        ]
+    );
+}
+
+#[test]
+fn test_returning_references_to_locals() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "local",
+            input: "@f()::ref int {\n int x := 1\n ref x\n}",
+            errors: vec!["cannot return a reference to local `x`"],
+        },
+        CheckCase {
+            name: "parameter by value",
+            input: "@f(int x)::ref int {\n return ref x\n}",
+            errors: vec!["cannot return a reference to local `x`"],
+        },
+        CheckCase {
+            name: "reference passed in",
+            input: "@f(ref int x)::ref int -> x;",
+            errors: vec![],
+        },
+    ]);
+}
+
+#[test]
+fn test_closures_borrow_what_they_capture() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "returning a closure over a local",
+            input: "@mk(int base)::@(int)::int {\n @(int)::int f := @(x) -> x + base\n f\n}",
+            errors: vec!["cannot return a reference to local `base`"],
+        },
+        CheckCase {
+            name: "writing a capture while it is borrowed",
+            input: "@f()::int {\n mut int x := 1\n ref int r := ref x\n \
+                    @()::unit set := @() {\n  x = 5\n }\n set()\n deref r\n}",
+            errors: vec!["cannot borrow `x` as mutable because it is also borrowed as immutable"],
+        },
+        CheckCase {
+            name: "moving a capture out",
+            input: &with_human(
+                "human a := human { name: \"al\", age: 3 }\n\
+                 @()::unit take := @() {\n keep(a)\n}\ntake()\ntake()",
+            ),
+            errors: vec!["cannot move out of `a`, which is captured by reference"],
+        },
+        CheckCase {
+            name: "using a closure's captures after it",
+            input: "@f()::int {\n mut int n := 0\n @()::unit bump := @() {\n  n += 1\n }\n \
+                    bump()\n bump()\n n\n}",
+            errors: vec![],
+        },
+    ]);
+}
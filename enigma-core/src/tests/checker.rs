@@ -0,0 +1,1047 @@
+use super::{CheckCase, run_check_cases};
+
+#[test]
+fn test_blocks_take_the_type_of_their_tail() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "block expression",
+            input: "int foo := {\n int u := 8\n int y := 9\n u + y\n}",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "block ending in a declaration is unit",
+            input: "int foo := {\n int u := 8\n}",
+            errors: vec!["expected `int`, found `unit`"],
+        },
+        CheckCase {
+            name: "function body tail is the return value",
+            input: "@diff(int a, int b)::int {\n a - b\n}\n@name()::string {\n 4\n}",
+            errors: vec!["expected `string`, found `int`"],
+        },
+        CheckCase {
+            name: "diverging block satisfies any type",
+            input: "@f(int a)::int {\n if a > 1 {\n return 1\n }\n return 2\n}",
+            errors: vec![],
+        },
+    ]);
+}
+
+#[test]
+fn test_if_expressions() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "if/else as a value",
+            input: "int foo := if 9 == 9 {\n 9\n} else {\n 0\n}",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "branches disagree",
+            input: "int foo := if 9 == 9 {\n 9\n} else {\n \"zero\"\n}",
+            errors: vec!["`if` and `else` have incompatible types"],
+        },
+        CheckCase {
+            name: "if without else used as a value",
+            input: "int foo := if 9 == 9 {\n 9\n}",
+            errors: vec!["`if` without `else` must have type `unit`"],
+        },
+        CheckCase {
+            name: "if without else as a statement",
+            input: "mut int i := 0\nif i > 1 {\n i = 2\n}",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "else if chains",
+            input: "int x := 3\nstring s := if x == 1 { \"one\" } else if x == 2 { \"two\" } else { \"many\" }",
+            errors: vec![],
+        },
+    ]);
+}
+
+#[test]
+fn test_loops_and_break_values() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "loop takes the type of its breaks",
+            input: "mut int i := 0\nint found := loop {\n i++\n if i > 10 {\n break i\n }\n}",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "break values must agree",
+            input: "mut int i := 0\nint found := loop {\n if i > 10 {\n break i\n }\n break \"done\"\n}",
+            errors: vec!["`break` values in this `loop` have incompatible types"],
+        },
+        CheckCase {
+            name: "break with a value in while",
+            input: "mut int i := 0\nwhile i < 1 {\n break i\n}",
+            errors: vec!["`break` with a value is only allowed inside `loop`, not `while`"],
+        },
+        CheckCase {
+            name: "break with a value in for",
+            input: "for i in 1..2 {\n break i\n}",
+            errors: vec!["only allowed inside `loop`, not `for`"],
+        },
+        CheckCase {
+            name: "while and for are unit",
+            input: "int x := for i in 1..2 {\n print(i)\n}",
+            errors: vec!["expected `int`, found `unit`"],
+        },
+        CheckCase {
+            name: "for over an iterator takes its element type",
+            input: "record ones {\n}\nimplement iterator[int] for ones {\n \
+                    @next(ref mut self)::Option[int] -> Option::Some(1);\n}\n\
+                    ones o := ones {}\nfor n in o {\n string s := n\n}",
+            errors: vec!["expected `string`, found `int`"],
+        },
+        CheckCase {
+            name: "for over something that isn't an iterator",
+            input: "record ones {\n}\nones o := ones {}\nfor n in o {\n print(n)\n}",
+            errors: vec!["`ones` cannot be iterated over with `for`"],
+        },
+        CheckCase {
+            name: "break outside of a loop",
+            input: "@f() {\n break\n}",
+            errors: vec!["`break` outside of a loop"],
+        },
+    ]);
+}
+
+#[test]
+fn test_items_and_calls() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "records, methods and labelled arguments",
+            input: "record human {\n name: string\n age: int\n health: Option[int]\n}\n\
+                    implement human {\n\
+                      pub @new(string name%name, int age%age)::human {\n human { name: name, age: age }\n }\n\
+                      pub @older(self)::int -> self::age + 1;\n\
+                    }\n\
+                    human h := human::new(name: \"Raju\", age: 28)\n\
+                    int a := h::older()",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "unknown label and missing field",
+            input: "record point {\n x: int\n y: int\n}\n\
+                    @mk(int x%x)::point -> point { x: x };\n\
+                    point p := mk(y: 1)",
+            errors: vec![
+                "missing field(s) `y`",
+                "has no parameter labelled `y`",
+                "missing argument(s) `x`",
+            ],
+        },
+        CheckCase {
+            name: "match arms unify",
+            input: "union Option[T] {\n Some(T),\n None\n}\n\
+                    Option[int] o := Option::Some(3)\n\
+                    int v := match o {\n case Option::Some(x): x,\n case Option::None: 0\n}\n\
+                    int w := match o {\n case Option::Some(x): x,\n case _: \"none\"\n}",
+            errors: vec!["`match` arms have incompatible types"],
+        },
+    ]);
+}
+
+#[test]
+fn test_semicolon_return_shorthand() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "shorthand returns",
+            input: "@sum(int a, int b)::int -> a + b;\n\
+                    @mutate(byte buf)::byte {\n mut byte b := buf\n b = b + 1\n b;\n}\n\
+                    @pick(bool c)::int {\n if c {\n 1;\n }\n 2\n}",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "shorthand return is type checked",
+            input: "@name()::string {\n 4;\n}",
+            errors: vec!["expected `string`, found `int`"],
+        },
+        CheckCase {
+            name: "semicolon after statements",
+            input: "@f() {\n mut int x := 4;\n x = 5;\n x++;\n}",
+            errors: vec![
+                "`;` can only follow a value to return",
+                "`;` can only follow a value to return",
+                "`;` can only follow a value to return",
+            ],
+        },
+        CheckCase {
+            name: "semicolon after return and break",
+            input: "@f()::int {\n loop {\n break;\n }\n return 1;\n}",
+            errors: vec!["`;` after `break`", "`;` after `return`"],
+        },
+        CheckCase {
+            name: "semicolon outside of a function",
+            input: "int x := 4\nx;",
+            errors: vec!["`;` returns from a function, but this code is not inside one"],
+        },
+    ]);
+}
+
+#[test]
+fn test_stray_semicolon_fixit_removes_it() {
+    let diagnostics = super::diagnostics_for("int x := 4;");
+    let fixit = &diagnostics[0].fixits[0];
+    assert_eq!(fixit.replacement, "");
+    assert_eq!((fixit.span.start, fixit.span.end), (10, 11));
+}
+
+#[test]
+fn test_generic_calls_are_inferred() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "generic argument from the argument",
+            input: "@id[T](T x)::T -> x;\nint a := id(3)\nbool b := id(true)",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "expected result flows into the arguments",
+            input: "union Option[T] {\n Some(T),\n None\n}\n\
+                    @wrap[T](T x)::Option[T] -> Option::Some(x);\n\
+                    Option[byte] o := wrap(200)\nstring s := wrap(1)",
+            errors: vec!["expected `string`, found `Option[int]`"],
+        },
+        CheckCase {
+            name: "arguments disagree with the expected result",
+            input: "@id[T](T x)::T -> x;\nstring s := id(3)",
+            errors: vec!["expected `string`, found `int`"],
+        },
+        CheckCase {
+            name: "nothing determines the generic argument",
+            input: "@zero[T]()::int -> 0;\nint n := zero()\nint m := zero[string]()",
+            errors: vec!["type annotations needed: cannot infer `T` for `zero`"],
+        },
+        CheckCase {
+            name: "variants infer their union's arguments",
+            input: "union Option[T] {\n Some(T),\n None\n}\n\
+                    print(Option::Some(1))\nprint(Option::None)",
+            errors: vec!["type annotations needed: cannot infer `T` for `Option`"],
+        },
+        CheckCase {
+            name: "generic parameters only match themselves",
+            input: "@first[T](T a)::int -> a;",
+            errors: vec!["expected `int`, found `T`"],
+        },
+        CheckCase {
+            name: "scrutinee type is inferred for the patterns",
+            input: "union Option[T] {\n Some(T),\n None\n}\n\
+                    int b := match Option::Some(7) {\n Option::Some(v): v\n Option::None: 0\n}",
+            errors: vec![],
+        },
+    ]);
+}
+
+#[test]
+fn test_closures() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "parameter types from the declaration",
+            input: "@(int)::int inc := @(x) -> x + 1\n\
+                    @(int, int)::int add := @(a, b) {\n a + b\n}\nint n := add(inc(1), 2)",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "body disagrees with the declared result",
+            input: "@(int)::string f := @(x) -> x + 1",
+            errors: vec!["expected `string`, found `int`"],
+        },
+        CheckCase {
+            name: "annotated parameters and return",
+            input: "@(int)::bool f := @(int x)::bool {\n return x > 2\n}",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "closure passed to a generic function",
+            input: "@apply[T](@(T)::T f, T v)::T -> f(v);\n\
+                    int r := apply(@(x) -> x * 2, 4)",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "closures see enclosing locals",
+            input: "@f(int base)::int {\n @(int)::int add := @(x) -> x + base\n add(1)\n}",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "unconstrained parameter",
+            input: "print(@(x) -> x)",
+            errors: vec!["type annotations needed: cannot infer the type of parameter `x`"],
+        },
+    ]);
+}
+
+const LIVE: &str = "protoc live {\n @eat(self)::string\n}\n\
+                    record human {\n name: string\n}\n\
+                    record rock {\n weight: int\n}\n\
+                    implement live for human {\n @eat(self)::string -> self::name;\n}\n\
+                    @feed[T: live](T x)::string -> x::eat();\n";
+
+#[test]
+fn test_protocol_bounds() {
+    let cases = [
+        (
+            "bounded parameter calls protocol methods",
+            "string s := feed(human { name: \"raju\" })",
+            vec![],
+        ),
+        (
+            "method not provided by any bound",
+            "@hungry[T](T x)::string -> x::eat();",
+            vec!["no method named `eat` found for type parameter `T`"],
+        ),
+        (
+            "argument doesn't implement the bound",
+            "string s := feed(rock { weight: 1 })",
+            vec!["the type `rock` does not implement protocol `live`"],
+        ),
+        (
+            "bounds carry through generic callers",
+            "@twice[U: live](U x)::string -> feed(x);\n@loose[U](U x)::string -> feed(x);",
+            vec!["the type `U` does not implement protocol `live`"],
+        ),
+        (
+            "bound that isn't a protocol",
+            "@f[T: human](T x) {\n}",
+            vec!["expected a protocol, found type `human`"],
+        ),
+    ];
+    for (name, input, errors) in cases {
+        let input = format!("{}{}", LIVE, input);
+        run_check_cases(vec![CheckCase {
+            name,
+            input: &input,
+            errors,
+        }]);
+    }
+}
+
+#[test]
+fn test_missing_bound_note_names_the_protocol() {
+    let diagnostics =
+        super::diagnostics_for(&format!("{}@hungry[T](T x)::string -> x::eat();", LIVE));
+    assert_eq!(
+        diagnostics[0].notes,
+        vec!["protocol `live` provides `eat`; bound the parameter with `[T: live]`"]
+    );
+}
+
+const SPEAK: &str = "protoc speak {\n @talk(ref self)::string\n}\n\
+                     record dog {\n name: string\n}\n\
+                     record cat {\n lives: int\n}\n\
+                     implement speak for dog {\n @talk(ref self)::string -> self::name;\n}\n";
+
+#[test]
+fn test_protocol_objects() {
+    let cases = [
+        (
+            "reference converts to a protocol object",
+            "dog d := dog { name: \"rex\" }\nref speak s := ref d\nstring t := s::talk()",
+            vec![],
+        ),
+        (
+            "protocol object parameters",
+            "@loud(ref speak s)::string -> s::talk();\n\
+             dog d := dog { name: \"rex\" }\nstring t := loud(ref d)",
+            vec![],
+        ),
+        (
+            "type doesn't implement the protocol",
+            "cat c := cat { lives: 9 }\nref speak s := ref c",
+            vec!["the type `cat` does not implement protocol `speak`"],
+        ),
+        (
+            "protocols are not types on their own",
+            "@f(speak s) {\n}",
+            vec!["expected a type, found protocol `speak`"],
+        ),
+        (
+            "unknown method on a protocol object",
+            "@f(ref speak s) {\n s::bark()\n}",
+            vec!["no method named `bark` found for protocol `speak`"],
+        ),
+    ];
+    for (name, input, errors) in cases {
+        let input = format!("{}{}", SPEAK, input);
+        run_check_cases(vec![CheckCase {
+            name,
+            input: &input,
+            errors,
+        }]);
+    }
+}
+
+#[test]
+fn test_object_safety() {
+    let source = format!(
+        "{}human h := human {{ name: \"x\" }}\nref live l := ref h",
+        LIVE
+    );
+    let diagnostics = super::diagnostics_for(&source);
+    assert_eq!(
+        diagnostics[0].message,
+        "protocol `live` cannot be made into an object"
+    );
+    assert_eq!(
+        diagnostics[0].notes,
+        vec!["`eat` takes `self` by value; use `ref self` or `ref mut self`"]
+    );
+
+    // Wherever the type is written, not only where a value becomes one.
+    let source = "protoc bad {\n @take(self)\n @gen[T](ref self, T x)\n}\n\
+                  @f(ref bad b) {}\nrecord holder { b: (int, ref bad) }\n\
+                  @g() {\n @(ref bad) h := @(ref bad x) {}\n}";
+    let diagnostics = super::diagnostics_for(source);
+    let lines: Vec<&str> = diagnostics
+        .iter()
+        .map(|d| {
+            assert_eq!(d.message, "protocol `bad` cannot be made into an object");
+            assert_eq!(
+                d.notes,
+                vec![
+                    "`take` takes `self` by value; use `ref self` or `ref mut self`",
+                    "`gen` has generic parameters",
+                ]
+            );
+            source[..d.span.start].lines().last().unwrap()
+        })
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            "@f(",
+            "record holder { b: (int, ",
+            " @(",
+            " @(ref bad) h := @("
+        ]
+    );
+}
+
+#[test]
+fn test_protocol_implementations() {
+    let cases = [
+        (
+            "signature must match the protocol",
+            "implement speak for cat {\n @talk(self)::string -> \"meow\";\n}",
+            vec!["method `talk` has an incompatible signature for protocol `speak`"],
+        ),
+        (
+            "every method must be implemented",
+            "implement speak for cat {\n}",
+            vec!["not all methods of protocol `speak` are implemented for `cat`: missing `talk`"],
+        ),
+        (
+            "extra methods are rejected",
+            "implement speak for cat {\n @talk(ref self)::string -> \"meow\";\n @purr(ref self) {\n }\n}",
+            vec!["method `purr` is not a member of protocol `speak`"],
+        ),
+        (
+            "one implementation per protocol and type",
+            "implement speak for dog {\n @talk(ref self)::string -> \"woof\";\n}",
+            vec![
+                "conflicting implementations of protocol `speak` for `dog`",
+                "duplicate definition of `talk` for `dog`",
+            ],
+        ),
+    ];
+    for (name, input, errors) in cases {
+        let input = format!("{}{}", SPEAK, input);
+        run_check_cases(vec![CheckCase {
+            name,
+            input: &input,
+            errors,
+        }]);
+    }
+}
+
+#[test]
+fn test_immutability() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "mutable bindings",
+            input: "mut int x := 4\nx = 5\nx += 1\nx++",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "assignments to an immutable binding",
+            input: "int x := 4\nx = 5\nx += 1\nx--",
+            errors: vec![
+                "cannot assign to `x`, as it is not declared `mut`",
+                "cannot assign to `x`, as it is not declared `mut`",
+                "cannot assign to `x`, as it is not declared `mut`",
+            ],
+        },
+        CheckCase {
+            name: "fields of an immutable record",
+            input: "record human {\n health: int\n}\n\
+                    human h := human { health: 3 }\nh::health = 1\n\
+                    mut human m := human { health: 3 }\nm::health -= 1",
+            errors: vec!["cannot assign to `h::health`, as `h` is not declared `mut`"],
+        },
+        CheckCase {
+            name: "through references",
+            input: "@set(ref int a, ref mut int b) {\n deref a = 1\n deref b = 2\n}\n\
+                    int x := 1\nref mut int r := ref mut x",
+            errors: vec![
+                "cannot assign to `deref a`, as it is behind a `ref` reference",
+                "cannot mutably borrow `x`, as it is not declared `mut`",
+            ],
+        },
+        CheckCase {
+            name: "self kinds",
+            input: "record human {\n health: int\n}\n\
+                    implement human {\n\
+                      @hit(ref self) {\n self::health -= 10\n }\n\
+                      @heal(self) {\n self::health += 10\n }\n\
+                      @grow(ref mut self) {\n self::health += 1\n }\n\
+                      @cough(mut self) {\n self::health -= 1\n }\n\
+                    }",
+            errors: vec![
+                "cannot assign to `self::health`, as it is behind a `ref` reference",
+                "cannot assign to `self::health`, as `self` is not declared `mut`",
+            ],
+        },
+        CheckCase {
+            name: "methods that change their receiver",
+            input: "record human {\n health: int\n}\n\
+                    implement human {\n\
+                      @cough(mut self) {\n }\n\
+                      @look(ref self) {\n }\n\
+                      @poke(ref self) {\n self::cough()\n }\n\
+                    }\n\
+                    human h := human { health: 3 }\nh::look()\nh::cough()\n\
+                    mut human m := human { health: 3 }\nm::cough()",
+            errors: vec![
+                "cannot call `cough` on `self`, as it is behind a `ref` reference",
+                "cannot call `cough` on `h`, as it is not declared `mut`",
+            ],
+        },
+    ]);
+}
+
+#[test]
+fn test_immutability_fixits_add_mut() {
+    let source = "int x := 4\nx = 5";
+    let diagnostics = super::diagnostics_for(source);
+    let fixit = &diagnostics[0].fixits[0];
+    assert_eq!((fixit.span.start, fixit.span.end), (0, 0));
+    assert_eq!(fixit.replacement, "mut ");
+    assert_eq!(fixit.message, "make `x` mutable");
+
+    let source = "record human {\n health: int\n}\n\
+                  implement human {\n @hit(ref self) {\n self::health -= 10\n }\n}";
+    let diagnostics = super::diagnostics_for(source);
+    let fixit = &diagnostics[0].fixits[0];
+    assert_eq!(&source[fixit.span.start..fixit.span.end], "ref self");
+    assert_eq!(fixit.replacement, "ref mut self");
+
+    let source = "@bump(ref int a) {\n deref a = 1\n}";
+    let diagnostics = super::diagnostics_for(source);
+    let fixit = &diagnostics[0].fixits[0];
+    assert_eq!(fixit.replacement, "mut ");
+    assert_eq!(&source[fixit.span.start..][..3], "int");
+}
+
+#[test]
+fn test_inline_assembly_operands() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "operands bound to variables",
+            input: "unsafe @add(int a, byte b, float c)::int {\n mut int sum := 0\n mut float f := c\n \
+                    asm {\n \"mov {sum}, {0}\"\n \"add {sum:b}, {1}\"\n \"addsd {f}, {f}\"\n \
+                    in(reg) a\n in(rcx) b\n sum = out(reg) sum\n f = inout(xreg) f\n \
+                    clobber(rdx, memory)\n options(volatile)\n }\n sum\n}",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "noreturn assembly diverges",
+            input: "unsafe @halt()::int {\n asm { \"hlt\" options(noreturn) }\n}",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "outputs need a mutable place",
+            input: "unsafe @f(int a) {\n asm { \"mov {0}, 1\" out(reg) a }\n asm { \"nop\" out(reg) 4 }\n}",
+            errors: vec![
+                "cannot assign to `a`",
+                "invalid left-hand side of assignment",
+            ],
+        },
+        CheckCase {
+            name: "operands must fit their register",
+            input: "unsafe @f(string s, float x, int n) {\n \
+                    asm { \"nop\" in(reg) s in(reg) x in(xmm1) n }\n}",
+            errors: vec![
+                "`string` doesn't fit in a register",
+                "a `float` operand can't go in `reg`",
+                "a `int` operand can't go in `xmm1`",
+            ],
+        },
+        CheckCase {
+            name: "registers and clobbers",
+            input: "unsafe @f(int a, int b) {\n \
+                    asm { \"nop\" in(rsp) a in(rdi) a in(rdi) b in(eax) b clobber(rdi, rbp, foo) }\n}",
+            errors: vec![
+                "`rsp` can't be an assembly operand",
+                "`rdi` is used by two assembly operands",
+                "unknown register `eax`",
+                "`rdi` is clobbered and also used by an operand",
+                "`rbp` can't be clobbered",
+                "unknown register `foo`",
+            ],
+        },
+        CheckCase {
+            name: "template references",
+            input: "unsafe @f(int a, float x) {\n \
+                    asm { \"mov {2}, {b}, {y:d}, {v:z}\" v = in(reg) a y = in(xreg) x }\n \
+                    asm { \"nop\" v = in(reg) a v = in(reg) a }\n}",
+            errors: vec![
+                "invalid reference to assembly operand 2",
+                "no assembly operand named `b`",
+                "the modifier `d` only applies to general purpose registers",
+                "unknown register modifier `z`",
+                "duplicate assembly operand `v`",
+            ],
+        },
+        CheckCase {
+            name: "noreturn assembly can't have outputs",
+            input: "unsafe @f(mut int a) {\n asm { \"hlt\" out(rax) a options(noreturn) }\n}",
+            errors: vec!["`noreturn` assembly can't have outputs"],
+        },
+    ]);
+}
+
+#[test]
+fn test_unsafe_operations() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "dereferencing a raw reference",
+            input: "@read(raw_ref int p)::int -> deref p;",
+            errors: vec!["dereference of a raw reference is unsafe and requires an `unsafe` block"],
+        },
+        CheckCase {
+            name: "dereferencing inside unsafe",
+            input: "@read(raw_ref int p)::int {\n unsafe {\n deref p\n }\n}",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "inline assembly",
+            input: "@halt() {\n asm { \"hlt\" }\n}\n@stop() {\n unsafe {\n asm { \"hlt\" }\n }\n}",
+            errors: vec!["use of inline assembly is unsafe and requires an `unsafe` block"],
+        },
+        CheckCase {
+            name: "calling an unsafe function",
+            input: "unsafe @poke(raw_ref mut int p) {\n deref p = 1\n}\n\
+                    @f(raw_ref mut int p) {\n poke(p)\n unsafe {\n poke(p)\n }\n}",
+            errors: vec!["call to unsafe function `poke` is unsafe and requires an `unsafe` block"],
+        },
+        CheckCase {
+            name: "unsafe methods",
+            input: "record port {\n id: int\n}\n\
+                    implement port {\n unsafe @write(ref self, byte value) {\n}\n}\n\
+                    @f(port p) {\n p::write(0)\n}",
+            errors: vec!["call to unsafe function `write` is unsafe"],
+        },
+        CheckCase {
+            name: "writing a mutable global",
+            input: "mut int ticks := 0\nticks = 1\n\
+                    @tick() {\n ticks += 1\n unsafe {\n ticks += 1\n }\n}\n\
+                    @now()::int -> ticks;",
+            errors: vec![
+                "assignment to mutable global `ticks` is unsafe and requires an `unsafe` block",
+            ],
+        },
+        CheckCase {
+            name: "closures inside unsafe functions",
+            input: "unsafe @f(raw_ref int p)::int {\n @()::int read := @() -> deref p\n read()\n}",
+            errors: vec![],
+        },
+    ]);
+}
+
+#[test]
+fn test_unnecessary_unsafe_blocks_warn() {
+    let diagnostics = super::diagnostics_for(
+        "@f(raw_ref int p)::int {\n int a := unsafe {\n 1\n }\n unsafe {\n deref p\n }\n}",
+    );
+    let warnings: Vec<&str> = diagnostics
+        .iter()
+        .filter(|d| !d.is_error())
+        .map(|d| d.message.as_str())
+        .collect();
+    assert_eq!(warnings, vec!["unnecessary `unsafe` block"]);
+    assert!(diagnostics.iter().all(|d| !d.is_error()));
+}
+
+#[test]
+fn test_function_attributes() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "attributes for a kernel",
+            input: "#[interrupt]\n#[no_mangle]\n@tick() {}\n\
+                    #[interrupt]\n@fault(int code) {}\n\
+                    #[section(\".boot\")]\n#[align(16)]\n@f()::int -> 1;\n\
+                    #[naked]\n@g() {\n unsafe {\n  asm { \"ret\" options(noreturn) }\n }\n}",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "unknown and duplicate attributes",
+            input: "#[inline]\n@f() {}\n#[no_mangle]\n#[no_mangle]\n@g() {}",
+            errors: vec![
+                "unknown attribute `inline`",
+                "duplicate attribute `no_mangle`",
+            ],
+        },
+        CheckCase {
+            name: "attribute arguments",
+            input: "#[section]\n@f() {}\n#[align(12)]\n@g() {}\n#[no_mangle(1)]\n@h() {}",
+            errors: vec![
+                "`section` takes the name of a section",
+                "`align` takes a power of two",
+                "`no_mangle` takes no arguments",
+            ],
+        },
+        CheckCase {
+            name: "interrupt handler signatures",
+            input: "#[interrupt]\n@f(string s) {}\n#[interrupt]\n@g()::int -> 1;\n\
+                    #[interrupt]\n@h[T]() {}",
+            errors: vec![
+                "an interrupt handler takes no parameters, or just an `int`",
+                "an interrupt handler returns `unit`, not `int`",
+                "`#[interrupt]` only applies to free functions without generic parameters",
+            ],
+        },
+        CheckCase {
+            name: "methods keep their mangled symbols",
+            input: "record r { a: int }\nimplement r {\n #[no_mangle]\n @f() {}\n}",
+            errors: vec!["`#[no_mangle]` only applies to free functions"],
+        },
+        CheckCase {
+            name: "only the processor calls interrupt handlers",
+            input: "#[interrupt]\n@tick() {}\ntick()\n@()::unit f := tick",
+            errors: vec![
+                "the interrupt handler `@tick` can't be called",
+                "the interrupt handler `@tick` can't be used as a value",
+            ],
+        },
+        CheckCase {
+            name: "naked bodies are assembly alone",
+            input: "#[naked]\n@f() {\n int x := 1\n}\n\
+                    #[naked]\n@g(int a) {\n unsafe {\n  asm { \"mov rax, {0}\" \"ret\" in(reg) a options(noreturn) }\n }\n}\n\
+                    #[naked]\n@h() {\n unsafe {\n  asm { \"ret\" }\n }\n}",
+            errors: vec![
+                "a `#[naked]` function can only contain `asm` blocks",
+                "assembly in a `#[naked]` function can't have operands",
+                "the assembly of a `#[naked]` function must end with `options(noreturn)`",
+            ],
+        },
+    ]);
+}
+
+#[test]
+fn test_record_layout_attributes() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "layouts for a kernel",
+            input: "#[repr(C)]\n#[packed]\nrecord gate { offset: int, selector: byte }\n\
+                    #[align(4096)]\nrecord table { entry: int }\n\
+                    #[repr(C)]\nrecord frame { gate: gate, next: raw_ref frame }\n\
+                    record entry { present: bool : 1, level: byte : 2, frame: int : 40 }",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "unknown, duplicate and malformed attributes",
+            input: "#[hot]\nrecord a { x: int }\n#[packed]\n#[packed]\nrecord b { x: int }\n\
+                    #[repr(D)]\nrecord c { x: int }\n#[align(3)]\nrecord d { x: int }\n\
+                    #[packed(1)]\nrecord e { x: int }",
+            errors: vec![
+                "unknown attribute `hot`",
+                "duplicate attribute `packed`",
+                "`repr` takes `C`",
+                "`align` takes a power of two",
+                "`packed` takes no arguments",
+            ],
+        },
+        CheckCase {
+            name: "packed records have no alignment to raise",
+            input: "#[packed]\n#[align(8)]\nrecord r { x: int }",
+            errors: vec!["a record can't be both `#[packed]` and `#[align]`"],
+        },
+        CheckCase {
+            name: "C records hold C types",
+            input: "#[repr(C)]\nrecord r { s: string }\nrecord plain { x: int }\n\
+                    #[repr(C)]\nrecord q { p: plain }",
+            errors: vec![
+                "the `#[repr(C)]` record `r` can't have a field of type `string`",
+                "the `#[repr(C)]` record `q` can't have a field of type `plain`",
+            ],
+        },
+        CheckCase {
+            name: "bitfield types and widths",
+            input: "record r { a: float : 3, b: byte : 9, c: bool : 0, d: int : 65 }",
+            errors: vec![
+                "a bitfield is an `int`, `byte` or `bool`, not `float`",
+                "`byte` bitfields are 1 to 8 bits wide",
+                "`bool` bitfields are 1 bit wide",
+                "`int` bitfields are 1 to 64 bits wide",
+            ],
+        },
+        CheckCase {
+            name: "bitfields have no address",
+            input: "record r { flag: bool : 1, x: int }\nmut r v := r { flag: true, x: 1 }\n\
+                    ref int x := ref v::x\nref bool f := ref v::flag\n\
+                    unsafe {\n raw_ref mut bool p := raw_ref mut v::flag\n}",
+            errors: vec![
+                "can't take a reference to the bitfield `flag`",
+                "can't take a reference to the bitfield `flag`",
+            ],
+        },
+    ]);
+}
+
+#[test]
+fn test_layout_intrinsics() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "sizes, alignments and offsets",
+            input: "#[repr(C)]\nrecord r { a: byte, b: int }\nprotoc p {\n @f(ref self)\n}\n\
+                    int n := size_of[r]() + align_of[(int, byte)]() + offset_of(r, b)\n\
+                    int m := size_of[ref p]()",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "one type and no arguments",
+            input: "int a := size_of()\nint b := align_of[int, int]()\nint c := size_of[int](1)",
+            errors: vec![
+                "`size_of` takes exactly one type",
+                "`align_of` takes exactly one type",
+                "`size_of` takes no arguments",
+            ],
+        },
+        CheckCase {
+            name: "layouts must be known to the checker",
+            input: "@f[T]()::int -> size_of[T]();",
+            errors: vec!["`size_of` can't measure values of type `T`"],
+        },
+        CheckCase {
+            name: "offsets of record fields",
+            input: "record r { a: int, flag: bool : 1 }\nrecord g[T] { x: T }\n\
+                    int a := offset_of(r, b)\nint b := offset_of(r, flag)\n\
+                    int c := offset_of(g, x)\nint d := offset_of(q, x)\n\
+                    int e := offset_of(1, 2)\nint f := offset_of(r)",
+            errors: vec![
+                "record `r` has no field `b`",
+                "the bitfield `flag` has no byte offset",
+                "`offset_of` can't measure the generic record `g`",
+                "cannot find record `q` in this scope",
+                "`offset_of` takes a record and one of its fields",
+                "`offset_of` takes exactly two arguments",
+            ],
+        },
+    ]);
+}
+
+#[test]
+fn test_volatile_accesses_and_port_io() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "a driver",
+            input: "unsafe @ack(raw_ref mut int status, raw_ref int data)::byte {\n \
+                    volatile_write(status, volatile_read(data) + 1)\n store_fence()\n \
+                    outb(0x20, 0x20)\n outl(0xcf8, inl(0xcfc) + inw(0x60))\n \
+                    fence()\n load_fence()\n inb(0x64)\n}",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "all of them are unsafe",
+            input: "@f(raw_ref mut int p) {\n volatile_write(p, 1)\n int x := volatile_read(p)\n \
+                    outb(0x80, 0)\n fence()\n}",
+            errors: vec![
+                "call to `volatile_write` is unsafe and requires an `unsafe` block",
+                "call to `volatile_read` is unsafe and requires an `unsafe` block",
+                "call to `outb` is unsafe and requires an `unsafe` block",
+                "call to `fence` is unsafe and requires an `unsafe` block",
+            ],
+        },
+        CheckCase {
+            name: "volatile accesses go through raw references",
+            input: "record pair { a: int, b: int }\n\
+                    @f(ref int r, raw_ref int p, raw_ref mut pair q) {\n unsafe {\n  \
+                    int a := volatile_read(r)\n  volatile_write(p, 1)\n  \
+                    pair b := volatile_read(q)\n  volatile_write(q, 2)\n }\n}",
+            errors: vec![
+                "`volatile_read` takes a `raw_ref`, found `ref int`",
+                "`volatile_write` takes a `raw_ref mut`, found `raw_ref int`",
+                "`volatile_read` can't access a `pair` at once",
+                "`volatile_write` can't access a `pair` at once",
+                "mismatched types: expected `pair`, found `int`",
+            ],
+        },
+        CheckCase {
+            name: "ports and values",
+            input: "unsafe {\n outb(0x80)\n outb(0x80, 1.5)\n byte b := inl(0x60)\n fence(1)\n}",
+            errors: vec![
+                "this call takes 2 argument(s) but 1 were supplied",
+                "mismatched types: expected `byte`, found `float`",
+                "mismatched types: expected `byte`, found `int`",
+                "this call takes 0 argument(s) but 1 were supplied",
+            ],
+        },
+    ]);
+}
+
+#[test]
+fn test_atomics_and_thread_markers() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "atomic types",
+            input: "AtomicInt hits := AtomicInt::new(0)\n\
+                    int before := hits::fetch_add(1, Ordering::Relaxed)\n\
+                    hits::store(5, Ordering::Release)\n\
+                    Result[int, int] swapped := \
+                    hits::compare_exchange(5, 6, Ordering::AcqRel, Ordering::Acquire)\n\
+                    mut int x := 1\n\
+                    AtomicRef[int] head := AtomicRef::new(raw_ref mut x)\n\
+                    raw_ref mut int p := head::swap(raw_ref mut x, Ordering::SeqCst)",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "orderings an atomic operation can't have",
+            input: "AtomicInt n := AtomicInt::new(0)\n\
+                    int a := n::load(Ordering::Release)\n\
+                    n::store(1, Ordering::AcqRel)\n\
+                    Result[int, int] r := \
+                    n::compare_exchange(1, 2, Ordering::Release, failure: Ordering::AcqRel)\n\
+                    mut int x := 1\n\
+                    AtomicRef[int] head := AtomicRef::new(raw_ref mut x)\n\
+                    head::store(raw_ref mut x, Ordering::Acquire)\n\
+                    Ordering order := Ordering::Release\n\
+                    int b := n::load(order)\n\
+                    int c := n::fetch_sub(1, Ordering::Acquire)",
+            errors: vec![
+                "a load can't have `Release` ordering",
+                "a store can't have `AcqRel` ordering",
+                "a load can't have `AcqRel` ordering",
+                "a store can't have `Acquire` ordering",
+                "the ordering of a load must be written out",
+            ],
+        },
+        CheckCase {
+            name: "orderings checked only where written out",
+            input: "AtomicInt n := AtomicInt::new(0)\n\
+                    @store(ref AtomicInt a, Ordering order) {\n a::store(1, order)\n}\n\
+                    @(Ordering)::int load := AtomicInt::load\n\
+                    Ordering relaxed := Ordering::Relaxed\n\
+                    Result[int, int] r := n::compare_exchange(0, 1, relaxed, Ordering::Relaxed)",
+            errors: vec![
+                "the ordering of a store must be written out",
+                "`core::AtomicInt::load` can't be used as a value",
+            ],
+        },
+        CheckCase {
+            name: "atomic intrinsics",
+            input: "@f(raw_ref mut int n, raw_ref mut bool b, raw_ref mut float x, ref int r) {\n \
+                    int a := atomic_fetch_add(n, 1)\n unsafe {\n  \
+                    int c := atomic_compare_exchange(n, 1, 2)\n  \
+                    bool d := atomic_swap(b, true)\n  atomic_fetch_sub(b, true)\n  \
+                    atomic_store(x, 1.5)\n  atomic_load(r)\n  atomic_compare_exchange(n, 1)\n }\n}",
+            errors: vec![
+                "call to `atomic_fetch_add` is unsafe and requires an `unsafe` block",
+                "`atomic_fetch_sub` can't do arithmetic on a `bool`",
+                "`atomic_store` can't access a `float` at once",
+                "`atomic_load` takes a `raw_ref`, found `ref int`",
+                "this call takes 3 argument(s) but 2 were supplied",
+            ],
+        },
+        CheckCase {
+            name: "what may cross threads",
+            input: "record job { id: int, done: AtomicInt, name: string }\n\
+                    record handle { ptr: raw_ref mut int }\n\
+                    record node { value: int, next: Option[ref node] }\n\
+                    record lock { owner: raw_ref mut int }\n\
+                    unsafe implement send for lock {}\n\
+                    @spawn[T: send](T task) {}\n\
+                    @publish[T: share](ref T value) {}\n\
+                    @lend[T: share](ref T value) { spawn(value) }\n\
+                    @forward[T](T value) { spawn(value) }\n\
+                    mut int x := 1\n\
+                    spawn(job { id: 1, done: AtomicInt::new(0), name: \"a\" })\n\
+                    spawn(node { value: 1, next: Option::None })\n\
+                    spawn(lock { owner: raw_ref mut x })\n\
+                    spawn(AtomicRef::new(raw_ref mut x))\n\
+                    spawn(handle { ptr: raw_ref mut x })\n\
+                    publish(ref lock { owner: raw_ref mut x })\n\
+                    spawn(ref mut x)\n\
+                    spawn(@() -> 1)",
+            errors: vec![
+                "the type `T` does not implement protocol `core::send`",
+                "the type `handle` does not implement protocol `core::send`",
+                "the type `lock` does not implement protocol `core::share`",
+                "the type `@()::int` does not implement protocol `core::send`",
+            ],
+        },
+        CheckCase {
+            name: "only thread markers are implemented unsafely",
+            input: "record r { a: int }\n\
+                    implement send for r {}\n\
+                    unsafe implement r {}\n\
+                    protoc live {}\n\
+                    unsafe implement live for r {}",
+            errors: vec![
+                "implementing `send` needs `unsafe implement`",
+                "only `send` and `share` are implemented with `unsafe implement`",
+                "only `send` and `share` are implemented with `unsafe implement`",
+            ],
+        },
+    ]);
+}
+
+#[test]
+fn test_c_functions() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "declarations, blocks and exports",
+            input: "#[repr(C)]\nrecord point { x: int, y: int }\n\
+                    extern \"C\" @abs(int n)::int\n\
+                    extern \"C\" {\n @printf(raw_ref byte format, ...)::int\n \
+                    @norm(point p)::float\n}\n\
+                    extern \"C\" @on_boot(raw_ref mut point p) {}\n\
+                    @main()::int {\n mut byte b := 1\n unsafe {\n  \
+                    printf(raw_ref b, 1, 2.5, true)\n  printf(raw_ref b)\n  \
+                    abs(-1) + 1\n }\n}",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "calls to foreign code are unsafe",
+            input: "extern \"C\" @abs(int n)::int\nint a := abs(-1)\n@(int)::int f := abs",
+            errors: vec![
+                "call to `extern` function `abs` is unsafe and requires an `unsafe` block",
+                "the `extern` function `@abs` can't be used as a value",
+            ],
+        },
+        CheckCase {
+            name: "only C types cross the boundary",
+            input: "record plain { a: int }\n\
+                    extern \"C\" @f(string s)::plain\n\
+                    extern \"C\" @g(int n, ...)\n\
+                    unsafe {\n g(1, plain { a: 1 })\n}",
+            errors: vec![
+                "`extern \"C\"` functions can't pass a `string`",
+                "`extern \"C\"` functions can't pass a `plain`",
+                "a `plain` can't be passed to C as a variadic argument",
+            ],
+        },
+        CheckCase {
+            name: "what can be extern",
+            input: "extern \"Rust\" @f()\n\
+                    extern \"C\" @g[T](T value) {}\n\
+                    #[interrupt]\nextern \"C\" @h() {}\n\
+                    #[no_mangle]\nextern \"C\" @i()\n\
+                    record r { a: int }\nimplement r {\n extern \"C\" @m() {}\n}",
+            errors: vec![
+                "unknown ABI `\"Rust\"`",
+                "`extern \"C\"` only applies to free functions without generic parameters",
+                "a function can't be both `#[interrupt]` and `extern \"C\"`",
+                "attributes don't apply to `extern` declarations",
+                "`extern \"C\"` only applies to free functions without generic parameters",
+            ],
+        },
+    ]);
+}
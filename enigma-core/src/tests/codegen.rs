@@ -0,0 +1,610 @@
+use super::interp::run_files;
+use super::mir::{PROGRAMS, lower};
+use crate::codegen::mono;
+use crate::codegen::x86::{self, Syntax};
+use crate::mir::opt::{self, OptLevel};
+use crate::target::Target;
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Compiles `source` at `level` to assembly in `syntax`.
+fn assembly(source: &str, level: OptLevel, syntax: Syntax) -> String {
+    let mut program = lower(source);
+    opt::optimize(&mut program, level, &mut |_, _| {}).unwrap();
+    x86::emit_asm(&program, Target::Hosted, syntax).unwrap_or_else(|error| panic!("{}", error))
+}
+
+/// The lines of the function `symbol`, from its label to the blank line
+/// after it.
+fn function<'a>(asm: &'a str, symbol: &str) -> Vec<&'a str> {
+    asm.lines()
+        .skip_while(|line| *line != format!("{}:", symbol))
+        .take_while(|line| !line.is_empty())
+        .collect()
+}
+
+#[test]
+fn test_symbols_keep_names_readable() {
+    use crate::checker::types::Ty;
+    assert_eq!(mono::symbol("human::new", &[]), "_EN.human.new");
+    assert_eq!(mono::symbol("id", &[Ty::Int]), "_EN.id$Lint$R");
+    assert_eq!(
+        mono::symbol("pair", &[Ty::Tuple(vec![Ty::Int, Ty::Str])]),
+        "_EN.pair$L$Pint$C_string$Q$R"
+    );
+    assert_eq!(mono::symbol("{init}", &[]), "_EN.$init");
+    assert_eq!(
+        mono::symbol("total::{closure#0}", &[]),
+        "_EN.total.$closure0"
+    );
+}
+
+#[test]
+fn test_att_and_intel_output() {
+    let source = "@max(int a, int b)::int {\n if a > b {\n  a\n } else {\n  b\n }\n}";
+    let att = assembly(source, OptLevel::O1, Syntax::Att);
+    assert_eq!(
+        function(&att, "_EN.max").join("\n"),
+        "_EN.max:
+\tpushq %rbp
+\tmovq %rsp, %rbp
+\tmovq %rdi, %rax
+\tmovq %rsi, %rcx
+.L1_0:
+\tcmpq %rcx, %rax
+\tsetg %dl
+\tmovzbl %dl, %edx
+\ttestb %dl, %dl
+\tjne .L1_1
+.L1_0_else:
+\tmovq %rcx, %rax
+.L1_1:
+\tmovq %rbp, %rsp
+\tpopq %rbp
+\tret
+\t.size _EN.max, .-_EN.max"
+    );
+    assert!(att.contains("\t.globl _EN.max\n\t.type _EN.max, @function\n"));
+    assert!(att.contains(".section .note.GNU-stack,\"\",@progbits"));
+
+    let intel = assembly(source, OptLevel::O1, Syntax::Intel);
+    assert_eq!(
+        function(&intel, "_EN.max").join("\n"),
+        "_EN.max:
+\tpush rbp
+\tmov rbp, rsp
+\tmov rax, rdi
+\tmov rcx, rsi
+.L1_0:
+\tcmp rax, rcx
+\tsetg dl
+\tmovzx edx, dl
+\ttest dl, dl
+\tjne .L1_1
+.L1_0_else:
+\tmov rax, rcx
+.L1_1:
+\tmov rsp, rbp
+\tpop rbp
+\tret"
+    );
+    assert!(intel.contains("global _EN.max:function\n"));
+}
+
+#[test]
+fn test_entry_runs_module_initializers() {
+    let asm = assembly("print(\"hi\")", OptLevel::O0, Syntax::Att);
+    let main = function(&asm, "main").join("\n");
+    let core = main
+        .find("call _EN.core.$init")
+        .expect("the prelude is initialized");
+    let init = main
+        .find("call _EN.$init")
+        .expect("the program is initialized");
+    assert!(core < init, "{}", main);
+    assert!(main.contains("movl $0, %eax"), "{}", main);
+    assert!(asm.contains("call printf@PLT"), "{}", asm);
+    assert!(
+        asm.contains("__enigma.bytes0:\n\t.ascii \"hi\"\n"),
+        "{}",
+        asm
+    );
+}
+
+#[test]
+fn test_spills_when_values_outnumber_registers() {
+    // Twenty values are live at the final sum, more than there are
+    // registers to hold them.
+    let mut source = String::from("@spill(int a)::int {\n");
+    for i in 0..20 {
+        source.push_str(&format!(" int v{} := a * {}\n", i, i + 2));
+    }
+    let sum: Vec<String> = (0..20).map(|i| format!("v{}", i)).collect();
+    source.push_str(&format!(" {}\n}}", sum.join(" + ")));
+    let asm = assembly(&source, OptLevel::O1, Syntax::Att);
+    let spill = function(&asm, "_EN.spill");
+    // Callee-saved registers hold values too, so they are saved and
+    // restored.
+    assert!(spill.contains(&"\tpushq %rbx"), "{:#?}", spill);
+    assert!(spill.contains(&"\tpopq %rbx"), "{:#?}", spill);
+    // Spilled values go through the scratch registers.
+    assert!(
+        spill
+            .iter()
+            .any(|line| line.contains("%r10") && line.contains("(%rbp)")),
+        "{:#?}",
+        spill
+    );
+    if let Some(output) = compile_and_run(&format!("{}\nprint(spill(1))", source)) {
+        assert_eq!(output, format!("{}\n", (2..22).sum::<i64>()));
+    }
+}
+
+#[test]
+fn test_unsupported_features_are_reported() {
+    let cases = [(
+        "print(1.5)",
+        "cannot compile `{init}`: printing `float` values aren't supported yet",
+    )];
+    for (source, expected) in cases {
+        let mut program = lower(source);
+        opt::optimize(&mut program, OptLevel::O0, &mut |_, _| {}).unwrap();
+        let error = x86::emit_asm(&program, Target::Hosted, Syntax::Att).expect_err(source);
+        assert_eq!(error.to_string(), expected);
+    }
+}
+
+/// Inline assembly with operands in chosen, named and scratch registers,
+/// and a callee-saved clobber. Prints 42, 1004, 0 and 42.
+pub(super) const ASM_OPERANDS: &str = "unsafe @add(int a, int b)::int {\n \
+     mut int sum := 0\n \
+     asm {\n  \"mov {sum}, {0}\"\n  \"add {sum}, {1}\"\n  in(reg) a\n  in(reg) b\n  sum = out(reg) sum\n }\n \
+     sum\n}\n\
+     unsafe @sumdiff(int x, int y)::int {\n \
+     mut int s := 0\n mut int d := 0\n \
+     asm {\n  \"mov {0}, {2}\"\n  \"add {0}, {3}\"\n  \"mov {1}, {2}\"\n  \"sub {1}, {3}\"\n  \"xor ebx, ebx\"\n  \
+     out(r10) s\n  out(reg) d\n  in(r11) x\n  in(reg) y\n  clobber(rbx)\n }\n \
+     s * 100 + d\n}\n\
+     unsafe @wrap(byte b)::byte {\n mut byte v := b\n asm { \"add {0:d}, 1\" inout(reg) v }\n v\n}\n\
+     unsafe {\n print(add(2, 40))\n print(sumdiff(7, 3))\n print(wrap(255))\n print(wrap(41))\n}";
+
+#[test]
+fn test_inline_assembly_operands_get_registers() {
+    let asm = assembly(ASM_OPERANDS, OptLevel::O0, Syntax::Att);
+    let add = function(&asm, "_EN.add").join("\n");
+    assert!(add.contains("\tmov rdx, rax\n\tadd rdx, rcx\n"), "{}", add);
+    let sumdiff = function(&asm, "_EN.sumdiff").join("\n");
+    assert!(sumdiff.contains("\tmov r10, r11\n"), "{}", sumdiff);
+    assert!(sumdiff.contains("pushq %rbx"), "{}", sumdiff);
+    let wrap = function(&asm, "_EN.wrap").join("\n");
+    assert!(wrap.contains("\tadd eax, 1\n"), "{}", wrap);
+
+    let Some(output) = compile_and_run(ASM_OPERANDS) else {
+        return;
+    };
+    assert_eq!(output, "42\n1004\n0\n42\n");
+}
+
+/// Assembles and links `source` with the system's C compiler and returns
+/// what it prints, or `None` if there is no C compiler to do it with.
+fn compile_and_run(source: &str) -> Option<String> {
+    let output = compile_and_exec(source)?;
+    Some(String::from_utf8(output.stdout).unwrap())
+}
+
+/// Like `compile_and_run`, but returns how the program ended too.
+fn compile_and_exec(source: &str) -> Option<Output> {
+    let asm = assembly(source, OptLevel::O2, Syntax::Att);
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    let run = RUNS.fetch_add(1, Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("enigma-codegen-{}-{}", std::process::id(), run));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (asm_path, exe_path) = (dir.join("main.s"), dir.join("main"));
+    std::fs::write(&asm_path, asm).unwrap();
+    let linked = Command::new("cc")
+        .arg(&asm_path)
+        .arg("-o")
+        .arg(&exe_path)
+        .status()
+        .ok()?;
+    assert!(linked.success(), "the assembly doesn't link");
+    let output = Command::new(&exe_path).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    Some(output)
+}
+
+#[test]
+fn test_a_match_no_arm_matches_is_a_runtime_error() {
+    let source = "@f(int x)::int {\n match x {\n  1: 10\n  2: 20\n }\n}\nprint(f(2))\nprint(f(3))";
+    let Some(output) = compile_and_exec(source) else {
+        return;
+    };
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "20\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "error: no `match` arm matches\n"
+    );
+    assert_eq!(output.status.code(), Some(101));
+}
+
+#[test]
+fn test_compiled_programs_print_what_the_interpreter_does() {
+    let programs = [
+        PROGRAMS[0],
+        PROGRAMS[1],
+        PROGRAMS[2],
+        PROGRAMS[3],
+        PROGRAMS[4],
+        "@fib(int n)::int {\n if n < 2 {\n  n\n } else {\n  fib(n - 1) + fib(n - 2)\n }\n}\n\
+         print(fib(15))",
+        "@apply(@(int)::int f, int v)::int -> f(v);\n\
+         @outer(int k)::int {\n mut int seen := 0\n \
+          @(int)::int f := @(x) {\n  seen += x\n  x + k\n }\n \
+          apply(f, 1) + seen\n}\n\
+         @dbl(int x)::int -> x * 2;\n\
+         print(outer(5))\nprint(apply(dbl, 21))",
+        "@g()::int {\n @(int)::int dbl := @(int x) {\n  x * 2\n }\n dbl(5)\n}\nprint(g())",
+        "union shape {\n circle(float)\n rect(int, int)\n}\n\
+         @area(shape s)::int {\n match s {\n  shape::circle(r): 3\n  shape::rect(w, h): w * h\n }\n}\n\
+         print(area(shape::rect(4, 5)))\nprint(area(shape::circle(1.5)))\n\
+         print(1.5 * 2.0 == 3.0)\nprint(-7 % 3)\nprint(\"a\" + \"b\" == \"ab\")",
+        "@many(int a, int b, int c, int d, int e, int f, int g, int h)::int \
+         -> a + b * 2 + c * 3 + d * 4 + e * 5 + f * 6 + g * 7 + h * 8;\n\
+         print(many(1, 2, 3, 4, 5, 6, 7, 8))\nbyte b := 200\nprint(b + 55)",
+        "protoc speak {\n @speak(ref self)::int\n @twice(ref self, int n)::int\n}\n\
+         record dog { age: int }\nrecord cat { lives: int, age: int }\n\
+         implement speak for dog {\n @speak(ref self)::int -> self::age;\n \
+          @twice(ref self, int n)::int -> self::age * n;\n}\n\
+         implement speak for cat {\n @speak(ref self)::int -> self::lives;\n \
+          @twice(ref self, int n)::int -> self::lives + n;\n}\n\
+         @talk(ref speak s)::int -> s::speak() + s::twice(10);\n\
+         dog d := dog { age: 3 }\ncat c := cat { lives: 9, age: 2 }\n\
+         print(talk(ref d))\nprint(talk(ref c))\nref speak s := ref c\nprint(s::speak())",
+    ];
+    for source in programs {
+        let expected = run_files(&[("proj/main.en", source)]).output;
+        let Some(output) = compile_and_run(source) else {
+            return;
+        };
+        assert_eq!(output, expected, "{}", source);
+    }
+}
+
+/// A freestanding program that exits through a system call with 42, or
+/// with 3 from its panic handler once `divisor` is 0.
+pub(super) fn freestanding(divisor: i64) -> String {
+    format!(
+        "#freestanding\nint answer := 40\n\
+         unsafe @sys_exit(int code) {{\n asm {{\n  \"syscall\"\n  in(rax) 60\n  in(rdi) code\n  \
+         options(noreturn)\n }}\n}}\n\
+         @start() {{\n int code := if \"abc\" == \"abc\" && \"abc\" != \"abd\" {{\n  \
+         answer + 2 / {}\n }} else {{\n  1\n }}\n unsafe {{\n  sys_exit(code)\n }}\n}}\n\
+         @panic(string message) {{\n unsafe {{\n  sys_exit(3)\n }}\n}}",
+        divisor
+    )
+}
+
+#[test]
+fn test_freestanding_programs_start_at_start() {
+    let mut program = lower(&freestanding(1));
+    opt::optimize(&mut program, OptLevel::O0, &mut |_, _| {}).unwrap();
+    let asm = x86::emit_asm(&program, Target::Freestanding, Syntax::Att).unwrap();
+    // `_start` comes first, and nothing needs the C library.
+    assert!(asm.starts_with("\t.text\n\n\t.globl _start\n"), "{}", asm);
+    assert!(!asm.contains("main") && !asm.contains("@PLT"), "{}", asm);
+    let start = function(&asm, "_start").join("\n");
+    let init = start
+        .find("call _EN.$init")
+        .expect("modules are initialized");
+    let entry = start.find("call _EN.start").expect("`@start` is called");
+    assert!(init < entry, "{}", start);
+    assert!(start.contains("\tcli\n\thlt\n"), "{}", start);
+    assert!(asm.contains("\trepe cmpsb\n"), "{}", asm);
+    // Division by zero calls the panic handler.
+    assert!(asm.contains("call _EN.panic"), "{}", asm);
+    assert!(
+        asm.contains("\t.section .multiboot2, \"a\"\n\t.balign 8\n\t.long 0xe85250d6, 0x0, 0x18, 0x17adaf12, 0x0, 0x8\n"),
+        "{}",
+        asm
+    );
+    assert!(asm.contains("_start32:\n\t.byte 0xfa\t# cli\n"), "{}", asm);
+
+    let intel = x86::emit_asm(&program, Target::Freestanding, Syntax::Intel).unwrap();
+    assert!(intel.contains("\tdd _start-4-$\n"), "{}", intel);
+    assert!(
+        intel.contains("section .bss nobits align=4096\n"),
+        "{}",
+        intel
+    );
+}
+
+#[test]
+fn test_the_whole_prelude_is_freestanding() {
+    // Every prelude item: `Option`, `Result` and `?`, ranges through
+    // `iterator`, and the atomics with their orderings.
+    let source = "#freestanding\nrecord odd {}\n\
+         @half(int n)::Result[int, odd] {\n \
+          if n % 2 == 0 { Result::Ok(n / 2) } else { Result::Err(odd {}) }\n}\n\
+         @quarter(int n)::Result[int, odd] -> Result::Ok(half(half(n)?)?);\n\
+         AtomicInt hits := AtomicInt::new(0)\n\
+         @start() {\n Option[int] three := Option::Some(3)\n \
+          for i in 0..three::extract() {\n  hits::fetch_add(i, Ordering::Relaxed)\n }\n \
+          hits::store(quarter(8)::extract(), Ordering::Release)\n \
+          mut int n := hits::load(Ordering::Acquire)\n \
+          AtomicRef[int] r := AtomicRef::new(raw_ref mut n)\n \
+          raw_ref mut int p := r::swap(r::load(Ordering::SeqCst), Ordering::AcqRel)\n \
+          int old := hits::compare_exchange(1, 2, Ordering::SeqCst, Ordering::Relaxed)::extract()\n}\n\
+         @panic(string message) {}";
+    let mut program = lower(source);
+    opt::optimize(&mut program, OptLevel::O2, &mut |_, _| {}).unwrap();
+    let compiled = x86::compile(&program, Target::Freestanding).unwrap();
+    assert_eq!(compiled.data.externs, Default::default());
+}
+
+/// Interrupt handlers, one taking an error code, entered as the processor
+/// would from user mode: `ss`, `rsp`, `rflags`, `cs` and the return address
+/// are pushed, then the error code, by a naked trampoline. `seven` is naked
+/// too, in a section of its own. Prints 9, then 109.
+pub(super) const INTERRUPTS: &str = "mut int ticks := 0\n\
+     #[interrupt]\n#[no_mangle]\n@tick() {\n unsafe {\n  ticks += 1\n }\n}\n\
+     #[interrupt]\n@fault(int code) {\n unsafe {\n  ticks += code\n }\n}\n\
+     #[naked]\n#[no_mangle]\n#[section(\".boot\")]\n#[align(16)]\n@seven()::int {\n \
+     unsafe {\n  asm { \"mov eax, 7\" \"ret\" options(noreturn) }\n }\n}\n\
+     #[naked]\n#[no_mangle]\n@deliver() {\n unsafe {\n  \
+     asm { \"push r10\" \"lea r11, [rip + _EN.fault]\" \"jmp r11\" options(noreturn) }\n }\n}\n\
+     unsafe @raise() {\n asm {\n  \"mov r11, rsp\"\n  \"push {0}\"\n  \"push r11\"\n  \"pushfq\"\n  \
+     \"push {1}\"\n  \"lea r11, [rip + tick]\"\n  \"call r11\"\n  in(reg) 43\n  in(reg) 51\n  clobber(r11)\n }\n}\n\
+     unsafe @fail(int code) {\n asm {\n  \"mov r11, rsp\"\n  \"push {0}\"\n  \"push r11\"\n  \"pushfq\"\n  \
+     \"push {1}\"\n  \"call deliver\"\n  in(reg) 43\n  in(reg) 51\n  in(r10) code\n  clobber(r11)\n }\n}\n\
+     unsafe {\n raise()\n raise()\n print(ticks + seven())\n fail(100)\n print(ticks + seven())\n}";
+
+#[test]
+fn test_interrupt_handlers_and_naked_functions() {
+    let asm = assembly(INTERRUPTS, OptLevel::O2, Syntax::Att);
+    // The handler saves what the body may change and returns with `iretq`.
+    let tick = function(&asm, "tick").join("\n");
+    assert!(tick.starts_with("tick:\n\tpushq %rbp\n"), "{}", tick);
+    assert!(tick.contains("\tfxsave64 [rsp]\n"), "{}", tick);
+    assert!(tick.contains("\tcall tick$body\n"), "{}", tick);
+    assert!(
+        tick.ends_with("\tpopq %rbp\n\t.intel_syntax noprefix\n\tiretq\n\t.att_syntax prefix\n\t.size tick, .-tick"),
+        "{}",
+        tick
+    );
+    assert!(!tick.contains("8(%rbp)"), "{}", tick);
+    // The error code is the body's argument, and popped before returning.
+    let fault = function(&asm, "_EN.fault").join("\n");
+    assert!(fault.contains("\tmovq 8(%rbp), %rdi\n"), "{}", fault);
+    assert!(fault.contains("\tcall _EN.fault$body\n"), "{}", fault);
+    assert!(
+        fault.contains("\taddq $8, %rsp\n\t.intel_syntax noprefix\n\tiretq\n"),
+        "{}",
+        fault
+    );
+    assert!(asm.contains("\n_EN.fault$body:\n"), "{}", asm);
+    // The naked function is its assembly alone, in its own section.
+    let seven = asm
+        .split("\t.section .boot, \"ax\", @progbits\n")
+        .nth(1)
+        .expect("`.boot` is a section");
+    assert!(
+        seven.starts_with(
+            "\n\t.balign 16, 0x90\n\t.globl seven\n\t.type seven, @function\nseven:\n\
+             \t.intel_syntax noprefix\n\tmov eax, 7\n\tret\n\t.att_syntax prefix\n\tud2\n"
+        ),
+        "{}",
+        seven
+    );
+
+    let Some(output) = compile_and_run(INTERRUPTS) else {
+        return;
+    };
+    assert_eq!(output, "9\n109\n");
+}
+
+/// Records laid out for hardware: a page table entry of bitfields, a packed
+/// descriptor, a C record and an aligned global, with their sizes and
+/// offsets.
+pub(super) const LAYOUTS: &str = "record entry {\n present: bool : 1\n writable: bool : 1\n \
+     level: byte : 3\n frame: int : 40\n spare: int : 16\n}\n\
+     #[packed]\nrecord gate {\n tag: byte\n offset: int\n selector: byte\n}\n\
+     #[repr(C)]\nrecord header {\n kind: byte\n len: int\n flags: byte\n}\n\
+     record mixed {\n kind: byte\n len: int\n flags: byte\n}\n\
+     #[align(64)]\nrecord line {\n x: int\n}\n\
+     mut line cache := line { x: 1 }\n\
+     @flip(ref mut entry e) {\n e::writable = !e::writable\n e::frame += 1\n}\n\
+     mut entry e := entry { present: true, writable: false, level: 13, frame: -1, spare: 7 }\n\
+     flip(ref mut e)\ne::level -= 1\n\
+     print(e::present)\nprint(e::writable)\nprint(e::level)\nprint(e::frame)\nprint(e::spare)\n\
+     mut gate g := gate { tag: 1, offset: 40, selector: 3 }\ng::offset += 2\n\
+     print(g::offset)\n\
+     cache::x += size_of[line]()\nprint(cache::x)\n\
+     print(size_of[entry]())\nprint(size_of[gate]())\nprint(align_of[gate]())\n\
+     print(size_of[header]())\nprint(offset_of(header, flags))\n\
+     print(size_of[mixed]())\nprint(offset_of(mixed, flags))";
+
+#[test]
+fn test_records_take_the_layout_they_ask_for() {
+    let asm = assembly(LAYOUTS, OptLevel::O0, Syntax::Att);
+    // Bitfields are read by shifting them to the bottom of a register.
+    assert!(asm.contains("\tshlq $24, "), "{}", asm);
+    assert!(asm.contains("\tshrq $24, "), "{}", asm);
+    assert!(asm.contains("\t.balign 64\n"), "{}", asm);
+
+    let expected = run_files(&[("proj/main.en", LAYOUTS)]).output;
+    assert_eq!(
+        expected,
+        "true\ntrue\n4\n0\n7\n42\n65\n8\n10\n1\n24\n16\n16\n9\n"
+    );
+    let Some(output) = compile_and_run(LAYOUTS) else {
+        return;
+    };
+    assert_eq!(output, expected);
+}
+
+/// Volatile accesses through raw references to a local and a global, with
+/// fences between them, and a driver for ports that is never called.
+/// Prints 8, 41 and 200.
+pub(super) const VOLATILE: &str = "mut int reg := 0\n\
+     unsafe @poke(raw_ref mut int p, int v) {\n volatile_write(p, v)\n volatile_write(p, v + 1)\n \
+     fence()\n}\n\
+     unsafe @serial(byte c)::int {\n outb(0x3f8, c)\n outw(0x3f8, 1)\n outl(0xcf8, 0x80000000)\n \
+     outb(0x80, inb(0x3fd))\n inw(0x60) + inl(0xcfc)\n}\n\
+     @main() {\n mut int x := 5\n mut byte b := 1\n unsafe {\n  \
+     raw_ref mut int p := raw_ref mut x\n  volatile_write(p, 7)\n  int unused := volatile_read(p)\n  \
+     volatile_write(p, 8)\n  load_fence()\n  store_fence()\n  print(volatile_read(p))\n  \
+     poke(raw_ref mut reg, 40)\n  print(volatile_read(raw_ref reg))\n  \
+     volatile_write(raw_ref mut b, 200)\n }\n print(b)\n}\nmain()";
+
+#[test]
+fn test_volatile_accesses_and_port_io_become_instructions() {
+    let asm = assembly(VOLATILE, OptLevel::O2, Syntax::Att);
+    // Both writes and the unused read are kept, in order.
+    let main = function(&asm, "_EN.main").join("\n");
+    let first = main.find("\tmovq $7, (").expect("the first write is kept");
+    let read = main[first..]
+        .find("\tmovq (")
+        .expect("the unused read is kept")
+        + first;
+    let second = main.find("\tmovq $8, (").expect("the second write is kept");
+    assert!(first < read && read < second, "{}", main);
+    assert!(main.contains("\tlfence\n\tsfence\n"), "{}", main);
+    let serial = function(&asm, "_EN.serial").join("\n");
+    for line in [
+        "\toutb %al, %dx\n",
+        "\toutw %ax, %dx\n",
+        "\toutl %eax, %dx\n",
+        "\tinb %dx, %al\n\tmovzbl %al, ",
+        "\tinw %dx, %ax\n\tmovzwl %ax, ",
+        "\tinl %dx, %eax\n",
+    ] {
+        assert!(serial.contains(line), "{}", serial);
+    }
+    let intel = assembly(VOLATILE, OptLevel::O2, Syntax::Intel);
+    assert!(intel.contains("\tout dx, al\n"), "{}", intel);
+    assert!(intel.contains("\tin eax, dx\n"), "{}", intel);
+
+    let expected = run_files(&[("proj/main.en", VOLATILE)]).output;
+    assert_eq!(expected, "8\n41\n200\n");
+    let Some(output) = compile_and_run(VOLATILE) else {
+        return;
+    };
+    assert_eq!(output, expected);
+}
+
+pub(super) const ATOMICS: &str = "AtomicInt hits := AtomicInt::new(5)\n\
+     mut byte small := 250\n\
+     mut int x := 4\n\
+     mut int y := 5\n\
+     @main() {\n \
+     print(hits::fetch_add(3, Ordering::Relaxed))\n \
+     hits::store(10, Ordering::Release)\n \
+     print(hits::swap(11, Ordering::AcqRel))\n \
+     print(hits::compare_exchange(11, 20, Ordering::SeqCst, Ordering::Relaxed)::extract())\n \
+     match hits::compare_exchange(11, 30, Ordering::SeqCst, Ordering::Acquire) {\n  \
+     Result::Ok(_): print(\"swapped\")\n  Result::Err(now): print(now)\n }\n \
+     hits::store(1, Ordering::SeqCst)\n \
+     print(hits::fetch_sub(4, Ordering::AcqRel))\n \
+     print(hits::load(Ordering::Acquire))\n \
+     unsafe {\n  \
+     AtomicRef[int] head := AtomicRef::new(raw_ref mut x)\n  \
+     print(deref head::swap(raw_ref mut y, Ordering::SeqCst))\n  \
+     print(deref head::load(Ordering::Relaxed))\n  \
+     print(atomic_fetch_add(raw_ref mut small, 10))\n  \
+     print(atomic_fetch_sub(raw_ref mut small, 5))\n  \
+     print(atomic_compare_exchange(raw_ref mut small, 255, 7))\n  \
+     print(atomic_load(raw_ref small))\n }\n}\nmain()";
+
+#[test]
+fn test_atomics_become_locked_instructions() {
+    let asm = assembly(ATOMICS, OptLevel::O2, Syntax::Att);
+    for line in [
+        "\tlock xaddq %",
+        "\tlock cmpxchgq %",
+        "\txchgq %",
+        "\tlock xaddb %",
+        "\tlock cmpxchgb %",
+    ] {
+        assert!(asm.contains(line), "{} in {}", line, asm);
+    }
+    // Atomic loads and release stores are plain moves.
+    assert!(!asm.contains("fence"), "{}", asm);
+    let intel = assembly(ATOMICS, OptLevel::O2, Syntax::Intel);
+    assert!(intel.contains("\tlock xadd qword ["), "{}", intel);
+
+    let expected = run_files(&[("proj/main.en", ATOMICS)]).output;
+    assert_eq!(expected, "5\n10\n11\n20\n1\n-3\n4\n5\n250\n4\n255\n7\n");
+    let Some(output) = compile_and_run(ATOMICS) else {
+        return;
+    };
+    assert_eq!(output, expected);
+}
+
+/// A program calling C, and called back from it, with records passed and
+/// returned by value in registers and on the stack.
+pub(super) const C_CALLS: &str = "#[repr(C)]\nrecord point {\n x: int\n y: int\n}\n\
+     #[repr(C)]\nrecord mixed {\n f: float\n n: int\n}\n\
+     #[repr(C)]\nrecord big {\n a: int\n b: int\n c: int\n}\n\
+     #[repr(C)]\nrecord text {\n a: byte\n b: byte\n c: byte\n d: byte\n}\n\
+     extern \"C\" {\n @sum(int count, ...)::int\n @make_point(int x, int y)::point\n \
+     @mixed_total(mixed m)::float\n @make_big(int a)::big\n @is_even(int n)::bool\n \
+     @call_back()::int\n \
+     @many(int a, int b, int c, int d, int e, point p, point q)::int\n}\n\
+     extern \"C\" @printf(raw_ref text format, ...)::int\n\
+     pub extern \"C\" @enigma_twice(int n)::int -> n * 2;\n\
+     pub extern \"C\" @enigma_point(point p)::point {\n point { x: p::y, y: p::x }\n}\n\
+     text format := text { a: 37, b: 100, c: 10, d: 0 }\n\
+     unsafe {\n \
+     print(sum(3, 10, 20, 30))\n \
+     point p := make_point(7, 9)\n print(p::y)\n \
+     print(mixed_total(mixed { f: 1.5, n: 2 }) == 3.5)\n \
+     big b := make_big(5)\n print(b::c)\n \
+     print(is_even(3))\n \
+     print(call_back())\n \
+     print(many(1, 2, 3, 4, 5, point { x: 1, y: 2 }, point { x: 3, y: 4 }))\n \
+     printf(raw_ref format, 6)\n}\n\
+     print(enigma_twice(4))";
+
+/// The C side of `C_CALLS`.
+pub(super) const C_HELPER: &str = "#include <stdarg.h>\n\
+     struct point { long x, y; };\n\
+     struct mixed { double f; long n; };\n\
+     struct big { long a, b, c; };\n\
+     long sum(long count, ...) {\n long t = 0;\n va_list ap;\n va_start(ap, count);\n \
+     while (count--) t += va_arg(ap, long);\n va_end(ap);\n return t;\n}\n\
+     struct point make_point(long x, long y) { return (struct point){x, y}; }\n\
+     double mixed_total(struct mixed m) { return m.f + m.n; }\n\
+     struct big make_big(long a) { return (struct big){a, a + 1, a + 2}; }\n\
+     _Bool is_even(long n) { return n % 2 == 0; }\n\
+     long enigma_twice(long);\n\
+     struct point enigma_point(struct point);\n\
+     long call_back(void) {\n struct point q = enigma_point((struct point){3, 4});\n \
+     return enigma_twice(21) + q.x * 100 + q.y;\n}\n\
+     long many(long a, long b, long c, long d, long e, struct point p, struct point q) {\n \
+     return a + b + c + d + e + p.x * 10 + p.y * 100 + q.x * 1000 + q.y * 10000;\n}\n";
+
+#[test]
+fn test_c_functions_follow_the_c_calling_convention() {
+    let asm = assembly(C_CALLS, OptLevel::O0, Syntax::Att);
+    // Foreign functions are called through the PLT, variadic ones with the
+    // number of vector registers used in `al`.
+    assert!(asm.contains("\tmovl $0, %eax\n\tcall sum@PLT\n"), "{}", asm);
+    assert!(asm.contains("\tcall make_point@PLT\n"), "{}", asm);
+    // A two-eightbyte record comes back in `rax` and `rdx`, and a larger one
+    // through a pointer to the caller's memory in `rdi`.
+    let pieces = asm.split("\tcall make_point@PLT\n").nth(1).unwrap();
+    assert!(pieces.starts_with("\tmovq %rax, "), "{}", pieces);
+    assert!(
+        pieces.lines().nth(1).unwrap().starts_with("\tmovq %rdx, "),
+        "{}",
+        pieces
+    );
+    let big = asm.split("\tcall make_big@PLT\n").next().unwrap();
+    assert!(big.ends_with("\tmovq $5, %rsi\n"), "{}", big);
+    // The exports keep their names, and take their record in registers.
+    assert!(asm.contains("\t.globl enigma_twice\n"), "{}", asm);
+    let point = function(&asm, "enigma_point").join("\n");
+    assert!(point.contains("%rdi, "), "{}", point);
+    assert!(point.contains("%rsi, "), "{}", point);
+    assert!(!asm.contains("_EN.enigma_point"), "{}", asm);
+}
@@ -0,0 +1,229 @@
+use crate::checker::{self, check_program};
+use crate::errorhandler::ErrorHandler;
+use crate::hir::{self, BodyOwner, Module};
+use crate::{loader, parser};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+fn lower(source: &str) -> Module {
+    let mut handler = ErrorHandler::new();
+    let program = parser::parse(source, &mut handler);
+    let (items, results) = check_program(&program, &mut handler);
+    assert!(
+        !handler.has_errors(),
+        "test input failed to check: {:?}",
+        handler.diagnostics()
+    );
+    hir::lower_module(&program, &items, &results)
+}
+
+/// The printed body of the function or method named `name`.
+fn body(module: &Module, name: &str) -> String {
+    module
+        .functions
+        .iter()
+        .find(|body| matches!(&body.owner, BodyOwner::Function(func) if func.name == name))
+        .unwrap_or_else(|| panic!("no function `{}`", name))
+        .to_string()
+}
+
+#[test]
+fn test_loops_become_loop() {
+    let module = lower(
+        "@sum(int n)::int {\n mut int total := 0\n for i in 0..n {\n  total += i\n }\n \
+         while total > 10 {\n  total--\n }\n total\n}",
+    );
+    assert_eq!(
+        body(&module, "sum"),
+        "@sum(n#0: int)::int {
+    let mut total#1: int := 0
+    {
+        let mut iter#2: Range[int] := 0..n#0
+        loop {
+            match <Range[int] as core::iterator>::next[int](ref mut iter#2) {
+                core::Option::Some(i#3): {
+                    total#1 = (total#1 + i#3)
+                }
+                core::Option::None: break
+            }
+        }
+    }
+    loop {
+        if (total#1 > 10) {
+            total#1 = (total#1 - 1)
+        } else {
+            break
+        }
+    }
+    total#1
+}
+"
+    );
+}
+
+#[test]
+fn test_question_mark_becomes_match() {
+    let module = lower(
+        "record parse_error {\n line: int\n}\nrecord io_error {\n code: int\n}\n\
+         implement into_error[io_error] for parse_error {\n\
+          @into_error(self)::io_error -> io_error { code: self::line };\n}\n\
+         @parse()::Result[int, parse_error] -> Result::Ok(1);\n\
+         @load()::Result[int, io_error] -> Result::Ok(parse()? + 1);\n\
+         @half(Option[int] o)::Option[int] -> Option::Some(o? / 2);",
+    );
+    assert_eq!(
+        body(&module, "load"),
+        "@load()::core::Result[int, io_error] {
+    return core::Result::Ok((match parse() {
+        core::Result::Ok(value#0): value#0
+        core::Result::Err(error#1): return core::Result::Err(<parse_error as core::into_error>::into_error[io_error](error#1))
+    } + 1))
+}
+"
+    );
+    assert_eq!(
+        body(&module, "half"),
+        "@half(o#0: core::Option[int])::core::Option[int] {
+    return core::Option::Some((match o#0 {
+        core::Option::Some(value#1): value#1
+        core::Option::None: return core::Option::None
+    } / 2))
+}
+"
+    );
+}
+
+#[test]
+fn test_receivers_fields_and_arguments_are_explicit() {
+    let module = lower(
+        "record human {\n name: string\n age: int\n nick: Option[string]\n}\n\
+         implement human {\n\
+          @older(ref mut self) {\n  self::age += 1\n }\n\
+          @named(self)::string -> self::name;\n\
+         }\n\
+         @sub(int from%a, int take%b)::int -> a - b;\n\
+         @both(bool a, bool b)::bool -> a && !b || b;\n\
+         @use(ref human r, int n)::int {\n\
+          mut human h := human { age: sub(take: n, from: 10), name: r::named() }\n\
+          h::older()\n\
+          h::age\n\
+         }",
+    );
+    assert_eq!(
+        body(&module, "older"),
+        "@human::older(self#0: ref mut human)::unit {
+    (deref self#0)::age = ((deref self#0)::age + 1)
+}
+"
+    );
+    assert_eq!(
+        body(&module, "both"),
+        "@both(a#0: bool, b#1: bool)::bool {
+    return if if a#0 {
+        !b#1
+    } else {
+        false
+    } {
+        true
+    } else {
+        b#1
+    }
+}
+"
+    );
+    assert_eq!(
+        body(&module, "use"),
+        "@use(r#0: ref human, n#1: int)::int {
+    let mut h#4: human := {
+        let arg#2: int := sub(10, n#1)
+        let arg#3: string := human::named((deref r#0))
+        human { name: arg#3, age: arg#2, nick: core::Option::None }
+    }
+    human::older(ref mut h#4)
+    h#4::age
+}
+"
+    );
+}
+
+#[test]
+fn test_reordered_arguments_keep_evaluation_order() {
+    let module = lower(
+        "@sub(int from%a, int take%b)::int -> a - b;\n\
+         @one()::int -> 1;\n@two()::int -> 2;\n\
+         int v := sub(take: one(), from: two())",
+    );
+    assert_eq!(
+        module.init.to_string(),
+        "init {
+    v = {
+        let arg#0: int := one()
+        let arg#1: int := two()
+        sub(arg#1, arg#0)
+    }
+}
+"
+    );
+}
+
+#[test]
+fn test_compound_assignment_evaluates_the_place_once() {
+    let module = lower(
+        "record counter {\n n: int\n}\n\
+         @pick(ref mut counter c)::ref mut int -> ref mut c::n;\n\
+         @bump(ref mut counter c) {\n deref pick(c) += 1\n}",
+    );
+    assert_eq!(
+        body(&module, "bump"),
+        "@bump(c#0: ref mut counter)::unit {
+    let place#1: ref mut int := ref mut (deref pick(c#0))
+    (deref place#1) = ((deref place#1) + 1)
+}
+"
+    );
+}
+
+#[test]
+fn test_graph_lowers_every_module_in_order() {
+    let provider: HashMap<PathBuf, String> = [
+        (
+            "proj/main.en",
+            "get module util\nprint(util::twice(util::base))",
+        ),
+        (
+            "proj/util.en",
+            "pub int base := 21\npub @twice(int n)::int -> n * 2;",
+        ),
+    ]
+    .into_iter()
+    .map(|(path, source)| (PathBuf::from(path), source.to_string()))
+    .collect();
+    let mut handler = ErrorHandler::new();
+    let graph = loader::load(Path::new("proj/main.en"), &[], &provider, &mut handler).unwrap();
+    let checked = checker::check_graph(&graph, &mut handler);
+    assert!(!handler.has_errors(), "{:?}", handler.diagnostics());
+    let modules = hir::lower_graph(&graph, &checked);
+    let names: Vec<&str> = modules.iter().map(|m| m.name.as_str()).collect();
+    assert_eq!(names, vec!["core", "util", ""]);
+    assert_eq!(
+        modules[1].to_string(),
+        "module util
+global util::base: int
+
+@util::twice(n#0: int)::int {
+    return (n#0 * 2)
+}
+
+init {
+    util::base = 21
+}
+"
+    );
+    assert_eq!(
+        modules[2].init.to_string(),
+        "init {
+    print(util::twice(util::base))
+}
+"
+    );
+}
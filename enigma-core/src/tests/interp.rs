@@ -0,0 +1,311 @@
+use crate::checker;
+use crate::errorhandler::{Diagnostic, ErrorHandler};
+use crate::interp::{self, CALL_DEPTH_LIMIT};
+use crate::loader;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// What running a program produced.
+pub(super) struct Run {
+    pub(super) output: String,
+    result: Result<i32, Diagnostic>,
+}
+
+/// Checks and runs `proj/main.en` from an in-memory tree.
+pub(super) fn run_files(files: &[(&str, &str)]) -> Run {
+    let provider: HashMap<PathBuf, String> = files
+        .iter()
+        .map(|(path, source)| (PathBuf::from(path), source.to_string()))
+        .collect();
+    let mut handler = ErrorHandler::new();
+    let graph = loader::load(Path::new("proj/main.en"), &[], &provider, &mut handler)
+        .expect("entry file exists");
+    let checked = checker::check_graph(&graph, &mut handler);
+    assert!(
+        !handler.has_errors(),
+        "test input failed to check: {:?}",
+        handler.diagnostics()
+    );
+    let mut output = Vec::new();
+    // Errors come back with spans relative to the file they point into.
+    let result = interp::run(&graph, &checked, &mut output).map_err(|error| {
+        let mut diagnostic = error.to_diagnostic(&graph.sources);
+        let file = graph.sources.lookup(diagnostic.span.start).unwrap();
+        diagnostic.span = file.local(diagnostic.span);
+        diagnostic
+    });
+    Run {
+        output: String::from_utf8(output).unwrap(),
+        result,
+    }
+}
+
+fn run(source: &str) -> Run {
+    run_files(&[("proj/main.en", source)])
+}
+
+#[test]
+fn test_values_and_control_flow() {
+    let cases = [
+        (
+            "arithmetic",
+            "print(1 + 2 * 3)\nprint(7 % 3)\nprint(1.5 * 2.0)",
+            "7\n1\n3.0\n",
+        ),
+        (
+            "strings, chars and tuples",
+            "print(\"a\" + \"b\")\nprint('c')\nprint((1, \"x\", true))",
+            "ab\nc\n(1, \"x\", true)\n",
+        ),
+        (
+            "bytes wrap into their own type",
+            "byte b := 250\nprint(b + 5)",
+            "255\n",
+        ),
+        (
+            "logical operators",
+            "print(true && true)\nprint(true && false)\nprint(false && true)\nprint(false && false)\n\
+             print(true || true)\nprint(true || false)\nprint(false || true)\nprint(false || false)",
+            "true\nfalse\nfalse\nfalse\ntrue\ntrue\ntrue\nfalse\n",
+        ),
+        (
+            "if and else",
+            "int x := 3\nif x > 2 {\n print(\"big\")\n} else {\n print(\"small\")\n}",
+            "big\n",
+        ),
+        (
+            "while and for",
+            "mut int i := 0\nwhile i < 3 {\n i++\n}\nmut int sum := 0\nfor k in 0..4 {\n sum += k\n}\nprint(i)\nprint(sum)",
+            "3\n6\n",
+        ),
+        (
+            "loops produce their break value",
+            "mut int i := 1\nint v := loop {\n i *= 2\n if i > 20 {\n  break i\n }\n}\nprint(v)",
+            "32\n",
+        ),
+        (
+            "continue skips the rest of the body",
+            "for k in 0..5 {\n if k % 2 == 0 {\n  continue\n }\n print(k)\n}",
+            "1\n3\n",
+        ),
+        (
+            "recursion",
+            "@fib(int n)::int {\n if n < 2 {\n  return n\n }\n fib(n - 1) + fib(n - 2)\n}\nprint(fib(15))",
+            "610\n",
+        ),
+        (
+            "labelled arguments",
+            "@sub(int from%a, int take%b)::int -> a - b;\nprint(sub(take: 1, from: 10))",
+            "9\n",
+        ),
+        (
+            "tuple destructuring",
+            "(int, string) t := (4, \"four\")\n(int n, string s) $= t\nprint(s)\nprint(n)",
+            "four\n4\n",
+        ),
+        (
+            "string interpolation",
+            "string name := \"Ravi\"\nprint(\"Mai ${name} hun\")\n\
+             print(\"${name + \"${\"!\"}\"}\")\nprint(\"\\${name}\")",
+            "Mai Ravi hun\nRavi!\n${name}\n",
+        ),
+    ];
+    for (name, source, expected) in cases {
+        let run = run(source);
+        assert_eq!(run.result, Ok(0), "Test {} failed", name);
+        assert_eq!(run.output, expected, "Test {} failed", name);
+    }
+}
+
+#[test]
+fn test_records_unions_and_methods() {
+    let source = "record human {\n name: string\n age: int\n nick: Option[string]\n}\n\
+                  implement human {\n\
+                   @new(string name)::human -> human { name: name, age: 0 };\n\
+                   @older(ref mut self) {\n self::age += 1\n }\n\
+                   @label(ref self)::string {\n match self::nick {\n  Option::Some(n): n\n  Option::None: self::name\n }\n }\n\
+                  }\n\
+                  mut human h := human::new(\"al\")\n\
+                  h::older()\nh::older()\n\
+                  print(h::age)\nprint(h::label())\n\
+                  ref mut int age := ref mut h::age\nderef age = 30\nprint(h)";
+    let run = run(source);
+    assert_eq!(run.result, Ok(0));
+    assert_eq!(
+        run.output,
+        "2\nal\nhuman { name: \"al\", age: 30, nick: None }\n"
+    );
+}
+
+#[test]
+fn test_protocol_calls_dispatch_on_the_receiver() {
+    let source = "protoc live {\n @eat(ref self)::string\n}\n\
+                  record cat {\n}\nrecord dog {\n}\n\
+                  implement live for cat {\n @eat(ref self)::string -> \"fish\";\n}\n\
+                  implement live for dog {\n @eat(ref self)::string -> \"bones\";\n}\n\
+                  @feed[T: live](ref T animal)::string -> animal::eat();\n\
+                  @feed_object(ref live animal)::string -> animal::eat();\n\
+                  cat c := cat {}\ndog d := dog {}\n\
+                  print(feed(ref c))\nprint(feed_object(ref d))";
+    let run = run(source);
+    assert_eq!(run.result, Ok(0));
+    assert_eq!(run.output, "fish\nbones\n");
+}
+
+#[test]
+fn test_closures_share_captured_locals() {
+    let source = "@total()::int {\n\
+                   mut int count := 0\n\
+                   @(int)::int add := @(n) {\n  count += n\n  count\n }\n\
+                   add(2)\n add(3)\n count\n}\n\
+                  print(total())\n\
+                  @apply(@(int)::int f, int v)::int -> f(v);\n\
+                  print(apply(@(x) -> x * 10, 4))";
+    let run = run(source);
+    assert_eq!(run.result, Ok(0));
+    assert_eq!(run.output, "5\n40\n");
+}
+
+#[test]
+fn test_question_mark_returns_early() {
+    let source = "record parse_error {\n line: int\n}\nrecord io_error {\n code: int\n}\n\
+                  implement into_error[io_error] for parse_error {\n\
+                   @into_error(self)::io_error -> io_error { code: self::line * 100 };\n\
+                  }\n\
+                  @parse(int n)::Result[int, parse_error] {\n\
+                   if n < 0 {\n  return Result::Err(parse_error { line: 7 })\n }\n Result::Ok(n)\n}\n\
+                  @load(int n)::Result[int, io_error] {\n int v := parse(n)?\n Result::Ok(v + 1)\n}\n\
+                  @half(Option[int] o)::Option[int] -> Option::Some(o? / 2);\n\
+                  print(load(1))\nprint(load(-1))\nprint(half(Option::Some(8)))\nprint(half(Option::None))";
+    let run = run(source);
+    assert_eq!(run.result, Ok(0));
+    assert_eq!(
+        run.output,
+        "Ok(2)\nErr(io_error { code: 700 })\nSome(4)\nNone\n"
+    );
+}
+
+#[test]
+fn test_exit_and_modules() {
+    let run = run_files(&[
+        (
+            "proj/main.en",
+            "get module util\nprint(util::twice(util::base))\nexit(3)\nprint(\"unreachable\")",
+        ),
+        (
+            "proj/util.en",
+            "pub int base := 21\npub @twice(int n)::int -> n * 2;\nprint(\"util loaded\")",
+        ),
+    ]);
+    assert_eq!(run.result, Ok(3));
+    assert_eq!(run.output, "util loaded\n42\n");
+}
+
+#[test]
+fn test_runtime_errors_report_the_span_and_call_stack() {
+    let source = "@div(int a, int b)::int -> a / b;\n\
+                  @mean(int total, int count)::int -> div(total, count);\n\
+                  print(mean(10, 2))\nint m := mean(1, 0)";
+    let run = run(source);
+    assert_eq!(run.output, "5\n");
+    let error = run.result.expect_err("division by zero");
+    assert_eq!(error.message, "attempt to divide by zero");
+    assert_eq!(&source[error.span.start..error.span.end], "a / b");
+    assert_eq!(
+        error.notes,
+        vec![
+            "in `div`, called from proj/main.en:2:37",
+            "in `mean`, called from proj/main.en:4:10",
+        ]
+    );
+}
+
+#[test]
+fn test_extract_on_none_points_at_the_caller() {
+    let source = "@first(Option[int] o)::int -> o::extract();\nint v := first(Option::None)";
+    let run = run(source);
+    let error = run.result.expect_err("extract on None");
+    assert_eq!(error.message, "called `extract` on `None`");
+    assert_eq!(&source[error.span.start..error.span.end], "o::extract()");
+    assert_eq!(
+        error.notes,
+        vec!["in `first`, called from proj/main.en:2:10"]
+    );
+}
+
+#[test]
+fn test_volatile_accesses_run_but_port_io_does_not() {
+    let source = "mut int x := 1\nunsafe {\n volatile_write(raw_ref mut x, 41)\n fence()\n \
+                  print(volatile_read(raw_ref x) + 1)\n outb(0x80, 0)\n}";
+    let run = run(source);
+    assert_eq!(run.output, "42\n");
+    let error = run.result.expect_err("port I/O");
+    assert_eq!(error.message, "port I/O can't be run by the interpreter");
+    assert_eq!(&source[error.span.start..error.span.end], "outb(0x80, 0)");
+}
+
+#[test]
+fn test_exported_functions_run_but_foreign_ones_do_not() {
+    let source = "extern \"C\" @abs(int n)::int\n\
+                  pub extern \"C\" @twice(int n)::int -> n * 2;\n\
+                  print(twice(4))\nunsafe {\n print(abs(-1))\n}";
+    let run = run(source);
+    assert_eq!(run.output, "8\n");
+    let error = run.result.expect_err("a foreign call");
+    assert_eq!(
+        error.message,
+        "`abs` is defined by foreign code, which only compiled programs can call"
+    );
+    assert_eq!(&source[error.span.start..error.span.end], "abs(-1)");
+}
+
+#[test]
+fn test_atomics_take_any_ordering_the_checker_allows() {
+    // The checker rejects the rest, so nothing is checked at runtime; the
+    // success ordering of `compare_exchange` may be anything.
+    let source = "AtomicInt n := AtomicInt::new(1)\nprint(n::fetch_add(1, Ordering::Relaxed))\n\
+                  Ordering order := Ordering::Release\n\
+                  n::store(3, Ordering::SeqCst)\nprint(n::load(Ordering::Acquire))\n\
+                  n::store(4, Ordering::Release)\n\
+                  print(n::compare_exchange(4, 5, order, Ordering::Relaxed)::extract())\n\
+                  print(n::load(Ordering::Relaxed))";
+    let run = run(source);
+    assert_eq!(run.result, Ok(0));
+    assert_eq!(run.output, "1\n3\n4\n5\n");
+}
+
+#[test]
+fn test_overflow_and_runaway_recursion_are_errors() {
+    let overflow = run("int big := 9223372036854775807\nint v := big + 1");
+    let error = overflow.result.expect_err("overflow");
+    assert_eq!(error.message, "attempt to add with overflow");
+
+    // Deep interpreter recursion needs more than a test thread's stack.
+    let handle = std::thread::Builder::new()
+        .stack_size(1 << 30)
+        .spawn(|| run("@down(int n)::int -> down(n + 1);\nint v := down(0)").result)
+        .unwrap();
+    let error = handle.join().unwrap().expect_err("stack overflow");
+    assert_eq!(
+        error.message,
+        format!(
+            "stack overflow: more than {} calls are active",
+            CALL_DEPTH_LIMIT
+        )
+    );
+    assert_eq!(error.notes.len(), CALL_DEPTH_LIMIT);
+}
+
+#[test]
+fn test_for_loops_step_through_user_iterators() {
+    let source = "record countdown {\n from: int\n}\n\
+                  implement iterator[int] for countdown {\n\
+                   @next(ref mut self)::Option[int] {\n\
+                    if self::from == 0 {\n   return Option::None\n  }\n\
+                    self::from -= 1\n  Option::Some(self::from + 1)\n }\n}\n\
+                  countdown c := countdown { from: 3 }\n\
+                  for n in c {\n print(n)\n}";
+    let run = run(source);
+    assert_eq!(run.result, Ok(0));
+    assert_eq!(run.output, "3\n2\n1\n");
+}
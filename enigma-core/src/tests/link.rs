@@ -0,0 +1,310 @@
+use super::codegen::freestanding;
+use super::mir::lower;
+use crate::codegen::elf::{
+    self, Binding, Executable, Object, R_X86_64_32, R_X86_64_64, R_X86_64_PC32, R_X86_64_PLT32,
+    Reloc, SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, Section, SectionKind, Symbol, SymbolKind,
+};
+use crate::codegen::link::{self, FLAT, KERNEL, Script, Statement};
+use crate::codegen::x86;
+use crate::mir::opt::{self, OptLevel};
+use crate::target::Target;
+use std::os::unix::fs::PermissionsExt;
+use std::process::Command;
+
+/// A kernel saying hello on the first serial port.
+const HELLO: &str = "#freestanding\n\
+     @put(byte c) {\n unsafe {\n  outb(0x3f8, c)\n }\n}\n\
+     @start() {\n put(104)\n put(101)\n put(108)\n put(108)\n put(111)\n put(10)\n}\n\
+     @panic(string message) {\n}";
+
+fn kernel_object(source: &str) -> Object {
+    let mut program = lower(source);
+    opt::optimize(&mut program, OptLevel::O2, &mut |_, _| {}).unwrap();
+    let bytes = x86::emit_object(&program, Target::Freestanding).unwrap();
+    elf::read(&bytes).unwrap()
+}
+
+fn symbol(name: &str, section: Option<usize>, value: u64, binding: Binding) -> Symbol {
+    Symbol {
+        name: name.to_string(),
+        section,
+        value,
+        size: 0,
+        binding,
+        kind: SymbolKind::NoType,
+    }
+}
+
+fn address(executable: &Executable, name: &str) -> u64 {
+    let symbol = executable.symbols.iter().find(|s| s.name == name).unwrap();
+    symbol.value
+}
+
+/// The `len` bytes at `address` in `executable`'s memory.
+fn memory(executable: &Executable, address: u64, len: usize) -> &[u8] {
+    let (section, start) = executable
+        .sections
+        .iter()
+        .zip(&executable.addresses)
+        .find(|(section, start)| address >= **start && address < **start + section.size)
+        .unwrap();
+    let at = (address - start) as usize;
+    &section.data[at..at + len]
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+#[test]
+fn test_linker_descriptions_parse() {
+    let script = Script::parse(KERNEL).unwrap();
+    assert_eq!(script.entry.as_deref(), Some("_start32"));
+    assert_eq!(script.statements[0], Statement::At(0x100000));
+    assert_eq!(
+        script.statements[2],
+        Statement::Section(".text".to_string(), vec![".text".into(), ".text.*".into()])
+    );
+    assert_eq!(Script::parse(FLAT).unwrap().discard, [".multiboot2"]);
+
+    let script = Script::parse("# a comment\n\nalign 16 # aligned\nsection .boot").unwrap();
+    assert_eq!(script.entry, None);
+    assert_eq!(script.statements.len(), 2);
+    for (text, error) in [
+        ("at 1 MiB", "line 1: wrong operands for `at`"),
+        ("\nalign 12", "line 2: expected a power of two"),
+        ("at 0x10zz", "line 1: expected an address"),
+        ("load .text", "line 1: unknown statement `load`"),
+        ("discard", "line 1: wrong operands for `discard`"),
+    ] {
+        assert_eq!(Script::parse(text).unwrap_err(), error, "{}", text);
+    }
+}
+
+#[test]
+fn test_objects_link_across_each_other() {
+    // `a.o` calls `f` and keeps its address; `b.o` defines it.
+    let mut text = Section::new(".text", SectionKind::Progbits, SHF_ALLOC | SHF_EXECINSTR, 1);
+    text.append(&[0xe8, 0, 0, 0, 0, 0xc3]);
+    text.relocs.push(Reloc {
+        offset: 1,
+        symbol: 0,
+        kind: R_X86_64_PLT32,
+        addend: -4,
+    });
+    let mut data = Section::new(".data", SectionKind::Progbits, SHF_ALLOC | SHF_WRITE, 8);
+    data.append(&[0; 12]);
+    data.relocs.push(Reloc {
+        offset: 0,
+        symbol: 0,
+        kind: R_X86_64_64,
+        addend: 1,
+    });
+    data.relocs.push(Reloc {
+        offset: 8,
+        symbol: 1,
+        kind: R_X86_64_32,
+        addend: 0,
+    });
+    let mut bss = Section::new(".bss", SectionKind::Nobits, SHF_ALLOC | SHF_WRITE, 16);
+    bss.reserve(32);
+    let a = Object {
+        sections: vec![text, data, bss],
+        symbols: vec![
+            symbol("f", None, 0, Binding::Global),
+            symbol("buffer", Some(2), 0, Binding::Local),
+            symbol("_start", Some(0), 0, Binding::Global),
+        ],
+    };
+
+    let mut text = Section::new(
+        ".text",
+        SectionKind::Progbits,
+        SHF_ALLOC | SHF_EXECINSTR,
+        16,
+    );
+    text.append(&[0x90, 0xc3]);
+    let mut table = Section::new(".table", SectionKind::Progbits, SHF_ALLOC, 4);
+    table.append(&[1, 2, 3, 4]);
+    let mut note = Section::new(".note.GNU-stack", SectionKind::Progbits, 0, 1);
+    note.append(&[0xff]);
+    let b = Object {
+        sections: vec![text, table, note],
+        symbols: vec![
+            symbol("f", Some(0), 1, Binding::Global),
+            symbol("_start", Some(0), 0, Binding::Weak),
+        ],
+    };
+
+    let objects = [("a.o".to_string(), a), ("b.o".to_string(), b)];
+    let script =
+        Script::parse("at 0x400000\nsection .text\nalign 4096\nsection .data\nsection .bss")
+            .unwrap();
+    let executable = link::link(&objects, &script).unwrap();
+    let names: Vec<&str> = executable
+        .sections
+        .iter()
+        .map(|s| s.name.as_str())
+        .collect();
+    // The orphan `.table` follows the last section with contents, and the
+    // unallocated note is left out.
+    assert_eq!(names, [".text", ".data", ".table", ".bss"]);
+    assert_eq!(
+        executable.addresses,
+        [0x400000, 0x401000, 0x40100c, 0x401010]
+    );
+    // `b.o`'s `.text` is aligned to 16 after `a.o`'s, and the global `_start`
+    // wins over the weak one.
+    let f = address(&executable, "f");
+    assert_eq!(f, 0x400011);
+    assert_eq!(executable.entry, 0x400000);
+    assert_eq!(
+        memory(&executable, 0x400001, 4),
+        (f as i32 - 0x400005).to_le_bytes()
+    );
+    assert_eq!(memory(&executable, 0x401000, 8), (f + 1).to_le_bytes());
+    assert_eq!(memory(&executable, 0x401008, 4), 0x401010u32.to_le_bytes());
+
+    // Segments don't share pages.
+    let segments = executable.segments();
+    assert_eq!(segments.len(), 2);
+    assert_eq!(
+        (
+            segments[1].address,
+            segments[1].file_size,
+            segments[1].memory_size
+        ),
+        (0x401000, 0x10, 0x30)
+    );
+    let binary = link::flat_binary(&executable);
+    assert_eq!(binary.len(), 0x1010);
+    assert_eq!(&binary[0x10..0x12], [0x90, 0xc3]);
+
+    let mut broken = objects.clone();
+    broken[1].1.symbols[0].binding = Binding::Local;
+    broken[1]
+        .1
+        .symbols
+        .push(symbol("_start", Some(0), 0, Binding::Global));
+    let errors = link::link(&broken, &script).unwrap_err();
+    assert_eq!(
+        errors,
+        [
+            "`_start` is defined in both `a.o` and `b.o`",
+            "undefined symbol `f`, used in `a.o`",
+        ]
+    );
+    let errors = link::link(&objects, &Script::parse("entry main\nat 0x400000").unwrap());
+    assert_eq!(errors.unwrap_err(), ["the entry `main` isn't defined"]);
+    let errors = link::link(
+        &objects,
+        &Script::parse("at 0x400000\nsection .data\nat 0x400008\nsection .table").unwrap(),
+    );
+    assert_eq!(errors.unwrap_err(), ["`.data` and `.table` overlap"]);
+    let errors = link::link(
+        &objects,
+        &Script::parse("at 0x100000000\nsection .text\nsection .data").unwrap(),
+    );
+    assert_eq!(
+        errors.unwrap_err(),
+        ["the address of `buffer` doesn't fit where `.data` of `a.o` needs it"]
+    );
+}
+
+#[test]
+fn test_hello_kernel_links_with_its_header_first() {
+    let object = kernel_object(HELLO);
+    let objects = [("hello.o".to_string(), object.clone())];
+    let executable = link::link(&objects, &Script::parse(KERNEL).unwrap()).unwrap();
+    assert_eq!(executable.entry, address(&executable, "_start32"));
+    assert_eq!(executable.sections[0].name, ".multiboot2");
+    assert_eq!(executable.addresses[0], 0x100000);
+
+    // Every field relocated holds what its relocation asks for.
+    let text = object.section(".text").unwrap();
+    let base = executable.addresses[1];
+    assert_eq!(executable.sections[1].name, ".text");
+    for reloc in &text.relocs {
+        let symbol = &object.symbols[reloc.symbol];
+        let target = match symbol.kind {
+            SymbolKind::Section => {
+                let name = &object.sections[symbol.section.unwrap()].name;
+                let i = executable.sections.iter().position(|s| s.name == *name);
+                executable.addresses[i.unwrap()]
+            }
+            _ => address(&executable, &symbol.name),
+        };
+        let place = base + reloc.offset;
+        let value = target.wrapping_add(reloc.addend as u64);
+        let expected = match reloc.kind {
+            R_X86_64_PC32 | R_X86_64_PLT32 => (value.wrapping_sub(place) as u32).to_le_bytes(),
+            R_X86_64_32 => (value as u32).to_le_bytes(),
+            kind => panic!("unexpected relocation {}", kind),
+        };
+        assert_eq!(memory(&executable, place, 4), expected, "{}", symbol.name);
+    }
+
+    // The header is in the first 32 KiB of the file, 8-byte aligned, where
+    // the first segment loads it at 1 MiB.
+    let file = elf::write_executable(&executable);
+    assert_eq!(&file[..4], b"\x7fELF");
+    assert_eq!(u16_at(&file, 16), 2);
+    assert_eq!(u64_at(&file, 24), executable.entry);
+    assert_eq!(u64_at(&file, 32), 64);
+    let segments = u16_at(&file, 56) as usize;
+    assert_eq!(segments, executable.segments().len());
+    let offset = u64_at(&file, 64 + 8) as usize;
+    assert_eq!(u64_at(&file, 64 + 16), 0x100000);
+    assert!(
+        offset.is_multiple_of(8) && offset < 32 * 1024,
+        "{:#x}",
+        offset
+    );
+    assert_eq!(&file[offset..offset + 4], 0xe852_50d6u32.to_le_bytes());
+    // The `.bss` with the page tables and the stack takes no room.
+    assert!(file.len() < 16 * 1024, "{}", file.len());
+
+    // A flat binary starts with `_start`, and leaves the header out.
+    let executable = link::link(&objects, &Script::parse(FLAT).unwrap()).unwrap();
+    let binary = link::flat_binary(&executable);
+    assert_eq!(executable.entry, 0x100000);
+    assert_eq!(address(&executable, "_start"), 0x100000);
+    assert!(executable.sections.iter().all(|s| s.name != ".multiboot2"));
+    assert_eq!(
+        &binary[..16],
+        &executable.sections[0].data[..16],
+        "the binary starts with `.text`"
+    );
+    let data = executable.sections.iter().position(|s| s.name == ".data");
+    let end = executable.addresses[data.unwrap()] + executable.sections[data.unwrap()].size;
+    assert_eq!(binary.len() as u64, end - 0x100000);
+    assert_eq!(executable.sections.last().unwrap().name, ".bss");
+
+    let errors = link::link(&objects, &Script::parse("discard .bss\nat 0x1000").unwrap());
+    assert!(errors.unwrap_err().contains(
+        &"`hello.o` refers to `__enigma.boot.stack` in `.bss`, which is discarded".to_string()
+    ));
+}
+
+#[test]
+fn test_linked_executables_run() {
+    let script =
+        Script::parse("entry _start\nat 0x400000\nsection .text\nalign 4096\nsection .data")
+            .unwrap();
+    let dir = std::env::temp_dir().join(format!("enigma-link-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (divisor, code) in [(1, 42), (0, 3)] {
+        let objects = [("main.o".to_string(), kernel_object(&freestanding(divisor)))];
+        let executable = link::link(&objects, &script).unwrap();
+        let path = dir.join(format!("main{}", divisor));
+        std::fs::write(&path, elf::write_executable(&executable)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let status = Command::new(&path).status().unwrap();
+        assert_eq!(status.code(), Some(code));
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,279 @@
+use crate::checker;
+use crate::errorhandler::{Diagnostic, ErrorHandler};
+use crate::loader::{self, ModuleGraph};
+use crate::target::Target;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Loads and checks `proj/main.en` from an in-memory tree, with `lib` as the
+/// only search path.
+fn load_files(files: &[(&str, &str)]) -> (ModuleGraph, Vec<Diagnostic>) {
+    let provider: HashMap<PathBuf, String> = files
+        .iter()
+        .map(|(path, source)| (PathBuf::from(path), source.to_string()))
+        .collect();
+    let mut handler = ErrorHandler::new();
+    let graph = loader::load(
+        Path::new("proj/main.en"),
+        &[PathBuf::from("lib")],
+        &provider,
+        &mut handler,
+    )
+    .expect("entry file exists");
+    if !handler.has_errors() {
+        checker::check_graph(&graph, &mut handler);
+    }
+    (graph, handler.take())
+}
+
+fn messages(diagnostics: &[Diagnostic]) -> Vec<&str> {
+    diagnostics.iter().map(|d| d.message.as_str()).collect()
+}
+
+#[test]
+fn test_modules_resolve_to_files_and_directories() {
+    let (graph, diagnostics) = load_files(&[
+        (
+            "proj/main.en",
+            "get module shapes.circle\nget module std.io as io\n\
+             float r := circle::area(2.0)\nio::put(\"done\")",
+        ),
+        (
+            "proj/shapes/circle.en",
+            "get module std.io\npub @area(float r)::float -> r * r;",
+        ),
+        ("lib/std/io/mod.en", "pub @put(string s)::unit {}"),
+    ]);
+    assert_eq!(messages(&diagnostics), Vec::<&str>::new());
+    // The `core` prelude is loaded ahead of the entry file.
+    assert_eq!(graph.modules.len(), 4);
+    assert_eq!(
+        graph.by_name("std.io").unwrap().path,
+        Path::new("lib/std/io/mod.en")
+    );
+    // `std.io` is shared, and dependencies come before their importers.
+    let names: Vec<&str> = graph
+        .order
+        .iter()
+        .map(|&id| graph.modules[id].name.as_str())
+        .collect();
+    assert_eq!(names, vec!["core", "std.io", "shapes.circle", ""]);
+}
+
+#[test]
+fn test_unresolved_module_lists_searched_paths() {
+    let (_, diagnostics) = load_files(&[("proj/main.en", "get module net.http")]);
+    assert_eq!(messages(&diagnostics), vec!["unresolved module `net.http`"]);
+    assert_eq!(
+        diagnostics[0].notes,
+        vec![
+            "searched proj/net/http.en",
+            "searched proj/net/http/mod.en",
+            "searched lib/net/http.en",
+            "searched lib/net/http/mod.en",
+        ]
+    );
+}
+
+#[test]
+fn test_import_cycles_are_reported() {
+    let (_, diagnostics) = load_files(&[
+        ("proj/main.en", "get module a"),
+        ("proj/a.en", "get module b"),
+        ("proj/b.en", "get module a"),
+    ]);
+    assert_eq!(
+        messages(&diagnostics),
+        vec!["import cycle involving module `a`"]
+    );
+    assert_eq!(diagnostics[0].notes, vec!["cycle: a -> b -> a"]);
+}
+
+#[test]
+fn test_a_file_is_one_module_whatever_path_reaches_it() {
+    // `lib/std` is a search path too, so `io` and `std.io` are the same file.
+    let provider: HashMap<PathBuf, String> = [
+        (
+            "proj/main.en",
+            "get module std.io as a\nget module io as b\na::file f := b::open()",
+        ),
+        (
+            "lib/std/io.en",
+            "pub record file { fd: int }\npub @open()::file -> file { fd: 3 };",
+        ),
+    ]
+    .into_iter()
+    .map(|(path, source)| (PathBuf::from(path), source.to_string()))
+    .collect();
+    let mut handler = ErrorHandler::new();
+    let graph = loader::load(
+        Path::new("proj/main.en"),
+        &[PathBuf::from("lib"), PathBuf::from("lib/std")],
+        &provider,
+        &mut handler,
+    )
+    .unwrap();
+    checker::check_graph(&graph, &mut handler);
+    assert_eq!(messages(&handler.take()), Vec::<&str>::new());
+    assert_eq!(graph.modules.len(), 3);
+    assert_eq!(graph.root().imports[0].1, graph.root().imports[1].1);
+
+    // Importing the entry file is a cycle back to it, not a second copy.
+    let (graph, diagnostics) = load_files(&[
+        ("proj/main.en", "get module a"),
+        ("proj/a.en", "get module main"),
+    ]);
+    assert_eq!(graph.modules.len(), 3);
+    assert_eq!(
+        messages(&diagnostics),
+        vec!["import cycle involving module `main`"]
+    );
+    assert_eq!(
+        diagnostics[0].notes,
+        vec!["cycle: proj/main.en -> a -> proj/main.en"]
+    );
+}
+
+#[test]
+fn test_pub_controls_visibility_across_modules() {
+    let geo = "pub record point { x: int, y: int }\nrecord secret { v: int }\n\
+               @hidden()::int -> 1;\npub @origin()::point -> point { x: 0, y: 0 };\n\
+               implement point {\n pub @new(int x, int y)::point -> point { x: x, y: y };\n \
+               @private_helper()::int -> 2;\n}";
+    let cases = [
+        (
+            "pub items are usable through the alias",
+            "get module geo as g\ng::point p := g::point::new(1, 2)\ng::point q := g::origin()",
+            vec![],
+        ),
+        (
+            "private function",
+            "get module geo\nint n := geo::hidden()",
+            vec!["`geo::hidden` is private to module `geo`"],
+        ),
+        (
+            "private record",
+            "get module geo\ngeo::secret s := geo::secret { v: 1 }",
+            vec![
+                "record `geo::secret` is private to module `geo`",
+                "type `geo::secret` is private to module `geo`",
+            ],
+        ),
+        (
+            "private associated function",
+            "get module geo\nint n := geo::point::private_helper()",
+            vec!["`geo::point::private_helper` is private to module `geo`"],
+        ),
+        (
+            "names aren't visible without the module prefix",
+            "get module geo\nint n := origin()",
+            vec!["cannot find value `origin` in this scope"],
+        ),
+    ];
+    for (name, main, expected) in cases {
+        let (_, diagnostics) = load_files(&[("proj/main.en", main), ("proj/geo.en", geo)]);
+        let found = messages(&diagnostics);
+        assert_eq!(found.len(), expected.len(), "{}: got {:?}", name, found);
+        for (message, expected) in found.iter().zip(&expected) {
+            assert!(message.contains(expected), "{}: got {:?}", name, found);
+        }
+    }
+}
+
+#[test]
+fn test_diagnostics_render_against_their_own_file() {
+    let (graph, diagnostics) = load_files(&[
+        ("proj/main.en", "get module util\nutil::f()"),
+        ("proj/util.en", "pub @f()::unit {\n int x := \"no\"\n}"),
+    ]);
+    let mut handler = ErrorHandler::new();
+    for diagnostic in diagnostics {
+        handler.emit(diagnostic);
+    }
+    let rendered = handler.render_with(&graph.sources);
+    assert!(rendered.contains("--> proj/util.en:2:11"), "{}", rendered);
+}
+
+#[test]
+fn test_orphan_implementations_are_rejected() {
+    let zoo = (
+        "lib/zoo.en",
+        "pub protoc speak {\n @talk(ref self)::string\n}\npub record dog {\n name: string\n}",
+    );
+    let (_, diagnostics) = load_files(&[
+        zoo,
+        (
+            "proj/main.en",
+            "get module zoo\nimplement zoo::speak for zoo::dog {\n @talk(ref self)::string -> \"woof\";\n}",
+        ),
+    ]);
+    assert_eq!(
+        messages(&diagnostics),
+        vec![
+            "cannot implement protocol `zoo::speak` for `zoo::dog` outside the modules that define them"
+        ]
+    );
+
+    // The module defining the type may implement an imported protocol.
+    let (_, diagnostics) = load_files(&[
+        zoo,
+        (
+            "proj/main.en",
+            "get module zoo\nrecord cat {\n lives: int\n}\n\
+             implement zoo::speak for cat {\n @talk(ref self)::string -> \"meow\";\n}",
+        ),
+    ]);
+    assert_eq!(messages(&diagnostics), Vec::<&str>::new());
+}
+
+#[test]
+fn test_freestanding_programs_lack_the_c_library() {
+    let entry = "@start() {}\n@panic(string message) {}\n";
+    let cases: Vec<(String, Vec<&str>)> = vec![
+        (format!("#freestanding\n{}", entry), vec![]),
+        // The pragma only counts ahead of the code.
+        (format!("{}#freestanding\nprint(1)", entry), vec![]),
+        (
+            format!(
+                "# A kernel.\n#freestanding\n{}print(1)\nexit(2)\nexit(\"stop\")\nstring s := \"a\" + \"b\"",
+                entry
+            ),
+            vec![
+                "`print` isn't available in freestanding programs",
+                "`exit` with a code isn't available in freestanding programs",
+                "strings can't be concatenated in freestanding programs",
+            ],
+        ),
+        (
+            "#freestanding\n".to_string(),
+            vec![
+                "a freestanding program must define `@start()`",
+                "a freestanding program must define `@panic(string message)`",
+            ],
+        ),
+        (
+            "#freestanding\n@start(int magic, int info) {}\n\
+             @panic(string message)::int -> 0;"
+                .to_string(),
+            vec!["`@panic` must be declared as `@panic(string message)`"],
+        ),
+        (
+            "#freestanding\n@start(int magic) {}\n@panic(string message) {}".to_string(),
+            vec!["`@start` must be declared as `@start()`"],
+        ),
+        (
+            "#freestanding\nextern \"C\" @start() {}\n@panic(string message) {}".to_string(),
+            vec!["`@start` can't be `#[interrupt]`, `#[naked]`, `#[no_mangle]` or `extern \"C\"`"],
+        ),
+        (
+            "#freestanding\nextern \"C\" @start()\n@panic(string message) {}".to_string(),
+            vec!["a freestanding program must define `@start()`"],
+        ),
+    ];
+    for (source, expected) in cases {
+        let (graph, diagnostics) = load_files(&[("proj/main.en", &source)]);
+        assert_eq!(messages(&diagnostics), expected, "{}", source);
+        let freestanding = source.starts_with('#');
+        assert_eq!(graph.target == Target::Freestanding, freestanding);
+    }
+}
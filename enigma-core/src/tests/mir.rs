@@ -0,0 +1,359 @@
+use crate::errorhandler::ErrorHandler;
+use crate::mir::{self, Inst, InstKind, Operand, Place, Program, Terminator};
+use crate::{checker, hir, loader, mono};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Checks `proj/main.en` from an in-memory tree and lowers every module,
+/// the prelude included, to MIR.
+pub(super) fn lower_files(files: &[(&str, &str)]) -> Program {
+    let provider: HashMap<PathBuf, String> = files
+        .iter()
+        .map(|(path, source)| (PathBuf::from(path), source.to_string()))
+        .collect();
+    let mut handler = ErrorHandler::new();
+    let graph = loader::load(Path::new("proj/main.en"), &[], &provider, &mut handler)
+        .expect("entry file exists");
+    let checked = checker::check_graph(&graph, &mut handler);
+    assert!(
+        !handler.has_errors(),
+        "test input failed to check: {:?}",
+        handler.diagnostics()
+    );
+    let modules = hir::lower_graph(&graph, &checked);
+    let items = mono::collect(&checked, &mut handler);
+    let tables: Vec<_> = checked.iter().map(|(items, _)| items).collect();
+    mir::lower_program(&modules, &tables, items)
+}
+
+pub(super) fn lower(source: &str) -> Program {
+    let program = lower_files(&[("proj/main.en", source)]);
+    if let Err(error) = mir::verify(&program) {
+        panic!("{}\n{}", error, program);
+    }
+    program
+}
+
+/// The printed function named `name`.
+pub(super) fn function(program: &Program, name: &str) -> String {
+    let function = program
+        .function(name)
+        .unwrap_or_else(|| panic!("no function `{}`", name));
+    program.display(function).to_string()
+}
+
+#[test]
+fn test_if_joins_through_a_block_parameter() {
+    let program = lower("@max(int a, int b)::int {\n if a > b {\n  a\n } else {\n  b\n }\n}");
+    assert_eq!(
+        function(&program, "max"),
+        "@max(int, int)::int {
+    _0: int  # a
+    _1: int  # b
+
+  bb0(%0: int, %1: int):
+    _0 = %0
+    _1 = %1
+    %2: int = copy _0
+    %3: int = copy _1
+    %4: bool = %2 > %3
+    branch %4, bb1, bb2
+
+  bb1:
+    %6: int = copy _0
+    goto bb3(%6)
+
+  bb2:
+    %7: int = copy _1
+    goto bb3(%7)
+
+  bb3(%5: int):
+    return %5
+}
+"
+    );
+}
+
+#[test]
+fn test_loops_match_and_drops() {
+    let program = lower(
+        "record human {\n name: string\n}\n\
+         @first(Option[human] o, int n)::int {\n\
+          mut int i := 0\n\
+          while i < n {\n  if i == 3 {\n   break\n  }\n  i++\n }\n\
+          match o {\n  Option::Some(h): i\n  Option::None: 0 - 1\n }\n\
+         }",
+    );
+    assert_eq!(
+        function(&program, "first"),
+        "@first(core::Option[human], int)::int {
+    _0: core::Option[human]  # o
+    _1: int  # n
+    mut _2: int  # i
+    _3: human  # h
+
+  bb0(%0: core::Option[human], %1: int):
+    _0 = %0
+    _1 = %1
+    _2 = 0
+    goto bb1
+
+  bb1:
+    %2: int = copy _2
+    %3: int = copy _1
+    %4: bool = %2 < %3
+    branch %4, bb2, bb7
+
+  bb2:
+    %5: int = copy _2
+    %6: bool = %5 == 3
+    branch %6, bb3, bb4
+
+  bb3:
+    goto bb8
+
+  bb4:
+    goto bb5
+
+  bb5:
+    %7: int = copy _2
+    %8: int = %7 + 1
+    _2 = %8
+    goto bb6
+
+  bb6:
+    goto bb1
+
+  bb7:
+    goto bb8
+
+  bb8:
+    %10: int = discriminant _0
+    %11: bool = %10 == 0
+    branch %11, bb9, bb10
+
+  bb9:
+    %12: human = move (_0 as Some).0
+    _3 = %12
+    %13: int = copy _2
+    drop _3
+    goto bb12(%13)
+
+  bb10:
+    %14: int = discriminant _0
+    %15: bool = %14 == 1
+    branch %15, bb11, bb13
+
+  bb11:
+    %16: int = 0 - 1
+    goto bb12(%16)
+
+  bb12(%9: int):
+    drop _0
+    return %9
+
+  bb13:
+    call exit(\"no `match` arm matches\")
+    unreachable
+}
+"
+    );
+}
+
+#[test]
+fn test_closures_become_functions_taking_their_captures() {
+    let program = lower(
+        "@total()::int {\n\
+          mut int count := 0\n\
+          @(int)::int add := @(n) {\n  count += n\n  count\n }\n\
+          add(2)\n}",
+    );
+    assert_eq!(
+        function(&program, "total::{closure#0}"),
+        "@total::{closure#0}((ref mut int), int)::int {
+    _0: (ref mut int)  # env
+    _1: int  # n
+
+  bb0(%0: (ref mut int), %1: int):
+    _0 = %0
+    _1 = %1
+    %2: int = copy (deref _0.0)
+    %3: int = copy _1
+    %4: int = %2 + %3
+    (deref _0.0) = %4
+    %5: int = copy (deref _0.0)
+    drop _0
+    return %5
+}
+"
+    );
+    let total = function(&program, "total");
+    assert!(
+        total.contains(
+            "%0: ref mut int = ref mut _0\n    %1: @(int)::int = closure total::{closure#0}(%0)"
+        ),
+        "{}",
+        total
+    );
+}
+
+#[test]
+fn test_calls_that_never_return_end_in_unreachable() {
+    let program = lower("@fail(int code)::int {\n exit(code)\n}");
+    assert_eq!(
+        function(&program, "fail"),
+        "@fail(int)::int {
+    _0: int  # code
+
+  bb0(%0: int):
+    _0 = %0
+    %1: int = copy _0
+    call exit(%1)
+    unreachable
+}
+"
+    );
+}
+
+/// Programs exercising most of the language, which must lower to valid MIR.
+pub(super) const PROGRAMS: &[&str] = &[
+    "mut int i := 1\nint v := loop {\n i *= 2\n if i > 20 {\n  break i\n }\n}\nprint(v)",
+    "for k in 0..5 {\n if k % 2 == 0 {\n  continue\n }\n print(k)\n}",
+    "(int, string) t := (4, \"four\")\n(int n, string s) $= t\nprint(s)\nprint(n)",
+    "@sub(int from%a, int take%b)::int -> a - b;\nprint(sub(take: 1, from: 10))",
+    "record human {\n name: string\n age: int\n nick: Option[string]\n}\n\
+     implement human {\n\
+      @new(string name)::human -> human { name: name, age: 0 };\n\
+      @older(ref mut self) {\n self::age += 1\n }\n\
+      @label(ref self)::string {\n match self::nick {\n  Option::Some(n): n\n  Option::None: self::name\n }\n }\n\
+     }\n\
+     mut human h := human::new(\"al\")\n\
+     h::older()\nprint(h::label())\n\
+     ref mut int age := ref mut h::age\nderef age = 30",
+    "protoc live {\n @eat(ref self)::string\n}\n\
+     record cat {\n}\n\
+     implement live for cat {\n @eat(ref self)::string -> \"fish\";\n}\n\
+     @feed[T: live](ref T animal)::string -> animal::eat();\n\
+     @feed_object(ref live animal)::string -> animal::eat();\n\
+     cat c := cat {}\nprint(feed(ref c))\nprint(feed_object(ref c))",
+    "record parse_error {\n line: int\n}\nrecord io_error {\n code: int\n}\n\
+     implement into_error[io_error] for parse_error {\n\
+      @into_error(self)::io_error -> io_error { code: self::line };\n}\n\
+     @parse()::Result[int, parse_error] -> Result::Err(parse_error { line: 3 });\n\
+     @load()::Result[int, io_error] -> Result::Ok(parse()? + 1);",
+    "@apply(@(int)::int f, int v)::int -> f(v);\n\
+     @outer(int k)::int {\n mut int seen := 0\n \
+      @(int)::int f := @(x) {\n  @(int)::int g := @(y) {\n   seen += y\n   y + k\n  }\n  g(x)\n }\n \
+      apply(f, 1) + seen\n}",
+    "@pick(int n)::string {\n match (n, n > 2) {\n  (0, _): \"zero\"\n  (_, true): \"big\"\n  _: \"small\"\n }\n}",
+];
+
+#[test]
+fn test_lowered_programs_verify() {
+    for source in PROGRAMS {
+        lower(source);
+    }
+}
+
+#[test]
+fn test_graph_lowering_keeps_globals_and_inits() {
+    let program = lower_files(&[
+        (
+            "proj/main.en",
+            "get module util\nprint(util::twice(util::base))",
+        ),
+        (
+            "proj/util.en",
+            "pub int base := 21\npub @twice(int n)::int -> n * 2;",
+        ),
+    ]);
+    assert_eq!(mir::verify(&program), Ok(()));
+    assert_eq!(
+        program.globals,
+        vec![("util::base".to_string(), crate::checker::types::Ty::Int)]
+    );
+    assert_eq!(
+        function(&program, "util::{init}"),
+        "init util {
+  bb0:
+    util::base = 21
+    return ()
+}
+"
+    );
+    assert!(program.function("core::{init}").is_some());
+}
+
+/// Replaces the instructions of block `index` of `name`.
+fn edit_block(program: &mut Program, name: &str, index: usize, edit: impl FnOnce(&mut Vec<Inst>)) {
+    let function = program
+        .functions
+        .iter_mut()
+        .find(|f| f.name == name)
+        .unwrap();
+    edit(&mut function.blocks[index].insts);
+}
+
+#[test]
+fn test_verifier_rejects_broken_functions() {
+    let source = "@max(int a, int b)::int {\n if a > b {\n  a\n } else {\n  b\n }\n}";
+    let expect_error = |program: &Program, expected: &str| {
+        let error = mir::verify(program).expect_err("the edit must be caught");
+        assert_eq!(error.function, "max");
+        assert!(
+            error.message.contains(expected),
+            "expected {:?} in {:?}",
+            expected,
+            error.message
+        );
+    };
+
+    // The comparison is moved before the loads it reads.
+    let mut program = lower(source);
+    edit_block(&mut program, "max", 0, |insts| insts.swap(2, 4));
+    expect_error(
+        &program,
+        "bb0: `%2` is used where its definition doesn't dominate",
+    );
+
+    // The join block returns a value only defined on one side.
+    let mut program = lower(source);
+    let max = program
+        .functions
+        .iter_mut()
+        .find(|f| f.name == "max")
+        .unwrap();
+    max.blocks[3].term = Terminator::Return(Operand::Value(mir::ValueId(6)));
+    expect_error(
+        &program,
+        "bb3: `%6` is used where its definition doesn't dominate",
+    );
+
+    // A `bool` is stored into an `int` local.
+    let mut program = lower(source);
+    edit_block(&mut program, "max", 0, |insts| {
+        let span = insts[0].span;
+        insts.push(Inst {
+            result: None,
+            kind: InstKind::Store {
+                place: Place::local(mir::LocalId(0)),
+                value: Operand::Value(mir::ValueId(4)),
+            },
+            span,
+        });
+    });
+    expect_error(&program, "store of type `bool` where `int` is expected");
+
+    // The join block is entered without its argument.
+    let mut program = lower(source);
+    let max = program
+        .functions
+        .iter_mut()
+        .find(|f| f.name == "max")
+        .unwrap();
+    let Terminator::Goto(target) = &mut max.blocks[1].term else {
+        panic!("bb1 ends in a goto");
+    };
+    target.args.clear();
+    expect_error(&program, "bb1: bb3 takes 1 arguments but 0 were given");
+}
@@ -1 +1,68 @@
+mod borrowck;
+mod checker;
+mod codegen;
+mod hir;
+mod interp;
+mod link;
+mod loader;
+mod mir;
+mod mono;
+mod object;
+mod opt;
+mod prelude;
+mod resolve;
 
+use crate::errorhandler::{Diagnostic, ErrorHandler};
+use crate::{checker as ck, parser};
+
+/// Runs the front end over `source` and returns every diagnostic produced.
+fn diagnostics_for(source: &str) -> Vec<Diagnostic> {
+    let mut handler = ErrorHandler::new();
+    let program = parser::parse(source, &mut handler);
+    assert!(
+        !handler.has_errors(),
+        "test input failed to parse: {:?}",
+        handler.diagnostics()
+    );
+    let checked = ck::check_program(&program, &mut handler);
+    if !handler.has_errors() {
+        crate::mono::collect(&[checked], &mut handler);
+    }
+    handler.take()
+}
+
+struct CheckCase<'a> {
+    name: &'a str,
+    input: &'a str,
+    /// Substrings of the expected error messages, in order. Empty means the
+    /// program must check cleanly.
+    errors: Vec<&'a str>,
+}
+
+fn run_check_cases(cases: Vec<CheckCase>) {
+    for case in cases {
+        let diagnostics = diagnostics_for(case.input);
+        let messages: Vec<&str> = diagnostics
+            .iter()
+            .filter(|d| d.is_error())
+            .map(|d| d.message.as_str())
+            .collect();
+        assert_eq!(
+            messages.len(),
+            case.errors.len(),
+            "Test {} failed: expected errors {:?}, got {:?}",
+            case.name,
+            case.errors,
+            messages
+        );
+        for (message, expected) in messages.iter().zip(&case.errors) {
+            assert!(
+                message.contains(expected),
+                "Test {} failed: expected {:?} in {:?}",
+                case.name,
+                expected,
+                message
+            );
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::checker::types::Ty;
+use crate::checker::{FnOwner, FnRef, check_program};
+use crate::errorhandler::ErrorHandler;
+use crate::mono::{self, Instance, MonoItems};
+use crate::parser;
+
+fn collect(source: &str) -> (MonoItems, ErrorHandler) {
+    let mut handler = ErrorHandler::new();
+    let program = parser::parse(source, &mut handler);
+    let checked = check_program(&program, &mut handler);
+    assert!(
+        !handler.has_errors(),
+        "test input failed to check: {:?}",
+        handler.diagnostics()
+    );
+    let items = mono::collect(&[checked], &mut handler);
+    (items, handler)
+}
+
+fn free(name: &str, args: Vec<Ty>) -> Instance {
+    Instance {
+        func: FnRef {
+            owner: FnOwner::Free,
+            name: name.to_string(),
+        },
+        args,
+    }
+}
+
+#[test]
+fn test_instances_are_deduplicated() {
+    let (items, handler) = collect(
+        "@id[T](T x)::T -> x;\n\
+         @twice[T](T x)::T -> id(id(x));\n\
+         int a := id(1)\nint b := id(2)\nbool c := twice(true)",
+    );
+    assert!(!handler.has_errors());
+    let names: Vec<String> = items.instances.iter().map(Instance::to_string).collect();
+    assert_eq!(names, vec!["id[int]", "twice[bool]", "id[bool]"]);
+}
+
+#[test]
+fn test_non_generic_functions_are_roots() {
+    let (items, _) = collect("@id[T](T x)::T -> x;\n@unused()::int -> id(4);");
+    assert!(items.contains(&free("unused", vec![])));
+    assert!(items.contains(&free("id", vec![Ty::Int])));
+    assert_eq!(items.instances.len(), 2);
+}
+
+#[test]
+fn test_protocol_calls_resolve_to_the_implementation() {
+    let (items, _) = collect(
+        "protoc live {\n @eat(self)::string\n}\n\
+         record human {\n name: string\n}\n\
+         implement live for human {\n @eat(self)::string -> self::name;\n}\n\
+         @feed[T: live](T x)::string -> x::eat();\n\
+         string s := feed(human { name: \"raju\" })",
+    );
+    let human = Ty::Adt {
+        name: "human".to_string(),
+        args: vec![],
+    };
+    assert!(items.contains(&free("feed", vec![human])));
+    assert!(items.contains(&Instance {
+        func: FnRef {
+            owner: FnOwner::Type("human".to_string()),
+            name: "eat".to_string(),
+        },
+        args: vec![],
+    }));
+}
+
+#[test]
+fn test_recursion_limit() {
+    let (_, handler) = collect(
+        "union Option[T] {\n Some(T),\n None\n}\n\
+         @grow[T](T x)::int -> grow(Option::Some(x));\n\
+         int n := grow(1)",
+    );
+    let errors: Vec<_> = handler
+        .diagnostics()
+        .iter()
+        .filter(|d| d.is_error())
+        .collect();
+    assert_eq!(errors.len(), 1);
+    assert!(
+        errors[0]
+            .message
+            .starts_with("reached the recursion limit while instantiating `grow[Option[Option["),
+        "{}",
+        errors[0].message
+    );
+}
+
+#[test]
+fn test_protocol_objects_get_vtables() {
+    let (items, _) = collect(
+        "protoc speak {\n @talk(ref self)::string\n @name(ref self)::string\n}\n\
+         record dog {\n name: string\n}\n\
+         implement speak for dog {\n @talk(ref self)::string -> \"woof\";\n @name(ref self)::string -> self::name;\n}\n\
+         @keep[T: speak](ref T x)::ref speak -> x;\n\
+         dog d := dog { name: \"rex\" }\nref speak a := ref d\nref speak b := keep(ref d)",
+    );
+    assert_eq!(items.vtables.len(), 1);
+    let vtable = &items.vtables[0];
+    assert_eq!(vtable.protocol, "speak");
+    let methods: Vec<String> = vtable.methods.iter().map(Instance::to_string).collect();
+    assert_eq!(methods, vec!["dog::talk", "dog::name"]);
+}
@@ -0,0 +1,777 @@
+use super::codegen::freestanding;
+use super::interp::run_files;
+use super::mir::{PROGRAMS, lower};
+use crate::codegen::elf::{
+    self, Binding, Object, R_X86_64_32, R_X86_64_64, R_X86_64_PC32, R_X86_64_PLT32, Reloc,
+    SHF_ALLOC, SHF_EXECINSTR, Section, SectionKind, Symbol, SymbolKind,
+};
+use crate::codegen::x86::lir::*;
+use crate::codegen::x86::{self, Syntax};
+use crate::codegen::x86::{boot, encode};
+use crate::mir::opt::{self, OptLevel};
+use crate::target::Target;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Encodes `insts` as the body of a function of one block.
+fn encode(insts: Vec<Inst>) -> Result<encode::Encoded, String> {
+    encode::function(&MFunction {
+        symbol: "f".to_string(),
+        global: true,
+        blocks: vec![MBlock {
+            label: ".Lf".to_string(),
+            insts,
+        }],
+        slots: Vec::new(),
+        outgoing: 0,
+        vregs: 0,
+        frame_size: 0,
+        saved: Vec::new(),
+        section: None,
+        align: None,
+        naked: false,
+    })
+}
+
+fn bytes(insts: Vec<Inst>) -> Vec<u8> {
+    encode(insts).unwrap().code
+}
+
+fn object(source: &str, level: OptLevel) -> Object {
+    let mut program = lower(source);
+    opt::optimize(&mut program, level, &mut |_, _| {}).unwrap();
+    let bytes =
+        x86::emit_object(&program, Target::Hosted).unwrap_or_else(|error| panic!("{}", error));
+    elf::read(&bytes).unwrap()
+}
+
+#[test]
+fn test_instructions_encode_as_the_assembler_does() {
+    use Gpr::*;
+    let cases: Vec<(Inst, &[u8])> = vec![
+        (Inst::mov(Size::Q, Rax, Rcx), &[0x48, 0x89, 0xc8]),
+        (Inst::mov(Size::Q, R12, Rsp), &[0x49, 0x89, 0xe4]),
+        (
+            Inst::mov(Size::Q, Rax, Mem::reg(Rbp, -8)),
+            &[0x48, 0x8b, 0x45, 0xf8],
+        ),
+        // `rsp` and `r12` need a SIB byte, `rbp` and `r13` a displacement.
+        (
+            Inst::mov(Size::D, Mem::reg(Rsp, 0), Rdx),
+            &[0x89, 0x14, 0x24],
+        ),
+        (
+            Inst::mov(Size::Q, Rax, Mem::reg(R13, 0)),
+            &[0x49, 0x8b, 0x45, 0x00],
+        ),
+        (
+            Inst::mov(Size::Q, R9, Mem::reg(R12, 400)),
+            &[0x4d, 0x8b, 0x8c, 0x24, 0x90, 0x01, 0x00, 0x00],
+        ),
+        // Byte registers past `bl` need a REX prefix.
+        (
+            Inst::mov(Size::B, Mem::reg(Rax, 0), Rsi),
+            &[0x40, 0x88, 0x30],
+        ),
+        (
+            Inst::mov(Size::D, Rax, Operand::Imm(-1)),
+            &[0xb8, 0xff, 0xff, 0xff, 0xff],
+        ),
+        (
+            Inst::mov(Size::Q, R8, Operand::Imm(1 << 40)),
+            &[0x49, 0xb8, 0, 0, 0, 0, 0, 1, 0, 0],
+        ),
+        (
+            Inst::alu(AluOp::Add, Size::Q, Rsp, Operand::Imm(16)),
+            &[0x48, 0x83, 0xc4, 0x10],
+        ),
+        (
+            Inst::alu(AluOp::Cmp, Size::Q, Rax, Operand::Imm(1000)),
+            &[0x48, 0x3d, 0xe8, 0x03, 0x00, 0x00],
+        ),
+        (
+            Inst::alu(AluOp::Test, Size::B, Rdi, Rdi),
+            &[0x40, 0x84, 0xff],
+        ),
+        (
+            Inst::Setcc {
+                cond: Cond::G,
+                dst: Reg::Gpr(Rsi),
+            },
+            &[0x40, 0x0f, 0x9f, 0xc6],
+        ),
+        (
+            Inst::MovZx {
+                size: Size::B,
+                dst: Reg::Gpr(Rdx),
+                src: Operand::Reg(Reg::Gpr(Rdx)),
+            },
+            &[0x0f, 0xb6, 0xd2],
+        ),
+        (
+            Inst::Imul {
+                size: Size::Q,
+                dst: Reg::Gpr(R10),
+                src: Operand::Imm(3),
+            },
+            &[0x4d, 0x6b, 0xd2, 0x03],
+        ),
+        (
+            Inst::Div {
+                signed: true,
+                size: Size::Q,
+                src: Operand::Reg(Reg::Gpr(Rcx)),
+            },
+            &[0x48, 0xf7, 0xf9],
+        ),
+        (
+            Inst::Sse {
+                op: SseOp::Addsd,
+                dst: Operand::Reg(Reg::Xmm(0)),
+                src: Operand::Reg(Reg::Xmm(14)),
+            },
+            &[0xf2, 0x41, 0x0f, 0x58, 0xc6],
+        ),
+        (
+            Inst::Movq {
+                dst: Reg::Gpr(Rax),
+                src: Reg::Xmm(1),
+            },
+            &[0x66, 0x48, 0x0f, 0x7e, 0xc8],
+        ),
+        // Shifts by one have an encoding of their own.
+        (
+            Inst::Shift {
+                op: ShiftOp::Shl,
+                size: Size::Q,
+                dst: Operand::Reg(Reg::Gpr(Rax)),
+                amount: 24,
+            },
+            &[0x48, 0xc1, 0xe0, 0x18],
+        ),
+        (
+            Inst::Shift {
+                op: ShiftOp::Shr,
+                size: Size::Q,
+                dst: Operand::Reg(Reg::Gpr(R9)),
+                amount: 1,
+            },
+            &[0x49, 0xd1, 0xe9],
+        ),
+        (
+            Inst::Shift {
+                op: ShiftOp::Shl,
+                size: Size::B,
+                dst: Operand::Mem(Mem::reg(Rax, 0)),
+                amount: 3,
+            },
+            &[0xc0, 0x20, 0x03],
+        ),
+        (Inst::In { size: Size::B }, &[0xec]),
+        (Inst::Out { size: Size::W }, &[0x66, 0xef]),
+        (Inst::Fence(Fence::Full), &[0x0f, 0xae, 0xf0]),
+        (
+            Inst::Atomic {
+                op: AtomicOp::Xadd,
+                size: Size::Q,
+                dst: Mem::reg(Rax, 0),
+                src: Reg::Gpr(Rcx),
+            },
+            &[0xf0, 0x48, 0x0f, 0xc1, 0x08],
+        ),
+        (
+            Inst::Atomic {
+                op: AtomicOp::Xchg,
+                size: Size::Q,
+                dst: Mem::reg(Rax, 0),
+                src: Reg::Gpr(Rcx),
+            },
+            &[0x48, 0x87, 0x08],
+        ),
+        (
+            Inst::Atomic {
+                op: AtomicOp::Cmpxchg,
+                size: Size::B,
+                dst: Mem::reg(Rdi, 0),
+                src: Reg::Gpr(Rsi),
+            },
+            &[0xf0, 0x40, 0x0f, 0xb0, 0x37],
+        ),
+        (
+            Inst::Atomic {
+                op: AtomicOp::Cmpxchg,
+                size: Size::Q,
+                dst: Mem::reg(R12, 0),
+                src: Reg::Gpr(R9),
+            },
+            &[0xf0, 0x4d, 0x0f, 0xb1, 0x0c, 0x24],
+        ),
+        (Inst::Push(R15), &[0x41, 0x57]),
+        (Inst::Pop(Rbp), &[0x5d]),
+    ];
+    for (inst, expected) in cases {
+        assert_eq!(bytes(vec![inst.clone()]), expected, "{:?}", inst);
+    }
+}
+
+#[test]
+fn test_jumps_are_as_short_as_their_distance_allows() {
+    let block = |label: &str, insts| MBlock {
+        label: label.to_string(),
+        insts,
+    };
+    let mut function = MFunction {
+        symbol: "f".to_string(),
+        global: true,
+        blocks: vec![
+            block(".Lf", vec![Inst::Jcc(Cond::E, ".Lf_2".to_string())]),
+            block(".Lf_1", vec![Inst::Ud2; 10]),
+            block(".Lf_2", vec![Inst::Jmp(".Lf_1".to_string())]),
+        ],
+        slots: Vec::new(),
+        outgoing: 0,
+        vregs: 0,
+        frame_size: 0,
+        saved: Vec::new(),
+        section: None,
+        align: None,
+        naked: false,
+    };
+    let code = encode::function(&function).unwrap().code;
+    assert_eq!(&code[..2], &[0x74, 20]);
+    assert_eq!(&code[22..], &[0xeb, (-22i8) as u8]);
+
+    // Past 127 bytes, both become long jumps.
+    function.blocks[1].insts = vec![Inst::Ud2; 100];
+    let code = encode::function(&function).unwrap().code;
+    assert_eq!(&code[..6], &[0x0f, 0x84, 200, 0, 0, 0]);
+    assert_eq!(code[206], 0xe9);
+    assert_eq!(
+        i32::from_le_bytes(code[207..].try_into().unwrap()),
+        -(200 + 6 + 5) + 6
+    );
+}
+
+#[test]
+fn test_symbol_references_become_relocations() {
+    let encoded = encode(vec![
+        Inst::Lea {
+            dst: Reg::Gpr(Gpr::Rdi),
+            src: Mem::symbol("__enigma.cstr0"),
+        },
+        // The immediate follows the displacement, which moves the end of
+        // the instruction `rip` is relative to.
+        Inst::mov(Size::Q, Mem::symbol("_EN.x").offset(8), Operand::Imm(7)),
+        Inst::Call {
+            target: CallTarget::External("puts".to_string()),
+            args: Vec::new(),
+            results: Vec::new(),
+        },
+    ])
+    .unwrap();
+    let relocs: Vec<_> = encoded
+        .relocs
+        .iter()
+        .map(|reloc| {
+            (
+                reloc.offset,
+                reloc.symbol.as_str(),
+                reloc.kind,
+                reloc.addend,
+            )
+        })
+        .collect();
+    assert_eq!(
+        relocs,
+        [
+            (3, "__enigma.cstr0", R_X86_64_PC32, -4),
+            (10, "_EN.x", R_X86_64_PC32, 8 - 4 - 4),
+            (19, "puts", R_X86_64_PLT32, -4),
+        ]
+    );
+}
+
+fn asm(lines: &[&str]) -> Inst {
+    Inst::Asm {
+        lines: lines.iter().map(ToString::to_string).collect(),
+        uses: Vec::new(),
+        defs: Vec::new(),
+        clobbers: Vec::new(),
+    }
+}
+
+#[test]
+fn test_inline_assembly_is_assembled() {
+    let code = bytes(vec![asm(&[
+        "mov eax , 1",
+        "int 0x80",
+        "out dx, al",
+        "in eax, 0x60",
+        "mov qword [rbp - 16], rcx",
+        "lidt [rdi]",
+        "cli",
+        "hlt",
+        "addsd xmm1, xmm2",
+        "fxsave64 [rsp]",
+        "fxrstor64 [rsp]",
+        "jmp r11",
+        "iretq",
+    ])]);
+    assert_eq!(
+        code,
+        [
+            0xb8, 1, 0, 0, 0, 0xcd, 0x80, 0xee, 0xe5, 0x60, 0x48, 0x89, 0x4d, 0xf0, 0x0f, 0x01,
+            0x1f, 0xfa, 0xf4, 0xf2, 0x0f, 0x58, 0xca, 0x48, 0x0f, 0xae, 0x04, 0x24, 0x48, 0x0f,
+            0xae, 0x0c, 0x24, 0x41, 0xff, 0xe3, 0x48, 0xcf
+        ]
+    );
+    // Symbols are addressed relative to `rip`, and called or jumped to, by
+    // relocation.
+    let encoded = encode(vec![asm(&[
+        "lea r11, [rip + tick]",
+        "call deliver",
+        "jmp _EN.fault",
+    ])])
+    .unwrap();
+    assert_eq!(
+        encoded.code,
+        [
+            0x4c, 0x8d, 0x1d, 0, 0, 0, 0, 0xe8, 0, 0, 0, 0, 0xe9, 0, 0, 0, 0
+        ]
+    );
+    let relocs: Vec<_> = encoded
+        .relocs
+        .iter()
+        .map(|reloc| (reloc.offset, reloc.symbol.as_str(), reloc.kind))
+        .collect();
+    assert_eq!(
+        relocs,
+        [
+            (3, "tick", R_X86_64_PC32),
+            (8, "deliver", R_X86_64_PLT32),
+            (13, "_EN.fault", R_X86_64_PLT32),
+        ]
+    );
+    let error = encode(vec![asm(&["fsin"])]).unwrap_err();
+    assert_eq!(error, "`fsin` instructions in objects");
+}
+
+#[test]
+fn test_objects_read_back_with_their_symbols_and_relocations() {
+    let object = object(
+        "mut int count := 0\n@bump()::int {\n unsafe {\n  count += 1\n  count\n }\n}\n\
+         print(\"hi\")\nprint(bump())",
+        OptLevel::O0,
+    );
+    let text = object.section(".text").unwrap();
+    assert_eq!(text.flags, SHF_ALLOC | SHF_EXECINSTR);
+    assert_eq!(object.section(".bss").unwrap().kind, SectionKind::Nobits);
+    assert!(object.section(".note.GNU-stack").is_some());
+
+    let section = |name: &str| object.sections.iter().position(|s| s.name == name);
+    let main = object.symbol("main").unwrap();
+    assert_eq!(
+        (main.section, main.binding, main.kind),
+        (section(".text"), Binding::Global, SymbolKind::Func)
+    );
+    assert!(main.size > 0);
+    let count = object.symbol("_EN.count").unwrap();
+    assert_eq!(
+        (count.section, count.size, count.binding),
+        (section(".bss"), 8, Binding::Local)
+    );
+    let printf = object.symbol("printf").unwrap();
+    assert_eq!((printf.section, printf.binding), (None, Binding::Global));
+    // Local symbols come first.
+    let first_global = object
+        .symbols
+        .iter()
+        .position(|symbol| symbol.binding != Binding::Local)
+        .unwrap();
+    assert!(
+        object.symbols[first_global..]
+            .iter()
+            .all(|symbol| symbol.binding != Binding::Local)
+    );
+
+    let target = |reloc: &Reloc| object.symbols[reloc.symbol].name.as_str();
+    let calls: Vec<_> = text
+        .relocs
+        .iter()
+        .filter(|reloc| target(reloc) == "printf")
+        .collect();
+    assert!(!calls.is_empty());
+    assert!(
+        calls
+            .iter()
+            .all(|reloc| reloc.kind == R_X86_64_PLT32 && reloc.addend == -4)
+    );
+    // The call's displacement is left for the linker.
+    let at = calls[0].offset as usize;
+    assert_eq!(text.data[at - 1..at + 4], [0xe8, 0, 0, 0, 0]);
+    assert!(
+        text.relocs
+            .iter()
+            .any(|reloc| target(reloc) == "_EN.count" && reloc.kind == R_X86_64_PC32)
+    );
+    // String headers point to their bytes.
+    let data = object.section(".data").unwrap();
+    let header = object.symbol("__enigma.str0").unwrap();
+    let reloc = &data.relocs[0];
+    assert_eq!((reloc.offset, reloc.kind), (header.value, R_X86_64_64));
+    assert_eq!(target(reloc), "__enigma.bytes0");
+    let at = header.value as usize + 8;
+    assert_eq!(data.data[at..at + 8], 2u64.to_le_bytes());
+}
+
+#[test]
+fn test_objects_round_trip() {
+    let mut text = Section::new(
+        ".text",
+        SectionKind::Progbits,
+        SHF_ALLOC | SHF_EXECINSTR,
+        16,
+    );
+    text.append(&[0xe8, 0, 0, 0, 0, 0xc3]);
+    text.relocs.push(Reloc {
+        offset: 1,
+        symbol: 0,
+        kind: R_X86_64_PLT32,
+        addend: -4,
+    });
+    let symbol = |name: &str, section, binding| Symbol {
+        name: name.to_string(),
+        section,
+        value: 0,
+        size: 0,
+        binding,
+        kind: SymbolKind::NoType,
+    };
+    // Globals given before locals are written after them.
+    let written = Object {
+        sections: vec![text],
+        symbols: vec![
+            symbol("exit", None, Binding::Global),
+            symbol("start", Some(0), Binding::Local),
+        ],
+    };
+    let read = elf::read(&elf::write(&written)).unwrap();
+    assert_eq!(
+        read.sections,
+        written
+            .sections
+            .clone()
+            .into_iter()
+            .map(|mut section| {
+                section.relocs[0].symbol = 1;
+                section
+            })
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        read.symbols,
+        [written.symbols[1].clone(), written.symbols[0].clone()]
+    );
+
+    assert_eq!(
+        elf::read(b"\x7fELF").unwrap_err(),
+        "the file ends before 0x5"
+    );
+    assert_eq!(elf::read(b"MZ\0\0").unwrap_err(), "not an ELF file");
+}
+
+/// Writes `source` as an object and links it with the system's C compiler,
+/// returning what it prints, or `None` if there is no C compiler.
+fn link_and_run(source: &str, level: OptLevel) -> Option<String> {
+    link_with_c_and_run(source, None, level)
+}
+
+/// Like `link_and_run`, also compiling and linking the C source `c`.
+fn link_with_c_and_run(source: &str, c: Option<&str>, level: OptLevel) -> Option<String> {
+    let mut program = lower(source);
+    opt::optimize(&mut program, level, &mut |_, _| {}).unwrap();
+    let bytes = x86::emit_object(&program, Target::Hosted).unwrap();
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    let run = RUNS.fetch_add(1, Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("enigma-object-{}-{}", std::process::id(), run));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (object_path, exe_path) = (dir.join("main.o"), dir.join("main"));
+    std::fs::write(&object_path, bytes).unwrap();
+
+    // Where there is an assembler, the code must be what it makes of the
+    // assembly.
+    let asm_path = dir.join("as.s");
+    let assembled = dir.join("as.o");
+    std::fs::write(
+        &asm_path,
+        x86::emit_asm(&program, Target::Hosted, Syntax::Att).unwrap(),
+    )
+    .unwrap();
+    let status = Command::new("as")
+        .arg(&asm_path)
+        .arg("-o")
+        .arg(&assembled)
+        .status();
+    if status.is_ok_and(|status| status.success()) {
+        let theirs = elf::read(&std::fs::read(&assembled).unwrap()).unwrap();
+        let ours = elf::read(&std::fs::read(&object_path).unwrap()).unwrap();
+        for section in ours
+            .sections
+            .iter()
+            .filter(|s| s.flags & SHF_EXECINSTR != 0)
+        {
+            assert!(
+                theirs.section(&section.name).unwrap().data == section.data,
+                "the code in `{}` differs from the assembler's for {}",
+                section.name,
+                source
+            );
+        }
+    }
+
+    let mut cc = Command::new("cc");
+    cc.arg(&object_path);
+    if let Some(c) = c {
+        let c_path = dir.join("helper.c");
+        std::fs::write(&c_path, c).unwrap();
+        cc.arg(&c_path);
+    }
+    let linked = cc.arg("-o").arg(&exe_path).status().ok()?;
+    assert!(linked.success(), "the object doesn't link");
+    let output = Command::new(&exe_path).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    Some(String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn test_linked_objects_print_what_the_interpreter_does() {
+    let programs = [
+        PROGRAMS[0],
+        PROGRAMS[2],
+        PROGRAMS[4],
+        "@fib(int n)::int {\n if n < 2 {\n  n\n } else {\n  fib(n - 1) + fib(n - 2)\n }\n}\n\
+         print(fib(15))\nprint(2.5 * -2.0 < 0.0)\nprint(\"a\" + \"b\")",
+    ];
+    for source in programs {
+        let expected = run_files(&[("proj/main.en", source)]).output;
+        for level in [OptLevel::O0, OptLevel::O2] {
+            let Some(output) = link_and_run(source, level) else {
+                return;
+            };
+            assert_eq!(output, expected, "{}", source);
+        }
+    }
+}
+
+#[test]
+fn test_linked_inline_assembly_reads_and_writes_variables() {
+    for level in [OptLevel::O0, OptLevel::O2] {
+        let Some(output) = link_and_run(super::codegen::ASM_OPERANDS, level) else {
+            return;
+        };
+        assert_eq!(output, "42\n1004\n0\n42\n");
+    }
+}
+
+#[test]
+fn test_linked_records_take_the_layout_they_ask_for() {
+    let expected = run_files(&[("proj/main.en", super::codegen::LAYOUTS)]).output;
+    for level in [OptLevel::O0, OptLevel::O2] {
+        let Some(output) = link_and_run(super::codegen::LAYOUTS, level) else {
+            return;
+        };
+        assert_eq!(output, expected);
+    }
+}
+
+#[test]
+fn test_linked_volatile_accesses_happen_as_written() {
+    for level in [OptLevel::O0, OptLevel::O2] {
+        let Some(output) = link_and_run(super::codegen::VOLATILE, level) else {
+            return;
+        };
+        assert_eq!(output, "8\n41\n200\n");
+    }
+}
+
+#[test]
+fn test_linked_atomics_give_what_the_interpreter_does() {
+    for level in [OptLevel::O0, OptLevel::O2] {
+        let Some(output) = link_and_run(super::codegen::ATOMICS, level) else {
+            return;
+        };
+        assert_eq!(output, "5\n10\n11\n20\n1\n-3\n4\n5\n250\n4\n255\n7\n");
+    }
+}
+
+#[test]
+fn test_linked_interrupt_handlers_return_with_iretq() {
+    for level in [OptLevel::O0, OptLevel::O2] {
+        let Some(output) = link_and_run(super::codegen::INTERRUPTS, level) else {
+            return;
+        };
+        assert_eq!(output, "9\n109\n");
+    }
+}
+
+#[test]
+fn test_linked_objects_call_and_are_called_by_c() {
+    for level in [OptLevel::O0, OptLevel::O2] {
+        let Some(output) = link_with_c_and_run(
+            super::codegen::C_CALLS,
+            Some(super::codegen::C_HELPER),
+            level,
+        ) else {
+            return;
+        };
+        assert_eq!(output, "60\n9\ntrue\n7\nfalse\n445\n43225\n6\n8\n");
+    }
+}
+
+#[test]
+fn test_freestanding_objects_boot_and_link_without_the_c_library() {
+    // The labels `_start32` jumps to are where the code says.
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    for (text, pieces) in boot::CODE {
+        offsets.push((*text, offset));
+        offset += pieces
+            .iter()
+            .map(|piece| match piece {
+                boot::Piece::Bytes(bytes) => bytes.len(),
+                _ => 4,
+            })
+            .sum::<usize>();
+    }
+    for (text, expected) in [
+        ("shl eax, 21", 0x23),
+        ("mov eax, 0x10", 0x78),
+        ("null descriptor", 0x9d),
+        ("GDT limit and base", 0xb5),
+    ] {
+        let found = offsets.iter().find(|(t, _)| *t == text).unwrap().1;
+        assert_eq!(found, expected, "{}", text);
+    }
+    assert_eq!(
+        offsets
+            .iter()
+            .filter(|(t, _)| *t == "cli")
+            .nth(1)
+            .unwrap()
+            .1,
+        0x99
+    );
+
+    for level in [OptLevel::O0, OptLevel::O2] {
+        let mut program = lower(&freestanding(1));
+        opt::optimize(&mut program, level, &mut |_, _| {}).unwrap();
+        let bytes = x86::emit_object(&program, Target::Freestanding).unwrap();
+        let object = elf::read(&bytes).unwrap();
+        let header = object.section(".multiboot2").unwrap();
+        assert_eq!((header.align, header.flags), (8, SHF_ALLOC));
+        let words: Vec<u32> = header
+            .data
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        assert_eq!(words[0], 0xe852_50d6);
+        assert_eq!(
+            words[..4].iter().fold(0u32, |sum, w| sum.wrapping_add(*w)),
+            0
+        );
+        assert_eq!(object.symbol("_start").unwrap().value, 0);
+        assert_eq!(object.symbol("_start32").unwrap().binding, Binding::Global);
+        assert!(
+            object.symbols.iter().all(|symbol| symbol.section.is_some()),
+            "nothing is left for the C library to define"
+        );
+        assert!(
+            object
+                .section(".text")
+                .unwrap()
+                .relocs
+                .iter()
+                .any(|reloc| reloc.kind == R_X86_64_32)
+        );
+    }
+
+    // Linked as a Linux program, `_start` runs without the C library.
+    for (divisor, code) in [(1, 42), (0, 3)] {
+        let Some(status) = link_freestanding(&freestanding(divisor)) else {
+            return;
+        };
+        assert_eq!(status, code);
+    }
+}
+
+/// Links `source` as a freestanding object with `ld`, once as a Multiboot2
+/// kernel and once as a Linux program, and returns the program's exit code,
+/// or `None` if there is no `ld`.
+fn link_freestanding(source: &str) -> Option<i32> {
+    let mut program = lower(source);
+    opt::optimize(&mut program, OptLevel::O2, &mut |_, _| {}).unwrap();
+    let dir = std::env::temp_dir().join(format!("enigma-freestanding-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (object_path, kernel_path, exe_path) = (
+        dir.join("kernel.o"),
+        dir.join("kernel.elf"),
+        dir.join("main"),
+    );
+    let bytes = x86::emit_object(&program, Target::Freestanding).unwrap();
+    std::fs::write(&object_path, bytes).unwrap();
+
+    let asm_path = dir.join("as.s");
+    let assembled = dir.join("as.o");
+    let asm = x86::emit_asm(&program, Target::Freestanding, Syntax::Att).unwrap();
+    std::fs::write(&asm_path, asm).unwrap();
+    let status = Command::new("as")
+        .arg(&asm_path)
+        .arg("-o")
+        .arg(&assembled)
+        .status();
+    if status.is_ok_and(|status| status.success()) {
+        let theirs = elf::read(&std::fs::read(&assembled).unwrap()).unwrap();
+        let ours = elf::read(&std::fs::read(&object_path).unwrap()).unwrap();
+        for section in [".text", ".multiboot2"] {
+            assert!(
+                theirs.section(section).unwrap().data == ours.section(section).unwrap().data,
+                "`{}` differs from the assembler's",
+                section
+            );
+        }
+    }
+
+    let linked = Command::new("ld")
+        .args([
+            "-n",
+            "--section-start=.multiboot2=0x100000",
+            "-Ttext=0x100020",
+        ])
+        .args(["-e", "_start32"])
+        .arg(&object_path)
+        .arg("-o")
+        .arg(&kernel_path)
+        .status()
+        .ok()?;
+    assert!(linked.success(), "the kernel doesn't link");
+    // The loader looks for the header in the first 32 KiB of the file.
+    let kernel = std::fs::read(&kernel_path).unwrap();
+    let magic = 0xe852_50d6u32.to_le_bytes();
+    let at = kernel
+        .windows(4)
+        .position(|window| window == magic)
+        .unwrap();
+    assert!(at % 8 == 0 && at < 32 * 1024, "the header is at {:#x}", at);
+
+    let linked = Command::new("ld")
+        .args(["-e", "_start"])
+        .arg(&object_path)
+        .arg("-o")
+        .arg(&exe_path)
+        .status()
+        .unwrap();
+    assert!(linked.success(), "the program doesn't link");
+    let status = Command::new(&exe_path).status().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    status.code()
+}
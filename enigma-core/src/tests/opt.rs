@@ -0,0 +1,188 @@
+use super::mir::{PROGRAMS, function, lower};
+use crate::mir::opt::{self, CLEANUP, OptLevel, Pass};
+use crate::mir::{Operand, Program, Terminator, ValueId};
+
+/// Lowers `source` and optimizes it at `level`, recording the passes that
+/// changed something.
+fn optimize(source: &str, level: OptLevel) -> (Program, Vec<&'static str>) {
+    let mut program = lower(source);
+    let mut passes = Vec::new();
+    if let Err(error) = opt::optimize(&mut program, level, &mut |pass, _| passes.push(pass)) {
+        panic!("{}\n{}", error, program);
+    }
+    (program, passes)
+}
+
+#[test]
+fn test_o0_leaves_the_mir_as_lowered() {
+    let source = "@max(int a, int b)::int {\n if a > b {\n  a\n } else {\n  b\n }\n}";
+    let (program, passes) = optimize(source, OptLevel::O0);
+    assert_eq!(program, lower(source));
+    assert!(passes.is_empty());
+}
+
+#[test]
+fn test_constants_propagate_and_fold() {
+    let (program, passes) = optimize(
+        "@folded()::int {\n int x := 4\n int y := x * 2\n y + 1\n}",
+        OptLevel::O1,
+    );
+    assert_eq!(
+        function(&program, "folded"),
+        "@folded()::int {
+  bb0:
+    return 9
+}
+"
+    );
+    for pass in ["const-prop", "fold", "dce"] {
+        assert!(passes.contains(&pass), "{} didn't run: {:?}", pass, passes);
+    }
+}
+
+#[test]
+fn test_copies_propagate_and_the_cfg_collapses() {
+    let (program, _) = optimize(
+        "@max(int a, int b)::int {\n if a > b {\n  a\n } else {\n  b\n }\n}",
+        OptLevel::O1,
+    );
+    assert_eq!(
+        function(&program, "max"),
+        "@max(int, int)::int {
+  bb0(%0: int, %1: int):
+    %4: bool = %0 > %1
+    branch %4, bb1(%0), bb1(%1)
+
+  bb1(%5: int):
+    return %5
+}
+"
+    );
+}
+
+#[test]
+fn test_constant_branches_are_taken() {
+    let (program, _) = optimize(
+        "@pick()::string {\n if 2 > 1 {\n  \"yes\"\n } else {\n  \"no\"\n }\n}",
+        OptLevel::O1,
+    );
+    assert_eq!(
+        function(&program, "pick"),
+        "@pick()::string {
+  bb0:
+    return \"yes\"
+}
+"
+    );
+}
+
+#[test]
+fn test_operations_that_fail_at_runtime_are_kept() {
+    let (program, _) = optimize(
+        "@checked(int a)::int {\n\
+          int big := 9223372036854775807 + 1\n\
+          int zero := a / 0\n\
+          int half := a / 2\n\
+          1\n}",
+        OptLevel::O2,
+    );
+    assert_eq!(
+        function(&program, "checked"),
+        "@checked(int)::int {
+  bb0(%0: int):
+    %1: int = 9223372036854775807 + 1
+    %3: int = %0 / 0
+    return 1
+}
+"
+    );
+}
+
+#[test]
+fn test_small_functions_are_inlined_at_o2() {
+    let source = "@sum(int a, int b)::int -> a + b;\n\
+                  @twice_sum(int x)::int -> sum(x, x) + sum(1, 2);";
+    let (program, _) = optimize(source, OptLevel::O1);
+    assert!(function(&program, "twice_sum").contains("call sum(1, 2)"));
+
+    let (program, passes) = optimize(source, OptLevel::O2);
+    assert!(passes.contains(&"inline"));
+    assert_eq!(
+        function(&program, "twice_sum"),
+        "@twice_sum(int)::int {
+  bb0(%0: int):
+    %10: int = %0 + %0
+    %5: int = %10 + 3
+    return %5
+}
+"
+    );
+}
+
+#[test]
+fn test_recursive_functions_are_not_inlined() {
+    let (program, _) = optimize(
+        "@count(int n)::int {\n if n == 0 {\n  0\n } else {\n  1 + count(n - 1)\n }\n}\n\
+         @three()::int -> count(3);",
+        OptLevel::O2,
+    );
+    assert!(function(&program, "three").contains("call count(3)"));
+    assert!(function(&program, "count").contains("call count("));
+}
+
+#[test]
+fn test_volatile_accesses_port_io_and_fences_are_kept() {
+    let (program, _) = optimize(
+        "@device()::int {\n mut int reg := 0\n unsafe {\n  \
+         raw_ref mut int r := raw_ref mut reg\n  volatile_write(r, 1)\n  volatile_write(r, 1)\n  \
+         int ignored := volatile_read(r)\n  fence()\n  byte status := inb(0x64)\n  outb(0x80, 0)\n  \
+         volatile_read(r)\n }\n}",
+        OptLevel::O2,
+    );
+    let device = function(&program, "device");
+    for (call, count) in [
+        ("call volatile_write(", 2),
+        ("call volatile_read(", 2),
+        ("call fence()", 1),
+        ("call inb(", 1),
+        ("call outb(", 1),
+    ] {
+        assert_eq!(device.matches(call).count(), count, "{}", device);
+    }
+    // The write happens before the read, and the fence between them.
+    let write = device.rfind("call volatile_write(").unwrap();
+    let fence = device.find("call fence()").unwrap();
+    assert!(write < fence && fence < device.rfind("call volatile_read(").unwrap());
+}
+
+#[test]
+fn test_programs_verify_after_every_pass() {
+    for source in PROGRAMS {
+        optimize(source, OptLevel::O2);
+    }
+}
+
+#[test]
+fn test_a_pass_breaking_the_mir_is_named() {
+    const BREAK: Pass = Pass {
+        name: "break",
+        run: |program| {
+            let max = program
+                .functions
+                .iter_mut()
+                .find(|f| f.name == "max")
+                .unwrap();
+            max.blocks[0].term = Terminator::Return(Operand::Value(ValueId(6)));
+            true
+        },
+    };
+    let mut program = lower("@max(int a, int b)::int {\n if a > b {\n  a\n } else {\n  b\n }\n}");
+    let mut passes = Vec::new();
+    let error = opt::run_passes(&mut program, &[CLEANUP[0], BREAK], &mut |pass, _| {
+        passes.push(pass)
+    })
+    .expect_err("the broken function must be caught");
+    assert_eq!(error.pass, "break");
+    assert_eq!(error.error.function, "max");
+    assert_eq!(passes, ["simplify-cfg"]);
+}
@@ -0,0 +1,138 @@
+use super::{CheckCase, run_check_cases};
+use crate::checker::check_program;
+use crate::errorhandler::ErrorHandler;
+use crate::mono::{self, Instance};
+use crate::parser;
+
+const ERRORS: &str = "record io_error {\n code: int\n}\n\
+                      record parse_error {\n line: int\n}\n\
+                      implement into_error[io_error] for parse_error {\n\
+                       @into_error(self)::io_error -> io_error { code: self::line };\n\
+                      }\n\
+                      @parse(string s)::Result[int, parse_error] -> Result::Ok(1);\n";
+
+#[test]
+fn test_prelude_items() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "used unqualified",
+            input: "Option[int] o := Option::Some(1)\nint v := o::extract()",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "used through core",
+            input: "core::Result[int, string] r := core::Result::Err(\"no\")\nint v := r::extract()",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "shadowed by a local definition",
+            input: "union Option[T] {\n Some(T),\n Nothing\n}\nOption[int] o := Option::Nothing",
+            errors: vec![],
+        },
+        CheckCase {
+            name: "wrong number of protocol arguments",
+            input: "record e {\n}\nimplement into_error for e {\n @into_error(self)::e -> self;\n}",
+            errors: vec!["protocol `into_error` expects 1 generic argument(s), found 0"],
+        },
+        CheckCase {
+            name: "generic protocols are not objects",
+            input: "record e {\n}\nimplement into_error[int] for e {\n @into_error(self)::int -> 1;\n}\n\
+                    @f(ref e x)::ref into_error -> x;",
+            errors: vec!["protocol `core::into_error` cannot be made into an object"],
+        },
+    ]);
+}
+
+#[test]
+fn test_question_mark() {
+    let cases = [
+        (
+            "options in a function returning an option",
+            "@first(Option[int] o)::Option[int] {\n int v := o?\n Option::Some(v + 1)\n}",
+            vec![],
+        ),
+        (
+            "results with the same error type",
+            "@twice(string s)::Result[int, parse_error] {\n int v := parse(s)?\n Result::Ok(v * 2)\n}",
+            vec![],
+        ),
+        (
+            "errors converted with into_error",
+            "@twice(string s)::Result[int, io_error] {\n int v := parse(s)?\n Result::Ok(v * 2)\n}",
+            vec![],
+        ),
+        (
+            "errors without a conversion",
+            "@twice(string s)::Result[int, string] {\n int v := parse(s)?\n Result::Ok(v * 2)\n}",
+            vec!["`?` cannot convert the error type `parse_error` into `string`"],
+        ),
+        (
+            "result in a function returning an option",
+            "@twice(string s)::Option[int] {\n int v := parse(s)?\n Option::Some(v)\n}",
+            vec![
+                "the `?` operator can only be used on `Result` in a function that returns `Result`",
+            ],
+        ),
+        (
+            "function returning a plain value",
+            "@value(Option[int] o)::int {\n o?\n}",
+            vec![
+                "the `?` operator can only be used on `Option` in a function that returns `Option`",
+            ],
+        ),
+        (
+            "top level",
+            "Option[int] o := Option::None\nint v := o?",
+            vec![
+                "the `?` operator can only be used on `Option` in a function that returns `Option`",
+            ],
+        ),
+        (
+            "closure results are inferred",
+            "@call[T](@()::T f)::T -> f();\n\
+             Option[int] o := call(@() -> Option::Some(Option::Some(1)? + 1))",
+            vec![],
+        ),
+        (
+            "not an option or result",
+            "@f()::Option[int] {\n int v := 1?\n Option::Some(v)\n}",
+            vec!["the `?` operator can only be applied to `Option` or `Result`, found `int`"],
+        ),
+    ];
+    for (name, body, errors) in cases {
+        let input = format!("{}{}", ERRORS, body);
+        run_check_cases(vec![CheckCase {
+            name,
+            input: &input,
+            errors,
+        }]);
+    }
+}
+
+#[test]
+fn test_question_mark_notes_the_return_type() {
+    let source = format!("{}@value(Option[int] o)::int {{\n o?\n}}", ERRORS);
+    let diagnostics = super::diagnostics_for(&source);
+    let error = diagnostics.iter().find(|d| d.is_error()).expect("an error");
+    assert_eq!(&source[error.span.start..error.span.end], "o?");
+    assert_eq!(error.notes, vec!["the enclosing function returns `int`"]);
+}
+
+#[test]
+fn test_error_conversions_are_instantiated() {
+    let mut handler = ErrorHandler::new();
+    let source = format!(
+        "{}@twice(string s)::Result[int, io_error] {{\n int v := parse(s)?\n Result::Ok(v * 2)\n}}",
+        ERRORS
+    );
+    let program = parser::parse(&source, &mut handler);
+    let checked = check_program(&program, &mut handler);
+    assert!(!handler.has_errors(), "{:?}", handler.diagnostics());
+    let items = mono::collect(&[checked], &mut handler);
+    let names: Vec<String> = items.instances.iter().map(Instance::to_string).collect();
+    assert!(
+        names.contains(&"parse_error::into_error".to_string()),
+        "{:?}",
+        names
+    );
+}
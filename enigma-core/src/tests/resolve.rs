@@ -0,0 +1,204 @@
+use super::{CheckCase, diagnostics_for, run_check_cases};
+use crate::checker;
+use crate::checker::resolve::{Res, edit_distance, similar_name};
+use crate::errorhandler::ErrorHandler;
+use crate::parser;
+use crate::parser::ast::{ExprKind, ItemKind, StmtKind};
+
+#[test]
+fn test_scopes_end_with_their_block() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "block locals",
+            input: "int a := {\n int inner := 1\n inner\n}\nint b := inner",
+            errors: vec!["cannot find value `inner` in this scope"],
+        },
+        CheckCase {
+            name: "for binding",
+            input: "@f()::int {\n for i in 0..3 {\n print(i)\n }\n i\n}",
+            errors: vec!["cannot find value `i` in this scope"],
+        },
+        CheckCase {
+            name: "match arm binding",
+            input: "union Option[T] {\n Some(T),\n None\n}\n\
+                    @f(Option[int] o)::int {\n match o {\n Option::Some(v): v\n _: 0\n }\n v\n}",
+            errors: vec!["cannot find value `v` in this scope"],
+        },
+        CheckCase {
+            name: "functions don't see script locals",
+            input: "(int x, int y) $= (1, 2)\n@f()::int -> x;",
+            errors: vec!["cannot find value `x` in this scope"],
+        },
+        CheckCase {
+            name: "initializer can't see its own name",
+            input: "@f()::int {\n int n := n + 1\n n\n}",
+            errors: vec!["cannot find value `n` in this scope"],
+        },
+        CheckCase {
+            name: "duplicate parameter",
+            input: "@f(int a, int a)::int -> a;",
+            errors: vec!["identifier `a` is bound more than once in this parameter list"],
+        },
+    ]);
+}
+
+#[test]
+fn test_paths_resolve_to_their_definitions() {
+    let source = "union Option[T] {\n Some(T),\n None\n}\n\
+                  record human {\n age: int\n}\n\
+                  implement human {\n pub @new(int age)::human -> human { age: age };\n}\n\
+                  @f(int x)::Option[int] -> Option::Some(x);\n\
+                  human h := human::new(3)\nint g := 1\nint y := g\nprint(y)";
+    let mut handler = ErrorHandler::new();
+    let program = parser::parse(source, &mut handler);
+    let (_, results) = checker::check_program(&program, &mut handler);
+    assert!(!handler.has_errors(), "{:?}", handler.diagnostics());
+
+    let mut found = Vec::new();
+    let mut callees = |expr: &crate::parser::ast::Expr| {
+        if let ExprKind::Call { callee, .. } = &expr.kind {
+            found.push(results.resolutions.get(callee.id).cloned());
+        }
+        if let ExprKind::Path(_) = &expr.kind {
+            found.push(results.resolutions.get(expr.id).cloned());
+        }
+    };
+    for item in &program.items {
+        match &item.kind {
+            ItemKind::Stmt(stmt) => match &stmt.kind {
+                StmtKind::Let { init, .. } => callees(init),
+                StmtKind::Expr(expr) => callees(expr),
+                _ => {}
+            },
+            ItemKind::Function(function) => {
+                if let Some(crate::parser::ast::FnBody::Inline(body)) = &function.body
+                    && let ExprKind::Return(Some(value)) = &body.kind
+                {
+                    callees(value);
+                }
+            }
+            _ => {}
+        }
+    }
+    assert_eq!(
+        found,
+        vec![
+            Some(Res::Variant {
+                union: "Option".to_string(),
+                variant: "Some".to_string(),
+            }),
+            Some(Res::AssocFn {
+                ty: "human".to_string(),
+                name: "new".to_string(),
+            }),
+            Some(Res::Global("g".to_string())),
+            Some(Res::Builtin("print".to_string())),
+        ]
+    );
+}
+
+#[test]
+fn test_shadowing_an_outer_binding_warns() {
+    let diagnostics = diagnostics_for(
+        "@f(int x)::int {\n int y := 1\n int y := 2\n if y > 0 {\n int x := 3\n }\n x + y\n}",
+    );
+    let warnings: Vec<&str> = diagnostics
+        .iter()
+        .filter(|d| !d.is_error())
+        .map(|d| d.message.as_str())
+        .collect();
+    // Rebinding `y` in the same block is fine; hiding the parameter isn't.
+    assert_eq!(warnings, vec!["`x` shadows a binding from an outer scope"]);
+    assert!(diagnostics.iter().all(|d| !d.is_error()));
+}
+
+#[test]
+fn test_unresolved_names_suggest_similar_ones() {
+    let cases = [
+        (
+            "@f(int count)::int -> conut;",
+            "cannot find value `conut`",
+            "count",
+        ),
+        (
+            "@total()::int -> 1;\nint n := totl()",
+            "cannot find value `totl`",
+            "total",
+        ),
+        (
+            "record point {\n x: int\n}\npoitn p := point { x: 1 }",
+            "cannot find type `poitn`",
+            "point",
+        ),
+        (
+            "record point {\n x: int\n}\npoint p := pont { x: 1 }",
+            "cannot find record `pont`",
+            "point",
+        ),
+        (
+            "union Option[T] {\n Some(T),\n None\n}\nOption[int] o := Option::Som(1)",
+            "no variant or associated function named `Som` in `Option`",
+            "Some",
+        ),
+        ("int n := prnt(1)", "cannot find value `prnt`", "print"),
+    ];
+    for (input, message, suggestion) in cases {
+        let diagnostics = diagnostics_for(input);
+        let errors: Vec<_> = diagnostics.iter().filter(|d| d.is_error()).collect();
+        assert_eq!(errors.len(), 1, "{}: {:?}", input, errors);
+        assert!(errors[0].message.contains(message), "{:?}", errors[0]);
+        assert_eq!(
+            errors[0].fixits.first().map(|f| f.replacement.as_str()),
+            Some(suggestion),
+            "{}",
+            input
+        );
+    }
+
+    let far = diagnostics_for("@f(int count)::int -> zzz;");
+    assert!(far[0].fixits.is_empty());
+}
+
+#[test]
+fn test_globals_are_used_after_their_declaration() {
+    run_check_cases(vec![
+        CheckCase {
+            name: "the script reads a global early",
+            input: "print(x)\nint x := 1\nint y := y + x",
+            errors: vec![
+                "`x` is used before its declaration",
+                "`y` is used before its declaration",
+            ],
+        },
+        CheckCase {
+            name: "a function reads a global early",
+            input: "@f()::int -> x;\n@g()::int -> f();\n\
+                    record r { a: int }\nimplement r {\n @make()::r -> r { a: x };\n}\n\
+                    print(f())\nprint(g())\nr made := r::make()\nint x := 1",
+            errors: vec![
+                "`@f` is called before `x` is declared, and reads it",
+                "`@g` is called before `x` is declared, and calls a function reading it",
+                "`@make` is called before `x` is declared, and reads it",
+            ],
+        },
+        CheckCase {
+            name: "globals in order",
+            input: "int x := 1\n@f()::int -> x + y;\nint y := x\nprint(f())\n\
+                    @again()::int -> again();\nprint(again())",
+            errors: vec![],
+        },
+    ]);
+}
+
+#[test]
+fn test_edit_distance() {
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    assert_eq!(edit_distance("", "abc"), 3);
+    assert_eq!(edit_distance("same", "same"), 0);
+    assert_eq!(edit_distance("ab", "ba"), 1);
+    assert_eq!(
+        similar_name("helo", ["hello", "help", "world"].into_iter()),
+        Some("hello".to_string())
+    );
+    assert_eq!(similar_name("x", ["abc"].into_iter()), None);
+}